@@ -1,6 +1,8 @@
 use bytesize::ByteSize;
 use futures::stream::iter;
 use futures::StreamExt;
+use libqueued::op::delete::OpDeleteInput;
+use libqueued::op::delete::OpDeleteInputMessage;
 use libqueued::op::poll::OpPollInput;
 use libqueued::op::push::OpPushInput;
 use libqueued::op::push::OpPushInputMessage;
@@ -11,10 +13,16 @@ use off64::int::Off64WriteMutInt;
 use off64::u64;
 use off64::usz;
 use parking_lot::Mutex;
+use queued_client_rs::Message;
+use queued_client_rs::PushMessage;
+use queued_client_rs::QueuedClient;
+use queued_client_rs::QueuedClientCfg;
+use queued_client_rs::QueuedQueueClient;
 use roaring::RoaringTreemap;
 use serde::Deserialize;
 use std::env;
 use std::fs;
+use std::future::Future;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
@@ -23,10 +31,26 @@ use tokio::fs::remove_dir_all;
 use tokio::time::Instant;
 use tracing::info;
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerConfig {
+  /// For example, `http://127.0.0.1:3333`.
+  endpoint: String,
+
+  api_key: Option<String>,
+
+  /// Must already exist, and should be empty.
+  queue: String,
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct Config {
-  data_dir: PathBuf,
+  /// Runs queued in-process using this data directory. Required unless `server` is set.
+  data_dir: Option<PathBuf>,
+
+  /// Benchmarks a running server over HTTP instead of running queued in-process, which includes the overhead of the HTTP API and network.
+  server: Option<ServerConfig>,
 
   /// Messages to create.
   messages: usize,
@@ -34,6 +58,9 @@ struct Config {
   /// Size of a created message.
   message_size: ByteSize,
 
+  /// Messages per push, poll, or delete op. Defaults to 1.
+  batch_size: Option<usize>,
+
   /// Concurrency level. Defaults to 64.
   concurrency: Option<usize>,
 
@@ -45,9 +72,175 @@ struct Config {
   #[serde(default)]
   skip_push: bool,
 
-  /// Skips polling messages.
+  /// Skips polling messages. Deleting requires polling, so this also skips deleting.
   #[serde(default)]
   skip_poll: bool,
+
+  /// Skips deleting polled messages.
+  #[serde(default)]
+  skip_delete: bool,
+}
+
+enum Target {
+  InProcess(Arc<Queued>),
+  Server(QueuedQueueClient),
+}
+
+impl Target {
+  async fn push(&self, contents: Vec<Vec<u8>>) {
+    match self {
+      Target::InProcess(queued) => {
+        queued
+          .push(OpPushInput {
+            messages: contents
+              .into_iter()
+              .map(|contents| OpPushInputMessage {
+                contents,
+                visibility_timeout_secs: 0,
+                visibility_jitter_secs: 0,
+                priority: 0,
+                attributes: Default::default(),
+                ttl_secs: None,
+                group_id: None,
+                external_id: None,
+              })
+              .collect(),
+          })
+          .await
+          .unwrap();
+      }
+      Target::Server(client) => {
+        client
+          .push_messages(
+            contents
+              .into_iter()
+              .map(|contents| PushMessage {
+                contents,
+                visibility_timeout: Duration::ZERO,
+                attributes: Default::default(),
+                ttl: None,
+                group_id: None,
+              })
+              .collect::<Vec<_>>(),
+          )
+          .await
+          .unwrap();
+      }
+    };
+  }
+
+  /// Returns the ID, poll tag, and contents of each polled message.
+  async fn poll(&self, count: usize) -> Vec<(u64, u32, Vec<u8>)> {
+    match self {
+      Target::InProcess(queued) => queued
+        .poll(OpPollInput {
+          count,
+          visibility_timeout_secs: 3600,
+          ignore_existing_visibility_timeouts: false,
+          prefer_group: None,
+          fields: None,
+        })
+        .await
+        .unwrap()
+        .messages
+        .into_iter()
+        .map(|m| (m.id, m.poll_tag, m.contents))
+        .collect(),
+      Target::Server(client) => client
+        .poll_messages(u64!(count), Duration::from_secs(3600))
+        .await
+        .unwrap()
+        .messages
+        .into_iter()
+        .map(|m| (m.id, m.poll_tag, m.contents))
+        .collect(),
+    }
+  }
+
+  async fn delete(&self, messages: Vec<(u64, u32)>) {
+    match self {
+      Target::InProcess(queued) => {
+        queued
+          .delete(OpDeleteInput {
+            messages: messages
+              .into_iter()
+              .map(|(id, poll_tag)| OpDeleteInputMessage { id, poll_tag })
+              .collect(),
+          })
+          .await
+          .unwrap();
+      }
+      Target::Server(client) => {
+        client
+          .delete_messages(
+            messages
+              .into_iter()
+              .map(|(id, poll_tag)| Message { id, poll_tag }),
+          )
+          .await
+          .unwrap();
+      }
+    };
+  }
+}
+
+/// Runs `op` for each batch with up to `concurrency` in flight, and returns how long each took.
+async fn run_ops<F: Future<Output = ()>>(
+  batches: Vec<Vec<u64>>,
+  concurrency: usize,
+  op: impl Fn(Vec<u64>) -> F,
+) -> Vec<Duration> {
+  let latencies: Mutex<Vec<Duration>> = Default::default();
+  iter(batches)
+    .for_each_concurrent(Some(concurrency), |batch| {
+      let fut = op(batch);
+      let latencies = &latencies;
+      async move {
+        let started = Instant::now();
+        fut.await;
+        latencies.lock().push(started.elapsed());
+      }
+    })
+    .await;
+  latencies.into_inner()
+}
+
+fn percentile_ms(sorted: &[Duration], p: f64) -> f64 {
+  let i = ((sorted.len() - 1) as f64 * p).round() as usize;
+  sorted[i].as_secs_f64() * 1000.0
+}
+
+fn report(
+  op: &str,
+  exec: Duration,
+  message_count: usize,
+  bytes: Option<usize>,
+  mut latencies: Vec<Duration>,
+) {
+  latencies.sort_unstable();
+  let exec_secs = exec.as_secs_f64();
+  let ops_per_second = latencies.len() as f64 / exec_secs;
+  let messages_per_second = message_count as f64 / exec_secs;
+  let p50_ms = percentile_ms(&latencies, 0.5);
+  let p99_ms = percentile_ms(&latencies, 0.99);
+  let max_ms = percentile_ms(&latencies, 1.0);
+  match bytes {
+    Some(bytes) => info!(
+      op,
+      exec_secs,
+      ops_per_second,
+      messages_per_second,
+      mib_per_second = bytes as f64 / exec_secs / 1024.0 / 1024.0,
+      p50_ms,
+      p99_ms,
+      max_ms,
+      "completed all ops",
+    ),
+    None => info!(
+      op,
+      exec_secs, ops_per_second, messages_per_second, p50_ms, p99_ms, max_ms, "completed all ops",
+    ),
+  };
 }
 
 #[tokio::main]
@@ -60,85 +253,112 @@ async fn main() {
   )
   .expect("parse config file");
   let concurrency = cli.concurrency.unwrap_or(64);
+  let batch_size = cli.batch_size.unwrap_or(1);
+  assert!(batch_size > 0, "batch size must be positive");
   let message_count = cli.messages;
+  assert!(message_count > 0, "at least one message must be created");
   let message_size = usz!(cli.message_size.as_u64());
+  // Each message's contents start with its index, so polls can verify that every message is polled exactly once.
+  let batches = (0..u64!(message_count))
+    .collect::<Vec<_>>()
+    .chunks(batch_size)
+    .map(|c| c.to_vec())
+    .collect::<Vec<_>>();
 
-  if !cli.skip_data_dir_reset {
-    remove_dir_all(&cli.data_dir).await.unwrap();
-    create_dir(&cli.data_dir).await.unwrap();
-    info!("cleared data dir");
+  let target = match cli.server {
+    Some(server) => {
+      let client = QueuedClient::new(QueuedClientCfg {
+        api_key: server.api_key,
+        endpoint: server.endpoint,
+      });
+      info!(queue = server.queue, "using server");
+      Target::Server(client.queue(&server.queue))
+    }
+    None => {
+      let data_dir = cli.data_dir.expect("data_dir or server must be set");
+      if !cli.skip_data_dir_reset {
+        remove_dir_all(&data_dir).await.unwrap();
+        create_dir(&data_dir).await.unwrap();
+        info!("cleared data dir");
+      };
+      let queued = Queued::load_and_start(&data_dir, QueuedCfg {
+        batch_sync_delay: Duration::from_millis(10),
+        ..Default::default()
+      })
+      .await;
+      info!("queued loaded");
+      Target::InProcess(Arc::new(queued))
+    }
   };
-  let queued = Arc::new(
-    Queued::load_and_start(&cli.data_dir, QueuedCfg {
-      batch_sync_delay: Duration::from_millis(10),
-    })
-    .await,
-  );
-  info!("queued loaded");
+  let target = &target;
 
   if !cli.skip_push {
     let now = Instant::now();
-    iter(0..message_count)
-      .for_each_concurrent(Some(concurrency), |i| {
-        let queued = queued.clone();
-        let mut contents = vec![0u8; message_size];
-        contents.write_u64_le_at(0, u64!(i));
-        async move {
-          queued
-            .push(OpPushInput {
-              messages: vec![OpPushInputMessage {
-                contents: contents.into(),
-                visibility_timeout_secs: 0,
-              }],
-            })
-            .await
-            .unwrap();
-        }
-      })
-      .await;
-    let push_exec_secs = now.elapsed().as_secs_f64();
-    info!(
-      push_exec_secs,
-      push_ops_per_second = (message_count as f64) / push_exec_secs,
-      push_mib_per_second =
-        (message_count * message_size) as f64 / push_exec_secs / 1024.0 / 1024.0,
-      "completed all push ops",
+    let latencies = run_ops(batches.clone(), concurrency, |batch| async move {
+      let contents = batch
+        .into_iter()
+        .map(|i| {
+          let mut contents = vec![0u8; message_size];
+          contents.write_u64_le_at(0, i);
+          contents
+        })
+        .collect();
+      target.push(contents).await;
+    })
+    .await;
+    report(
+      "push",
+      now.elapsed(),
+      message_count,
+      Some(message_count * message_size),
+      latencies,
     );
   };
 
   if !cli.skip_poll {
     let now = Instant::now();
-    let unseen: Arc<Mutex<RoaringTreemap>> = Default::default();
+    let unseen: Mutex<RoaringTreemap> = Default::default();
     unseen.lock().insert_range(0..u64!(message_count));
-    iter(0..message_count)
-      .for_each_concurrent(Some(concurrency), |_| {
-        let queued = queued.clone();
-        let unseen = unseen.clone();
-        async move {
-          let msg = &queued
-            .poll(OpPollInput {
-              count: 1,
-              visibility_timeout_secs: 3600,
-              ignore_existing_visibility_timeouts: false,
-            })
-            .await
-            .unwrap()
-            .messages[0]
-            .contents;
-          assert_eq!(msg.len(), message_size);
-          let id = msg.read_u64_le_at(0);
-          assert!(unseen.lock().remove(id));
+    let polled: Mutex<Vec<(u64, u32)>> = Default::default();
+    let latencies = run_ops(batches.clone(), concurrency, |batch| {
+      let unseen = &unseen;
+      let polled = &polled;
+      async move {
+        let msgs = target.poll(batch.len()).await;
+        assert_eq!(msgs.len(), batch.len());
+        for (id, poll_tag, contents) in msgs {
+          assert_eq!(contents.len(), message_size);
+          assert!(unseen.lock().remove(contents.read_u64_le_at(0)));
+          polled.lock().push((id, poll_tag));
         }
-      })
-      .await;
+      }
+    })
+    .await;
+    let exec = now.elapsed();
     // Use `min` instead of `is_empty` so the panic will show the ID if it exists.
     assert_eq!(unseen.lock().min(), None);
-    let poll_exec_secs = now.elapsed().as_secs_f64();
-    info!(
-      poll_exec_secs,
-      poll_ops_per_second = (message_count as f64) / poll_exec_secs,
-      "completed all poll ops",
+    report(
+      "poll",
+      exec,
+      message_count,
+      Some(message_count * message_size),
+      latencies,
     );
+
+    if !cli.skip_delete {
+      let polled = polled.into_inner();
+      let now = Instant::now();
+      let latencies = run_ops(batches, concurrency, |batch| {
+        // Batches are only used for their size, as polled messages are deleted in the order they were polled.
+        let start = usz!(batch[0]);
+        let messages = polled[start..start + batch.len()].to_vec();
+        async move {
+          target.delete(messages).await;
+        }
+      })
+      .await;
+      report("delete", now.elapsed(), message_count, None, latencies);
+    };
   };
 
   info!("all done");
@@ -0,0 +1,101 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub const MAX_ATTRIBUTES: usize = 16;
+pub const MAX_ATTRIBUTE_NAME_LEN: usize = 256;
+pub const MAX_ATTRIBUTE_STRING_LEN: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageAttributeValue {
+  Integer(i64),
+  Float(f64),
+  String(String),
+}
+
+/// Small key/value pairs stored alongside a message's contents, for routing and filtering without parsing the contents.
+pub type MessageAttributes = BTreeMap<String, MessageAttributeValue>;
+
+pub(crate) fn attributes_are_valid(attrs: &MessageAttributes) -> bool {
+  attrs.len() <= MAX_ATTRIBUTES
+    && attrs.iter().all(|(k, v)| {
+      !k.is_empty()
+        && k.len() <= MAX_ATTRIBUTE_NAME_LEN
+        && match v {
+          MessageAttributeValue::String(s) => s.len() <= MAX_ATTRIBUTE_STRING_LEN,
+          _ => true,
+        }
+    })
+}
+
+const TYPE_INTEGER: u8 = 0;
+const TYPE_FLOAT: u8 = 1;
+const TYPE_STRING: u8 = 2;
+
+// Each attribute is encoded as the u16 LE name length, the name, the type byte, and then an i64 LE, f64 LE, or u16 LE length followed by the string.
+pub(crate) fn encode_attributes(attrs: &MessageAttributes) -> Vec<u8> {
+  let mut out = Vec::new();
+  for (k, v) in attrs {
+    out.extend_from_slice(&(k.len() as u16).to_le_bytes());
+    out.extend_from_slice(k.as_bytes());
+    match v {
+      MessageAttributeValue::Integer(i) => {
+        out.push(TYPE_INTEGER);
+        out.extend_from_slice(&i.to_le_bytes());
+      }
+      MessageAttributeValue::Float(f) => {
+        out.push(TYPE_FLOAT);
+        out.extend_from_slice(&f.to_le_bytes());
+      }
+      MessageAttributeValue::String(s) => {
+        out.push(TYPE_STRING);
+        out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+      }
+    };
+  }
+  out
+}
+
+pub(crate) fn decode_attributes(raw: &[u8]) -> MessageAttributes {
+  try_decode_attributes(raw).expect("corrupt message attributes")
+}
+
+/// Returns None if `raw` isn't validly encoded, e.g. due to corruption.
+pub(crate) fn try_decode_attributes(raw: &[u8]) -> Option<MessageAttributes> {
+  fn read_u16(raw: &[u8], i: &mut usize) -> Option<u16> {
+    let v = u16::from_le_bytes(raw.get(*i..*i + 2)?.try_into().unwrap());
+    *i += 2;
+    Some(v)
+  }
+
+  fn read_u64(raw: &[u8], i: &mut usize) -> Option<u64> {
+    let v = u64::from_le_bytes(raw.get(*i..*i + 8)?.try_into().unwrap());
+    *i += 8;
+    Some(v)
+  }
+
+  fn read_str(raw: &[u8], i: &mut usize) -> Option<String> {
+    let len = read_u16(raw, i)? as usize;
+    let s = String::from_utf8(raw.get(*i..*i + len)?.to_vec()).ok()?;
+    *i += len;
+    Some(s)
+  }
+
+  let mut attrs = MessageAttributes::new();
+  let mut i = 0;
+  while i < raw.len() {
+    let k = read_str(raw, &mut i)?;
+    let typ = *raw.get(i)?;
+    i += 1;
+    let v = match typ {
+      TYPE_INTEGER => MessageAttributeValue::Integer(read_u64(raw, &mut i)? as i64),
+      TYPE_FLOAT => MessageAttributeValue::Float(f64::from_bits(read_u64(raw, &mut i)?)),
+      TYPE_STRING => MessageAttributeValue::String(read_str(raw, &mut i)?),
+      _ => return None,
+    };
+    attrs.insert(k, v);
+  }
+  Some(attrs)
+}
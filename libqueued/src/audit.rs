@@ -0,0 +1,153 @@
+use crate::storage::Storage;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use off64::int::Off64ReadInt;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+// Each event is stored under the message's ID, then the time and a counter to keep events in the same millisecond apart, so that a message's history is a single ordered prefix scan.
+const AUDIT_KEY_PREFIX: &[u8] = b"audit/";
+const AUDIT_KEY_LEN: usize = AUDIT_KEY_PREFIX.len() + 8 + 8 + 4;
+const AUDIT_KEY_TS_OFFSET: u64 = AUDIT_KEY_PREFIX.len() as u64 + 8;
+
+tokio::task_local! {
+  static AUDIT_CLIENT: String;
+}
+
+/// Runs `f` with `client` recorded as who performed any operations in it, if the queue has an audit log.
+pub async fn with_audit_client<F: Future>(client: String, f: F) -> F::Output {
+  AUDIT_CLIENT.scope(client, f).await
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, FromPrimitive)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum AuditAction {
+  Push = 1,
+  Poll = 2,
+  Update = 3,
+  Nack = 4,
+  Takeover = 5,
+  Delete = 6,
+  DeadLetter = 7,
+  ForceDelete = 8,
+}
+
+#[derive(Serialize)]
+pub struct AuditEvent {
+  /// In milliseconds since the epoch.
+  pub ts_ms: i64,
+  pub action: AuditAction,
+  /// Who performed the action, or None if it was done by the server itself, e.g. a webhook delivery.
+  pub client: Option<String>,
+  /// The poll tag that the action was performed with, if it required one.
+  pub poll_tag: Option<u32>,
+  /// The message's poll tag after the action, if it changed.
+  pub new_poll_tag: Option<u32>,
+}
+
+// Encoded as the action byte, the poll tag and new poll tag as u64 LE (u64::MAX if none), and then the UTF-8 client, if any.
+const EVENT_HEADER_LEN: usize = 1 + 8 + 8;
+
+fn encode_event(e: &AuditEvent) -> Vec<u8> {
+  let mut out = vec![e.action as u8];
+  for tag in [e.poll_tag, e.new_poll_tag] {
+    out.extend_from_slice(&tag.map_or(u64::MAX, u64::from).to_le_bytes());
+  }
+  if let Some(c) = &e.client {
+    out.extend_from_slice(c.as_bytes());
+  };
+  out
+}
+
+fn decode_event(ts_ms: i64, raw: &[u8]) -> Option<AuditEvent> {
+  let tag = |off: u64| {
+    Some(raw.read_u64_le_at(off))
+      .filter(|&t| t != u64::MAX)
+      .map(|t| t as u32)
+  };
+  if raw.len() < EVENT_HEADER_LEN {
+    return None;
+  };
+  Some(AuditEvent {
+    ts_ms,
+    action: AuditAction::from_u8(raw[0])?,
+    poll_tag: tag(1),
+    new_poll_tag: tag(9),
+    client: match &raw[EVENT_HEADER_LEN..] {
+      [] => None,
+      c => Some(String::from_utf8(c.to_vec()).ok()?),
+    },
+  })
+}
+
+fn audit_key_id_prefix(id: u64) -> Vec<u8> {
+  let mut k = AUDIT_KEY_PREFIX.to_vec();
+  k.extend_from_slice(&id.to_be_bytes());
+  k
+}
+
+/// An append-only history of the operations on each message, written along with the operations themselves.
+#[derive(Default)]
+pub(crate) struct AuditLog {
+  seq: AtomicU32,
+}
+
+impl AuditLog {
+  pub fn put(
+    &self,
+    b: &mut WriteBatchWithTransaction<false>,
+    id: u64,
+    ts_ms: i64,
+    action: AuditAction,
+    poll_tag: Option<u32>,
+    new_poll_tag: Option<u32>,
+  ) {
+    let mut k = audit_key_id_prefix(id);
+    k.extend_from_slice(&ts_ms.max(0).to_be_bytes());
+    k.extend_from_slice(&self.seq.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    let event = AuditEvent {
+      ts_ms,
+      action,
+      client: AUDIT_CLIENT.try_with(|c| c.clone()).ok(),
+      poll_tag,
+      new_poll_tag,
+    };
+    b.put(k, encode_event(&event));
+  }
+}
+
+/// Returns the events of a message, oldest first. Events that can't be decoded, e.g. written by a newer version, are skipped.
+pub(crate) fn read_audit_history(
+  storage: &dyn Storage,
+  id: u64,
+) -> Result<Vec<AuditEvent>, String> {
+  let mut events = Vec::new();
+  storage.scan(&[&audit_key_id_prefix(id)], &mut |k, v| {
+    if k.len() != AUDIT_KEY_LEN {
+      return;
+    };
+    if let Some(e) = decode_event(k.read_i64_be_at(AUDIT_KEY_TS_OFFSET), v) {
+      events.push(e);
+    };
+  })?;
+  Ok(events)
+}
+
+/// Returns a write batch deleting every event from before `before_ms`.
+pub(crate) fn stale_audit_events(
+  storage: &dyn Storage,
+  before_ms: i64,
+) -> Result<WriteBatchWithTransaction<false>, String> {
+  let mut b = WriteBatchWithTransaction::default();
+  storage.scan(&[AUDIT_KEY_PREFIX], &mut |k, _| {
+    if k.len() == AUDIT_KEY_LEN && k.read_i64_be_at(AUDIT_KEY_TS_OFFSET) < before_ms {
+      b.delete(k);
+    };
+  })?;
+  Ok(b)
+}
@@ -0,0 +1,251 @@
+use crate::db::rocksdb_migrate_legacy_keys;
+use crate::db::rocksdb_opts;
+use crate::db::rocksdb_write_opts;
+use crate::db::RocksDbStorage;
+use crate::db::RocksDbTuning;
+use crate::db::FORMAT_VERSION;
+use crate::db::UNRECORDED_FORMAT_VERSION;
+use crate::index_check::verify_index;
+use crate::index_snapshot::IndexState;
+use crate::metrics::Metrics;
+use crate::op::scrub::scan_message_keys;
+use crate::op::scrub::ScrubProblem;
+use crate::storage::Storage;
+use off64::int::create_u64_le;
+use off64::int::Off64ReadInt;
+use rocksdb::IteratorMode;
+use rocksdb::ReadOptions;
+use rocksdb::WriteBatchWithTransaction;
+use rocksdb::DB;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Written to every snapshot of a queue, including incremental snapshots, so that later incremental snapshots can contain only the writes since then.
+pub const SNAPSHOT_META_FILE_NAME: &str = "snapshot_meta";
+
+// Each record is the u32 LE length and then the raw write batch, preceded by the u64 LE sequence number of the snapshot it's relative to.
+const INCREMENT_FILE_NAME: &str = "increment";
+
+// Encoded as the u64 LE sequence number of the latest write in the snapshot, followed by the RocksDB identity of the source database, so that increments are never applied to a snapshot of a different database, e.g. of a queue that has since been deleted and created again with the same name.
+struct SnapshotMeta {
+  seq: u64,
+  identity: Vec<u8>,
+}
+
+impl SnapshotMeta {
+  fn read(dir: &Path) -> Result<Self, String> {
+    let raw = fs::read(dir.join(SNAPSHOT_META_FILE_NAME))
+      .map_err(|err| format!("failed to read snapshot metadata in {dir:?}: {err}"))?;
+    if raw.len() < 8 {
+      return Err(format!("invalid snapshot metadata in {dir:?}"));
+    };
+    Ok(Self {
+      seq: raw.read_u64_le_at(0),
+      identity: raw[8..].to_vec(),
+    })
+  }
+
+  fn write(&self, dir: &Path) -> Result<(), String> {
+    let mut raw = create_u64_le(self.seq).to_vec();
+    raw.extend_from_slice(&self.identity);
+    fs::write(dir.join(SNAPSHOT_META_FILE_NAME), raw).map_err(|err| err.to_string())
+  }
+}
+
+pub(crate) fn read_db_identity(data_dir: &Path) -> Result<Vec<u8>, String> {
+  fs::read(data_dir.join("IDENTITY"))
+    .map_err(|err| format!("failed to read database identity: {err}"))
+}
+
+pub enum SnapshotIncrementError {
+  /// The WAL no longer has all writes since the base snapshot, so a full snapshot is needed.
+  Unavailable,
+  Failed(String),
+}
+
+impl From<String> for SnapshotIncrementError {
+  fn from(err: String) -> Self {
+    Self::Failed(err)
+  }
+}
+
+/// Takes a full snapshot into `dir`, which must not exist.
+pub(crate) fn write_snapshot(
+  storage: &dyn Storage,
+  data_dir: &Path,
+  dir: &Path,
+) -> Result<(), String> {
+  // Read before the checkpoint, so that an increment relative to this snapshot may also have some writes already in it, which is harmless, rather than miss some.
+  let seq = storage.latest_sequence_number();
+  storage.checkpoint(dir)?;
+  if let Some(seq) = seq {
+    SnapshotMeta {
+      seq,
+      identity: read_db_identity(data_dir)?,
+    }
+    .write(dir)?;
+  };
+  Ok(())
+}
+
+/// Writes the writes since the snapshot in `base`, which can itself be incremental, into `dir`, which must not exist.
+pub(crate) fn write_snapshot_increment(
+  storage: &dyn Storage,
+  data_dir: &Path,
+  base: &Path,
+  dir: &Path,
+) -> Result<(), SnapshotIncrementError> {
+  let Some(until) = storage.latest_sequence_number() else {
+    return Err(SnapshotIncrementError::Failed(
+      "incremental snapshots require RocksDB storage".to_string(),
+    ));
+  };
+  let base = SnapshotMeta::read(base)?;
+  let identity = read_db_identity(data_dir)?;
+  if base.identity != identity || base.seq > until {
+    return Err(SnapshotIncrementError::Failed(
+      "base snapshot is of a different database".to_string(),
+    ));
+  };
+  // Writes are only flushed to the WAL periodically, and only flushed writes can be read from it.
+  storage.flush()?;
+
+  fs::create_dir(dir).map_err(|err| err.to_string())?;
+  let file = File::create(dir.join(INCREMENT_FILE_NAME)).map_err(|err| err.to_string())?;
+  let mut out = BufWriter::new(file);
+  out
+    .write_all(&create_u64_le(base.seq))
+    .map_err(|err| err.to_string())?;
+  let complete = storage.write_batches_since(base.seq, until, &mut |b| {
+    out
+      .write_all(&(b.len() as u32).to_le_bytes())
+      .and_then(|_| out.write_all(b))
+      .map(|_| true)
+      .map_err(|err| err.to_string())
+  })?;
+  if !complete {
+    return Err(SnapshotIncrementError::Unavailable);
+  };
+  out
+    .into_inner()
+    .map_err(|err| err.to_string())?
+    .sync_all()
+    .map_err(|err| err.to_string())?;
+  SnapshotMeta {
+    seq: until,
+    identity,
+  }
+  .write(dir)?;
+  Ok(())
+}
+
+/// Whether `dir` is an incremental snapshot of a queue, rather than a full one.
+pub fn is_snapshot_increment(dir: &Path) -> bool {
+  dir.join(INCREMENT_FILE_NAME).exists()
+}
+
+/// Applies the incremental snapshot in `increment_dir` to the data dir of a queue restored from a snapshot, which must have been taken from the same database no later than the increment's base. Increments must be applied in the order they were taken, but each one can be relative to any earlier snapshot in the chain, e.g. always the full one.
+pub fn apply_snapshot_increment(data_dir: &Path, increment_dir: &Path) -> Result<(), String> {
+  let current = SnapshotMeta::read(data_dir)?;
+  let increment = SnapshotMeta::read(increment_dir)?;
+  let raw = fs::read(increment_dir.join(INCREMENT_FILE_NAME)).map_err(|err| err.to_string())?;
+  if raw.len() < 8 {
+    return Err(format!("invalid increment in {increment_dir:?}"));
+  };
+  let since = raw.read_u64_le_at(0);
+  if current.identity != increment.identity {
+    return Err(format!(
+      "increment in {increment_dir:?} is of a different database"
+    ));
+  };
+  // The increment would leave out writes between its base and the data dir, or undo writes already applied.
+  if since > current.seq || increment.seq < current.seq {
+    return Err(format!(
+      "increment in {increment_dir:?} doesn't follow the snapshot in {data_dir:?}"
+    ));
+  };
+
+  let db = DB::open(
+    &rocksdb_opts(None, None, &RocksDbTuning::default()),
+    data_dir,
+  )
+  .map_err(|err| err.to_string())?;
+  let mut pos = 8;
+  while pos < raw.len() {
+    let len = raw
+      .get(pos..pos + 4)
+      .ok_or_else(|| format!("truncated increment in {increment_dir:?}"))?
+      .read_u32_le_at(0) as usize;
+    let data = raw
+      .get(pos + 4..pos + 4 + len)
+      .ok_or_else(|| format!("truncated increment in {increment_dir:?}"))?;
+    db.write_opt(
+      WriteBatchWithTransaction::<false>::from_data(data),
+      &rocksdb_write_opts(),
+    )
+    .map_err(|err| err.to_string())?;
+    pos += 4 + len;
+  }
+  db.flush_wal(true).map_err(|err| err.to_string())?;
+  drop(db);
+  increment.write(data_dir)
+}
+
+/// Checks that the data dir of a queue, e.g. one restored from a snapshot, would load correctly and has no corrupt messages: every record must be readable with a valid checksum, the index must load and match storage, and every message must pass a scrub. Returns how many messages there are. Opening the data dir can change it (e.g. by recovering its WAL), so only use this on a copy.
+pub fn verify_data_dir(data_dir: &Path) -> Result<usize, String> {
+  let db = DB::open(
+    &rocksdb_opts(None, None, &RocksDbTuning::default()),
+    data_dir,
+  )
+  .map_err(|err| err.to_string())?;
+  let format_version = db
+    .get("format_version")
+    .map_err(|err| err.to_string())?
+    .map(|raw| raw.read_u32_le_at(0))
+    .unwrap_or(UNRECORDED_FORMAT_VERSION);
+  if format_version > FORMAT_VERSION {
+    return Err(format!("uses on-disk format version {format_version}, but this release only supports up to {FORMAT_VERSION}"));
+  };
+
+  // Read every record before anything else reads or writes them, so that corruption is reported instead of failing some later step.
+  let mut opts = ReadOptions::default();
+  opts.set_verify_checksums(true);
+  opts.fill_cache(false);
+  for e in db.iterator_opt(IteratorMode::Start, opts) {
+    e.map_err(|err| format!("failed to read record: {err}"))?;
+  }
+
+  rocksdb_migrate_legacy_keys(&db);
+  let storage = RocksDbStorage {
+    db,
+    statistics: None,
+  };
+  let messages = IndexState::scan(&storage, 0)?
+    .into_loaded(Arc::new(Metrics::default()), 1)
+    .messages;
+  verify_index(&storage, &messages)?;
+
+  let mut problems = BTreeMap::<ScrubProblem, (usize, u64)>::new();
+  for (id, keys) in scan_message_keys(&storage)? {
+    match keys.problem() {
+      // These are harmless, and are ignored when loading.
+      None | Some(ScrubProblem::OrphanedKeys) => {}
+      Some(p) => problems.entry(p).or_insert((0, id)).0 += 1,
+    };
+  }
+  if !problems.is_empty() {
+    return Err(
+      problems
+        .into_iter()
+        .map(|(p, (count, id))| format!("{count} messages have problem {p:?}, e.g. {id}"))
+        .collect::<Vec<_>>()
+        .join("; "),
+    );
+  };
+  Ok(messages.len())
+}
@@ -1,7 +1,12 @@
+use crate::replication::ReplicationSequencer;
+use crate::storage::Storage;
+use crate::write_batch::concat_write_batches;
 use off64::int::create_u64_le;
-use rocksdb::DB;
+use rocksdb::WriteBatchWithTransaction;
 use signal_future::SignalFuture;
 use signal_future::SignalFutureController;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::spawn;
@@ -10,46 +15,109 @@ use tokio::sync::mpsc::UnboundedSender;
 use tokio::time::timeout_at;
 use tokio::time::Instant;
 
+#[derive(Clone)]
+pub(crate) struct SyncError {
+  /// Whether the submitted write was applied before failing, in which case it may still become durable.
+  pub applied: bool,
+}
+
+struct Submission {
+  new_next_id_or_zero: u64,
+  write: Option<WriteBatchWithTransaction<false>>,
+  signal: SignalFutureController<Result<(), SyncError>>,
+}
+
 pub(crate) struct BatchSync {
-  sender: UnboundedSender<(u64, SignalFutureController<()>)>,
+  sender: UnboundedSender<Submission>,
+  // Submissions that haven't been signalled yet, including those being written.
+  pending: Arc<AtomicUsize>,
 }
 
 impl BatchSync {
-  pub fn start(batch_sync_delay: Duration, db: Arc<DB>, mut persisted_next_id: u64) -> Self {
-    let (sender, mut receiver) = unbounded_channel::<(u64, SignalFutureController<()>)>();
-    spawn(async move {
-      let mut signals = Vec::new();
-      while let Some((nid, sig)) = receiver.recv().await {
+  /// Submissions are collected for up to `batch_sync_delay` after the first one, which is the most latency this adds to each. Their writes are then applied as a single storage write (group commit), followed by a single flush for all of them. If `replication` is provided, the submitted writes are queued for replication in the same order as other writes.
+  pub fn start(
+    batch_sync_delay: Duration,
+    storage: Arc<dyn Storage>,
+    replication: Option<Arc<ReplicationSequencer>>,
+    mut persisted_next_id: u64,
+  ) -> Self {
+    let (sender, mut receiver) = unbounded_channel::<Submission>();
+    let pending = Arc::new(AtomicUsize::new(0));
+    spawn({
+      let pending = pending.clone();
+      async move {
+        let mut submissions = Vec::new();
+        // This persists across iterations so that a failed write of `next_id` is retried on the next iteration.
         let mut next_id_requires_update = false;
-        if nid > persisted_next_id {
-          persisted_next_id = nid;
-          next_id_requires_update = true;
-        };
-        signals.push(sig);
-        // TODO Tune, allow configuration. The optimal value is the highest number while remaining as close to original (i.e. no `flush_wal()`) performance as possible.
-        let deadline = Instant::now() + batch_sync_delay;
-        while let Ok(Some((nid, sig))) = timeout_at(deadline, receiver.recv()).await {
-          if nid > persisted_next_id {
-            persisted_next_id = nid;
-            next_id_requires_update = true;
+        while let Some(s) = receiver.recv().await {
+          submissions.push(s);
+          // TODO Tune, allow configuration. The optimal value is the highest number while remaining as close to original (i.e. no `flush_wal()`) performance as possible.
+          let deadline = Instant::now() + batch_sync_delay;
+          while let Ok(Some(s)) = timeout_at(deadline, receiver.recv()).await {
+            submissions.push(s);
+          }
+          let mut writes = Vec::new();
+          for s in submissions.iter_mut() {
+            if s.new_next_id_or_zero > persisted_next_id {
+              persisted_next_id = s.new_next_id_or_zero;
+              next_id_requires_update = true;
+            };
+            writes.extend(s.write.take());
+          }
+          // Peers track `next_id` themselves, so it isn't replicated.
+          let replicated = (replication.is_some() && !writes.is_empty())
+            .then(|| concat_write_batches(&writes).data().to_vec());
+          if next_id_requires_update {
+            let mut b = WriteBatchWithTransaction::default();
+            b.put("next_id", create_u64_le(persisted_next_id));
+            writes.push(b);
           };
-          signals.push(sig);
-        }
-        if next_id_requires_update {
-          db.put("next_id", create_u64_le(persisted_next_id)).unwrap();
-        };
-        db.flush_wal(true).unwrap();
-        for sig in signals.drain(..) {
-          sig.signal(());
+          // The writes are applied in submission order, so later writes to the same key win as if they were written separately.
+          let mut res = match (writes.is_empty(), &replication, replicated) {
+            (true, _, _) => Ok(()),
+            (false, Some(replication), Some(data)) => {
+              let b = concat_write_batches(&writes);
+              replication.write(data, || storage.write(b))
+            }
+            (false, _, _) => storage.write(concat_write_batches(&writes)),
+          };
+          let applied = res.is_ok();
+          if applied {
+            next_id_requires_update = false;
+            res = storage.flush();
+          };
+          pending.fetch_sub(submissions.len(), Ordering::Relaxed);
+          for s in submissions.drain(..) {
+            s.signal
+              .signal(res.clone().map_err(|_| SyncError { applied }));
+          }
         }
       }
     });
-    Self { sender }
+    Self { sender, pending }
+  }
+
+  /// How many submissions are waiting to be written and synced. This grows if storage is slower than the rate of writes.
+  pub fn backlog(&self) -> usize {
+    self.pending.load(Ordering::Relaxed)
   }
 
-  pub async fn submit_and_wait(&self, new_next_id_or_zero: u64) {
+  /// If `write` is provided, it's applied together with the writes of other submissions before they're all synced.
+  pub async fn submit_and_wait(
+    &self,
+    new_next_id_or_zero: u64,
+    write: Option<WriteBatchWithTransaction<false>>,
+  ) -> Result<(), SyncError> {
     let (fut, fut_ctl) = SignalFuture::new();
-    self.sender.send((new_next_id_or_zero, fut_ctl)).unwrap();
-    fut.await;
+    self.pending.fetch_add(1, Ordering::Relaxed);
+    self
+      .sender
+      .send(Submission {
+        new_next_id_or_zero,
+        write,
+        signal: fut_ctl,
+      })
+      .unwrap();
+    fut.await
   }
 }
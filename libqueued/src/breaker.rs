@@ -0,0 +1,79 @@
+use parking_lot::Mutex;
+use std::cmp::min;
+use std::time::Duration;
+use std::time::Instant;
+
+struct BreakerState {
+  consecutive_failures: u32,
+  // How many times the breaker has opened since the last success; used to progressively increase the backoff.
+  trips: u32,
+  open_until: Option<Instant>,
+  // How long the breaker is open for this time, which is also how long a probe has to report back before another one is let through.
+  backoff: Duration,
+}
+
+// Guards storage writes and syncs so that a failing device/RocksDB isn't hammered in a tight loop. After `threshold` consecutive failures, the breaker opens and rejects all writes until the backoff elapses, after which a single write is let through as a probe while others are still rejected; if it succeeds, the breaker closes, while a failure reopens it with double the previous backoff (up to `max_backoff`). Reads bypass the breaker entirely, as they often keep working while writes fail, e.g. on a full or read-only disk, so they can't show that writes work again, and must not use up the probe.
+pub(crate) struct StorageBreaker {
+  state: Mutex<BreakerState>,
+  threshold: u32,
+  base_backoff: Duration,
+  max_backoff: Duration,
+}
+
+impl StorageBreaker {
+  pub fn new(threshold: u32, base_backoff: Duration, max_backoff: Duration) -> Self {
+    assert!(threshold > 0);
+    Self {
+      state: Mutex::new(BreakerState {
+        consecutive_failures: 0,
+        trips: 0,
+        open_until: None,
+        backoff: base_backoff,
+      }),
+      threshold,
+      base_backoff,
+      max_backoff,
+    }
+  }
+
+  /// Returns false if the breaker is open and the operation should not be attempted. Once the backoff has elapsed, this returns true for exactly one operation, which must then record its result.
+  pub fn allow(&self) -> bool {
+    let mut state = self.state.lock();
+    let Some(open_until) = state.open_until else {
+      return true;
+    };
+    let now = Instant::now();
+    if now < open_until {
+      return false;
+    };
+    // Keep rejecting everything else until the probe reports back. If it never does, e.g. because it was cancelled, another probe is let through after the same backoff.
+    state.open_until = Some(now + state.backoff);
+    true
+  }
+
+  pub fn is_open(&self) -> bool {
+    self.state.lock().open_until.is_some()
+  }
+
+  /// Must only be called for writes and syncs; see `StorageBreaker`.
+  pub fn record_success(&self) {
+    let mut state = self.state.lock();
+    state.consecutive_failures = 0;
+    state.trips = 0;
+    state.open_until = None;
+  }
+
+  pub fn record_failure(&self) {
+    let mut state = self.state.lock();
+    state.consecutive_failures += 1;
+    if state.consecutive_failures >= self.threshold {
+      let backoff = min(
+        self.max_backoff,
+        self.base_backoff * 2u32.saturating_pow(min(state.trips, 16)),
+      );
+      state.trips += 1;
+      state.backoff = backoff;
+      state.open_until = Some(Instant::now() + backoff);
+    };
+  }
+}
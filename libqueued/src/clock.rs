@@ -0,0 +1,22 @@
+use chrono::Utc;
+
+/// The source of wall clock time for visibility, expiry, and other timestamps. Timestamps are persisted and compared across restarts, so they must stay comparable with those from the system clock, but they can be adjusted, e.g. by a hybrid logical clock shared by replicas, or controlled by tests.
+pub trait Clock: Send + Sync {
+  /// Milliseconds since the epoch.
+  fn now_ms(&self) -> i64;
+
+  /// Seconds since the epoch.
+  fn now(&self) -> i64 {
+    self.now_ms().div_euclid(1000)
+  }
+}
+
+/// The system's wall clock, which can go backwards.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now_ms(&self) -> i64 {
+    Utc::now().timestamp_millis()
+  }
+}
@@ -0,0 +1,592 @@
+use crate::db::ZSTD_TRAINING_BYTES_PER_DICTIONARY_BYTE;
+use off64::int::Off64ReadInt;
+use off64::int::Off64WriteMutInt;
+use parking_lot::Mutex;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_uint;
+use std::sync::Arc;
+
+/// How message contents are compressed before being stored. Compression is decided per message, so a queue can contain both compressed and uncompressed messages, and this can be changed or disabled at any time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ContentsCodec {
+  /// Compresses better, e.g. for JSON.
+  Zstd,
+  /// Compresses and decompresses faster.
+  Lz4,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ContentsCompression {
+  pub codec: ContentsCodec,
+  /// Contents shorter than this are stored as is, as they rarely compress well enough to be worth it. Longer contents are also stored as is if compressing them doesn't make them smaller.
+  pub min_len: usize,
+  /// If nonzero, the codec must be zstd, and a dictionary of up to this many bytes is periodically trained from the queue's recently pushed contents (see `Queued::train_contents_dictionary`) and stored in the queue. New contents are compressed with the newest dictionary, which greatly improves compression of small, similar messages, like JSON with the same fields. Requires on-disk format version 11.
+  pub dictionary_bytes: usize,
+}
+
+// Compressed contents start with the codec and the length of the original contents as a u32 LE, so that lengths can be reported without decompressing, and so that the codec can be changed without affecting existing messages.
+const HEADER_LEN: usize = 5;
+const CODEC_ZSTD: u8 = 1;
+const CODEC_LZ4: u8 = 2;
+const CODEC_ZSTD_DICTIONARY: u8 = 3;
+// Contents compressed with a dictionary have its ID as a u32 LE after the header.
+const DICTIONARY_ID_LEN: usize = 4;
+// Only contents up to this long are sampled for training dictionaries. Dictionaries mostly help small messages, and large samples would crowd out the rest.
+const MAX_DICTIONARY_SAMPLE_LEN: usize = 16 * 1024;
+
+/// A trained dictionary, prepared once for both compression and decompression. zstd allows using prepared dictionaries from multiple threads at once.
+pub(crate) struct ZstdDictionary {
+  id: u32,
+  cdict: *mut zstd_sys::ZSTD_CDict,
+  ddict: *mut zstd_sys::ZSTD_DDict,
+}
+
+unsafe impl Send for ZstdDictionary {}
+unsafe impl Sync for ZstdDictionary {}
+
+impl ZstdDictionary {
+  fn new(id: u32, raw: &[u8]) -> Self {
+    // Both copy the dictionary, so `raw` doesn't need to outlive them.
+    let cdict = unsafe {
+      zstd_sys::ZSTD_createCDict(
+        raw.as_ptr().cast(),
+        raw.len(),
+        zstd_sys::ZSTD_CLEVEL_DEFAULT as c_int,
+      )
+    };
+    let ddict = unsafe { zstd_sys::ZSTD_createDDict(raw.as_ptr().cast(), raw.len()) };
+    assert!(
+      !cdict.is_null() && !ddict.is_null(),
+      "failed to load zstd dictionary"
+    );
+    Self { id, cdict, ddict }
+  }
+}
+
+impl Drop for ZstdDictionary {
+  fn drop(&mut self) {
+    unsafe {
+      zstd_sys::ZSTD_freeCDict(self.cdict);
+      zstd_sys::ZSTD_freeDDict(self.ddict);
+    };
+  }
+}
+
+/// Trains a dictionary of up to `capacity` bytes from samples concatenated in `samples`, whose lengths are `sample_lens`. Fails if there are too few samples, or they have too little in common.
+pub(crate) fn train_dictionary(
+  samples: &[u8],
+  sample_lens: &[usize],
+  capacity: usize,
+) -> Result<Vec<u8>, String> {
+  let mut out = vec![0u8; capacity];
+  let n = unsafe {
+    zstd_sys::ZDICT_trainFromBuffer(
+      out.as_mut_ptr().cast(),
+      capacity,
+      samples.as_ptr().cast(),
+      sample_lens.as_ptr(),
+      sample_lens.len() as c_uint,
+    )
+  };
+  if unsafe { zstd_sys::ZDICT_isError(n) } != 0 {
+    let name = unsafe { CStr::from_ptr(zstd_sys::ZDICT_getErrorName(n)) };
+    return Err(name.to_string_lossy().into_owned());
+  };
+  out.truncate(n);
+  Ok(out)
+}
+
+#[derive(Default)]
+struct DictionarySamples {
+  contents: VecDeque<Vec<u8>>,
+  bytes: usize,
+  // Bytes sampled since the last training, so that a new dictionary is only trained once the samples have been replaced.
+  new_bytes: usize,
+}
+
+/// The dictionaries of a queue's contents (see `ContentsCompression::dictionary_bytes`), and the recently pushed contents to train the next one on.
+pub(crate) struct ContentsDictionaries {
+  dictionary_bytes: usize,
+  // Dictionaries that have been used since the queue was loaded. Others are loaded from storage when needed, e.g. those trained by another node.
+  by_id: RwLock<HashMap<u32, Arc<ZstdDictionary>>>,
+  // The newest dictionary, which new contents are compressed with.
+  current: RwLock<Option<Arc<ZstdDictionary>>>,
+  samples: Mutex<DictionarySamples>,
+  // Held while training, so that concurrent trainings can't pick the same ID.
+  pub training: tokio::sync::Mutex<()>,
+}
+
+impl ContentsDictionaries {
+  /// `latest` is the newest stored dictionary, if any, and its ID.
+  pub fn new(dictionary_bytes: usize, latest: Option<(u32, Vec<u8>)>) -> Self {
+    let out = Self {
+      dictionary_bytes,
+      by_id: RwLock::new(HashMap::new()),
+      current: RwLock::new(None),
+      samples: Mutex::new(DictionarySamples::default()),
+      training: tokio::sync::Mutex::new(()),
+    };
+    if let Some((id, raw)) = latest.filter(|_| dictionary_bytes > 0) {
+      out.insert(id, &raw, true);
+    };
+    out
+  }
+
+  pub fn dictionary_bytes(&self) -> usize {
+    self.dictionary_bytes
+  }
+
+  fn training_bytes(&self) -> usize {
+    self.dictionary_bytes * ZSTD_TRAINING_BYTES_PER_DICTIONARY_BYTE as usize
+  }
+
+  pub fn current(&self) -> Option<Arc<ZstdDictionary>> {
+    self.current.read().clone()
+  }
+
+  pub fn get(&self, id: u32) -> Option<Arc<ZstdDictionary>> {
+    self.by_id.read().get(&id).cloned()
+  }
+
+  /// Adds the stored dictionary `id`. If `current`, new contents are compressed with it from now on.
+  pub fn insert(&self, id: u32, raw: &[u8], current: bool) -> Arc<ZstdDictionary> {
+    let dictionary = self
+      .by_id
+      .write()
+      .entry(id)
+      .or_insert_with(|| Arc::new(ZstdDictionary::new(id, raw)))
+      .clone();
+    if current {
+      *self.current.write() = Some(dictionary.clone());
+    };
+    dictionary
+  }
+
+  /// Keeps `contents` to train the next dictionary on, discarding the oldest samples once there are enough.
+  pub fn sample(&self, contents: &[u8]) {
+    if self.dictionary_bytes == 0 || contents.len() > MAX_DICTIONARY_SAMPLE_LEN {
+      return;
+    };
+    let mut samples = self.samples.lock();
+    samples.contents.push_back(contents.to_vec());
+    samples.bytes += contents.len();
+    samples.new_bytes += contents.len();
+    while samples.bytes > self.training_bytes() {
+      let oldest = samples.contents.pop_front().unwrap();
+      samples.bytes -= oldest.len();
+    }
+  }
+
+  /// Returns the samples to train a new dictionary on, concatenated, and the length of each, if enough contents have been sampled since the last time.
+  pub fn take_training_samples(&self) -> Option<(Vec<u8>, Vec<usize>)> {
+    let mut samples = self.samples.lock();
+    if self.dictionary_bytes == 0 || samples.new_bytes < self.training_bytes() {
+      return None;
+    };
+    samples.new_bytes = 0;
+    Some((
+      samples.contents.iter().flatten().copied().collect(),
+      samples.contents.iter().map(|c| c.len()).collect(),
+    ))
+  }
+}
+
+fn zstd_compress(contents: &[u8]) -> Option<Vec<u8>> {
+  let bound = unsafe { zstd_sys::ZSTD_compressBound(contents.len()) };
+  let mut out = vec![0u8; HEADER_LEN + bound];
+  let n = unsafe {
+    zstd_sys::ZSTD_compress(
+      out[HEADER_LEN..].as_mut_ptr().cast(),
+      bound,
+      contents.as_ptr().cast(),
+      contents.len(),
+      zstd_sys::ZSTD_CLEVEL_DEFAULT as c_int,
+    )
+  };
+  if unsafe { zstd_sys::ZSTD_isError(n) } != 0 {
+    return None;
+  };
+  out.truncate(HEADER_LEN + n);
+  Some(out)
+}
+
+fn zstd_compress_with_dictionary(dictionary: &ZstdDictionary, contents: &[u8]) -> Option<Vec<u8>> {
+  let offset = HEADER_LEN + DICTIONARY_ID_LEN;
+  let bound = unsafe { zstd_sys::ZSTD_compressBound(contents.len()) };
+  let mut out = vec![0u8; offset + bound];
+  let n = unsafe {
+    let cctx = zstd_sys::ZSTD_createCCtx();
+    let n = zstd_sys::ZSTD_compress_usingCDict(
+      cctx,
+      out[offset..].as_mut_ptr().cast(),
+      bound,
+      contents.as_ptr().cast(),
+      contents.len(),
+      dictionary.cdict,
+    );
+    zstd_sys::ZSTD_freeCCtx(cctx);
+    n
+  };
+  if unsafe { zstd_sys::ZSTD_isError(n) } != 0 {
+    return None;
+  };
+  out.truncate(offset + n);
+  out.write_u32_le_at(HEADER_LEN as u64, dictionary.id);
+  Some(out)
+}
+
+fn lz4_compress(contents: &[u8]) -> Option<Vec<u8>> {
+  let len = c_int::try_from(contents.len()).ok()?;
+  let bound = unsafe { lz4_sys::LZ4_compressBound(len) };
+  let mut out = vec![0u8; HEADER_LEN + bound as usize];
+  let n = unsafe {
+    lz4_sys::LZ4_compress_default(
+      contents.as_ptr() as *const c_char,
+      out[HEADER_LEN..].as_mut_ptr() as *mut c_char,
+      len,
+      bound,
+    )
+  };
+  if n <= 0 {
+    return None;
+  };
+  out.truncate(HEADER_LEN + n as usize);
+  Some(out)
+}
+
+/// Returns the contents to store if they should be stored compressed. If the codec is zstd and `dictionary` is provided, they're compressed with it.
+pub(crate) fn compress_contents(
+  cfg: &ContentsCompression,
+  dictionary: Option<&ZstdDictionary>,
+  contents: &[u8],
+) -> Option<Vec<u8>> {
+  if contents.len() < cfg.min_len {
+    return None;
+  };
+  let len = u32::try_from(contents.len()).ok()?;
+  let (codec, mut out) = match (cfg.codec, dictionary) {
+    (ContentsCodec::Zstd, Some(d)) => (
+      CODEC_ZSTD_DICTIONARY,
+      zstd_compress_with_dictionary(d, contents)?,
+    ),
+    (ContentsCodec::Zstd, None) => (CODEC_ZSTD, zstd_compress(contents)?),
+    (ContentsCodec::Lz4, _) => (CODEC_LZ4, lz4_compress(contents)?),
+  };
+  if out.len() >= contents.len() {
+    return None;
+  };
+  out[0] = codec;
+  out.write_u32_le_at(1, len);
+  Some(out)
+}
+
+/// Returns the length of the original contents of `raw`, which must have been returned by `compress_contents`.
+pub(crate) fn decompressed_len(raw: &[u8]) -> Option<usize> {
+  (raw.len() >= HEADER_LEN).then(|| raw.read_u32_le_at(1) as usize)
+}
+
+/// The ID of the dictionary that `raw` was compressed with, if any. `raw` must have been returned by `compress_contents`.
+pub(crate) fn contents_dictionary_id(raw: &[u8]) -> Option<u32> {
+  (raw.len() >= HEADER_LEN + DICTIONARY_ID_LEN && raw[0] == CODEC_ZSTD_DICTIONARY)
+    .then(|| raw.read_u32_le_at(HEADER_LEN as u64))
+}
+
+fn decompress_contents(raw: &[u8], dictionary: Option<&ZstdDictionary>) -> Result<Vec<u8>, String> {
+  let len = decompressed_len(raw).ok_or("compressed contents are truncated")?;
+  let src = &raw[HEADER_LEN..];
+  let mut out = vec![0u8; len];
+  let n = match raw[0] {
+    CODEC_ZSTD => {
+      let n = unsafe {
+        zstd_sys::ZSTD_decompress(out.as_mut_ptr().cast(), len, src.as_ptr().cast(), src.len())
+      };
+      if unsafe { zstd_sys::ZSTD_isError(n) } != 0 {
+        return Err("invalid zstd data".to_string());
+      };
+      n
+    }
+    CODEC_ZSTD_DICTIONARY => {
+      let dictionary = dictionary.ok_or("missing zstd dictionary")?;
+      let src = src
+        .get(DICTIONARY_ID_LEN..)
+        .ok_or("compressed contents are truncated")?;
+      let n = unsafe {
+        let dctx = zstd_sys::ZSTD_createDCtx();
+        let n = zstd_sys::ZSTD_decompress_usingDDict(
+          dctx,
+          out.as_mut_ptr().cast(),
+          len,
+          src.as_ptr().cast(),
+          src.len(),
+          dictionary.ddict,
+        );
+        zstd_sys::ZSTD_freeDCtx(dctx);
+        n
+      };
+      if unsafe { zstd_sys::ZSTD_isError(n) } != 0 {
+        return Err("invalid zstd data".to_string());
+      };
+      n
+    }
+    CODEC_LZ4 => {
+      let n = unsafe {
+        lz4_sys::LZ4_decompress_safe(
+          src.as_ptr() as *const c_char,
+          out.as_mut_ptr() as *mut c_char,
+          c_int::try_from(src.len()).map_err(|_| "lz4 data is too long")?,
+          c_int::try_from(len).map_err(|_| "lz4 contents are too long")?,
+        )
+      };
+      usize::try_from(n).map_err(|_| "invalid lz4 data")?
+    }
+    c => return Err(format!("unknown codec {c}")),
+  };
+  if n != len {
+    return Err(format!("expected {len} bytes but got {n}"));
+  };
+  Ok(out)
+}
+
+/// Returns the original contents of a message from its stored contents. `dictionary` must be the one `contents_dictionary_id` returns, if any. Panics if compressed contents are corrupt, like other undecodable values.
+pub(crate) fn decode_contents(
+  raw: Vec<u8>,
+  compressed: bool,
+  dictionary: Option<&ZstdDictionary>,
+) -> Vec<u8> {
+  match compressed {
+    true => decompress_contents(&raw, dictionary).unwrap(),
+    false => raw,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::compress_contents;
+  use super::contents_dictionary_id;
+  use super::decompress_contents;
+  use super::decompressed_len;
+  use super::train_dictionary;
+  use super::ContentsCodec;
+  use super::ContentsCompression;
+  use super::ContentsDictionaries;
+  use super::ZstdDictionary;
+  use super::MAX_DICTIONARY_SAMPLE_LEN;
+  use crate::op::export::OpExportInput;
+  use crate::op::push::OpPushInput;
+  use crate::op::push::OpPushInputMessage;
+  use crate::Queued;
+  use crate::QueuedCfg;
+  use std::path::PathBuf;
+
+  fn cfg(codec: ContentsCodec) -> ContentsCompression {
+    ContentsCompression {
+      codec,
+      min_len: 16,
+      dictionary_bytes: 0,
+    }
+  }
+
+  // Small JSON messages with the same fields, which is what dictionaries are for.
+  fn event(i: usize) -> Vec<u8> {
+    format!(
+      r#"{{"event":"page_view","user_id":{},"session":"s-{:x}","path":"/products/{}","referrer":"https://example.com/search?q=item{}","ts":{}}}"#,
+      i * 7919 % 100_000,
+      i * 104_729,
+      i % 500,
+      i % 37,
+      1_700_000_000 + i * 13
+    )
+    .into_bytes()
+  }
+
+  fn train(capacity: usize) -> Vec<u8> {
+    let samples = (0..2_000).map(event).collect::<Vec<_>>();
+    let lens = samples.iter().map(|s| s.len()).collect::<Vec<_>>();
+    train_dictionary(&samples.concat(), &lens, capacity).unwrap()
+  }
+
+  #[test]
+  fn contents_round_trip_with_every_codec() {
+    let contents = "hello world! ".repeat(100).into_bytes();
+    for codec in [ContentsCodec::Zstd, ContentsCodec::Lz4] {
+      let raw = compress_contents(&cfg(codec), None, &contents).unwrap();
+      assert!(raw.len() < contents.len());
+      assert_eq!(decompressed_len(&raw), Some(contents.len()));
+      assert_eq!(contents_dictionary_id(&raw), None);
+      assert_eq!(decompress_contents(&raw, None).unwrap(), contents);
+    }
+  }
+
+  #[test]
+  fn only_compresses_when_worth_it() {
+    let cfg = cfg(ContentsCodec::Zstd);
+    assert!(compress_contents(&cfg, None, &[b'a'; 15]).is_none());
+    // Random bytes don't compress.
+    let random = (0..1_000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+    assert!(compress_contents(&cfg, None, &random).is_none());
+  }
+
+  #[test]
+  fn trained_dictionaries_compress_small_contents_better() {
+    let raw = train(4096);
+    assert!(!raw.is_empty() && raw.len() <= 4096);
+    let dictionary = ZstdDictionary::new(7, &raw);
+    let zstd = cfg(ContentsCodec::Zstd);
+    let contents = event(123_456);
+    let with = compress_contents(&zstd, Some(&dictionary), &contents).unwrap();
+    let without = compress_contents(&zstd, None, &contents).map_or(contents.len(), |c| c.len());
+    assert!(with.len() < without, "{} >= {without}", with.len());
+    assert_eq!(contents_dictionary_id(&with), Some(7));
+    assert_eq!(
+      decompress_contents(&with, Some(&dictionary)).unwrap(),
+      contents
+    );
+    // Lz4 never uses the dictionary.
+    let lz4 = compress_contents(
+      &cfg(ContentsCodec::Lz4),
+      Some(&dictionary),
+      &"x".repeat(100).into_bytes(),
+    )
+    .unwrap();
+    assert_eq!(contents_dictionary_id(&lz4), None);
+  }
+
+  #[test]
+  fn training_fails_without_enough_samples() {
+    let samples = event(1);
+    assert!(train_dictionary(&samples, &[samples.len()], 4096).is_err());
+    assert!(train_dictionary(&[], &[], 4096).is_err());
+  }
+
+  #[test]
+  fn rejects_corrupt_contents() {
+    let contents = "hello world! ".repeat(100).into_bytes();
+    let dictionary = ZstdDictionary::new(1, &train(4096));
+    let zstd = compress_contents(&cfg(ContentsCodec::Zstd), None, &contents).unwrap();
+    let lz4 = compress_contents(&cfg(ContentsCodec::Lz4), None, &contents).unwrap();
+    let dict = compress_contents(&cfg(ContentsCodec::Zstd), Some(&dictionary), &event(5)).unwrap();
+
+    assert!(decompress_contents(&zstd[..4], None).is_err());
+    for raw in [&zstd, &lz4, &dict] {
+      // Truncated.
+      assert!(decompress_contents(&raw[..raw.len() - 1], Some(&dictionary)).is_err());
+      // The wrong length.
+      let mut wrong_len = raw.to_vec();
+      wrong_len[1] ^= 1;
+      assert!(decompress_contents(&wrong_len, Some(&dictionary)).is_err());
+    }
+    let mut unknown = zstd.clone();
+    unknown[0] = 9;
+    assert_eq!(
+      decompress_contents(&unknown, None).unwrap_err(),
+      "unknown codec 9"
+    );
+    assert_eq!(
+      decompress_contents(&dict, None).unwrap_err(),
+      "missing zstd dictionary"
+    );
+    assert!(decompress_contents(&dict[..7], Some(&dictionary)).is_err());
+    // A different dictionary.
+    let other = ZstdDictionary::new(2, &train(2048));
+    assert!(decompress_contents(&dict, Some(&other)).is_err());
+  }
+
+  #[test]
+  fn samples_recent_contents_for_training() {
+    let dictionaries = ContentsDictionaries::new(10, None);
+    // 1,000 bytes are needed to train a 10 byte dictionary.
+    for _ in 0..9 {
+      dictionaries.sample(&[1; 100]);
+    }
+    dictionaries.sample(&vec![2; MAX_DICTIONARY_SAMPLE_LEN + 1]);
+    assert!(dictionaries.take_training_samples().is_none());
+    dictionaries.sample(&[3; 100]);
+    dictionaries.sample(&[4; 100]);
+    let (samples, lens) = dictionaries.take_training_samples().unwrap();
+    // The oldest sample was discarded.
+    assert_eq!(lens, vec![100; 10]);
+    assert_eq!(
+      &samples[samples.len() - 200..],
+      &[[3; 100], [4; 100]].concat()[..]
+    );
+    // Not again until as many new bytes have been sampled.
+    dictionaries.sample(&[5; 100]);
+    assert!(dictionaries.take_training_samples().is_none());
+
+    let disabled = ContentsDictionaries::new(0, Some((1, train(1024))));
+    disabled.sample(&[1; 100]);
+    assert!(disabled.take_training_samples().is_none());
+    assert!(disabled.current().is_none());
+  }
+
+  async fn push(q: &Queued, range: std::ops::Range<usize>) {
+    let messages = range
+      .map(|i| OpPushInputMessage {
+        contents: event(i),
+        visibility_timeout_secs: 0,
+        visibility_jitter_secs: 0,
+        priority: 0,
+        attributes: Default::default(),
+        ttl_secs: None,
+        group_id: None,
+        external_id: None,
+      })
+      .collect();
+    q.push(OpPushInput { messages }).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn queues_train_and_keep_their_dictionaries() {
+    let dir = std::env::temp_dir().join(format!(
+      "queued-compression-test-{}-dictionary",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let cfg = || QueuedCfg {
+      contents_compression: Some(ContentsCompression {
+        codec: ContentsCodec::Zstd,
+        min_len: 16,
+        dictionary_bytes: 1024,
+      }),
+      ..Default::default()
+    };
+    let contents = |dir: PathBuf| async move {
+      // The original queue keeps its data dir locked, so read a copy of it.
+      let q = Queued::load_and_start(&dir, cfg()).await;
+      let mut contents = Vec::new();
+      let mut after = None;
+      loop {
+        let out = q
+          .export(OpExportInput { after, limit: 1000 })
+          .await
+          .unwrap();
+        contents.extend(out.messages.into_iter().map(|m| m.contents));
+        after = out.next;
+        if after.is_none() {
+          break contents;
+        };
+      }
+    };
+
+    let q = Queued::load_and_start(&dir.join("queue"), cfg()).await;
+    assert!(!q.train_contents_dictionary().await.unwrap());
+    push(&q, 0..1_000).await;
+    assert!(q.train_contents_dictionary().await.unwrap());
+    let current = q.ctx.contents_dictionaries.current().unwrap();
+    assert_eq!(current.id, 1);
+    // Not enough has been pushed since.
+    assert!(!q.train_contents_dictionary().await.unwrap());
+    push(&q, 1_000..1_010).await;
+
+    q.ctx.storage.checkpoint(&dir.join("copy")).unwrap();
+    assert_eq!(
+      contents(dir.join("copy")).await,
+      (0..1_010).map(event).collect::<Vec<_>>()
+    );
+  }
+}
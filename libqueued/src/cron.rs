@@ -0,0 +1,216 @@
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Duration;
+use chrono::NaiveDate;
+use chrono::Timelike;
+use chrono::Utc;
+
+// How far ahead to look for the next run, so that expressions that can never match (e.g. February 30) are detected instead of searched forever. Every valid expression matches at least once in any 8 years, as leap days occur at least that often.
+const MAX_SEARCH_YEARS: i32 = 8;
+
+/// A standard five-field cron expression (minute, hour, day of month, month, day of week), evaluated in UTC. Each field is `*`, a number, a range like `1-5`, any of these with a step like `*/15`, or a comma-separated list of these. Days of the week are 0 to 7, where both 0 and 7 are Sunday. As with other crons, if both the day of month and day of week are restricted, a day matches if either does. `@hourly`, `@daily`, `@weekly`, `@monthly`, and `@yearly` are also supported.
+#[derive(Clone, Debug)]
+pub struct CronSchedule {
+  minutes: u64,
+  hours: u64,
+  days_of_month: u64,
+  months: u64,
+  days_of_week: u64,
+  days_of_month_restricted: bool,
+  days_of_week_restricted: bool,
+}
+
+// Returns the set of values matched by `field` as a bitmask, and whether it's restricted (i.e. not `*`).
+fn parse_field(field: &str, min: u32, max: u32) -> Option<(u64, bool)> {
+  let mut mask = 0u64;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&s| s > 0)?),
+      None => (part, 1),
+    };
+    let (start, end) = match range {
+      "*" => (min, max),
+      r => match r.split_once('-') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        // Like other crons, `5/15` means from 5 to the maximum in steps of 15.
+        None if step > 1 => (r.parse().ok()?, max),
+        None => {
+          let v = r.parse().ok()?;
+          (v, v)
+        }
+      },
+    };
+    if start < min || end > max || start > end {
+      return None;
+    };
+    for v in (start..=end).step_by(step as usize) {
+      mask |= 1 << v;
+    }
+  }
+  Some((mask, field != "*"))
+}
+
+impl CronSchedule {
+  /// Returns None if the expression is invalid, or can never match.
+  pub fn parse(expr: &str) -> Option<Self> {
+    let expr = match expr.trim() {
+      "@yearly" | "@annually" => "0 0 1 1 *",
+      "@monthly" => "0 0 1 * *",
+      "@weekly" => "0 0 * * 0",
+      "@daily" | "@midnight" => "0 0 * * *",
+      "@hourly" => "0 * * * *",
+      e => e,
+    };
+    let fields = expr.split_whitespace().collect::<Vec<_>>();
+    let &[minutes, hours, days_of_month, months, days_of_week] = fields.as_slice() else {
+      return None;
+    };
+    let (days_of_week, days_of_week_restricted) = parse_field(days_of_week, 0, 7)?;
+    let (days_of_month, days_of_month_restricted) = parse_field(days_of_month, 1, 31)?;
+    let schedule = Self {
+      minutes: parse_field(minutes, 0, 59)?.0,
+      hours: parse_field(hours, 0, 23)?.0,
+      days_of_month,
+      months: parse_field(months, 1, 12)?.0,
+      // Sunday can be 0 or 7.
+      days_of_week: (days_of_week | (days_of_week >> 7)) & 0x7f,
+      days_of_month_restricted,
+      days_of_week_restricted,
+    };
+    schedule.next_after(0)?;
+    Some(schedule)
+  }
+
+  fn matches_day(&self, date: NaiveDate) -> bool {
+    let dom = self.days_of_month & (1 << date.day()) != 0;
+    let dow = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+    match (self.days_of_month_restricted, self.days_of_week_restricted) {
+      (true, true) => dom || dow,
+      _ => dom && dow,
+    }
+  }
+
+  /// The earliest time matching this schedule that's after `ts`, in seconds since the epoch. None if there's no such time within the next few years, which only happens for expressions that can never match.
+  pub fn next_after(&self, ts: i64) -> Option<i64> {
+    let mut t = DateTime::<Utc>::from_timestamp(ts.div_euclid(60) * 60 + 60, 0)?;
+    let until = t.year() + MAX_SEARCH_YEARS;
+    while t.year() <= until {
+      if self.months & (1 << t.month()) == 0 {
+        // Go to the start of the next month.
+        let (y, m) = match t.month() {
+          12 => (t.year() + 1, 1),
+          m => (t.year(), m + 1),
+        };
+        t = NaiveDate::from_ymd_opt(y, m, 1)?
+          .and_hms_opt(0, 0, 0)?
+          .and_utc();
+      } else if !self.matches_day(t.date_naive()) {
+        t = (t.date_naive() + Duration::days(1))
+          .and_hms_opt(0, 0, 0)?
+          .and_utc();
+      } else if self.hours & (1 << t.hour()) == 0 {
+        t = t.with_minute(0)? + Duration::hours(1);
+      } else if self.minutes & (1 << t.minute()) == 0 {
+        t += Duration::minutes(1);
+      } else {
+        return Some(t.timestamp());
+      };
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::CronSchedule;
+  use chrono::DateTime;
+
+  fn ts(rfc3339: &str) -> i64 {
+    DateTime::parse_from_rfc3339(rfc3339).unwrap().timestamp()
+  }
+
+  fn next(expr: &str, after: &str) -> String {
+    let next = CronSchedule::parse(expr)
+      .unwrap()
+      .next_after(ts(after))
+      .unwrap();
+    DateTime::from_timestamp(next, 0).unwrap().to_rfc3339()
+  }
+
+  #[test]
+  fn finds_the_next_matching_minute() {
+    // 2024-01-01 is a Monday.
+    let after = "2024-01-01T10:07:30+00:00";
+    assert_eq!(next("* * * * *", after), "2024-01-01T10:08:00+00:00");
+    // Never the same minute, even if it matches.
+    assert_eq!(
+      next("7 * * * *", "2024-01-01T10:07:00+00:00"),
+      "2024-01-01T11:07:00+00:00"
+    );
+    assert_eq!(next("*/15 * * * *", after), "2024-01-01T10:15:00+00:00");
+    assert_eq!(next("5/20 * * * *", after), "2024-01-01T10:25:00+00:00");
+    assert_eq!(next("0 9-17/4 * * *", after), "2024-01-01T13:00:00+00:00");
+    assert_eq!(next("30 8,9 * * *", after), "2024-01-02T08:30:00+00:00");
+    assert_eq!(next("0 0 29 2 *", after), "2024-02-29T00:00:00+00:00");
+    assert_eq!(
+      next("0 0 1 * *", "2024-12-15T00:00:00+00:00"),
+      "2025-01-01T00:00:00+00:00"
+    );
+  }
+
+  #[test]
+  fn matches_days_of_month_or_week() {
+    let after = "2024-01-01T10:07:30+00:00";
+    // Both 0 and 7 are Sunday.
+    assert_eq!(next("0 0 * * 0", after), "2024-01-07T00:00:00+00:00");
+    assert_eq!(next("0 0 * * 7", after), "2024-01-07T00:00:00+00:00");
+    assert_eq!(next("0 0 * * 3-5", after), "2024-01-03T00:00:00+00:00");
+    // Either restricted field matching is enough.
+    assert_eq!(next("0 0 5 * 2", after), "2024-01-02T00:00:00+00:00");
+    assert_eq!(next("0 0 5 * *", after), "2024-01-05T00:00:00+00:00");
+  }
+
+  #[test]
+  fn expands_shorthands() {
+    let after = "2024-03-15T10:07:30+00:00";
+    for (shorthand, expr) in [
+      ("@yearly", "0 0 1 1 *"),
+      ("@annually", "0 0 1 1 *"),
+      ("@monthly", "0 0 1 * *"),
+      ("@weekly", "0 0 * * 0"),
+      ("@daily", "0 0 * * *"),
+      ("@midnight", "0 0 * * *"),
+      ("@hourly", "0 * * * *"),
+    ] {
+      assert_eq!(next(shorthand, after), next(expr, after), "{shorthand}");
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_expressions() {
+    for expr in [
+      "",
+      "* * * *",
+      "* * * * * *",
+      "@sometimes",
+      "60 * * * *",
+      "* 24 * * *",
+      "* * 0 * *",
+      "* * 32 * *",
+      "* * * 13 *",
+      "* * * * 8",
+      "5-1 * * * *",
+      "*/0 * * * *",
+      "*/x * * * *",
+      "a * * * *",
+      "-1 * * * *",
+      "1,,2 * * * *",
+      "1- * * * *",
+      // Can never match.
+      "0 0 30 2 *",
+      "0 0 31 4,6,9,11 *",
+    ] {
+      assert!(CronSchedule::parse(expr).is_none(), "{expr:?}");
+    }
+  }
+}
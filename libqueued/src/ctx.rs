@@ -1,18 +1,436 @@
+use crate::audit::AuditAction;
+use crate::audit::AuditLog;
 use crate::batch_sync::BatchSync;
-use crate::messages::Messages;
+use crate::breaker::StorageBreaker;
+use crate::clock::Clock;
+use crate::compression::contents_dictionary_id;
+use crate::compression::decode_contents;
+use crate::compression::ContentsCompression;
+use crate::compression::ContentsDictionaries;
+use crate::compression::ZstdDictionary;
+use crate::db::contents_dictionary_key;
+use crate::debug_sampler::DebugSampler;
+use crate::external_id::ExternalIds;
+use crate::fanout::FanoutLog;
+use crate::fault;
+use crate::fault::FaultStage;
+use crate::id_gen::IdStrategy;
+use crate::id_gen::PendingIds;
+use crate::index_check::IndexMismatchAction;
+use crate::index_snapshot::IndexState;
+use crate::lifecycle::check_transition;
+use crate::lifecycle::IllegalTransition;
+use crate::lifecycle::MessageState;
+use crate::lifecycle::MessageTransition;
+use crate::message_shards::MessageShards;
+use crate::messages::PollOrder;
 use crate::metrics::Metrics;
+use crate::offload::ContentsStore;
+use crate::op::poll::RedeliveryBackoff;
+use crate::op::result::OpError;
+use crate::op::result::OpResult;
+use crate::op::schedule::Schedule;
+use crate::push_cap::PushCap;
+use crate::quota::Quota;
+use crate::random::Random;
+use crate::replication::ReplicationSequencer;
+use crate::routing::RoutingRule;
+use crate::storage::Storage;
 use crate::suspend::SuspendState;
 use crate::throttler::Throttler;
+use crate::transform::PollTransform;
+use crate::webhook::WebhookCfg;
+use crate::QueuedCfg;
 use parking_lot::Mutex;
+use rocksdb::WriteBatchWithTransaction;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tracing::debug;
+use tracing::instrument;
 
 pub(crate) struct Ctx {
+  pub audit_log: Option<AuditLog>,
+  pub auto_visibility_timeout: AtomicBool,
   pub batch_sync: BatchSync,
-  pub db: Arc<rocksdb::DB>,
-  pub messages: Mutex<Messages>,
+  pub breaker: StorageBreaker,
+  // Operations that may have temporarily removed messages from `messages`, which are then neither visible nor in flight. This must be read while holding every shard of `messages` to be consistent with it.
+  pub busy_ops: AtomicUsize,
+  pub clock: Arc<dyn Clock>,
+  pub contents_compression: Option<ContentsCompression>,
+  pub contents_dictionaries: ContentsDictionaries,
+  pub contents_store: Option<Arc<dyn ContentsStore>>,
+  pub data_dir: PathBuf,
+  pub debug_sampler: Mutex<Option<DebugSampler>>,
+  pub default_ttl_secs: Mutex<Option<u32>>,
+  pub external_ids: ExternalIds,
+  pub fanout_log: Option<Arc<FanoutLog>>,
+  pub format_version: u32,
+  pub id_strategy: IdStrategy,
+  // Dropping this stops writing index snapshots.
+  pub _index_snapshots: Option<oneshot::Sender<()>>,
+  pub inline_max_contents_len: usize,
+  pub known_schema_versions: Mutex<HashSet<u32>>,
+  pub last_push_ms: Mutex<Option<i64>>,
+  // Snapshots and scrubs currently running.
+  pub maintenance_ops: AtomicUsize,
+  pub messages: MessageShards,
   pub metrics: Arc<Metrics>,
+  // Only tracks sequential and Snowflake IDs; see `RANDOM_ID_MIN`.
   pub next_id: AtomicU64,
+  pub pending_ids: PendingIds,
+  pub poll_order: PollOrder,
+  pub poll_transform: Mutex<Option<PollTransform>>,
+  pub push_cap: Option<Mutex<PushCap>>,
+  pub quota: Quota,
+  pub random: Arc<dyn Random>,
+  pub recommended_visibility_timeout_factor: f64,
+  pub offload_min_contents_len: usize,
+  pub reloadable: Mutex<ReloadableSettings>,
+  // The index as of the latest batch applied by `Queued::apply_replica_batches`, which is only used on read-only replicas.
+  pub replica_index: Mutex<Option<IndexState>>,
+  pub replication: Option<Arc<ReplicationSequencer>>,
+  pub routing_rules: Mutex<Vec<RoutingRule>>,
+  // Writes to schedules, including runs, are serialised so that a schedule isn't run while it's being replaced or removed.
+  pub schedule_ops: tokio::sync::Mutex<()>,
+  pub schedules: Mutex<BTreeMap<String, Schedule>>,
+  pub schema_registration: tokio::sync::Mutex<()>,
+  pub storage: Arc<dyn Storage>,
   pub suspension: Arc<SuspendState>,
+  pub suspension_ops: tokio::sync::Mutex<()>,
   pub throttler: Mutex<Option<Throttler>>,
+  // Dropping this stops refreshing storage usage.
+  pub _usage_refresh: oneshot::Sender<()>,
+  pub verify_index: Option<IndexMismatchAction>,
+  pub webhook: Mutex<Option<WebhookCfg>>,
+}
+
+/// Settings that `Queued::reload_cfg` can change while the queue is open. See `QueuedCfg` for what each does.
+#[derive(Clone, Copy)]
+pub(crate) struct ReloadableSettings {
+  pub max_message_size: Option<usize>,
+  pub max_visibility_timeout_secs: Option<i64>,
+  pub min_visibility_timeout_secs: i64,
+  pub redelivery_backoff: Option<RedeliveryBackoff>,
+  pub release_pacing_max_per_sec: Option<u32>,
+}
+
+impl From<&QueuedCfg> for ReloadableSettings {
+  fn from(cfg: &QueuedCfg) -> Self {
+    Self {
+      max_message_size: cfg.max_message_size,
+      max_visibility_timeout_secs: cfg.max_visibility_timeout_secs,
+      min_visibility_timeout_secs: cfg.min_visibility_timeout_secs,
+      redelivery_backoff: cfg.redelivery_backoff,
+      release_pacing_max_per_sec: cfg.release_pacing_max_per_sec,
+    }
+  }
+}
+
+/// Returned by `Ctx::db_commit` if the write may not be durable.
+pub(crate) struct CommitError {
+  pub err: OpError,
+  /// Whether the write was applied to storage, in which case in-memory state must reflect it, as it may still become durable. Otherwise, any in-memory changes should be undone.
+  pub applied: bool,
+}
+
+/// Counts an operation in `Ctx::busy_ops` or `Ctx::maintenance_ops` until dropped.
+pub(crate) struct BusyOpGuard<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for BusyOpGuard<'a> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+impl Ctx {
+  /// Checks the visibility timeout of a poll, update, or takeover, i.e. how long the message is leased for.
+  pub fn check_visibility_timeout(&self, secs: i64) -> OpResult<()> {
+    if secs < self.reloadable.lock().min_visibility_timeout_secs.max(0) {
+      return Err(OpError::InvalidVisibilityTimeout);
+    };
+    self.check_visibility_delay(secs)
+  }
+
+  /// See `Queued::recommended_visibility_timeout_secs`. It's within the configured bounds, and at least a second, as processing times are only measured in seconds.
+  pub fn recommended_visibility_timeout_secs(&self) -> Option<i64> {
+    let p99 = self.metrics.processing_times.percentile_secs(0.99)?;
+    let settings = *self.reloadable.lock();
+    let secs = (p99 as f64 * self.recommended_visibility_timeout_factor).ceil() as i64;
+    let secs = secs.max(settings.min_visibility_timeout_secs).max(1);
+    Some(
+      settings
+        .max_visibility_timeout_secs
+        .map_or(secs, |max| secs.min(max)),
+    )
+  }
+
+  /// Adds an event for message `id` to `b` if the audit log is enabled.
+  pub fn audit(
+    &self,
+    b: &mut WriteBatchWithTransaction<false>,
+    id: u64,
+    action: AuditAction,
+    poll_tag: Option<u32>,
+    new_poll_tag: Option<u32>,
+  ) {
+    let Some(log) = &self.audit_log else {
+      return;
+    };
+    log.put(b, id, self.clock.now_ms(), action, poll_tag, new_poll_tag);
+    self
+      .metrics
+      .audit_event_counter
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// A poll tag for a message being polled or having its visibility changed, replacing `old`. Tags are random rather than sequential, so that a client can't guess the tag of a message someone else has polled and delete or update it. Zero is never used, as it's the tag of a message that has never been polled.
+  pub(crate) fn new_poll_tag(&self, old: u32) -> u32 {
+    loop {
+      let tag = self.random.next_u64() as u32;
+      if tag != 0 && tag != old {
+        return tag;
+      };
+    }
+  }
+
+  /// The visibility timeout to use for a poll that requested `secs`.
+  pub fn poll_visibility_timeout(&self, secs: i64) -> i64 {
+    if !self.auto_visibility_timeout.load(Ordering::Relaxed) {
+      return secs;
+    };
+    self.recommended_visibility_timeout_secs().unwrap_or(secs)
+  }
+
+  /// Checks how long a pushed or nacked message is delayed before becoming visible. Only the maximum applies, as these are usually zero.
+  pub fn check_visibility_delay(&self, secs: i64) -> OpResult<()> {
+    if self
+      .reloadable
+      .lock()
+      .max_visibility_timeout_secs
+      .is_some_and(|max| secs > max)
+    {
+      return Err(OpError::InvalidVisibilityTimeout);
+    };
+    Ok(())
+  }
+
+  pub fn begin_busy_op(&self) -> BusyOpGuard<'_> {
+    self.busy_ops.fetch_add(1, Ordering::Relaxed);
+    BusyOpGuard(&self.busy_ops)
+  }
+
+  pub fn begin_maintenance(&self) -> BusyOpGuard<'_> {
+    self.maintenance_ops.fetch_add(1, Ordering::Relaxed);
+    BusyOpGuard(&self.maintenance_ops)
+  }
+
+  pub fn is_maintenance_running(&self) -> bool {
+    self.maintenance_ops.load(Ordering::Relaxed) > 0 || self.storage.is_compacting()
+  }
+
+  /// Validates applying `t` to message `id`, which is currently in state `from`, counting it if it's illegal.
+  pub fn check_transition(
+    &self,
+    id: u64,
+    t: MessageTransition,
+    from: MessageState,
+    poll_tag_matches: bool,
+  ) -> Result<(), IllegalTransition> {
+    check_transition(t, from, poll_tag_matches).inspect_err(|&err| {
+      self.metrics.transitions.record_illegal(err);
+      debug!(id, error = err.to_string(), "illegal message transition");
+    })
+  }
+
+  pub fn check_storage_available(&self) -> OpResult<()> {
+    if !self.breaker.allow() {
+      self
+        .metrics
+        .storage_breaker_rejected_counter
+        .fetch_add(1, Ordering::Relaxed);
+      return Err(OpError::StorageUnavailable);
+    };
+    Ok(())
+  }
+
+  /// Records the result of a storage write or sync, which can close the breaker.
+  pub fn record_storage_result<T, E>(&self, res: Result<T, E>) -> OpResult<T> {
+    match res {
+      Ok(v) => {
+        self.breaker.record_success();
+        Ok(v)
+      }
+      Err(_) => Err(self.storage_failed()),
+    }
+  }
+
+  /// Records the result of a storage read. Reads bypass the breaker (see `StorageBreaker`), so they don't check it first, and their failures are only counted.
+  pub fn record_storage_read_result<T, E>(&self, res: Result<T, E>) -> OpResult<T> {
+    res.map_err(|_| {
+      self.count_storage_error();
+      OpError::StorageUnavailable
+    })
+  }
+
+  fn count_storage_error(&self) {
+    self
+      .metrics
+      .storage_error_counter
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn storage_failed(&self) -> OpError {
+    self.count_storage_error();
+    self.breaker.record_failure();
+    OpError::StorageUnavailable
+  }
+
+  // Counted like a real storage failure, so that the breaker and metrics behave the same.
+  fn injected_sync_failure(&self) -> OpError {
+    self.storage_failed()
+  }
+
+  #[instrument(name = "rocksdb_write", skip_all)]
+  pub async fn db_write_local(&self, b: WriteBatchWithTransaction<false>) -> OpResult<()> {
+    self.check_storage_available()?;
+    let storage = self.storage.clone();
+    let res = spawn_blocking(move || storage.write(b)).await.unwrap();
+    self.record_storage_result(res)
+  }
+
+  /// Applies the write locally and queues it for replication, which `db_sync` waits for. If this fails, the write wasn't applied. Otherwise, in-memory state must reflect it, even if the following `db_sync` fails.
+  pub async fn db_write(&self, b: WriteBatchWithTransaction<false>) -> OpResult<()> {
+    let Some(replication) = &self.replication else {
+      return self.db_write_local(b).await;
+    };
+    self.check_storage_available()?;
+    let storage = self.storage.clone();
+    let replication = replication.clone();
+    let data = b.data().to_vec();
+    let res = spawn_blocking(move || replication.write(data, || storage.write(b)))
+      .await
+      .unwrap();
+    self.record_storage_result(res)
+  }
+
+  pub async fn db_get(&self, key: impl AsRef<[u8]> + Send + 'static) -> OpResult<Option<Vec<u8>>> {
+    let storage = self.storage.clone();
+    let res = spawn_blocking(move || storage.get(key.as_ref()))
+      .await
+      .unwrap();
+    self.record_storage_read_result(res)
+  }
+
+  /// Returns all keys starting with `prefix` and their values, in key order.
+  pub async fn db_scan_prefix(
+    &self,
+    prefix: &'static [u8],
+  ) -> OpResult<Vec<(Box<[u8]>, Box<[u8]>)>> {
+    let storage = self.storage.clone();
+    let res = spawn_blocking(move || {
+      let mut entries = Vec::new();
+      storage
+        .scan(&[prefix], &mut |k, v| entries.push((k.into(), v.into())))
+        .map(|_| entries)
+    })
+    .await
+    .unwrap();
+    self.record_storage_read_result(res)
+  }
+
+  /// The dictionary that contents were compressed with, which is loaded from storage if it hasn't been used yet, e.g. because another node trained it.
+  pub async fn contents_dictionary(&self, id: u32) -> OpResult<Arc<ZstdDictionary>> {
+    if let Some(dictionary) = self.contents_dictionaries.get(id) {
+      return Ok(dictionary);
+    };
+    let raw = self
+      .db_get(contents_dictionary_key(id))
+      .await?
+      .expect("missing contents dictionary");
+    Ok(self.contents_dictionaries.insert(id, &raw, false))
+  }
+
+  /// Returns the original contents of a message from its stored contents.
+  pub async fn decode_contents(&self, raw: Vec<u8>, compressed: bool) -> OpResult<Vec<u8>> {
+    let dictionary = match contents_dictionary_id(&raw).filter(|_| compressed) {
+      Some(id) => Some(self.contents_dictionary(id).await?),
+      None => None,
+    };
+    Ok(decode_contents(raw, compressed, dictionary.as_deref()))
+  }
+
+  /// Waits for writes so far to become durable, including being replicated.
+  pub async fn db_sync(&self, new_next_id_or_zero: u64) -> OpResult<()> {
+    self.db_sync_local(new_next_id_or_zero).await?;
+    self.wait_replicated().await
+  }
+
+  async fn wait_replicated(&self) -> OpResult<()> {
+    let Some(replication) = &self.replication else {
+      return Ok(());
+    };
+    if replication.wait().await.is_err() {
+      self
+        .metrics
+        .replication_error_counter
+        .fetch_add(1, Ordering::Relaxed);
+      return Err(OpError::ReplicationFailed);
+    };
+    Ok(())
+  }
+
+  /// Waits for writes so far to become durable locally, e.g. for writes received from another node that mustn't wait for our own writes to be replicated.
+  #[instrument(name = "batch_sync_wait", skip_all)]
+  pub async fn db_sync_local(&self, new_next_id_or_zero: u64) -> OpResult<()> {
+    if fault::inject(FaultStage::BeforeCommit) {
+      return Err(self.injected_sync_failure());
+    };
+    let res = self
+      .batch_sync
+      .submit_and_wait(new_next_id_or_zero, None)
+      .await;
+    self.record_storage_result(res)?;
+    if fault::inject(FaultStage::AfterCommit) {
+      return Err(self.injected_sync_failure());
+    };
+    Ok(())
+  }
+
+  /// Like `db_write` followed by `db_sync`, but the write is combined with those of other concurrent commits into a single storage write just before they're all synced, so concurrent operations don't each need their own write.
+  #[instrument(name = "group_commit_wait", skip_all)]
+  pub async fn db_commit(&self, b: WriteBatchWithTransaction<false>) -> Result<(), CommitError> {
+    self.check_storage_available().map_err(|err| CommitError {
+      err,
+      applied: false,
+    })?;
+    if fault::inject(FaultStage::BeforeCommit) {
+      return Err(CommitError {
+        err: self.injected_sync_failure(),
+        applied: false,
+      });
+    };
+    let res = self.batch_sync.submit_and_wait(0, Some(b)).await;
+    let applied = res.as_ref().map_or_else(|e| e.applied, |_| true);
+    self
+      .record_storage_result(res)
+      .map_err(|err| CommitError { err, applied })?;
+    if fault::inject(FaultStage::AfterCommit) {
+      return Err(CommitError {
+        err: self.injected_sync_failure(),
+        applied: true,
+      });
+    };
+    self
+      .wait_replicated()
+      .await
+      .map_err(|err| CommitError { err, applied: true })
+  }
 }
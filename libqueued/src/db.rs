@@ -1,51 +1,314 @@
-use crate::messages::Messages;
-use crate::metrics::Metrics;
+use crate::message_shards::MessageShards;
+use crate::storage::Storage;
+use crate::storage::StorageStats;
 use num_derive::FromPrimitive;
+use off64::int::create_u32_le;
 use off64::int::Off64ReadInt;
 use off64::int::Off64WriteMutInt;
+use rocksdb::checkpoint::Checkpoint;
 use rocksdb::BlockBasedOptions;
+use rocksdb::BottommostLevelCompaction;
 use rocksdb::Cache;
+use rocksdb::CompactOptions;
+use rocksdb::DBCompactionStyle;
 use rocksdb::Direction;
 use rocksdb::IteratorMode;
+use rocksdb::WriteBatchWithTransaction;
 use rocksdb::WriteOptions;
 use rocksdb::DB;
 use std::path::Path;
-use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, FromPrimitive)]
 #[repr(u8)]
+#[allow(clippy::enum_variant_names)]
+// Message keys use big-endian IDs so that keys are ordered by ID, which allows deleting contiguous ID ranges with a single range tombstone. Keys with the legacy little-endian encoding used `prefix - LEGACY_LE_KEY_PREFIX_OFFSET` as the prefix, and are migrated on open.
 pub(crate) enum RocksDbKeyPrefix {
-  MessagePollTag = 1, // Only exists for messages that have been polled at least once.
-  MessageVisibleTimestampSec = 2,
-  MessageData = 3,
+  MessagePollTag = 0x11, // Only exists for messages that have been polled at least once.
+  MessageVisibleTimestampSec = 0x12,
+  MessageData = 0x13,
+  MessagePinned = 0x14,    // Only exists for messages that are currently pinned.
+  MessagePriority = 0x15,  // Only exists for messages with a non-zero priority.
+  MessageInline = 0x16, // Visible timestamp, poll tag, and contents of small messages. Messages with this key do not have the MessagePollTag, MessageVisibleTimestampSec, or MessageData keys.
+  MessageOffloaded = 0x17, // Only exists for messages whose contents are in the `ContentsStore`, in which case the MessageData key does not exist.
+  MessagePollCount = 0x18, // Only exists for messages that have been polled at least once. Unlike the poll tag, this is not incremented by updates.
+  MessageAttributes = 0x19, // Only exists for messages with at least one attribute.
+  MessageExpiry = 0x1a,    // Only exists for messages with a TTL.
+  MessagePushedAt = 0x1b, // Unix timestamp in milliseconds. Only exists for messages pushed using format version 6 or newer.
+  MessageGroup = 0x1c,    // The UTF-8 group ID. Only exists for messages in a group.
+  MessageExternalId = 0x1d, // The UTF-8 external ID. Only exists for messages pushed with one.
+  MessageCompressed = 0x1e, // Only exists for messages whose contents are compressed (see `compress_contents`), wherever they're stored.
 }
 
+impl RocksDbKeyPrefix {
+  /// The range of all message keys, from the first prefix (inclusive) to past the last (exclusive).
+  pub const MESSAGE_KEY_RANGE: ([u8; 1], [u8; 1]) = ([RocksDbKeyPrefix::MessagePollTag as u8], [
+    RocksDbKeyPrefix::MessageCompressed as u8 + 1,
+  ]);
+  pub const MESSAGE_PREFIXES: [RocksDbKeyPrefix; 14] = [
+    RocksDbKeyPrefix::MessagePollTag,
+    RocksDbKeyPrefix::MessageVisibleTimestampSec,
+    RocksDbKeyPrefix::MessageData,
+    RocksDbKeyPrefix::MessagePinned,
+    RocksDbKeyPrefix::MessagePriority,
+    RocksDbKeyPrefix::MessageInline,
+    RocksDbKeyPrefix::MessageOffloaded,
+    RocksDbKeyPrefix::MessagePollCount,
+    RocksDbKeyPrefix::MessageAttributes,
+    RocksDbKeyPrefix::MessageExpiry,
+    RocksDbKeyPrefix::MessagePushedAt,
+    RocksDbKeyPrefix::MessageGroup,
+    RocksDbKeyPrefix::MessageExternalId,
+    RocksDbKeyPrefix::MessageCompressed,
+  ];
+}
+
+const LEGACY_LE_KEY_PREFIX_OFFSET: u8 = 0x10;
+
+/// The newest on-disk format this release can read and write. Older releases ignore keys they don't know about, so each version adds keys that would be unsafe to ignore:
+/// - 1: big-endian message keys. Releases before this can't read any messages written since.
+/// - 2: offloaded contents (`MessageOffloaded`). Older releases would return these messages with empty contents.
+/// - 3: poll counts (`MessagePollCount`). Older releases would never delete these keys, and would reset poll counts.
+/// - 4: attributes (`MessageAttributes`). Older releases would return messages without their attributes.
+/// - 5: expiry times (`MessageExpiry`). Older releases would never expire these messages nor delete these keys.
+/// - 6: push times (`MessagePushedAt`). Older releases would never delete these keys.
+/// - 7: message groups (`MessageGroup`). Older releases would deliver grouped messages out of order.
+/// - 8: external IDs (`MessageExternalId`). Older releases would never delete these keys, and would allow duplicate external IDs.
+/// - 9: random message IDs (`IdStrategy::Random`). Older releases would advance `next_id` past them, and eventually wrap around to IDs already in use.
+/// - 10: compressed contents (`MessageCompressed`). Older releases would return these messages with their compressed contents.
+/// - 11: contents compressed with a trained dictionary (`CONTENTS_DICTIONARY_KEY_PREFIX`). Older releases would fail to decompress them.
+/// - 12: prepared multi-queue pushes (`FANOUT_PUSH_KEY_PREFIX`). Older releases would never apply committed ones, losing their messages.
+pub const FORMAT_VERSION: u32 = 12;
+/// The oldest on-disk format this release can write, for rolling back to an older release.
+pub const MIN_FORMAT_VERSION: u32 = 1;
+// Data dirs written before the format version was recorded use this version (after migrating legacy keys).
+pub(crate) const UNRECORDED_FORMAT_VERSION: u32 = 1;
+
 pub(crate) fn rocksdb_key(p: RocksDbKeyPrefix, id: u64) -> [u8; 9] {
   let mut out = [0u8; 9];
   out[0] = p as u8;
-  out.write_u64_le_at(1, id);
+  out.write_u64_be_at(1, id);
+  out
+}
+
+pub(crate) fn rocksdb_key_id(k: &[u8]) -> u64 {
+  k.read_u64_be_at(1)
+}
+
+// Schemas aren't messages, so use a textual prefix like our other non-message keys. These keys are never 9 bytes long, so can't be confused with message keys.
+pub(crate) const SCHEMA_KEY_PREFIX: &[u8] = b"schema/";
+
+pub(crate) fn schema_key(version: u32) -> Vec<u8> {
+  let mut out = SCHEMA_KEY_PREFIX.to_vec();
+  out.extend_from_slice(&version.to_be_bytes());
+  out
+}
+
+pub(crate) fn schema_key_version(k: &[u8]) -> u32 {
+  k.read_u32_be_at(SCHEMA_KEY_PREFIX.len() as u64)
+}
+
+// Schedules are keyed by this prefix followed by their UTF-8 name. Older releases ignore these keys, so they just don't run schedules.
+pub(crate) const SCHEDULE_KEY_PREFIX: &[u8] = b"schedule/";
+
+pub(crate) fn schedule_key(name: &str) -> Vec<u8> {
+  let mut out = SCHEDULE_KEY_PREFIX.to_vec();
+  out.extend_from_slice(name.as_bytes());
+  out
+}
+
+// Keys of corrupt messages are moved here by scrubbing, as this prefix followed by the original key, so that they no longer affect the queue but can still be inspected and recovered manually.
+pub(crate) const QUARANTINE_KEY_PREFIX: &[u8] = b"quarantine/";
+
+pub(crate) fn quarantine_key(k: &[u8]) -> Vec<u8> {
+  let mut out = QUARANTINE_KEY_PREFIX.to_vec();
+  out.extend_from_slice(k);
+  out
+}
+
+// Dictionaries that contents may be compressed with (see `ContentsCompression::dictionary_bytes`) are keyed by this prefix followed by their u32 BE ID, so the newest is last. They're never deleted, as it isn't known when no message uses one anymore.
+pub(crate) const CONTENTS_DICTIONARY_KEY_PREFIX: &[u8] = b"contents_dictionary/";
+
+pub(crate) fn contents_dictionary_key(id: u32) -> Vec<u8> {
+  let mut out = CONTENTS_DICTIONARY_KEY_PREFIX.to_vec();
+  out.extend_from_slice(&id.to_be_bytes());
+  out
+}
+
+/// Returns the newest stored contents dictionary and its ID.
+pub(crate) fn load_latest_contents_dictionary(
+  storage: &dyn Storage,
+) -> Result<Option<(u32, Vec<u8>)>, String> {
+  let mut latest = None;
+  storage.scan(&[CONTENTS_DICTIONARY_KEY_PREFIX], &mut |k, v| {
+    let id = k.read_u32_be_at(CONTENTS_DICTIONARY_KEY_PREFIX.len() as u64);
+    latest = Some((id, v.to_vec()));
+  })?;
+  Ok(latest)
+}
+
+// Multi-queue pushes that have been prepared but not yet applied to this queue (see `FanoutLog`) are keyed by this prefix followed by the u64 BE ID of the push and the u32 BE position of this queue in it, with the push's raw write batch as the value.
+pub(crate) const FANOUT_PUSH_KEY_PREFIX: &[u8] = b"fanout_push/";
+
+pub(crate) fn fanout_push_key(id: u64, position: u32) -> Vec<u8> {
+  let mut out = FANOUT_PUSH_KEY_PREFIX.to_vec();
+  out.extend_from_slice(&id.to_be_bytes());
+  out.extend_from_slice(&position.to_be_bytes());
   out
 }
 
+pub(crate) fn fanout_push_key_id(k: &[u8]) -> u64 {
+  k.read_u64_be_at(FANOUT_PUSH_KEY_PREFIX.len() as u64)
+}
+
+// The queue's default TTL for pushed messages that don't have their own, as a u32 LE number of seconds. This is replicated like messages, so followers that become leader use the same default.
+pub(crate) const DEFAULT_TTL_KEY: &[u8] = b"default_ttl_secs";
+
+pub(crate) fn load_default_ttl(storage: &dyn Storage) -> Option<u32> {
+  storage
+    .get(DEFAULT_TTL_KEY)
+    .unwrap()
+    .map(|raw| raw.read_u32_le_at(0))
+}
+
+// The queue's suspended operations, as a single byte bitmask (see `SuspendState::bits`). Like the default TTL, this is replicated, so followers that become leader have the same suspension.
+pub(crate) const SUSPENSION_KEY: &[u8] = b"suspension";
+
+pub(crate) fn load_suspension(storage: &dyn Storage) -> u8 {
+  storage.get(SUSPENSION_KEY).unwrap().map_or(0, |raw| raw[0])
+}
+
+// When a push was last accepted, as an i64 LE number of milliseconds since the epoch. This is written with every push, so that quiescence can be determined across restarts.
+pub(crate) const LAST_PUSH_KEY: &[u8] = b"last_push_ms";
+
+pub(crate) fn load_last_push_ms(storage: &dyn Storage) -> Option<i64> {
+  storage
+    .get(LAST_PUSH_KEY)
+    .unwrap()
+    .map(|raw| raw.read_i64_le_at(0))
+}
+
 // There's no need to optimise for point lookups as our keys are always sequential 8-byte integers with (almost) no skips inserted in order, and our workload is write heavy with almost 1 write for every read.
 // - (Almost) every key exists, so adding bloom filters, hash indices, or in-memory structures only consumes more memory and index space and slows down inserts without much gain in total system performance.
 // - These options generally require careful tuning and come with sensitive tradeoffs.
 // - We still need to be able to scan the entire database initially, so using a prefix extractor isn't applicable; a prefix extractor also wouldn't work well given our key distribution (we insert sequential IDs, so the prefix will be very unbalanced until literally the entire keyspace is used i.e. we run out of IDs).
 // TODO Consider using separate column family for MessageData with blob files enabled.
-fn rocksdb_opts() -> rocksdb::Options {
+/// Compresses data on disk using zstd.
+#[derive(Clone, Copy, Debug)]
+pub struct ZstdCompression {
+  /// From 1 (fastest) to 22 (smallest).
+  pub level: i32,
+  /// If nonzero, a dictionary of up to this many bytes is trained from a sample of the data in each file written by a flush or compaction, and stored in that file. This greatly improves compression of small, similar messages (e.g. JSON with the same fields), which have too little data each to compress well on their own. Dictionaries are retrained as data is compacted, so they follow changes in message contents.
+  pub dictionary_bytes: u32,
+}
+
+/// How RocksDB merges files in the background. See https://github.com/facebook/rocksdb/wiki/Compaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStyle {
+  /// The default. Uses the least disk space, at the cost of more write amplification.
+  Level,
+  /// Less write amplification, but may temporarily need up to double the disk space.
+  Universal,
+  /// Deletes the oldest files once the database is too large, without merging. Only suitable for queues whose messages are always deleted long before then.
+  Fifo,
+}
+
+/// A compression algorithm for RocksDB's files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileCompression {
+  None,
+  Snappy,
+  Lz4,
+  Zstd,
+}
+
+impl FileCompression {
+  fn rocksdb_type(self) -> rocksdb::DBCompressionType {
+    match self {
+      FileCompression::None => rocksdb::DBCompressionType::None,
+      FileCompression::Snappy => rocksdb::DBCompressionType::Snappy,
+      FileCompression::Lz4 => rocksdb::DBCompressionType::Lz4,
+      FileCompression::Zstd => rocksdb::DBCompressionType::Zstd,
+    }
+  }
+}
+
+/// Overrides for RocksDB options. Unset options use defaults suited to a write-heavy workload on a dedicated machine. Each queue has its own database, so sizes apply to each queue separately.
+#[derive(Clone, Debug, Default)]
+pub struct RocksDbTuning {
+  /// The size of the cache of uncompressed blocks read from files. Defaults to 1 GiB.
+  pub block_cache_bytes: Option<usize>,
+  /// How much is written to memory before being flushed to a file. Defaults to 1 GiB.
+  pub write_buffer_bytes: Option<usize>,
+  pub compaction_style: Option<CompactionStyle>,
+  /// Once WAL files take up more than this, memtables are flushed so that the oldest can be deleted. Defaults to 4 times the write buffer size. WAL files kept for index or incremental snapshots aren't affected.
+  pub max_total_wal_bytes: Option<u64>,
+  /// If set, WAL files are synced to disk in the background every this many bytes, which smooths out I/O from the periodic syncs of the WAL.
+  pub wal_bytes_per_sync: Option<u64>,
+  /// The compression algorithm of each level, starting from L0, with the last one used for all lower levels. This overrides the algorithm chosen by `ZstdCompression`, whose level and dictionary still apply to levels using zstd.
+  pub compression_per_level: Option<Vec<FileCompression>>,
+  /// Collects statistics such as write stall time and block cache hits, which are reported in `Metrics`. This has a small performance cost.
+  pub statistics: bool,
+}
+
+// zstd recommends training on about 100 times as much data as the dictionary's size.
+pub(crate) const ZSTD_TRAINING_BYTES_PER_DICTIONARY_BYTE: u64 = 100;
+
+/// WAL files are kept for at least `wal_retention`, if set, so that writes since then can be replayed.
+pub(crate) fn rocksdb_opts(
+  wal_retention: Option<Duration>,
+  zstd: Option<ZstdCompression>,
+  tuning: &RocksDbTuning,
+) -> rocksdb::Options {
   // https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning#other-general-options.
   let mut opt = rocksdb::Options::default();
   opt.create_if_missing(true);
   opt.set_max_background_jobs(num_cpus::get() as i32 * 2);
   opt.set_bytes_per_sync(1024 * 1024 * 4);
   // https://github.com/facebook/rocksdb/wiki/BlobDB#performance-tuning
-  opt.set_write_buffer_size(1024 * 1024 * 1024 * 1);
+  opt.set_write_buffer_size(tuning.write_buffer_bytes.unwrap_or(1024 * 1024 * 1024));
   // By default, RocksDB does not fsync WAL after fwrite, so we can lose data even when Put()/Write() returns with success, which is not OK for us. However, requiring fsync() after every Put()/Write() kills performance; therefore, we instead take over responsibility of both fwrite() and fsync() for the WAL, and do so in the background at intervals.
   opt.set_manual_wal_flush(true);
-  opt.set_compression_type(rocksdb::DBCompressionType::None);
+  match zstd {
+    // Every file records how it's compressed, so compression can be enabled, disabled, or changed at any time, and only affects newly written files.
+    Some(zstd) => {
+      opt.set_compression_type(rocksdb::DBCompressionType::Zstd);
+      // -14 is zstd's default window size, and 0 is the default strategy.
+      opt.set_compression_options(-14, zstd.level, 0, zstd.dictionary_bytes as i32);
+      if zstd.dictionary_bytes > 0 {
+        opt.set_zstd_max_train_bytes(
+          (zstd.dictionary_bytes as u64 * ZSTD_TRAINING_BYTES_PER_DICTIONARY_BYTE)
+            .min(i32::MAX as u64) as i32,
+        );
+      };
+    }
+    None => opt.set_compression_type(rocksdb::DBCompressionType::None),
+  };
+  if let Some(levels) = &tuning.compression_per_level {
+    opt.set_compression_per_level(&levels.iter().map(|c| c.rocksdb_type()).collect::<Vec<_>>());
+  };
+  if let Some(retention) = wal_retention {
+    opt.set_wal_ttl_seconds(retention.as_secs());
+  };
+  if let Some(bytes) = tuning.max_total_wal_bytes {
+    opt.set_max_total_wal_size(bytes);
+  };
+  if let Some(bytes) = tuning.wal_bytes_per_sync {
+    opt.set_wal_bytes_per_sync(bytes);
+  };
+  if let Some(style) = tuning.compaction_style {
+    opt.set_compaction_style(match style {
+      CompactionStyle::Level => DBCompactionStyle::Level,
+      CompactionStyle::Universal => DBCompactionStyle::Universal,
+      CompactionStyle::Fifo => DBCompactionStyle::Fifo,
+    });
+  };
+  if tuning.statistics {
+    opt.enable_statistics();
+  };
 
   // https://github.com/facebook/rocksdb/wiki/Block-Cache.
-  let block_cache = Cache::new_lru_cache(1024 * 1024 * 1024 * 1);
+  let block_cache = Cache::new_lru_cache(tuning.block_cache_bytes.unwrap_or(1024 * 1024 * 1024));
   let mut bbt_opt = BlockBasedOptions::default();
   bbt_opt.set_block_size(1024 * 64);
   bbt_opt.set_block_cache(&block_cache);
@@ -56,45 +319,272 @@ fn rocksdb_opts() -> rocksdb::Options {
   opt
 }
 
-pub(crate) fn rocksdb_open(data_dir: &Path) -> Arc<DB> {
-  Arc::new(DB::open(&rocksdb_opts(), data_dir).unwrap())
+// Below this length, a run of contiguous IDs is deleted using individual tombstones, as range tombstones have a higher read cost until they're compacted away.
+const DELETE_RANGE_MIN_RUN_LEN: usize = 4;
+
+/// Deletes all keys of the messages with the IDs in `ids`, which must be sorted and deduplicated. Contiguous runs of IDs are deleted with a single range tombstone per key prefix.
+pub(crate) fn rocksdb_delete_messages(b: &mut WriteBatchWithTransaction<false>, ids: &[u64]) {
+  let mut i = 0;
+  while i < ids.len() {
+    let mut j = i + 1;
+    while j < ids.len() && ids[j] == ids[j - 1] + 1 {
+      j += 1;
+    }
+    let run = &ids[i..j];
+    for p in RocksDbKeyPrefix::MESSAGE_PREFIXES {
+      if run.len() >= DELETE_RANGE_MIN_RUN_LEN {
+        b.delete_range(
+          rocksdb_key(p, run[0]),
+          rocksdb_key(p, run[run.len() - 1] + 1),
+        );
+      } else {
+        for &id in run {
+          b.delete(rocksdb_key(p, id));
+        }
+      };
+    }
+    i = j;
+  }
+}
+
+pub(crate) fn inline_record(visible_time: i64, poll_tag: u32, contents: &[u8]) -> Vec<u8> {
+  let mut out = vec![0u8; 9 + contents.len()];
+  out.write_i40_le_at(0, visible_time);
+  out.write_u32_le_at(5, poll_tag);
+  out[9..].copy_from_slice(contents);
+  out
+}
+
+pub(crate) fn inline_record_contents(mut raw: Vec<u8>) -> Vec<u8> {
+  raw.drain(..9);
+  raw
+}
+
+// Each batch atomically moves keys to the new encoding, so this can safely resume if interrupted.
+pub(crate) fn rocksdb_migrate_legacy_keys(db: &DB) {
+  for p in RocksDbKeyPrefix::MESSAGE_PREFIXES {
+    let legacy_prefix = p as u8 - LEGACY_LE_KEY_PREFIX_OFFSET;
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    for e in db.iterator(IteratorMode::From(&[legacy_prefix], Direction::Forward)) {
+      let (k, v) = e.unwrap();
+      if k[0] != legacy_prefix {
+        break;
+      };
+      b.delete(&k);
+      b.put(rocksdb_key(p, k.read_u64_le_at(1)), v);
+      if b.len() >= 10_000 {
+        db.write_opt(std::mem::take(&mut b), &rocksdb_write_opts())
+          .unwrap();
+      };
+    }
+    db.write_opt(b, &rocksdb_write_opts()).unwrap();
+  }
+}
+
+/// Opens the database, and records that it may contain features up to `format_version`. Panics if it already contains newer features, as writing to it or rolling back would then be unsafe.
+/// Panics if a data dir using the on-disk format version `existing` (None if it isn't recorded) can't be opened with the compatibility version `format_version`. Returns whether `format_version` must be recorded, as it's newer.
+pub(crate) fn check_format_version(
+  data_dir: &Path,
+  existing: Option<u32>,
+  format_version: u32,
+) -> bool {
+  let existing = existing.unwrap_or(UNRECORDED_FORMAT_VERSION);
+  assert!(
+    existing <= FORMAT_VERSION,
+    "data dir {data_dir:?} uses on-disk format version {existing}, but this release only supports up to {FORMAT_VERSION}; it was written by a newer release, which must be run with `--format-compat {FORMAT_VERSION}` for it to be safe to roll back to this release"
+  );
+  assert!(
+    existing <= format_version,
+    "data dir {data_dir:?} already uses on-disk format version {existing}, which is newer than the requested compatibility version {format_version}"
+  );
+  existing < format_version
+}
+
+pub(crate) fn rocksdb_open(
+  data_dir: &Path,
+  format_version: u32,
+  wal_retention: Option<Duration>,
+  zstd: Option<ZstdCompression>,
+  tuning: &RocksDbTuning,
+) -> RocksDbStorage {
+  let opts = rocksdb_opts(wal_retention, zstd, tuning);
+  let db = DB::open(&opts, data_dir).unwrap();
+  rocksdb_migrate_legacy_keys(&db);
+  let existing = db
+    .get("format_version")
+    .unwrap()
+    .map(|raw| raw.read_u32_le_at(0));
+  if check_format_version(data_dir, existing, format_version) {
+    db.put("format_version", create_u32_le(format_version))
+      .unwrap();
+  };
+  RocksDbStorage {
+    db,
+    statistics: tuning.statistics.then_some(opts),
+  }
 }
 
 pub(crate) struct LoadedData {
   pub next_id: u64,
-  pub messages: Messages,
+  pub messages: MessageShards,
 }
 
-pub(crate) fn rocksdb_load(db: &DB, metrics: Arc<Metrics>) -> LoadedData {
-  let mut messages = Messages::new(metrics);
-  // WARNING: We must use next_id instead of simply getting the maximum ID, as that would cause ID reuse if a message is deleted and then a new one is created in quick succession.
-  let mut next_id = db
-    .get("next_id")
-    .unwrap()
-    .map(|raw| raw.read_u64_le_at(0))
-    .unwrap_or(0);
-  for e in db.iterator(IteratorMode::From(
-    &[RocksDbKeyPrefix::MessageVisibleTimestampSec as u8],
-    Direction::Forward,
-  )) {
-    let (k, v) = e.unwrap();
-    if k[0] != RocksDbKeyPrefix::MessageVisibleTimestampSec as u8 {
-      break;
+pub(crate) struct RocksDbStorage {
+  pub db: DB,
+  // The options the database was opened with, if statistics are enabled, as they're read from there.
+  pub statistics: Option<rocksdb::Options>,
+}
+
+impl RocksDbStorage {
+  fn int_property(&self, name: &str) -> u64 {
+    self.db.property_int_value(name).ok().flatten().unwrap_or(0)
+  }
+}
+
+/// Reads the number `name` from the `rocksdb.aggregated-table-properties` property, which looks like `# entries=123; # deletions=45; ...`.
+fn table_property(properties: &str, name: &str) -> u64 {
+  properties
+    .split("; ")
+    .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+    .and_then(|n| n.trim().parse().ok())
+    .unwrap_or(0)
+}
+
+/// Reads the count of the ticker `name` from the output of `Options::get_statistics`, which has a line like `rocksdb.block.cache.hit COUNT : 123` for each ticker.
+fn statistics_ticker(statistics: &str, name: &str) -> u64 {
+  statistics
+    .lines()
+    .find_map(|l| l.strip_prefix(name)?.strip_prefix(" COUNT : "))
+    .and_then(|n| n.trim().parse().ok())
+    .unwrap_or(0)
+}
+
+impl Storage for RocksDbStorage {
+  fn write(&self, b: WriteBatchWithTransaction<false>) -> Result<(), String> {
+    self
+      .db
+      .write_opt(b, &rocksdb_write_opts())
+      .map_err(|err| err.to_string())
+  }
+
+  fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    self.db.get(key).map_err(|err| err.to_string())
+  }
+
+  fn scan(&self, prefixes: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8])) -> Result<(), String> {
+    let snapshot = self.db.snapshot();
+    for &p in prefixes {
+      for e in snapshot.iterator(IteratorMode::From(p, Direction::Forward)) {
+        let (k, v) = e.map_err(|err| err.to_string())?;
+        if !k.starts_with(p) {
+          break;
+        };
+        f(&k, &v);
+      }
+    }
+    Ok(())
+  }
+
+  fn flush(&self) -> Result<(), String> {
+    self.db.flush_wal(true).map_err(|err| err.to_string())
+  }
+
+  fn checkpoint(&self, dir: &Path) -> Result<(), String> {
+    Checkpoint::new(&self.db)
+      .and_then(|c| c.create_checkpoint(dir))
+      .map_err(|err| err.to_string())
+  }
+
+  fn latest_sequence_number(&self) -> Option<u64> {
+    Some(self.db.latest_sequence_number())
+  }
+
+  fn write_batches_since(
+    &self,
+    since: u64,
+    until: u64,
+    f: &mut dyn FnMut(&[u8]) -> Result<bool, String>,
+  ) -> Result<bool, String> {
+    if since >= until {
+      return Ok(true);
     };
-    let id = k.read_u64_le_at(1);
-    // In some rare situations, it's possible for some pushed messages to persist to the WAL but not yet reach `BatchSync::submit_and_wait` and update the `next_id` key; therefore, we must also update `next_id` to be above any existing ID. This is safe to do as, because if they did not complete `submit_and_wait`, they were never acknowledged nor inserted into the in-memory messages, so could not be polled and deleted and therefore have their IDs reused.
-    if id >= next_id {
-      next_id = id + 1;
+    // This skips a batch starting at `since`, which can only contain that write, as `since` is always the latest sequence number at some point. Like when loading index snapshots, a WAL that can't be read from `since` is treated as no longer having the writes.
+    let Ok(updates) = self.db.get_updates_since(since) else {
+      return Ok(false);
     };
-    let visible_time = v.read_i40_le_at(0);
-    let poll_tag = db
-      .get(rocksdb_key(RocksDbKeyPrefix::MessagePollTag, id))
-      .unwrap()
-      .map(|raw| raw.read_u32_le_at(0))
-      .unwrap_or(0);
-    messages.insert(id, visible_time, poll_tag);
-  }
-  LoadedData { messages, next_id }
+    let mut covered = since;
+    for u in updates {
+      let (seq, b) = u.map_err(|err| err.to_string())?;
+      // There's a gap, so some writes are no longer in the WAL.
+      if seq > covered + 1 {
+        return Ok(false);
+      };
+      let data = b.data();
+      if data.len() < 12 {
+        return Err(format!("invalid write batch at sequence number {seq}"));
+      };
+      if !f(data)? {
+        return Ok(true);
+      };
+      let count = u64::from(data.read_u32_le_at(8));
+      covered = covered.max(seq + count.max(1) - 1);
+      if covered >= until {
+        break;
+      };
+    }
+    Ok(covered >= until)
+  }
+
+  fn compact_range(&self, start: &[u8], end: &[u8]) {
+    let mut opts = CompactOptions::default();
+    // Otherwise, the last level is skipped, which is where most tombstones end up.
+    opts.set_bottommost_level_compaction(BottommostLevelCompaction::ForceOptimized);
+    self.db.compact_range_opt(Some(start), Some(end), &opts);
+  }
+
+  fn is_compacting(&self) -> bool {
+    self
+      .db
+      .property_int_value("rocksdb.num-running-compactions")
+      .is_ok_and(|n| n.is_some_and(|n| n > 0))
+  }
+
+  fn live_data_size(&self) -> u64 {
+    self.int_property("rocksdb.estimate-live-data-size")
+      + self.int_property("rocksdb.cur-size-all-mem-tables")
+  }
+
+  fn disk_usage(&self) -> u64 {
+    self.int_property("rocksdb.total-sst-files-size")
+      + self.int_property("rocksdb.cur-size-all-mem-tables")
+  }
+
+  fn stats(&self) -> StorageStats {
+    let statistics = self
+      .statistics
+      .as_ref()
+      .and_then(|o| o.get_statistics())
+      .unwrap_or_default();
+    let tables = self
+      .db
+      .property_value("rocksdb.aggregated-table-properties")
+      .ok()
+      .flatten()
+      .unwrap_or_default();
+    StorageStats {
+      compaction_pending_bytes: self.int_property("rocksdb.estimate-pending-compaction-bytes"),
+      running_compactions: self.int_property("rocksdb.num-running-compactions"),
+      write_stopped: self.int_property("rocksdb.is-write-stopped") > 0,
+      delayed_write_rate: self.int_property("rocksdb.actual-delayed-write-rate"),
+      block_cache_usage_bytes: self.int_property("rocksdb.block-cache-usage"),
+      stall_micros: statistics_ticker(&statistics, "rocksdb.stall.micros"),
+      block_cache_hits: statistics_ticker(&statistics, "rocksdb.block.cache.hit"),
+      block_cache_misses: statistics_ticker(&statistics, "rocksdb.block.cache.miss"),
+      entries: table_property(&tables, "# entries"),
+      tombstones: table_property(&tables, "# deletions")
+        + table_property(&tables, "# range deletions"),
+    }
+  }
 }
 
 // This exists in case we need to override options for all writes in the future.
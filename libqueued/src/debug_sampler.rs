@@ -0,0 +1,41 @@
+use std::num::NonZeroU64;
+
+pub(crate) struct DebugSampler {
+  // State.
+  count: u64,
+  // Configuration.
+  debug_queue: String,
+  min_poll_count: u32,
+  every_nth: NonZeroU64,
+}
+
+impl DebugSampler {
+  pub fn new(debug_queue: String, min_poll_count: u32, every_nth: NonZeroU64) -> Self {
+    Self {
+      count: 0,
+      debug_queue,
+      min_poll_count,
+      every_nth,
+    }
+  }
+
+  pub fn should_sample(&mut self, poll_count: u32) -> bool {
+    if poll_count < self.min_poll_count {
+      return false;
+    };
+    self.count += 1;
+    self.count.is_multiple_of(self.every_nth.get())
+  }
+
+  pub fn get_debug_queue(&self) -> &str {
+    &self.debug_queue
+  }
+
+  pub fn get_min_poll_count(&self) -> u32 {
+    self.min_poll_count
+  }
+
+  pub fn get_every_nth(&self) -> NonZeroU64 {
+    self.every_nth
+  }
+}
@@ -0,0 +1,179 @@
+use crate::op::result::OpError;
+use serde::Serialize;
+
+macro_rules! error_codes {
+  ($($(#[doc = $doc:literal])* $name:ident = $code:literal, $retryable:literal;)*) => {
+    /// Every error that the HTTP API can return, by the name in the `error` field of error responses. Names and numbers never change once released, and are never reused, so clients can match on either.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    #[repr(u16)]
+    pub enum ErrorCode {
+      $($(#[doc = $doc])* $name = $code,)*
+    }
+
+    impl ErrorCode {
+      pub const ALL: &'static [ErrorCode] = &[$(ErrorCode::$name,)*];
+
+      pub fn name(self) -> &'static str {
+        match self {
+          $(ErrorCode::$name => stringify!($name),)*
+        }
+      }
+
+      /// Whether the same request may succeed if retried later without changes, e.g. after a backoff. Requests that failed with other errors will keep failing, so their messages can be dead-lettered instead.
+      pub fn is_retryable(self) -> bool {
+        match self {
+          $(ErrorCode::$name => $retryable,)*
+        }
+      }
+
+      pub fn description(self) -> &'static str {
+        match self {
+          $(ErrorCode::$name => concat!($($doc),*).trim_ascii(),)*
+        }
+      }
+    }
+  };
+}
+
+// Numbers are grouped by kind: 1xxx invalid requests, 2xxx missing resources or disabled features, 3xxx conflicts with current state, 4xxx auth, 5xxx temporary failures, and 6xxx internal errors. New codes are added at the end of their group.
+error_codes! {
+  /// There are too many attributes or they are too large, or the on-disk format doesn't support attributes.
+  InvalidAttributes = 1000, false;
+  /// The external ID is empty or too long, or the on-disk format doesn't support external IDs.
+  InvalidExternalId = 1001, false;
+  /// The group ID is empty or too long, the on-disk format doesn't support message groups, or the queue uses random IDs.
+  InvalidGroupId = 1002, false;
+  /// The poll tag doesn't match the message's current one, e.g. because its lease expired and it was polled again.
+  InvalidPollTag = 1003, false;
+  /// The schedule's name is empty or too long, or its cron expression is invalid or never matches.
+  InvalidSchedule = 1004, false;
+  /// The visibility timeout or delay is negative or outside the configured bounds.
+  InvalidVisibilityTimeout = 1005, false;
+  /// A message's contents are larger than the configured maximum.
+  MessageTooLarge = 1006, false;
+  /// The on-disk format doesn't support message TTLs.
+  TtlUnsupported = 1007, false;
+  /// The archive being imported is malformed.
+  InvalidArchive = 1008, false;
+  /// The archive being imported ended before its end marker.
+  ArchiveTruncated = 1009, false;
+  /// The request body couldn't be read.
+  InvalidBody = 1010, false;
+  /// A message in a streamed push couldn't be parsed.
+  InvalidMessage = 1011, false;
+  /// The queue name is empty, too long, or has invalid characters.
+  InvalidQueueName = 1012, false;
+  /// A routing rule is invalid. The details are the index of the first invalid rule.
+  InvalidRoutingRule = 1013, false;
+  /// The webhook's URL or settings are invalid.
+  InvalidWebhook = 1014, false;
+  /// More messages were requested than can be generated at once.
+  TooManyMessages = 1015, false;
+  /// The config has an invalid setting, or changes a setting that can't be changed without restarting. The details describe the problem.
+  InvalidConfig = 1016, false;
+  /// A write replicated from the cluster leader doesn't have a valid term, sequence number, or term start.
+  InvalidClusterPosition = 1017, false;
+  /// Multi-queue pushes aren't supported, because the server is part of a cluster or a read-only replica, or its format compatibility version is older than 12.
+  FanoutUnsupported = 1018, false;
+  /// The message doesn't exist, or is currently being polled or updated.
+  MessageNotFound = 2000, false;
+  /// The queue doesn't exist.
+  QueueNotFound = 2001, false;
+  /// The schedule doesn't exist.
+  ScheduleNotFound = 2002, false;
+  /// The queue template doesn't exist.
+  TemplateNotFound = 2003, false;
+  /// The API key or queue to delete doesn't exist, or API keys are disabled.
+  NotFound = 2004, false;
+  /// The snapshot that an incremental snapshot should be relative to doesn't exist.
+  SnapshotBaseNotFound = 2005, false;
+  /// The schema version doesn't exist, or a message declares a schema version that isn't an integer.
+  UnknownSchemaVersion = 2006, false;
+  /// The queue doesn't have an audit log.
+  AuditLogDisabled = 2007, false;
+  /// The server doesn't have a bridge.
+  BridgeNotEnabled = 2008, false;
+  /// The server isn't part of a cluster.
+  ClusterNotEnabled = 2009, false;
+  /// The server doesn't have a mirror.
+  MirrorNotEnabled = 2010, false;
+  /// The queue's name doesn't start with the name of a configured tenant.
+  TenantNotFound = 2011, false;
+  /// Another message in the queue has the external ID, or it was given to more than one message in the push.
+  ExternalIdExists = 3000, false;
+  /// A queue with the name already exists.
+  QueueAlreadyExists = 3001, false;
+  /// Something already exists at the snapshot's destination path.
+  SnapshotPathAlreadyExists = 3002, false;
+  /// The writes since the base snapshot are no longer available, so a full snapshot is needed.
+  SnapshotIncrementUnavailable = 3003, false;
+  /// The writes since the replica's position are no longer available, so the replica must be recreated.
+  WalUnavailable = 3004, false;
+  /// The request was replicated to a node that is itself the cluster leader.
+  IsLeader = 3005, false;
+  /// The replicated write doesn't follow the latest write this node has applied. The details have `resend_from`, the sequence number the leader should resend from, or null if this node's data has diverged from the leader's and it must be reseeded.
+  ReplicationOutOfOrder = 3006, false;
+  /// The API key is missing or invalid, or doesn't have the required permission.
+  NotAuthorized = 4000, false;
+  /// The poll throttle or maintenance push cap was exceeded.
+  Throttled = 5000, true;
+  /// The client exceeded its rate limit.
+  RateLimited = 5001, true;
+  /// Too many requests of the same class are being processed. The details are the class.
+  Overloaded = 5002, true;
+  /// The push would take the queue, or all queues sharing a quota, over a message count, data size, or disk usage limit.
+  QueueFull = 5003, true;
+  /// The operation is suspended on the queue.
+  Suspended = 5004, true;
+  /// The server is draining, so it doesn't accept pushes.
+  Draining = 5005, true;
+  /// The server isn't the cluster leader. The details have the leader's URL, if known, to retry the request with.
+  NotLeader = 5006, true;
+  /// The queue's storage is failing, or its circuit breaker is open.
+  StorageUnavailable = 5007, true;
+  /// The write was applied locally but couldn't be replicated, so it may or may not have taken effect.
+  ReplicationFailed = 5008, true;
+  /// Offloaded contents couldn't be stored or read.
+  OffloadFailed = 5009, true;
+  /// The push was applied locally but couldn't be forwarded to the bridged cluster. Retrying it may push the messages locally again.
+  BridgeFailed = 5010, true;
+  /// Free disk space on the server is below the configured minimum, so it doesn't accept pushes until space is reclaimed.
+  DiskFull = 5011, true;
+  /// A system call failed. The details have the OS error.
+  Sys = 6000, false;
+  /// Creating a snapshot failed.
+  SnapshotFailed = 6001, false;
+}
+
+impl From<OpError> for ErrorCode {
+  fn from(err: OpError) -> Self {
+    match err {
+      OpError::AuditLogDisabled => ErrorCode::AuditLogDisabled,
+      OpError::ExternalIdExists => ErrorCode::ExternalIdExists,
+      OpError::FanoutUnsupported => ErrorCode::FanoutUnsupported,
+      OpError::InvalidAttributes => ErrorCode::InvalidAttributes,
+      OpError::InvalidExternalId => ErrorCode::InvalidExternalId,
+      OpError::InvalidGroupId => ErrorCode::InvalidGroupId,
+      OpError::InvalidPollTag => ErrorCode::InvalidPollTag,
+      OpError::InvalidSchedule => ErrorCode::InvalidSchedule,
+      OpError::InvalidVisibilityTimeout => ErrorCode::InvalidVisibilityTimeout,
+      OpError::MessageNotFound => ErrorCode::MessageNotFound,
+      OpError::MessageTooLarge => ErrorCode::MessageTooLarge,
+      OpError::OffloadFailed => ErrorCode::OffloadFailed,
+      OpError::QueueFull => ErrorCode::QueueFull,
+      OpError::ReplicationFailed => ErrorCode::ReplicationFailed,
+      OpError::ScheduleNotFound => ErrorCode::ScheduleNotFound,
+      OpError::StorageUnavailable => ErrorCode::StorageUnavailable,
+      OpError::Suspended => ErrorCode::Suspended,
+      OpError::Throttled => ErrorCode::Throttled,
+      OpError::TtlUnsupported => ErrorCode::TtlUnsupported,
+      OpError::UnknownSchemaVersion => ErrorCode::UnknownSchemaVersion,
+    }
+  }
+}
+
+impl Serialize for ErrorCode {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(self.name())
+  }
+}
@@ -0,0 +1,132 @@
+use crate::db::rocksdb_key;
+use crate::db::RocksDbKeyPrefix;
+use crate::message_shards::MessageShards;
+use crate::storage::Storage;
+use off64::int::Off64ReadInt;
+use parking_lot::Mutex;
+use rocksdb::WriteBatchWithTransaction;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub const MAX_EXTERNAL_ID_LEN: usize = 256;
+
+// The index from external IDs to message IDs. Each message also has its external ID under `MessageExternalId`, which is deleted with the message, so an index entry is only current if that key still matches. Entries of deleted messages are removed along with them, but may be left behind if that fails, in which case they're ignored and replaced when the external ID is reused.
+const EXTERNAL_ID_KEY_PREFIX: &[u8] = b"external_id/";
+
+pub(crate) fn external_id_is_valid(external_id: &str) -> bool {
+  !external_id.is_empty() && external_id.len() <= MAX_EXTERNAL_ID_LEN
+}
+
+pub(crate) fn external_id_key(external_id: &str) -> Vec<u8> {
+  let mut k = EXTERNAL_ID_KEY_PREFIX.to_vec();
+  k.extend_from_slice(external_id.as_bytes());
+  k
+}
+
+/// Returns the ID of the message that currently has `external_id`, if any.
+pub(crate) fn find_external_id(
+  storage: &dyn Storage,
+  external_id: &str,
+) -> Result<Option<u64>, String> {
+  let Some(raw) = storage.get(&external_id_key(external_id))? else {
+    return Ok(None);
+  };
+  if raw.len() != 8 {
+    return Ok(None);
+  };
+  let id = raw.read_u64_be_at(0);
+  let current = storage.get(&rocksdb_key(RocksDbKeyPrefix::MessageExternalId, id))?;
+  Ok(Some(id).filter(|_| current.as_deref() == Some(external_id.as_bytes())))
+}
+
+/// The external IDs of messages that have one, so that their index entries can be deleted with them, and external IDs being pushed.
+#[derive(Default)]
+pub(crate) struct ExternalIds {
+  by_id: Mutex<HashMap<u64, String>>,
+  // Shared with `ExternalIdReservation`s, which release them when dropped.
+  pending: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Held by a push until it's committed or fails, so that concurrent pushes can't both find an external ID unused.
+pub(crate) struct ExternalIdReservation {
+  pending: Arc<Mutex<HashSet<String>>>,
+  external_ids: Vec<String>,
+}
+
+impl Drop for ExternalIdReservation {
+  fn drop(&mut self) {
+    let mut pending = self.pending.lock();
+    for e in self.external_ids.iter() {
+      pending.remove(e);
+    }
+  }
+}
+
+impl ExternalIds {
+  pub fn load(storage: &dyn Storage, messages: &MessageShards) -> Self {
+    let mut by_id = HashMap::new();
+    storage
+      .scan(&[EXTERNAL_ID_KEY_PREFIX], &mut |k, v| {
+        if v.len() != 8 {
+          return;
+        };
+        let id = v.read_u64_be_at(0);
+        if !messages.lock(id).contains(id) {
+          return;
+        };
+        let Ok(external_id) = String::from_utf8(k[EXTERNAL_ID_KEY_PREFIX.len()..].to_vec()) else {
+          return;
+        };
+        by_id.insert(id, external_id);
+      })
+      .unwrap();
+    Self {
+      by_id: Mutex::new(by_id),
+      pending: Default::default(),
+    }
+  }
+
+  pub fn get(&self, id: u64) -> Option<String> {
+    self.by_id.lock().get(&id).cloned()
+  }
+
+  /// Returns None if any of them is already being pushed.
+  pub fn reserve(&self, external_ids: Vec<String>) -> Option<ExternalIdReservation> {
+    let mut pending = self.pending.lock();
+    if external_ids.iter().any(|e| pending.contains(e)) {
+      return None;
+    };
+    pending.extend(external_ids.iter().cloned());
+    Some(ExternalIdReservation {
+      pending: self.pending.clone(),
+      external_ids,
+    })
+  }
+
+  pub fn put(&self, b: &mut WriteBatchWithTransaction<false>, id: u64, external_id: &str) {
+    b.put(
+      rocksdb_key(RocksDbKeyPrefix::MessageExternalId, id),
+      external_id,
+    );
+    b.put(external_id_key(external_id), id.to_be_bytes());
+  }
+
+  /// Call once the messages written by `put` have been committed.
+  pub fn insert(&self, id: u64, external_id: String) {
+    self.by_id.lock().insert(id, external_id);
+  }
+
+  /// Deletes the index entries of messages being deleted. Their `MessageExternalId` keys are deleted with their other keys.
+  pub fn delete(&self, b: &mut WriteBatchWithTransaction<false>, ids: &[u64]) {
+    let mut by_id = self.by_id.lock();
+    if by_id.is_empty() {
+      return;
+    };
+    for id in ids {
+      if let Some(external_id) = by_id.remove(id) {
+        b.delete(external_id_key(&external_id));
+      };
+    }
+  }
+}
@@ -0,0 +1,358 @@
+use crate::ctx::Ctx;
+use crate::db::fanout_push_key;
+use crate::db::fanout_push_key_id;
+use crate::db::FANOUT_PUSH_KEY_PREFIX;
+use crate::op::push::finish_push;
+use crate::op::push::prepare_push;
+use crate::op::push::OpPushInput;
+use crate::op::push::OpPushOutput;
+use crate::op::push::PreparedPush;
+use crate::op::result::OpError;
+use crate::op::result::OpResult;
+use crate::storage::Storage;
+use crate::Queued;
+use rocksdb::WriteBatchWithTransaction;
+use std::fs;
+use std::fs::File;
+use std::mem::take;
+use std::path::Path;
+use std::path::PathBuf;
+use tokio::task::spawn_blocking;
+use tracing::error;
+use tracing::warn;
+
+// Multi-queue pushes were added in this on-disk format version.
+const FANOUT_FORMAT_VERSION: u32 = 12;
+
+const RECORD_FILE_PREFIX: &str = "fanout-";
+
+/// Pushes messages to several queues, so that either every queue gets its messages or none do. Each queue has its own storage, so there's no single write that covers all of them. Instead, each queue first stores its messages as a prepared push, which isn't visible; then a commit record is durably created in this log, after which the push has succeeded; and then each queue's prepared push is applied. When a queue is loaded with a log (see `QueuedCfg::fanout_log`), it applies its prepared pushes that have a commit record and discards the rest, so a crash at any point either pushes to all queues or none.
+pub struct FanoutLog {
+  dir: PathBuf,
+}
+
+impl FanoutLog {
+  /// Commit records are stored as files in `dir`, which must already exist and mustn't be a queue's data dir.
+  pub fn new(dir: PathBuf) -> Self {
+    Self { dir }
+  }
+
+  fn record_path(&self, id: u64) -> PathBuf {
+    self.dir.join(format!("{RECORD_FILE_PREFIX}{id:016x}"))
+  }
+
+  pub(crate) fn is_committed(&self, id: u64) -> bool {
+    self.record_path(id).exists()
+  }
+
+  /// Removes all commit records. Call this once every queue that may have taken part in a multi-queue push has been loaded with this log, as they've then applied or discarded all prepared pushes.
+  pub fn clear(&self) -> std::io::Result<()> {
+    for e in fs::read_dir(&self.dir)? {
+      let e = e?;
+      if e
+        .file_name()
+        .to_string_lossy()
+        .starts_with(RECORD_FILE_PREFIX)
+      {
+        fs::remove_file(e.path())?;
+      };
+    }
+    Ok(())
+  }
+
+  /// Pushes each input to its queue, returning the outputs in the same order. A queue can appear more than once. If preparing any of the pushes fails, e.g. because a queue is full, nothing is pushed and that error is returned. Once the push has been committed, it succeeds even if applying it to some queue fails, e.g. because its storage is unavailable; that queue then gets its messages when it's next loaded. Every queue must have been loaded with this log, and fails with `OpError::FanoutUnsupported` otherwise.
+  pub async fn push(&self, pushes: Vec<(&Queued, OpPushInput)>) -> OpResult<Vec<OpPushOutput>> {
+    if pushes.iter().any(|(q, _)| {
+      q.ctx.fanout_log.is_none()
+        || q.ctx.replication.is_some()
+        || q.ctx.format_version < FANOUT_FORMAT_VERSION
+    }) {
+      return Err(OpError::FanoutUnsupported);
+    };
+
+    let id = rand::random::<u64>();
+    let mut prepared = Vec::new();
+    let mut failed = None;
+    for (position, (q, req)) in pushes.into_iter().enumerate() {
+      let key = fanout_push_key(id, position as u32);
+      let push = match prepare_push(&q.ctx, req).await {
+        Ok(push) => push,
+        Err(err) => {
+          failed = Some(err);
+          break;
+        }
+      };
+      // The push's write batch is stored as is, and doesn't affect the queue until it's applied.
+      let mut b = WriteBatchWithTransaction::default();
+      b.put(&key, push.b.data());
+      let next_id = push.next_id();
+      // Prepared pushes are discarded if anything fails, including this write, which may still have been applied.
+      prepared.push((&q.ctx, key, push));
+      if let Err(err) = write_prepared(&q.ctx, b, next_id).await {
+        failed = Some(err);
+        break;
+      };
+    }
+    if failed.is_none() {
+      let path = self.record_path(id);
+      let dir = self.dir.clone();
+      if let Err(err) = spawn_blocking(move || write_commit_record(&path, &dir))
+        .await
+        .unwrap()
+      {
+        error!(
+          id,
+          error = err.to_string(),
+          "failed to commit multi-queue push"
+        );
+        failed = Some(OpError::StorageUnavailable);
+      };
+    };
+    if let Some(err) = failed {
+      for (ctx, key, _) in prepared {
+        discard_prepared(ctx, key).await;
+      }
+      return Err(err);
+    };
+
+    let mut outputs = Vec::new();
+    let mut applied = true;
+    for (ctx, key, push) in prepared {
+      let ids = push.ids().to_vec();
+      match apply_prepared(ctx, key, push).await {
+        Ok(out) => outputs.push(out),
+        Err(err) => {
+          warn!(
+            error = format!("{err:?}"),
+            "failed to apply committed multi-queue push, which will be applied when the queue is next loaded"
+          );
+          applied = false;
+          outputs.push(OpPushOutput { ids });
+        }
+      };
+    }
+    // The record is still needed to apply the push to queues that failed. Otherwise, it's no longer needed, and if removing it fails, it's cleared on the next start.
+    if applied {
+      let path = self.record_path(id);
+      let _ = spawn_blocking(move || fs::remove_file(path)).await.unwrap();
+    };
+    Ok(outputs)
+  }
+}
+
+// Durably creates the empty file at `path` in `dir`. This blocks, so it must be called from a blocking thread.
+fn write_commit_record(path: &Path, dir: &Path) -> std::io::Result<()> {
+  File::create(path)?.sync_all()?;
+  File::open(dir)?.sync_all()
+}
+
+// IDs are only used once, so `next_id` is persisted now, in case the push is discarded.
+async fn write_prepared(
+  ctx: &Ctx,
+  b: WriteBatchWithTransaction<false>,
+  next_id: u64,
+) -> OpResult<()> {
+  ctx.db_write(b).await?;
+  ctx.db_sync(next_id).await
+}
+
+// If this fails, the prepared push is discarded when the queue is next loaded, as it has no commit record.
+async fn discard_prepared(ctx: &Ctx, key: Vec<u8>) {
+  let mut b = WriteBatchWithTransaction::default();
+  b.delete(key);
+  if ctx.db_write(b).await.is_ok() {
+    let _ = ctx.db_sync(0).await;
+  };
+}
+
+async fn apply_prepared(ctx: &Ctx, key: Vec<u8>, mut push: PreparedPush) -> OpResult<OpPushOutput> {
+  let mut b = take(&mut push.b);
+  b.delete(key);
+  ctx.db_write(b).await?;
+  // The write has been applied, so it must be reflected in memory even if syncing fails, as it may still become durable.
+  let synced = ctx.db_sync(push.next_id()).await;
+  let out = finish_push(ctx, push);
+  synced?;
+  Ok(out)
+}
+
+/// Applies prepared pushes that have a commit record in `log` and discards the rest. This must be done before the index is loaded, so that it includes the applied messages.
+pub(crate) fn resolve_prepared_pushes(storage: &dyn Storage, log: &FanoutLog) {
+  let mut prepared = Vec::new();
+  storage
+    .scan(&[FANOUT_PUSH_KEY_PREFIX], &mut |k, v| {
+      prepared.push((k.to_vec(), v.to_vec()))
+    })
+    .unwrap();
+  if prepared.is_empty() {
+    return;
+  };
+  for (k, v) in prepared {
+    let mut b = if log.is_committed(fanout_push_key_id(&k)) {
+      WriteBatchWithTransaction::from_data(&v)
+    } else {
+      WriteBatchWithTransaction::default()
+    };
+    b.delete(k);
+    storage.write(b).unwrap();
+  }
+  storage.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::write_commit_record;
+  use super::write_prepared;
+  use super::FanoutLog;
+  use crate::db::fanout_push_key;
+  use crate::db::FANOUT_PUSH_KEY_PREFIX;
+  use crate::lifecycle::MessageState;
+  use crate::op::push::prepare_push;
+  use crate::op::push::OpPushInput;
+  use crate::op::push::OpPushInputMessage;
+  use crate::op::result::OpError;
+  use crate::Queued;
+  use crate::QueuedCfg;
+  use rocksdb::WriteBatchWithTransaction;
+  use std::path::Path;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir =
+      std::env::temp_dir().join(format!("queued-fanout-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn input(contents: &[u8]) -> OpPushInput {
+    OpPushInput {
+      messages: vec![OpPushInputMessage {
+        contents: contents.to_vec(),
+        visibility_timeout_secs: 0,
+        visibility_jitter_secs: 0,
+        priority: 0,
+        attributes: Default::default(),
+        ttl_secs: None,
+        group_id: None,
+        external_id: None,
+      }],
+    }
+  }
+
+  async fn load(dir: &Path, log: &Arc<FanoutLog>, max_message_size: Option<usize>) -> Queued {
+    Queued::load_and_start(dir, QueuedCfg {
+      fanout_log: Some(log.clone()),
+      max_message_size,
+      ..Default::default()
+    })
+    .await
+  }
+
+  fn prepared_count(q: &Queued) -> usize {
+    let mut n = 0;
+    q.ctx
+      .storage
+      .scan(&[FANOUT_PUSH_KEY_PREFIX], &mut |_, _| n += 1)
+      .unwrap();
+    n
+  }
+
+  fn record_count(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+      .unwrap()
+      .filter(|e| {
+        e.as_ref()
+          .unwrap()
+          .file_name()
+          .to_string_lossy()
+          .starts_with("fanout-")
+      })
+      .count()
+  }
+
+  #[tokio::test]
+  async fn pushes_to_every_queue() {
+    let dir = test_dir("push");
+    let log = Arc::new(FanoutLog::new(dir.clone()));
+    let a = load(&dir.join("a"), &log, None).await;
+    let b = load(&dir.join("b"), &log, None).await;
+    let out = log
+      .push(vec![
+        (&a, input(b"1")),
+        (&b, input(b"2")),
+        (&a, input(b"3")),
+      ])
+      .await
+      .unwrap();
+    assert_eq!(out.iter().map(|o| o.ids.clone()).collect::<Vec<_>>(), vec![
+      vec![0],
+      vec![0],
+      vec![1]
+    ]);
+    for (q, id) in [(&a, 0), (&a, 1), (&b, 0)] {
+      assert_eq!(q.message_state(id), MessageState::Available);
+    }
+    assert_eq!(prepared_count(&a) + prepared_count(&b), 0);
+    assert_eq!(record_count(&dir), 0);
+  }
+
+  #[tokio::test]
+  async fn pushes_to_no_queue_if_any_fails() {
+    let dir = test_dir("fail");
+    let log = Arc::new(FanoutLog::new(dir.clone()));
+    let a = load(&dir.join("a"), &log, None).await;
+    let b = load(&dir.join("b"), &log, Some(1)).await;
+    let res = log
+      .push(vec![(&a, input(b"1")), (&b, input(b"too large"))])
+      .await;
+    assert_eq!(res.err(), Some(OpError::MessageTooLarge));
+    assert_eq!(a.message_state(0), MessageState::Vacant);
+    assert_eq!(prepared_count(&a) + prepared_count(&b), 0);
+    assert_eq!(record_count(&dir), 0);
+  }
+
+  #[tokio::test]
+  async fn queues_without_the_log_are_unsupported() {
+    let dir = test_dir("unsupported");
+    let log = Arc::new(FanoutLog::new(dir.clone()));
+    let a = load(&dir.join("a"), &log, None).await;
+    let b = Queued::load_and_start(&dir.join("b"), QueuedCfg::default()).await;
+    let res = log.push(vec![(&a, input(b"1")), (&b, input(b"2"))]).await;
+    assert_eq!(res.err(), Some(OpError::FanoutUnsupported));
+    assert_eq!(prepared_count(&a), 0);
+  }
+
+  // Prepares a push to `q` as part of multi-queue push `id`, as if the process then crashed before applying it.
+  async fn prepare_only(q: &Queued, id: u64, contents: &[u8]) -> u64 {
+    let push = prepare_push(&q.ctx, input(contents)).await.unwrap();
+    let mut b = WriteBatchWithTransaction::default();
+    b.put(fanout_push_key(id, 0), push.b.data());
+    write_prepared(&q.ctx, b, push.next_id()).await.unwrap();
+    push.ids()[0]
+  }
+
+  #[tokio::test]
+  async fn prepared_pushes_are_applied_on_load_only_if_committed() {
+    let dir = test_dir("load");
+    let log = Arc::new(FanoutLog::new(dir.clone()));
+    let a = load(&dir.join("a"), &log, None).await;
+    let committed = prepare_only(&a, 1, b"committed").await;
+    let uncommitted = prepare_only(&a, 2, b"uncommitted").await;
+    write_commit_record(&log.record_path(1), &dir).unwrap();
+    // The original queue keeps its data dir locked, so load a copy of it instead.
+    a.ctx.storage.checkpoint(&dir.join("copy")).unwrap();
+
+    let a = load(&dir.join("copy"), &log, None).await;
+    assert_eq!(a.message_state(committed), MessageState::Available);
+    assert_eq!(a.message_state(uncommitted), MessageState::Vacant);
+    assert_eq!(prepared_count(&a), 0);
+    // IDs of discarded pushes aren't used again.
+    let out = a.push(input(b"new")).await.unwrap();
+    assert!(out.ids[0] > uncommitted);
+
+    log.clear().unwrap();
+    assert_eq!(record_count(&dir), 0);
+  }
+}
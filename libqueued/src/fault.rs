@@ -0,0 +1,195 @@
+#[cfg(feature = "fault_injection")]
+use parking_lot::RwLock;
+#[cfg(feature = "fault_injection")]
+use rand::thread_rng;
+#[cfg(feature = "fault_injection")]
+use rand::Rng;
+use std::future::Future;
+#[cfg(feature = "fault_injection")]
+use tracing::warn;
+
+// Fault injection for crash-consistency testing. Faults are only ever injected with the `fault_injection` feature, which must never be enabled in production builds; without it, `scope` and `inject` do nothing.
+
+/// An operation that faults can be injected into.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultOp {
+  Push,
+  Poll,
+  Update,
+  Delete,
+}
+
+/// Where in an operation a fault is injected.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultStage {
+  /// Just before the operation's write is committed and synced to disk. Pushes have already written to storage by then, but not synced.
+  BeforeCommit,
+  /// Just after the operation's write has been committed and synced to disk, before the in-memory index is updated and the operation returns.
+  AfterCommit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultAction {
+  /// Aborts the process immediately, without closing anything.
+  Crash,
+  /// Fails the operation as if syncing to disk had failed. Before committing, nothing is written; after committing, the write has been applied and may still be durable.
+  FailSync,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FaultRule {
+  /// None matches every operation.
+  pub op: Option<FaultOp>,
+  pub stage: FaultStage,
+  pub action: FaultAction,
+  /// From 0 to 1.
+  pub probability: f64,
+}
+
+#[cfg(feature = "fault_injection")]
+static RULES: RwLock<Vec<FaultRule>> = parking_lot::const_rwlock(Vec::new());
+
+#[cfg(feature = "fault_injection")]
+tokio::task_local! {
+  static CURRENT_OP: FaultOp;
+}
+
+/// Parses comma-separated rules like `push.after_commit=crash@0.01`, where the operation is `push`, `poll`, `update`, `delete`, or `*`, the stage is `before_commit` or `after_commit`, the action is `crash` or `fail_sync`, and the probability is from 0 to 1.
+pub fn parse_faults(raw: &str) -> Result<Vec<FaultRule>, String> {
+  raw
+    .split(',')
+    .filter(|r| !r.is_empty())
+    .map(|r| {
+      let invalid = || format!("invalid fault rule {r:?}");
+      let (point, rest) = r.split_once('=').ok_or_else(invalid)?;
+      let (op, stage) = point.split_once('.').ok_or_else(invalid)?;
+      let (action, probability) = rest.split_once('@').ok_or_else(invalid)?;
+      let probability = probability.parse::<f64>().map_err(|_| invalid())?;
+      if !(0.0..=1.0).contains(&probability) {
+        return Err(invalid());
+      };
+      Ok(FaultRule {
+        op: match op {
+          "*" => None,
+          "push" => Some(FaultOp::Push),
+          "poll" => Some(FaultOp::Poll),
+          "update" => Some(FaultOp::Update),
+          "delete" => Some(FaultOp::Delete),
+          _ => return Err(invalid()),
+        },
+        stage: match stage {
+          "before_commit" => FaultStage::BeforeCommit,
+          "after_commit" => FaultStage::AfterCommit,
+          _ => return Err(invalid()),
+        },
+        action: match action {
+          "crash" => FaultAction::Crash,
+          "fail_sync" => FaultAction::FailSync,
+          _ => return Err(invalid()),
+        },
+        probability,
+      })
+    })
+    .collect()
+}
+
+/// Replaces the faults injected into every queue in this process.
+#[cfg(feature = "fault_injection")]
+pub fn set_faults(rules: Vec<FaultRule>) {
+  *RULES.write() = rules;
+}
+
+/// Runs `f` as operation `op`, so that faults for `op` are injected into it.
+pub(crate) async fn scope<F: Future>(op: FaultOp, f: F) -> F::Output {
+  #[cfg(feature = "fault_injection")]
+  return CURRENT_OP.scope(op, f).await;
+  #[cfg(not(feature = "fault_injection"))]
+  {
+    let _ = op;
+    f.await
+  }
+}
+
+/// Injects a fault at `stage` of the current operation if a rule says so. Crashes don't return; returns true if the operation should fail as if syncing had failed.
+pub(crate) fn inject(stage: FaultStage) -> bool {
+  #[cfg(feature = "fault_injection")]
+  {
+    let Ok(op) = CURRENT_OP.try_with(|op| *op) else {
+      return false;
+    };
+    let Some(action) = RULES
+      .read()
+      .iter()
+      .find(|r| {
+        r.op.is_none_or(|o| o == op) && r.stage == stage && thread_rng().gen_bool(r.probability)
+      })
+      .map(|r| r.action)
+    else {
+      return false;
+    };
+    warn!(?op, ?stage, ?action, "injecting fault");
+    match action {
+      FaultAction::Crash => std::process::abort(),
+      FaultAction::FailSync => true,
+    }
+  }
+  #[cfg(not(feature = "fault_injection"))]
+  {
+    let _ = stage;
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::inject;
+  use super::parse_faults;
+  use super::scope;
+  use super::FaultAction;
+  use super::FaultOp;
+  use super::FaultStage;
+
+  #[test]
+  fn parses_rules() {
+    let rules = parse_faults("push.after_commit=crash@0.01,*.before_commit=fail_sync@1").unwrap();
+    assert_eq!(rules.len(), 2);
+    assert_eq!(rules[0].op, Some(FaultOp::Push));
+    assert_eq!(rules[0].stage, FaultStage::AfterCommit);
+    assert_eq!(rules[0].action, FaultAction::Crash);
+    assert_eq!(rules[0].probability, 0.01);
+    assert_eq!(rules[1].op, None);
+    assert_eq!(rules[1].stage, FaultStage::BeforeCommit);
+    assert_eq!(rules[1].action, FaultAction::FailSync);
+    assert_eq!(rules[1].probability, 1.0);
+    assert!(parse_faults("").unwrap().is_empty());
+  }
+
+  #[test]
+  fn rejects_invalid_rules() {
+    for raw in [
+      "push",
+      "push.after_commit",
+      "push.after_commit=crash",
+      "push=crash@0.1",
+      "peek.after_commit=crash@0.1",
+      "push.during_commit=crash@0.1",
+      "push.after_commit=hang@0.1",
+      "push.after_commit=crash@x",
+      "push.after_commit=crash@1.5",
+      "push.after_commit=crash@-0.1",
+      "push.after_commit=crash@0.1,poll",
+    ] {
+      assert_eq!(
+        parse_faults(raw).unwrap_err(),
+        format!("invalid fault rule {:?}", raw.rsplit(',').next().unwrap()),
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn injects_nothing_without_rules() {
+    // No rules are set, which is the case in every build without the feature.
+    assert!(!inject(FaultStage::BeforeCommit));
+    assert!(!scope(FaultOp::Push, async { inject(FaultStage::AfterCommit) }).await);
+  }
+}
@@ -0,0 +1,12 @@
+pub const MAX_GROUP_ID_LEN: usize = 128;
+
+pub(crate) fn group_id_is_valid(group_id: &str) -> bool {
+  !group_id.is_empty() && group_id.len() <= MAX_GROUP_ID_LEN
+}
+
+/// Group IDs are only needed in memory to tell groups apart, so they're kept as 64-bit FNV-1a hashes instead of strings. This must never change, as hashes are persisted in index snapshots. A collision only means that two groups are delivered as if they were one, which preserves ordering.
+pub(crate) fn group_hash(group_id: &[u8]) -> u64 {
+  group_id.iter().fold(0xcbf29ce484222325, |h, &b| {
+    (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+  })
+}
@@ -0,0 +1,297 @@
+use crate::ctx::Ctx;
+use crate::db::rocksdb_key;
+use crate::db::RocksDbKeyPrefix;
+use crate::op::result::OpResult;
+use crate::storage::Storage;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::task::spawn_blocking;
+
+/// How IDs of new messages are generated. IDs are unique within a queue whichever is used, and a queue can switch between them at any time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum IdStrategy {
+  /// Consecutive IDs, starting from 0.
+  #[default]
+  Sequential,
+  /// The push time in milliseconds, then `node_id`, then a sequence number, like Twitter's Snowflake IDs. These are ordered by push time, and unique across servers with different node IDs (up to `MAX_SNOWFLAKE_NODE_ID`).
+  Snowflake { node_id: u16 },
+  /// Random IDs, which can't be guessed from other IDs. Requires on-disk format version 9. Messages with random IDs can't be in a group, as groups are delivered in order of ID.
+  Random,
+}
+
+pub const MAX_SNOWFLAKE_NODE_ID: u16 = 1023;
+
+// 2024-01-01T00:00:00Z. The 41 bits of the timestamp last until 2093.
+const SNOWFLAKE_EPOCH_MS: i64 = 1_704_067_200_000;
+const SNOWFLAKE_NODE_SHIFT: u32 = 12;
+const SNOWFLAKE_TIME_SHIFT: u32 = 22;
+const SNOWFLAKE_NODE_MASK: u64 = (MAX_SNOWFLAKE_NODE_ID as u64) << SNOWFLAKE_NODE_SHIFT;
+
+/// Random IDs are at least this, and sequential and Snowflake IDs are always less, so they never collide. Random IDs don't advance `next_id`, which only tracks the latter.
+pub(crate) const RANDOM_ID_MIN: u64 = 1 << 63;
+
+pub(crate) fn is_random_id(id: u64) -> bool {
+  id >= RANDOM_ID_MIN
+}
+
+// Returns `n` increasing Snowflake IDs that are at least `next`.
+fn snowflake_ids(next: u64, now_ms: i64, node_id: u16, n: u64) -> Vec<u64> {
+  let node = u64::from(node_id) << SNOWFLAKE_NODE_SHIFT;
+  let now = (((now_ms - SNOWFLAKE_EPOCH_MS).max(0) as u64) << SNOWFLAKE_TIME_SHIFT) | node;
+  let mut id = next.max(now);
+  let mut ids = Vec::with_capacity(n as usize);
+  for _ in 0..n {
+    // Either `next` was generated differently (e.g. by another strategy or node ID), or the sequence number of this millisecond has overflowed, so continue from the next millisecond that's free.
+    if id & SNOWFLAKE_NODE_MASK != node {
+      let ms = id >> SNOWFLAKE_TIME_SHIFT;
+      let start = (ms << SNOWFLAKE_TIME_SHIFT) | node;
+      id = if start >= id {
+        start
+      } else {
+        ((ms + 1) << SNOWFLAKE_TIME_SHIFT) | node
+      };
+    };
+    ids.push(id);
+    id += 1;
+  }
+  ids
+}
+
+// Returns whether message `id` exists in storage, including messages that are currently being polled or updated, which aren't in the in-memory index.
+pub(crate) fn message_exists(storage: &dyn Storage, id: u64) -> Result<bool, String> {
+  Ok(
+    storage
+      .get(&rocksdb_key(RocksDbKeyPrefix::MessageInline, id))?
+      .is_some()
+      || storage
+        .get(&rocksdb_key(
+          RocksDbKeyPrefix::MessageVisibleTimestampSec,
+          id,
+        ))?
+        .is_some(),
+  )
+}
+
+/// Random IDs that are being pushed or imported, so that concurrent operations can't use the same one before either has been committed.
+#[derive(Default)]
+pub(crate) struct PendingIds {
+  // Shared with `PendingIdReservation`s, which release them when dropped.
+  ids: Arc<Mutex<HashSet<u64>>>,
+}
+
+pub(crate) struct PendingIdReservation {
+  pending: Arc<Mutex<HashSet<u64>>>,
+  ids: Vec<u64>,
+}
+
+impl Drop for PendingIdReservation {
+  fn drop(&mut self) {
+    let mut pending = self.pending.lock();
+    for id in self.ids.iter() {
+      pending.remove(id);
+    }
+  }
+}
+
+impl PendingIds {
+  /// Reserves the IDs in `ids` that aren't already reserved, and returns those that were.
+  pub fn reserve(&self, ids: &[u64]) -> (PendingIdReservation, Vec<u64>) {
+    let mut pending = self.ids.lock();
+    let mut reserved = Vec::new();
+    let mut taken = Vec::new();
+    for &id in ids {
+      if pending.insert(id) {
+        reserved.push(id);
+      } else {
+        taken.push(id);
+      };
+    }
+    (
+      PendingIdReservation {
+        pending: self.ids.clone(),
+        ids: reserved,
+      },
+      taken,
+    )
+  }
+}
+
+/// IDs allocated for a push.
+pub(crate) struct AllocatedIds {
+  pub ids: Vec<u64>,
+  /// The value `next_id` must be persisted as at least once the push is committed, or 0 if it doesn't need to be.
+  pub next_id: u64,
+  // Held until the push has been committed or has failed, when this is dropped.
+  _reservations: Vec<PendingIdReservation>,
+}
+
+pub(crate) async fn allocate_ids(ctx: &Ctx, n: u64) -> OpResult<AllocatedIds> {
+  let ids = match ctx.id_strategy {
+    IdStrategy::Sequential => {
+      let base_id = ctx.next_id.fetch_add(n, Ordering::Relaxed);
+      (base_id..base_id + n).collect()
+    }
+    IdStrategy::Snowflake { node_id } => {
+      let now_ms = ctx.clock.now_ms();
+      let mut ids = Vec::new();
+      ctx
+        .next_id
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+          ids = snowflake_ids(next, now_ms, node_id, n);
+          Some(ids.last().map_or(next, |id| id + 1))
+        })
+        .unwrap();
+      ids
+    }
+    IdStrategy::Random => return allocate_random_ids(ctx, n).await,
+  };
+  Ok(AllocatedIds {
+    next_id: ids.last().map_or(0, |id| id + 1),
+    ids,
+    _reservations: Vec::new(),
+  })
+}
+
+// Random IDs could collide with existing messages or each other, however unlikely, so any that do are replaced.
+async fn allocate_random_ids(ctx: &Ctx, n: u64) -> OpResult<AllocatedIds> {
+  let mut ids = Vec::with_capacity(n as usize);
+  let mut reservations = Vec::new();
+  while (ids.len() as u64) < n {
+    let candidates = {
+      let mut rng = ctx.random.rng();
+      let mut candidates = HashSet::new();
+      while ((ids.len() + candidates.len()) as u64) < n {
+        let id = rng.gen_range(RANDOM_ID_MIN..u64::MAX);
+        if !ids.contains(&id) {
+          candidates.insert(id);
+        };
+      }
+      candidates.into_iter().collect::<Vec<_>>()
+    };
+    let (reservation, taken) = ctx.pending_ids.reserve(&candidates);
+    let candidates = candidates
+      .into_iter()
+      .filter(|id| !taken.contains(id))
+      .collect::<Vec<_>>();
+    let storage = ctx.storage.clone();
+    let res = spawn_blocking(move || {
+      let mut free = Vec::new();
+      for id in candidates {
+        if !message_exists(&*storage, id)? {
+          free.push(id);
+        };
+      }
+      Ok::<_, String>(free)
+    })
+    .await
+    .unwrap();
+    ids.extend(ctx.record_storage_read_result(res)?);
+    reservations.push(reservation);
+  }
+  Ok(AllocatedIds {
+    ids,
+    next_id: 0,
+    _reservations: reservations,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::is_random_id;
+  use super::snowflake_ids;
+  use super::PendingIds;
+  use super::MAX_SNOWFLAKE_NODE_ID;
+  use super::SNOWFLAKE_EPOCH_MS;
+  use super::SNOWFLAKE_NODE_SHIFT;
+  use super::SNOWFLAKE_TIME_SHIFT;
+
+  // Splits a Snowflake ID into its milliseconds since the Snowflake epoch, node ID, and sequence number.
+  fn decode(id: u64) -> (u64, u16, u64) {
+    (
+      id >> SNOWFLAKE_TIME_SHIFT,
+      ((id >> SNOWFLAKE_NODE_SHIFT) & u64::from(MAX_SNOWFLAKE_NODE_ID)) as u16,
+      id & ((1 << SNOWFLAKE_NODE_SHIFT) - 1),
+    )
+  }
+
+  const NOW_MS: i64 = SNOWFLAKE_EPOCH_MS + 1_000;
+
+  #[test]
+  fn snowflake_ids_have_the_time_node_and_sequence() {
+    let ids = snowflake_ids(0, NOW_MS, 7, 3);
+    assert_eq!(ids.iter().map(|&id| decode(id)).collect::<Vec<_>>(), vec![
+      (1_000, 7, 0),
+      (1_000, 7, 1),
+      (1_000, 7, 2)
+    ]);
+    // Continuing in the same millisecond.
+    let more = snowflake_ids(ids[2] + 1, NOW_MS, 7, 1);
+    assert_eq!(decode(more[0]), (1_000, 7, 3));
+    // A later millisecond restarts the sequence.
+    assert_eq!(
+      decode(snowflake_ids(ids[2] + 1, NOW_MS + 1, 7, 1)[0]),
+      (1_001, 7, 0)
+    );
+    assert_eq!(
+      decode(snowflake_ids(0, NOW_MS, MAX_SNOWFLAKE_NODE_ID, 1)[0]),
+      (1_000, MAX_SNOWFLAKE_NODE_ID, 0)
+    );
+    // The last millisecond the 41 bits of the timestamp can represent.
+    let last = snowflake_ids(
+      0,
+      SNOWFLAKE_EPOCH_MS + (1 << 41) - 1,
+      MAX_SNOWFLAKE_NODE_ID,
+      1,
+    )[0];
+    assert!(!is_random_id(last | ((1 << SNOWFLAKE_NODE_SHIFT) - 1)));
+  }
+
+  #[test]
+  fn snowflake_ids_never_go_backwards() {
+    // The clock went backwards, or is before the epoch.
+    let ids = snowflake_ids(0, NOW_MS, 7, 1);
+    assert_eq!(
+      decode(snowflake_ids(ids[0] + 1, NOW_MS - 500, 7, 1)[0]),
+      (1_000, 7, 1)
+    );
+    assert_eq!(decode(snowflake_ids(0, 0, 7, 1)[0]), (0, 7, 0));
+    // The sequence of a millisecond overflows into the next.
+    let ids = snowflake_ids(0, NOW_MS, 7, 4097);
+    assert_eq!(decode(ids[4095]), (1_000, 7, 4095));
+    assert_eq!(decode(ids[4096]), (1_001, 7, 0));
+    // After IDs of another node, or sequential IDs larger than the current time.
+    let other = snowflake_ids(0, NOW_MS, 9, 1)[0];
+    assert_eq!(
+      decode(snowflake_ids(other + 1, NOW_MS, 7, 1)[0]),
+      (1_001, 7, 0)
+    );
+    let other = snowflake_ids(0, NOW_MS, 3, 1)[0];
+    assert_eq!(
+      decode(snowflake_ids(other + 1, NOW_MS, 7, 1)[0]),
+      (1_000, 7, 0)
+    );
+    for n in [1, 2, 100, 5000] {
+      let ids = snowflake_ids(12_345_678_901, NOW_MS, 7, n);
+      assert_eq!(ids.len() as u64, n);
+      assert!(ids[0] >= 12_345_678_901);
+      assert!(ids.windows(2).all(|w| w[0] < w[1]));
+      assert!(ids.iter().all(|&id| decode(id).1 == 7));
+    }
+  }
+
+  #[test]
+  fn pending_ids_are_reserved_until_dropped() {
+    let pending = PendingIds::default();
+    let (first, taken) = pending.reserve(&[1, 2]);
+    assert!(taken.is_empty());
+    let (second, taken) = pending.reserve(&[2, 3]);
+    assert_eq!(taken, vec![2]);
+    drop(first);
+    let (_third, taken) = pending.reserve(&[1, 2, 3]);
+    assert_eq!(taken, vec![3]);
+    drop(second);
+  }
+}
@@ -0,0 +1,144 @@
+use crate::db::rocksdb_key_id;
+use crate::db::RocksDbKeyPrefix;
+use crate::index_snapshot::IndexState;
+use crate::message_shards::MessageShards;
+use crate::metrics::Metrics;
+use crate::storage::Storage;
+use crate::suspend::SuspendState;
+use rand::thread_rng;
+use rand::Rng;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::error;
+use tracing::info;
+
+// How many messages have their metadata compared with storage. Every message is checked for existence.
+const SPOT_CHECKS: usize = 1000;
+
+/// What to do if the index doesn't match storage after it's loaded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IndexMismatchAction {
+  /// Panic instead of loading the queue, so that it's never served.
+  Refuse,
+  /// Suspend pushes, polls, updates, and deletes, so that the queue can still be inspected (e.g. peeked, listed, and scrubbed) but isn't changed until they're unsuspended.
+  Suspend,
+}
+
+// Picks up to `n` random IDs from the index.
+fn sample_ids(messages: &MessageShards, n: usize) -> Vec<u64> {
+  let mut rng = thread_rng();
+  let mut sample = Vec::with_capacity(n);
+  let mut seen = 0;
+  for shard in messages.lock_each() {
+    for id in shard.ids() {
+      seen += 1;
+      if sample.len() < n {
+        sample.push(id);
+      } else {
+        let i = rng.gen_range(0..seen);
+        if i < n {
+          sample[i] = id;
+        };
+      };
+    }
+  }
+  sample
+}
+
+/// Checks that the index has exactly the messages in storage, and that the metadata of a random sample of them matches storage. This reads every message's visible time, so it's about as slow as loading the index without an index snapshot. Messages must not be changed while this runs.
+pub(crate) fn verify_index(storage: &dyn Storage, messages: &MessageShards) -> Result<(), String> {
+  let mut problems = Vec::new();
+
+  // Every message has exactly one of these keys.
+  let mut stored = 0;
+  let mut unindexed = Vec::new();
+  storage.scan(
+    &[&[RocksDbKeyPrefix::MessageInline as u8], &[
+      RocksDbKeyPrefix::MessageVisibleTimestampSec as u8,
+    ]],
+    &mut |k, _| {
+      if k.len() != 9 {
+        return;
+      };
+      stored += 1;
+      let id = rocksdb_key_id(k);
+      if !messages.lock(id).contains(id) {
+        unindexed.push(id);
+      };
+    },
+  )?;
+  if let Some(id) = unindexed.first() {
+    problems.push(format!(
+      "{} messages in storage are missing from the index, e.g. {id}",
+      unindexed.len()
+    ));
+  };
+  let extra = messages.len().saturating_sub(stored - unindexed.len());
+  if extra > 0 {
+    problems.push(format!("{extra} messages in the index aren't in storage"));
+  };
+
+  let ids = sample_ids(messages, SPOT_CHECKS);
+  // This must not affect the queue's metrics.
+  let expected = IndexState::read(storage, &ids)?.into_loaded(Arc::new(Metrics::default()), 1);
+  let mismatched = ids
+    .iter()
+    .copied()
+    .filter(|&id| messages.lock(id).indexed(id) != expected.messages.lock(id).indexed(id))
+    .collect::<Vec<_>>();
+  if let Some(id) = mismatched.first() {
+    problems.push(format!(
+      "{} of {} spot-checked messages don't match storage, e.g. {id}",
+      mismatched.len(),
+      ids.len()
+    ));
+  };
+
+  match problems.is_empty() {
+    true => Ok(()),
+    false => Err(problems.join("; ")),
+  }
+}
+
+/// Verifies a newly loaded index if `action` is set, handling any mismatch as requested.
+pub(crate) fn check_loaded_index(
+  data_dir: &Path,
+  storage: &dyn Storage,
+  messages: &MessageShards,
+  action: Option<IndexMismatchAction>,
+  suspension: &SuspendState,
+) {
+  let Some(action) = action else {
+    return;
+  };
+  let started = Instant::now();
+  let err = match verify_index(storage, messages) {
+    Ok(()) => {
+      info!(
+        ?data_dir,
+        messages = messages.len(),
+        duration_ms = started.elapsed().as_millis() as u64,
+        "verified index"
+      );
+      return;
+    }
+    Err(err) => err,
+  };
+  match action {
+    IndexMismatchAction::Refuse => {
+      panic!("index of data dir {data_dir:?} doesn't match storage: {err}")
+    }
+    IndexMismatchAction::Suspend => {
+      error!(
+        ?data_dir,
+        error = err,
+        "index doesn't match storage, suspending all operations"
+      );
+      suspension.set_push_suspension(true);
+      suspension.set_poll_suspension(true);
+      suspension.set_update_suspension(true);
+      suspension.set_delete_suspension(true);
+    }
+  };
+}
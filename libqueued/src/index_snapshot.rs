@@ -0,0 +1,670 @@
+use crate::db::rocksdb_key;
+use crate::db::rocksdb_key_id;
+use crate::db::LoadedData;
+use crate::db::RocksDbKeyPrefix;
+use crate::db::RocksDbStorage;
+use crate::group::group_hash;
+use crate::id_gen::is_random_id;
+use crate::message_shards::MessageShards;
+use crate::metrics::Metrics;
+use crate::storage::Storage;
+use crate::write_batch::parse_write_batch;
+use crate::write_batch::WriteBatchOp;
+use num_traits::FromPrimitive;
+use off64::int::create_i64_le;
+use off64::int::create_u32_le;
+use off64::int::create_u64_le;
+use off64::int::Off64ReadInt;
+use rocksdb::DB;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tracing::info;
+use tracing::warn;
+
+const FILE_NAME: &str = "index-snapshot";
+const MAGIC: &[u8; 8] = b"QDIDX004";
+const ENTRY_LEN: usize = 50;
+
+const FLAG_SPLIT: u8 = 1 << 0;
+const FLAG_OFFLOADED: u8 = 1 << 1;
+const FLAG_ATTRIBUTES: u8 = 1 << 2;
+const FLAG_PINNED: u8 = 1 << 3;
+const FLAG_EXPIRY: u8 = 1 << 4;
+const FLAG_PUSHED_AT: u8 = 1 << 5;
+const FLAG_GROUP: u8 = 1 << 6;
+const FLAG_COMPRESSED: u8 = 1 << 7;
+
+// What the message keys of one ID contain, excluding contents. This is tracked per key rather than per message so that write batches can be replayed onto it in any state.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+struct IndexEntry {
+  inline: Option<(i64, u32)>,
+  visible_time: Option<i64>,
+  poll_tag: Option<u32>,
+  priority: u8,
+  offloaded: bool,
+  poll_count: u32,
+  attributes: bool,
+  pinned: bool,
+  expiry: Option<i64>,
+  pushed_at_ms: Option<i64>,
+  group: Option<u64>,
+  compressed: bool,
+}
+
+/// The index of all messages as of a RocksDB sequence number, which is much smaller than the database and can be rebuilt into `Messages` quickly.
+pub(crate) struct IndexState {
+  seq: u64,
+  next_id: u64,
+  entries: BTreeMap<u64, IndexEntry>,
+}
+
+impl IndexState {
+  /// Reads the index from `storage`, which must happen after reading `seq`, so that replaying writes after `seq` is safe even if some were already included.
+  pub fn scan(storage: &dyn Storage, seq: u64) -> Result<Self, String> {
+    let mut state = IndexState {
+      seq,
+      next_id: 0,
+      entries: BTreeMap::new(),
+    };
+    let message_prefixes = RocksDbKeyPrefix::MESSAGE_PREFIXES
+      .into_iter()
+      // Contents don't affect the index.
+      .filter(|&p| p != RocksDbKeyPrefix::MessageData)
+      .map(|p| [p as u8])
+      .collect::<Vec<_>>();
+    let mut prefixes: Vec<&[u8]> = vec![b"next_id"];
+    prefixes.extend(message_prefixes.iter().map(|p| &p[..]));
+    storage.scan(&prefixes, &mut |k, v| {
+      if let Some((p, id)) = Self::message_key(k) {
+        state.put(p, id, v);
+      } else if k == b"next_id" {
+        // WARNING: We must use next_id instead of simply getting the maximum ID, as that would cause ID reuse if a message is deleted and then a new one is created in quick succession.
+        state.next_id = v.read_u64_le_at(0);
+      };
+    })?;
+    Ok(state)
+  }
+
+  /// Reads only the messages with the IDs in `ids` from `storage`, e.g. to spot-check another index.
+  pub fn read(storage: &dyn Storage, ids: &[u64]) -> Result<Self, String> {
+    let mut state = IndexState {
+      seq: 0,
+      next_id: 0,
+      entries: BTreeMap::new(),
+    };
+    for &id in ids {
+      for p in RocksDbKeyPrefix::MESSAGE_PREFIXES {
+        if p == RocksDbKeyPrefix::MessageData {
+          continue;
+        };
+        if let Some(v) = storage.get(&rocksdb_key(p, id))? {
+          state.put(p, id, &v);
+        };
+      }
+    }
+    Ok(state)
+  }
+
+  fn put(&mut self, p: RocksDbKeyPrefix, id: u64, v: &[u8]) {
+    let e = self.entries.entry(id).or_default();
+    match p {
+      RocksDbKeyPrefix::MessagePollTag => e.poll_tag = Some(v.read_u32_le_at(0)),
+      RocksDbKeyPrefix::MessageVisibleTimestampSec => e.visible_time = Some(v.read_i40_le_at(0)),
+      RocksDbKeyPrefix::MessageData => {}
+      RocksDbKeyPrefix::MessagePinned => e.pinned = true,
+      RocksDbKeyPrefix::MessagePriority => e.priority = v[0],
+      RocksDbKeyPrefix::MessageInline => {
+        e.inline = Some((v.read_i40_le_at(0), v.read_u32_le_at(5)))
+      }
+      RocksDbKeyPrefix::MessageOffloaded => e.offloaded = true,
+      RocksDbKeyPrefix::MessagePollCount => e.poll_count = v.read_u32_le_at(0),
+      RocksDbKeyPrefix::MessageAttributes => e.attributes = true,
+      RocksDbKeyPrefix::MessageExpiry => e.expiry = Some(v.read_i64_le_at(0)),
+      RocksDbKeyPrefix::MessagePushedAt => e.pushed_at_ms = Some(v.read_i64_le_at(0)),
+      RocksDbKeyPrefix::MessageGroup => e.group = Some(group_hash(v)),
+      RocksDbKeyPrefix::MessageExternalId => {}
+      RocksDbKeyPrefix::MessageCompressed => e.compressed = true,
+    };
+  }
+
+  fn delete(&mut self, p: RocksDbKeyPrefix, id: u64) {
+    let Some(e) = self.entries.get_mut(&id) else {
+      return;
+    };
+    match p {
+      RocksDbKeyPrefix::MessagePollTag => e.poll_tag = None,
+      RocksDbKeyPrefix::MessageVisibleTimestampSec => e.visible_time = None,
+      RocksDbKeyPrefix::MessageData => {}
+      RocksDbKeyPrefix::MessagePinned => e.pinned = false,
+      RocksDbKeyPrefix::MessagePriority => e.priority = 0,
+      RocksDbKeyPrefix::MessageInline => e.inline = None,
+      RocksDbKeyPrefix::MessageOffloaded => e.offloaded = false,
+      RocksDbKeyPrefix::MessagePollCount => e.poll_count = 0,
+      RocksDbKeyPrefix::MessageAttributes => e.attributes = false,
+      RocksDbKeyPrefix::MessageExpiry => e.expiry = None,
+      RocksDbKeyPrefix::MessagePushedAt => e.pushed_at_ms = None,
+      RocksDbKeyPrefix::MessageGroup => e.group = None,
+      RocksDbKeyPrefix::MessageExternalId => {}
+      RocksDbKeyPrefix::MessageCompressed => e.compressed = false,
+    };
+  }
+
+  fn message_key(k: &[u8]) -> Option<(RocksDbKeyPrefix, u64)> {
+    if k.len() != 9 {
+      return None;
+    };
+    Some((RocksDbKeyPrefix::from_u8(k[0])?, rocksdb_key_id(k)))
+  }
+
+  /// Applies a raw write batch as read from the WAL. Returns false if it contains something this can't interpret, in which case the state must be discarded.
+  fn apply_batch(&mut self, data: &[u8]) -> bool {
+    let Some(ops) = parse_write_batch(data) else {
+      return false;
+    };
+    for op in ops {
+      match op {
+        WriteBatchOp::Put(k, v) => {
+          if let Some((p, id)) = Self::message_key(k) {
+            self.put(p, id, v);
+          } else if k == b"next_id" {
+            self.next_id = self.next_id.max(v.read_u64_le_at(0));
+          };
+        }
+        WriteBatchOp::Delete(k) => {
+          if let Some((p, id)) = Self::message_key(k) {
+            self.delete(p, id);
+          };
+        }
+        // We only create these within a single prefix.
+        WriteBatchOp::DeleteRange(start, end) => {
+          match (Self::message_key(start), Self::message_key(end)) {
+            (Some((p, start)), Some((end_p, end))) if p == end_p => {
+              let ids = self.entries.range(start..end).map(|(&id, _)| id);
+              for id in ids.collect::<Vec<_>>() {
+                self.delete(p, id);
+              }
+            }
+            (None, None) => {}
+            _ => return false,
+          };
+        }
+      };
+    }
+    true
+  }
+
+  /// Like `apply_batch`, but also updates `messages`, which must have been built from this state, for every message the batch changes, so that it stays in sync without being rebuilt.
+  pub fn apply_batch_to_index(&mut self, data: &[u8], messages: &MessageShards) -> bool {
+    let Some(ops) = parse_write_batch(data) else {
+      return false;
+    };
+    let mut changed = BTreeSet::new();
+    for op in ops {
+      match op {
+        WriteBatchOp::Put(k, _) | WriteBatchOp::Delete(k) => {
+          changed.extend(Self::message_key(k).map(|(_, id)| id));
+        }
+        WriteBatchOp::DeleteRange(start, end) => {
+          if let (Some((_, start)), Some((_, end))) =
+            (Self::message_key(start), Self::message_key(end))
+          {
+            changed.extend(self.entries.range(start..end).map(|(&id, _)| id));
+          };
+        }
+      };
+    }
+    if !self.apply_batch(data) {
+      return false;
+    };
+    for id in changed {
+      messages.remove(id);
+      let Some(e) = self.entries.get(&id) else {
+        continue;
+      };
+      // Otherwise, entries of deleted messages would accumulate forever.
+      if *e == IndexEntry::default() {
+        self.entries.remove(&id);
+        continue;
+      };
+      if insert_entry(messages, id, e) && id >= self.next_id && !is_random_id(id) {
+        self.next_id = id + 1;
+      };
+    }
+    true
+  }
+
+  pub fn next_id(&self) -> u64 {
+    self.next_id
+  }
+
+  pub fn into_loaded(self, metrics: Arc<Metrics>, shards: usize) -> LoadedData {
+    let messages = MessageShards::new(metrics, shards);
+    let mut next_id = self.next_id;
+    for (id, e) in self.entries {
+      // In some rare situations, it's possible for some pushed messages to persist to the WAL but not yet reach `BatchSync::submit_and_wait` and update the `next_id` key; therefore, we must also update `next_id` to be above any existing ID. This is safe to do as, because if they did not complete `submit_and_wait`, they were never acknowledged nor inserted into the in-memory messages, so could not be polled and deleted and therefore have their IDs reused.
+      if insert_entry(&messages, id, &e) && id >= next_id && !is_random_id(id) {
+        next_id = id + 1;
+      };
+    }
+    LoadedData { messages, next_id }
+  }
+
+  fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::with_capacity(32 + self.entries.len() * ENTRY_LEN);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&create_u64_le(self.seq));
+    out.extend_from_slice(&create_u64_le(self.next_id));
+    out.extend_from_slice(&create_u64_le(self.entries.len() as u64));
+    for (&id, e) in self.entries.iter() {
+      let (visible_time, poll_tag, split) = match (e.visible_time, e.inline) {
+        (Some(visible_time), _) => (visible_time, e.poll_tag.unwrap_or(0), true),
+        (None, Some((visible_time, poll_tag))) => (visible_time, poll_tag, false),
+        (None, None) => continue,
+      };
+      let mut flags = 0;
+      for (set, flag) in [
+        (split, FLAG_SPLIT),
+        (e.offloaded, FLAG_OFFLOADED),
+        (e.attributes, FLAG_ATTRIBUTES),
+        (e.pinned, FLAG_PINNED),
+        (e.expiry.is_some(), FLAG_EXPIRY),
+        (e.pushed_at_ms.is_some(), FLAG_PUSHED_AT),
+        (e.group.is_some(), FLAG_GROUP),
+        (e.compressed, FLAG_COMPRESSED),
+      ] {
+        if set {
+          flags |= flag;
+        };
+      }
+      out.extend_from_slice(&create_u64_le(id));
+      out.extend_from_slice(&create_i64_le(visible_time));
+      out.extend_from_slice(&create_u32_le(poll_tag));
+      out.push(e.priority);
+      out.push(flags);
+      out.extend_from_slice(&create_u32_le(e.poll_count));
+      out.extend_from_slice(&create_i64_le(e.expiry.unwrap_or(0)));
+      out.extend_from_slice(&create_i64_le(e.pushed_at_ms.unwrap_or(0)));
+      out.extend_from_slice(&create_u64_le(e.group.unwrap_or(0)));
+    }
+    // Stale entries were skipped, so fix up the count.
+    let count = (out.len() - 32) / ENTRY_LEN;
+    out[24..32].copy_from_slice(&create_u64_le(count as u64));
+    out
+  }
+
+  fn decode(raw: &[u8]) -> Option<Self> {
+    if raw.len() < 32 || &raw[..8] != MAGIC {
+      return None;
+    };
+    let count = raw.read_u64_le_at(24) as usize;
+    // The count may be corrupt, so it mustn't overflow.
+    if Some(raw.len() - 32) != count.checked_mul(ENTRY_LEN) {
+      return None;
+    };
+    let mut entries = BTreeMap::new();
+    for i in 0..count {
+      let e = &raw[32 + i * ENTRY_LEN..32 + (i + 1) * ENTRY_LEN];
+      let visible_time = e.read_i64_le_at(8);
+      let poll_tag = e.read_u32_le_at(16);
+      let flags = e[21];
+      let split = flags & FLAG_SPLIT != 0;
+      entries.insert(e.read_u64_le_at(0), IndexEntry {
+        inline: (!split).then_some((visible_time, poll_tag)),
+        visible_time: split.then_some(visible_time),
+        poll_tag: split.then_some(poll_tag),
+        priority: e[20],
+        offloaded: flags & FLAG_OFFLOADED != 0,
+        poll_count: e.read_u32_le_at(22),
+        attributes: flags & FLAG_ATTRIBUTES != 0,
+        pinned: flags & FLAG_PINNED != 0,
+        expiry: (flags & FLAG_EXPIRY != 0).then(|| e.read_i64_le_at(26)),
+        pushed_at_ms: (flags & FLAG_PUSHED_AT != 0).then(|| e.read_i64_le_at(34)),
+        group: (flags & FLAG_GROUP != 0).then(|| e.read_u64_le_at(42)),
+        compressed: flags & FLAG_COMPRESSED != 0,
+      });
+    }
+    Some(IndexState {
+      seq: raw.read_u64_le_at(8),
+      next_id: raw.read_u64_le_at(16),
+      entries,
+    })
+  }
+}
+
+// Returns false if the entry isn't a message.
+fn insert_entry(messages: &MessageShards, id: u64, e: &IndexEntry) -> bool {
+  let (visible_time, poll_tag, split) = match (e.visible_time, e.inline) {
+    (Some(visible_time), _) => (visible_time, e.poll_tag.unwrap_or(0), true),
+    (None, Some((visible_time, poll_tag))) => (visible_time, poll_tag, false),
+    // These are stale keys of a deleted message, e.g. a pin that raced with a delete.
+    (None, None) => return false,
+  };
+  // Priorities and groups must be set before messages are inserted, as they determine a message's position in the index.
+  let mut shard = messages.lock_for_insert(id, e.group);
+  shard.set_priority(id, e.priority);
+  shard.set_group(id, e.group);
+  shard.set_split(id, split);
+  shard.insert(id, visible_time, poll_tag);
+  shard.set_offloaded(id, e.offloaded);
+  shard.set_compressed(id, e.compressed);
+  shard.set_poll_count(id, e.poll_count);
+  shard.set_has_attributes(id, e.attributes);
+  shard.set_expiry(id, e.expiry);
+  shard.set_pushed_at_ms(id, e.pushed_at_ms);
+  shard.set_pinned(id, e.pinned);
+  true
+}
+
+fn snapshot_path(data_dir: &Path) -> PathBuf {
+  data_dir.join(FILE_NAME)
+}
+
+/// Loads the index from the snapshot in `data_dir`, replaying writes since it was taken from the WAL. Returns None if there's no usable snapshot or the WAL no longer has all writes since it was taken, in which case the index must be rebuilt from the database.
+pub(crate) fn load_index_snapshot(
+  db: &DB,
+  data_dir: &Path,
+  metrics: Arc<Metrics>,
+  shards: usize,
+) -> Option<LoadedData> {
+  let raw = fs::read(snapshot_path(data_dir)).ok()?;
+  let Some(mut state) = IndexState::decode(&raw) else {
+    warn!(?data_dir, "ignoring invalid index snapshot");
+    return None;
+  };
+  let latest_seq = db.latest_sequence_number();
+  // The snapshot can't be newer than the database, but check anyway in case the database was replaced.
+  if state.seq > latest_seq {
+    warn!(?data_dir, "ignoring index snapshot newer than database");
+    return None;
+  };
+  let mut replayed_until = state.seq;
+  if latest_seq > state.seq {
+    let updates = match db.get_updates_since(state.seq) {
+      Ok(u) => u,
+      Err(err) => {
+        warn!(
+          ?data_dir,
+          error = err.to_string(),
+          "cannot replay WAL since index snapshot"
+        );
+        return None;
+      }
+    };
+    for u in updates {
+      let (seq, b) = u.ok()?;
+      let data = b.data();
+      if data.len() < 12 {
+        return None;
+      };
+      let count = u64::from(data.read_u32_le_at(8));
+      // There's a gap, so some writes are no longer in the WAL.
+      if seq > replayed_until + 1 {
+        warn!(
+          ?data_dir,
+          "WAL no longer has all writes since index snapshot"
+        );
+        return None;
+      };
+      if !state.apply_batch(data) {
+        warn!(?data_dir, "cannot replay WAL since index snapshot");
+        return None;
+      };
+      replayed_until = replayed_until.max(seq + count.max(1) - 1);
+    }
+  };
+  if replayed_until < latest_seq {
+    warn!(
+      ?data_dir,
+      "WAL no longer has all writes since index snapshot"
+    );
+    return None;
+  };
+  info!(
+    ?data_dir,
+    snapshot_seq = state.seq,
+    latest_seq,
+    "loaded index from snapshot"
+  );
+  Some(state.into_loaded(metrics, shards))
+}
+
+fn write_index_snapshot(storage: &RocksDbStorage, data_dir: &Path) -> std::io::Result<()> {
+  let seq = storage.db.latest_sequence_number();
+  let state = IndexState::scan(storage, seq).map_err(std::io::Error::other)?;
+  // Writes are only flushed to the WAL periodically, so make sure everything in the snapshot is durable; otherwise after a crash the snapshot could contain messages that don't exist.
+  storage.flush().map_err(std::io::Error::other)?;
+  let raw = state.encode();
+  let path = snapshot_path(data_dir);
+  let tmp = path.with_extension("tmp");
+  let mut f = File::create(&tmp)?;
+  f.write_all(&raw)?;
+  f.sync_all()?;
+  fs::rename(tmp, path)
+}
+
+/// Writes an index snapshot every `interval` until the returned sender is dropped.
+pub(crate) fn start_index_snapshots(
+  storage: Arc<RocksDbStorage>,
+  data_dir: PathBuf,
+  interval: Duration,
+) -> oneshot::Sender<()> {
+  let (stop, mut stopped) = oneshot::channel::<()>();
+  spawn(async move {
+    loop {
+      tokio::select! {
+        _ = &mut stopped => break,
+        _ = sleep(interval) => {}
+      };
+      let storage = storage.clone();
+      let data_dir = data_dir.clone();
+      let res = spawn_blocking(move || write_index_snapshot(&storage, &data_dir))
+        .await
+        .unwrap();
+      if let Err(err) = res {
+        warn!(error = err.to_string(), "failed to write index snapshot");
+      };
+    }
+  });
+  stop
+}
+
+#[cfg(test)]
+mod tests {
+  use super::IndexEntry;
+  use super::IndexState;
+  use super::ENTRY_LEN;
+  use crate::attributes::MessageAttributeValue;
+  use crate::db::rocksdb_key;
+  use crate::db::RocksDbKeyPrefix;
+  use crate::op::poll::OpPollInput;
+  use crate::op::push::OpPushInput;
+  use crate::op::push::OpPushInputMessage;
+  use crate::storage::StorageBackend;
+  use crate::Queued;
+  use crate::QueuedCfg;
+  use off64::int::create_u64_le;
+  use rocksdb::WriteBatchWithTransaction;
+  use std::collections::BTreeMap;
+  use std::path::Path;
+
+  fn message(contents: &[u8]) -> OpPushInputMessage {
+    OpPushInputMessage {
+      contents: contents.to_vec(),
+      visibility_timeout_secs: 0,
+      visibility_jitter_secs: 0,
+      priority: 0,
+      attributes: Default::default(),
+      ttl_secs: None,
+      group_id: None,
+      external_id: None,
+    }
+  }
+
+  // Pushes messages with every optional key to a new queue, and polls some of them.
+  async fn queue() -> Queued {
+    let q = Queued::load_and_start(Path::new("/nonexistent"), QueuedCfg {
+      storage: StorageBackend::InMemory,
+      ..Default::default()
+    })
+    .await;
+    q.push(OpPushInput {
+      messages: vec![
+        message(b"plain"),
+        OpPushInputMessage {
+          priority: 3,
+          ttl_secs: Some(60),
+          group_id: Some("g".to_string()),
+          attributes: [("a".to_string(), MessageAttributeValue::Integer(1))].into(),
+          ..message(b"everything")
+        },
+        OpPushInputMessage {
+          visibility_timeout_secs: 3600,
+          ..message(b"invisible")
+        },
+        message(b"polled"),
+      ],
+    })
+    .await
+    .unwrap();
+    q.poll(OpPollInput {
+      count: 2,
+      visibility_timeout_secs: 30,
+      ignore_existing_visibility_timeouts: false,
+      prefer_group: None,
+      fields: None,
+    })
+    .await
+    .unwrap();
+    q
+  }
+
+  #[tokio::test]
+  async fn snapshots_round_trip() {
+    let q = queue().await;
+    let state = IndexState::scan(&*q.ctx.storage, 42).unwrap();
+    assert_eq!(state.entries.len(), 4);
+    assert_eq!(state.next_id, 4);
+    let e = &state.entries[&1];
+    assert_eq!(
+      (
+        e.priority,
+        e.attributes,
+        e.expiry.is_some(),
+        e.group.is_some()
+      ),
+      (3, true, true, true)
+    );
+    assert!(state.entries.values().any(|e| e.poll_count == 1));
+
+    let raw = state.encode();
+    assert_eq!(raw.len(), 32 + 4 * ENTRY_LEN);
+    let decoded = IndexState::decode(&raw).unwrap();
+    assert_eq!((decoded.seq, decoded.next_id), (42, 4));
+    assert_eq!(decoded.entries, state.entries);
+    // Reading only some messages gets the same entries.
+    assert_eq!(
+      IndexState::read(&*q.ctx.storage, &[1, 3]).unwrap().entries,
+      state
+        .entries
+        .iter()
+        .filter(|(id, _)| [1, 3].contains(*id))
+        .map(|(&id, &e)| (id, e))
+        .collect::<BTreeMap<_, _>>()
+    );
+  }
+
+  #[test]
+  fn snapshots_skip_stale_entries() {
+    let mut state = IndexState {
+      seq: 1,
+      next_id: 10,
+      entries: BTreeMap::new(),
+    };
+    state.entries.insert(3, IndexEntry {
+      inline: Some((100, 7)),
+      ..Default::default()
+    });
+    // A pin that raced with a delete.
+    state.entries.insert(5, IndexEntry {
+      pinned: true,
+      ..Default::default()
+    });
+    let decoded = IndexState::decode(&state.encode()).unwrap();
+    assert_eq!(decoded.entries.keys().collect::<Vec<_>>(), vec![&3]);
+    assert_eq!(decoded.entries[&3], state.entries[&3]);
+  }
+
+  #[tokio::test]
+  async fn rejects_malformed_snapshots() {
+    let q = queue().await;
+    let raw = IndexState::scan(&*q.ctx.storage, 1).unwrap().encode();
+    assert!(IndexState::decode(&raw[..31]).is_none());
+    assert!(IndexState::decode(&raw[..raw.len() - 1]).is_none());
+    let mut extra = raw.clone();
+    extra.push(0);
+    assert!(IndexState::decode(&extra).is_none());
+    let mut magic = raw.clone();
+    // An older version of the format.
+    magic[7] = b'3';
+    assert!(IndexState::decode(&magic).is_none());
+    let mut count = raw.clone();
+    count[24] += 1;
+    assert!(IndexState::decode(&count).is_none());
+    // A count so large that the expected length would overflow.
+    count[24..32].copy_from_slice(&create_u64_le(u64::MAX / ENTRY_LEN as u64 + 1));
+    assert!(IndexState::decode(&count).is_none());
+  }
+
+  #[test]
+  fn replays_write_batches() {
+    let mut state = IndexState {
+      seq: 0,
+      next_id: 0,
+      entries: BTreeMap::new(),
+    };
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    for id in 0..3 {
+      b.put(rocksdb_key(RocksDbKeyPrefix::MessageInline, id), [0u8; 9]);
+      b.put(rocksdb_key(RocksDbKeyPrefix::MessagePriority, id), [2]);
+    }
+    b.put(b"next_id", create_u64_le(3));
+    assert!(state.apply_batch(b.data()));
+    assert_eq!((state.entries.len(), state.next_id), (3, 3));
+
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    b.delete(rocksdb_key(RocksDbKeyPrefix::MessageInline, 0));
+    b.delete_range(
+      rocksdb_key(RocksDbKeyPrefix::MessagePriority, 1),
+      rocksdb_key(RocksDbKeyPrefix::MessagePriority, 3),
+    );
+    // Never goes backwards.
+    b.put(b"next_id", create_u64_le(1));
+    b.delete_range(&b"unrelated"[..], &b"unrelatedz"[..]);
+    assert!(state.apply_batch(b.data()));
+    assert_eq!(state.next_id, 3);
+    assert_eq!(state.entries[&0].inline, None);
+    assert_eq!(state.entries[&0].priority, 2);
+    assert_eq!(state.entries[&2].inline, Some((0, 0)));
+    assert_eq!(state.entries[&2].priority, 0);
+
+    // Ranges across prefixes can't be replayed.
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    b.delete_range(
+      rocksdb_key(RocksDbKeyPrefix::MessagePollTag, 0),
+      rocksdb_key(RocksDbKeyPrefix::MessagePriority, 0),
+    );
+    assert!(!state.apply_batch(b.data()));
+    assert!(!state.apply_batch(&[0; 11]));
+  }
+}
@@ -1,43 +1,310 @@
+pub mod attributes;
+pub mod audit;
+pub mod backup;
 pub mod batch_sync;
+pub mod breaker;
+pub mod clock;
+pub mod compression;
+pub mod cron;
 pub mod ctx;
 pub mod db;
+pub mod debug_sampler;
+pub mod error_code;
+pub mod external_id;
+pub mod fanout;
+pub mod fault;
+pub mod group;
+pub mod id_gen;
+pub mod index_check;
+mod index_snapshot;
+pub mod lifecycle;
+mod memory_storage;
+pub mod message_shards;
 pub mod messages;
 pub mod metrics;
+pub mod offload;
 pub mod op;
+pub mod processing_time;
+mod push_cap;
+pub mod quota;
+pub mod random;
+#[cfg(feature = "redb")]
+mod redb_storage;
+pub mod replica;
+pub mod replication;
+pub mod routing;
+pub mod storage;
 pub mod suspend;
 pub mod throttler;
+pub mod transform;
+pub mod webhook;
+mod write_batch;
 
+use crate::audit::read_audit_history;
+use crate::audit::stale_audit_events;
+use crate::audit::AuditEvent;
+use crate::audit::AuditLog;
 use crate::batch_sync::BatchSync;
+use crate::breaker::StorageBreaker;
+use backup::read_db_identity;
+use backup::write_snapshot;
+use backup::write_snapshot_increment;
+use backup::SnapshotIncrementError;
+use clock::Clock;
+use clock::SystemClock;
+use compression::train_dictionary;
+use compression::ContentsCodec;
+use compression::ContentsCompression;
+use compression::ContentsDictionaries;
 use ctx::Ctx;
-use db::rocksdb_load;
+use ctx::ReloadableSettings;
+use db::contents_dictionary_key;
+use db::load_default_ttl;
+use db::load_last_push_ms;
+use db::load_latest_contents_dictionary;
+use db::load_suspension;
 use db::rocksdb_open;
+use db::RocksDbTuning;
+use db::ZstdCompression;
+use db::FORMAT_VERSION;
+use db::MIN_FORMAT_VERSION;
+use debug_sampler::DebugSampler;
+use external_id::ExternalIds;
+use fanout::resolve_prepared_pushes;
+use fanout::FanoutLog;
+use fault::FaultOp;
+use futures::Stream;
+use id_gen::IdStrategy;
+use id_gen::PendingIds;
+use id_gen::MAX_SNOWFLAKE_NODE_ID;
+use index_check::check_loaded_index;
+use index_check::IndexMismatchAction;
+use index_snapshot::load_index_snapshot;
+use index_snapshot::start_index_snapshots;
+use index_snapshot::IndexState;
+use lifecycle::MessageState;
+use lifecycle::MessageTransition;
+use memory_storage::MemoryStorage;
+use messages::PollOrder;
 use metrics::Metrics;
+use offload::ContentsStore;
+use op::compact::op_compact;
+use op::compact::OpCompactOutput;
 use op::delete::op_delete;
+use op::delete::op_delete_forced;
+use op::delete::OpDeleteForcedOutput;
 use op::delete::OpDeleteInput;
 use op::delete::OpDeleteOutput;
+use op::export::op_export;
+use op::export::OpExportInput;
+use op::export::OpExportOutput;
+use op::import::op_import;
+use op::import::OpImportInput;
+use op::import::OpImportOutput;
+use op::list::op_list;
+use op::list::OpListInput;
+use op::list::OpListOutput;
+use op::nack::op_nack;
+use op::nack::OpNackInput;
+use op::nack::OpNackOutput;
+use op::peek::op_peek;
+use op::peek::op_peek_external_id;
+use op::peek::OpPeekInput;
+use op::peek::OpPeekOutput;
+use op::peek::OpPeekOutputMessage;
+use op::pin::op_pin;
+use op::pin::OpPinInput;
+use op::pin::OpPinOutput;
 use op::poll::op_poll;
+use op::poll::op_poll_stream;
 use op::poll::OpPollInput;
 use op::poll::OpPollOutput;
+use op::poll::OpPollOutputMessage;
+use op::poll::RedeliveryBackoff;
+use op::purge::op_purge;
+use op::purge::OpPurgeOutput;
 use op::push::op_push;
 use op::push::OpPushInput;
 use op::push::OpPushOutput;
+use op::result::OpError;
 use op::result::OpResult;
+use op::schedule::get_schedule;
+use op::schedule::list_schedules;
+use op::schedule::load_schedules;
+use op::schedule::op_remove_schedule;
+use op::schedule::op_run_due_schedules;
+use op::schedule::op_set_schedule;
+use op::schedule::OpSetScheduleInput;
+use op::schedule::ScheduleInfo;
+use op::schema::op_get_schema;
+use op::schema::op_list_schemas;
+use op::schema::op_register_schema;
+use op::schema::OpGetSchemaOutput;
+use op::schema::OpListSchemasOutput;
+use op::schema::OpRegisterSchemaInput;
+use op::schema::OpRegisterSchemaOutput;
+use op::scrub::op_scrub;
+use op::scrub::OpScrubInput;
+use op::scrub::OpScrubOutput;
+use op::suspend::op_set_suspension;
+use op::takeover::op_takeover;
+use op::takeover::OpTakeoverInput;
+use op::takeover::OpTakeoverOutput;
+use op::transaction::op_transaction;
+use op::transaction::OpTransactionInput;
+use op::transaction::OpTransactionOutput;
+use op::ttl::op_expire;
+use op::ttl::op_set_default_ttl;
+use op::ttl::DefaultTtlState;
+use op::ttl::OpExpireOutput;
 use op::update::op_update;
 use op::update::OpUpdateInput;
 use op::update::OpUpdateOutput;
 use parking_lot::Mutex;
+use push_cap::PushCap;
+use quota::start_usage_refresh;
+use quota::Quota;
+use quota::QuotaLimits;
+use quota::SharedQuota;
+use random::Random;
+use random::ThreadRandom;
+#[cfg(feature = "redb")]
+use redb_storage::RedbStorage;
+use replica::read_wal_since;
+use replica::send_records;
+use replica::ReplicaPosition;
+use replica::ReplicaRecords;
+use replica::ReplicaWal;
+use replica::REPLICA_POSITION_KEY;
+use replication::MaxCreatedIdFinder;
+use replication::ReplicationSequencer;
+use replication::Replicator;
+use rocksdb::WriteBatchWithTransaction;
+use routing::RoutingRule;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashSet;
+use std::num::NonZeroU64;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
+use storage::storage_load;
+use storage::Storage;
+use storage::StorageBackend;
 use suspend::SuspendState;
+use suspend::SuspensionChange;
 use throttler::Throttler;
+use tokio::sync::mpsc::channel;
+use tokio::task::spawn_blocking;
+use tracing::warn;
+use transform::PollTransform;
+use webhook::WebhookCfg;
 
 #[derive(Clone)]
 pub struct QueuedCfg {
+  /// If set, every push, poll, update, nack, takeover, and delete is recorded in an append-only history of each message, stored with the queue. Events aren't removed with their messages; use `Queued::trim_audit_log` to remove old ones.
+  pub audit_log: bool,
   pub batch_sync_delay: Duration,
+  /// Used for all timestamps, e.g. visible times, expiries, and push times. Defaults to the system clock.
+  pub clock: Arc<dyn Clock>,
+  /// Used for visibility jitter and random message IDs. Defaults to the thread-local RNG.
+  pub random: Arc<dyn Random>,
+  /// How many consecutive storage failures before the storage circuit breaker opens.
+  pub storage_breaker_threshold: u32,
+  /// How long the storage circuit breaker stays open the first time it opens. Each subsequent consecutive opening doubles this, up to `storage_breaker_max_backoff`.
+  pub storage_breaker_base_backoff: Duration,
+  pub storage_breaker_max_backoff: Duration,
+  pub replicator: Option<Arc<dyn Replicator>>,
+  /// If set, at most this many messages per second will become available for polling, even if more have reached their visibility time. Useful to protect consumers when a large number of messages become visible at once (e.g. leases expiring after an outage).
+  pub release_pacing_max_per_sec: Option<u32>,
+  /// The order that visible messages with the same priority are polled in. Defaults to `PollOrder::VisibleTime`.
+  pub poll_order: PollOrder,
+  /// If set, pushing a message with contents larger than this many bytes fails with `OpError::MessageTooLarge`.
+  pub max_message_size: Option<usize>,
+  /// Polls, updates, and takeovers with a visibility timeout shorter than this fail with `OpError::InvalidVisibilityTimeout`. Negative visibility timeouts are always invalid. Pushes and nacks aren't checked against this, as their visibility timeout is a delay that's usually zero.
+  pub min_visibility_timeout_secs: i64,
+  /// If set, pushes, polls, updates, takeovers, and nacks with a visibility timeout or delay longer than this fail with `OpError::InvalidVisibilityTimeout`.
+  pub max_visibility_timeout_secs: Option<i64>,
+  /// Messages with contents up to this many bytes are stored in a single record with their visible time and poll tag, which reduces the amount of writes and reads for each operation. Larger messages have their contents stored separately, so that polls and updates don't have to rewrite them.
+  pub inline_max_contents_len: usize,
+  /// If set, contents of messages that are at least `offload_min_contents_len` bytes are stored here instead of in RocksDB.
+  pub contents_store: Option<Arc<dyn ContentsStore>>,
+  pub offload_min_contents_len: usize,
+  /// The newest on-disk format to write, which must be between `MIN_FORMAT_VERSION` and `FORMAT_VERSION`. Use an older version to be able to roll back to an older release; features that need a newer format are disabled or not persisted.
+  pub format_version: u32,
+  /// How IDs of new messages are generated. Defaults to `IdStrategy::Sequential`.
+  pub id_strategy: IdStrategy,
+  /// If set, a compact snapshot of the in-memory index is written to the data dir at this interval, so that restarting only needs to replay writes since the last snapshot instead of scanning the entire database. WAL files are kept for twice this interval (plus 10 minutes), which uses more disk space.
+  pub index_snapshot_interval: Option<Duration>,
+  /// If set, WAL files are kept for at least this long, so that incremental snapshots can be created relative to snapshots taken up to this long ago. This uses more disk space, in proportion to the amount of writes.
+  pub snapshot_wal_retention: Option<Duration>,
+  /// If set, data on disk is compressed using zstd, optionally with dictionaries trained from each queue's data. Only RocksDB storage is compressed.
+  pub zstd_compression: Option<ZstdCompression>,
+  /// If set, message contents are compressed before being stored, wherever they're stored, and decompressed when read. Requires on-disk format version 10.
+  pub contents_compression: Option<ContentsCompression>,
+  /// The in-memory index is split into this many independently locked shards, so that concurrent operations contend less. With more than one shard, priorities and visible times only order polls within each shard, so messages may be polled slightly out of order. Defaults to 1.
+  pub index_shards: usize,
+  /// If set, the index is checked against storage whenever it's loaded, and this is done if they don't match. This catches corruption and bugs before they cause messages to be lost silently, but takes about as long as loading without an index snapshot.
+  pub verify_index: Option<IndexMismatchAction>,
+  /// Required for the queue to take part in multi-queue pushes (see `FanoutLog`). If set, prepared multi-queue pushes are applied or discarded when the queue is loaded; otherwise, they're left as is.
+  pub fanout_log: Option<Arc<FanoutLog>>,
+  pub storage: StorageBackend,
+  /// Only applies to RocksDB storage.
+  pub rocksdb: RocksDbTuning,
+  /// If set, while a compaction, snapshot, or scrub is running, pushes are capped at this percentage (1 to 100) of the push rate before it started, and pushes over the cap fail with `OpError::Throttled`.
+  pub maintenance_push_cap_percent: Option<u8>,
+  /// Limits on this queue's storage. Pushes that would exceed them fail with `OpError::QueueFull`.
+  pub quota: QuotaLimits,
+  /// Limits on the combined storage of every queue given the same `SharedQuota`, e.g. all queues on a server or of a tenant. Pushes fail if they would exceed any of them.
+  pub shared_quotas: Vec<Arc<SharedQuota>>,
+  /// The recommended visibility timeout is the 99th percentile of how long consumers took to process messages multiplied by this, to leave room for slower than usual processing. Defaults to 2.
+  pub recommended_visibility_timeout_factor: f64,
+  /// If set, messages that are polled again become visible later each time, on top of the poll's visibility timeout.
+  pub redelivery_backoff: Option<RedeliveryBackoff>,
+}
+
+impl Default for QueuedCfg {
+  fn default() -> Self {
+    Self {
+      audit_log: false,
+      batch_sync_delay: Duration::from_millis(10),
+      clock: Arc::new(SystemClock),
+      random: Arc::new(ThreadRandom),
+      storage_breaker_threshold: 5,
+      storage_breaker_base_backoff: Duration::from_millis(250),
+      storage_breaker_max_backoff: Duration::from_secs(60),
+      replicator: None,
+      release_pacing_max_per_sec: None,
+      poll_order: PollOrder::VisibleTime,
+      max_message_size: None,
+      min_visibility_timeout_secs: 0,
+      max_visibility_timeout_secs: None,
+      inline_max_contents_len: 1024,
+      contents_store: None,
+      offload_min_contents_len: 1024 * 1024,
+      format_version: FORMAT_VERSION,
+      id_strategy: IdStrategy::Sequential,
+      index_snapshot_interval: None,
+      snapshot_wal_retention: None,
+      index_shards: 1,
+      zstd_compression: None,
+      contents_compression: None,
+      verify_index: None,
+      fanout_log: None,
+      storage: StorageBackend::RocksDb,
+      rocksdb: RocksDbTuning::default(),
+      maintenance_push_cap_percent: None,
+      recommended_visibility_timeout_factor: 2.0,
+      quota: QuotaLimits::default(),
+      shared_quotas: Vec::new(),
+      redelivery_backoff: None,
+    }
+  }
 }
 
 // This is intentionally not cheaply cloneable to make it clear and explicit that dropping this will safely close the database and free all resources.
@@ -45,55 +312,709 @@ pub struct Queued {
   ctx: Ctx,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct ThrottleState {
   max_polls_per_time_window: u64,
   time_window_sec: i64,
 }
 
+impl ThrottleState {
+  /// Throttles must have a time window of more than one second.
+  pub fn is_valid(&self) -> bool {
+    self.time_window_sec > 1
+  }
+}
+
+/// Whether a queue has no outstanding work, e.g. to determine if a batch pipeline stage is complete.
+#[derive(Serialize, Deserialize)]
+pub struct QueueQuiescence {
+  /// Whether any message is currently visible.
+  pub visible: bool,
+  /// Whether any message has been polled and hasn't been deleted or become visible again, or is currently being polled, updated, or deleted.
+  pub in_flight: bool,
+  /// When a push was last accepted, in milliseconds since the epoch. None if nothing has been pushed since this was first tracked.
+  pub last_push_ms: Option<i64>,
+}
+
+/// When consumers of a queue next need to poll, e.g. so that schedulers can wake them instead of polling an idle queue.
+#[derive(Serialize, Deserialize)]
+pub struct VisibilityWatermark {
+  /// Whether any message is currently visible, in which case consumers should poll now.
+  pub visible_now: bool,
+  /// The earliest time, in seconds since the epoch, at which a message that isn't visible yet becomes visible. None if there are no such messages.
+  pub next_visible_time: Option<i64>,
+}
+
+/// Copies every `every_nth` polled message that has been polled at least `min_poll_count` times into `debug_queue`.
+#[derive(Serialize, Deserialize)]
+pub struct DebugSamplingState {
+  pub debug_queue: String,
+  pub min_poll_count: u32,
+  pub every_nth: NonZeroU64,
+}
+
 impl Queued {
   pub async fn load_and_start(data_dir: &Path, cfg: QueuedCfg) -> Self {
     let metrics = Arc::new(Metrics::default());
 
-    let db = rocksdb_open(data_dir);
-    let data = rocksdb_load(&db, metrics.clone());
+    assert!(
+      (MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&cfg.format_version),
+      "unsupported on-disk format version {}",
+      cfg.format_version
+    );
+    assert!(
+      cfg.contents_store.is_none() || cfg.format_version >= 2,
+      "offloading contents requires on-disk format version 2 or newer"
+    );
+    assert!(
+      cfg.id_strategy != IdStrategy::Random || cfg.format_version >= 9,
+      "random IDs require on-disk format version 9 or newer"
+    );
+    assert!(
+      cfg.contents_compression.is_none() || cfg.format_version >= 10,
+      "contents compression requires on-disk format version 10 or newer"
+    );
+    if let Some(c) = cfg.contents_compression.filter(|c| c.dictionary_bytes > 0) {
+      assert!(
+        c.codec == ContentsCodec::Zstd,
+        "contents compression dictionaries require zstd"
+      );
+      assert!(
+        cfg.format_version >= 11,
+        "contents compression dictionaries require on-disk format version 11 or newer"
+      );
+    };
+    if let IdStrategy::Snowflake { node_id } = cfg.id_strategy {
+      assert!(
+        node_id <= MAX_SNOWFLAKE_NODE_ID,
+        "Snowflake node ID must be at most {MAX_SNOWFLAKE_NODE_ID}"
+      );
+    };
+    // Index snapshots are loaded by replaying the WAL since they were taken, so old WAL files must be kept until there's a newer snapshot, with some leeway for restarts. Incremental snapshots similarly contain the writes since the previous snapshot.
+    let wal_retention = [
+      cfg
+        .index_snapshot_interval
+        .map(|i| i * 2 + Duration::from_secs(600)),
+      cfg.snapshot_wal_retention,
+    ]
+    .into_iter()
+    .flatten()
+    .max();
+    let (storage, data, index_snapshots): (Arc<dyn Storage>, _, _) = match cfg.storage {
+      StorageBackend::RocksDb => {
+        let storage = Arc::new(rocksdb_open(
+          data_dir,
+          cfg.format_version,
+          wal_retention,
+          cfg.zstd_compression,
+          &cfg.rocksdb,
+        ));
+        if let Some(log) = &cfg.fanout_log {
+          resolve_prepared_pushes(&*storage, log);
+        };
+        let data = cfg
+          .index_snapshot_interval
+          .and_then(|_| {
+            load_index_snapshot(&storage.db, data_dir, metrics.clone(), cfg.index_shards)
+          })
+          .unwrap_or_else(|| storage_load(&*storage, metrics.clone(), cfg.index_shards));
+        let index_snapshots = cfg
+          .index_snapshot_interval
+          .map(|i| start_index_snapshots(storage.clone(), data_dir.to_path_buf(), i));
+        (storage, data, index_snapshots)
+      }
+      StorageBackend::InMemory => {
+        assert!(
+          cfg.index_snapshot_interval.is_none(),
+          "index snapshots require RocksDB storage"
+        );
+        let storage = Arc::new(MemoryStorage::new(cfg.format_version));
+        let data = storage_load(&*storage, metrics.clone(), cfg.index_shards);
+        (storage, data, None)
+      }
+      #[cfg(feature = "redb")]
+      StorageBackend::Redb => {
+        assert!(
+          cfg.index_snapshot_interval.is_none(),
+          "index snapshots require RocksDB storage"
+        );
+        let storage = Arc::new(RedbStorage::open(data_dir, cfg.format_version));
+        if let Some(log) = &cfg.fanout_log {
+          resolve_prepared_pushes(&*storage, log);
+        };
+        let data = storage_load(&*storage, metrics.clone(), cfg.index_shards);
+        (storage, data, None)
+      }
+    };
+    data.messages.set_loaded_at(cfg.clock.now());
+    data.messages.set_poll_order(cfg.poll_order);
+    data
+      .messages
+      .set_release_pacing(cfg.release_pacing_max_per_sec);
+
+    let suspension = Arc::new(SuspendState::default());
+    suspension.set_bits(load_suspension(&*storage));
+    check_loaded_index(
+      data_dir,
+      &*storage,
+      &data.messages,
+      cfg.verify_index,
+      &suspension,
+    );
+
+    let default_ttl_secs = load_default_ttl(&*storage);
+    let last_push_ms = load_last_push_ms(&*storage);
+    let schedules = load_schedules(&*storage);
+    let contents_dictionaries = ContentsDictionaries::new(
+      cfg.contents_compression.map_or(0, |c| c.dictionary_bytes),
+      load_latest_contents_dictionary(&*storage).unwrap(),
+    );
+    let external_ids = ExternalIds::load(&*storage, &data.messages);
+    let usage_refresh =
+      start_usage_refresh(storage.clone(), metrics.clone(), cfg.shared_quotas.clone());
+    let reloadable = ReloadableSettings::from(&cfg);
+    let replication = cfg
+      .replicator
+      .map(|r| Arc::new(ReplicationSequencer::new(r)));
 
     let ctx = Ctx {
-      // We can safely create a strong reference clone to the database, as BatchSync's background thread will stop once the channel sender is dropped, which will then drop the DB.
-      batch_sync: BatchSync::start(cfg.batch_sync_delay, db.clone(), data.next_id),
-      db,
-      messages: Mutex::new(data.messages),
+      audit_log: cfg.audit_log.then(AuditLog::default),
+      // We can safely create a strong reference clone to the storage, as BatchSync's background thread will stop once the channel sender is dropped, which will then drop the storage.
+      batch_sync: BatchSync::start(
+        cfg.batch_sync_delay,
+        storage.clone(),
+        replication.clone(),
+        data.next_id,
+      ),
+      breaker: StorageBreaker::new(
+        cfg.storage_breaker_threshold,
+        cfg.storage_breaker_base_backoff,
+        cfg.storage_breaker_max_backoff,
+      ),
+      busy_ops: AtomicUsize::new(0),
+      clock: cfg.clock,
+      contents_compression: cfg.contents_compression,
+      contents_dictionaries,
+      contents_store: cfg.contents_store,
+      data_dir: data_dir.to_path_buf(),
+      debug_sampler: Mutex::new(None),
+      default_ttl_secs: Mutex::new(default_ttl_secs),
+      external_ids,
+      fanout_log: cfg.fanout_log,
+      format_version: cfg.format_version,
+      id_strategy: cfg.id_strategy,
+      _index_snapshots: index_snapshots,
+      inline_max_contents_len: cfg.inline_max_contents_len,
+      known_schema_versions: Mutex::new(HashSet::new()),
+      last_push_ms: Mutex::new(last_push_ms),
+      maintenance_ops: AtomicUsize::new(0),
+      messages: data.messages,
+      auto_visibility_timeout: AtomicBool::new(false),
       metrics,
       next_id: AtomicU64::new(data.next_id),
-      suspension: Arc::new(SuspendState::default()),
+      pending_ids: PendingIds::default(),
+      poll_order: cfg.poll_order,
+      poll_transform: Mutex::new(None),
+      push_cap: cfg
+        .maintenance_push_cap_percent
+        .map(|p| Mutex::new(PushCap::new(p))),
+      quota: Quota {
+        limits: cfg.quota,
+        shared: cfg.shared_quotas,
+      },
+      random: cfg.random,
+      offload_min_contents_len: cfg.offload_min_contents_len,
+      recommended_visibility_timeout_factor: cfg.recommended_visibility_timeout_factor,
+      reloadable: Mutex::new(reloadable),
+      replica_index: Mutex::new(None),
+      replication,
+      routing_rules: Mutex::new(Vec::new()),
+      schedule_ops: tokio::sync::Mutex::new(()),
+      schedules: Mutex::new(schedules),
+      schema_registration: tokio::sync::Mutex::new(()),
+      storage,
+      suspension,
+      suspension_ops: tokio::sync::Mutex::new(()),
       throttler: Mutex::new(None),
+      _usage_refresh: usage_refresh,
+      verify_index: cfg.verify_index,
+      webhook: Mutex::new(None),
     };
 
     Self { ctx }
   }
 
+  /// Returns the audit log events of message `id`, oldest first, including those from before it was deleted. Returns `OpError::AuditLogDisabled` if the audit log isn't enabled (see `QueuedCfg::audit_log`).
+  pub async fn audit_history(&self, id: u64) -> OpResult<Vec<AuditEvent>> {
+    if self.ctx.audit_log.is_none() {
+      return Err(OpError::AuditLogDisabled);
+    };
+    let storage = self.ctx.storage.clone();
+    let res = spawn_blocking(move || read_audit_history(&*storage, id))
+      .await
+      .unwrap();
+    self.ctx.record_storage_read_result(res)
+  }
+
+  /// Trains a new contents compression dictionary from recently pushed contents, if dictionaries are enabled (see `ContentsCompression::dictionary_bytes`) and the contents sampled since the last one was trained would fill a new training sample. New contents are then compressed with it. Returns whether a dictionary was trained. This should be called periodically, and only on the node that accepts writes.
+  pub async fn train_contents_dictionary(&self) -> OpResult<bool> {
+    let dictionaries = &self.ctx.contents_dictionaries;
+    let _training = dictionaries.training.lock().await;
+    let Some((samples, sample_lens)) = dictionaries.take_training_samples() else {
+      return Ok(false);
+    };
+    let capacity = dictionaries.dictionary_bytes();
+    let raw = match spawn_blocking(move || train_dictionary(&samples, &sample_lens, capacity))
+      .await
+      .unwrap()
+    {
+      Ok(raw) => raw,
+      // Recent contents may have too little in common, in which case the current dictionary is kept.
+      Err(err) => {
+        warn!(error = err, "failed to train contents dictionary");
+        return Ok(false);
+      }
+    };
+    // Another node may have trained dictionaries while it was the leader, so only storage knows the latest ID.
+    let storage = self.ctx.storage.clone();
+    let res = spawn_blocking(move || load_latest_contents_dictionary(&*storage))
+      .await
+      .unwrap();
+    let id = self
+      .ctx
+      .record_storage_read_result(res)?
+      .map_or(1, |(id, _)| id + 1);
+    let mut b = WriteBatchWithTransaction::default();
+    b.put(contents_dictionary_key(id), &raw);
+    self.ctx.db_write(b).await?;
+    // Contents compressed with the dictionary must never be durable without it.
+    self.ctx.db_sync(0).await?;
+    dictionaries.insert(id, &raw, true);
+    Ok(true)
+  }
+
+  /// Removes audit log events from before `before_ms` (in milliseconds since the epoch), as they're otherwise kept forever. Returns how many were removed. This should be called periodically, and only on the node that accepts writes.
+  pub async fn trim_audit_log(&self, before_ms: i64) -> OpResult<usize> {
+    let storage = self.ctx.storage.clone();
+    let res = spawn_blocking(move || stale_audit_events(&*storage, before_ms))
+      .await
+      .unwrap();
+    let b = self.ctx.record_storage_read_result(res)?;
+    let n = b.len();
+    if n > 0 {
+      self.ctx.db_write(b).await?;
+    };
+    Ok(n)
+  }
+
+  /// Compacts all message keys on disk, removing tombstones left behind by deleted messages, which otherwise slow down iteration until RocksDB gets around to compacting them. This can take a while and uses a lot of I/O, so pushes are capped while it runs if a maintenance push cap is configured.
+  pub async fn compact(&self) -> OpResult<OpCompactOutput> {
+    op_compact(&self.ctx).await
+  }
+
   pub async fn delete(&self, input: OpDeleteInput) -> OpResult<OpDeleteOutput> {
-    op_delete(&self.ctx, input).await
+    fault::scope(
+      FaultOp::Delete,
+      op_delete(&self.ctx, input, MessageTransition::Delete),
+    )
+    .await
+  }
+
+  /// Deletes messages that have been moved to a dead letter queue. This is the same as `delete`, except that they're counted as dead lettered.
+  pub async fn dead_letter(&self, input: OpDeleteInput) -> OpResult<OpDeleteOutput> {
+    op_delete(&self.ctx, input, MessageTransition::DeadLetter).await
+  }
+
+  /// Deletes message `id` regardless of its poll tag, i.e. whether or not it's in flight and who polled it, for operators removing a bad message. Returns `OpError::MessageNotFound` if it doesn't exist or is currently being polled or updated. Forced deletes are audited and counted separately from regular deletes.
+  pub async fn delete_forced(&self, id: u64) -> OpResult<OpDeleteForcedOutput> {
+    fault::scope(FaultOp::Delete, op_delete_forced(&self.ctx, id)).await
+  }
+
+  /// Deletes all messages whose TTL has passed, except those that are pinned or currently being polled or updated. This should be called periodically, and only on the node that accepts writes.
+  pub async fn expire(&self) -> OpResult<OpExpireOutput> {
+    op_expire(&self.ctx).await
+  }
+
+  pub async fn export(&self, input: OpExportInput) -> OpResult<OpExportOutput> {
+    op_export(&self.ctx, input).await
+  }
+
+  /// Waits until all writes so far are durably persisted. Operations already do this before returning, so this is only useful before exiting to also persist background writes.
+  pub async fn flush(&self) -> OpResult<()> {
+    self.ctx.db_sync(0).await
+  }
+
+  pub async fn get_schema(&self, version: u32) -> OpResult<OpGetSchemaOutput> {
+    op_get_schema(&self.ctx, version).await
+  }
+
+  pub async fn list_schemas(&self) -> OpResult<OpListSchemasOutput> {
+    op_list_schemas(&self.ctx).await
+  }
+
+  pub async fn import(&self, input: OpImportInput) -> OpResult<OpImportOutput> {
+    op_import(&self.ctx, input).await
+  }
+
+  pub async fn list(&self, input: OpListInput) -> OpResult<OpListOutput> {
+    op_list(&self.ctx, input).await
+  }
+
+  pub async fn nack(&self, input: OpNackInput) -> OpResult<OpNackOutput> {
+    op_nack(&self.ctx, input).await
+  }
+
+  pub async fn peek(&self, input: OpPeekInput) -> OpResult<OpPeekOutput> {
+    op_peek(&self.ctx, input).await
+  }
+
+  /// Returns the message pushed with `external_id`, if it still exists.
+  pub async fn peek_external_id(&self, external_id: String) -> OpResult<OpPeekOutputMessage> {
+    op_peek_external_id(&self.ctx, external_id).await
+  }
+
+  pub async fn pin(&self, input: OpPinInput) -> OpResult<OpPinOutput> {
+    op_pin(&self.ctx, input).await
   }
 
   pub async fn poll(&self, input: OpPollInput) -> OpResult<OpPollOutput> {
-    op_poll(&self.ctx, input).await
+    fault::scope(FaultOp::Poll, op_poll(&self.ctx, input)).await
+  }
+
+  /// Polls like `poll`, but yields each message as soon as it's been read, in no particular order. This reduces the time to the first message and the memory used by large polls. The stream only starts once the poll has been committed; if reading a message fails, the remaining messages are still polled.
+  pub async fn poll_stream(
+    &self,
+    input: OpPollInput,
+  ) -> OpResult<impl Stream<Item = OpResult<OpPollOutputMessage>> + Send + '_> {
+    op_poll_stream(&self.ctx, input).await
+  }
+
+  /// Deletes all messages except those that are pinned or currently being polled or updated.
+  pub async fn purge(&self) -> OpResult<OpPurgeOutput> {
+    op_purge(&self.ctx).await
   }
 
   pub async fn push(&self, input: OpPushInput) -> OpResult<OpPushOutput> {
-    op_push(&self.ctx, input).await
+    fault::scope(FaultOp::Push, op_push(&self.ctx, input)).await
+  }
+
+  /// Stores a schema under the next version, or returns the existing version if the same schema has already been registered.
+  pub async fn register_schema(
+    &self,
+    input: OpRegisterSchemaInput,
+  ) -> OpResult<OpRegisterSchemaOutput> {
+    op_register_schema(&self.ctx, input).await
+  }
+
+  /// Creates or replaces a schedule that pushes a message at times matching a cron expression. Schedules only run when `run_due_schedules` is called.
+  pub async fn set_schedule(
+    &self,
+    name: &str,
+    input: OpSetScheduleInput,
+  ) -> OpResult<ScheduleInfo> {
+    op_set_schedule(&self.ctx, name, input).await
+  }
+
+  pub async fn remove_schedule(&self, name: &str) -> OpResult<()> {
+    op_remove_schedule(&self.ctx, name).await
+  }
+
+  pub fn get_schedule(&self, name: &str) -> Option<ScheduleInfo> {
+    get_schedule(&self.ctx, name)
+  }
+
+  /// Ordered by name.
+  pub fn list_schedules(&self) -> Vec<ScheduleInfo> {
+    list_schedules(&self.ctx)
+  }
+
+  /// Pushes the messages of schedules that are due, and returns how many were pushed. Call this about once a second, and only on the leader if replicated.
+  pub async fn run_due_schedules(&self) -> usize {
+    op_run_due_schedules(&self.ctx).await
+  }
+
+  /// Checks that all messages in storage are intact and consistent, which can take a while for large queues. Corrupt messages are reported, and quarantined if requested.
+  pub async fn scrub(&self, input: OpScrubInput) -> OpResult<OpScrubOutput> {
+    op_scrub(&self.ctx, input).await
+  }
+
+  /// Takes over an in-flight message whose lease is old enough that its holder is presumed gone, giving it a new poll tag and visibility timeout without waiting for the current one to expire.
+  pub async fn takeover(&self, input: OpTakeoverInput) -> OpResult<OpTakeoverOutput> {
+    op_takeover(&self.ctx, input).await
+  }
+
+  /// Deletes and pushes messages atomically, e.g. so that a pipeline stage can't lose or duplicate work if it crashes while handing off. Both must be on this queue, as other queues have separate storage.
+  pub async fn transaction(&self, input: OpTransactionInput) -> OpResult<OpTransactionOutput> {
+    op_transaction(&self.ctx, input).await
   }
 
   pub async fn update(&self, input: OpUpdateInput) -> OpResult<OpUpdateOutput> {
-    op_update(&self.ctx, input).await
+    fault::scope(FaultOp::Update, op_update(&self.ctx, input)).await
+  }
+
+  /// Applies a write batch received from a `Replicator` on another node. The in-memory index is not updated; call `reload_index` before serving operations from this queue.
+  pub async fn apply_replicated_batch(&self, data: Vec<u8>) -> OpResult<()> {
+    let b = WriteBatchWithTransaction::<false>::from_data(&data);
+    let mut finder = MaxCreatedIdFinder::default();
+    b.iterate(&mut finder);
+    self.ctx.db_write_local(b).await?;
+    let new_next_id = finder.max_id.map_or(0, |id| id + 1);
+    self.ctx.next_id.fetch_max(new_next_id, Ordering::Relaxed);
+    self.ctx.db_sync_local(new_next_id).await
+  }
+
+  /// Rebuilds the in-memory index from storage, e.g. after applying replicated batches.
+  pub fn reload_index(&self) {
+    let mut next_id = 0;
+    // Loaded first, as verifying the index may suspend operations.
+    self
+      .ctx
+      .suspension
+      .set_bits(load_suspension(&*self.ctx.storage));
+    self.ctx.messages.replace(|| {
+      self.ctx.metrics.message_counter.store(0, Ordering::Relaxed);
+      let data = storage_load(
+        &*self.ctx.storage,
+        self.ctx.metrics.clone(),
+        self.ctx.messages.count(),
+      );
+      data.messages.set_loaded_at(self.ctx.clock.now());
+      data.messages.set_poll_order(self.ctx.poll_order);
+      data
+        .messages
+        .set_release_pacing(self.ctx.reloadable.lock().release_pacing_max_per_sec);
+      check_loaded_index(
+        &self.ctx.data_dir,
+        &*self.ctx.storage,
+        &data.messages,
+        self.ctx.verify_index,
+        &self.ctx.suspension,
+      );
+      next_id = data.next_id;
+      data.messages
+    });
+    self.ctx.next_id.fetch_max(next_id, Ordering::Relaxed);
+    self.reload_settings();
+  }
+
+  // Reloads state other than messages that can be changed by writes from another node.
+  fn reload_settings(&self) {
+    *self.ctx.default_ttl_secs.lock() = load_default_ttl(&*self.ctx.storage);
+    *self.ctx.last_push_ms.lock() = load_last_push_ms(&*self.ctx.storage);
+    *self.ctx.schedules.lock() = load_schedules(&*self.ctx.storage);
+  }
+
+  /// Applies raw write batches from the primary's `replica_records` or `replica_wal_since`, updating only the affected messages in the in-memory index, unlike `reload_index`. This must only be used on a read-only replica, as it assumes nothing else writes to this queue.
+  pub async fn apply_replica_batches(&self, batches: Vec<Vec<u8>>) -> OpResult<()> {
+    if self.ctx.replica_index.lock().is_none() {
+      let storage = self.ctx.storage.clone();
+      let res = spawn_blocking(move || IndexState::scan(&*storage, 0))
+        .await
+        .unwrap();
+      let state = self.ctx.record_storage_read_result(res)?;
+      self.ctx.replica_index.lock().get_or_insert(state);
+    };
+    let mut reload = false;
+    for data in batches {
+      self
+        .ctx
+        .db_write_local(WriteBatchWithTransaction::from_data(&data))
+        .await?;
+      let mut state = self.ctx.replica_index.lock();
+      let Some(s) = state.as_mut() else {
+        continue;
+      };
+      if s.apply_batch_to_index(&data, &self.ctx.messages) {
+        self.ctx.next_id.fetch_max(s.next_id(), Ordering::Relaxed);
+      } else {
+        // The index will be rebuilt from storage once all batches have been written.
+        *state = None;
+        reload = true;
+      };
+    }
+    self.ctx.db_sync(0).await?;
+    if reload {
+      self.reload_index();
+    } else {
+      self
+        .ctx
+        .suspension
+        .set_bits(load_suspension(&*self.ctx.storage));
+      self.reload_settings();
+    };
+    Ok(())
+  }
+
+  /// How far this read-only replica has caught up with its primary, or None if it hasn't started copying from it.
+  pub async fn replica_position(&self) -> OpResult<Option<ReplicaPosition>> {
+    let raw = self.ctx.db_get(REPLICA_POSITION_KEY).await?;
+    Ok(raw.and_then(|raw| ReplicaPosition::decode(&raw)))
+  }
+
+  pub async fn set_replica_position(&self, pos: &ReplicaPosition) -> OpResult<()> {
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    b.put(REPLICA_POSITION_KEY, pos.encode());
+    self.ctx.db_write_local(b).await?;
+    self.ctx.db_sync(0).await
+  }
+
+  /// The identity of this queue's database, which a replica uses to detect that its primary's queue has been replaced.
+  pub fn db_identity(&self) -> Result<String, String> {
+    read_db_identity(&self.ctx.data_dir).map(|raw| String::from_utf8_lossy(&raw).trim().to_string())
+  }
+
+  /// Starts copying every record for a new replica, which receives them as raw write batches to pass to `apply_replica_batches`. Also returns the sequence number to pass to `replica_wal_since` afterwards, which may repeat some of the copied writes.
+  pub fn replica_records(&self) -> Result<(u64, ReplicaRecords), String> {
+    let Some(seq) = self.ctx.storage.latest_sequence_number() else {
+      return Err("replicas require RocksDB storage".to_string());
+    };
+    let (tx, rx) = channel(4);
+    let storage = self.ctx.storage.clone();
+    spawn_blocking(move || {
+      if let Err(err) = send_records(&*storage, &tx) {
+        // The receiver may have been dropped, which is fine.
+        let _ = tx.blocking_send(Err(err));
+      };
+    });
+    Ok((seq, rx))
+  }
+
+  /// Returns the raw write batches after sequence number `since` for a replica, up to about `max_bytes`, and the sequence number to pass next time. Returns None if some of the writes are no longer in the WAL (see `QueuedCfg::snapshot_wal_retention`), in which case the replica must copy all records again.
+  pub async fn replica_wal_since(
+    &self,
+    since: u64,
+    max_bytes: usize,
+  ) -> Result<Option<ReplicaWal>, String> {
+    let storage = self.ctx.storage.clone();
+    spawn_blocking(move || read_wal_since(&*storage, since, max_bytes))
+      .await
+      .unwrap()
+  }
+
+  /// Creates a consistent point-in-time copy of this queue's storage in `dir`, which must not exist. The copy can be used as a data dir for `Queued::load_and_start`. Files are hard linked where possible, so `dir` should be on the same filesystem for the snapshot to be fast and use little space.
+  pub async fn snapshot(&self, dir: PathBuf) -> Result<(), String> {
+    let _maintenance = self.ctx.begin_maintenance();
+    let storage = self.ctx.storage.clone();
+    let data_dir = self.ctx.data_dir.clone();
+    spawn_blocking(move || write_snapshot(&*storage, &data_dir, &dir))
+      .await
+      .unwrap()
+  }
+
+  /// Like `snapshot`, but only writes the writes since the snapshot in `base`, which must have been created by `snapshot` or this method and be recent enough for the WAL to still have all writes since then (see `QueuedCfg::snapshot_wal_retention`). Restore it using `apply_snapshot_increment`.
+  pub async fn snapshot_increment(
+    &self,
+    dir: PathBuf,
+    base: PathBuf,
+  ) -> Result<(), SnapshotIncrementError> {
+    let _maintenance = self.ctx.begin_maintenance();
+    let storage = self.ctx.storage.clone();
+    let data_dir = self.ctx.data_dir.clone();
+    spawn_blocking(move || write_snapshot_increment(&*storage, &data_dir, &base, &dir))
+      .await
+      .unwrap()
+  }
+
+  pub fn quiescence(&self) -> QueueQuiescence {
+    let now = self.ctx.clock.now();
+    let shards = self.ctx.messages.lock_all();
+    QueueQuiescence {
+      visible: shards
+        .iter()
+        .any(|s| s.youngest_time().is_some_and(|t| t <= now)),
+      in_flight: shards.iter().any(|s| s.has_in_flight(now))
+        || self.ctx.busy_ops.load(Ordering::Relaxed) > 0,
+      last_push_ms: *self.ctx.last_push_ms.lock(),
+    }
+  }
+
+  pub fn visibility_watermark(&self) -> VisibilityWatermark {
+    let now = self.ctx.clock.now();
+    let shards = self.ctx.messages.lock_all();
+    VisibilityWatermark {
+      visible_now: shards
+        .iter()
+        .any(|s| s.youngest_time().is_some_and(|t| t <= now)),
+      next_visible_time: shards.iter().filter_map(|s| s.next_visible_time(now)).min(),
+    }
   }
 
   pub fn youngest_message_time(&self) -> Option<i64> {
-    self.ctx.messages.lock().youngest_time()
+    self.ctx.messages.youngest_time()
   }
 
   pub fn oldest_message_time(&self) -> Option<i64> {
-    self.ctx.messages.lock().oldest_time()
+    self.ctx.messages.oldest_time()
+  }
+
+  /// Returns the current poll tag of a message if it has been polled exactly `poll_count` times. Useful for APIs that identify a received message by its poll count, as the poll tag also changes on updates.
+  pub fn poll_tag_if_poll_count_matches(&self, id: u64, poll_count: u32) -> Option<u32> {
+    let messages = self.ctx.messages.lock(id);
+    if messages.poll_count(id) != poll_count {
+      return None;
+    };
+    messages.poll_tag(id)
+  }
+
+  /// Messages currently being polled or updated are reported as vacant, as are deleted messages, which aren't remembered.
+  pub fn message_state(&self, id: u64) -> MessageState {
+    self.ctx.messages.lock(id).state(id, self.ctx.clock.now())
+  }
+
+  /// How many messages can be polled now, which excludes those held back by release pacing or waiting behind an earlier message in their group.
+  pub fn visible_message_count(&self) -> usize {
+    self.ctx.messages.available_count(self.ctx.clock.now())
+  }
+
+  /// The earliest visible time of messages that can be polled now, in seconds since the epoch, which is when the longest waiting one became visible. None if no message can be polled.
+  pub fn oldest_visible_message_time(&self) -> Option<i64> {
+    self
+      .ctx
+      .messages
+      .earliest_available_time(self.ctx.clock.now())
+  }
+
+  pub fn in_flight_message_count(&self) -> usize {
+    self.ctx.messages.in_flight_count(self.ctx.clock.now())
+  }
+
+  /// The current cap on pushes in messages per second, if maintenance is running and `QueuedCfg::maintenance_push_cap_percent` is set.
+  pub fn maintenance_push_cap(&self) -> Option<u64> {
+    self.ctx.push_cap.as_ref()?.lock().active_cap()
+  }
+
+  pub fn pinned_message_count(&self) -> usize {
+    self.ctx.messages.pinned_count()
+  }
+
+  /// Whether the message has been pinned using `Queued::pin`, which excludes it from bulk removal. Messages that don't exist aren't pinned.
+  pub fn is_message_pinned(&self, id: u64) -> bool {
+    self.ctx.messages.lock(id).is_pinned(id)
+  }
+
+  /// Returns false if the storage circuit breaker is currently open due to repeated storage failures.
+  pub fn is_storage_available(&self) -> bool {
+    !self.ctx.breaker.is_open()
+  }
+
+  /// Reads from storage to check that it's working. Like any other read, this bypasses the storage circuit breaker, so it neither uses up the breaker's probe nor keeps it open.
+  pub async fn check_storage(&self) -> OpResult<()> {
+    self.ctx.db_get("next_id").await.map(|_| ())
+  }
+
+  /// Applies the maximum message size, visibility timeout bounds, release pacing, and redelivery backoff in `cfg`, which may differ from those the queue was loaded with. Other settings can only be changed by loading the queue again, and are ignored.
+  pub fn reload_cfg(&self, cfg: &QueuedCfg) {
+    let settings = ReloadableSettings::from(cfg);
+    *self.ctx.reloadable.lock() = settings;
+    self
+      .ctx
+      .messages
+      .set_release_pacing(settings.release_pacing_max_per_sec);
+  }
+
+  /// How many writes are waiting to be synced to storage.
+  pub fn sync_backlog(&self) -> usize {
+    self.ctx.batch_sync.backlog()
   }
 
   pub fn metrics(&self) -> &Arc<Metrics> {
@@ -104,6 +1025,11 @@ impl Queued {
     self.ctx.suspension.clone()
   }
 
+  /// Persists the change, unlike changing `suspension()` directly.
+  pub async fn set_suspension(&self, change: SuspensionChange) -> OpResult<()> {
+    op_set_suspension(&self.ctx, change).await
+  }
+
   pub fn get_throttle_state(&self) -> Option<ThrottleState> {
     let throttler = self.ctx.throttler.lock();
     throttler.as_ref().map(|t| ThrottleState {
@@ -116,4 +1042,88 @@ impl Queued {
     *self.ctx.throttler.lock() =
       t.map(|t| Throttler::new(t.max_polls_per_time_window, t.time_window_sec));
   }
+
+  /// The visibility timeout that polls should use, based on how long consumers have taken to process messages so far, or None if there haven't been enough deletes yet.
+  pub fn recommended_visibility_timeout_secs(&self) -> Option<i64> {
+    self.ctx.recommended_visibility_timeout_secs()
+  }
+
+  pub fn get_auto_visibility_timeout(&self) -> bool {
+    self.ctx.auto_visibility_timeout.load(Ordering::Relaxed)
+  }
+
+  /// If enabled, polls use the recommended visibility timeout instead of the one requested, once there is one.
+  pub fn set_auto_visibility_timeout(&self, enabled: bool) {
+    self
+      .ctx
+      .auto_visibility_timeout
+      .store(enabled, Ordering::Relaxed);
+  }
+
+  pub fn get_default_ttl(&self) -> DefaultTtlState {
+    DefaultTtlState {
+      default_ttl_secs: *self.ctx.default_ttl_secs.lock(),
+    }
+  }
+
+  pub async fn set_default_ttl(&self, state: DefaultTtlState) -> OpResult<()> {
+    op_set_default_ttl(&self.ctx, state).await
+  }
+
+  pub fn get_poll_transform(&self) -> Option<PollTransform> {
+    self.ctx.poll_transform.lock().clone()
+  }
+
+  pub fn set_poll_transform(&self, t: Option<PollTransform>) {
+    *self.ctx.poll_transform.lock() = t;
+  }
+
+  pub fn get_routing_rules(&self) -> Vec<RoutingRule> {
+    self.ctx.routing_rules.lock().clone()
+  }
+
+  /// Routing rules aren't applied by `push`, as they involve other queues, but are stored here so that they're dropped along with the queue.
+  pub fn set_routing_rules(&self, rules: Vec<RoutingRule>) {
+    *self.ctx.routing_rules.lock() = rules;
+  }
+
+  pub fn get_webhook(&self) -> Option<WebhookCfg> {
+    self.ctx.webhook.lock().clone()
+  }
+
+  /// Like routing rules, webhooks are delivered to by the server rather than this crate.
+  pub fn set_webhook(&self, cfg: Option<WebhookCfg>) {
+    *self.ctx.webhook.lock() = cfg;
+  }
+
+  pub fn get_debug_sampling_state(&self) -> Option<DebugSamplingState> {
+    let sampler = self.ctx.debug_sampler.lock();
+    sampler.as_ref().map(|s| DebugSamplingState {
+      debug_queue: s.get_debug_queue().to_string(),
+      min_poll_count: s.get_min_poll_count(),
+      every_nth: s.get_every_nth(),
+    })
+  }
+
+  pub fn set_debug_sampling(&self, s: Option<DebugSamplingState>) {
+    *self.ctx.debug_sampler.lock() =
+      s.map(|s| DebugSampler::new(s.debug_queue, s.min_poll_count, s.every_nth));
+  }
+
+  /// Returns the debug queue and the polled messages that should be copied to it, if debug sampling is enabled. Call this once for every successful poll, or for each message of a streamed poll.
+  pub fn sample_for_debug<'a>(
+    &self,
+    polled: impl IntoIterator<Item = &'a OpPollOutputMessage>,
+  ) -> Option<(String, Vec<&'a OpPollOutputMessage>)> {
+    let mut sampler = self.ctx.debug_sampler.lock();
+    let sampler = sampler.as_mut()?;
+    let sampled = polled
+      .into_iter()
+      .filter(|m| sampler.should_sample(m.poll_count))
+      .collect::<Vec<_>>();
+    if sampled.is_empty() {
+      return None;
+    };
+    Some((sampler.get_debug_queue().to_string(), sampled))
+  }
 }
@@ -0,0 +1,166 @@
+use serde::Serialize;
+use std::fmt;
+use std::fmt::Display;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// Where a message is in its lifecycle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageState {
+  /// No message with the ID exists. Deleted and dead lettered messages are forgotten, so they're vacant afterwards.
+  Vacant,
+  /// Pushed with a delay and not yet visible.
+  Delayed,
+  /// Visible, so it can be polled.
+  Available,
+  /// Polled (at least once) and not yet visible again.
+  InFlight,
+  Deleted,
+  /// Deleted after being moved to a dead letter queue.
+  DeadLettered,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageTransition {
+  /// Vacant to delayed or available.
+  Push,
+  /// Available to in flight.
+  Poll,
+  /// Updating or nacking. The message's poller can still do this after its visibility timeout passes, until it's polled again. Messages pushed using older formats don't have poll counts after a restart, so in-flight messages may appear delayed.
+  ChangeVisibility,
+  Delete,
+  /// Deleted by an operator regardless of its poll tag.
+  ForceDelete,
+  DeadLetter,
+  /// Deleted because its TTL passed.
+  Expire,
+  /// Deleted by purging the queue.
+  Purge,
+}
+
+impl MessageTransition {
+  pub const ALL: [MessageTransition; 8] = [
+    MessageTransition::Push,
+    MessageTransition::Poll,
+    MessageTransition::ChangeVisibility,
+    MessageTransition::Delete,
+    MessageTransition::ForceDelete,
+    MessageTransition::DeadLetter,
+    MessageTransition::Expire,
+    MessageTransition::Purge,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      MessageTransition::Push => "push",
+      MessageTransition::Poll => "poll",
+      MessageTransition::ChangeVisibility => "change_visibility",
+      MessageTransition::Delete => "delete",
+      MessageTransition::ForceDelete => "force_delete",
+      MessageTransition::DeadLetter => "dead_letter",
+      MessageTransition::Expire => "expire",
+      MessageTransition::Purge => "purge",
+    }
+  }
+
+  /// The states this transition can be applied to.
+  pub fn from_states(self) -> &'static [MessageState] {
+    use MessageState::*;
+    match self {
+      MessageTransition::Push => &[Vacant],
+      MessageTransition::Poll => &[Available],
+      MessageTransition::ChangeVisibility
+      | MessageTransition::Delete
+      | MessageTransition::ForceDelete
+      | MessageTransition::DeadLetter
+      | MessageTransition::Expire
+      | MessageTransition::Purge => &[Delayed, Available, InFlight],
+    }
+  }
+
+  /// The state after this transition, if it's always the same.
+  pub fn to_state(self) -> Option<MessageState> {
+    match self {
+      MessageTransition::Push | MessageTransition::ChangeVisibility => None,
+      MessageTransition::Poll => Some(MessageState::InFlight),
+      MessageTransition::Delete
+      | MessageTransition::ForceDelete
+      | MessageTransition::Expire
+      | MessageTransition::Purge => Some(MessageState::Deleted),
+      MessageTransition::DeadLetter => Some(MessageState::DeadLettered),
+    }
+  }
+
+  fn index(self) -> usize {
+    Self::ALL.iter().position(|&t| t == self).unwrap()
+  }
+}
+
+/// Why a transition was rejected.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IllegalTransition {
+  /// The message isn't in a state the transition can be applied to, e.g. it doesn't exist.
+  InvalidState(MessageTransition, MessageState),
+  /// The message is in a valid state, but has been polled or had its visibility changed since the provided poll tag was issued.
+  StalePollTag(MessageTransition, MessageState),
+}
+
+impl Display for IllegalTransition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      IllegalTransition::InvalidState(t, s) => write!(f, "cannot {} a {s:?} message", t.name()),
+      IllegalTransition::StalePollTag(t, s) => {
+        write!(
+          f,
+          "cannot {} a {s:?} message with a stale poll tag",
+          t.name()
+        )
+      }
+    }
+  }
+}
+
+/// Validates applying `t` to a message in state `from`. `poll_tag_matches` should be true for transitions that don't require a poll tag.
+pub(crate) fn check_transition(
+  t: MessageTransition,
+  from: MessageState,
+  poll_tag_matches: bool,
+) -> Result<(), IllegalTransition> {
+  if !t.from_states().contains(&from) {
+    return Err(IllegalTransition::InvalidState(t, from));
+  };
+  if !poll_tag_matches {
+    return Err(IllegalTransition::StalePollTag(t, from));
+  };
+  Ok(())
+}
+
+/// How many times each transition was applied or rejected.
+#[derive(Default)]
+pub struct TransitionMetrics {
+  applied: [AtomicU64; MessageTransition::ALL.len()],
+  illegal: [AtomicU64; MessageTransition::ALL.len()],
+}
+
+impl TransitionMetrics {
+  /// Total number of messages that went through `t`.
+  pub fn counter(&self, t: MessageTransition) -> u64 {
+    self.applied[t.index()].load(Ordering::Relaxed)
+  }
+
+  /// Total number of attempts to apply `t` to a message that was rejected as illegal.
+  pub fn illegal_counter(&self, t: MessageTransition) -> u64 {
+    self.illegal[t.index()].load(Ordering::Relaxed)
+  }
+
+  pub(crate) fn record(&self, t: MessageTransition, n: usize) {
+    self.applied[t.index()].fetch_add(n as u64, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_illegal(&self, err: IllegalTransition) {
+    let (IllegalTransition::InvalidState(t, _) | IllegalTransition::StalePollTag(t, _)) = err;
+    self.illegal[t.index()].fetch_add(1, Ordering::Relaxed);
+  }
+}
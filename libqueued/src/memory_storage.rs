@@ -0,0 +1,120 @@
+use crate::storage::rocksdb_checkpoint;
+use crate::storage::Storage;
+use crate::storage::StorageStats;
+use crate::write_batch::parse_write_batch;
+use crate::write_batch::WriteBatchOp;
+use off64::int::create_u32_le;
+use parking_lot::RwLock;
+use rocksdb::WriteBatchWithTransaction;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub(crate) struct MemoryStorage {
+  data: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+  pub fn new(format_version: u32) -> Self {
+    // Record the format version like `rocksdb_open` does, so that checkpoints can be safely opened.
+    let mut data = BTreeMap::new();
+    data.insert(
+      b"format_version".to_vec(),
+      create_u32_le(format_version).to_vec(),
+    );
+    Self {
+      data: RwLock::new(data),
+    }
+  }
+}
+
+impl Storage for MemoryStorage {
+  fn write(&self, b: WriteBatchWithTransaction<false>) -> Result<(), String> {
+    let ops = parse_write_batch(b.data()).ok_or("unsupported write batch")?;
+    let mut data = self.data.write();
+    for op in ops {
+      match op {
+        WriteBatchOp::Put(k, v) => {
+          data.insert(k.to_vec(), v.to_vec());
+        }
+        WriteBatchOp::Delete(k) => {
+          data.remove(k);
+        }
+        WriteBatchOp::DeleteRange(start, end) => {
+          let keys = data
+            .range(start.to_vec()..end.to_vec())
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+          for k in keys {
+            data.remove(&k);
+          }
+        }
+      };
+    }
+    Ok(())
+  }
+
+  fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    Ok(self.data.read().get(key).cloned())
+  }
+
+  fn scan(&self, prefixes: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8])) -> Result<(), String> {
+    let data = self.data.read();
+    for &p in prefixes {
+      for (k, v) in data.range(p.to_vec()..) {
+        if !k.starts_with(p) {
+          break;
+        };
+        f(k, v);
+      }
+    }
+    Ok(())
+  }
+
+  fn flush(&self) -> Result<(), String> {
+    Ok(())
+  }
+
+  fn checkpoint(&self, dir: &Path) -> Result<(), String> {
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    for (k, v) in self.data.read().iter() {
+      b.put(k, v);
+    }
+    rocksdb_checkpoint(dir, b)
+  }
+
+  fn latest_sequence_number(&self) -> Option<u64> {
+    None
+  }
+
+  fn write_batches_since(
+    &self,
+    _since: u64,
+    _until: u64,
+    _f: &mut dyn FnMut(&[u8]) -> Result<bool, String>,
+  ) -> Result<bool, String> {
+    Err("writes to in-memory storage aren't sequenced".to_string())
+  }
+
+  fn compact_range(&self, _start: &[u8], _end: &[u8]) {}
+
+  fn is_compacting(&self) -> bool {
+    false
+  }
+
+  fn live_data_size(&self) -> u64 {
+    self
+      .data
+      .read()
+      .iter()
+      .map(|(k, v)| (k.len() + v.len()) as u64)
+      .sum()
+  }
+
+  fn disk_usage(&self) -> u64 {
+    0
+  }
+
+  fn stats(&self) -> StorageStats {
+    StorageStats::default()
+  }
+}
@@ -0,0 +1,415 @@
+use crate::messages::Messages;
+use crate::messages::PollOrder;
+use crate::messages::RemovedMessage;
+use crate::metrics::Metrics;
+use parking_lot::Mutex;
+use parking_lot::MutexGuard;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+type TimestampSec = i64;
+
+/// The in-memory index split into independently locked shards, so that concurrent operations on different messages don't contend on a single lock. Ungrouped messages are in the shard of their ID, spreading sequentially pushed messages evenly. Grouped messages are in the shard of their group instead, as a group's messages must be ordered together.
+///
+/// At most one shard is locked at a time, except by `lock_all`, which locks them in order. Ordering and priorities only apply within each shard, so with more than one shard, polls may return messages slightly out of order.
+pub(crate) struct MessageShards {
+  shards: Box<[Mutex<Messages>]>,
+  // Grouped messages that aren't in the shard of their ID, by the shard of their ID, mapped to the shard they're in. These locks are never held while locking a shard.
+  relocated: Box<[Mutex<HashMap<u64, usize>>]>,
+  next_poll_shard: AtomicUsize,
+}
+
+impl MessageShards {
+  pub fn new(metrics: Arc<Metrics>, count: usize) -> Self {
+    assert!(count > 0, "there must be at least one index shard");
+    MessageShards {
+      shards: (0..count)
+        .map(|_| Mutex::new(Messages::new(metrics.clone())))
+        .collect(),
+      relocated: (0..count).map(|_| Mutex::new(HashMap::new())).collect(),
+      next_poll_shard: AtomicUsize::new(0),
+    }
+  }
+
+  pub fn count(&self) -> usize {
+    self.shards.len()
+  }
+
+  /// The number of messages in all shards, excluding those currently being polled or updated.
+  pub fn len(&self) -> usize {
+    self.shards.iter().map(|s| s.lock().len()).sum()
+  }
+
+  fn home(&self, id: u64) -> usize {
+    (id % self.shards.len() as u64) as usize
+  }
+
+  fn placement(&self, id: u64, group: Option<u64>) -> usize {
+    match group {
+      Some(group) => (group % self.shards.len() as u64) as usize,
+      None => self.home(id),
+    }
+  }
+
+  /// The shard that contains message `id`, or would if it were pushed without a group.
+  pub fn shard_of(&self, id: u64) -> usize {
+    let home = self.home(id);
+    if self.shards.len() == 1 {
+      return home;
+    };
+    self.relocated[home]
+      .lock()
+      .get(&id)
+      .copied()
+      .unwrap_or(home)
+  }
+
+  pub fn lock_shard(&self, shard: usize) -> MutexGuard<'_, Messages> {
+    self.shards[shard].lock()
+  }
+
+  /// Locks the shard that contains message `id`, or would if it were pushed without a group.
+  pub fn lock(&self, id: u64) -> MutexGuard<'_, Messages> {
+    self.lock_shard(self.shard_of(id))
+  }
+
+  /// Locks each shard in turn, for reads that don't need to be consistent across shards. Each should be released before the next is locked.
+  pub fn lock_each(&self) -> impl Iterator<Item = MutexGuard<'_, Messages>> {
+    self.shards.iter().map(|s| s.lock())
+  }
+
+  /// Locks every shard, for reads that must be consistent across all of them.
+  pub fn lock_all(&self) -> Vec<MutexGuard<'_, Messages>> {
+    self.shards.iter().map(|s| s.lock()).collect()
+  }
+
+  /// Locks the shard that a new message must be inserted into, which must happen before the lock is released. Like priorities, the group must be set before the message is inserted.
+  pub fn lock_for_insert(&self, id: u64, group: Option<u64>) -> MutexGuard<'_, Messages> {
+    let home = self.home(id);
+    let shard = self.placement(id, group);
+    if shard != home {
+      self.relocated[home].lock().insert(id, shard);
+    };
+    self.lock_shard(shard)
+  }
+
+  /// Calls `f` with each item and its shard, which is locked once for each run of consecutive items in the same shard.
+  pub fn with_each<T>(
+    &self,
+    items: impl IntoIterator<Item = (usize, T)>,
+    mut f: impl FnMut(&mut Messages, T),
+  ) {
+    let mut locked: Option<(usize, MutexGuard<'_, Messages>)> = None;
+    for (shard, item) in items {
+      if locked.as_ref().is_none_or(|&(s, _)| s != shard) {
+        // Release the previous shard first, as only one may be locked at a time.
+        drop(locked.take());
+        locked = Some((shard, self.lock_shard(shard)));
+      };
+      f(&mut locked.as_mut().unwrap().1, item);
+    }
+  }
+
+  /// The order in which a poll should try shards. Polls start at different shards so that concurrent polls don't all contend on the same one, except that polls preferring a group start at its shard.
+  pub fn poll_order(&self, prefer_group: Option<u64>) -> impl Iterator<Item = usize> {
+    let n = self.shards.len();
+    let start = match prefer_group {
+      Some(group) => (group % n as u64) as usize,
+      None => self.next_poll_shard.fetch_add(1, Ordering::Relaxed) % n,
+    };
+    (start..start + n).map(move |i| i % n)
+  }
+
+  /// Like `Messages::forget`, but `shard` must be the locked shard containing the message.
+  pub fn forget(
+    &self,
+    shard: &mut Messages,
+    id: u64,
+    ts: TimestampSec,
+    poll_tag: u32,
+  ) -> RemovedMessage {
+    let removed = shard.forget(id, ts, poll_tag);
+    self.forget_relocation(&removed);
+    removed
+  }
+
+  fn forget_relocation(&self, m: &RemovedMessage) {
+    let home = self.home(m.id);
+    if self.placement(m.id, m.group) != home {
+      self.relocated[home].lock().remove(&m.id);
+    };
+  }
+
+  pub fn restore(&self, m: RemovedMessage) {
+    self.lock_for_insert(m.id, m.group).restore(m);
+  }
+
+  pub fn remove(&self, id: u64) -> Option<RemovedMessage> {
+    let removed = self.lock(id).remove(id)?;
+    self.forget_relocation(&removed);
+    Some(removed)
+  }
+
+  fn remove_from_each(
+    &self,
+    mut f: impl FnMut(&mut Messages) -> Vec<RemovedMessage>,
+  ) -> Vec<RemovedMessage> {
+    let mut removed = Vec::new();
+    for shard in self.shards.iter() {
+      removed.extend(f(&mut shard.lock()));
+    }
+    for m in removed.iter() {
+      self.forget_relocation(m);
+    }
+    removed
+  }
+
+  /// See `Messages::remove_all_unpinned`.
+  pub fn remove_all_unpinned(&self) -> Vec<RemovedMessage> {
+    self.remove_from_each(|s| s.remove_all_unpinned())
+  }
+
+  /// See `Messages::remove_expired`.
+  pub fn remove_expired(&self, now: TimestampSec) -> Vec<RemovedMessage> {
+    self.remove_from_each(|s| s.remove_expired(now))
+  }
+
+  /// See `Messages::set_loaded_at`.
+  pub fn set_loaded_at(&self, ts: TimestampSec) {
+    for shard in self.shards.iter() {
+      shard.lock().set_loaded_at(ts);
+    }
+  }
+
+  /// Like release pacing, this is applied within each shard, so messages are only polled in exactly this order with a single shard.
+  pub fn set_poll_order(&self, order: PollOrder) {
+    for shard in self.shards.iter() {
+      shard.lock().set_poll_order(order);
+    }
+  }
+
+  /// The rate is split evenly between shards, so it's only approximate if messages become visible unevenly across them.
+  pub fn set_release_pacing(&self, max_per_sec: Option<u32>) {
+    let n = self.shards.len() as u32;
+    for shard in self.shards.iter() {
+      shard
+        .lock()
+        .set_release_pacing(max_per_sec.map(|m| m.div_ceil(n)));
+    }
+  }
+
+  /// Replaces the entire index with the one returned by `load`, e.g. after reloading it from storage. It's called while every shard is locked, so no operation can use the old index in the meantime.
+  pub fn replace(&self, load: impl FnOnce() -> MessageShards) {
+    let mut shards = self.lock_all();
+    let other = load();
+    assert_eq!(shards.len(), other.shards.len());
+    let new = other.shards.into_vec().into_iter();
+    let new_relocated = other.relocated.into_vec().into_iter();
+    for (((shard, new), relocated), new_relocated) in shards
+      .iter_mut()
+      .zip(new)
+      .zip(self.relocated.iter())
+      .zip(new_relocated)
+    {
+      **shard = new.into_inner();
+      *relocated.lock() = new_relocated.into_inner();
+    }
+  }
+
+  pub fn youngest_time(&self) -> Option<TimestampSec> {
+    self
+      .shards
+      .iter()
+      .filter_map(|s| s.lock().youngest_time())
+      .min()
+  }
+
+  pub fn oldest_time(&self) -> Option<TimestampSec> {
+    self
+      .shards
+      .iter()
+      .filter_map(|s| s.lock().oldest_time())
+      .max()
+  }
+
+  pub fn in_flight_count(&self, now: TimestampSec) -> usize {
+    self
+      .shards
+      .iter()
+      .map(|s| s.lock().in_flight_count(now))
+      .sum()
+  }
+
+  pub fn available_count(&self, now: TimestampSec) -> usize {
+    self
+      .shards
+      .iter()
+      .map(|s| s.lock().available_count(now))
+      .sum()
+  }
+
+  pub fn earliest_available_time(&self, now: TimestampSec) -> Option<TimestampSec> {
+    self
+      .shards
+      .iter()
+      .filter_map(|s| s.lock().earliest_available_time(now))
+      .min()
+  }
+
+  pub fn pinned_count(&self) -> usize {
+    self.shards.iter().map(|s| s.lock().pinned_count()).sum()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MessageShards;
+  use crate::messages::PollOrder;
+  use crate::metrics::Metrics;
+  use std::sync::Arc;
+
+  const NOW: i64 = 1_000;
+
+  // Orders are only exact with a single shard.
+  fn single_shard(order: PollOrder) -> MessageShards {
+    let shards = MessageShards::new(Arc::new(Metrics::default()), 1);
+    shards.set_poll_order(order);
+    shards
+  }
+
+  fn push(shards: &MessageShards, id: u64, priority: u8, ts: i64) {
+    let mut shard = shards.lock_for_insert(id, None);
+    shard.set_priority(id, priority);
+    shard.insert(id, ts, 0);
+  }
+
+  fn poll(shards: &MessageShards, n: usize) -> Vec<u64> {
+    shards
+      .lock_shard(0)
+      .remove_earliest_n(n, false, None, NOW)
+      .into_iter()
+      .map(|(id, _, _)| id)
+      .collect()
+  }
+
+  fn poll_all(shards: &MessageShards, n: usize) -> Vec<u64> {
+    let mut polled = Vec::new();
+    loop {
+      let batch = poll(shards, n);
+      if batch.is_empty() {
+        return polled;
+      };
+      polled.extend(batch);
+    }
+  }
+
+  // Later IDs become visible earlier, so that the two orders differ within each priority.
+  fn push_interleaved(shards: &MessageShards) {
+    for (id, priority) in (1..).zip([0, 9, 3, 9, 0, 3, 9, 0]) {
+      push(shards, id, priority, NOW - id as i64);
+    }
+  }
+
+  #[test]
+  fn fifo_polls_by_priority_then_id() {
+    let shards = single_shard(PollOrder::Fifo);
+    push_interleaved(&shards);
+    assert_eq!(poll_all(&shards, 3), vec![2, 4, 7, 3, 6, 1, 5, 8]);
+  }
+
+  #[test]
+  fn visible_time_polls_by_priority_then_visible_time() {
+    let shards = single_shard(PollOrder::VisibleTime);
+    push_interleaved(&shards);
+    assert_eq!(poll_all(&shards, 3), vec![7, 4, 2, 6, 3, 8, 5, 1]);
+  }
+
+  #[test]
+  fn changing_poll_order_reorders_available_messages() {
+    let shards = single_shard(PollOrder::VisibleTime);
+    push_interleaved(&shards);
+    assert_eq!(poll(&shards, 1), vec![7]);
+    shards.set_poll_order(PollOrder::Fifo);
+    assert_eq!(poll_all(&shards, 3), vec![2, 4, 3, 6, 1, 5, 8]);
+  }
+
+  // Message 1 is polled, then its visibility timeout expires after messages 2 and 3 were pushed and became visible.
+  fn redeliver_after_newer_messages(order: PollOrder) -> MessageShards {
+    let shards = single_shard(order);
+    push(&shards, 1, 0, NOW - 10);
+    assert_eq!(poll(&shards, 1), vec![1]);
+    shards.lock(1).insert(1, NOW - 1, 1);
+    push(&shards, 2, 0, NOW - 5);
+    push(&shards, 3, 0, NOW - 5);
+    shards
+  }
+
+  #[test]
+  fn fifo_polls_redelivered_message_before_newer_ones() {
+    let shards = redeliver_after_newer_messages(PollOrder::Fifo);
+    assert_eq!(poll_all(&shards, 1), vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn visible_time_polls_redelivered_message_after_newer_ones() {
+    let shards = redeliver_after_newer_messages(PollOrder::VisibleTime);
+    assert_eq!(poll_all(&shards, 1), vec![2, 3, 1]);
+  }
+
+  #[test]
+  fn fifo_does_not_starve_redelivered_messages_at_any_priority() {
+    for priority in [1, 5, 9] {
+      let shards = single_shard(PollOrder::Fifo);
+      // A lower priority backlog, which waits until the higher priority is drained.
+      for id in 100..110 {
+        push(&shards, id, priority - 1, NOW - 100);
+      }
+      push(&shards, 1, priority, NOW - 100);
+      assert_eq!(poll(&shards, 1), vec![1]);
+      // New messages keep arriving at the same priority before and after its visibility timeout expires.
+      for id in 2..12 {
+        push(&shards, id, priority, NOW - 50);
+      }
+      shards.lock(1).insert(1, NOW - 1, 1);
+      for id in 12..22 {
+        push(&shards, id, priority, NOW);
+      }
+      let expected = [1]
+        .into_iter()
+        .chain(2..22)
+        .chain(100..110)
+        .collect::<Vec<_>>();
+      assert_eq!(poll_all(&shards, 1), expected, "priority {priority}");
+    }
+  }
+
+  #[test]
+  fn unpolled_messages_have_no_matching_poll_tag() {
+    let shards = single_shard(PollOrder::Fifo);
+    push(&shards, 1, 0, NOW + 10);
+    push(&shards, 2, 0, NOW);
+    // Neither a delayed nor an available message can be mutated with the tag it was pushed with.
+    assert!(!shards.lock(1).poll_tag_matches(1, 0));
+    assert!(!shards.lock(2).poll_tag_matches(2, 0));
+    assert_eq!(poll(&shards, 1), vec![2]);
+    shards.lock(2).insert(2, NOW + 30, 7);
+    assert!(!shards.lock(2).poll_tag_matches(2, 0));
+    assert!(shards.lock(2).poll_tag_matches(2, 7));
+  }
+
+  #[test]
+  fn lower_priorities_are_polled_once_higher_are_drained() {
+    let shards = single_shard(PollOrder::Fifo);
+    for id in 1..=3 {
+      push(&shards, id, 0, NOW - 10);
+    }
+    // Higher priority messages pushed later still go first.
+    for id in 10..13 {
+      push(&shards, id, 5, NOW);
+      assert_eq!(poll(&shards, 1), vec![id]);
+    }
+    assert_eq!(poll_all(&shards, 2), vec![1, 2, 3]);
+  }
+}
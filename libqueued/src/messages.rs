@@ -1,20 +1,137 @@
+use crate::lifecycle::MessageState;
 use crate::metrics::Metrics;
-use chrono::Utc;
 use itertools::Itertools;
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::ops::Bound;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
 
 type TimestampSec = i64;
 
+/// The order that visible messages with the same priority are polled in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PollOrder {
+  /// Earliest visible time first, then lowest ID. A message whose visibility timeout has just expired is polled after every message that became visible before it, including those pushed after it, so under constant load it can wait behind a steady stream of new messages.
+  #[default]
+  VisibleTime,
+  /// Lowest ID first, regardless of visible time. For sequential and Snowflake IDs, this is push order, so a message that becomes visible again is polled before every visible message pushed after it, and can't be starved by new messages. Random IDs have no meaningful order.
+  Fifo,
+}
+
+fn available_key(
+  order: PollOrder,
+  priority: u8,
+  ts: TimestampSec,
+  id: u64,
+) -> (Reverse<u8>, TimestampSec, u64) {
+  match order {
+    PollOrder::VisibleTime => (Reverse(priority), ts, id),
+    PollOrder::Fifo => (Reverse(priority), 0, id),
+  }
+}
+
+struct ReleasePacer {
+  max_per_sec: u32,
+  // Allows bursts of up to one second's worth of messages.
+  tokens: f64,
+  last_refill: Instant,
+}
+
+impl ReleasePacer {
+  fn take_budget(&mut self) -> usize {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.max_per_sec as f64).min(self.max_per_sec as f64);
+    self.last_refill = now;
+    self.tokens.floor() as usize
+  }
+
+  fn consume(&mut self, n: usize) {
+    self.tokens -= n as f64;
+  }
+}
+
+/// Everything known about a message that has been removed, so that it can be restored if removing it from storage fails.
+pub(crate) struct RemovedMessage {
+  pub id: u64,
+  pub ts: TimestampSec,
+  pub poll_tag: u32,
+  pub pinned: bool,
+  pub priority: u8,
+  pub split: bool,
+  pub offloaded: bool,
+  pub compressed: bool,
+  pub poll_count: u32,
+  pub has_attributes: bool,
+  pub expiry: Option<TimestampSec>,
+  pub pushed_at_ms: Option<i64>,
+  pub group: Option<u64>,
+  pub leased_at: Option<TimestampSec>,
+}
+
+/// Everything the index has about a message, e.g. to compare it with storage.
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) struct IndexedMessage {
+  pub visible_time: TimestampSec,
+  pub poll_tag: u32,
+  pub pinned: bool,
+  pub priority: u8,
+  pub split: bool,
+  pub offloaded: bool,
+  pub compressed: bool,
+  pub poll_count: u32,
+  pub has_attributes: bool,
+  pub expiry: Option<TimestampSec>,
+  pub pushed_at_ms: Option<i64>,
+  pub group: Option<u64>,
+}
+
 pub(crate) struct Messages {
   metrics: Arc<Metrics>,
   // We use a map instead of a heap as we want to be able to remove/mutate individual specific entries.
-  ordered_by_visible_time: BTreeMap<TimestampSec, HashSet<u64>>,
+  // The IDs are ordered so that promotion to `available` can resume from the middle of a set of messages with the same visible time when pacing.
+  ordered_by_visible_time: BTreeMap<TimestampSec, BTreeSet<u64>>,
   by_id: HashMap<u64, (TimestampSec, u32)>,
+  // Messages that have become visible, ordered by priority first, then as `poll_order` requires (see `available_key`). Messages are lazily moved here from `ordered_by_visible_time` when polling; `promoted_until` is the last (visible time, ID) that has been promoted, so any message inserted at or before it goes directly here.
+  available: BTreeSet<(Reverse<u8>, TimestampSec, u64)>,
+  // How many messages in `available` have each visible time, so that the earliest can be found whatever the poll order.
+  available_times: BTreeMap<TimestampSec, usize>,
+  promoted_until: Option<(TimestampSec, u64)>,
+  poll_order: PollOrder,
+  // If set, caps the rate at which messages are promoted to `available`, to avoid a thundering herd when many messages become visible at once.
+  release_pacer: Option<ReleasePacer>,
+  // Only contains messages with a non-zero priority. Like `pinned`, this is tracked separately from `by_id`.
+  priorities: HashMap<u64, u8>,
+  // Messages whose contents are stored separately from their visible time and poll tag instead of in a single inline record. This is tracked instead of inline messages as most messages are expected to be small. Like `pinned`, this is tracked separately from `by_id`.
+  split_contents: HashSet<u64>,
+  // Messages whose contents are in the `ContentsStore`. These are always also in `split_contents`.
+  offloaded: HashSet<u64>,
+  // Messages whose contents are compressed, wherever they're stored.
+  compressed: HashSet<u64>,
+  // Only contains messages that have been polled at least once. Like `pinned`, this is tracked separately from `by_id`.
+  poll_counts: HashMap<u64, u32>,
+  // Messages that have a `MessageAttributes` key, so that polling doesn't have to look it up for every message.
+  with_attributes: HashSet<u64>,
+  // Only contains messages with a TTL. Like `pinned`, this is tracked separately from `by_id`, and `expiries` is ordered so that expired messages can be found without scanning all messages.
+  expiry_by_id: HashMap<u64, TimestampSec>,
+  expiries: BTreeSet<(TimestampSec, u64)>,
+  // Only contains messages whose push time is known, which excludes those pushed using older formats. Like `pinned`, this is tracked separately from `by_id`.
+  pushed_at_ms: HashMap<u64, i64>,
+  // Only contains messages in a group, as a hash of the group ID. Only the message with the lowest ID in each group (its head) can become available, so messages in a group are delivered one at a time in push order. `groups` has the IDs of each group's messages that haven't been forgotten, which includes those currently being polled or updated. Like `pinned`, this is tracked separately from `by_id`.
+  group_by_id: HashMap<u64, u64>,
+  groups: HashMap<u64, BTreeSet<u64>>,
+  // When each message was last polled or had its visibility changed, if that happened since `loaded_at`. This isn't persisted, so leases from before the index was loaded are assumed to have started then. Like `pinned`, this is tracked separately from `by_id`.
+  leased_at: HashMap<u64, TimestampSec>,
+  loaded_at: TimestampSec,
+  // Pinned messages are still pollable and deletable as normal, but are excluded from bulk removal policies. This is tracked separately from `by_id` as messages are temporarily removed from `by_id` while being polled or updated.
+  pinned: HashSet<u64>,
 }
 
 impl Messages {
@@ -23,14 +140,302 @@ impl Messages {
       metrics,
       by_id: HashMap::new(),
       ordered_by_visible_time: BTreeMap::new(),
+      available: BTreeSet::new(),
+      available_times: BTreeMap::new(),
+      promoted_until: None,
+      poll_order: PollOrder::default(),
+      release_pacer: None,
+      priorities: HashMap::new(),
+      split_contents: HashSet::new(),
+      offloaded: HashSet::new(),
+      compressed: HashSet::new(),
+      poll_counts: HashMap::new(),
+      with_attributes: HashSet::new(),
+      expiry_by_id: HashMap::new(),
+      expiries: BTreeSet::new(),
+      pushed_at_ms: HashMap::new(),
+      group_by_id: HashMap::new(),
+      groups: HashMap::new(),
+      leased_at: HashMap::new(),
+      loaded_at: 0,
+      pinned: HashSet::new(),
     }
   }
 
-  #[allow(unused)]
   pub fn len(&self) -> usize {
     self.by_id.len()
   }
 
+  /// Messages currently being polled or updated are skipped.
+  pub fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+    self.by_id.keys().copied()
+  }
+
+  /// Returns None if the message doesn't exist or is currently being polled or updated.
+  pub fn indexed(&self, id: u64) -> Option<IndexedMessage> {
+    let &(visible_time, poll_tag) = self.by_id.get(&id)?;
+    Some(IndexedMessage {
+      visible_time,
+      poll_tag,
+      pinned: self.is_pinned(id),
+      priority: self.priority(id),
+      split: self.is_split(id),
+      offloaded: self.is_offloaded(id),
+      compressed: self.is_compressed(id),
+      poll_count: self.poll_count(id),
+      has_attributes: self.has_attributes(id),
+      expiry: self.expiry(id),
+      pushed_at_ms: self.pushed_at_ms(id),
+      group: self.group(id),
+    })
+  }
+
+  pub fn contains(&self, id: u64) -> bool {
+    self.by_id.contains_key(&id)
+  }
+
+  /// Returns None if the message doesn't exist or is currently being polled or updated.
+  pub fn poll_tag(&self, id: u64) -> Option<u32> {
+    self.by_id.get(&id).map(|&(_, poll_tag)| poll_tag)
+  }
+
+  /// Whether `poll_tag` is the message's current poll tag. A message that has never been polled has the tag zero, which no poll ever hands out, so zero never matches and a client can't delete or update a message it hasn't polled.
+  pub fn poll_tag_matches(&self, id: u64, poll_tag: u32) -> bool {
+    poll_tag != 0 && self.poll_tag(id) == Some(poll_tag)
+  }
+
+  /// Messages currently being polled or updated are vacant, as they're temporarily removed.
+  pub fn state(&self, id: u64, now: TimestampSec) -> MessageState {
+    match self.by_id.get(&id) {
+      None => MessageState::Vacant,
+      Some(&(ts, _)) if ts <= now => MessageState::Available,
+      Some(_) if self.poll_counts.contains_key(&id) => MessageState::InFlight,
+      Some(_) => MessageState::Delayed,
+    }
+  }
+
+  pub fn pinned_count(&self) -> usize {
+    self.pinned.len()
+  }
+
+  pub fn is_pinned(&self, id: u64) -> bool {
+    self.pinned.contains(&id)
+  }
+
+  pub fn set_pinned(&mut self, id: u64, pinned: bool) {
+    if pinned {
+      self.pinned.insert(id);
+    } else {
+      self.pinned.remove(&id);
+    };
+  }
+
+  /// Must be called once the index has been loaded.
+  pub fn set_loaded_at(&mut self, ts: TimestampSec) {
+    self.loaded_at = ts;
+  }
+
+  pub fn set_poll_order(&mut self, order: PollOrder) {
+    if order == self.poll_order {
+      return;
+    };
+    self.poll_order = order;
+    let available = std::mem::take(&mut self.available);
+    self.available = available
+      .into_iter()
+      .map(|(_, _, id)| self.available_key(id, self.by_id[&id].0))
+      .collect();
+  }
+
+  fn available_key(&self, id: u64, ts: TimestampSec) -> (Reverse<u8>, TimestampSec, u64) {
+    available_key(self.poll_order, self.priority(id), ts, id)
+  }
+
+  fn insert_available(&mut self, id: u64, ts: TimestampSec) {
+    if self.available.insert(self.available_key(id, ts)) {
+      *self.available_times.entry(ts).or_default() += 1;
+    };
+  }
+
+  fn remove_available(&mut self, id: u64, ts: TimestampSec) {
+    if !self.available.remove(&self.available_key(id, ts)) {
+      return;
+    };
+    let count = self.available_times.get_mut(&ts).unwrap();
+    *count -= 1;
+    if *count == 0 {
+      self.available_times.remove(&ts);
+    };
+  }
+
+  pub fn set_release_pacing(&mut self, max_per_sec: Option<u32>) {
+    self.release_pacer = max_per_sec.map(|max_per_sec| ReleasePacer {
+      max_per_sec,
+      tokens: max_per_sec as f64,
+      last_refill: Instant::now(),
+    });
+  }
+
+  pub fn is_split(&self, id: u64) -> bool {
+    self.split_contents.contains(&id)
+  }
+
+  pub fn set_split(&mut self, id: u64, split: bool) {
+    if split {
+      self.split_contents.insert(id);
+    } else {
+      self.split_contents.remove(&id);
+    };
+  }
+
+  pub fn is_offloaded(&self, id: u64) -> bool {
+    self.offloaded.contains(&id)
+  }
+
+  pub fn set_offloaded(&mut self, id: u64, offloaded: bool) {
+    if offloaded {
+      self.offloaded.insert(id);
+    } else {
+      self.offloaded.remove(&id);
+    };
+  }
+
+  pub fn is_compressed(&self, id: u64) -> bool {
+    self.compressed.contains(&id)
+  }
+
+  pub fn set_compressed(&mut self, id: u64, compressed: bool) {
+    if compressed {
+      self.compressed.insert(id);
+    } else {
+      self.compressed.remove(&id);
+    };
+  }
+
+  pub fn has_attributes(&self, id: u64) -> bool {
+    self.with_attributes.contains(&id)
+  }
+
+  pub fn set_has_attributes(&mut self, id: u64, has_attributes: bool) {
+    if has_attributes {
+      self.with_attributes.insert(id);
+    } else {
+      self.with_attributes.remove(&id);
+    };
+  }
+
+  pub fn expiry(&self, id: u64) -> Option<TimestampSec> {
+    self.expiry_by_id.get(&id).copied()
+  }
+
+  pub fn set_expiry(&mut self, id: u64, expiry: Option<TimestampSec>) {
+    if let Some(old) = self.expiry_by_id.remove(&id) {
+      self.expiries.remove(&(old, id));
+    };
+    if let Some(expiry) = expiry {
+      self.expiry_by_id.insert(id, expiry);
+      self.expiries.insert((expiry, id));
+    };
+  }
+
+  pub fn pushed_at_ms(&self, id: u64) -> Option<i64> {
+    self.pushed_at_ms.get(&id).copied()
+  }
+
+  pub fn set_pushed_at_ms(&mut self, id: u64, pushed_at_ms: Option<i64>) {
+    match pushed_at_ms {
+      Some(ts) => self.pushed_at_ms.insert(id, ts),
+      None => self.pushed_at_ms.remove(&id),
+    };
+  }
+
+  pub fn group(&self, id: u64) -> Option<u64> {
+    self.group_by_id.get(&id).copied()
+  }
+
+  /// Like priorities, this must be set before the message is inserted.
+  pub fn set_group(&mut self, id: u64, group: Option<u64>) {
+    if let Some(old) = self.group_by_id.remove(&id) {
+      let members = self.groups.get_mut(&old).unwrap();
+      let was_head = members.first() == Some(&id);
+      members.remove(&id);
+      let next = members.first().copied();
+      if members.is_empty() {
+        self.groups.remove(&old);
+      };
+      if let (true, Some(next)) = (was_head, next) {
+        self.make_available_if_promoted(next);
+      };
+    };
+    if let Some(group) = group {
+      let members = self.groups.entry(group).or_default();
+      let prev_head = members.first().copied();
+      members.insert(id);
+      self.group_by_id.insert(id, group);
+      // An earlier message may have been restored, in which case the previous head must wait again.
+      if let Some(prev_head) = prev_head.filter(|&h| h > id) {
+        if let Some(&(ts, _)) = self.by_id.get(&prev_head) {
+          self.remove_available(prev_head, ts);
+        };
+      };
+    };
+  }
+
+  fn is_group_head(&self, id: u64) -> bool {
+    match self.group_by_id.get(&id) {
+      Some(group) => self.groups[group].first() == Some(&id),
+      None => true,
+    }
+  }
+
+  // Makes a message available if it has already been passed over by promotion, e.g. when it becomes its group's head.
+  fn make_available_if_promoted(&mut self, id: u64) {
+    let Some(&(ts, _)) = self.by_id.get(&id) else {
+      return;
+    };
+    if self.promoted_until.is_some_and(|p| (ts, id) <= p) {
+      self.insert_available(id, ts);
+    };
+  }
+
+  /// When the message's current lease started, i.e. when it was last polled or had its visibility changed. This is never earlier than when the index was loaded.
+  pub fn lease_started(&self, id: u64) -> TimestampSec {
+    self.leased_at.get(&id).copied().unwrap_or(self.loaded_at)
+  }
+
+  pub fn set_leased_at(&mut self, id: u64, leased_at: Option<TimestampSec>) {
+    match leased_at {
+      Some(ts) => self.leased_at.insert(id, ts),
+      None => self.leased_at.remove(&id),
+    };
+  }
+
+  pub fn poll_count(&self, id: u64) -> u32 {
+    self.poll_counts.get(&id).copied().unwrap_or(0)
+  }
+
+  pub fn set_poll_count(&mut self, id: u64, poll_count: u32) {
+    if poll_count == 0 {
+      self.poll_counts.remove(&id);
+    } else {
+      self.poll_counts.insert(id, poll_count);
+    };
+  }
+
+  pub fn priority(&self, id: u64) -> u8 {
+    self.priorities.get(&id).copied().unwrap_or(0)
+  }
+
+  /// This must only be called while the message is not inserted.
+  pub fn set_priority(&mut self, id: u64, priority: u8) {
+    debug_assert!(!self.by_id.contains_key(&id));
+    if priority == 0 {
+      self.priorities.remove(&id);
+    } else {
+      self.priorities.insert(id, priority);
+    };
+  }
+
   pub fn youngest_time(&self) -> Option<TimestampSec> {
     self
       .ordered_by_visible_time
@@ -45,6 +450,80 @@ impl Messages {
       .map(|(k, _v)| *k)
   }
 
+  /// The earliest visible time of all messages that aren't visible at `now`.
+  pub fn next_visible_time(&self, now: TimestampSec) -> Option<TimestampSec> {
+    self
+      .ordered_by_visible_time
+      .range((Bound::Excluded(now), Bound::Unbounded))
+      .next()
+      .map(|(k, _v)| *k)
+  }
+
+  /// Whether any message has been polled and hasn't become visible again yet. This doesn't include messages currently being polled or updated.
+  pub fn has_in_flight(&self, now: TimestampSec) -> bool {
+    self
+      .poll_counts
+      .keys()
+      .any(|id| self.by_id.get(id).is_some_and(|&(ts, _)| ts > now))
+  }
+
+  /// How many messages can be polled now. Messages that have reached their visible time are made available first, as polling would, so this doesn't include those held back by release pacing or waiting behind an earlier message in their group.
+  pub fn available_count(&mut self, now: TimestampSec) -> usize {
+    self.promote_visible(now);
+    self.available.len()
+  }
+
+  /// The earliest visible time of messages that can be polled now, i.e. how long the longest waiting one has been visible. See `available_count`.
+  pub fn earliest_available_time(&mut self, now: TimestampSec) -> Option<TimestampSec> {
+    self.promote_visible(now);
+    self.available_times.keys().next().copied()
+  }
+
+  /// How many messages have been polled and haven't become visible again yet. Like `has_in_flight`, this doesn't include messages currently being polled or updated.
+  pub fn in_flight_count(&self, now: TimestampSec) -> usize {
+    self
+      .poll_counts
+      .keys()
+      .filter(|id| self.by_id.get(id).is_some_and(|&(ts, _)| ts > now))
+      .count()
+  }
+
+  /// Returns up to `n` messages with IDs greater than `after` for which `filter` returns true, in ID order. Messages currently being polled or updated are skipped. Messages aren't kept in ID order, so this checks every message.
+  pub fn list(
+    &self,
+    after: Option<u64>,
+    n: usize,
+    filter: impl Fn(u64, TimestampSec) -> bool,
+  ) -> Vec<(u64, TimestampSec)> {
+    // A max-heap of the lowest IDs found so far.
+    let mut lowest = BinaryHeap::with_capacity(n + 1);
+    for (&id, &(ts, _)) in self.by_id.iter() {
+      if after.is_some_and(|a| id <= a) || !filter(id, ts) {
+        continue;
+      };
+      lowest.push((id, ts));
+      if lowest.len() > n {
+        lowest.pop();
+      };
+    }
+    lowest.into_sorted_vec()
+  }
+
+  /// Returns up to `n` messages ordered by visible time then ID, starting after `after`, without changing anything. Messages currently being polled or updated are skipped.
+  pub fn peek(&self, after: Option<(TimestampSec, u64)>, n: usize) -> Vec<(u64, TimestampSec)> {
+    let start = match after {
+      Some((ts, _)) => Bound::Included(ts),
+      None => Bound::Unbounded,
+    };
+    self
+      .ordered_by_visible_time
+      .range((start, Bound::Unbounded))
+      .flat_map(|(&ts, ids)| ids.iter().map(move |&id| (id, ts)))
+      .filter(|&(id, ts)| after.is_none_or(|a| (ts, id) > a))
+      .take(n)
+      .collect_vec()
+  }
+
   pub fn insert(&mut self, id: u64, ts: TimestampSec, poll_tag: u32) {
     if !self
       .ordered_by_visible_time
@@ -57,6 +536,9 @@ impl Messages {
     let None = self.by_id.insert(id, (ts, poll_tag)) else {
       panic!("ID already exists");
     };
+    if self.promoted_until.is_some_and(|p| (ts, id) <= p) && self.is_group_head(id) {
+      self.insert_available(id, ts);
+    };
     self.metrics.message_counter.fetch_add(1, Ordering::Relaxed);
   }
 
@@ -76,53 +558,209 @@ impl Messages {
     if set.is_empty() {
       self.ordered_by_visible_time.remove(&ts).unwrap();
     }
+    self.remove_available(id, ts);
     self.metrics.message_counter.fetch_sub(1, Ordering::Relaxed);
     Some((ts, poll_tag))
   }
 
-  pub fn remove_if_poll_tag_matches(&mut self, id: u64, expected_poll_tag: u32) -> bool {
+  /// Returns the visible time of the removed message, if it existed and the poll tag matched.
+  pub fn remove_if_poll_tag_matches(
+    &mut self,
+    id: u64,
+    expected_poll_tag: u32,
+  ) -> Option<TimestampSec> {
     self
       .remove_if(id, |(_ts, poll_tag)| poll_tag == expected_poll_tag)
-      .is_some()
+      .map(|(ts, _poll_tag)| ts)
+  }
+
+  /// Clears all other state of a message that has just been removed, e.g. using `remove_if_poll_tag_matches`, in preparation for deleting it.
+  pub fn forget(&mut self, id: u64, ts: TimestampSec, poll_tag: u32) -> RemovedMessage {
+    let removed = RemovedMessage {
+      id,
+      ts,
+      poll_tag,
+      pinned: self.is_pinned(id),
+      priority: self.priority(id),
+      split: self.is_split(id),
+      offloaded: self.is_offloaded(id),
+      compressed: self.is_compressed(id),
+      poll_count: self.poll_count(id),
+      has_attributes: self.has_attributes(id),
+      expiry: self.expiry(id),
+      pushed_at_ms: self.pushed_at_ms(id),
+      group: self.group(id),
+      leased_at: self.leased_at.get(&id).copied(),
+    };
+    self.set_pinned(id, false);
+    self.set_priority(id, 0);
+    self.set_split(id, false);
+    self.set_offloaded(id, false);
+    self.set_compressed(id, false);
+    self.set_poll_count(id, 0);
+    self.set_has_attributes(id, false);
+    self.set_expiry(id, None);
+    self.set_pushed_at_ms(id, None);
+    self.set_group(id, None);
+    self.set_leased_at(id, None);
+    removed
+  }
+
+  pub fn restore(&mut self, m: RemovedMessage) {
+    self.set_priority(m.id, m.priority);
+    self.set_split(m.id, m.split);
+    self.set_offloaded(m.id, m.offloaded);
+    self.set_compressed(m.id, m.compressed);
+    self.set_poll_count(m.id, m.poll_count);
+    self.set_has_attributes(m.id, m.has_attributes);
+    self.set_expiry(m.id, m.expiry);
+    self.set_pushed_at_ms(m.id, m.pushed_at_ms);
+    self.set_group(m.id, m.group);
+    self.set_leased_at(m.id, m.leased_at);
+    self.insert(m.id, m.ts, m.poll_tag);
+    self.set_pinned(m.id, m.pinned);
+  }
+
+  /// Removes and forgets a message, unless it doesn't exist or is currently being polled or updated.
+  pub fn remove(&mut self, id: u64) -> Option<RemovedMessage> {
+    let (ts, poll_tag) = self.remove_if(id, |_| true)?;
+    Some(self.forget(id, ts, poll_tag))
+  }
+
+  /// Removes and forgets all messages that aren't pinned. Messages currently being polled or updated aren't removed.
+  pub fn remove_all_unpinned(&mut self) -> Vec<RemovedMessage> {
+    let ids = self
+      .by_id
+      .keys()
+      .copied()
+      .filter(|id| !self.pinned.contains(id))
+      .collect_vec();
+    ids
+      .into_iter()
+      .map(|id| {
+        let (ts, poll_tag) = self.remove_if(id, |_| true).unwrap();
+        self.forget(id, ts, poll_tag)
+      })
+      .collect_vec()
   }
 
+  /// Removes and forgets all messages that expired at or before `now` and aren't pinned. Messages currently being polled or updated aren't removed, and will be once they're reinserted.
+  pub fn remove_expired(&mut self, now: TimestampSec) -> Vec<RemovedMessage> {
+    let ids = self
+      .expiries
+      .range(..=(now, u64::MAX))
+      .map(|&(_, id)| id)
+      .filter(|id| !self.pinned.contains(id))
+      .collect_vec();
+    ids
+      .into_iter()
+      .filter_map(|id| {
+        let (ts, poll_tag) = self.remove_if(id, |_| true)?;
+        Some(self.forget(id, ts, poll_tag))
+      })
+      .collect_vec()
+  }
+
+  fn promote_visible(&mut self, now: TimestampSec) {
+    let mut budget = match &mut self.release_pacer {
+      Some(pacer) => pacer.take_budget(),
+      None => usize::MAX,
+    };
+    let resume_after = self.promoted_until;
+    let start = match resume_after {
+      Some((ts, _)) => Bound::Included(ts),
+      None => Bound::Unbounded,
+    };
+    let mut promoted = 0;
+    'outer: for (&ts, ids) in self
+      .ordered_by_visible_time
+      .range((start, Bound::Included(now)))
+    {
+      let ids = match resume_after {
+        Some((p_ts, p_id)) if p_ts == ts => ids.range((Bound::Excluded(p_id), Bound::Unbounded)),
+        _ => ids.range(..),
+      };
+      for &id in ids {
+        // Messages that aren't their group's head are made available once they are.
+        if self
+          .group_by_id
+          .get(&id)
+          .is_some_and(|g| self.groups[g].first() != Some(&id))
+        {
+          self.promoted_until = Some((ts, id));
+          continue;
+        };
+        if budget == 0 {
+          break 'outer;
+        };
+        budget -= 1;
+        promoted += 1;
+        let priority = self.priorities.get(&id).copied().unwrap_or(0);
+        if self
+          .available
+          .insert(available_key(self.poll_order, priority, ts, id))
+        {
+          *self.available_times.entry(ts).or_default() += 1;
+        };
+        self.promoted_until = Some((ts, id));
+      }
+    }
+    if let Some(pacer) = &mut self.release_pacer {
+      pacer.consume(promoted);
+    };
+  }
+
+  /// Visible messages are removed in order of highest priority first, then by the `PollOrder`, except that only the first message of each group is removed. If `ignore_existing_visibility_timeouts`, messages are removed in order of earliest visible time only. Either way, if the head of `prefer_group` can be removed, it's removed first.
   pub fn remove_earliest_n(
     &mut self,
     n: usize,
     ignore_existing_visibility_timeouts: bool,
-  ) -> Vec<(u64, u32)> {
-    let now = Utc::now().timestamp();
-    let mut removed_ids = Vec::new();
-    while removed_ids.len() < n {
-      let Some(mut ids) = self
-        .ordered_by_visible_time
-        .first_entry()
-        .filter(|e| ignore_existing_visibility_timeouts || *e.key() <= now)
-      else {
-        break;
-      };
-      for id in ids
-        .get()
-        .iter()
-        .cloned()
-        .take(n - removed_ids.len())
+    prefer_group: Option<u64>,
+    now: TimestampSec,
+  ) -> Vec<(u64, TimestampSec, u32)> {
+    if !ignore_existing_visibility_timeouts {
+      self.promote_visible(now);
+    };
+    // The head may currently be polled, or not yet visible, in which case there's nothing to prefer.
+    let preferred = prefer_group
+      .and_then(|g| self.groups.get(&g)?.first().copied())
+      .filter(|&id| match self.by_id.get(&id) {
+        Some(_) if ignore_existing_visibility_timeouts => true,
+        Some(&(ts, _)) => self.available.contains(&self.available_key(id, ts)),
+        None => false,
+      });
+    let ids = if ignore_existing_visibility_timeouts {
+      preferred
+        .into_iter()
+        .chain(
+          self
+            .ordered_by_visible_time
+            .values()
+            .flatten()
+            .cloned()
+            .filter(|&id| self.is_group_head(id) && Some(id) != preferred),
+        )
+        .take(n)
         .collect_vec()
-      {
-        removed_ids.push(id);
-        assert!(ids.get_mut().remove(&id));
-      }
-      if ids.get().is_empty() {
-        ids.remove();
-      }
-    }
-    assert!(removed_ids.len() <= n);
-    self
-      .metrics
-      .message_counter
-      .fetch_sub(removed_ids.len() as u64, Ordering::Relaxed);
-    removed_ids
+    } else {
+      preferred
+        .into_iter()
+        .chain(
+          self
+            .available
+            .iter()
+            .map(|&(_, _, id)| id)
+            .filter(|&id| Some(id) != preferred),
+        )
+        .take(n)
+        .collect_vec()
+    };
+    ids
       .into_iter()
-      .map(|id| (id, self.by_id.remove(&id).unwrap().1))
+      .map(|id| {
+        let (ts, poll_tag) = self.remove_if(id, |_| true).unwrap();
+        (id, ts, poll_tag)
+      })
       .collect_vec()
   }
 }
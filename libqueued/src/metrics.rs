@@ -1,24 +1,76 @@
+use crate::lifecycle::TransitionMetrics;
+use crate::processing_time::ProcessingTimes;
+use crate::storage::StorageStats;
+use parking_lot::Mutex;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 
 #[derive(Default)]
 pub struct Metrics {
+  /// Total number of events written to the audit log.
+  pub(crate) audit_event_counter: AtomicU64,
+  /// Total number of pushed or imported messages whose contents were stored compressed.
+  pub(crate) compressed_contents_counter: AtomicU64,
+  /// Total number of bytes saved by compressing the contents of pushed or imported messages.
+  pub(crate) compression_saved_bytes_counter: AtomicU64,
+  /// Total number of compactions of message keys run to remove tombstones, whether scheduled or requested.
+  pub(crate) tombstone_compaction_counter: AtomicU64,
+  /// Estimated bytes of live data currently stored by the queue. Refreshed about once a second.
+  pub(crate) data_bytes: AtomicU64,
+  /// Bytes currently used on disk by the queue, including data not yet compacted away. Refreshed about once a second.
+  pub(crate) disk_bytes: AtomicU64,
   /// Total number of poll requests that failed due to no message being available.
   pub(crate) empty_poll_counter: AtomicU64,
+  /// Total number of messages that were removed because their TTL passed before they were deleted.
+  pub(crate) expired_counter: AtomicU64,
+  /// Total number of messages deleted regardless of their poll tag by an operator. They're also counted as successful deletes.
+  pub(crate) forced_delete_counter: AtomicU64,
+  /// Total number of push requests that were rejected because the queue or server was over a quota.
+  pub(crate) full_push_counter: AtomicU64,
   /// Amount of messages currently in the queue. They may have been created, polled, or updated.
   pub(crate) message_counter: AtomicU64,
   /// Total number of delete requests that failed due to the requested message not being found.
   pub(crate) missing_delete_counter: AtomicU64,
+  /// Total number of nack requests that failed due to the requested message not being found.
+  pub(crate) missing_nack_counter: AtomicU64,
   /// Total number of update requests that failed due to the requested message not being found.
   pub(crate) missing_update_counter: AtomicU64,
+  /// Total number of takeover requests that were rejected because the message wasn't in flight or its lease was too recent.
+  pub(crate) rejected_takeover_counter: AtomicU64,
+  /// Total number of transactions that were rejected because a message to delete wasn't found.
+  pub(crate) rejected_transaction_counter: AtomicU64,
   /// Total number of delete requests that did delete a message successfully.
   pub(crate) successful_delete_counter: AtomicU64,
+  /// Total number of nack requests that did return a message to the queue successfully.
+  pub(crate) successful_nack_counter: AtomicU64,
   /// Total number of poll requests that did poll a message successfully.
   pub(crate) successful_poll_counter: AtomicU64,
   /// Total number of push requests that did push a message successfully.
   pub(crate) successful_push_counter: AtomicU64,
+  /// Total number of takeover requests that did take over a message successfully.
+  pub(crate) successful_takeover_counter: AtomicU64,
+  /// Total number of transactions that were committed successfully.
+  pub(crate) successful_transaction_counter: AtomicU64,
   /// Total number of update requests that did update a message successfully.
   pub(crate) successful_update_counter: AtomicU64,
+  /// Total number of scheduled messages that failed to be pushed. They're retried a second later.
+  pub(crate) failed_schedule_counter: AtomicU64,
+  /// Total number of messages pushed by schedules.
+  pub(crate) scheduled_push_counter: AtomicU64,
+  /// Total number of operations on offloaded message contents that failed.
+  pub(crate) offload_error_counter: AtomicU64,
+  /// Total number of poll requests that were rejected because the client exceeded its rate limit.
+  pub(crate) rate_limited_poll_counter: AtomicU64,
+  /// Total number of push requests that were rejected because the client exceeded its rate limit.
+  pub(crate) rate_limited_push_counter: AtomicU64,
+  /// Total number of writes that were applied locally but failed to replicate.
+  pub(crate) replication_error_counter: AtomicU64,
+  /// Total number of storage operations that failed.
+  pub(crate) storage_error_counter: AtomicU64,
+  /// Total number of storage operations that were rejected without being attempted because the storage circuit breaker was open.
+  pub(crate) storage_breaker_rejected_counter: AtomicU64,
+  /// Internal state of the storage engine, such as compaction backlog and write stalls. Refreshed about once a second.
+  pub(crate) storage_stats: Mutex<StorageStats>,
   /// Total number of delete requests while the endpoint was suspended.
   pub(crate) suspended_delete_counter: AtomicU64,
   /// Total number of poll requests while the endpoint was suspended.
@@ -29,13 +81,61 @@ pub struct Metrics {
   pub(crate) suspended_update_counter: AtomicU64,
   /// Total number of poll requests that were throttled.
   pub(crate) throttled_poll_counter: AtomicU64,
+  /// Total number of push requests that were rejected because they exceeded the push cap while maintenance was running.
+  pub(crate) throttled_push_counter: AtomicU64,
+  /// How long consumers took from polling each message to deleting it.
+  pub(crate) processing_times: ProcessingTimes,
+  /// How many messages went through each lifecycle transition, and how many attempts were illegal.
+  pub(crate) transitions: TransitionMetrics,
+  /// Total number of messages that were delivered to the queue's webhook.
+  pub(crate) webhook_delivered_counter: AtomicU64,
+  /// Total number of webhook delivery attempts that failed.
+  pub(crate) webhook_failed_counter: AtomicU64,
+  /// Total number of messages moved to the webhook's dead letter queue after too many failed delivery attempts.
+  pub(crate) webhook_dead_lettered_counter: AtomicU64,
 }
 
 impl Metrics {
+  pub fn audit_event_counter(&self) -> u64 {
+    self.audit_event_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn compressed_contents_counter(&self) -> u64 {
+    self.compressed_contents_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn compression_saved_bytes_counter(&self) -> u64 {
+    self.compression_saved_bytes_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn tombstone_compaction_counter(&self) -> u64 {
+    self.tombstone_compaction_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn data_bytes(&self) -> u64 {
+    self.data_bytes.load(Ordering::Relaxed)
+  }
+
+  pub fn disk_bytes(&self) -> u64 {
+    self.disk_bytes.load(Ordering::Relaxed)
+  }
+
   pub fn empty_poll_counter(&self) -> u64 {
     self.empty_poll_counter.load(Ordering::Relaxed)
   }
 
+  pub fn expired_counter(&self) -> u64 {
+    self.expired_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn forced_delete_counter(&self) -> u64 {
+    self.forced_delete_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn full_push_counter(&self) -> u64 {
+    self.full_push_counter.load(Ordering::Relaxed)
+  }
+
   pub fn message_counter(&self) -> u64 {
     self.message_counter.load(Ordering::Relaxed)
   }
@@ -44,14 +144,30 @@ impl Metrics {
     self.missing_delete_counter.load(Ordering::Relaxed)
   }
 
+  pub fn missing_nack_counter(&self) -> u64 {
+    self.missing_nack_counter.load(Ordering::Relaxed)
+  }
+
   pub fn missing_update_counter(&self) -> u64 {
     self.missing_update_counter.load(Ordering::Relaxed)
   }
 
+  pub fn rejected_takeover_counter(&self) -> u64 {
+    self.rejected_takeover_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn rejected_transaction_counter(&self) -> u64 {
+    self.rejected_transaction_counter.load(Ordering::Relaxed)
+  }
+
   pub fn successful_delete_counter(&self) -> u64 {
     self.successful_delete_counter.load(Ordering::Relaxed)
   }
 
+  pub fn successful_nack_counter(&self) -> u64 {
+    self.successful_nack_counter.load(Ordering::Relaxed)
+  }
+
   pub fn successful_poll_counter(&self) -> u64 {
     self.successful_poll_counter.load(Ordering::Relaxed)
   }
@@ -60,10 +176,56 @@ impl Metrics {
     self.successful_push_counter.load(Ordering::Relaxed)
   }
 
+  pub fn successful_takeover_counter(&self) -> u64 {
+    self.successful_takeover_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn successful_transaction_counter(&self) -> u64 {
+    self.successful_transaction_counter.load(Ordering::Relaxed)
+  }
+
   pub fn successful_update_counter(&self) -> u64 {
     self.successful_update_counter.load(Ordering::Relaxed)
   }
 
+  pub fn failed_schedule_counter(&self) -> u64 {
+    self.failed_schedule_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn scheduled_push_counter(&self) -> u64 {
+    self.scheduled_push_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn offload_error_counter(&self) -> u64 {
+    self.offload_error_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn rate_limited_poll_counter(&self) -> u64 {
+    self.rate_limited_poll_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn rate_limited_push_counter(&self) -> u64 {
+    self.rate_limited_push_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn replication_error_counter(&self) -> u64 {
+    self.replication_error_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn storage_error_counter(&self) -> u64 {
+    self.storage_error_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn storage_breaker_rejected_counter(&self) -> u64 {
+    self
+      .storage_breaker_rejected_counter
+      .load(Ordering::Relaxed)
+  }
+
+  pub fn storage_stats(&self) -> StorageStats {
+    *self.storage_stats.lock()
+  }
+
   pub fn suspended_delete_counter(&self) -> u64 {
     self.suspended_delete_counter.load(Ordering::Relaxed)
   }
@@ -83,4 +245,58 @@ impl Metrics {
   pub fn throttled_poll_counter(&self) -> u64 {
     self.throttled_poll_counter.load(Ordering::Relaxed)
   }
+
+  pub fn throttled_push_counter(&self) -> u64 {
+    self.throttled_push_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn processing_times(&self) -> &ProcessingTimes {
+    &self.processing_times
+  }
+
+  pub fn transitions(&self) -> &TransitionMetrics {
+    &self.transitions
+  }
+
+  pub fn webhook_delivered_counter(&self) -> u64 {
+    self.webhook_delivered_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn webhook_failed_counter(&self) -> u64 {
+    self.webhook_failed_counter.load(Ordering::Relaxed)
+  }
+
+  pub fn webhook_dead_lettered_counter(&self) -> u64 {
+    self.webhook_dead_lettered_counter.load(Ordering::Relaxed)
+  }
+
+  // Rate limits are per client rather than per queue, so they're enforced by the server instead of this crate.
+  pub fn record_rate_limited_poll(&self) {
+    self
+      .rate_limited_poll_counter
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_rate_limited_push(&self) {
+    self
+      .rate_limited_push_counter
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  // Webhooks are delivered to by the server.
+  pub fn record_webhook_delivered(&self) {
+    self
+      .webhook_delivered_counter
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_webhook_failed(&self) {
+    self.webhook_failed_counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_webhook_dead_lettered(&self) {
+    self
+      .webhook_dead_lettered_counter
+      .fetch_add(1, Ordering::Relaxed);
+  }
 }
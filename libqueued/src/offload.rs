@@ -0,0 +1,8 @@
+use futures::future::BoxFuture;
+
+/// Stores the contents of large messages outside of RocksDB, e.g. in an object store. Each instance is used by a single queue, so implementations must namespace IDs per queue if shared.
+pub trait ContentsStore: Send + Sync {
+  fn put(&self, id: u64, contents: Vec<u8>) -> BoxFuture<'static, Result<(), String>>;
+  fn get(&self, id: u64) -> BoxFuture<'static, Result<Vec<u8>, String>>;
+  fn delete(&self, id: u64) -> BoxFuture<'static, Result<(), String>>;
+}
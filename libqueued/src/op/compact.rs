@@ -0,0 +1,49 @@
+use super::result::OpResult;
+use crate::ctx::Ctx;
+use crate::db::RocksDbKeyPrefix;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::task::spawn_blocking;
+use tracing::info;
+use tracing::instrument;
+
+#[derive(Serialize)]
+pub struct OpCompactOutput {
+  /// Tombstones in files on disk before compacting.
+  pub tombstones_before: u64,
+  /// Tombstones in files on disk after compacting. Some may remain, e.g. if still needed by a checkpoint, or written while compacting.
+  pub tombstones_after: u64,
+  pub duration_ms: u64,
+}
+
+// Heavy delete workloads leave behind many tombstones, which RocksDB only removes once their files happen to be compacted. Until then, iterating visible times (e.g. when loading) has to skip over them.
+#[instrument(skip_all)]
+pub(crate) async fn op_compact(ctx: &Ctx) -> OpResult<OpCompactOutput> {
+  ctx.check_storage_available()?;
+  let _maintenance = ctx.begin_maintenance();
+  let storage = ctx.storage.clone();
+  let started = Instant::now();
+  let (tombstones_before, tombstones_after) = spawn_blocking(move || {
+    let before = storage.stats().tombstones;
+    let (start, end) = RocksDbKeyPrefix::MESSAGE_KEY_RANGE;
+    storage.compact_range(&start, &end);
+    (before, storage.stats().tombstones)
+  })
+  .await
+  .unwrap();
+  let duration_ms = started.elapsed().as_millis() as u64;
+  ctx
+    .metrics
+    .tombstone_compaction_counter
+    .fetch_add(1, Ordering::Relaxed);
+  info!(
+    tombstones_before,
+    tombstones_after, duration_ms, "compacted message keys"
+  );
+  Ok(OpCompactOutput {
+    tombstones_before,
+    tombstones_after,
+    duration_ms,
+  })
+}
@@ -1,14 +1,17 @@
 use super::result::OpError;
 use super::result::OpResult;
+use crate::audit::AuditAction;
 use crate::ctx::Ctx;
-use crate::db::rocksdb_key;
-use crate::db::rocksdb_write_opts;
-use crate::db::RocksDbKeyPrefix;
+use crate::db::rocksdb_delete_messages;
+use crate::lifecycle::MessageTransition;
+use crate::messages::RemovedMessage;
+use futures::future::join_all;
+use itertools::Itertools;
 use rocksdb::WriteBatchWithTransaction;
 use serde::Deserialize;
 use serde::Serialize;
 use std::sync::atomic::Ordering;
-use tokio::task::spawn_blocking;
+use tracing::instrument;
 
 #[derive(Serialize, Deserialize)]
 pub struct OpDeleteInputMessage {
@@ -24,7 +27,43 @@ pub struct OpDeleteInput {
 #[derive(Serialize, Deserialize)]
 pub struct OpDeleteOutput {}
 
-pub(crate) async fn op_delete(ctx: &Ctx, req: OpDeleteInput) -> OpResult<OpDeleteOutput> {
+#[instrument(skip_all, fields(count = req.messages.len()))]
+pub(crate) async fn op_delete(
+  ctx: &Ctx,
+  req: OpDeleteInput,
+  transition: MessageTransition,
+) -> OpResult<OpDeleteOutput> {
+  delete_messages(
+    ctx,
+    req.messages.into_iter().map(|m| (m.id, Some(m.poll_tag))),
+    transition,
+  )
+  .await?;
+  Ok(OpDeleteOutput {})
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpDeleteForcedOutput {}
+
+#[instrument(skip_all, fields(id = id))]
+pub(crate) async fn op_delete_forced(ctx: &Ctx, id: u64) -> OpResult<OpDeleteForcedOutput> {
+  let removed = delete_messages(ctx, [(id, None)], MessageTransition::ForceDelete).await?;
+  if removed == 0 {
+    return Err(OpError::MessageNotFound);
+  };
+  ctx
+    .metrics
+    .forced_delete_counter
+    .fetch_add(1, Ordering::Relaxed);
+  Ok(OpDeleteForcedOutput {})
+}
+
+// Deletes each message whose poll tag matches, or regardless of its poll tag if it's None, and returns how many were deleted. Messages that don't exist or have a stale poll tag are skipped.
+async fn delete_messages(
+  ctx: &Ctx,
+  messages: impl IntoIterator<Item = (u64, Option<u32>)>,
+  transition: MessageTransition,
+) -> OpResult<usize> {
   if ctx.suspension.is_delete_suspended() {
     ctx
       .metrics
@@ -33,34 +72,92 @@ pub(crate) async fn op_delete(ctx: &Ctx, req: OpDeleteInput) -> OpResult<OpDelet
     return Err(OpError::Suspended);
   };
 
+  // Removed messages are restored if writing fails, so they are still in flight until then.
+  let _busy = ctx.begin_busy_op();
   let mut b = WriteBatchWithTransaction::default();
-  {
-    let mut msgs = ctx.messages.lock();
-    for m in req.messages {
-      if !msgs.remove_if_poll_tag_matches(m.id, m.poll_tag) {
-        ctx
-          .metrics
-          .missing_delete_counter
-          .fetch_add(1, Ordering::Relaxed);
-        continue;
-      };
-      b.delete(rocksdb_key(RocksDbKeyPrefix::MessageData, m.id));
-      b.delete(rocksdb_key(RocksDbKeyPrefix::MessagePollTag, m.id));
-      b.delete(rocksdb_key(
-        RocksDbKeyPrefix::MessageVisibleTimestampSec,
-        m.id,
-      ));
+  let mut removed = Vec::new();
+  let now = ctx.clock.now();
+  for (id, poll_tag) in messages {
+    let mut msgs = ctx.messages.lock(id);
+    let from = msgs.state(id, now);
+    let current_poll_tag = msgs.poll_tag(id);
+    let poll_tag_matches = poll_tag.is_none_or(|t| msgs.poll_tag_matches(id, t));
+    if ctx
+      .check_transition(id, transition, from, poll_tag_matches)
+      .is_err()
+    {
       ctx
         .metrics
-        .successful_delete_counter
+        .missing_delete_counter
         .fetch_add(1, Ordering::Relaxed);
-    }
+      continue;
+    };
+    let poll_tag = current_poll_tag.unwrap();
+    let ts = msgs.remove_if_poll_tag_matches(id, poll_tag).unwrap();
+    removed.push(ctx.messages.forget(&mut msgs, id, ts, poll_tag));
+  }
+  let action = match transition {
+    MessageTransition::DeadLetter => AuditAction::DeadLetter,
+    MessageTransition::ForceDelete => AuditAction::ForceDelete,
+    _ => AuditAction::Delete,
   };
-  let db = ctx.db.clone();
-  spawn_blocking(move || db.write_opt(b, &rocksdb_write_opts()).unwrap())
-    .await
-    .unwrap();
-  ctx.batch_sync.submit_and_wait(0).await;
+  for r in removed.iter() {
+    ctx.audit(&mut b, r.id, action, Some(r.poll_tag), None);
+  }
+  let mut ids = removed.iter().map(|r| r.id).collect_vec();
+  ids.sort_unstable();
+  ids.dedup();
+  rocksdb_delete_messages(&mut b, &ids);
+  ctx.external_ids.delete(&mut b, &ids);
+  if let Err(err) = ctx.db_commit(b).await {
+    if !err.applied {
+      // The messages are still in storage, so restore them.
+      for m in removed {
+        ctx.messages.restore(m);
+      }
+    };
+    return Err(err.err);
+  };
+  finish_delete(ctx, &removed, transition).await;
 
-  Ok(OpDeleteOutput {})
+  Ok(removed.len())
+}
+
+// Cleans up after deleted messages once their removal has been committed.
+pub(crate) async fn finish_delete(
+  ctx: &Ctx,
+  removed: &[RemovedMessage],
+  transition: MessageTransition,
+) {
+  if let Some(store) = &ctx.contents_store {
+    // The messages no longer exist, so failing to delete their contents only leaks storage and shouldn't fail the request.
+    let failed = join_all(
+      removed
+        .iter()
+        .filter(|r| r.offloaded)
+        .map(|r| store.delete(r.id)),
+    )
+    .await
+    .into_iter()
+    .filter(|res| res.is_err())
+    .count();
+    ctx
+      .metrics
+      .offload_error_counter
+      .fetch_add(failed as u64, Ordering::Relaxed);
+  };
+  ctx
+    .metrics
+    .successful_delete_counter
+    .fetch_add(removed.len() as u64, Ordering::Relaxed);
+  ctx.metrics.transitions.record(transition, removed.len());
+  if transition == MessageTransition::Delete {
+    let now = ctx.clock.now();
+    ctx.metrics.processing_times.record(
+      removed
+        .iter()
+        .filter_map(|r| r.leased_at)
+        .map(|leased_at| now - leased_at),
+    );
+  };
 }
@@ -0,0 +1,81 @@
+use super::peek::read_contents;
+use super::result::OpResult;
+use crate::ctx::Ctx;
+use futures::future::try_join_all;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde::Serialize;
+
+pub const EXPORT_MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct OpExportInput {
+  /// If set, only messages with a greater ID are returned, for paging through all messages.
+  #[serde(default)]
+  pub after: Option<u64>,
+  /// At most `EXPORT_MAX_LIMIT`.
+  pub limit: usize,
+}
+
+#[derive(Serialize)]
+pub struct OpExportOutputMessage {
+  pub id: u64,
+  /// In seconds since the epoch.
+  pub visible_time: i64,
+  pub poll_count: u32,
+  #[serde(with = "serde_bytes")]
+  pub contents: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct OpExportOutput {
+  pub messages: Vec<OpExportOutputMessage>,
+  /// Pass this as `after` to get the next page. None if there are no more messages.
+  pub next: Option<u64>,
+}
+
+/// Reads messages with their contents in ID order, for copying them elsewhere with `op_import`. Unlike peeking, the order doesn't change as messages are polled, so paging through a queue that's in use still returns every message that exists throughout.
+pub(crate) async fn op_export(ctx: &Ctx, req: OpExportInput) -> OpResult<OpExportOutput> {
+  let limit = req.limit.min(EXPORT_MAX_LIMIT);
+  let mut listed = Vec::new();
+  for messages in ctx.messages.lock_each() {
+    let ids = messages.list(req.after, limit + 1, |_, _| true);
+    listed.extend(ids.into_iter().map(|(id, ts)| {
+      (
+        id,
+        ts,
+        messages.poll_count(id),
+        messages.is_split(id),
+        messages.is_offloaded(id),
+        messages.is_compressed(id),
+      )
+    }));
+  }
+  // Each shard returns its lowest IDs, so the lowest of them all are the lowest overall.
+  listed.sort_unstable_by_key(|m| m.0);
+  let has_more = listed.len() > limit;
+  listed.truncate(limit);
+  let next = listed.last().filter(|_| has_more).map(|m| m.0);
+  let contents = try_join_all(
+    listed
+      .iter()
+      .map(|&(id, _, _, split, offloaded, compressed)| {
+        read_contents(ctx, id, split, offloaded, compressed)
+      }),
+  )
+  .await?;
+  let messages = listed
+    .into_iter()
+    .zip(contents)
+    // The message was deleted while being read.
+    .filter_map(|((id, visible_time, poll_count, _, _, _), contents)| {
+      Some(OpExportOutputMessage {
+        id,
+        visible_time,
+        poll_count,
+        contents: contents?,
+      })
+    })
+    .collect_vec();
+  Ok(OpExportOutput { messages, next })
+}
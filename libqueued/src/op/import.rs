@@ -0,0 +1,169 @@
+use super::push::put_contents;
+use super::push::store_offloaded;
+use super::result::OpError;
+use super::result::OpResult;
+use crate::ctx::Ctx;
+use crate::db::rocksdb_key;
+use crate::db::RocksDbKeyPrefix;
+use crate::id_gen::is_random_id;
+use crate::id_gen::message_exists;
+use itertools::Itertools;
+use off64::int::create_u32_le;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use tokio::task::spawn_blocking;
+use tracing::instrument;
+
+#[derive(Deserialize)]
+pub struct OpImportInputMessage {
+  pub id: u64,
+  /// In seconds since the epoch.
+  pub visible_time: i64,
+  pub poll_count: u32,
+  #[serde(with = "serde_bytes")]
+  pub contents: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct OpImportInput {
+  pub messages: Vec<OpImportInputMessage>,
+}
+
+#[derive(Serialize)]
+pub struct OpImportOutput {
+  /// Messages that weren't imported, as their IDs had already been used, e.g. by an earlier import of the same messages, or they have random IDs and the on-disk format doesn't support them.
+  pub skipped: usize,
+}
+
+/// Adds messages exported from another queue, keeping their IDs, visible times, and poll counts. Messages are only imported if their ID is greater than every ID used so far by the queue (or, for random IDs, isn't used by an existing message), so importing the same messages again, e.g. to resume an interrupted import, skips those already imported. Imports are suspended along with pushes.
+#[instrument(skip_all, fields(count = req.messages.len()))]
+pub(crate) async fn op_import(ctx: &Ctx, req: OpImportInput) -> OpResult<OpImportOutput> {
+  if ctx.suspension.is_push_suspended() {
+    ctx
+      .metrics
+      .suspended_push_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::Suspended);
+  };
+
+  if let Some(max) = ctx.reloadable.lock().max_message_size {
+    if req.messages.iter().any(|m| m.contents.len() > max) {
+      return Err(OpError::MessageTooLarge);
+    };
+  };
+
+  let bytes = req.messages.iter().map(|m| m.contents.len() as u64).sum();
+  if ctx
+    .quota
+    .would_exceed(&ctx.metrics, req.messages.len() as u64, bytes)
+  {
+    ctx
+      .metrics
+      .full_push_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::QueueFull);
+  };
+
+  let mut messages = req.messages;
+  messages.sort_unstable_by_key(|m| m.id);
+  messages.dedup_by_key(|m| m.id);
+  if messages.is_empty() {
+    return Ok(OpImportOutput { skipped: 0 });
+  };
+  let next_id = messages
+    .iter()
+    .rev()
+    .find(|m| !is_random_id(m.id))
+    .map_or(0, |m| m.id + 1);
+  // Reserving the IDs means pushes can't use them, and concurrent imports of the same messages skip them.
+  let prev_next_id = ctx.next_id.fetch_max(next_id, Ordering::Relaxed);
+  // Random IDs don't advance `next_id`, so like newly generated ones, they're reserved separately and checked against storage instead.
+  let random_ids = messages
+    .iter()
+    .map(|m| m.id)
+    .filter(|&id| is_random_id(id) && ctx.format_version >= 9)
+    .collect_vec();
+  let (_reservation, taken) = ctx.pending_ids.reserve(&random_ids);
+  let mut used = HashSet::<u64>::from_iter(taken);
+  if !random_ids.is_empty() {
+    let storage = ctx.storage.clone();
+    let res = spawn_blocking(move || {
+      let mut existing = Vec::new();
+      for id in random_ids {
+        if message_exists(&*storage, id)? {
+          existing.push(id);
+        };
+      }
+      Ok::<_, String>(existing)
+    })
+    .await
+    .unwrap();
+    used.extend(ctx.record_storage_read_result(res)?);
+  };
+  let total = messages.len();
+  messages.retain(|m| {
+    if is_random_id(m.id) {
+      ctx.format_version >= 9 && !used.contains(&m.id)
+    } else {
+      m.id >= prev_next_id
+    }
+  });
+  let skipped = total - messages.len();
+
+  let mut b = WriteBatchWithTransaction::default();
+  let mut offloads = Vec::new();
+  let mut to_add = Vec::new();
+  let mut compressed_count = 0;
+  let mut compression_saved_bytes = 0;
+  for m in messages {
+    let (split, offloaded, saved_bytes) =
+      put_contents(ctx, &mut b, &mut offloads, m.id, m.visible_time, m.contents);
+    if let Some(saved_bytes) = saved_bytes {
+      compressed_count += 1;
+      compression_saved_bytes += saved_bytes;
+    };
+    // Older formats don't have poll counts, so they're only kept in memory until restart.
+    if m.poll_count > 0 && ctx.format_version >= 3 {
+      b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessagePollCount, m.id),
+        create_u32_le(m.poll_count),
+      );
+    };
+    to_add.push((
+      m.id,
+      m.visible_time,
+      m.poll_count,
+      split,
+      offloaded,
+      saved_bytes.is_some(),
+    ));
+  }
+  store_offloaded(ctx, offloads).await?;
+  ctx.db_write(b).await?;
+  // As with pushes, the messages are added even if syncing fails, as the write may still become durable.
+  let synced = ctx.db_sync(next_id).await;
+
+  for (id, visible_time, poll_count, split, offloaded, compressed) in to_add {
+    let mut messages = ctx.messages.lock_for_insert(id, None);
+    messages.set_split(id, split);
+    messages.set_offloaded(id, offloaded);
+    messages.set_compressed(id, compressed);
+    messages.insert(id, visible_time, 0);
+    messages.set_poll_count(id, poll_count);
+  }
+
+  ctx
+    .metrics
+    .compressed_contents_counter
+    .fetch_add(compressed_count, Ordering::Relaxed);
+  ctx
+    .metrics
+    .compression_saved_bytes_counter
+    .fetch_add(compression_saved_bytes, Ordering::Relaxed);
+  synced?;
+
+  Ok(OpImportOutput { skipped })
+}
@@ -0,0 +1,116 @@
+use super::result::OpResult;
+use crate::compression::decompressed_len;
+use crate::ctx::Ctx;
+use crate::db::inline_record_contents;
+use crate::db::rocksdb_key;
+use crate::db::RocksDbKeyPrefix;
+use futures::future::try_join_all;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde::Serialize;
+
+pub const LIST_MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct OpListInput {
+  /// If set, only messages with a greater ID are returned, for paging through all messages.
+  #[serde(default)]
+  pub after: Option<u64>,
+  /// At most `LIST_MAX_LIMIT`.
+  pub limit: usize,
+  /// If set, only messages that are (or aren't) currently visible are returned.
+  #[serde(default)]
+  pub visible: Option<bool>,
+  #[serde(default)]
+  pub min_poll_count: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct OpListOutputMessage {
+  pub id: u64,
+  /// When the message was pushed, in milliseconds since the epoch. None for messages pushed using older on-disk formats.
+  pub pushed_at_ms: Option<i64>,
+  /// In seconds since the epoch.
+  pub visible_time: i64,
+  pub poll_count: u32,
+  /// None if the contents are offloaded.
+  pub contents_len: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct OpListOutput {
+  pub messages: Vec<OpListOutputMessage>,
+  /// Pass this as `after` to get the next page. None if there are no more messages.
+  pub next: Option<u64>,
+}
+
+/// Lists the metadata of messages in ID order, e.g. for finding stuck messages. Unlike peeking, contents aren't returned, so pages can be much larger.
+pub(crate) async fn op_list(ctx: &Ctx, req: OpListInput) -> OpResult<OpListOutput> {
+  let limit = req.limit.min(LIST_MAX_LIMIT);
+  let now = ctx.clock.now();
+  let mut listed = Vec::new();
+  for messages in ctx.messages.lock_each() {
+    let ids = messages.list(req.after, limit + 1, |id, ts| {
+      req.visible.is_none_or(|v| v == (ts <= now))
+        && req
+          .min_poll_count
+          .is_none_or(|min| messages.poll_count(id) >= min)
+    });
+    listed.extend(ids.into_iter().map(|(id, ts)| {
+      let key = match (messages.is_offloaded(id), messages.is_split(id)) {
+        (true, _) => None,
+        (false, true) => Some(RocksDbKeyPrefix::MessageData),
+        (false, false) => Some(RocksDbKeyPrefix::MessageInline),
+      };
+      (
+        id,
+        ts,
+        messages.poll_count(id),
+        messages.pushed_at_ms(id),
+        key,
+        messages.is_compressed(id),
+      )
+    }));
+  }
+  // Each shard returns its lowest IDs, so the lowest of them all are the lowest overall.
+  listed.sort_unstable_by_key(|m| m.0);
+  let has_more = listed.len() > limit;
+  listed.truncate(limit);
+  let next = listed.last().filter(|_| has_more).map(|m| m.0);
+  // Contents lengths aren't tracked in memory, so they're read from storage.
+  let raws = try_join_all(listed.iter().map(|&(id, _, _, _, key, _)| async move {
+    match key {
+      Some(p) => ctx.db_get(rocksdb_key(p, id)).await,
+      None => Ok(None),
+    }
+  }))
+  .await?;
+  let messages = listed
+    .into_iter()
+    .zip(raws)
+    .filter_map(
+      |((id, visible_time, poll_count, pushed_at_ms, key, compressed), raw)| {
+        let contents = match (key, raw) {
+          // The message was deleted while being read.
+          (Some(_), None) => return None,
+          (Some(RocksDbKeyPrefix::MessageInline), Some(raw)) => Some(inline_record_contents(raw)),
+          (Some(_), Some(raw)) => Some(raw),
+          (None, _) => None,
+        };
+        // Compressed contents record their original length, so they don't need to be decompressed.
+        let contents_len = contents.map(|c| match compressed {
+          true => decompressed_len(&c).unwrap(),
+          false => c.len(),
+        });
+        Some(OpListOutputMessage {
+          id,
+          pushed_at_ms,
+          visible_time,
+          poll_count,
+          contents_len,
+        })
+      },
+    )
+    .collect_vec();
+  Ok(OpListOutput { messages, next })
+}
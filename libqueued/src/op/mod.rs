@@ -1,5 +1,20 @@
+pub mod compact;
 pub mod delete;
+pub mod export;
+pub mod import;
+pub mod list;
+pub mod nack;
+pub mod peek;
+pub mod pin;
 pub mod poll;
+pub mod purge;
 pub mod push;
 pub mod result;
+pub mod schedule;
+pub mod schema;
+pub mod scrub;
+pub mod suspend;
+pub mod takeover;
+pub mod transaction;
+pub mod ttl;
 pub mod update;
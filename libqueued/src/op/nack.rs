@@ -0,0 +1,57 @@
+use super::result::OpError;
+use super::result::OpResult;
+use super::update::set_visible_times;
+use crate::audit::AuditAction;
+use crate::ctx::Ctx;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use tracing::instrument;
+
+#[derive(Deserialize)]
+pub struct OpNackInput {
+  pub id: u64,
+  pub poll_tag: u32,
+  /// If set, the message only becomes visible again after this many seconds.
+  #[serde(default)]
+  pub delay_secs: i64,
+}
+
+#[derive(Serialize)]
+pub struct OpNackOutput {}
+
+// Nacks are updates to the message's visibility, so they are suspended along with updates.
+#[instrument(skip_all, fields(id = req.id))]
+pub(crate) async fn op_nack(ctx: &Ctx, req: OpNackInput) -> OpResult<OpNackOutput> {
+  if ctx.suspension.is_update_suspended() {
+    ctx
+      .metrics
+      .suspended_update_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::Suspended);
+  };
+
+  ctx.check_visibility_delay(req.delay_secs)?;
+
+  let new_visible_time = ctx.clock.now() + req.delay_secs.max(0);
+  let new_poll_tags = set_visible_times(
+    ctx,
+    vec![(req.id, req.poll_tag, new_visible_time)],
+    AuditAction::Nack,
+  )
+  .await?;
+  if new_poll_tags[0].is_none() {
+    ctx
+      .metrics
+      .missing_nack_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::MessageNotFound);
+  };
+
+  ctx
+    .metrics
+    .successful_nack_counter
+    .fetch_add(1, Ordering::Relaxed);
+
+  Ok(OpNackOutput {})
+}
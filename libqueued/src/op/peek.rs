@@ -0,0 +1,232 @@
+use super::result::OpError;
+use super::result::OpResult;
+use crate::attributes::decode_attributes;
+use crate::attributes::MessageAttributes;
+use crate::ctx::Ctx;
+use crate::db::inline_record_contents;
+use crate::db::rocksdb_key;
+use crate::db::RocksDbKeyPrefix;
+use crate::external_id::find_external_id;
+use futures::future::join_all;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use tokio::task::spawn_blocking;
+
+pub const PEEK_MAX_COUNT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct OpPeekCursor {
+  pub visible_time: i64,
+  pub id: u64,
+}
+
+#[derive(Deserialize)]
+pub struct OpPeekInput {
+  /// At most `PEEK_MAX_COUNT`.
+  pub count: usize,
+  /// If set, only messages after this one are returned, for paging through all messages.
+  #[serde(default)]
+  pub after: Option<OpPeekCursor>,
+}
+
+#[derive(Serialize)]
+pub struct OpPeekOutputMessage {
+  pub id: u64,
+  /// In seconds since the epoch. If it's in the future, the message is either in flight or was pushed with a delay.
+  pub visible_time: i64,
+  pub poll_count: u32,
+  pub priority: u8,
+  pub pinned: bool,
+  /// In seconds since the epoch.
+  pub expiry: Option<i64>,
+  /// In milliseconds since the epoch.
+  pub pushed_at_ms: Option<i64>,
+  pub group_id: Option<String>,
+  pub external_id: Option<String>,
+  pub attributes: MessageAttributes,
+  #[serde(with = "serde_bytes")]
+  pub contents: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct OpPeekOutput {
+  pub messages: Vec<OpPeekOutputMessage>,
+  /// Pass this as `after` to get the next page. None if there are no more messages.
+  pub next: Option<OpPeekCursor>,
+}
+
+struct Peeked {
+  id: u64,
+  visible_time: i64,
+  split: bool,
+  offloaded: bool,
+  compressed: bool,
+  has_attributes: bool,
+  grouped: bool,
+  poll_count: u32,
+  priority: u8,
+  pinned: bool,
+  expiry: Option<i64>,
+  pushed_at_ms: Option<i64>,
+}
+
+/// Returns None if the message doesn't exist.
+pub(crate) async fn read_contents(
+  ctx: &Ctx,
+  id: u64,
+  split: bool,
+  offloaded: bool,
+  compressed: bool,
+) -> OpResult<Option<Vec<u8>>> {
+  let Some(raw) = read_stored_contents(ctx, id, split, offloaded).await? else {
+    return Ok(None);
+  };
+  Ok(Some(ctx.decode_contents(raw, compressed).await?))
+}
+
+async fn read_stored_contents(
+  ctx: &Ctx,
+  id: u64,
+  split: bool,
+  offloaded: bool,
+) -> OpResult<Option<Vec<u8>>> {
+  if offloaded {
+    return ctx
+      .contents_store
+      .as_ref()
+      .unwrap()
+      .get(id)
+      .await
+      .map(Some)
+      .map_err(|_| {
+        ctx
+          .metrics
+          .offload_error_counter
+          .fetch_add(1, Ordering::Relaxed);
+        OpError::OffloadFailed
+      });
+  };
+  if split {
+    return ctx
+      .db_get(rocksdb_key(RocksDbKeyPrefix::MessageData, id))
+      .await;
+  };
+  Ok(
+    ctx
+      .db_get(rocksdb_key(RocksDbKeyPrefix::MessageInline, id))
+      .await?
+      .map(inline_record_contents),
+  )
+}
+
+// Returns None if the message was deleted while being read.
+async fn read_message(ctx: &Ctx, m: Peeked) -> OpResult<Option<OpPeekOutputMessage>> {
+  let Some(contents) = read_contents(ctx, m.id, m.split, m.offloaded, m.compressed).await? else {
+    return Ok(None);
+  };
+  let attributes = match m.has_attributes {
+    true => ctx
+      .db_get(rocksdb_key(RocksDbKeyPrefix::MessageAttributes, m.id))
+      .await?
+      .map(|raw| decode_attributes(&raw))
+      .unwrap_or_default(),
+    false => MessageAttributes::default(),
+  };
+  let group_id = match m.grouped {
+    true => ctx
+      .db_get(rocksdb_key(RocksDbKeyPrefix::MessageGroup, m.id))
+      .await?
+      .map(|raw| String::from_utf8(raw).unwrap()),
+    false => None,
+  };
+  Ok(Some(OpPeekOutputMessage {
+    id: m.id,
+    visible_time: m.visible_time,
+    poll_count: m.poll_count,
+    priority: m.priority,
+    pinned: m.pinned,
+    expiry: m.expiry,
+    pushed_at_ms: m.pushed_at_ms,
+    group_id,
+    external_id: ctx.external_ids.get(m.id),
+    attributes,
+    contents,
+  }))
+}
+
+/// Returns the message that has `external_id`, with its contents, without polling it. Fails with `OpError::MessageNotFound` if there's no such message, or it's currently being polled or updated.
+pub(crate) async fn op_peek_external_id(
+  ctx: &Ctx,
+  external_id: String,
+) -> OpResult<OpPeekOutputMessage> {
+  let storage = ctx.storage.clone();
+  let res = spawn_blocking(move || find_external_id(&*storage, &external_id))
+    .await
+    .unwrap();
+  let Some(id) = ctx.record_storage_read_result(res)? else {
+    return Err(OpError::MessageNotFound);
+  };
+  let Some(m) = ctx.messages.lock(id).indexed(id) else {
+    return Err(OpError::MessageNotFound);
+  };
+  let peeked = Peeked {
+    id,
+    visible_time: m.visible_time,
+    split: m.split,
+    offloaded: m.offloaded,
+    compressed: m.compressed,
+    has_attributes: m.has_attributes,
+    grouped: m.group.is_some(),
+    poll_count: m.poll_count,
+    priority: m.priority,
+    pinned: m.pinned,
+    expiry: m.expiry,
+    pushed_at_ms: m.pushed_at_ms,
+  };
+  read_message(ctx, peeked)
+    .await?
+    .ok_or(OpError::MessageNotFound)
+}
+
+/// Lists messages with their contents in order of visible time without polling them, e.g. for inspecting a queue. The messages may change or be deleted while they're being read, so this is only a best-effort view.
+pub(crate) async fn op_peek(ctx: &Ctx, req: OpPeekInput) -> OpResult<OpPeekOutput> {
+  let count = req.count.min(PEEK_MAX_COUNT);
+  let after = req.after.map(|a| (a.visible_time, a.id));
+  let mut peeked = Vec::new();
+  for messages in ctx.messages.lock_each() {
+    let ids = messages.peek(after, count + 1);
+    peeked.extend(ids.into_iter().map(|(id, visible_time)| Peeked {
+      id,
+      visible_time,
+      split: messages.is_split(id),
+      offloaded: messages.is_offloaded(id),
+      compressed: messages.is_compressed(id),
+      has_attributes: messages.has_attributes(id),
+      grouped: messages.group(id).is_some(),
+      poll_count: messages.poll_count(id),
+      priority: messages.priority(id),
+      pinned: messages.is_pinned(id),
+      expiry: messages.expiry(id),
+      pushed_at_ms: messages.pushed_at_ms(id),
+    }));
+  }
+  // Each shard returns its earliest messages, so the earliest of them all are the earliest overall.
+  peeked.sort_unstable_by_key(|m| (m.visible_time, m.id));
+  let has_more = peeked.len() > count;
+  peeked.truncate(count);
+  // The cursor is based on what was listed, not read, so deleted messages don't end paging early.
+  let next = peeked.last().filter(|_| has_more).map(|m| OpPeekCursor {
+    visible_time: m.visible_time,
+    id: m.id,
+  });
+  let messages = join_all(peeked.into_iter().map(|m| read_message(ctx, m)))
+    .await
+    .into_iter()
+    .collect::<OpResult<Vec<_>>>()?
+    .into_iter()
+    .flatten()
+    .collect_vec();
+  Ok(OpPeekOutput { messages, next })
+}
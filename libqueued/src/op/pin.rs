@@ -0,0 +1,54 @@
+use super::result::OpResult;
+use crate::ctx::Ctx;
+use crate::db::rocksdb_key;
+use crate::db::RocksDbKeyPrefix;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::instrument;
+
+#[derive(Serialize, Deserialize)]
+pub struct OpPinInput {
+  pub ids: Vec<u64>,
+  pub pinned: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpPinOutput {
+  /// IDs that were not found and therefore not changed.
+  pub missing_ids: Vec<u64>,
+}
+
+#[instrument(skip_all, fields(count = req.ids.len()))]
+pub(crate) async fn op_pin(ctx: &Ctx, req: OpPinInput) -> OpResult<OpPinOutput> {
+  let mut b = WriteBatchWithTransaction::default();
+  let mut changed = Vec::new();
+  let mut missing_ids = Vec::new();
+  for id in req.ids {
+    let mut msgs = ctx.messages.lock(id);
+    // Messages currently being polled or updated are temporarily absent, so are reported as missing.
+    if !msgs.contains(id) {
+      missing_ids.push(id);
+      continue;
+    };
+    let k = rocksdb_key(RocksDbKeyPrefix::MessagePinned, id);
+    if req.pinned {
+      b.put(k, []);
+    } else {
+      b.delete(k);
+    };
+    changed.push((id, msgs.is_pinned(id)));
+    msgs.set_pinned(id, req.pinned);
+  }
+  if changed.is_empty() {
+    return Ok(OpPinOutput { missing_ids });
+  };
+  if let Err(err) = ctx.db_write(b).await {
+    for (id, was_pinned) in changed {
+      ctx.messages.lock(id).set_pinned(id, was_pinned);
+    }
+    return Err(err);
+  };
+  ctx.db_sync(0).await?;
+  Ok(OpPinOutput { missing_ids })
+}
@@ -1,22 +1,55 @@
 use super::result::OpError;
 use super::result::OpResult;
+use crate::attributes::decode_attributes;
+use crate::attributes::MessageAttributes;
+use crate::audit::AuditAction;
+use crate::ctx::BusyOpGuard;
 use crate::ctx::Ctx;
+use crate::db::inline_record;
+use crate::db::inline_record_contents;
 use crate::db::rocksdb_key;
-use crate::db::rocksdb_write_opts;
 use crate::db::RocksDbKeyPrefix;
-use chrono::Utc;
-use dashmap::DashMap;
+use crate::group::group_hash;
+use crate::group::group_id_is_valid;
+use crate::lifecycle::MessageTransition;
+use futures::future::try_join_all;
+use futures::join;
 use futures::stream::iter;
+use futures::try_join;
+use futures::Stream;
 use futures::StreamExt;
-use itertools::Itertools;
 use off64::int::create_i40_le;
 use off64::int::create_u32_le;
 use rocksdb::WriteBatchWithTransaction;
 use serde::Deserialize;
 use serde::Serialize;
+use std::mem::take;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use tokio::task::spawn_blocking;
+use tracing::instrument;
+
+// How many messages of a streamed poll are read at once. This bounds the memory used by large polls, as messages are only read once the previous ones have been consumed.
+const STREAM_READ_CONCURRENCY: usize = 64;
+
+/// Delays redeliveries of messages that keep being polled without being deleted, e.g. because they crash consumers, so that they don't take up consumers that could process other messages. The delay is added to the visibility timeout of each poll after the first, so a message that isn't deleted or updated becomes visible again later each time.
+#[derive(Clone, Copy, Debug)]
+pub struct RedeliveryBackoff {
+  /// The delay of the second poll.
+  pub base_secs: u32,
+  /// Each later poll's delay is the previous one's multiplied by this.
+  pub multiplier: f64,
+  pub max_secs: u32,
+}
+
+impl RedeliveryBackoff {
+  /// The delay of the poll that brings a message's poll count to `poll_count`.
+  pub fn delay_secs(&self, poll_count: u32) -> i64 {
+    if poll_count <= 1 {
+      return 0;
+    };
+    let delay = self.base_secs as f64 * self.multiplier.powi((poll_count - 2) as i32);
+    delay.min(self.max_secs as f64) as i64
+  }
+}
 
 #[derive(Deserialize)]
 pub struct OpPollInput {
@@ -24,6 +57,28 @@ pub struct OpPollInput {
   pub visibility_timeout_secs: i64,
   #[serde(default)]
   pub ignore_existing_visibility_timeouts: bool, // This can be used for debugging purposes e.g. visibility timeout was set incorrectly.
+  /// If set and the next message of this group is visible, it's polled before any other message, regardless of priority. Consumers can use this to keep processing groups they've recently processed, for better cache locality; other groups are still polled once this one has nothing visible.
+  #[serde(default)]
+  pub prefer_group: Option<String>,
+  /// If set, only these fields are returned, and the rest have their default value (e.g. empty contents). Fields that aren't requested aren't read, so consumers that only claim messages and fetch their payloads elsewhere can skip reading contents. `id` and `poll_tag` are always returned.
+  #[serde(default)]
+  pub fields: Option<Vec<OpPollField>>,
+}
+
+impl OpPollInput {
+  fn wants(&self, field: OpPollField) -> bool {
+    self.fields.as_ref().is_none_or(|f| f.contains(&field))
+  }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OpPollField {
+  Contents,
+  PollCount,
+  Attributes,
+  LatencyMs,
+  GroupId,
 }
 
 #[derive(Serialize, Default)]
@@ -32,6 +87,13 @@ pub struct OpPollOutputMessage {
   pub contents: Vec<u8>,
   pub id: u64,
   pub poll_tag: u32,
+  /// How many times this message has been polled, including this poll.
+  pub poll_count: u32,
+  pub attributes: MessageAttributes,
+  /// Milliseconds between the message being pushed and this poll, as measured by the server, so it's unaffected by clock skew between producers and consumers. None for messages pushed using older on-disk formats.
+  pub latency_ms: Option<u64>,
+  /// None if the message isn't in a group.
+  pub group_id: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -39,7 +101,92 @@ pub struct OpPollOutput {
   pub messages: Vec<OpPollOutputMessage>,
 }
 
-pub(crate) async fn op_poll(ctx: &Ctx, req: OpPollInput) -> OpResult<OpPollOutput> {
+// A polled message whose split contents, attributes, and group ID may still need to be read. These aren't changed by polling, so they can be read while or after the poll is committed.
+struct PolledMessage {
+  id: u64,
+  visible_time: i64,
+  poll_tag: u32,
+  poll_count: u32,
+  // Only returned if requested, so None if it wasn't.
+  returned_poll_count: Option<u32>,
+  pushed_at_ms: Option<i64>,
+  split: bool,
+  // None if they still need to be read. Inline contents are always read before the write.
+  contents: Option<Vec<u8>>,
+  offloaded: bool,
+  compressed: bool,
+  has_attributes: bool,
+  grouped: bool,
+}
+
+// A message that has been removed from the index to be polled, with its state before and after the poll.
+struct PendingPollMessage {
+  shard: usize,
+  id: u64,
+  old_visible_time: i64,
+  old_poll_tag: u32,
+  new_poll_count: u32,
+  new_visible_time: i64,
+  new_poll_tag: u32,
+}
+
+// Messages that have been removed from the index, with their new state in `b` but not yet committed.
+struct PendingPoll<'a> {
+  ctx: &'a Ctx,
+  _busy: BusyOpGuard<'a>,
+  b: WriteBatchWithTransaction<false>,
+  messages: Vec<PendingPollMessage>,
+}
+
+impl<'a> PendingPoll<'a> {
+  fn rollback(&self) {
+    self
+      .ctx
+      .messages
+      .with_each(self.messages.iter().map(|m| (m.shard, m)), |messages, m| {
+        messages.insert(m.id, m.old_visible_time, m.old_poll_tag)
+      });
+  }
+
+  async fn commit(mut self) -> OpResult<()> {
+    let ctx = self.ctx;
+    let res = ctx.db_commit(take(&mut self.b)).await;
+    match &res {
+      Err(err) if !err.applied => {
+        self.rollback();
+        return Err(err.err);
+      }
+      _ => {}
+    };
+
+    // The write has already been applied, so the new state must be reflected in memory even if syncing fails.
+    let now = ctx.clock.now();
+    ctx
+      .messages
+      .with_each(self.messages.iter().map(|m| (m.shard, m)), |messages, m| {
+        messages.set_poll_count(m.id, m.new_poll_count);
+        messages.set_leased_at(m.id, Some(now));
+        messages.insert(m.id, m.new_visible_time, m.new_poll_tag);
+      });
+    res.map_err(|e| e.err)?;
+
+    ctx
+      .metrics
+      .successful_poll_counter
+      .fetch_add(self.messages.len() as u64, Ordering::Relaxed);
+    ctx
+      .metrics
+      .transitions
+      .record(MessageTransition::Poll, self.messages.len());
+    Ok(())
+  }
+}
+
+// Removes the messages to poll from the index and prepares the write that polls them. The messages are reinserted once the write is committed or fails.
+async fn begin_poll<'a>(
+  ctx: &'a Ctx,
+  req: &OpPollInput,
+) -> OpResult<(PendingPoll<'a>, Vec<PolledMessage>)> {
   if ctx.suspension.is_poll_suspended() {
     ctx
       .metrics
@@ -50,7 +197,7 @@ pub(crate) async fn op_poll(ctx: &Ctx, req: OpPollInput) -> OpResult<OpPollOutpu
 
   {
     let mut throttler = ctx.throttler.lock();
-    if throttler.is_some() && !throttler.as_mut().unwrap().increment_count() {
+    if throttler.is_some() && !throttler.as_mut().unwrap().increment_count(ctx.clock.now()) {
       ctx
         .metrics
         .throttled_poll_counter
@@ -59,70 +206,245 @@ pub(crate) async fn op_poll(ctx: &Ctx, req: OpPollInput) -> OpResult<OpPollOutpu
     };
   };
 
-  let new_visible_time = Utc::now().timestamp() + req.visibility_timeout_secs as i64;
+  let visibility_timeout_secs = ctx.poll_visibility_timeout(req.visibility_timeout_secs);
+  ctx.check_visibility_timeout(visibility_timeout_secs)?;
+
+  if req
+    .prefer_group
+    .as_deref()
+    .is_some_and(|g| ctx.format_version < 7 || !group_id_is_valid(g))
+  {
+    return Err(OpError::InvalidGroupId);
+  };
+  let prefer_group = req
+    .prefer_group
+    .as_deref()
+    .map(|g| group_hash(g.as_bytes()));
+
+  let now = ctx.clock.now();
+  let new_visible_time = now + visibility_timeout_secs;
+  let redelivery_backoff = ctx.reloadable.lock().redelivery_backoff;
+
+  let mut pending = PendingPoll {
+    ctx,
+    _busy: ctx.begin_busy_op(),
+    b: WriteBatchWithTransaction::default(),
+    messages: Vec::new(),
+  };
+  let mut polled = Vec::new();
+  for shard in ctx.messages.poll_order(prefer_group) {
+    if polled.len() == req.count {
+      break;
+    };
+    let mut messages = ctx.messages.lock_shard(shard);
+    for (id, ts, poll_tag) in messages.remove_earliest_n(
+      req.count - polled.len(),
+      req.ignore_existing_visibility_timeouts,
+      prefer_group,
+      now,
+    ) {
+      let poll_count = messages.poll_count(id) + 1;
+      let visible_time =
+        new_visible_time + redelivery_backoff.map_or(0, |b| b.delay_secs(poll_count));
+      let new_poll_tag = ctx.new_poll_tag(poll_tag);
+      pending.messages.push(PendingPollMessage {
+        shard,
+        id,
+        old_visible_time: ts,
+        old_poll_tag: poll_tag,
+        new_poll_count: poll_count,
+        new_visible_time: visible_time,
+        new_poll_tag,
+      });
+      polled.push(PolledMessage {
+        id,
+        visible_time,
+        poll_tag: new_poll_tag,
+        poll_count,
+        returned_poll_count: req.wants(OpPollField::PollCount).then_some(poll_count),
+        pushed_at_ms: messages
+          .pushed_at_ms(id)
+          .filter(|_| req.wants(OpPollField::LatencyMs)),
+        split: messages.is_split(id),
+        contents: None,
+        offloaded: messages.is_offloaded(id),
+        compressed: messages.is_compressed(id) && req.wants(OpPollField::Contents),
+        has_attributes: messages.has_attributes(id) && req.wants(OpPollField::Attributes),
+        grouped: messages.group(id).is_some() && req.wants(OpPollField::GroupId),
+      });
+    }
+  }
+  assert!(polled.len() <= req.count);
 
-  let msgs = ctx
-    .messages
-    .lock()
-    .remove_earliest_n(req.count, req.ignore_existing_visibility_timeouts);
-  assert!(msgs.len() <= req.count);
+  // Inline messages must be read before the write, as their contents are rewritten along with their new state.
+  let inline_res = try_join_all(
+    polled
+      .iter()
+      .filter(|m| !m.split)
+      .map(|m| ctx.db_get(rocksdb_key(RocksDbKeyPrefix::MessageInline, m.id))),
+  )
+  .await;
+  let mut inline_contents = match inline_res {
+    Ok(raws) => raws
+      .into_iter()
+      .map(|raw| inline_record_contents(raw.unwrap())),
+    Err(err) => {
+      pending.rollback();
+      return Err(err);
+    }
+  };
 
-  let mut b = WriteBatchWithTransaction::default();
-  for &(id, old_poll_tag) in msgs.iter() {
-    b.put(
-      rocksdb_key(RocksDbKeyPrefix::MessagePollTag, id),
-      create_u32_le(old_poll_tag + 1),
-    );
-    b.put(
-      rocksdb_key(RocksDbKeyPrefix::MessageVisibleTimestampSec, id),
-      create_i40_le(new_visible_time),
+  let want_contents = req.wants(OpPollField::Contents);
+  for m in polled.iter_mut() {
+    let id = m.id;
+    ctx.audit(
+      &mut pending.b,
+      id,
+      AuditAction::Poll,
+      None,
+      Some(m.poll_tag),
     );
+    // Older formats don't have poll counts, so they're only kept in memory until restart.
+    if ctx.format_version >= 3 {
+      pending.b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessagePollCount, id),
+        create_u32_le(m.poll_count),
+      );
+    };
+    if m.split {
+      pending.b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessagePollTag, id),
+        create_u32_le(m.poll_tag),
+      );
+      pending.b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessageVisibleTimestampSec, id),
+        create_i40_le(m.visible_time),
+      );
+      if !want_contents {
+        m.contents = Some(Vec::new());
+      };
+    } else {
+      let contents = inline_contents.next().unwrap();
+      pending.b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessageInline, id),
+        inline_record(m.visible_time, m.poll_tag, &contents),
+      );
+      m.contents = Some(match want_contents {
+        true => contents,
+        false => Vec::new(),
+      });
+    };
   }
-  let db = ctx.db.clone();
-  spawn_blocking(move || db.write_opt(b, &rocksdb_write_opts()).unwrap())
-    .await
-    .unwrap();
-
-  let msg_contents = Arc::new(DashMap::new());
-  iter(msgs.iter())
-    .for_each_concurrent(None, |&(id, _)| {
-      let db = ctx.db.clone();
-      let msg_datas = msg_contents.clone();
-      async move {
-        spawn_blocking(move || {
-          let data = db
-            .get(rocksdb_key(RocksDbKeyPrefix::MessageData, id))
-            .unwrap()
-            .unwrap();
-          msg_datas.insert(id, data);
-        })
-        .await
-        .unwrap();
-      }
-    })
-    .await;
-  ctx.batch_sync.submit_and_wait(0).await;
 
-  {
-    let mut messages = ctx.messages.lock();
-    for &(id, old_poll_tag) in msgs.iter() {
-      messages.insert(id, new_visible_time, old_poll_tag + 1);
+  Ok((pending, polled))
+}
+
+// Reads whatever wasn't read before the write, then applies the poll transform.
+async fn read_polled(ctx: &Ctx, m: PolledMessage) -> OpResult<OpPollOutputMessage> {
+  let PolledMessage {
+    id,
+    poll_tag,
+    returned_poll_count,
+    pushed_at_ms,
+    contents,
+    offloaded,
+    compressed,
+    has_attributes,
+    grouped,
+    ..
+  } = m;
+  let contents = async {
+    match contents {
+      Some(c) => Ok(c),
+      None if offloaded => ctx
+        .contents_store
+        .as_ref()
+        .unwrap()
+        .get(id)
+        .await
+        .map_err(|_| {
+          ctx
+            .metrics
+            .offload_error_counter
+            .fetch_add(1, Ordering::Relaxed);
+          OpError::OffloadFailed
+        }),
+      None => ctx
+        .db_get(rocksdb_key(RocksDbKeyPrefix::MessageData, id))
+        .await
+        .map(|raw| raw.unwrap()),
     }
   };
+  let attributes = async {
+    Ok(match has_attributes {
+      true => decode_attributes(
+        &ctx
+          .db_get(rocksdb_key(RocksDbKeyPrefix::MessageAttributes, id))
+          .await?
+          .unwrap(),
+      ),
+      false => MessageAttributes::default(),
+    })
+  };
+  let group_id = async {
+    Ok(match grouped {
+      true => Some(
+        String::from_utf8(
+          ctx
+            .db_get(rocksdb_key(RocksDbKeyPrefix::MessageGroup, id))
+            .await?
+            .unwrap(),
+        )
+        .unwrap(),
+      ),
+      false => None,
+    })
+  };
+  let (contents, attributes, group_id) = try_join!(contents, attributes, group_id)?;
+  let contents = ctx.decode_contents(contents, compressed).await?;
 
-  ctx
-    .metrics
-    .successful_poll_counter
-    .fetch_add(msgs.len() as u64, Ordering::Relaxed);
+  let now_ms = ctx.clock.now_ms();
+  let mut message = OpPollOutputMessage {
+    contents,
+    id,
+    poll_tag,
+    poll_count: returned_poll_count.unwrap_or_default(),
+    attributes,
+    // Clocks can go backwards, but a negative latency is never useful.
+    latency_ms: pushed_at_ms.map(|ts| now_ms.saturating_sub(ts).max(0) as u64),
+    group_id,
+  };
+  if let Some(t) = ctx.poll_transform.lock().as_ref() {
+    t.apply(&mut message, now_ms.div_euclid(1000));
+  };
+  Ok(message)
+}
 
+#[instrument(skip_all, fields(count = req.count))]
+pub(crate) async fn op_poll(ctx: &Ctx, req: OpPollInput) -> OpResult<OpPollOutput> {
+  let (pending, polled) = begin_poll(ctx, &req).await?;
+  // Contents, attributes, and groups aren't changed by the write, so they're read while it's being committed.
+  let (commit_res, read_res) = join!(
+    pending.commit(),
+    try_join_all(polled.into_iter().map(|m| read_polled(ctx, m)))
+  );
+  commit_res?;
   Ok(OpPollOutput {
-    messages: msgs
-      .into_iter()
-      .map(|(id, old_poll_tag)| OpPollOutputMessage {
-        contents: msg_contents.remove(&id).unwrap().1,
-        id,
-        poll_tag: old_poll_tag + 1,
-      })
-      .collect_vec(),
+    messages: read_res?,
   })
 }
+
+/// Like `op_poll`, but each message is yielded as soon as it's been read, in no particular order, rather than once all have been. Nothing is yielded until the poll has been committed. If reading a message fails, it's still polled, and will become visible again once its visibility timeout passes.
+#[instrument(skip_all, fields(count = req.count))]
+pub(crate) async fn op_poll_stream(
+  ctx: &Ctx,
+  req: OpPollInput,
+) -> OpResult<impl Stream<Item = OpResult<OpPollOutputMessage>> + Send + '_> {
+  let (pending, polled) = begin_poll(ctx, &req).await?;
+  pending.commit().await?;
+  Ok(
+    iter(polled)
+      .map(move |m| read_polled(ctx, m))
+      .buffer_unordered(STREAM_READ_CONCURRENCY),
+  )
+}
@@ -0,0 +1,75 @@
+use super::result::OpError;
+use super::result::OpResult;
+use crate::ctx::Ctx;
+use crate::db::rocksdb_delete_messages;
+use crate::lifecycle::MessageTransition;
+use crate::messages::RemovedMessage;
+use futures::future::join_all;
+use itertools::Itertools;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use tracing::instrument;
+
+#[derive(Serialize, Deserialize)]
+pub struct OpPurgeOutput {
+  pub purged: usize,
+}
+
+// Purges are bulk deletes, so they are suspended along with deletes. Pinned messages and messages currently being polled or updated are kept.
+#[instrument(skip_all)]
+pub(crate) async fn op_purge(ctx: &Ctx) -> OpResult<OpPurgeOutput> {
+  if ctx.suspension.is_delete_suspended() {
+    ctx
+      .metrics
+      .suspended_delete_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::Suspended);
+  };
+
+  let removed = ctx.messages.remove_all_unpinned();
+  let purged = delete_removed_messages(ctx, removed, MessageTransition::Purge).await?;
+  Ok(OpPurgeOutput { purged })
+}
+
+/// Deletes messages that have already been removed and forgotten from the in-memory index, restoring them if deleting them from storage fails. Returns how many messages were deleted.
+pub(crate) async fn delete_removed_messages(
+  ctx: &Ctx,
+  removed: Vec<RemovedMessage>,
+  transition: MessageTransition,
+) -> OpResult<usize> {
+  let mut ids = removed.iter().map(|r| r.id).collect_vec();
+  ids.sort_unstable();
+  // IDs are allocated sequentially, so most of these will be deleted with range tombstones.
+  let mut b = WriteBatchWithTransaction::default();
+  rocksdb_delete_messages(&mut b, &ids);
+  ctx.external_ids.delete(&mut b, &ids);
+  if let Err(err) = ctx.db_write(b).await {
+    // The messages are still in storage, so restore them.
+    for m in removed {
+      ctx.messages.restore(m);
+    }
+    return Err(err);
+  };
+  ctx.db_sync(0).await?;
+  if let Some(store) = &ctx.contents_store {
+    // The messages no longer exist, so failing to delete their contents only leaks storage and shouldn't fail the request.
+    let failed = join_all(
+      removed
+        .iter()
+        .filter(|r| r.offloaded)
+        .map(|r| store.delete(r.id)),
+    )
+    .await
+    .into_iter()
+    .filter(|res| res.is_err())
+    .count();
+    ctx
+      .metrics
+      .offload_error_counter
+      .fetch_add(failed as u64, Ordering::Relaxed);
+  };
+  ctx.metrics.transitions.record(transition, removed.len());
+  Ok(removed.len())
+}
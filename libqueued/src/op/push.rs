@@ -1,23 +1,61 @@
 use super::result::OpError;
 use super::result::OpResult;
+use super::schema::declared_schema_version;
+use super::schema::schema_exists;
+use crate::attributes::attributes_are_valid;
+use crate::attributes::encode_attributes;
+use crate::attributes::MessageAttributes;
+use crate::audit::AuditAction;
+use crate::compression::compress_contents;
 use crate::ctx::Ctx;
+use crate::db::inline_record;
 use crate::db::rocksdb_key;
-use crate::db::rocksdb_write_opts;
 use crate::db::RocksDbKeyPrefix;
-use chrono::Utc;
+use crate::db::LAST_PUSH_KEY;
+use crate::external_id::external_id_is_valid;
+use crate::external_id::find_external_id;
+use crate::external_id::ExternalIdReservation;
+use crate::group::group_hash;
+use crate::group::group_id_is_valid;
+use crate::id_gen::allocate_ids;
+use crate::id_gen::AllocatedIds;
+use crate::id_gen::IdStrategy;
+use crate::lifecycle::MessageTransition;
+use futures::future::try_join_all;
 use itertools::Itertools;
 use off64::int::create_i40_le;
+use off64::int::create_i64_le;
+use rand::Rng;
 use rocksdb::WriteBatchWithTransaction;
 use serde::Deserialize;
 use serde::Serialize;
+use std::mem::take;
 use std::sync::atomic::Ordering;
 use tokio::task::spawn_blocking;
+use tracing::instrument;
 
 #[derive(Deserialize)]
 pub struct OpPushInputMessage {
   #[serde(with = "serde_bytes")]
   pub contents: Vec<u8>,
   pub visibility_timeout_secs: u32,
+  /// If set, the visibility time is randomly moved earlier or later by up to this many seconds, to avoid many messages scheduled for the same time all becoming visible at once. The visibility time will never be before the push time.
+  #[serde(default)]
+  pub visibility_jitter_secs: u32,
+  /// Among visible messages, those with a higher priority are polled first.
+  #[serde(default)]
+  pub priority: u8,
+  #[serde(default)]
+  pub attributes: MessageAttributes,
+  /// If set, the message is deleted if it still exists this many seconds after being pushed, even if it's currently invisible. Defaults to the queue's default TTL, if any.
+  #[serde(default)]
+  pub ttl_secs: Option<u32>,
+  /// If set, messages with the same group ID are delivered one at a time in push order: a message can only be polled once all earlier messages in its group have been deleted.
+  #[serde(default)]
+  pub group_id: Option<String>,
+  /// If set, the message can be looked up by this ID, which must be unique among the queue's messages. The push fails with `OpError::ExternalIdExists` if another message has it, so it can also be used to avoid pushing the same message twice.
+  #[serde(default)]
+  pub external_id: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -30,7 +68,60 @@ pub struct OpPushOutput {
   pub ids: Vec<u64>,
 }
 
+// A pushed message to add to the index once its write has been committed.
+struct PendingInsert {
+  id: u64,
+  visible_time: i64,
+  priority: u8,
+  split: bool,
+  offloaded: bool,
+  compressed: bool,
+  has_attributes: bool,
+  expiry: Option<i64>,
+  // None for older formats, which don't have push times.
+  pushed_at_ms: Option<i64>,
+  // A hash of the group ID, if the message is in a group.
+  group: Option<u64>,
+}
+
+/// A validated push whose write batch has been built but not yet committed, so that it can be committed along with other writes.
+pub(crate) struct PreparedPush {
+  pub b: WriteBatchWithTransaction<false>,
+  ids: AllocatedIds,
+  n: u64,
+  to_add: Vec<PendingInsert>,
+  last_push_ms: i64,
+  external_ids: Vec<(u64, String)>,
+  compressed_count: u64,
+  compression_saved_bytes: u64,
+  // Released once the push has been committed or has failed, when this is dropped.
+  _external_id_reservation: Option<ExternalIdReservation>,
+}
+
+impl PreparedPush {
+  /// The value `next_id` must be persisted as at least once this is committed.
+  pub fn next_id(&self) -> u64 {
+    self.ids.next_id
+  }
+
+  pub fn ids(&self) -> &[u64] {
+    &self.ids.ids
+  }
+}
+
+#[instrument(skip_all, fields(count = req.messages.len()))]
 pub(crate) async fn op_push(ctx: &Ctx, req: OpPushInput) -> OpResult<OpPushOutput> {
+  let mut push = prepare_push(ctx, req).await?;
+  ctx.db_write(take(&mut push.b)).await?;
+  // The write has been applied, so it must be reflected in memory even if syncing fails, as it may still become durable.
+  let synced = ctx.db_sync(push.next_id()).await;
+  let out = finish_push(ctx, push);
+  synced?;
+  Ok(out)
+}
+
+// Validates the push and builds its write batch. Offloaded contents are stored, as they must be before the messages are.
+pub(crate) async fn prepare_push(ctx: &Ctx, req: OpPushInput) -> OpResult<PreparedPush> {
   if ctx.suspension.is_push_suspended() {
     ctx
       .metrics
@@ -39,40 +130,329 @@ pub(crate) async fn op_push(ctx: &Ctx, req: OpPushInput) -> OpResult<OpPushOutpu
     return Err(OpError::Suspended);
   };
 
+  if let Some(cap) = &ctx.push_cap {
+    if !cap
+      .lock()
+      .try_push(req.messages.len() as u64, || ctx.is_maintenance_running())
+    {
+      ctx
+        .metrics
+        .throttled_push_counter
+        .fetch_add(1, Ordering::Relaxed);
+      return Err(OpError::Throttled);
+    };
+  };
+
+  if let Some(max) = ctx.reloadable.lock().max_message_size {
+    if req.messages.iter().any(|m| m.contents.len() > max) {
+      return Err(OpError::MessageTooLarge);
+    };
+  };
+
+  for m in req.messages.iter() {
+    ctx.check_visibility_delay(m.visibility_timeout_secs.into())?;
+  }
+
+  let bytes = req.messages.iter().map(|m| m.contents.len() as u64).sum();
+  if ctx
+    .quota
+    .would_exceed(&ctx.metrics, req.messages.len() as u64, bytes)
+  {
+    ctx
+      .metrics
+      .full_push_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::QueueFull);
+  };
+
+  if req.messages.iter().any(|m| {
+    !m.attributes.is_empty() && (ctx.format_version < 4 || !attributes_are_valid(&m.attributes))
+  }) {
+    return Err(OpError::InvalidAttributes);
+  };
+
+  if req.messages.iter().any(|m| {
+    m.group_id.as_deref().is_some_and(|g| {
+      ctx.format_version < 7 || ctx.id_strategy == IdStrategy::Random || !group_id_is_valid(g)
+    })
+  }) {
+    return Err(OpError::InvalidGroupId);
+  };
+
+  let default_ttl_secs = *ctx.default_ttl_secs.lock();
+  if ctx.format_version < 5 && req.messages.iter().any(|m| m.ttl_secs.is_some()) {
+    return Err(OpError::TtlUnsupported);
+  };
+
+  for m in req.messages.iter() {
+    if let Some(version) = declared_schema_version(&m.attributes) {
+      if !schema_exists(ctx, version?).await? {
+        return Err(OpError::UnknownSchemaVersion);
+      };
+    };
+  }
+
+  let external_id_reservation = reserve_external_ids(ctx, &req.messages).await?;
+
   let n = req.messages.len() as u64;
-  let base_id = ctx.next_id.fetch_add(n, Ordering::Relaxed);
+  let ids = allocate_ids(ctx, n).await?;
   let mut to_add = Vec::new();
   // We must not update the `next_id` key as part of this write batch as we can never be certain that batches are written in order. Instead, we'll do so as part of `submit_and_wait` which guarantees that (if successful) the `next_id` has always persisted to a value greater than or equal to what we want.
   let mut b = WriteBatchWithTransaction::default();
-  for (i, msg) in req.messages.into_iter().enumerate() {
-    let id = base_id + i as u64;
-    let visible_time = Utc::now().timestamp() + msg.visibility_timeout_secs as i64;
-    b.put(rocksdb_key(RocksDbKeyPrefix::MessageData, id), msg.contents);
+  let mut offloads = Vec::new();
+  let mut external_ids = Vec::new();
+  let mut compressed_count = 0;
+  let mut compression_saved_bytes = 0;
+  for (msg, &id) in req.messages.into_iter().zip(ids.ids.iter()) {
+    let now_ms = ctx.clock.now_ms();
+    let now = now_ms.div_euclid(1000);
+    let jitter = match msg.visibility_jitter_secs {
+      0 => 0,
+      j => ctx.random.rng().gen_range(-(j as i64)..=j as i64),
+    };
+    let visible_time = (now + msg.visibility_timeout_secs as i64 + jitter).max(now);
+    let expiry = msg
+      .ttl_secs
+      .or(default_ttl_secs)
+      .map(|ttl| now + ttl as i64);
+    let (split, offloaded, saved_bytes) =
+      put_contents(ctx, &mut b, &mut offloads, id, visible_time, msg.contents);
+    if let Some(saved_bytes) = saved_bytes {
+      compressed_count += 1;
+      compression_saved_bytes += saved_bytes;
+    };
+    if msg.priority != 0 {
+      b.put(rocksdb_key(RocksDbKeyPrefix::MessagePriority, id), [
+        msg.priority
+      ]);
+    };
+    let has_attributes = !msg.attributes.is_empty();
+    if has_attributes {
+      b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessageAttributes, id),
+        encode_attributes(&msg.attributes),
+      );
+    };
+    if let Some(expiry) = expiry {
+      b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessageExpiry, id),
+        create_i64_le(expiry),
+      );
+    };
+    // Older formats don't have push times, so polls of these messages don't report their latency.
+    let pushed_at_ms = (ctx.format_version >= 6).then_some(now_ms);
+    if let Some(pushed_at_ms) = pushed_at_ms {
+      b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessagePushedAt, id),
+        create_i64_le(pushed_at_ms),
+      );
+    };
+    let group = msg.group_id.map(|g| {
+      let hash = group_hash(g.as_bytes());
+      b.put(rocksdb_key(RocksDbKeyPrefix::MessageGroup, id), g);
+      hash
+    });
+    if let Some(e) = msg.external_id {
+      ctx.external_ids.put(&mut b, id, &e);
+      external_ids.push((id, e));
+    };
+    ctx.audit(&mut b, id, AuditAction::Push, None, None);
+    to_add.push(PendingInsert {
+      id,
+      visible_time,
+      priority: msg.priority,
+      split,
+      offloaded,
+      compressed: saved_bytes.is_some(),
+      has_attributes,
+      expiry,
+      pushed_at_ms,
+      group,
+    });
+  }
+  store_offloaded(ctx, offloads).await?;
+  let last_push_ms = ctx.clock.now_ms();
+  b.put(LAST_PUSH_KEY, create_i64_le(last_push_ms));
+  Ok(PreparedPush {
+    b,
+    ids,
+    n,
+    to_add,
+    last_push_ms,
+    external_ids,
+    compressed_count,
+    compression_saved_bytes,
+    _external_id_reservation: external_id_reservation,
+  })
+}
+
+// Checks that no message has or is being pushed with any of the external IDs of `messages`, and reserves them until the push is committed.
+async fn reserve_external_ids(
+  ctx: &Ctx,
+  messages: &[OpPushInputMessage],
+) -> OpResult<Option<ExternalIdReservation>> {
+  let external_ids = messages
+    .iter()
+    .filter_map(|m| m.external_id.clone())
+    .collect_vec();
+  if external_ids.is_empty() {
+    return Ok(None);
+  };
+  if ctx.format_version < 8 || !external_ids.iter().all(|e| external_id_is_valid(e)) {
+    return Err(OpError::InvalidExternalId);
+  };
+  if external_ids.iter().duplicates().next().is_some() {
+    return Err(OpError::ExternalIdExists);
+  };
+  let Some(reservation) = ctx.external_ids.reserve(external_ids.clone()) else {
+    return Err(OpError::ExternalIdExists);
+  };
+  let storage = ctx.storage.clone();
+  let res = spawn_blocking(move || {
+    for e in external_ids {
+      if find_external_id(&*storage, &e)?.is_some() {
+        return Ok(true);
+      };
+    }
+    Ok::<_, String>(false)
+  })
+  .await
+  .unwrap();
+  if ctx.record_storage_read_result(res)? {
+    return Err(OpError::ExternalIdExists);
+  };
+  Ok(Some(reservation))
+}
+
+/// Adds the records of a new message's visible time and contents to `b`, or to `offloads` if the contents should be offloaded. The contents are compressed first if configured and worthwhile. Returns whether the message is split and offloaded, and if its contents were compressed, how many bytes that saved.
+pub(crate) fn put_contents(
+  ctx: &Ctx,
+  b: &mut WriteBatchWithTransaction<false>,
+  offloads: &mut Vec<(u64, Vec<u8>)>,
+  id: u64,
+  visible_time: i64,
+  contents: Vec<u8>,
+) -> (bool, bool, Option<u64>) {
+  let compressed = ctx
+    .contents_compression
+    .as_ref()
+    .filter(|cfg| contents.len() >= cfg.min_len)
+    .and_then(|cfg| {
+      ctx.contents_dictionaries.sample(&contents);
+      let dictionary = ctx.contents_dictionaries.current();
+      compress_contents(cfg, dictionary.as_deref(), &contents)
+    });
+  let saved_bytes = compressed
+    .as_ref()
+    .map(|c| (contents.len() - c.len()) as u64);
+  let contents = compressed.unwrap_or(contents);
+  if saved_bytes.is_some() {
+    b.put(rocksdb_key(RocksDbKeyPrefix::MessageCompressed, id), []);
+  };
+  let offloaded = ctx.contents_store.is_some() && contents.len() >= ctx.offload_min_contents_len;
+  let split = offloaded || contents.len() > ctx.inline_max_contents_len;
+  if offloaded {
+    b.put(rocksdb_key(RocksDbKeyPrefix::MessageOffloaded, id), []);
     b.put(
       rocksdb_key(RocksDbKeyPrefix::MessageVisibleTimestampSec, id),
       create_i40_le(visible_time),
     );
-    to_add.push((id, visible_time));
-  }
-  let db = ctx.db.clone();
-  spawn_blocking(move || db.write_opt(b, &rocksdb_write_opts()).unwrap())
+    offloads.push((id, contents));
+  } else if split {
+    b.put(rocksdb_key(RocksDbKeyPrefix::MessageData, id), contents);
+    b.put(
+      rocksdb_key(RocksDbKeyPrefix::MessageVisibleTimestampSec, id),
+      create_i40_le(visible_time),
+    );
+  } else {
+    b.put(
+      rocksdb_key(RocksDbKeyPrefix::MessageInline, id),
+      inline_record(visible_time, 0, &contents),
+    );
+  };
+  (split, offloaded, saved_bytes)
+}
+
+/// Contents must be stored before their messages are, so that a persisted message always has its contents. If this fails, the IDs are never used, so any contents that were stored are simply orphaned.
+pub(crate) async fn store_offloaded(ctx: &Ctx, offloads: Vec<(u64, Vec<u8>)>) -> OpResult<()> {
+  if offloads.is_empty() {
+    return Ok(());
+  };
+  let store = ctx.contents_store.as_ref().unwrap();
+  if try_join_all(offloads.into_iter().map(|(id, c)| store.put(id, c)))
     .await
-    .unwrap();
-  ctx.batch_sync.submit_and_wait(base_id + n).await;
+    .is_err()
+  {
+    ctx
+      .metrics
+      .offload_error_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::OffloadFailed);
+  };
+  Ok(())
+}
 
+// Adds the pushed messages to the index once their write has been committed.
+pub(crate) fn finish_push(ctx: &Ctx, push: PreparedPush) -> OpPushOutput {
+  let PreparedPush {
+    ids,
+    n,
+    to_add,
+    last_push_ms,
+    external_ids,
+    compressed_count,
+    compression_saved_bytes,
+    ..
+  } = push;
   {
-    let mut messages = ctx.messages.lock();
-    for (id, vt) in to_add {
-      messages.insert(id, vt, 0);
-    }
+    let mut l = ctx.last_push_ms.lock();
+    *l = (*l).max(Some(last_push_ms));
+  };
+
+  for PendingInsert {
+    id,
+    visible_time,
+    priority,
+    split,
+    offloaded,
+    compressed,
+    has_attributes,
+    expiry,
+    pushed_at_ms,
+    group,
+  } in to_add
+  {
+    let mut messages = ctx.messages.lock_for_insert(id, group);
+    messages.set_priority(id, priority);
+    messages.set_group(id, group);
+    messages.set_split(id, split);
+    messages.set_offloaded(id, offloaded);
+    messages.set_compressed(id, compressed);
+    messages.set_has_attributes(id, has_attributes);
+    messages.set_expiry(id, expiry);
+    messages.set_pushed_at_ms(id, pushed_at_ms);
+    messages.insert(id, visible_time, 0);
+  }
+  for (id, e) in external_ids {
+    ctx.external_ids.insert(id, e);
   }
 
   ctx
     .metrics
     .successful_push_counter
     .fetch_add(n, Ordering::Relaxed);
+  ctx
+    .metrics
+    .compressed_contents_counter
+    .fetch_add(compressed_count, Ordering::Relaxed);
+  ctx
+    .metrics
+    .compression_saved_bytes_counter
+    .fetch_add(compression_saved_bytes, Ordering::Relaxed);
+  ctx
+    .metrics
+    .transitions
+    .record(MessageTransition::Push, n as usize);
 
-  Ok(OpPushOutput {
-    ids: (0..n).map(|i| base_id + i).collect_vec(),
-  })
+  OpPushOutput { ids: ids.ids }
 }
@@ -1,9 +1,37 @@
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum OpError {
+  /// The queue doesn't have an audit log.
+  AuditLogDisabled,
+  /// Another message in the queue has the external ID, or it was given to more than one message in the push.
+  ExternalIdExists,
+  /// The queue can't take part in multi-queue pushes, as it wasn't loaded with a `FanoutLog`, it's replicated, or its on-disk format doesn't support them.
+  FanoutUnsupported,
+  /// There are too many attributes or they are too large, or the on-disk format doesn't support attributes.
+  InvalidAttributes,
+  /// The external ID is empty or too long, or the on-disk format doesn't support external IDs.
+  InvalidExternalId,
+  /// The group ID is empty or too long, the on-disk format doesn't support message groups, or the queue uses random IDs (see `IdStrategy::Random`).
+  InvalidGroupId,
   InvalidPollTag,
+  /// The schedule's name is empty or too long, or its cron expression is invalid or never matches.
+  InvalidSchedule,
+  /// The visibility timeout or delay is negative or outside the configured bounds.
+  InvalidVisibilityTimeout,
   MessageNotFound,
+  MessageTooLarge,
+  OffloadFailed,
+  /// The push would take the queue, or all queues sharing a quota, over a message count, data size, or disk usage limit.
+  QueueFull,
+  ReplicationFailed,
+  ScheduleNotFound,
+  StorageUnavailable,
   Suspended,
+  /// The poll throttle or maintenance push cap was exceeded.
   Throttled,
+  /// The on-disk format doesn't support message TTLs.
+  TtlUnsupported,
+  /// The schema version doesn't exist, or a message declares a schema version that isn't an integer.
+  UnknownSchemaVersion,
 }
 
 pub type OpResult<T> = Result<T, OpError>;
@@ -0,0 +1,318 @@
+use super::push::finish_push;
+use super::push::prepare_push;
+use super::push::OpPushInput;
+use super::push::OpPushInputMessage;
+use super::result::OpError;
+use super::result::OpResult;
+use crate::attributes::attributes_are_valid;
+use crate::attributes::encode_attributes;
+use crate::attributes::try_decode_attributes;
+use crate::attributes::MessageAttributes;
+use crate::cron::CronSchedule;
+use crate::ctx::Ctx;
+use crate::db::schedule_key;
+use crate::db::SCHEDULE_KEY_PREFIX;
+use crate::group::group_id_is_valid;
+use crate::storage::Storage;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::mem::take;
+use std::sync::atomic::Ordering;
+use tracing::instrument;
+use tracing::warn;
+
+pub const MAX_SCHEDULE_NAME_LEN: usize = 256;
+
+/// The message pushed each time a schedule runs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+  #[serde(with = "serde_bytes")]
+  pub contents: Vec<u8>,
+  #[serde(default)]
+  pub visibility_timeout_secs: u32,
+  #[serde(default)]
+  pub priority: u8,
+  #[serde(default)]
+  pub attributes: MessageAttributes,
+  #[serde(default)]
+  pub ttl_secs: Option<u32>,
+  #[serde(default)]
+  pub group_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OpSetScheduleInput {
+  /// See `CronSchedule` for the syntax.
+  pub cron: String,
+  pub message: ScheduledMessage,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ScheduleInfo {
+  pub name: String,
+  pub cron: String,
+  pub message: ScheduledMessage,
+  /// When the schedule next runs, in seconds since the epoch.
+  pub next_run_secs: i64,
+}
+
+pub(crate) struct Schedule {
+  pub cron_expr: String,
+  pub cron: CronSchedule,
+  pub message: ScheduledMessage,
+  pub next_run: i64,
+}
+
+impl Schedule {
+  fn info(&self, name: &str) -> ScheduleInfo {
+    ScheduleInfo {
+      name: name.to_string(),
+      cron: self.cron_expr.clone(),
+      message: self.message.clone(),
+      next_run_secs: self.next_run,
+    }
+  }
+}
+
+// Encoded as the i64 LE next run time, u32 LE visibility timeout, priority byte, u32 LE TTL (or u32::MAX if none), u16 LE length and cron expression, u16 LE length and group ID (or u16::MAX if none), u32 LE length and encoded attributes, and then the contents.
+fn encode_schedule(s: &Schedule) -> Vec<u8> {
+  let m = &s.message;
+  let mut out = Vec::new();
+  out.extend_from_slice(&s.next_run.to_le_bytes());
+  out.extend_from_slice(&m.visibility_timeout_secs.to_le_bytes());
+  out.push(m.priority);
+  out.extend_from_slice(&m.ttl_secs.unwrap_or(u32::MAX).to_le_bytes());
+  out.extend_from_slice(&(s.cron_expr.len() as u16).to_le_bytes());
+  out.extend_from_slice(s.cron_expr.as_bytes());
+  match &m.group_id {
+    Some(g) => {
+      out.extend_from_slice(&(g.len() as u16).to_le_bytes());
+      out.extend_from_slice(g.as_bytes());
+    }
+    None => out.extend_from_slice(&u16::MAX.to_le_bytes()),
+  };
+  let attributes = encode_attributes(&m.attributes);
+  out.extend_from_slice(&(attributes.len() as u32).to_le_bytes());
+  out.extend_from_slice(&attributes);
+  out.extend_from_slice(&m.contents);
+  out
+}
+
+fn decode_schedule(raw: &[u8]) -> Option<Schedule> {
+  fn take_bytes<'a>(raw: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let (head, rest) = raw.split_at_checked(n)?;
+    *raw = rest;
+    Some(head)
+  }
+  fn take_str(raw: &mut &[u8], len: usize) -> Option<String> {
+    String::from_utf8(take_bytes(raw, len)?.to_vec()).ok()
+  }
+
+  let mut raw = raw;
+  let next_run = i64::from_le_bytes(take_bytes(&mut raw, 8)?.try_into().unwrap());
+  let visibility_timeout_secs = u32::from_le_bytes(take_bytes(&mut raw, 4)?.try_into().unwrap());
+  let priority = take_bytes(&mut raw, 1)?[0];
+  let ttl_secs = Some(u32::from_le_bytes(
+    take_bytes(&mut raw, 4)?.try_into().unwrap(),
+  ))
+  .filter(|&t| t != u32::MAX);
+  let cron_len = u16::from_le_bytes(take_bytes(&mut raw, 2)?.try_into().unwrap());
+  let cron_expr = take_str(&mut raw, cron_len.into())?;
+  let group_len = u16::from_le_bytes(take_bytes(&mut raw, 2)?.try_into().unwrap());
+  let group_id = match group_len {
+    u16::MAX => None,
+    len => Some(take_str(&mut raw, len.into())?),
+  };
+  let attributes_len = u32::from_le_bytes(take_bytes(&mut raw, 4)?.try_into().unwrap());
+  let attributes = try_decode_attributes(take_bytes(&mut raw, attributes_len as usize)?)?;
+  Some(Schedule {
+    cron: CronSchedule::parse(&cron_expr)?,
+    cron_expr,
+    message: ScheduledMessage {
+      contents: raw.to_vec(),
+      visibility_timeout_secs,
+      priority,
+      attributes,
+      ttl_secs,
+      group_id,
+    },
+    next_run,
+  })
+}
+
+pub(crate) fn load_schedules(storage: &dyn Storage) -> BTreeMap<String, Schedule> {
+  let mut schedules = BTreeMap::new();
+  storage
+    .scan(&[SCHEDULE_KEY_PREFIX], &mut |k, v| {
+      let name = String::from_utf8(k[SCHEDULE_KEY_PREFIX.len()..].to_vec())
+        .expect("schedule name as UTF-8 string");
+      let schedule = decode_schedule(v).expect("corrupt schedule");
+      schedules.insert(name, schedule);
+    })
+    .unwrap();
+  schedules
+}
+
+fn schedule_is_valid(ctx: &Ctx, name: &str, m: &ScheduledMessage) -> OpResult<()> {
+  if name.is_empty() || name.len() > MAX_SCHEDULE_NAME_LEN {
+    return Err(OpError::InvalidSchedule);
+  };
+  if ctx
+    .reloadable
+    .lock()
+    .max_message_size
+    .is_some_and(|max| m.contents.len() > max)
+  {
+    return Err(OpError::MessageTooLarge);
+  };
+  ctx.check_visibility_delay(m.visibility_timeout_secs.into())?;
+  if !m.attributes.is_empty() && (ctx.format_version < 4 || !attributes_are_valid(&m.attributes)) {
+    return Err(OpError::InvalidAttributes);
+  };
+  if m
+    .group_id
+    .as_deref()
+    .is_some_and(|g| ctx.format_version < 7 || !group_id_is_valid(g))
+  {
+    return Err(OpError::InvalidGroupId);
+  };
+  if m.ttl_secs.is_some() && ctx.format_version < 5 {
+    return Err(OpError::TtlUnsupported);
+  };
+  Ok(())
+}
+
+/// Creates or replaces a schedule. Replacing a schedule restarts it from now.
+#[instrument(skip_all, fields(name = name))]
+pub(crate) async fn op_set_schedule(
+  ctx: &Ctx,
+  name: &str,
+  req: OpSetScheduleInput,
+) -> OpResult<ScheduleInfo> {
+  schedule_is_valid(ctx, name, &req.message)?;
+  let cron = CronSchedule::parse(&req.cron).ok_or(OpError::InvalidSchedule)?;
+  let _ops = ctx.schedule_ops.lock().await;
+  let schedule = Schedule {
+    next_run: cron.next_after(ctx.clock.now()).unwrap_or(i64::MAX),
+    cron,
+    cron_expr: req.cron,
+    message: req.message,
+  };
+  let mut b = WriteBatchWithTransaction::default();
+  b.put(schedule_key(name), encode_schedule(&schedule));
+  ctx.db_write(b).await?;
+  // The write may still become durable if syncing fails, so the schedule is stored regardless.
+  let synced = ctx.db_sync(0).await;
+  let info = schedule.info(name);
+  ctx.schedules.lock().insert(name.to_string(), schedule);
+  synced?;
+  Ok(info)
+}
+
+#[instrument(skip_all, fields(name = name))]
+pub(crate) async fn op_remove_schedule(ctx: &Ctx, name: &str) -> OpResult<()> {
+  let _ops = ctx.schedule_ops.lock().await;
+  if !ctx.schedules.lock().contains_key(name) {
+    return Err(OpError::ScheduleNotFound);
+  };
+  let mut b = WriteBatchWithTransaction::default();
+  b.delete(schedule_key(name));
+  ctx.db_write(b).await?;
+  // Likewise, the schedule is removed even if syncing fails.
+  let synced = ctx.db_sync(0).await;
+  ctx.schedules.lock().remove(name);
+  synced
+}
+
+pub(crate) fn get_schedule(ctx: &Ctx, name: &str) -> Option<ScheduleInfo> {
+  ctx.schedules.lock().get(name).map(|s| s.info(name))
+}
+
+pub(crate) fn list_schedules(ctx: &Ctx) -> Vec<ScheduleInfo> {
+  ctx
+    .schedules
+    .lock()
+    .iter()
+    .map(|(name, s)| s.info(name))
+    .collect()
+}
+
+// Pushes the message of one due schedule, and advances the schedule to its next run after now in the same write, so that a run is never pushed twice or skipped because of a crash.
+async fn fire_schedule(ctx: &Ctx, name: &str, now: i64) -> OpResult<()> {
+  let (message, next_run) = {
+    let schedules = ctx.schedules.lock();
+    let s = &schedules[name];
+    (
+      s.message.clone(),
+      s.cron.next_after(now).unwrap_or(i64::MAX),
+    )
+  };
+  let mut push = prepare_push(ctx, OpPushInput {
+    messages: vec![OpPushInputMessage {
+      contents: message.contents.clone(),
+      visibility_timeout_secs: message.visibility_timeout_secs,
+      visibility_jitter_secs: 0,
+      priority: message.priority,
+      attributes: message.attributes.clone(),
+      ttl_secs: message.ttl_secs,
+      group_id: message.group_id.clone(),
+      external_id: None,
+    }],
+  })
+  .await?;
+  let mut b = take(&mut push.b);
+  let encoded = {
+    let schedules = ctx.schedules.lock();
+    let s = &schedules[name];
+    encode_schedule(&Schedule {
+      cron_expr: s.cron_expr.clone(),
+      cron: s.cron.clone(),
+      message,
+      next_run,
+    })
+  };
+  b.put(schedule_key(name), encoded);
+  ctx.db_write(b).await?;
+  // As in `op_push`, the write has been applied, so it's reflected in memory even if syncing fails.
+  let synced = ctx.db_sync(push.next_id()).await;
+  finish_push(ctx, push);
+  if let Some(s) = ctx.schedules.lock().get_mut(name) {
+    s.next_run = next_run;
+  };
+  synced?;
+  ctx
+    .metrics
+    .scheduled_push_counter
+    .fetch_add(1, Ordering::Relaxed);
+  Ok(())
+}
+
+/// Pushes the message of every schedule that's due. Runs that were missed, e.g. while the server was down, are pushed once rather than once for each missed run. If a push fails, e.g. because pushes are suspended, it's retried the next time this is called.
+#[instrument(skip_all)]
+pub(crate) async fn op_run_due_schedules(ctx: &Ctx) -> usize {
+  let _ops = ctx.schedule_ops.lock().await;
+  let now = ctx.clock.now();
+  let due = ctx
+    .schedules
+    .lock()
+    .iter()
+    .filter(|(_, s)| s.next_run <= now)
+    .map(|(name, _)| name.clone())
+    .collect::<Vec<_>>();
+  let mut pushed = 0;
+  for name in due {
+    match fire_schedule(ctx, &name, now).await {
+      Ok(()) => pushed += 1,
+      Err(err) => {
+        ctx
+          .metrics
+          .failed_schedule_counter
+          .fetch_add(1, Ordering::Relaxed);
+        warn!(schedule = name, ?err, "failed to push scheduled message");
+      }
+    };
+  }
+  pushed
+}
@@ -0,0 +1,103 @@
+use super::result::OpError;
+use super::result::OpResult;
+use crate::attributes::MessageAttributeValue;
+use crate::attributes::MessageAttributes;
+use crate::ctx::Ctx;
+use crate::db::schema_key;
+use crate::db::schema_key_version;
+use crate::db::SCHEMA_KEY_PREFIX;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::instrument;
+
+/// The attribute a pushed message uses to declare the version of the queue's schema its contents conform to.
+pub const SCHEMA_VERSION_ATTRIBUTE: &str = "schema_version";
+
+#[derive(Deserialize)]
+pub struct OpRegisterSchemaInput {
+  #[serde(with = "serde_bytes")]
+  pub schema: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct OpRegisterSchemaOutput {
+  pub version: u32,
+}
+
+#[derive(Serialize)]
+pub struct OpGetSchemaOutput {
+  #[serde(with = "serde_bytes")]
+  pub schema: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct OpListSchemasOutput {
+  pub versions: Vec<u32>,
+}
+
+/// Returns the schema version a message declares, if any.
+pub(crate) fn declared_schema_version(attrs: &MessageAttributes) -> Option<OpResult<u32>> {
+  attrs.get(SCHEMA_VERSION_ATTRIBUTE).map(|v| match v {
+    MessageAttributeValue::Integer(v) => (*v).try_into().map_err(|_| OpError::UnknownSchemaVersion),
+    _ => Err(OpError::UnknownSchemaVersion),
+  })
+}
+
+pub(crate) async fn schema_exists(ctx: &Ctx, version: u32) -> OpResult<bool> {
+  // Schemas can never be changed or removed once registered, so only versions known to exist are cached.
+  if ctx.known_schema_versions.lock().contains(&version) {
+    return Ok(true);
+  };
+  let exists = ctx.db_get(schema_key(version)).await?.is_some();
+  if exists {
+    ctx.known_schema_versions.lock().insert(version);
+  };
+  Ok(exists)
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn op_register_schema(
+  ctx: &Ctx,
+  req: OpRegisterSchemaInput,
+) -> OpResult<OpRegisterSchemaOutput> {
+  // Registrations must be serialised so that two different schemas aren't assigned the same version.
+  let _registration = ctx.schema_registration.lock().await;
+  let existing = ctx.db_scan_prefix(SCHEMA_KEY_PREFIX).await?;
+  // Registering an existing schema again is a no-op, so producers can safely register their schema on startup.
+  if let Some((k, _)) = existing.iter().find(|(_, v)| **v == *req.schema) {
+    return Ok(OpRegisterSchemaOutput {
+      version: schema_key_version(k),
+    });
+  };
+  let version = existing
+    .last()
+    .map(|(k, _)| schema_key_version(k) + 1)
+    .unwrap_or(1);
+  let mut b = WriteBatchWithTransaction::default();
+  b.put(schema_key(version), req.schema);
+  ctx.db_write(b).await?;
+  // The version is known even if syncing fails, as the write may still become durable.
+  let synced = ctx.db_sync(0).await;
+  ctx.known_schema_versions.lock().insert(version);
+  synced?;
+  Ok(OpRegisterSchemaOutput { version })
+}
+
+pub(crate) async fn op_get_schema(ctx: &Ctx, version: u32) -> OpResult<OpGetSchemaOutput> {
+  let schema = ctx
+    .db_get(schema_key(version))
+    .await?
+    .ok_or(OpError::UnknownSchemaVersion)?;
+  Ok(OpGetSchemaOutput { schema })
+}
+
+pub(crate) async fn op_list_schemas(ctx: &Ctx) -> OpResult<OpListSchemasOutput> {
+  let versions = ctx
+    .db_scan_prefix(SCHEMA_KEY_PREFIX)
+    .await?
+    .iter()
+    .map(|(k, _)| schema_key_version(k))
+    .collect();
+  Ok(OpListSchemasOutput { versions })
+}
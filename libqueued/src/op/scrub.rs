@@ -0,0 +1,219 @@
+use super::result::OpResult;
+use crate::attributes::try_decode_attributes;
+use crate::ctx::Ctx;
+use crate::db::quarantine_key;
+use crate::db::rocksdb_key;
+use crate::db::rocksdb_key_id;
+use crate::db::RocksDbKeyPrefix;
+use crate::external_id::external_id_is_valid;
+use crate::group::group_id_is_valid;
+use crate::storage::Storage;
+use futures::future::try_join_all;
+use num_traits::FromPrimitive;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::task::spawn_blocking;
+use tracing::instrument;
+use tracing::warn;
+
+#[derive(Deserialize, Default)]
+pub struct OpScrubInput {
+  /// If true, the keys of corrupt messages are moved out of the queue into a separate keyspace, where they can still be inspected. Messages currently being polled or updated aren't quarantined.
+  #[serde(default)]
+  pub quarantine: bool,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrubProblem {
+  /// A key's value has the wrong length or can't be decoded.
+  UndecodableValue,
+  /// The message has an inline record, as well as keys only used by messages with separately stored contents.
+  InlineWithSplitKeys,
+  MissingContents,
+  /// The message has both stored and offloaded contents.
+  DuplicateContents,
+  MissingVisibleTime,
+  /// Keys exist without the message itself. These are usually harmless leftovers of a deleted message, e.g. a pin that raced with a delete.
+  OrphanedKeys,
+}
+
+#[derive(Serialize)]
+pub struct OpScrubIssue {
+  pub id: u64,
+  pub problem: ScrubProblem,
+  pub quarantined: bool,
+}
+
+#[derive(Serialize)]
+pub struct OpScrubOutput {
+  /// How many message IDs had at least one key.
+  pub scanned: usize,
+  pub issues: Vec<OpScrubIssue>,
+}
+
+// Which keys of a message exist, and whether any of their values can't be decoded.
+#[derive(Default)]
+pub(crate) struct MessageKeys {
+  present: u32,
+  undecodable: bool,
+}
+
+impl MessageKeys {
+  fn bit(p: RocksDbKeyPrefix) -> u32 {
+    1 << (p as u8 - RocksDbKeyPrefix::MessagePollTag as u8)
+  }
+
+  fn add(&mut self, p: RocksDbKeyPrefix, v: &[u8]) {
+    self.present |= Self::bit(p);
+    if !value_is_decodable(p, v) {
+      self.undecodable = true;
+    };
+  }
+
+  fn has(&self, p: RocksDbKeyPrefix) -> bool {
+    self.present & Self::bit(p) != 0
+  }
+
+  pub fn problem(&self) -> Option<ScrubProblem> {
+    use RocksDbKeyPrefix::*;
+    if self.undecodable {
+      return Some(ScrubProblem::UndecodableValue);
+    };
+    if self.has(MessageInline) {
+      if [
+        MessagePollTag,
+        MessageVisibleTimestampSec,
+        MessageData,
+        MessageOffloaded,
+      ]
+      .into_iter()
+      .any(|p| self.has(p))
+      {
+        return Some(ScrubProblem::InlineWithSplitKeys);
+      };
+      return None;
+    };
+    if self.has(MessageVisibleTimestampSec) {
+      return match (self.has(MessageData), self.has(MessageOffloaded)) {
+        (true, false) | (false, true) => None,
+        (false, false) => Some(ScrubProblem::MissingContents),
+        (true, true) => Some(ScrubProblem::DuplicateContents),
+      };
+    };
+    if self.has(MessageData) || self.has(MessageOffloaded) {
+      return Some(ScrubProblem::MissingVisibleTime);
+    };
+    Some(ScrubProblem::OrphanedKeys)
+  }
+}
+
+fn value_is_decodable(p: RocksDbKeyPrefix, v: &[u8]) -> bool {
+  match p {
+    RocksDbKeyPrefix::MessagePollTag => v.len() == 4,
+    RocksDbKeyPrefix::MessageVisibleTimestampSec => v.len() == 5,
+    RocksDbKeyPrefix::MessageData => true,
+    RocksDbKeyPrefix::MessagePinned => true,
+    RocksDbKeyPrefix::MessagePriority => v.len() == 1,
+    RocksDbKeyPrefix::MessageInline => v.len() >= 9,
+    RocksDbKeyPrefix::MessageOffloaded => true,
+    RocksDbKeyPrefix::MessagePollCount => v.len() == 4,
+    RocksDbKeyPrefix::MessageAttributes => try_decode_attributes(v).is_some(),
+    RocksDbKeyPrefix::MessageExpiry => v.len() == 8,
+    RocksDbKeyPrefix::MessagePushedAt => v.len() == 8,
+    RocksDbKeyPrefix::MessageGroup => std::str::from_utf8(v).is_ok_and(group_id_is_valid),
+    RocksDbKeyPrefix::MessageExternalId => std::str::from_utf8(v).is_ok_and(external_id_is_valid),
+    RocksDbKeyPrefix::MessageCompressed => true,
+  }
+}
+
+// Reads the keys of every message in storage. This reads every key, so it's slow for large queues.
+pub(crate) fn scan_message_keys(
+  storage: &dyn Storage,
+) -> Result<BTreeMap<u64, MessageKeys>, String> {
+  let prefixes = RocksDbKeyPrefix::MESSAGE_PREFIXES.map(|p| [p as u8]);
+  let prefixes = prefixes.iter().map(|p| &p[..]).collect::<Vec<_>>();
+  let mut messages = BTreeMap::<u64, MessageKeys>::new();
+  storage.scan(&prefixes, &mut |k, v| {
+    // Message keys are always 9 bytes, so anything else under these prefixes can't be interpreted and is ignored.
+    if k.len() != 9 {
+      return;
+    };
+    let p = RocksDbKeyPrefix::from_u8(k[0]).unwrap();
+    messages.entry(rocksdb_key_id(k)).or_default().add(p, v);
+  })?;
+  Ok(messages)
+}
+
+// Moves all keys of a corrupt message into the quarantine keyspace, if it's still corrupt. Returns whether it was quarantined.
+async fn quarantine(ctx: &Ctx, id: u64) -> OpResult<bool> {
+  let keys = RocksDbKeyPrefix::MESSAGE_PREFIXES.map(|p| rocksdb_key(p, id));
+  let values = try_join_all(keys.iter().map(|&k| ctx.db_get(k))).await?;
+  let mut found = MessageKeys::default();
+  let mut b = WriteBatchWithTransaction::default();
+  for ((p, k), v) in RocksDbKeyPrefix::MESSAGE_PREFIXES
+    .into_iter()
+    .zip(keys)
+    .zip(values)
+  {
+    if let Some(v) = v {
+      found.add(p, &v);
+      b.delete(k);
+      b.put(quarantine_key(&k), v);
+    };
+  }
+  // It may have been deleted since it was scanned.
+  if found.present == 0 || found.problem().is_none() {
+    return Ok(false);
+  };
+  ctx.db_write(b).await?;
+  Ok(true)
+}
+
+/// Checks that every message's keys are decodable and consistent with each other, optionally quarantining corrupt messages.
+#[instrument(skip_all)]
+pub(crate) async fn op_scrub(ctx: &Ctx, req: OpScrubInput) -> OpResult<OpScrubOutput> {
+  let _maintenance = ctx.begin_maintenance();
+  let storage = ctx.storage.clone();
+  let res = spawn_blocking(move || scan_message_keys(&*storage))
+    .await
+    .unwrap();
+  let messages = ctx.record_storage_read_result(res)?;
+
+  let mut issues = Vec::new();
+  for (&id, keys) in messages.iter() {
+    let Some(problem) = keys.problem() else {
+      continue;
+    };
+    warn!(id, ?problem, "found corrupt message");
+    let mut quarantined = false;
+    if req.quarantine {
+      // Removing the message from the index prevents any other operation from changing it while it's being quarantined. Orphaned keys aren't in the index at all.
+      let removed = ctx.messages.remove(id);
+      if removed.is_some() || problem == ScrubProblem::OrphanedKeys {
+        let res = quarantine(ctx, id).await;
+        if !matches!(res, Ok(true)) {
+          if let Some(m) = removed {
+            ctx.messages.restore(m);
+          };
+        };
+        quarantined = res?;
+      };
+    };
+    issues.push(OpScrubIssue {
+      id,
+      problem,
+      quarantined,
+    });
+  }
+  if issues.iter().any(|i| i.quarantined) {
+    ctx.db_sync(0).await?;
+  };
+
+  Ok(OpScrubOutput {
+    scanned: messages.len(),
+    issues,
+  })
+}
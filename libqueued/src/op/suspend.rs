@@ -0,0 +1,21 @@
+use super::result::OpResult;
+use crate::ctx::Ctx;
+use crate::db::SUSPENSION_KEY;
+use crate::suspend::SuspensionChange;
+use rocksdb::WriteBatchWithTransaction;
+use tracing::instrument;
+
+/// Suspends or resumes operations on the queue. Unlike suspension by index verification, this is persisted, so it survives restarts.
+#[instrument(skip_all)]
+pub(crate) async fn op_set_suspension(ctx: &Ctx, change: SuspensionChange) -> OpResult<()> {
+  // Serialised so that concurrent changes aren't persisted out of order.
+  let _ops = ctx.suspension_ops.lock().await;
+  let bits = ctx.suspension.bits_with(change);
+  let mut b = WriteBatchWithTransaction::default();
+  b.put(SUSPENSION_KEY, [bits]);
+  ctx.db_write(b).await?;
+  // The write may still become durable if syncing fails, so the suspension takes effect regardless.
+  let synced = ctx.db_sync(0).await;
+  ctx.suspension.set_bits(bits);
+  synced
+}
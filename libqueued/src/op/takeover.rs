@@ -0,0 +1,90 @@
+use super::result::OpError;
+use super::result::OpResult;
+use super::update::set_visible_times;
+use crate::audit::AuditAction;
+use crate::ctx::Ctx;
+use crate::lifecycle::MessageState;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use tracing::instrument;
+
+#[derive(Deserialize)]
+pub struct OpTakeoverInput {
+  pub id: u64,
+  /// The message is only taken over if its current lease started at least this many seconds ago, i.e. it hasn't been polled or had its visibility changed since then.
+  pub min_lease_age_secs: i64,
+  pub visibility_timeout_secs: i64,
+}
+
+#[derive(Serialize)]
+pub struct OpTakeoverOutput {
+  /// None if the message isn't in flight or its lease is too recent.
+  pub new_poll_tag: Option<u32>,
+  /// How many seconds ago the message's current lease started, if it's in flight. When the message wasn't taken over, retry once this reaches `min_lease_age_secs`.
+  pub lease_age_secs: Option<i64>,
+}
+
+// Takeovers change the message's visibility, so they are suspended along with updates.
+#[instrument(skip_all, fields(id = req.id))]
+pub(crate) async fn op_takeover(ctx: &Ctx, req: OpTakeoverInput) -> OpResult<OpTakeoverOutput> {
+  if ctx.suspension.is_update_suspended() {
+    ctx
+      .metrics
+      .suspended_update_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::Suspended);
+  };
+
+  ctx.check_visibility_timeout(req.visibility_timeout_secs)?;
+
+  let now = ctx.clock.now();
+  let lease = {
+    let messages = ctx.messages.lock(req.id);
+    match messages.state(req.id, now) {
+      MessageState::InFlight => Some((
+        messages.poll_tag(req.id).unwrap(),
+        (now - messages.lease_started(req.id)).max(0),
+      )),
+      _ => None,
+    }
+  };
+  let Some((poll_tag, lease_age_secs)) = lease else {
+    ctx
+      .metrics
+      .rejected_takeover_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Ok(OpTakeoverOutput {
+      new_poll_tag: None,
+      lease_age_secs: None,
+    });
+  };
+  if lease_age_secs < req.min_lease_age_secs {
+    ctx
+      .metrics
+      .rejected_takeover_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Ok(OpTakeoverOutput {
+      new_poll_tag: None,
+      lease_age_secs: Some(lease_age_secs),
+    });
+  };
+
+  // If the previous holder updates, nacks, or deletes the message in the meantime, its poll tag will no longer match and the takeover fails, as the holder isn't gone after all.
+  let new_poll_tag = set_visible_times(
+    ctx,
+    vec![(req.id, poll_tag, now + req.visibility_timeout_secs)],
+    AuditAction::Takeover,
+  )
+  .await?[0];
+  match new_poll_tag {
+    Some(_) => &ctx.metrics.successful_takeover_counter,
+    None => &ctx.metrics.rejected_takeover_counter,
+  }
+  .fetch_add(1, Ordering::Relaxed);
+
+  Ok(OpTakeoverOutput {
+    new_poll_tag,
+    lease_age_secs: Some(lease_age_secs),
+  })
+}
@@ -0,0 +1,109 @@
+use super::delete::finish_delete;
+use super::delete::OpDeleteInputMessage;
+use super::push::finish_push;
+use super::push::prepare_push;
+use super::push::OpPushInput;
+use super::push::OpPushInputMessage;
+use super::result::OpError;
+use super::result::OpResult;
+use crate::audit::AuditAction;
+use crate::ctx::Ctx;
+use crate::db::rocksdb_delete_messages;
+use crate::lifecycle::MessageTransition;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde::Serialize;
+use std::mem::take;
+use std::sync::atomic::Ordering;
+use tracing::instrument;
+
+#[derive(Deserialize)]
+pub struct OpTransactionInput {
+  /// In-flight messages to delete, e.g. the inputs of a pipeline stage.
+  #[serde(default)]
+  pub deletes: Vec<OpDeleteInputMessage>,
+  /// Messages to push, e.g. the outputs of a pipeline stage.
+  #[serde(default)]
+  pub pushes: Vec<OpPushInputMessage>,
+}
+
+#[derive(Serialize)]
+pub struct OpTransactionOutput {
+  /// The IDs of the pushed messages, in request order.
+  pub ids: Vec<u64>,
+}
+
+/// Deletes and pushes messages in a single write, so that either all of them happen or none do. If any message to delete doesn't exist or its poll tag doesn't match, e.g. because its lease expired and it was polled again, nothing is deleted or pushed and this fails with `OpError::MessageNotFound`.
+#[instrument(skip_all, fields(deletes = req.deletes.len(), pushes = req.pushes.len()))]
+pub(crate) async fn op_transaction(
+  ctx: &Ctx,
+  req: OpTransactionInput,
+) -> OpResult<OpTransactionOutput> {
+  if ctx.suspension.is_delete_suspended() {
+    ctx
+      .metrics
+      .suspended_delete_counter
+      .fetch_add(1, Ordering::Relaxed);
+    return Err(OpError::Suspended);
+  };
+
+  // This also stores offloaded contents, which are simply orphaned if the transaction then fails.
+  let mut push = prepare_push(ctx, OpPushInput {
+    messages: req.pushes,
+  })
+  .await?;
+
+  // Removed messages are restored if the transaction fails, so they are still in flight until then.
+  let _busy = ctx.begin_busy_op();
+  let now = ctx.clock.now();
+  let mut removed = Vec::new();
+  for m in req.deletes {
+    let mut msgs = ctx.messages.lock(m.id);
+    let from = msgs.state(m.id, now);
+    let poll_tag_matches = msgs.poll_tag_matches(m.id, m.poll_tag);
+    if ctx
+      .check_transition(m.id, MessageTransition::Delete, from, poll_tag_matches)
+      .is_err()
+    {
+      drop(msgs);
+      for m in removed {
+        ctx.messages.restore(m);
+      }
+      ctx
+        .metrics
+        .rejected_transaction_counter
+        .fetch_add(1, Ordering::Relaxed);
+      return Err(OpError::MessageNotFound);
+    };
+    let ts = msgs.remove_if_poll_tag_matches(m.id, m.poll_tag).unwrap();
+    removed.push(ctx.messages.forget(&mut msgs, m.id, ts, m.poll_tag));
+  }
+
+  let mut b = take(&mut push.b);
+  for r in removed.iter() {
+    ctx.audit(&mut b, r.id, AuditAction::Delete, Some(r.poll_tag), None);
+  }
+  let mut ids = removed.iter().map(|r| r.id).collect_vec();
+  ids.sort_unstable();
+  rocksdb_delete_messages(&mut b, &ids);
+  ctx.external_ids.delete(&mut b, &ids);
+  if let Err(err) = ctx.db_write(b).await {
+    // The messages are still in storage, so restore them.
+    for m in removed {
+      ctx.messages.restore(m);
+    }
+    return Err(err);
+  };
+  // The write has been applied, so even if syncing fails, the messages must not be restored as they may be deleted once it becomes durable.
+  let synced = ctx.db_sync(push.next_id()).await;
+  finish_delete(ctx, &removed, MessageTransition::Delete).await;
+  synced?;
+  let out = finish_push(ctx, push);
+
+  ctx
+    .metrics
+    .successful_transaction_counter
+    .fetch_add(1, Ordering::Relaxed);
+
+  Ok(OpTransactionOutput { ids: out.ids })
+}
@@ -0,0 +1,58 @@
+use super::purge::delete_removed_messages;
+use super::result::OpError;
+use super::result::OpResult;
+use crate::ctx::Ctx;
+use crate::db::DEFAULT_TTL_KEY;
+use crate::lifecycle::MessageTransition;
+use off64::int::create_u32_le;
+use rocksdb::WriteBatchWithTransaction;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use tracing::instrument;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct DefaultTtlState {
+  /// Messages pushed without their own TTL expire this many seconds after being pushed. If unset, they never expire.
+  pub default_ttl_secs: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpExpireOutput {
+  pub expired: usize,
+}
+
+pub(crate) async fn op_set_default_ttl(ctx: &Ctx, state: DefaultTtlState) -> OpResult<()> {
+  if state.default_ttl_secs.is_some() && ctx.format_version < 5 {
+    return Err(OpError::TtlUnsupported);
+  };
+  let mut b = WriteBatchWithTransaction::default();
+  match state.default_ttl_secs {
+    Some(secs) => b.put(DEFAULT_TTL_KEY, create_u32_le(secs)),
+    None => b.delete(DEFAULT_TTL_KEY),
+  };
+  ctx.db_write(b).await?;
+  // The write may still become durable if syncing fails, so the default TTL takes effect regardless.
+  let synced = ctx.db_sync(0).await;
+  *ctx.default_ttl_secs.lock() = state.default_ttl_secs;
+  synced
+}
+
+// Expiring is a bulk delete, so like purging it doesn't happen while deletes are suspended. Unlike purging, this is done in the background, so being suspended isn't an error or counted as a suspended request.
+#[instrument(skip_all)]
+pub(crate) async fn op_expire(ctx: &Ctx) -> OpResult<OpExpireOutput> {
+  if ctx.suspension.is_delete_suspended() {
+    return Ok(OpExpireOutput { expired: 0 });
+  };
+
+  let removed = ctx.messages.remove_expired(ctx.clock.now());
+  if removed.is_empty() {
+    return Ok(OpExpireOutput { expired: 0 });
+  };
+  let expired = delete_removed_messages(ctx, removed, MessageTransition::Expire).await?;
+  ctx
+    .metrics
+    .expired_counter
+    .fetch_add(expired as u64, Ordering::Relaxed);
+  Ok(OpExpireOutput { expired })
+}
@@ -1,30 +1,40 @@
 use super::result::OpError;
 use super::result::OpResult;
+use crate::audit::AuditAction;
 use crate::ctx::Ctx;
+use crate::db::inline_record;
+use crate::db::inline_record_contents;
 use crate::db::rocksdb_key;
-use crate::db::rocksdb_write_opts;
 use crate::db::RocksDbKeyPrefix;
-use chrono::Utc;
+use crate::lifecycle::MessageTransition;
+use futures::future::try_join_all;
 use off64::int::create_i40_le;
 use off64::int::create_u32_le;
 use rocksdb::WriteBatchWithTransaction;
 use serde::Deserialize;
 use serde::Serialize;
 use std::sync::atomic::Ordering;
-use tokio::task::spawn_blocking;
+use tracing::instrument;
 
-#[derive(Deserialize)]
-pub struct OpUpdateInput {
+#[derive(Serialize, Deserialize)]
+pub struct OpUpdateInputMessage {
   pub id: u64,
   pub poll_tag: u32,
   pub visibility_timeout_secs: i64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct OpUpdateInput {
+  pub messages: Vec<OpUpdateInputMessage>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct OpUpdateOutput {
-  pub new_poll_tag: u32,
+  /// In the same order as the input messages. None if the message wasn't found or its poll tag didn't match.
+  pub new_poll_tags: Vec<Option<u32>>,
 }
 
+#[instrument(skip_all, fields(count = req.messages.len()))]
 pub(crate) async fn op_update(ctx: &Ctx, req: OpUpdateInput) -> OpResult<OpUpdateOutput> {
   if ctx.suspension.is_update_suspended() {
     ctx
@@ -34,46 +44,164 @@ pub(crate) async fn op_update(ctx: &Ctx, req: OpUpdateInput) -> OpResult<OpUpdat
     return Err(OpError::Suspended);
   };
 
-  if !ctx
-    .messages
-    .lock()
-    .remove_if_poll_tag_matches(req.id, req.poll_tag)
-  {
-    ctx
-      .metrics
-      .missing_update_counter
-      .fetch_add(1, Ordering::Relaxed);
-    return Err(OpError::MessageNotFound);
+  for m in req.messages.iter() {
+    ctx.check_visibility_timeout(m.visibility_timeout_secs)?;
+  }
+
+  let now = ctx.clock.now();
+  let new_poll_tags = set_visible_times(
+    ctx,
+    req
+      .messages
+      .into_iter()
+      .map(|m| (m.id, m.poll_tag, now + m.visibility_timeout_secs))
+      .collect(),
+    AuditAction::Update,
+  )
+  .await?;
+
+  let updated = new_poll_tags.iter().filter(|t| t.is_some()).count();
+  ctx
+    .metrics
+    .missing_update_counter
+    .fetch_add((new_poll_tags.len() - updated) as u64, Ordering::Relaxed);
+  ctx
+    .metrics
+    .successful_update_counter
+    .fetch_add(updated as u64, Ordering::Relaxed);
+
+  Ok(OpUpdateOutput { new_poll_tags })
+}
+
+// A message whose visible time is being changed. It's removed from the index until the change is committed or rolled back.
+struct FoundMessage {
+  id: u64,
+  shard: usize,
+  old_visible_time: i64,
+  poll_tag: u32,
+  new_visible_time: i64,
+  // Whether its contents are stored separately from its inline record.
+  split: bool,
+  new_poll_tag: u32,
+}
+
+/// Changes the visible times of messages currently held with the provided poll tags, using one write and sync, and records it in the audit log as `action`. Returns the new poll tag of each message, or None if it wasn't found or its poll tag didn't match.
+pub(crate) async fn set_visible_times(
+  ctx: &Ctx,
+  changes: Vec<(u64, u32, i64)>,
+  action: AuditAction,
+) -> OpResult<Vec<Option<u32>>> {
+  let _busy = ctx.begin_busy_op();
+  let (found, new_poll_tags) = {
+    let now = ctx.clock.now();
+    let mut found = Vec::new();
+    let mut new_poll_tags = Vec::new();
+    for (id, poll_tag, new_visible_time) in changes {
+      let shard = ctx.messages.shard_of(id);
+      let mut messages = ctx.messages.lock_shard(shard);
+      let from = messages.state(id, now);
+      let poll_tag_matches = messages.poll_tag_matches(id, poll_tag);
+      if ctx
+        .check_transition(
+          id,
+          MessageTransition::ChangeVisibility,
+          from,
+          poll_tag_matches,
+        )
+        .is_err()
+      {
+        new_poll_tags.push(None);
+        continue;
+      };
+      let old_visible_time = messages.remove_if_poll_tag_matches(id, poll_tag).unwrap();
+      let new_poll_tag = ctx.new_poll_tag(poll_tag);
+      found.push(FoundMessage {
+        id,
+        shard,
+        old_visible_time,
+        poll_tag,
+        new_visible_time,
+        split: messages.is_split(id),
+        new_poll_tag,
+      });
+      new_poll_tags.push(Some(new_poll_tag));
+    }
+    (found, new_poll_tags)
   };
-  let new_visible_time = Utc::now().timestamp() + req.visibility_timeout_secs as i64;
-  let new_poll_tag = req.poll_tag + 1;
 
-  let db = ctx.db.clone();
-  spawn_blocking(move || {
-    let mut b = WriteBatchWithTransaction::default();
-    b.put(
-      rocksdb_key(RocksDbKeyPrefix::MessagePollTag, req.id),
-      create_u32_le(new_poll_tag),
+  let rollback = || {
+    ctx.messages.with_each(
+      found
+        .iter()
+        .map(|m| (m.shard, (m.id, m.old_visible_time, m.poll_tag))),
+      |messages, (id, old_visible_time, poll_tag)| messages.insert(id, old_visible_time, poll_tag),
     );
-    b.put(
-      rocksdb_key(RocksDbKeyPrefix::MessageVisibleTimestampSec, req.id),
-      create_i40_le(new_visible_time),
-    );
-    db.write_opt(b, &rocksdb_write_opts()).unwrap();
-  })
-  .await
-  .unwrap();
-  ctx.batch_sync.submit_and_wait(0).await;
+  };
 
-  ctx
-    .messages
-    .lock()
-    .insert(req.id, new_visible_time, new_poll_tag);
+  // Inline messages must be read before the write, as their contents are rewritten along with their new state.
+  let inline_res = try_join_all(
+    found
+      .iter()
+      .filter(|m| !m.split)
+      .map(|m| ctx.db_get(rocksdb_key(RocksDbKeyPrefix::MessageInline, m.id))),
+  )
+  .await;
+  let mut inline_raws = match inline_res {
+    Ok(raws) => raws.into_iter(),
+    Err(err) => {
+      rollback();
+      return Err(err);
+    }
+  };
 
+  let mut b = WriteBatchWithTransaction::default();
+  for m in found.iter() {
+    ctx.audit(&mut b, m.id, action, Some(m.poll_tag), Some(m.new_poll_tag));
+    if m.split {
+      b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessagePollTag, m.id),
+        create_u32_le(m.new_poll_tag),
+      );
+      b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessageVisibleTimestampSec, m.id),
+        create_i40_le(m.new_visible_time),
+      );
+    } else {
+      let raw = inline_raws.next().unwrap().unwrap();
+      b.put(
+        rocksdb_key(RocksDbKeyPrefix::MessageInline, m.id),
+        inline_record(
+          m.new_visible_time,
+          m.new_poll_tag,
+          &inline_record_contents(raw),
+        ),
+      );
+    };
+  }
+  let commit_res = ctx.db_commit(b).await;
+  let now = ctx.clock.now();
+  match &commit_res {
+    Err(err) if !err.applied => {
+      rollback();
+      return Err(err.err);
+    }
+    _ => {}
+  };
+  // The write has already been applied, so the new state must be reflected in memory even if syncing fails.
+  ctx.messages.with_each(
+    found
+      .iter()
+      .map(|m| (m.shard, (m.id, m.new_visible_time, m.new_poll_tag))),
+    |messages, (id, new_visible_time, new_poll_tag)| {
+      messages.set_leased_at(id, Some(now));
+      messages.insert(id, new_visible_time, new_poll_tag)
+    },
+  );
+  commit_res.map_err(|e| e.err)?;
   ctx
     .metrics
-    .successful_update_counter
-    .fetch_add(1, Ordering::Relaxed);
+    .transitions
+    .record(MessageTransition::ChangeVisibility, found.len());
 
-  Ok(OpUpdateOutput { new_poll_tag })
+  Ok(new_poll_tags)
 }
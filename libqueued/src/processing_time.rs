@@ -0,0 +1,58 @@
+use parking_lot::Mutex;
+
+// Upper bounds in seconds; there's an implicit final bucket for anything longer.
+const BUCKETS: [i64; 22] = [
+  1, 2, 3, 5, 10, 15, 20, 30, 45, 60, 90, 120, 180, 300, 600, 900, 1200, 1800, 3600, 7200, 21600,
+  43200,
+];
+// Once this many samples have been recorded, all counts are halved, so that the distribution follows changes in how long consumers take instead of being dominated by old samples.
+const DECAY_AT_SAMPLES: u64 = 10_000;
+/// Fewer samples than this don't give a meaningful recommendation.
+const MIN_SAMPLES: u64 = 100;
+
+/// The distribution of how long consumers took to process messages, i.e. from when a message was polled until it was deleted, which is what a visibility timeout must cover.
+#[derive(Default)]
+pub struct ProcessingTimes {
+  counts: Mutex<[u64; BUCKETS.len() + 1]>,
+}
+
+impl ProcessingTimes {
+  pub(crate) fn record(&self, secs: impl IntoIterator<Item = i64>) {
+    let mut counts = self.counts.lock();
+    for secs in secs {
+      let i = BUCKETS
+        .iter()
+        .position(|&le| secs <= le)
+        .unwrap_or(BUCKETS.len());
+      counts[i] += 1;
+    }
+    if counts.iter().sum::<u64>() >= DECAY_AT_SAMPLES {
+      for c in counts.iter_mut() {
+        *c /= 2;
+      }
+    };
+  }
+
+  /// How many samples the distribution currently has, which is decayed over time.
+  pub fn samples(&self) -> u64 {
+    self.counts.lock().iter().sum()
+  }
+
+  /// The upper bound in seconds of the bucket containing the `p` percentile (0.0 to 1.0), or None if there aren't enough samples or it's longer than the largest bucket.
+  pub fn percentile_secs(&self, p: f64) -> Option<i64> {
+    let counts = self.counts.lock();
+    let total = counts.iter().sum::<u64>();
+    if total < MIN_SAMPLES {
+      return None;
+    };
+    let rank = ((total as f64 * p).ceil() as u64).max(1);
+    let mut cumulative = 0;
+    for (i, c) in counts.iter().enumerate() {
+      cumulative += c;
+      if cumulative >= rank {
+        return BUCKETS.get(i).copied();
+      };
+    }
+    None
+  }
+}
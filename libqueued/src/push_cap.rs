@@ -0,0 +1,56 @@
+use std::time::Duration;
+use std::time::Instant;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// While maintenance (compaction, a snapshot, or a scrub) is running, caps pushes to a percentage of the rate before it started, so that maintenance competing for disk doesn't slow down foreground operations too much. Rates are measured in messages per one-second window, and maintenance is checked at the start of each window.
+pub(crate) struct PushCap {
+  // Configuration.
+  percent: u64,
+  // State.
+  window_start: Instant,
+  window_count: u64,
+  // Messages pushed per second in the last window without maintenance.
+  baseline_per_sec: u64,
+  active: Option<u64>,
+}
+
+impl PushCap {
+  pub fn new(percent: u8) -> Self {
+    assert!((1..=100).contains(&percent));
+    Self {
+      percent: u64::from(percent),
+      window_start: Instant::now(),
+      window_count: 0,
+      baseline_per_sec: 0,
+      active: None,
+    }
+  }
+
+  /// Returns false if pushing `n` messages would exceed the cap. A push is always allowed if it's the first in its window, so that batches larger than the cap can still make progress.
+  pub fn try_push(&mut self, n: u64, maintenance_running: impl FnOnce() -> bool) -> bool {
+    let elapsed = self.window_start.elapsed();
+    if elapsed >= WINDOW {
+      // A window under maintenance doesn't reflect the normal rate.
+      if self.active.is_none() {
+        self.baseline_per_sec = self.window_count / elapsed.as_secs();
+      };
+      self.active =
+        maintenance_running().then(|| (self.baseline_per_sec * self.percent / 100).max(1));
+      self.window_start = Instant::now();
+      self.window_count = 0;
+    };
+    if let Some(cap) = self.active {
+      if self.window_count > 0 && self.window_count + n > cap {
+        return false;
+      };
+    };
+    self.window_count += n;
+    true
+  }
+
+  /// The current cap in messages per second, if maintenance is running.
+  pub fn active_cap(&self) -> Option<u64> {
+    self.active
+  }
+}
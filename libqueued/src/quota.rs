@@ -0,0 +1,148 @@
+use crate::metrics::Metrics;
+use crate::storage::Storage;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits on how much can be stored. Pushes that would exceed a limit fail with `OpError::QueueFull`.
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct QuotaLimits {
+  pub max_messages: Option<u64>,
+  /// Estimated bytes of live data, i.e. messages' contents and metadata. Offloaded contents aren't included.
+  pub max_data_bytes: Option<u64>,
+  /// Bytes used on disk, which includes data that's been deleted but not yet compacted away.
+  pub max_disk_bytes: Option<u64>,
+}
+
+impl QuotaLimits {
+  pub fn is_empty(&self) -> bool {
+    self.max_messages.is_none() && self.max_data_bytes.is_none() && self.max_disk_bytes.is_none()
+  }
+
+  /// Whether adding `messages` messages with `bytes` bytes of contents to `usage` would exceed any limit.
+  fn would_exceed(&self, usage: QuotaUsage, messages: u64, bytes: u64) -> bool {
+    let over =
+      |max: Option<u64>, used: u64, adding: u64| max.is_some_and(|max| used + adding > max);
+    over(self.max_messages, usage.messages, messages)
+      || over(self.max_data_bytes, usage.data_bytes, bytes)
+      || over(self.max_disk_bytes, usage.disk_bytes, bytes)
+  }
+}
+
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct QuotaUsage {
+  pub messages: u64,
+  pub data_bytes: u64,
+  pub disk_bytes: u64,
+}
+
+/// Limits shared by several queues, e.g. all queues on a server. Each queue reports its usage about once a second, so these can be exceeded until the next report.
+pub struct SharedQuota {
+  limits: QuotaLimits,
+  next_queue: AtomicU64,
+  usage: Mutex<HashMap<u64, QuotaUsage>>,
+}
+
+impl SharedQuota {
+  pub fn new(limits: QuotaLimits) -> Self {
+    Self {
+      limits,
+      next_queue: AtomicU64::new(0),
+      usage: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn limits(&self) -> QuotaLimits {
+    self.limits
+  }
+
+  /// The total usage of all queues sharing the quota, as of their last refresh.
+  pub fn total(&self) -> QuotaUsage {
+    let usage = self.usage.lock();
+    QuotaUsage {
+      messages: usage.values().map(|u| u.messages).sum(),
+      data_bytes: usage.values().map(|u| u.data_bytes).sum(),
+      disk_bytes: usage.values().map(|u| u.disk_bytes).sum(),
+    }
+  }
+}
+
+/// The limits of a single queue, and any shared limits it's subject to.
+pub(crate) struct Quota {
+  pub limits: QuotaLimits,
+  pub shared: Vec<Arc<SharedQuota>>,
+}
+
+impl Quota {
+  /// Whether pushing `messages` messages with `bytes` bytes of contents would exceed the queue's or any shared limits. The queue's own message count is exact, but everything else is as of the last refresh.
+  pub fn would_exceed(&self, metrics: &Metrics, messages: u64, bytes: u64) -> bool {
+    let own = QuotaUsage {
+      messages: metrics.message_counter(),
+      data_bytes: metrics.data_bytes(),
+      disk_bytes: metrics.disk_bytes(),
+    };
+    self.limits.would_exceed(own, messages, bytes)
+      || self
+        .shared
+        .iter()
+        .any(|s| s.limits.would_exceed(s.total(), messages, bytes))
+  }
+}
+
+/// Refreshes the queue's storage usage in `metrics`, and reports its usage to each of `shared`, about once a second until the returned sender is dropped. The queue's usage is then removed from `shared`.
+pub(crate) fn start_usage_refresh(
+  storage: Arc<dyn Storage>,
+  metrics: Arc<Metrics>,
+  shared: Vec<Arc<SharedQuota>>,
+) -> oneshot::Sender<()> {
+  let (stop, mut stopped) = oneshot::channel::<()>();
+  let shared = shared
+    .into_iter()
+    .map(|s| {
+      let queue = s.next_queue.fetch_add(1, Ordering::Relaxed);
+      (s, queue)
+    })
+    .collect::<Vec<_>>();
+  spawn(async move {
+    loop {
+      let storage = storage.clone();
+      let (data_bytes, disk_bytes, stats) = spawn_blocking(move || {
+        (
+          storage.live_data_size(),
+          storage.disk_usage(),
+          storage.stats(),
+        )
+      })
+      .await
+      .unwrap();
+      metrics.data_bytes.store(data_bytes, Ordering::Relaxed);
+      metrics.disk_bytes.store(disk_bytes, Ordering::Relaxed);
+      *metrics.storage_stats.lock() = stats;
+      for (shared, queue) in shared.iter() {
+        shared.usage.lock().insert(*queue, QuotaUsage {
+          messages: metrics.message_counter(),
+          data_bytes,
+          disk_bytes,
+        });
+      }
+      tokio::select! {
+        _ = &mut stopped => break,
+        _ = sleep(REFRESH_INTERVAL) => {}
+      };
+    }
+    for (shared, queue) in shared.iter() {
+      shared.usage.lock().remove(queue);
+    }
+  });
+  stop
+}
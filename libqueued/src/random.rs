@@ -0,0 +1,66 @@
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::thread_rng;
+use rand::RngCore;
+use rand::SeedableRng;
+
+/// The source of randomness for visibility jitter and random message IDs. Tests and simulations can use a seeded source, together with a controlled `Clock`, so that runs are reproducible.
+pub trait Random: Send + Sync {
+  fn next_u64(&self) -> u64;
+}
+
+impl dyn Random {
+  /// An RNG drawing from this source, for use with `rand::Rng`'s methods.
+  pub(crate) fn rng(&self) -> RandomRng<'_> {
+    RandomRng(self)
+  }
+}
+
+pub(crate) struct RandomRng<'a>(&'a dyn Random);
+
+impl<'a> RngCore for RandomRng<'a> {
+  fn next_u32(&mut self) -> u32 {
+    self.0.next_u64() as u32
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.0.next_u64()
+  }
+
+  fn fill_bytes(&mut self, dest: &mut [u8]) {
+    for chunk in dest.chunks_mut(8) {
+      let len = chunk.len();
+      chunk.copy_from_slice(&self.0.next_u64().to_le_bytes()[..len]);
+    }
+  }
+
+  fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+    self.fill_bytes(dest);
+    Ok(())
+  }
+}
+
+/// The thread-local RNG seeded by the OS.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ThreadRandom;
+
+impl Random for ThreadRandom {
+  fn next_u64(&self) -> u64 {
+    thread_rng().next_u64()
+  }
+}
+
+/// A deterministic RNG, which produces the same sequence for the same seed as long as it's called in the same order.
+pub struct SeededRandom(Mutex<StdRng>);
+
+impl SeededRandom {
+  pub fn new(seed: u64) -> Self {
+    Self(Mutex::new(StdRng::seed_from_u64(seed)))
+  }
+}
+
+impl Random for SeededRandom {
+  fn next_u64(&self) -> u64 {
+    self.0.lock().next_u64()
+  }
+}
@@ -0,0 +1,215 @@
+use crate::db::check_format_version;
+use crate::storage::rocksdb_checkpoint;
+use crate::storage::Storage;
+use crate::storage::StorageStats;
+use crate::write_batch::parse_write_batch;
+use crate::write_batch::WriteBatchOp;
+use off64::int::create_u32_le;
+use off64::int::Off64ReadInt;
+use parking_lot::RwLock;
+use redb::Database;
+use redb::Durability;
+use redb::ReadOnlyTable;
+use redb::ReadableTable;
+use redb::ReadableTableMetadata;
+use redb::TableDefinition;
+use rocksdb::WriteBatchWithTransaction;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use tracing::warn;
+
+// Everything is in one table, as keys already have a prefix for each kind of data.
+const DATA: TableDefinition<&[u8], &[u8]> = TableDefinition::new("data");
+
+pub(crate) struct RedbStorage {
+  // Only written to compact the file, which requires that there are no other transactions.
+  db: RwLock<Database>,
+  path: PathBuf,
+  compacting: AtomicBool,
+}
+
+impl RedbStorage {
+  /// Opens or creates the database file in `data_dir`, recording the format version like `rocksdb_open` does.
+  pub fn open(data_dir: &Path, format_version: u32) -> Self {
+    fs::create_dir_all(data_dir).unwrap();
+    let path = data_dir.join("queued.redb");
+    let db = Database::create(&path).unwrap();
+    let txn = db.begin_write().unwrap();
+    {
+      let mut table = txn.open_table(DATA).unwrap();
+      let existing = table
+        .get(&b"format_version"[..])
+        .unwrap()
+        .map(|raw| raw.value().read_u32_le_at(0));
+      if check_format_version(data_dir, existing, format_version) {
+        table
+          .insert(&b"format_version"[..], &create_u32_le(format_version)[..])
+          .unwrap();
+      };
+    };
+    txn.commit().unwrap();
+    Self {
+      db: RwLock::new(db),
+      path,
+      compacting: AtomicBool::new(false),
+    }
+  }
+
+  // Calls `f` with the table as of a single point in time.
+  fn read<T>(
+    &self,
+    f: impl FnOnce(&ReadOnlyTable<&'static [u8], &'static [u8]>) -> Result<T, redb::StorageError>,
+  ) -> Result<T, String> {
+    let db = self.db.read();
+    let txn = db.begin_read().map_err(|err| err.to_string())?;
+    let table = txn.open_table(DATA).map_err(|err| err.to_string())?;
+    f(&table).map_err(|err| err.to_string())
+  }
+}
+
+impl Storage for RedbStorage {
+  fn write(&self, b: WriteBatchWithTransaction<false>) -> Result<(), String> {
+    let ops = parse_write_batch(b.data()).ok_or("unsupported write batch")?;
+    let db = self.db.read();
+    let mut txn = db.begin_write().map_err(|err| err.to_string())?;
+    // Made durable by the next `flush`.
+    txn.set_durability(Durability::None);
+    {
+      let mut table = txn.open_table(DATA).map_err(|err| err.to_string())?;
+      for op in ops {
+        match op {
+          WriteBatchOp::Put(k, v) => {
+            table.insert(k, v).map_err(|err| err.to_string())?;
+          }
+          WriteBatchOp::Delete(k) => {
+            table.remove(k).map_err(|err| err.to_string())?;
+          }
+          WriteBatchOp::DeleteRange(start, end) => {
+            table
+              .retain_in(start..end, |_, _| false)
+              .map_err(|err| err.to_string())?;
+          }
+        };
+      }
+    };
+    txn.commit().map_err(|err| err.to_string())
+  }
+
+  fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    self.read(|table| Ok(table.get(key)?.map(|v| v.value().to_vec())))
+  }
+
+  fn scan(&self, prefixes: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8])) -> Result<(), String> {
+    self.read(|table| {
+      for &p in prefixes {
+        for e in table.range(p..)? {
+          let (k, v) = e?;
+          if !k.value().starts_with(p) {
+            break;
+          };
+          f(k.value(), v.value());
+        }
+      }
+      Ok(())
+    })
+  }
+
+  fn flush(&self) -> Result<(), String> {
+    // Committing with immediate durability also persists all earlier commits that didn't have it.
+    let db = self.db.read();
+    let mut txn = db.begin_write().map_err(|err| err.to_string())?;
+    txn.set_durability(Durability::Immediate);
+    txn.commit().map_err(|err| err.to_string())
+  }
+
+  fn checkpoint(&self, dir: &Path) -> Result<(), String> {
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    self.scan(&[&[]], &mut |k, v| b.put(k, v))?;
+    rocksdb_checkpoint(dir, b)
+  }
+
+  fn latest_sequence_number(&self) -> Option<u64> {
+    None
+  }
+
+  fn write_batches_since(
+    &self,
+    _since: u64,
+    _until: u64,
+    _f: &mut dyn FnMut(&[u8]) -> Result<bool, String>,
+  ) -> Result<bool, String> {
+    Err("writes to redb storage aren't sequenced".to_string())
+  }
+
+  // redb can only compact the whole file, and only while nothing else is using it, so this blocks all other operations until done.
+  fn compact_range(&self, _start: &[u8], _end: &[u8]) {
+    self.compacting.store(true, Ordering::Relaxed);
+    if let Err(err) = self.db.write().compact() {
+      warn!(error = err.to_string(), "failed to compact redb storage");
+    };
+    self.compacting.store(false, Ordering::Relaxed);
+  }
+
+  fn is_compacting(&self) -> bool {
+    self.compacting.load(Ordering::Relaxed)
+  }
+
+  fn live_data_size(&self) -> u64 {
+    self
+      .read(|table| Ok(table.stats()?.stored_bytes()))
+      .unwrap_or(0)
+  }
+
+  fn disk_usage(&self) -> u64 {
+    fs::metadata(&self.path).map_or(0, |m| m.len())
+  }
+
+  fn stats(&self) -> StorageStats {
+    let entries = self.read(|table| table.len()).unwrap_or(0);
+    StorageStats {
+      entries,
+      ..Default::default()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RedbStorage;
+  use crate::db::FORMAT_VERSION;
+  use crate::storage::Storage;
+  use rocksdb::WriteBatchWithTransaction;
+  use std::path::PathBuf;
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("queued-redb-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+  }
+
+  #[test]
+  fn flushed_writes_survive_reopening() {
+    let dir = test_dir("reopen");
+    {
+      let storage = RedbStorage::open(&dir, FORMAT_VERSION);
+      let mut b = WriteBatchWithTransaction::<false>::default();
+      b.put("k", "v");
+      storage.write(b).unwrap();
+      storage.flush().unwrap();
+    };
+    let storage = RedbStorage::open(&dir, FORMAT_VERSION);
+    assert_eq!(storage.get(b"k").unwrap(), Some(b"v".to_vec()));
+    assert!(storage.disk_usage() > 0);
+  }
+
+  #[test]
+  #[should_panic(expected = "newer than the requested compatibility version")]
+  fn refuses_to_open_with_an_older_format_version() {
+    let dir = test_dir("format");
+    drop(RedbStorage::open(&dir, FORMAT_VERSION));
+    RedbStorage::open(&dir, FORMAT_VERSION - 1);
+  }
+}
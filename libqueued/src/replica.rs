@@ -0,0 +1,111 @@
+use crate::storage::Storage;
+use off64::int::Off64ReadInt;
+use rocksdb::WriteBatchWithTransaction;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+
+// Limits on a single batch of copied records, so that a follower can apply them as they arrive.
+const RECORDS_BATCH_MAX_BYTES: usize = 1024 * 1024;
+const RECORDS_BATCH_MAX_KEYS: usize = 10_000;
+
+pub(crate) const REPLICA_POSITION_KEY: &[u8] = b"replica_position";
+
+/// How far a read-only replica of a queue has caught up with its primary.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ReplicaPosition {
+  /// The primary's RocksDB sequence number that all writes up to have been applied.
+  pub seq: u64,
+  /// The primary's database identity, which changes if the queue is deleted and recreated.
+  pub identity: String,
+}
+
+impl ReplicaPosition {
+  pub(crate) fn encode(&self) -> Vec<u8> {
+    let mut raw = self.seq.to_le_bytes().to_vec();
+    raw.extend_from_slice(self.identity.as_bytes());
+    raw
+  }
+
+  pub(crate) fn decode(raw: &[u8]) -> Option<Self> {
+    if raw.len() < 8 {
+      return None;
+    };
+    Some(Self {
+      seq: raw.read_u64_le_at(0),
+      identity: String::from_utf8(raw[8..].to_vec()).ok()?,
+    })
+  }
+}
+
+/// Raw write batches of every record, or an error if they couldn't all be read.
+pub type ReplicaRecords = Receiver<Result<Vec<u8>, String>>;
+
+/// Writes read from the WAL for a replica.
+pub struct ReplicaWal {
+  /// Raw write batches, in the order they were written.
+  pub batches: Vec<Vec<u8>>,
+  /// The sequence number that all writes up to are in `batches` or earlier ones.
+  pub seq: u64,
+}
+
+/// Sends every record in `storage` as raw write batches of puts, stopping early if the receiver is dropped.
+pub(crate) fn send_records(
+  storage: &dyn Storage,
+  tx: &Sender<Result<Vec<u8>, String>>,
+) -> Result<(), String> {
+  let mut b = WriteBatchWithTransaction::<false>::default();
+  let mut closed = false;
+  storage.scan(&[&[]], &mut |k, v| {
+    if closed {
+      return;
+    };
+    b.put(k, v);
+    if b.size_in_bytes() >= RECORDS_BATCH_MAX_BYTES || b.len() >= RECORDS_BATCH_MAX_KEYS {
+      closed = tx
+        .blocking_send(Ok(std::mem::take(&mut b).data().to_vec()))
+        .is_err();
+    };
+  })?;
+  if !closed && !b.is_empty() {
+    // The receiver may have been dropped, which is fine.
+    let _ = tx.blocking_send(Ok(b.data().to_vec()));
+  };
+  Ok(())
+}
+
+/// Reads the raw write batches after sequence number `since`, stopping once they exceed `max_bytes`. Returns None if some of the writes are no longer in the WAL.
+pub(crate) fn read_wal_since(
+  storage: &dyn Storage,
+  since: u64,
+  max_bytes: usize,
+) -> Result<Option<ReplicaWal>, String> {
+  let Some(until) = storage.latest_sequence_number() else {
+    return Err("replicas require RocksDB storage".to_string());
+  };
+  if since > until {
+    return Ok(None);
+  };
+  // Only flushed writes can be read from the WAL.
+  storage.flush()?;
+  let mut batches = Vec::new();
+  let mut bytes = 0;
+  let mut covered = since;
+  let complete = storage.write_batches_since(since, until, &mut |data| {
+    let seq = data.read_u64_le_at(0);
+    let count = u64::from(data.read_u32_le_at(8));
+    covered = covered.max(seq + count.max(1) - 1);
+    bytes += data.len();
+    batches.push(data.to_vec());
+    Ok(bytes < max_bytes)
+  })?;
+  if !complete {
+    return Ok(None);
+  };
+  if bytes < max_bytes {
+    covered = covered.max(until);
+  };
+  Ok(Some(ReplicaWal {
+    batches,
+    seq: covered,
+  }))
+}
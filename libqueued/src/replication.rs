@@ -0,0 +1,77 @@
+use crate::db::rocksdb_key_id;
+use crate::db::RocksDbKeyPrefix;
+use crate::id_gen::is_random_id;
+use futures::future::BoxFuture;
+use futures::future::Shared;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use rocksdb::WriteBatchIterator;
+use std::sync::Arc;
+
+/// Receives every write batch committed to a queue's local store, before the originating operation is acknowledged. If replication fails, the operation fails with `OpError::ReplicationFailed`, even though the write has already been applied locally.
+pub trait Replicator: Send + Sync {
+  /// `batch` is the raw RocksDB write batch representation, which can be applied on another node using `Queued::apply_replicated_batch`. Batches must be applied elsewhere in the order this is called, which is the order they were committed locally; the returned future only waits for `batch` to be applied.
+  fn replicate(&self, batch: Vec<u8>) -> BoxFuture<'static, Result<(), String>>;
+}
+
+type ReplicationFuture = Shared<BoxFuture<'static, Result<(), String>>>;
+
+// Passes batches to a `Replicator` in the order they're written locally, by writing and handing each one over while holding a lock.
+pub(crate) struct ReplicationSequencer {
+  replicator: Arc<dyn Replicator>,
+  // The replication of the latest batch. Batches are applied elsewhere in order, so once it's done, so are all earlier ones.
+  latest: Mutex<Option<ReplicationFuture>>,
+}
+
+impl ReplicationSequencer {
+  pub fn new(replicator: Arc<dyn Replicator>) -> Self {
+    Self {
+      replicator,
+      latest: Mutex::new(None),
+    }
+  }
+
+  /// Applies a write locally by calling `write`, and if that succeeds, queues `replicated` (the raw write batch of what should be replicated) for replication. This blocks other writes, so it should be called from a blocking thread.
+  pub fn write(
+    &self,
+    replicated: Vec<u8>,
+    write: impl FnOnce() -> Result<(), String>,
+  ) -> Result<(), String> {
+    let mut latest = self.latest.lock();
+    write()?;
+    *latest = Some(self.replicator.replicate(replicated).shared());
+    Ok(())
+  }
+
+  /// Waits until every batch written so far has been replicated.
+  pub async fn wait(&self) -> Result<(), String> {
+    let latest = self.latest.lock().clone();
+    match latest {
+      Some(f) => f.await,
+      None => Ok(()),
+    }
+  }
+}
+
+// Finds the highest sequential or Snowflake message ID created by a replicated write batch, so that a follower can keep its `next_id` ahead of all IDs ever used by the leader.
+#[derive(Default)]
+pub(crate) struct MaxCreatedIdFinder {
+  pub max_id: Option<u64>,
+}
+
+impl WriteBatchIterator for MaxCreatedIdFinder {
+  fn put(&mut self, key: Box<[u8]>, _value: Box<[u8]>) {
+    if key.len() == 9
+      && (key[0] == RocksDbKeyPrefix::MessageData as u8
+        || key[0] == RocksDbKeyPrefix::MessageInline as u8
+        || key[0] == RocksDbKeyPrefix::MessageOffloaded as u8)
+    {
+      let id = rocksdb_key_id(&key);
+      if !is_random_id(id) {
+        self.max_id = Some(self.max_id.map_or(id, |m| m.max(id)));
+      };
+    };
+  }
+
+  fn delete(&mut self, _key: Box<[u8]>) {}
+}
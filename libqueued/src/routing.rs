@@ -0,0 +1,32 @@
+use crate::attributes::MessageAttributeValue;
+use crate::attributes::MessageAttributes;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Sends pushed messages with a matching attribute to another queue instead.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoutingRule {
+  pub attribute: String,
+  /// If unset, any message with the attribute matches.
+  #[serde(default)]
+  pub equals: Option<MessageAttributeValue>,
+  pub queue: String,
+}
+
+impl RoutingRule {
+  pub fn matches(&self, attrs: &MessageAttributes) -> bool {
+    match (attrs.get(&self.attribute), &self.equals) {
+      (Some(v), Some(expected)) => v == expected,
+      (Some(_), None) => true,
+      (None, _) => false,
+    }
+  }
+}
+
+/// Returns the queue of the first rule that matches, if any.
+pub fn route<'a>(rules: &'a [RoutingRule], attrs: &MessageAttributes) -> Option<&'a str> {
+  rules
+    .iter()
+    .find(|r| r.matches(attrs))
+    .map(|r| r.queue.as_str())
+}
@@ -0,0 +1,315 @@
+use crate::db::rocksdb_opts;
+use crate::db::rocksdb_write_opts;
+use crate::db::LoadedData;
+use crate::db::RocksDbTuning;
+use crate::index_snapshot::IndexState;
+use crate::metrics::Metrics;
+use rocksdb::WriteBatchWithTransaction;
+use rocksdb::DB;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where a queue's messages and other state are stored.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum StorageBackend {
+  /// Stored durably in RocksDB in the data dir.
+  #[default]
+  RocksDb,
+  /// Only kept in memory, so everything is lost once the queue is dropped, and the data dir is unused. Useful for tests and ephemeral queues. Operations behave identically, including `Queued::snapshot`, which creates a RocksDB data dir. Index snapshots aren't supported.
+  InMemory,
+  /// Stored durably in a redb database file in the data dir. redb is pure Rust, unlike RocksDB. Writes aren't sequenced, so like `InMemory`, index snapshots, incremental snapshots, and replicating from the queue aren't supported. Compaction always compacts the whole file, and blocks all other operations on the queue until done.
+  #[cfg(feature = "redb")]
+  Redb,
+}
+
+/// The key-value store backing a queue. Keys and write batches use the RocksDB representation regardless of the backend, so that they can be replicated and snapshotted the same way. Methods may block, so must be called from a blocking context.
+pub(crate) trait Storage: Send + Sync {
+  /// Atomically applies `b`. The writes don't have to be durable until `flush` is called.
+  fn write(&self, b: WriteBatchWithTransaction<false>) -> Result<(), String>;
+  fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+  /// Calls `f` with every key starting with one of `prefixes` and its value, all as of a single point in time. Keys are in order within each prefix.
+  fn scan(&self, prefixes: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8])) -> Result<(), String>;
+  /// Makes all writes so far durable.
+  fn flush(&self) -> Result<(), String>;
+  /// Creates a consistent point-in-time copy in `dir`, which must not exist, that can be used as a RocksDB data dir.
+  fn checkpoint(&self, dir: &Path) -> Result<(), String>;
+  /// The sequence number of the latest write, or None if writes aren't sequenced, in which case incremental snapshots aren't supported.
+  fn latest_sequence_number(&self) -> Option<u64>;
+  /// Calls `f` in order with every raw write batch that has writes after sequence number `since` and up to `until`. Batches may also have writes at or before `since`, which are harmless to apply again as long as everything after them is too. Returns false if some of the writes are no longer available. Only writes that have been flushed are available. If `f` returns false, no more batches are read, and the writes are considered available.
+  fn write_batches_since(
+    &self,
+    since: u64,
+    until: u64,
+    f: &mut dyn FnMut(&[u8]) -> Result<bool, String>,
+  ) -> Result<bool, String>;
+  /// Compacts keys from `start` (inclusive) to `end` (exclusive) into the last level, which removes deleted and overwritten data and their tombstones. This blocks until done, which can take a while.
+  fn compact_range(&self, start: &[u8], end: &[u8]);
+  /// Whether a background compaction is currently running. This must be cheap, as it may be called on every push.
+  fn is_compacting(&self) -> bool;
+  /// Estimated bytes of live data, i.e. what the data would take up once fully compacted.
+  fn live_data_size(&self) -> u64;
+  /// Bytes currently used on disk, including data that has been deleted or overwritten but not yet compacted away.
+  fn disk_usage(&self) -> u64;
+  fn stats(&self) -> StorageStats;
+}
+
+/// Internal state of the storage engine, for monitoring. Storage that doesn't have some of these reports zero for them.
+#[derive(Clone, Copy, Default)]
+pub struct StorageStats {
+  /// Estimated bytes that compactions need to rewrite to bring the database back into shape. Writes are slowed down and then stopped when this gets too large.
+  pub compaction_pending_bytes: u64,
+  pub running_compactions: u64,
+  /// Whether writes are currently stopped until compactions or flushes catch up.
+  pub write_stopped: bool,
+  /// If writes are currently being slowed down, the rate they're limited to in bytes per second, otherwise zero.
+  pub delayed_write_rate: u64,
+  pub block_cache_usage_bytes: u64,
+  /// Total microseconds that writes were stalled. Only available with statistics enabled.
+  pub stall_micros: u64,
+  /// Only available with statistics enabled.
+  pub block_cache_hits: u64,
+  /// Only available with statistics enabled.
+  pub block_cache_misses: u64,
+  /// Entries in files on disk, including tombstones.
+  pub entries: u64,
+  /// Deletions in files on disk, including range deletions. These slow down iteration until they're compacted away.
+  pub tombstones: u64,
+}
+
+pub(crate) fn storage_load(
+  storage: &dyn Storage,
+  metrics: Arc<Metrics>,
+  shards: usize,
+) -> LoadedData {
+  IndexState::scan(storage, 0)
+    .unwrap()
+    .into_loaded(metrics, shards)
+}
+
+/// Implements `Storage::checkpoint` for backends that aren't RocksDB, by writing `b`, which has everything in the backend, to a new RocksDB data dir.
+pub(crate) fn rocksdb_checkpoint(
+  dir: &Path,
+  b: WriteBatchWithTransaction<false>,
+) -> Result<(), String> {
+  // Match RocksDB checkpoints, which refuse to overwrite anything.
+  if dir.exists() {
+    return Err(format!("{dir:?} already exists"));
+  };
+  let db = DB::open(&rocksdb_opts(None, None, &RocksDbTuning::default()), dir)
+    .map_err(|err| err.to_string())?;
+  db.write_opt(b, &rocksdb_write_opts())
+    .map_err(|err| err.to_string())?;
+  db.flush_wal(true).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Storage;
+  use super::StorageBackend;
+  use crate::db::rocksdb_open;
+  use crate::db::RocksDbTuning;
+  use crate::db::FORMAT_VERSION;
+  use crate::lifecycle::MessageState;
+  use crate::memory_storage::MemoryStorage;
+  use crate::op::delete::OpDeleteInput;
+  use crate::op::delete::OpDeleteInputMessage;
+  use crate::op::poll::OpPollInput;
+  use crate::op::push::OpPushInput;
+  use crate::op::push::OpPushInputMessage;
+  use crate::op::update::OpUpdateInput;
+  use crate::op::update::OpUpdateInputMessage;
+  #[cfg(feature = "redb")]
+  use crate::redb_storage::RedbStorage;
+  use crate::Queued;
+  use crate::QueuedCfg;
+  use rocksdb::WriteBatchWithTransaction;
+  use rocksdb::DB;
+  use std::path::PathBuf;
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir =
+      std::env::temp_dir().join(format!("queued-storage-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn backends(name: &str) -> Vec<(&'static str, Box<dyn Storage>)> {
+    let dir = test_dir(name);
+    vec![
+      ("memory", Box::new(MemoryStorage::new(FORMAT_VERSION))),
+      (
+        "rocksdb",
+        Box::new(rocksdb_open(
+          &dir.join("rocksdb"),
+          FORMAT_VERSION,
+          None,
+          None,
+          &RocksDbTuning::default(),
+        )),
+      ),
+      #[cfg(feature = "redb")]
+      (
+        "redb",
+        Box::new(RedbStorage::open(&dir.join("redb"), FORMAT_VERSION)),
+      ),
+    ]
+  }
+
+  fn scan_all(storage: &dyn Storage, prefixes: &[&[u8]]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut out = Vec::new();
+    storage
+      .scan(prefixes, &mut |k, v| out.push((k.to_vec(), v.to_vec())))
+      .unwrap();
+    out
+  }
+
+  #[test]
+  fn write_batches_apply_the_same_on_every_backend() {
+    for (name, storage) in backends("write") {
+      let mut b = WriteBatchWithTransaction::<false>::default();
+      for k in ["a1", "a2", "a3", "b1", "b2", "c1"] {
+        b.put(k, k.to_uppercase());
+      }
+      storage.write(b).unwrap();
+      let mut b = WriteBatchWithTransaction::<false>::default();
+      b.delete("a2");
+      b.delete_range("a3", "b2");
+      b.put("c1", "C1'");
+      storage.write(b).unwrap();
+      storage.flush().unwrap();
+      storage.compact_range(b"a", b"d");
+
+      assert_eq!(storage.get(b"a1").unwrap(), Some(b"A1".to_vec()), "{name}");
+      assert_eq!(storage.get(b"a2").unwrap(), None, "{name}");
+      // Prefixes are scanned in the order given, and keys in order within each.
+      assert_eq!(
+        scan_all(&*storage, &[b"c", b"a", b"b"]),
+        vec![
+          (b"c1".to_vec(), b"C1'".to_vec()),
+          (b"a1".to_vec(), b"A1".to_vec()),
+          (b"b2".to_vec(), b"B2".to_vec()),
+        ],
+        "{name}"
+      );
+      assert_eq!(
+        storage.get(b"format_version").unwrap(),
+        Some(FORMAT_VERSION.to_le_bytes().to_vec()),
+        "{name}"
+      );
+    }
+  }
+
+  #[test]
+  fn checkpoints_are_rocksdb_data_dirs() {
+    let dir = test_dir("checkpoint");
+    for (name, storage) in backends("checkpoint-src") {
+      let mut b = WriteBatchWithTransaction::<false>::default();
+      b.put("k", "v");
+      storage.write(b).unwrap();
+      let checkpoint = dir.join(name);
+      storage.checkpoint(&checkpoint).unwrap();
+      // Like RocksDB checkpoints, existing dirs aren't overwritten.
+      assert!(storage.checkpoint(&checkpoint).is_err(), "{name}");
+      let db = DB::open_default(&checkpoint).unwrap();
+      assert_eq!(db.get("k").unwrap(), Some(b"v".to_vec()), "{name}");
+    }
+  }
+
+  fn message(contents: &[u8], visibility_timeout_secs: u32) -> OpPushInputMessage {
+    OpPushInputMessage {
+      contents: contents.to_vec(),
+      visibility_timeout_secs,
+      visibility_jitter_secs: 0,
+      priority: 0,
+      attributes: Default::default(),
+      ttl_secs: None,
+      group_id: None,
+      external_id: None,
+    }
+  }
+
+  async fn delete(q: &Queued, id: u64, poll_tag: u32) {
+    q.delete(OpDeleteInput {
+      messages: vec![OpDeleteInputMessage { id, poll_tag }],
+    })
+    .await
+    .unwrap();
+  }
+
+  #[tokio::test]
+  async fn ops_behave_the_same_on_every_backend() {
+    let storages = [
+      StorageBackend::InMemory,
+      StorageBackend::RocksDb,
+      #[cfg(feature = "redb")]
+      StorageBackend::Redb,
+    ];
+    for storage in storages {
+      let dir = test_dir(&format!("ops-{storage:?}"));
+      let cfg = QueuedCfg {
+        storage,
+        ..Default::default()
+      };
+      let q = Queued::load_and_start(&dir.join("data"), cfg).await;
+      let ids = q
+        .push(OpPushInput {
+          messages: vec![message(b"a", 0), message(b"b", 0), message(b"c", 60)],
+        })
+        .await
+        .unwrap()
+        .ids;
+      let polled = q
+        .poll(OpPollInput {
+          count: 10,
+          visibility_timeout_secs: 30,
+          ignore_existing_visibility_timeouts: false,
+          prefer_group: None,
+          fields: None,
+        })
+        .await
+        .unwrap()
+        .messages;
+      assert_eq!(
+        polled
+          .iter()
+          .map(|m| (m.id, m.contents.as_slice()))
+          .collect::<Vec<_>>(),
+        vec![(ids[0], &b"a"[..]), (ids[1], &b"b"[..])],
+        "{storage:?}"
+      );
+
+      // Updating makes the message visible again with a new poll tag, so the old one no longer works.
+      let new_poll_tag = q
+        .update(OpUpdateInput {
+          messages: vec![OpUpdateInputMessage {
+            id: ids[0],
+            poll_tag: polled[0].poll_tag,
+            visibility_timeout_secs: 0,
+          }],
+        })
+        .await
+        .unwrap()
+        .new_poll_tags[0]
+        .unwrap();
+      delete(&q, ids[0], polled[0].poll_tag).await;
+      assert_eq!(q.message_state(ids[0]), MessageState::Available);
+      delete(&q, ids[0], new_poll_tag).await;
+      delete(&q, ids[1], polled[1].poll_tag).await;
+      // Messages that were never polled can't be deleted with a poll tag.
+      delete(&q, ids[2], 0).await;
+      for (id, state) in [
+        (ids[0], MessageState::Vacant),
+        (ids[1], MessageState::Vacant),
+        (ids[2], MessageState::Delayed),
+      ] {
+        assert_eq!(q.message_state(id), state, "{storage:?}");
+      }
+
+      q.snapshot(dir.join("snapshot")).await.unwrap();
+      let restored = Queued::load_and_start(&dir.join("snapshot"), QueuedCfg::default()).await;
+      assert_eq!(restored.message_state(ids[0]), MessageState::Vacant);
+      assert_eq!(restored.message_state(ids[2]), MessageState::Delayed);
+    }
+  }
+}
@@ -1,6 +1,23 @@
+use serde::Deserialize;
+use serde::Serialize;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 
+/// Which operations to suspend or resume. Operations that are unset are left as they are.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuspensionChange {
+  pub delete: Option<bool>,
+  pub poll: Option<bool>,
+  pub push: Option<bool>,
+  pub update: Option<bool>,
+}
+
+const DELETE_BIT: u8 = 1 << 0;
+const POLL_BIT: u8 = 1 << 1;
+const PUSH_BIT: u8 = 1 << 2;
+const UPDATE_BIT: u8 = 1 << 3;
+
 #[derive(Default)]
 pub struct SuspendState {
   delete: AtomicBool,
@@ -41,4 +58,40 @@ impl SuspendState {
   pub fn set_update_suspension(&self, s: bool) {
     self.update.store(s, Ordering::Relaxed);
   }
+
+  /// The suspended operations as a bitmask, which is how they're persisted.
+  pub(crate) fn bits(&self) -> u8 {
+    [
+      (self.is_delete_suspended(), DELETE_BIT),
+      (self.is_poll_suspended(), POLL_BIT),
+      (self.is_push_suspended(), PUSH_BIT),
+      (self.is_update_suspended(), UPDATE_BIT),
+    ]
+    .into_iter()
+    .filter(|(s, _)| *s)
+    .fold(0, |bits, (_, bit)| bits | bit)
+  }
+
+  pub(crate) fn set_bits(&self, bits: u8) {
+    self.set_delete_suspension(bits & DELETE_BIT != 0);
+    self.set_poll_suspension(bits & POLL_BIT != 0);
+    self.set_push_suspension(bits & PUSH_BIT != 0);
+    self.set_update_suspension(bits & UPDATE_BIT != 0);
+  }
+
+  /// The bitmask after applying `change` to the current suspension.
+  pub(crate) fn bits_with(&self, change: SuspensionChange) -> u8 {
+    [
+      (change.delete, DELETE_BIT),
+      (change.poll, POLL_BIT),
+      (change.push, PUSH_BIT),
+      (change.update, UPDATE_BIT),
+    ]
+    .into_iter()
+    .fold(self.bits(), |bits, (s, bit)| match s {
+      Some(true) => bits | bit,
+      Some(false) => bits & !bit,
+      None => bits,
+    })
+  }
 }
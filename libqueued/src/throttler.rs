@@ -1,5 +1,3 @@
-use chrono::Utc;
-
 pub(crate) struct Throttler {
   // State.
   count: u64,
@@ -20,10 +18,10 @@ impl Throttler {
     }
   }
 
-  pub fn increment_count(&mut self) -> bool {
-    let cur_window = Utc::now().timestamp() / self.time_base;
-    if self.window != cur_window {
-      self.window = cur_window;
+  pub fn increment_count(&mut self, now: i64) -> bool {
+    let cur_window = now / self.window;
+    if self.time_base != cur_window {
+      self.time_base = cur_window;
       self.count = 0;
     };
     self.count += 1;
@@ -35,6 +33,6 @@ impl Throttler {
   }
 
   pub fn get_time_window_sec(&self) -> i64 {
-    self.time_base
+    self.window
   }
 }
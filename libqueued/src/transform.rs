@@ -0,0 +1,31 @@
+use crate::attributes::MessageAttributeValue;
+use crate::op::poll::OpPollOutputMessage;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Changes applied to every polled message before it's returned. Stored messages are never changed.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PollTransform {
+  /// Attributes with these names are removed.
+  pub remove_attributes: Vec<String>,
+  /// If set, an attribute with this name is set to the poll time, as a Unix timestamp in seconds.
+  pub delivered_at_attribute: Option<String>,
+  /// If set, contents are truncated to at most this many bytes.
+  pub truncate_contents: Option<usize>,
+}
+
+impl PollTransform {
+  pub(crate) fn apply(&self, m: &mut OpPollOutputMessage, now: i64) {
+    for name in self.remove_attributes.iter() {
+      m.attributes.remove(name);
+    }
+    if let Some(name) = &self.delivered_at_attribute {
+      m.attributes
+        .insert(name.clone(), MessageAttributeValue::Integer(now));
+    };
+    if let Some(len) = self.truncate_contents {
+      m.contents.truncate(len);
+    };
+  }
+}
@@ -0,0 +1,21 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+fn default_max_attempts() -> u32 {
+  8
+}
+
+/// Where to deliver a queue's messages to instead of waiting for them to be polled. Delivery is done by the server, as it involves other queues.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WebhookCfg {
+  pub url: String,
+  /// If set, each delivery is signed with HMAC-SHA256 using this key. It's never returned by the API.
+  #[serde(default, skip_serializing)]
+  pub secret: Option<String>,
+  /// How many times a message is attempted before it's moved to `dead_letter_queue`.
+  #[serde(default = "default_max_attempts")]
+  pub max_attempts: u32,
+  /// If unset, messages are retried forever.
+  #[serde(default)]
+  pub dead_letter_queue: Option<String>,
+}
@@ -0,0 +1,193 @@
+use rocksdb::WriteBatchWithTransaction;
+
+// The sequence number (u64) and count (u32).
+const HEADER_LEN: usize = 12;
+
+/// A single operation in a raw RocksDB write batch.
+pub(crate) enum WriteBatchOp<'a> {
+  Put(&'a [u8], &'a [u8]),
+  Delete(&'a [u8]),
+  // The end key is exclusive.
+  DeleteRange(&'a [u8], &'a [u8]),
+}
+
+/// Parses the raw representation of a write batch (e.g. from `WriteBatch::data` or the WAL). Returns None if it contains something this can't interpret, such as merges or column families, which we never create.
+pub(crate) fn parse_write_batch(data: &[u8]) -> Option<Vec<WriteBatchOp<'_>>> {
+  if data.len() < HEADER_LEN {
+    return None;
+  };
+  let mut ops = Vec::new();
+  let mut r = BatchReader {
+    data,
+    pos: HEADER_LEN,
+  };
+  while r.pos < data.len() {
+    match r.byte()? {
+      // Value.
+      0x1 => ops.push(WriteBatchOp::Put(r.slice()?, r.slice()?)),
+      // Deletion and single deletion.
+      0x0 | 0x7 => ops.push(WriteBatchOp::Delete(r.slice()?)),
+      // Range deletion.
+      0xf => ops.push(WriteBatchOp::DeleteRange(r.slice()?, r.slice()?)),
+      // Log data.
+      0x3 => {
+        r.slice()?;
+      }
+      // No-op.
+      0xd => {}
+      _ => return None,
+    };
+  }
+  Some(ops)
+}
+
+/// Combines write batches into one that applies all of their operations in order, atomically.
+pub(crate) fn concat_write_batches(
+  batches: &[WriteBatchWithTransaction<false>],
+) -> WriteBatchWithTransaction<false> {
+  let mut count = 0u32;
+  let mut data = vec![0u8; HEADER_LEN];
+  for b in batches {
+    let raw = b.data();
+    count += u32::from_le_bytes(raw[8..HEADER_LEN].try_into().unwrap());
+    data.extend_from_slice(&raw[HEADER_LEN..]);
+  }
+  data[8..HEADER_LEN].copy_from_slice(&count.to_le_bytes());
+  WriteBatchWithTransaction::from_data(&data)
+}
+
+struct BatchReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> BatchReader<'a> {
+  fn byte(&mut self) -> Option<u8> {
+    let b = *self.data.get(self.pos)?;
+    self.pos += 1;
+    Some(b)
+  }
+
+  fn varint32(&mut self) -> Option<u32> {
+    let mut v = 0u32;
+    for shift in (0..35).step_by(7) {
+      let b = self.byte()?;
+      v |= u32::from(b & 0x7f) << shift;
+      if b & 0x80 == 0 {
+        return Some(v);
+      };
+    }
+    None
+  }
+
+  fn slice(&mut self) -> Option<&'a [u8]> {
+    let len = self.varint32()? as usize;
+    let s = self.data.get(self.pos..self.pos + len)?;
+    self.pos += len;
+    Some(s)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::concat_write_batches;
+  use super::parse_write_batch;
+  use super::WriteBatchOp;
+  use rocksdb::WriteBatchWithTransaction;
+
+  // An owned copy of a parsed op, so they can be compared.
+  #[derive(PartialEq, Eq, Debug)]
+  enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+  }
+
+  fn parse(data: &[u8]) -> Option<Vec<Op>> {
+    Some(
+      parse_write_batch(data)?
+        .into_iter()
+        .map(|op| match op {
+          WriteBatchOp::Put(k, v) => Op::Put(k.to_vec(), v.to_vec()),
+          WriteBatchOp::Delete(k) => Op::Delete(k.to_vec()),
+          WriteBatchOp::DeleteRange(start, end) => Op::DeleteRange(start.to_vec(), end.to_vec()),
+        })
+        .collect(),
+    )
+  }
+
+  #[test]
+  fn parses_what_rocksdb_writes() {
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    b.put("k1", "v1");
+    // Long enough that its length needs a multi-byte varint.
+    b.put("k2", vec![7u8; 300]);
+    b.delete("k1");
+    b.delete_range("a", "z");
+    b.put("", "");
+    assert_eq!(parse(b.data()).unwrap(), vec![
+      Op::Put(b"k1".to_vec(), b"v1".to_vec()),
+      Op::Put(b"k2".to_vec(), vec![7u8; 300]),
+      Op::Delete(b"k1".to_vec()),
+      Op::DeleteRange(b"a".to_vec(), b"z".to_vec()),
+      Op::Put(Vec::new(), Vec::new()),
+    ]);
+    assert_eq!(
+      parse(WriteBatchWithTransaction::<false>::default().data()).unwrap(),
+      vec![]
+    );
+  }
+
+  #[test]
+  fn skips_log_data_and_no_ops() {
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    b.put("k", "v");
+    let mut data = b.data().to_vec();
+    data.extend_from_slice(&[0x3, 3, b'l', b'o', b'g', 0xd]);
+    assert_eq!(parse(&data).unwrap(), vec![Op::Put(
+      b"k".to_vec(),
+      b"v".to_vec()
+    )]);
+  }
+
+  #[test]
+  fn concatenated_batches_have_every_op_in_order() {
+    let mut b1 = WriteBatchWithTransaction::<false>::default();
+    b1.put("a", "1");
+    b1.delete("b");
+    let mut b2 = WriteBatchWithTransaction::<false>::default();
+    b2.put("b", "2");
+    let b = concat_write_batches(&[b1, WriteBatchWithTransaction::default(), b2]);
+    assert_eq!(b.len(), 3);
+    assert_eq!(parse(b.data()).unwrap(), vec![
+      Op::Put(b"a".to_vec(), b"1".to_vec()),
+      Op::Delete(b"b".to_vec()),
+      Op::Put(b"b".to_vec(), b"2".to_vec()),
+    ]);
+  }
+
+  #[test]
+  fn rejects_malformed_batches() {
+    let mut b = WriteBatchWithTransaction::<false>::default();
+    b.put("key", "value");
+    let data = b.data();
+    // Too short for the header.
+    assert!(parse(&data[..11]).is_none());
+    // Every truncation of the put itself.
+    for len in 13..data.len() {
+      assert!(parse(&data[..len]).is_none(), "{len}");
+    }
+    // An unknown op type.
+    let mut unknown = data.to_vec();
+    unknown[12] = 0x42;
+    assert!(parse(&unknown).is_none());
+    // A varint longer than five bytes.
+    let mut varint = data[..12].to_vec();
+    varint.extend_from_slice(&[0x0, 0xff, 0xff, 0xff, 0xff, 0xff, 0x1]);
+    assert!(parse(&varint).is_none());
+    // Merges, which we never create.
+    let mut merge = WriteBatchWithTransaction::<false>::default();
+    merge.merge("key", "value");
+    assert!(parse(merge.data()).is_none());
+  }
+}
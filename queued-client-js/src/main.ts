@@ -17,7 +17,12 @@ export class QueuedUnauthorizedError extends Error {
 export class QueuedApiError extends Error {
   constructor(
     readonly status: number,
+    // The name of the error code, which is stable and can be matched on. See `GET /error-codes` on the server for all of them.
     readonly error: string | undefined,
+    // Undefined if the response didn't come from queued (e.g. from a proxy).
+    readonly errorCode: number | undefined,
+    // Whether the same request may succeed if retried later. If not, it will keep failing, so e.g. the message being processed can be dead-lettered instead.
+    readonly retryable: boolean,
     readonly errorDetails: any | undefined,
   ) {
     super(
@@ -281,6 +286,8 @@ export class QueuedClient {
           throw new QueuedApiError(
             res.statusCode!,
             resBody?.error ?? resBody,
+            resBody?.error_code ?? undefined,
+            resBody?.retryable ?? res.statusCode! >= 500,
             resBody?.error_details ?? undefined,
           );
         }
@@ -289,7 +296,7 @@ export class QueuedClient {
         if (
           attempt === maxRetries ||
           err instanceof QueuedUnauthorizedError ||
-          (err instanceof QueuedApiError && err.status < 500)
+          (err instanceof QueuedApiError && !err.retryable)
         ) {
           throw err;
         }
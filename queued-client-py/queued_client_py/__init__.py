@@ -16,13 +16,25 @@ class QueuedUnauthorizedError(Exception):
 
 
 class QueuedApiError(Exception):
-    def __init__(self, status: int, error: Optional[str], error_details: Optional[Any]):
+    def __init__(
+        self,
+        status: int,
+        error: Optional[str],
+        error_code: Optional[int],
+        retryable: bool,
+        error_details: Optional[Any],
+    ):
         error_message = f"Request to queued failed with status {status}: {error}"
         if error_details:
             error_message += f"\n\n\tDetails: {json.dumps(error_details, indent=2)}"
         super().__init__(error_message)
         self.status = status
+        # The name of the error code, which is stable and can be matched on. See `GET /error-codes` on the server for all of them.
         self.error = error
+        # None if the response didn't come from queued (e.g. from a proxy).
+        self.error_code = error_code
+        # Whether the same request may succeed if retried later. If not, it will keep failing, so e.g. the message being processed can be dead-lettered instead.
+        self.retryable = retryable
         self.error_details = error_details
 
 
@@ -207,6 +219,10 @@ class QueuedClient:
             raise QueuedApiError(
                 res.status_code,
                 res_body.get("error") if type(res_body) is dict else res_body,
+                res_body.get("error_code") if type(res_body) is dict else None,
+                res_body.get("retryable", False)
+                if type(res_body) is dict
+                else res.status_code in (502, 503, 504),
                 res_body.get("error_details") if type(res_body) is dict else None,
             )
         return res_body
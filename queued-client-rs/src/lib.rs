@@ -6,16 +6,24 @@ use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_with::serde_as;
+use serde_with::DurationMilliSeconds;
 use serde_with::DurationSeconds;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::RwLock;
 use std::time::Duration;
 
 #[derive(Debug)]
 pub enum QueuedClientError {
   Api {
     status: u16,
+    /// The name of the error code, which is stable and can be matched on. See `GET /error-codes` on the server for all of them.
     error: String,
+    /// The number of the error code, or None if the response didn't come from queued (e.g. from a proxy).
+    error_code: Option<u16>,
+    retryable: bool,
     error_details: Option<String>,
   },
   Unauthorized,
@@ -29,6 +37,7 @@ impl Display for QueuedClientError {
         status,
         error,
         error_details,
+        ..
       } => write!(f, "API error ({status} - {error}): {error_details:?}"),
       QueuedClientError::Unauthorized => write!(f, "unauthorized"),
       QueuedClientError::Request(e) => write!(f, "request error: {e}"),
@@ -38,6 +47,17 @@ impl Display for QueuedClientError {
 
 impl Error for QueuedClientError {}
 
+impl QueuedClientError {
+  /// Whether the same request may succeed if retried later. If not, it will keep failing, so e.g. the message being processed can be dead-lettered instead. Request errors (e.g. timeouts) are considered retryable, though a write may have already been applied.
+  pub fn is_retryable(&self) -> bool {
+    match self {
+      QueuedClientError::Api { retryable, .. } => *retryable,
+      QueuedClientError::Unauthorized => false,
+      QueuedClientError::Request(_) => true,
+    }
+  }
+}
+
 pub type QueuedClientResult<T> = Result<T, QueuedClientError>;
 
 #[derive(Clone, Debug)]
@@ -46,10 +66,15 @@ pub struct QueuedClientCfg {
   pub endpoint: String,
 }
 
+// A follower can redirect to a leader that has since lost leadership, so don't follow redirects forever.
+const MAX_LEADER_REDIRECTS: usize = 3;
+
 #[derive(Clone, Debug)]
 pub struct QueuedClient {
   r: reqwest::Client,
   cfg: QueuedClientCfg,
+  // The cluster leader's URL, if `cfg.endpoint` is a node that's not the leader. Shared between clones.
+  leader: Arc<RwLock<Option<String>>>,
 }
 
 impl QueuedClient {
@@ -57,6 +82,7 @@ impl QueuedClient {
     Self {
       r: request_client,
       cfg,
+      leader: Default::default(),
     }
   }
 
@@ -70,51 +96,93 @@ impl QueuedClient {
     path: impl AsRef<str>,
     body: Option<&I>,
   ) -> QueuedClientResult<O> {
-    let mut req = self
-      .r
-      .request(method, format!("{}{}", self.cfg.endpoint, path.as_ref()))
-      .header("accept", "application/msgpack");
-    if let Some(k) = &self.cfg.api_key {
-      req = req.header("authorization", k);
-    };
-    if let Some(b) = body {
-      let raw = rmp_serde::to_vec_named(b).unwrap();
-      req = req.header("content-type", "application/msgpack").body(raw);
+    #[derive(Deserialize)]
+    struct NotLeaderDetails {
+      leader_url: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct NotLeaderError {
+      error_details: NotLeaderDetails,
+    }
+
+    let raw_body = body.map(|b| rmp_serde::to_vec_named(b).unwrap());
+    let mut redirects = 0;
+    let (status, res_type, res_body_raw) = loop {
+      let endpoint = self
+        .leader
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| self.cfg.endpoint.clone());
+      let mut req = self
+        .r
+        .request(method.clone(), format!("{}{}", endpoint, path.as_ref()))
+        .header("accept", "application/msgpack");
+      if let Some(k) = &self.cfg.api_key {
+        req = req.header("authorization", k);
+      };
+      if let Some(raw) = &raw_body {
+        req = req
+          .header("content-type", "application/msgpack")
+          .body(raw.clone());
+      };
+      let res = match req.send().await {
+        Ok(res) => res,
+        Err(err) => {
+          // The leader may have gone away, so go back to the configured endpoint to find the new one.
+          *self.leader.write().unwrap() = None;
+          return Err(QueuedClientError::Request(err));
+        }
+      };
+      let status = res.status().as_u16();
+      let res_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok().map(|v| v.to_string()))
+        .unwrap_or_default();
+      let res_body_raw = res.bytes().await.map_err(QueuedClientError::Request)?;
+      // Nodes that aren't the cluster leader reject requests before doing anything, so it's always safe to retry them with the leader.
+      if status == 421 && redirects < MAX_LEADER_REDIRECTS {
+        if let Ok(NotLeaderError {
+          error_details: NotLeaderDetails {
+            leader_url: Some(leader_url),
+          },
+        }) = rmp_serde::from_slice(&res_body_raw)
+        {
+          *self.leader.write().unwrap() = Some(leader_url);
+          redirects += 1;
+          continue;
+        };
+      };
+      break (status, res_type, res_body_raw);
     };
-    let res = req
-      .send()
-      .await
-      .map_err(|err| QueuedClientError::Request(err))?;
-    let status = res.status().as_u16();
-    let res_type = res
-      .headers()
-      .get(CONTENT_TYPE)
-      .and_then(|v| v.to_str().ok().map(|v| v.to_string()))
-      .unwrap_or_default();
-    let res_body_raw = res
-      .bytes()
-      .await
-      .map_err(|err| QueuedClientError::Request(err))?;
     if status == 401 {
       return Err(QueuedClientError::Unauthorized);
     };
     #[derive(Deserialize)]
     struct ApiError {
       error: String,
+      error_code: u16,
+      retryable: bool,
       error_details: Option<String>,
     }
-    if status < 200 || status > 299 || !res_type.starts_with("application/msgpack") {
+    if !(200..=299).contains(&status) || !res_type.starts_with("application/msgpack") {
       // The server may be behind some proxy, LB, etc., so we don't know what the body looks like for sure.
       return Err(match rmp_serde::from_slice::<ApiError>(&res_body_raw) {
         Ok(api_error) => QueuedClientError::Api {
           status,
           error: api_error.error,
+          error_code: Some(api_error.error_code),
+          retryable: api_error.retryable,
           error_details: api_error.error_details,
         },
         Err(_) => QueuedClientError::Api {
           status,
           // We don't know if the response contains valid UTF-8 text or not.
           error: String::from_utf8_lossy(&res_body_raw).into_owned(),
+          error_code: None,
+          // Gateway errors from a proxy or LB are usually temporary.
+          retryable: matches!(status, 502..=504),
           error_details: None,
         },
       });
@@ -127,10 +195,38 @@ impl QueuedClient {
       c: self.clone(),
       qpp: format!(
         "/queue/{}",
-        utf8_percent_encode(&queue_name, NON_ALPHANUMERIC)
+        utf8_percent_encode(queue_name, NON_ALPHANUMERIC)
       ),
     }
   }
+
+  /// Pushes messages to several queues atomically, so that either all are pushed or none are. Returns the IDs of each queue's messages, in the same order. Older servers, clusters, and replicas don't support this.
+  pub async fn push_to_queues<'a>(
+    &self,
+    pushes: impl IntoIterator<Item = (&'a str, &'a [PushMessage])>,
+  ) -> QueuedClientResult<PushToQueuesOutput> {
+    #[derive(Serialize)]
+    struct InputPush<'a> {
+      queue: &'a str,
+      messages: &'a [PushMessage],
+    }
+    #[derive(Serialize)]
+    struct Input<'a> {
+      pushes: Vec<InputPush<'a>>,
+    }
+    self
+      .raw_request(
+        Method::POST,
+        "/messages/push",
+        Some(&Input {
+          pushes: pushes
+            .into_iter()
+            .map(|(queue, messages)| InputPush { queue, messages })
+            .collect(),
+        }),
+      )
+      .await
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -151,12 +247,34 @@ impl From<PolledMessage> for Message {
   }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum AttributeValue {
+  Integer(i64),
+  Float(f64),
+  String(String),
+}
+
+#[serde_as]
 #[derive(Deserialize, Clone, Debug)]
 pub struct PolledMessage {
   #[serde(with = "serde_bytes")]
   pub contents: Vec<u8>,
   pub id: u64,
   pub poll_tag: u32,
+  // Older servers don't return this.
+  #[serde(default)]
+  pub poll_count: u32,
+  // Older servers don't return this.
+  #[serde(default)]
+  pub attributes: BTreeMap<String, AttributeValue>,
+  /// How long after being pushed the message was polled, as measured by the server. Older servers don't return this, and it's None for messages pushed before the server supported it.
+  #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+  #[serde(rename = "latency_ms", default)]
+  pub latency: Option<Duration>,
+  // Older servers don't return this.
+  #[serde(default)]
+  pub group_id: Option<String>,
 }
 
 impl PolledMessage {
@@ -181,31 +299,114 @@ pub struct PushMessage {
   #[serde_as(as = "DurationSeconds<u64>")]
   #[serde(rename = "visibility_timeout_secs")]
   pub visibility_timeout: Duration,
+  // Older servers don't support attributes, so don't send them unless necessary.
+  #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+  pub attributes: BTreeMap<String, AttributeValue>,
+  /// If set, the message is deleted if it hasn't been deleted this long after being pushed. Older servers don't support this.
+  #[serde_as(as = "Option<DurationSeconds<u64>>")]
+  #[serde(rename = "ttl_secs", skip_serializing_if = "Option::is_none")]
+  pub ttl: Option<Duration>,
+  /// Messages with the same group ID are delivered one at a time in push order. Older servers don't support this.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub group_id: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct PushMessagesOutput {
   pub ids: Vec<u64>,
+  /// The queue each message was pushed to, if the queue has routing rules.
+  #[serde(default)]
+  pub queues: Option<Vec<String>>,
 }
 
 #[derive(Deserialize)]
-pub struct UpdateMessageOutput {
-  pub new_poll_tag: u32,
+pub struct PushToQueuesOutput {
+  /// The IDs of the messages pushed to each queue, in the same order.
+  pub ids: Vec<Vec<u64>>,
 }
 
+#[derive(Deserialize)]
+pub struct TransactionOutput {
+  /// The IDs of the pushed messages, in the same order.
+  pub ids: Vec<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateMessagesOutput {
+  /// In the same order as the updated messages. None if the message wasn't found or its poll tag didn't match.
+  pub new_poll_tags: Vec<Option<u32>>,
+}
+
+#[derive(Deserialize)]
+pub struct NackMessageOutput {}
+
 #[derive(Deserialize)]
 pub struct DeleteMessagesOutput {}
 
+#[serde_as]
+#[derive(Deserialize)]
+pub struct TakeoverMessageOutput {
+  /// None if the message isn't in flight or its lease is too recent.
+  pub new_poll_tag: Option<u32>,
+  /// How long ago the message's current lease started, if it's in flight.
+  #[serde_as(as = "Option<DurationSeconds<u64>>")]
+  #[serde(rename = "lease_age_secs")]
+  pub lease_age: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+pub struct VisibilityWatermarkOutput {
+  pub visible_now: bool,
+  /// Seconds since the epoch.
+  pub next_visible_time: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterSchemaOutput {
+  pub version: u32,
+}
+
+#[derive(Deserialize)]
+pub struct GetSchemaOutput {
+  #[serde(with = "serde_bytes")]
+  pub schema: Vec<u8>,
+}
+
 impl QueuedQueueClient {
   pub async fn poll_messages(
     &self,
     count: u64,
     visibility_timeout: Duration,
+  ) -> QueuedClientResult<PollMessagesOutput> {
+    self
+      .poll_messages_inner(count, visibility_timeout, None)
+      .await
+  }
+
+  /// Like `poll_messages`, but if the next message of `group_id` is visible, it's returned first. Older servers don't support this.
+  pub async fn poll_messages_preferring_group(
+    &self,
+    count: u64,
+    visibility_timeout: Duration,
+    group_id: &str,
+  ) -> QueuedClientResult<PollMessagesOutput> {
+    self
+      .poll_messages_inner(count, visibility_timeout, Some(group_id))
+      .await
+  }
+
+  async fn poll_messages_inner(
+    &self,
+    count: u64,
+    visibility_timeout: Duration,
+    prefer_group: Option<&str>,
   ) -> QueuedClientResult<PollMessagesOutput> {
     #[derive(Serialize)]
-    struct Input {
+    struct Input<'a> {
       count: u64,
       visibility_timeout_secs: u64,
+      #[serde(skip_serializing_if = "Option::is_none")]
+      prefer_group: Option<&'a str>,
     }
     self
       .c
@@ -215,6 +416,7 @@ impl QueuedQueueClient {
         Some(&Input {
           count,
           visibility_timeout_secs: visibility_timeout.as_secs(),
+          prefer_group,
         }),
       )
       .await
@@ -240,26 +442,110 @@ impl QueuedQueueClient {
       .await
   }
 
-  pub async fn update_message(
+  /// Deletes `deletes` and pushes `pushes` atomically, so that either both happen or neither does. Fails with a 404 if any message to delete wasn't found or its poll tag didn't match.
+  pub async fn transaction(
     &self,
-    m: Message,
-    new_visibility_timeout: Duration,
-  ) -> QueuedClientResult<UpdateMessageOutput> {
+    deletes: impl IntoIterator<Item = Message>,
+    pushes: impl AsRef<[PushMessage]>,
+  ) -> QueuedClientResult<TransactionOutput> {
     #[derive(Serialize)]
-    struct Input {
+    struct Input<'a> {
+      deletes: Vec<Message>,
+      pushes: &'a [PushMessage],
+    }
+    self
+      .c
+      .raw_request(
+        Method::POST,
+        format!("{}/messages/transaction", self.qpp),
+        Some(&Input {
+          deletes: deletes.into_iter().collect(),
+          pushes: pushes.as_ref(),
+        }),
+      )
+      .await
+  }
+
+  pub async fn update_messages(
+    &self,
+    msgs: impl IntoIterator<Item = (Message, Duration)>,
+  ) -> QueuedClientResult<UpdateMessagesOutput> {
+    #[derive(Serialize)]
+    struct InputMessage {
       id: u64,
       poll_tag: u32,
       visibility_timeout_secs: u64,
     }
+    #[derive(Serialize)]
+    struct Input {
+      messages: Vec<InputMessage>,
+    }
     self
       .c
       .raw_request(
         Method::POST,
         format!("{}/messages/update", self.qpp),
+        Some(&Input {
+          messages: msgs
+            .into_iter()
+            .map(|(m, new_visibility_timeout)| InputMessage {
+              id: m.id,
+              poll_tag: m.poll_tag,
+              visibility_timeout_secs: new_visibility_timeout.as_secs(),
+            })
+            .collect(),
+        }),
+      )
+      .await
+  }
+
+  pub async fn nack_message(
+    &self,
+    m: Message,
+    delay: Duration,
+  ) -> QueuedClientResult<NackMessageOutput> {
+    #[derive(Serialize)]
+    struct Input {
+      id: u64,
+      poll_tag: u32,
+      delay_secs: u64,
+    }
+    self
+      .c
+      .raw_request(
+        Method::POST,
+        format!("{}/messages/nack", self.qpp),
         Some(&Input {
           id: m.id,
           poll_tag: m.poll_tag,
-          visibility_timeout_secs: new_visibility_timeout.as_secs(),
+          delay_secs: delay.as_secs(),
+        }),
+      )
+      .await
+  }
+
+  /// Takes over an in-flight message whose lease started at least `min_lease_age` ago, e.g. when failing over from a singleton worker that has stopped updating it.
+  pub async fn takeover_message(
+    &self,
+    id: u64,
+    min_lease_age: Duration,
+    visibility_timeout: Duration,
+  ) -> QueuedClientResult<TakeoverMessageOutput> {
+    #[derive(Serialize)]
+    struct Input {
+      id: u64,
+      min_lease_age_secs: u64,
+      visibility_timeout_secs: u64,
+    }
+    self
+      .c
+      .raw_request(
+        Method::POST,
+        format!("{}/messages/takeover", self.qpp),
+        Some(&Input {
+          id,
+          min_lease_age_secs: min_lease_age.as_secs(),
+          visibility_timeout_secs: visibility_timeout.as_secs(),
         }),
       )
       .await
@@ -284,4 +570,49 @@ impl QueuedQueueClient {
       )
       .await
   }
+
+  /// Returns whether any message is visible now, and otherwise when the next one becomes visible, so consumers can sleep until then instead of polling.
+  pub async fn visibility_watermark(&self) -> QueuedClientResult<VisibilityWatermarkOutput> {
+    self
+      .c
+      .raw_request::<(), _>(
+        Method::GET,
+        format!("{}/visibility-watermark", self.qpp),
+        None,
+      )
+      .await
+  }
+
+  /// Registering a schema that's already registered returns its existing version.
+  pub async fn register_schema(
+    &self,
+    schema: impl Into<Vec<u8>>,
+  ) -> QueuedClientResult<RegisterSchemaOutput> {
+    #[derive(Serialize)]
+    struct Input {
+      #[serde(with = "serde_bytes")]
+      schema: Vec<u8>,
+    }
+    self
+      .c
+      .raw_request(
+        Method::POST,
+        format!("{}/schemas", self.qpp),
+        Some(&Input {
+          schema: schema.into(),
+        }),
+      )
+      .await
+  }
+
+  pub async fn get_schema(&self, version: u32) -> QueuedClientResult<GetSchemaOutput> {
+    self
+      .c
+      .raw_request::<(), _>(
+        Method::GET,
+        format!("{}/schemas/{}", self.qpp, version),
+        None,
+      )
+      .await
+  }
 }
@@ -0,0 +1,547 @@
+use crate::endpoint::archive::EndpointImportOutput;
+use axum::body::Bytes;
+use libqueued::op::export::OpExportInput;
+use libqueued::op::export::EXPORT_MAX_LIMIT;
+use libqueued::Queued;
+use serde::Deserialize;
+use serde::Serialize;
+use std::mem::take;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::Sender;
+use tracing::error;
+use tracing::info;
+
+// An archive is a portable copy of the messages of any number of queues, for migrating them to another server, storage backend, or major version. It starts with `ARCHIVE_MAGIC` and the archive format version as a u32 LE, followed by MessagePack encoded `ArchiveRecord`s, each prefixed by its length as a u32 LE, and ends with an empty record, so that a truncated archive can be told apart from a complete one. Unlike snapshots, archives don't depend on the storage format, so new fields can be added to records without changing the version as long as older readers can ignore them.
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"QUEUEDAR";
+const ARCHIVE_VERSION: u32 = 1;
+const ARCHIVE_HEADER_LEN: usize = ARCHIVE_MAGIC.len() + 4;
+pub(crate) const ARCHIVE_END: [u8; 4] = [0, 0, 0, 0];
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ArchiveRecord {
+  /// The following messages are in this queue, up to the next `Queue` record.
+  Queue { name: String },
+  Message {
+    id: u64,
+    /// In seconds since the epoch.
+    visible_time: i64,
+    poll_count: u32,
+    #[serde(with = "serde_bytes")]
+    contents: Vec<u8>,
+  },
+}
+
+pub(crate) fn archive_header() -> Vec<u8> {
+  let mut out = ARCHIVE_MAGIC.to_vec();
+  out.extend_from_slice(&ARCHIVE_VERSION.to_le_bytes());
+  out
+}
+
+pub(crate) fn encode_record(out: &mut Vec<u8>, record: &ArchiveRecord) {
+  let raw = rmp_serde::to_vec_named(record).unwrap();
+  out.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+  out.extend_from_slice(&raw);
+}
+
+/// Sends an archive of every message in `queues`, one chunk per page of messages. Returns early if the receiver is dropped. If reading a queue fails, the archive is left without its end.
+pub(crate) async fn send_archive(
+  queues: Vec<(String, Arc<Queued>)>,
+  tx: &Sender<Bytes>,
+) -> Result<(), String> {
+  if tx.send(archive_header().into()).await.is_err() {
+    return Ok(());
+  };
+  for (name, q) in queues {
+    let mut out = Vec::new();
+    encode_record(&mut out, &ArchiveRecord::Queue { name: name.clone() });
+    let mut after = None;
+    loop {
+      let page = q
+        .export(OpExportInput {
+          after,
+          limit: EXPORT_MAX_LIMIT,
+        })
+        .await
+        .map_err(|err| format!("failed to export queue {name}: {err:?}"))?;
+      for m in page.messages {
+        encode_record(&mut out, &ArchiveRecord::Message {
+          id: m.id,
+          visible_time: m.visible_time,
+          poll_count: m.poll_count,
+          contents: m.contents,
+        });
+      }
+      if tx.send(std::mem::take(&mut out).into()).await.is_err() {
+        return Ok(());
+      };
+      after = page.next;
+      if after.is_none() {
+        break;
+      };
+    }
+  }
+  let _ = tx.send(Bytes::from_static(&ARCHIVE_END)).await;
+  Ok(())
+}
+
+/// Decodes an archive as its chunks arrive.
+#[derive(Default)]
+pub(crate) struct ArchiveDecoder {
+  buf: Vec<u8>,
+  // Decoded bytes are only removed from `buf` when more are fed, to avoid moving the rest for every record.
+  pos: usize,
+  started: bool,
+  ended: bool,
+}
+
+impl ArchiveDecoder {
+  pub fn feed(&mut self, chunk: &[u8]) {
+    self.buf.drain(..self.pos);
+    self.pos = 0;
+    self.buf.extend_from_slice(chunk);
+  }
+
+  /// Whether the end of the archive has been read. Anything after it is ignored.
+  pub fn is_ended(&self) -> bool {
+    self.ended
+  }
+
+  /// How many bytes have been fed but not yet decoded.
+  pub fn pending_len(&self) -> usize {
+    self.buf.len() - self.pos
+  }
+
+  /// Returns the next record, or None if more chunks are needed or the archive has ended.
+  pub fn next_record(&mut self) -> Result<Option<ArchiveRecord>, String> {
+    if self.ended {
+      return Ok(None);
+    };
+    let raw = &self.buf[self.pos..];
+    if !self.started {
+      if raw.len() < ARCHIVE_HEADER_LEN {
+        return Ok(None);
+      };
+      if &raw[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC {
+        return Err("not an archive".to_string());
+      };
+      let version = u32::from_le_bytes(
+        raw[ARCHIVE_MAGIC.len()..ARCHIVE_HEADER_LEN]
+          .try_into()
+          .unwrap(),
+      );
+      if version > ARCHIVE_VERSION {
+        return Err(format!("unsupported archive version {version}"));
+      };
+      self.pos += ARCHIVE_HEADER_LEN;
+      self.started = true;
+      return self.next_record();
+    };
+    if raw.len() < 4 {
+      return Ok(None);
+    };
+    let len = u32::from_le_bytes(raw[..4].try_into().unwrap()) as usize;
+    if len == 0 {
+      self.ended = true;
+      self.pos = self.buf.len();
+      return Ok(None);
+    };
+    let Some(raw) = raw.get(4..4 + len) else {
+      return Ok(None);
+    };
+    let record = rmp_serde::from_slice(raw).map_err(|err| format!("invalid record: {err}"))?;
+    self.pos += 4 + len;
+    Ok(Some(record))
+  }
+}
+
+fn request(
+  client: &reqwest::Client,
+  method: reqwest::Method,
+  url: String,
+  api_key: Option<&str>,
+) -> reqwest::RequestBuilder {
+  let mut req = client
+    .request(method, url)
+    .header("accept", "application/msgpack");
+  if let Some(k) = api_key {
+    req = req.header("authorization", k);
+  };
+  req
+}
+
+async fn download(
+  server: &str,
+  api_key: Option<&str>,
+  path: &Path,
+) -> Result<(usize, usize), String> {
+  let mut res = request(
+    &reqwest::Client::new(),
+    reqwest::Method::GET,
+    format!("{server}/admin/export"),
+    api_key,
+  )
+  .send()
+  .await
+  .map_err(|err| err.to_string())?
+  .error_for_status()
+  .map_err(|err| err.to_string())?;
+  let mut file = File::create_new(path)
+    .await
+    .map_err(|err| format!("failed to create {path:?}: {err}"))?;
+  let mut decoder = ArchiveDecoder::default();
+  let (mut queues, mut messages) = (0, 0);
+  while let Some(chunk) = res.chunk().await.map_err(|err| err.to_string())? {
+    file
+      .write_all(&chunk)
+      .await
+      .map_err(|err| format!("failed to write {path:?}: {err}"))?;
+    // Decode as we go to check that the archive is complete.
+    decoder.feed(&chunk);
+    while let Some(record) = decoder.next_record()? {
+      match record {
+        ArchiveRecord::Queue { .. } => queues += 1,
+        ArchiveRecord::Message { .. } => messages += 1,
+      };
+    }
+  }
+  if !decoder.is_ended() {
+    return Err("server ended export early".to_string());
+  };
+  file
+    .sync_all()
+    .await
+    .map_err(|err| format!("failed to write {path:?}: {err}"))?;
+  Ok((queues, messages))
+}
+
+/// Downloads an archive of every queue on a server to a new file at `path`, which is removed if the archive is incomplete. Returns whether it succeeded.
+pub(crate) async fn export(server: &str, api_key: Option<&str>, path: &Path) -> bool {
+  if path.exists() {
+    error!(path = format!("{:?}", path), "export file already exists");
+    return false;
+  };
+  match download(server, api_key, path).await {
+    Ok((queues, messages)) => {
+      info!(queues, messages, "exported");
+      true
+    }
+    Err(err) => {
+      error!(error = err, "failed to export");
+      let _ = std::fs::remove_file(path);
+      false
+    }
+  }
+}
+
+// Archives are uploaded in parts of about this size, each a complete archive itself, so that neither side has to hold all of it and an interrupted import only needs to be repeated.
+const IMPORT_PART_BYTES: usize = 8 * 1024 * 1024;
+
+async fn upload_part(
+  client: &reqwest::Client,
+  server: &str,
+  api_key: Option<&str>,
+  mut part: Vec<u8>,
+  out: &mut EndpointImportOutput,
+) -> Result<(), String> {
+  part.extend_from_slice(&ARCHIVE_END);
+  let raw = request(
+    client,
+    reqwest::Method::POST,
+    format!("{server}/admin/import"),
+    api_key,
+  )
+  .header("content-type", "application/octet-stream")
+  .body(part)
+  .send()
+  .await
+  .map_err(|err| err.to_string())?
+  .error_for_status()
+  .map_err(|err| err.to_string())?
+  .bytes()
+  .await
+  .map_err(|err| err.to_string())?;
+  let res: EndpointImportOutput = rmp_serde::from_slice(&raw).map_err(|err| err.to_string())?;
+  for name in res.queues {
+    if out.queues.last() != Some(&name) {
+      out.queues.push(name);
+    };
+  }
+  out.imported += res.imported;
+  out.skipped += res.skipped;
+  Ok(())
+}
+
+async fn upload(
+  server: &str,
+  api_key: Option<&str>,
+  path: &Path,
+  out: &mut EndpointImportOutput,
+) -> Result<(), String> {
+  let client = reqwest::Client::new();
+  let mut file = File::open(path)
+    .await
+    .map_err(|err| format!("failed to open {path:?}: {err}"))?;
+  let mut decoder = ArchiveDecoder::default();
+  let mut queue = None;
+  let mut part = archive_header();
+  let mut chunk = vec![0; 1024 * 1024];
+  loop {
+    let n = file
+      .read(&mut chunk)
+      .await
+      .map_err(|err| format!("failed to read {path:?}: {err}"))?;
+    if n == 0 {
+      break;
+    };
+    decoder.feed(&chunk[..n]);
+    while let Some(record) = decoder.next_record()? {
+      if let ArchiveRecord::Queue { name } = &record {
+        queue = Some(name.clone());
+      };
+      encode_record(&mut part, &record);
+      if part.len() >= IMPORT_PART_BYTES {
+        upload_part(&client, server, api_key, take(&mut part), out).await?;
+        // Each part must say which queue its messages are in.
+        part = archive_header();
+        if let Some(name) = &queue {
+          encode_record(&mut part, &ArchiveRecord::Queue { name: name.clone() });
+        };
+      };
+    }
+  }
+  if !decoder.is_ended() {
+    return Err("archive is truncated".to_string());
+  };
+  upload_part(&client, server, api_key, part, out).await
+}
+
+/// Imports an archive file into a server, in parts. Returns whether it succeeded. As importing is idempotent, a failed import can be resumed by running it again.
+pub(crate) async fn import(server: &str, api_key: Option<&str>, path: &Path) -> bool {
+  let mut out = EndpointImportOutput::default();
+  let res = upload(server, api_key, path, &mut out).await;
+  let queues = out.queues.len();
+  match res {
+    Ok(()) => {
+      info!(
+        queues,
+        imported = out.imported,
+        skipped = out.skipped,
+        "imported"
+      );
+      true
+    }
+    Err(err) => {
+      error!(
+        queues,
+        imported = out.imported,
+        skipped = out.skipped,
+        error = err,
+        "failed to import"
+      );
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::archive_header;
+  use super::encode_record;
+  use super::send_archive;
+  use super::ArchiveDecoder;
+  use super::ArchiveRecord;
+  use super::ARCHIVE_END;
+  use super::ARCHIVE_HEADER_LEN;
+  use libqueued::op::push::OpPushInput;
+  use libqueued::op::push::OpPushInputMessage;
+  use libqueued::storage::StorageBackend;
+  use libqueued::Queued;
+  use libqueued::QueuedCfg;
+  use serde::Serialize;
+  use std::path::Path;
+  use std::sync::Arc;
+
+  fn records() -> Vec<ArchiveRecord> {
+    vec![
+      ArchiveRecord::Queue {
+        name: "orders".to_string(),
+      },
+      ArchiveRecord::Message {
+        id: 3,
+        visible_time: 1_700_000_000,
+        poll_count: 2,
+        contents: b"first".to_vec(),
+      },
+      ArchiveRecord::Message {
+        id: u64::MAX,
+        visible_time: -1,
+        poll_count: 0,
+        contents: vec![0; 70_000],
+      },
+      ArchiveRecord::Queue {
+        name: "empty".to_string(),
+      },
+    ]
+  }
+
+  fn encode(records: &[ArchiveRecord]) -> Vec<u8> {
+    let mut out = archive_header();
+    for r in records {
+      encode_record(&mut out, r);
+    }
+    out.extend_from_slice(&ARCHIVE_END);
+    out
+  }
+
+  // Decodes `raw` fed in chunks of `chunk_len`, returning the records and whether the end was reached.
+  fn decode(raw: &[u8], chunk_len: usize) -> Result<(Vec<ArchiveRecord>, bool), String> {
+    let mut decoder = ArchiveDecoder::default();
+    let mut out = Vec::new();
+    for chunk in raw.chunks(chunk_len) {
+      decoder.feed(chunk);
+      while let Some(record) = decoder.next_record()? {
+        out.push(record);
+      }
+    }
+    Ok((out, decoder.is_ended()))
+  }
+
+  #[test]
+  fn records_round_trip_however_they_are_chunked() {
+    let raw = encode(&records());
+    for chunk_len in [1, 3, 4, 13, 1024, raw.len()] {
+      assert_eq!(
+        decode(&raw, chunk_len).unwrap(),
+        (records(), true),
+        "{chunk_len}"
+      );
+    }
+    // Anything after the end is ignored.
+    let mut trailing = raw.clone();
+    trailing.extend_from_slice(b"garbage");
+    assert_eq!(decode(&trailing, 5).unwrap(), (records(), true));
+    assert_eq!(decode(&encode(&[]), 1).unwrap(), (vec![], true));
+  }
+
+  #[test]
+  fn truncated_archives_never_end() {
+    let raw = encode(&records());
+    for len in [
+      0,
+      ARCHIVE_HEADER_LEN - 1,
+      ARCHIVE_HEADER_LEN,
+      20,
+      raw.len() - 1,
+    ] {
+      let (_, ended) = decode(&raw[..len], 7).unwrap();
+      assert!(!ended, "{len}");
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_archives() {
+    let raw = encode(&records());
+    let mut magic = raw.clone();
+    magic[0] = b'X';
+    assert_eq!(decode(&magic, 1024).unwrap_err(), "not an archive");
+    let mut version = raw.clone();
+    version[ARCHIVE_HEADER_LEN - 4] = 2;
+    assert_eq!(
+      decode(&version, 1024).unwrap_err(),
+      "unsupported archive version 2"
+    );
+    let mut record = archive_header();
+    record.extend_from_slice(&3u32.to_le_bytes());
+    record.extend_from_slice(b"bad");
+    assert!(decode(&record, 1024)
+      .unwrap_err()
+      .starts_with("invalid record"));
+  }
+
+  #[test]
+  fn records_can_have_new_fields() {
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case")]
+    enum NewerRecord {
+      Queue { name: String, owner: String },
+    }
+    let mut raw = archive_header();
+    let record = rmp_serde::to_vec_named(&NewerRecord::Queue {
+      name: "orders".to_string(),
+      owner: "team".to_string(),
+    })
+    .unwrap();
+    raw.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    raw.extend_from_slice(&record);
+    raw.extend_from_slice(&ARCHIVE_END);
+    assert_eq!(
+      decode(&raw, 1024).unwrap(),
+      (
+        vec![ArchiveRecord::Queue {
+          name: "orders".to_string()
+        }],
+        true
+      )
+    );
+  }
+
+  #[tokio::test]
+  async fn sent_archives_have_every_message() {
+    let q = Arc::new(
+      Queued::load_and_start(Path::new("/nonexistent"), QueuedCfg {
+        storage: StorageBackend::InMemory,
+        ..Default::default()
+      })
+      .await,
+    );
+    let messages = [b"a".to_vec(), b"b".to_vec()]
+      .into_iter()
+      .map(|contents| OpPushInputMessage {
+        contents,
+        visibility_timeout_secs: 0,
+        visibility_jitter_secs: 0,
+        priority: 0,
+        attributes: Default::default(),
+        ttl_secs: None,
+        group_id: None,
+        external_id: None,
+      })
+      .collect();
+    q.push(OpPushInput { messages }).await.unwrap();
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    send_archive(vec![("q".to_string(), q)], &tx).await.unwrap();
+    drop(tx);
+    let mut raw = Vec::new();
+    while let Some(chunk) = rx.recv().await {
+      raw.extend_from_slice(&chunk);
+    }
+    let (records, ended) = decode(&raw, 1024).unwrap();
+    assert!(ended);
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0], ArchiveRecord::Queue {
+      name: "q".to_string()
+    });
+    for (record, (id, contents)) in records[1..].iter().zip([(0, b"a"), (1, b"b")]) {
+      let ArchiveRecord::Message {
+        id: got_id,
+        contents: got_contents,
+        poll_count,
+        ..
+      } = record
+      else {
+        panic!("expected a message: {record:?}");
+      };
+      assert_eq!(
+        (*got_id, &got_contents[..], *poll_count),
+        (id, &contents[..], 0)
+      );
+    }
+  }
+}
@@ -0,0 +1,435 @@
+use crate::queue_tree::is_in_subtree;
+use axum::http::HeaderMap;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use dashmap::DashMap;
+use hmac::Hmac;
+use hmac::Mac;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+  /// Push messages.
+  Push,
+  /// Poll, update, and delete messages.
+  Poll,
+  /// Everything, including managing queues. Identities with an empty prefix and this permission can also use server-wide admin endpoints.
+  Admin,
+}
+
+impl FromStr for Permission {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "push" => Ok(Permission::Push),
+      "poll" => Ok(Permission::Poll),
+      "admin" => Ok(Permission::Admin),
+      _ => Err(format!("unknown permission {s}")),
+    }
+  }
+}
+
+/// Who a request is from, and what it can access.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Identity {
+  /// Only queues whose names start with this prefix can be accessed.
+  pub prefix: String,
+  pub permissions: BTreeSet<Permission>,
+  /// If set, only this tenant's queues can be accessed, and server-wide admin endpoints can't be used.
+  #[serde(default)]
+  pub tenant: Option<String>,
+}
+
+impl Identity {
+  /// Creates an identity with all permissions, which is what API keys created before permissions existed had.
+  pub fn unrestricted(prefix: String) -> Self {
+    Self {
+      prefix,
+      permissions: BTreeSet::from([Permission::Push, Permission::Poll, Permission::Admin]),
+      tenant: None,
+    }
+  }
+
+  /// Creates an identity scoped to a tenant, with `prefix` relative to the tenant, e.g. `orders` for `acme/orders`. An empty prefix allows all of the tenant's queues.
+  pub fn for_tenant(tenant: String, prefix: &str, permissions: BTreeSet<Permission>) -> Self {
+    Self {
+      prefix: match prefix {
+        "" => tenant.clone(),
+        p => format!("{tenant}/{p}"),
+      },
+      permissions,
+      tenant: Some(tenant),
+    }
+  }
+
+  pub fn has_permission(&self, p: Permission) -> bool {
+    self.permissions.contains(&Permission::Admin) || self.permissions.contains(&p)
+  }
+
+  pub fn allows_queue(&self, queue: &str, p: Permission) -> bool {
+    queue.starts_with(&self.prefix)
+      && self.tenant.as_ref().is_none_or(|t| is_in_subtree(queue, t))
+      && self.has_permission(p)
+  }
+
+  pub fn is_server_admin(&self) -> bool {
+    self.prefix.is_empty() && self.tenant.is_none() && self.permissions.contains(&Permission::Admin)
+  }
+}
+
+/// The client's certificate, if the request was made over mutual TLS. This is added to every request on the connection as an extension.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+  /// The first common name (CN) in the certificate's subject, if any.
+  pub common_name: Option<String>,
+}
+
+/// What a request provides to authenticate itself.
+pub struct Credentials<'a> {
+  pub headers: &'a HeaderMap,
+  pub client_certificate: Option<&'a ClientCertificate>,
+}
+
+/// Authenticates requests. The global API key is always checked first and doesn't go through any provider. If any providers are configured, queues require authentication.
+pub trait AuthProvider: Send + Sync {
+  /// A short name for this kind of authentication (e.g. "jwt"), which is listed by `GET /capabilities`.
+  fn kind(&self) -> &'static str;
+
+  /// Returns None if the request doesn't have valid credentials for this provider, in which case the next provider is tried.
+  fn authenticate(&self, creds: &Credentials) -> Option<Identity>;
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+  let raw = headers.get("authorization")?.to_str().ok()?;
+  Some(raw.strip_prefix("Bearer ").unwrap_or(raw))
+}
+
+/// API keys provided on startup or managed using the `/admin/tokens` endpoints.
+pub(crate) struct StaticTokenAuthProvider {
+  tokens: Arc<DashMap<String, Identity>>,
+}
+
+impl StaticTokenAuthProvider {
+  pub fn new(tokens: Arc<DashMap<String, Identity>>) -> Self {
+    Self { tokens }
+  }
+}
+
+impl AuthProvider for StaticTokenAuthProvider {
+  fn kind(&self) -> &'static str {
+    "api_key"
+  }
+
+  fn authenticate(&self, creds: &Credentials) -> Option<Identity> {
+    self
+      .tokens
+      .get(bearer_token(creds.headers)?)
+      .map(|e| e.value().clone())
+  }
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+  alg: String,
+}
+
+#[derive(Deserialize)]
+struct JwtClaims {
+  exp: Option<i64>,
+  nbf: Option<i64>,
+  #[serde(default)]
+  queued_prefix: String,
+  queued_permissions: BTreeSet<Permission>,
+  queued_tenant: Option<String>,
+}
+
+/// JWTs signed using HS256 with a shared secret, provided as a bearer token. The `queued_prefix` (defaults to empty), `queued_permissions`, and optional `queued_tenant` claims determine the identity. The `exp` and `nbf` claims are enforced if present.
+pub(crate) struct JwtAuthProvider {
+  secret: Vec<u8>,
+}
+
+impl JwtAuthProvider {
+  pub fn new(secret: Vec<u8>) -> Self {
+    Self { secret }
+  }
+}
+
+impl AuthProvider for JwtAuthProvider {
+  fn kind(&self) -> &'static str {
+    "jwt"
+  }
+
+  fn authenticate(&self, creds: &Credentials) -> Option<Identity> {
+    let token = bearer_token(creds.headers)?;
+    let (signed, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signed.split_once('.')?;
+    let header: JwtHeader = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    // Never trust any other algorithm, including "none".
+    if header.alg != "HS256" {
+      return None;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+    mac.update(signed.as_bytes());
+    mac
+      .verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?)
+      .ok()?;
+    let claims: JwtClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+    let now = Utc::now().timestamp();
+    if claims.exp.is_some_and(|exp| now >= exp) || claims.nbf.is_some_and(|nbf| now < nbf) {
+      return None;
+    };
+    Some(match claims.queued_tenant {
+      Some(tenant) => {
+        Identity::for_tenant(tenant, &claims.queued_prefix, claims.queued_permissions)
+      }
+      None => Identity {
+        prefix: claims.queued_prefix,
+        permissions: claims.queued_permissions,
+        tenant: None,
+      },
+    })
+  }
+}
+
+/// Client certificates verified using mutual TLS, identified by their common name.
+pub(crate) struct ClientCertificateAuthProvider {
+  identities: HashMap<String, Identity>,
+}
+
+impl ClientCertificateAuthProvider {
+  pub fn new(identities: HashMap<String, Identity>) -> Self {
+    Self { identities }
+  }
+}
+
+impl AuthProvider for ClientCertificateAuthProvider {
+  fn kind(&self) -> &'static str {
+    "client_certificate"
+  }
+
+  fn authenticate(&self, creds: &Credentials) -> Option<Identity> {
+    let cn = creds.client_certificate?.common_name.as_ref()?;
+    self.identities.get(cn).cloned()
+  }
+}
+
+/// What a request needs to be authorized for.
+pub(crate) enum Access<'a> {
+  Public,
+  /// Requests between cluster nodes. Only the global API key is accepted.
+  Internal,
+  /// Server-wide management, e.g. listing queues and managing API keys.
+  Server,
+  /// Creating or deleting a queue.
+  QueueManagement(&'a str),
+  /// Using a queue.
+  Queue(&'a str, Permission),
+  /// Reading a queue's metadata that both producers and consumers need, e.g. schemas. Any permission on the queue allows this.
+  QueueRead(&'a str),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::AuthProvider;
+  use super::ClientCertificate;
+  use super::ClientCertificateAuthProvider;
+  use super::Credentials;
+  use super::Identity;
+  use super::JwtAuthProvider;
+  use super::Permission;
+  use super::StaticTokenAuthProvider;
+  use axum::http::HeaderMap;
+  use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+  use base64::Engine;
+  use chrono::Utc;
+  use dashmap::DashMap;
+  use hmac::Hmac;
+  use hmac::Mac;
+  use serde_json::json;
+  use serde_json::Value;
+  use sha2::Sha256;
+  use std::collections::BTreeSet;
+  use std::collections::HashMap;
+  use std::sync::Arc;
+
+  const SECRET: &[u8] = b"secret";
+
+  fn headers(authorization: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("authorization", authorization.parse().unwrap());
+    headers
+  }
+
+  fn authenticate(provider: &dyn AuthProvider, authorization: &str) -> Option<Identity> {
+    provider.authenticate(&Credentials {
+      headers: &headers(authorization),
+      client_certificate: None,
+    })
+  }
+
+  fn jwt_with(header: &Value, claims: &Value, secret: &[u8]) -> String {
+    let signed = format!(
+      "{}.{}",
+      URL_SAFE_NO_PAD.encode(header.to_string()),
+      URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(signed.as_bytes());
+    format!(
+      "{signed}.{}",
+      URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    )
+  }
+
+  fn jwt(claims: Value) -> String {
+    jwt_with(&json!({ "alg": "HS256", "typ": "JWT" }), &claims, SECRET)
+  }
+
+  #[test]
+  fn identities_only_allow_their_queues_and_permissions() {
+    let id = Identity {
+      prefix: "orders".to_string(),
+      permissions: BTreeSet::from([Permission::Push]),
+      tenant: None,
+    };
+    assert!(id.allows_queue("orders-eu", Permission::Push));
+    assert!(!id.allows_queue("orders-eu", Permission::Poll));
+    assert!(!id.allows_queue("audit", Permission::Push));
+    assert!(!id.is_server_admin());
+
+    let admin = Identity::unrestricted(String::new());
+    assert!(admin.allows_queue("anything", Permission::Poll));
+    assert!(admin.is_server_admin());
+    assert!(!Identity::unrestricted("orders".to_string()).is_server_admin());
+
+    let tenant = Identity::for_tenant("acme".to_string(), "", BTreeSet::from([Permission::Admin]));
+    assert!(tenant.allows_queue("acme/orders", Permission::Poll));
+    assert!(!tenant.allows_queue("acme-other/orders", Permission::Poll));
+    assert!(!tenant.is_server_admin());
+    let scoped = Identity::for_tenant(
+      "acme".to_string(),
+      "orders",
+      BTreeSet::from([Permission::Poll]),
+    );
+    assert_eq!(scoped.prefix, "acme/orders");
+
+    assert_eq!("poll".parse::<Permission>(), Ok(Permission::Poll));
+    assert!("Poll".parse::<Permission>().is_err());
+  }
+
+  #[test]
+  fn static_tokens_are_looked_up() {
+    let tokens = Arc::new(DashMap::new());
+    tokens.insert("k1".to_string(), Identity::unrestricted("a".to_string()));
+    let provider = StaticTokenAuthProvider::new(tokens.clone());
+    assert_eq!(authenticate(&provider, "k1").unwrap().prefix, "a");
+    assert_eq!(authenticate(&provider, "Bearer k1").unwrap().prefix, "a");
+    assert!(authenticate(&provider, "k2").is_none());
+    // Revoked tokens stop working immediately.
+    tokens.remove("k1");
+    assert!(authenticate(&provider, "k1").is_none());
+  }
+
+  #[test]
+  fn valid_jwts_have_the_claimed_identity() {
+    let provider = JwtAuthProvider::new(SECRET.to_vec());
+    let token = jwt(json!({
+      "queued_prefix": "orders",
+      "queued_permissions": ["push", "poll"],
+      "exp": Utc::now().timestamp() + 60,
+      "nbf": Utc::now().timestamp() - 60,
+    }));
+    let id = authenticate(&provider, &format!("Bearer {token}")).unwrap();
+    assert_eq!(id.prefix, "orders");
+    assert_eq!(
+      id.permissions,
+      BTreeSet::from([Permission::Push, Permission::Poll])
+    );
+    assert_eq!(id.tenant, None);
+
+    let token = jwt(json!({
+      "queued_permissions": ["admin"],
+      "queued_tenant": "acme",
+    }));
+    let id = authenticate(&provider, &token).unwrap();
+    assert_eq!(id.prefix, "acme");
+    assert_eq!(id.tenant.as_deref(), Some("acme"));
+  }
+
+  #[test]
+  fn rejects_invalid_jwts() {
+    let provider = JwtAuthProvider::new(SECRET.to_vec());
+    let claims = json!({ "queued_permissions": ["admin"] });
+    let token = jwt(claims.clone());
+    assert!(authenticate(&provider, &token).is_some());
+
+    let now = Utc::now().timestamp();
+    let (signed, signature) = token.rsplit_once('.').unwrap();
+    let tampered = format!(
+      "{}.{}.{signature}",
+      signed.split_once('.').unwrap().0,
+      URL_SAFE_NO_PAD.encode(
+        json!({ "queued_prefix": "", "queued_permissions": ["admin", "push"] }).to_string()
+      )
+    );
+    for invalid in [
+      jwt_with(&json!({ "alg": "HS256" }), &claims, b"other secret"),
+      jwt_with(&json!({ "alg": "none" }), &claims, SECRET),
+      jwt_with(&json!({ "alg": "HS512" }), &claims, SECRET),
+      format!("{signed}."),
+      tampered,
+      jwt(json!({ "queued_permissions": ["admin"], "exp": now - 1 })),
+      jwt(json!({ "queued_permissions": ["admin"], "nbf": now + 60 })),
+      jwt(json!({ "queued_prefix": "orders" })),
+      jwt(json!({ "queued_permissions": ["everything"] })),
+      jwt_with(&json!({ "typ": "JWT" }), &claims, SECRET),
+      "".to_string(),
+      "not a jwt".to_string(),
+      "a.b".to_string(),
+      "!!.!!.!!".to_string(),
+      format!(
+        "{}.{}.",
+        URL_SAFE_NO_PAD.encode("{"),
+        URL_SAFE_NO_PAD.encode("{")
+      ),
+    ] {
+      assert!(authenticate(&provider, &invalid).is_none(), "{invalid}");
+    }
+  }
+
+  #[test]
+  fn client_certificates_are_identified_by_common_name() {
+    let provider = ClientCertificateAuthProvider::new(HashMap::from([(
+      "worker".to_string(),
+      Identity::unrestricted("jobs".to_string()),
+    )]));
+    let cert = |cn: Option<&str>| ClientCertificate {
+      common_name: cn.map(|cn| cn.to_string()),
+    };
+    let authenticate = |authorization: &str, cert: Option<&ClientCertificate>| {
+      provider.authenticate(&Credentials {
+        headers: &headers(authorization),
+        client_certificate: cert,
+      })
+    };
+    assert_eq!(
+      authenticate("", Some(&cert(Some("worker"))))
+        .unwrap()
+        .prefix,
+      "jobs"
+    );
+    assert!(authenticate("", Some(&cert(Some("other")))).is_none());
+    assert!(authenticate("", Some(&cert(None))).is_none());
+    // The common name must come from a verified certificate, not a header.
+    assert!(authenticate("worker", None).is_none());
+  }
+}
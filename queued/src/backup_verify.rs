@@ -0,0 +1,94 @@
+use crate::apply_increment_dir;
+use crate::copy_dir_all;
+use crate::endpoint::queues::QUEUE_CREATE_OK_MARKER_FILE;
+use crate::queue_tree::queue_name_from_dir_name;
+use libqueued::backup::is_snapshot_increment;
+use libqueued::backup::verify_data_dir;
+use std::path::Path;
+use std::path::PathBuf;
+use tracing::error;
+use tracing::info;
+
+// Restores in the same way as on startup with `restore_from` and `restore_increments`.
+fn restore(path: &Path, increments: &[PathBuf], dir: &Path) -> Result<(), String> {
+  copy_dir_all(path, dir).map_err(|err| format!("failed to copy snapshot: {err}"))?;
+  for src in increments {
+    apply_increment_dir(src, dir)
+      .map_err(|err| format!("failed to apply incremental snapshot {src:?}: {err}"))?;
+  }
+  Ok(())
+}
+
+fn verify_queue(dir: &Path) -> Result<usize, String> {
+  if is_snapshot_increment(dir) {
+    return Err("is an incremental snapshot, so its base snapshot must be provided".to_string());
+  };
+  match std::fs::read_to_string(dir.join(QUEUE_CREATE_OK_MARKER_FILE)) {
+    Ok(c) if c.is_empty() => {}
+    Ok(_) => return Err(format!("unexpected {QUEUE_CREATE_OK_MARKER_FILE} contents")),
+    Err(err) => {
+      return Err(format!(
+        "failed to read {QUEUE_CREATE_OK_MARKER_FILE}: {err}"
+      ))
+    }
+  };
+  verify_data_dir(dir)
+}
+
+/// Restores a snapshot and its incremental snapshots into a scratch dir, and then checks that every queue in it would load and has no corrupt messages, logging the result for each queue. Returns whether there were no problems. The snapshot itself is never changed.
+pub(crate) fn backup_verify(
+  path: &Path,
+  increments: &[PathBuf],
+  scratch_dir: Option<PathBuf>,
+) -> bool {
+  let dir = scratch_dir.unwrap_or_else(|| {
+    std::env::temp_dir().join(format!("queued-backup-verify-{}", std::process::id()))
+  });
+  assert!(!dir.exists(), "scratch dir {dir:?} already exists");
+  info!(
+    path = format!("{:?}", path),
+    scratch_dir = format!("{:?}", dir),
+    "restoring snapshot"
+  );
+
+  let ok = match restore(path, increments, &dir) {
+    Ok(()) => {
+      let mut queues = 0;
+      let mut broken = 0;
+      for d in std::fs::read_dir(&dir).expect("read scratch dir") {
+        let d = d.expect("read scratch dir entry");
+        if !d
+          .metadata()
+          .expect("get scratch dir entry metadata")
+          .is_dir()
+        {
+          continue;
+        };
+        let name = queue_name_from_dir_name(&d.file_name().to_string_lossy());
+        queues += 1;
+        match verify_queue(&d.path()) {
+          Ok(messages) => info!(name, messages, "verified queue"),
+          Err(err) => {
+            error!(name, error = err, "queue is broken");
+            broken += 1;
+          }
+        };
+      }
+      info!(queues, broken, "verified snapshot");
+      broken == 0
+    }
+    Err(err) => {
+      error!(error = err, "snapshot can't be restored");
+      false
+    }
+  };
+
+  if let Err(err) = std::fs::remove_dir_all(&dir) {
+    error!(
+      scratch_dir = format!("{:?}", dir),
+      error = err.to_string(),
+      "failed to remove scratch dir"
+    );
+  };
+  ok
+}
@@ -0,0 +1,242 @@
+use axum::body::Bytes;
+use libqueued::op::delete::OpDeleteInput;
+use libqueued::op::delete::OpDeleteInputMessage;
+use libqueued::op::poll::OpPollInput;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use libqueued::Queued;
+use libqueued::QueuedCfg;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::NON_ALPHANUMERIC;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::warn;
+
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const OUTBOX_RETRY_BATCH: usize = 64;
+// How long before a push that failed to be forwarded from the outbox is retried.
+const OUTBOX_RETRY_BACKOFF_SECS: i64 = 30;
+
+#[derive(Serialize)]
+pub(crate) struct BridgeStatus {
+  pub url: String,
+  // Pushes forwarded when they were accepted.
+  pub forwarded: u64,
+  // Pushes that couldn't be forwarded when they were accepted, so were stored in the outbox.
+  pub outboxed: u64,
+  // Pushes in the outbox that have since been forwarded.
+  pub retried: u64,
+  // Attempts to forward a push from the outbox that failed.
+  pub retry_failures: u64,
+  // Pushes accepted by this server that the other cluster doesn't have yet. Consumers can be cut over once this is zero.
+  pub pending: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboxEntry {
+  queue: String,
+  #[serde(with = "serde_bytes")]
+  body: Vec<u8>,
+}
+
+// Forwards every accepted push to another queued cluster before acknowledging it, for migrating between clusters without losing messages. Pushes that can't be forwarded are durably stored in a local outbox and retried until they succeed. Unlike mirroring, the other cluster's responses matter, and only pushes are forwarded, as polls, updates, and deletes refer to message IDs that only exist on this server.
+pub(crate) struct Bridge {
+  url: String,
+  api_key: Option<String>,
+  client: reqwest::Client,
+  outbox: Queued,
+  forwarded: AtomicU64,
+  outboxed: AtomicU64,
+  retried: AtomicU64,
+  retry_failures: AtomicU64,
+}
+
+impl Bridge {
+  pub async fn start(
+    url: String,
+    api_key: Option<String>,
+    outbox_dir: &Path,
+    format_version: u32,
+  ) -> Arc<Self> {
+    std::fs::create_dir_all(outbox_dir).expect("create bridge outbox dir");
+    let outbox = Queued::load_and_start(outbox_dir, QueuedCfg {
+      format_version,
+      ..Default::default()
+    })
+    .await;
+    let bridge = Arc::new(Self {
+      url: url.trim_end_matches('/').to_string(),
+      api_key,
+      client: reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap(),
+      outbox,
+      forwarded: AtomicU64::new(0),
+      outboxed: AtomicU64::new(0),
+      retried: AtomicU64::new(0),
+      retry_failures: AtomicU64::new(0),
+    });
+    spawn(retry_outbox(Arc::downgrade(&bridge)));
+    bridge
+  }
+
+  /// Builds a push request body, for forwarding pushes that weren't made using the queued API.
+  pub fn push_body(messages: impl IntoIterator<Item = (Vec<u8>, u32)>) -> Bytes {
+    #[derive(Serialize)]
+    struct Message {
+      #[serde(with = "serde_bytes")]
+      contents: Vec<u8>,
+      visibility_timeout_secs: u32,
+    }
+    #[derive(Serialize)]
+    struct Input {
+      messages: Vec<Message>,
+    }
+    let messages = messages
+      .into_iter()
+      .map(|(contents, visibility_timeout_secs)| Message {
+        contents,
+        visibility_timeout_secs,
+      })
+      .collect();
+    rmp_serde::to_vec_named(&Input { messages }).unwrap().into()
+  }
+
+  async fn send(&self, queue: &str, body: Bytes) -> bool {
+    let mut req = self
+      .client
+      .post(format!(
+        "{}/queue/{}/messages/push",
+        self.url,
+        utf8_percent_encode(queue, NON_ALPHANUMERIC)
+      ))
+      .header("content-type", "application/msgpack")
+      .body(body);
+    if let Some(k) = &self.api_key {
+      req = req.header("authorization", k);
+    };
+    match req.send().await {
+      Ok(res) if res.status().is_success() => true,
+      Ok(res) => {
+        warn!(
+          queue,
+          status = res.status().as_u16(),
+          "bridge rejected forwarded push"
+        );
+        false
+      }
+      Err(err) => {
+        warn!(queue, error = err.to_string(), "failed to forward push");
+        false
+      }
+    }
+  }
+
+  /// Forwards a push request that has been accepted by this server. Returns an error only if the push could neither be forwarded nor stored in the outbox, in which case the push must not be acknowledged.
+  pub async fn forward(&self, queue: &str, body: Bytes) -> Result<(), ()> {
+    if self.send(queue, body.clone()).await {
+      self.forwarded.fetch_add(1, Ordering::Relaxed);
+      return Ok(());
+    };
+    let entry = OutboxEntry {
+      queue: queue.to_string(),
+      body: body.to_vec(),
+    };
+    self
+      .outbox
+      .push(OpPushInput {
+        messages: vec![OpPushInputMessage {
+          contents: rmp_serde::to_vec_named(&entry).unwrap(),
+          visibility_timeout_secs: 0,
+          visibility_jitter_secs: 0,
+          priority: 0,
+          attributes: Default::default(),
+          ttl_secs: None,
+          group_id: None,
+          external_id: None,
+        }],
+      })
+      .await
+      .map_err(|err| {
+        warn!(
+          queue,
+          error = format!("{err:?}"),
+          "failed to store push in bridge outbox"
+        )
+      })?;
+    self.outboxed.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+  }
+
+  pub fn status(&self) -> BridgeStatus {
+    BridgeStatus {
+      url: self.url.clone(),
+      forwarded: self.forwarded.load(Ordering::Relaxed),
+      outboxed: self.outboxed.load(Ordering::Relaxed),
+      retried: self.retried.load(Ordering::Relaxed),
+      retry_failures: self.retry_failures.load(Ordering::Relaxed),
+      pending: self.outbox.metrics().message_counter(),
+    }
+  }
+
+  pub async fn flush(&self) {
+    if let Err(err) = self.outbox.flush().await {
+      warn!(error = format!("{err:?}"), "failed to flush bridge outbox");
+    };
+  }
+}
+
+async fn retry_outbox(bridge: Weak<Bridge>) {
+  loop {
+    sleep(OUTBOX_RETRY_INTERVAL).await;
+    let Some(bridge) = Weak::upgrade(&bridge) else {
+      break;
+    };
+    let Ok(polled) = bridge
+      .outbox
+      .poll(OpPollInput {
+        count: OUTBOX_RETRY_BATCH,
+        visibility_timeout_secs: OUTBOX_RETRY_BACKOFF_SECS,
+        ignore_existing_visibility_timeouts: false,
+        prefer_group: None,
+        fields: None,
+      })
+      .await
+    else {
+      continue;
+    };
+    let mut done = Vec::new();
+    for m in polled.messages {
+      let entry: OutboxEntry = rmp_serde::from_slice(&m.contents).unwrap();
+      // Stop at the first failure, as the other cluster is probably unavailable. The rest will be retried after the backoff.
+      if !bridge.send(&entry.queue, entry.body.into()).await {
+        bridge.retry_failures.fetch_add(1, Ordering::Relaxed);
+        break;
+      };
+      bridge.retried.fetch_add(1, Ordering::Relaxed);
+      done.push(OpDeleteInputMessage {
+        id: m.id,
+        poll_tag: m.poll_tag,
+      });
+    }
+    if done.is_empty() {
+      continue;
+    };
+    // If this fails, the pushes will be forwarded again, which is safe as delivery is at-least-once anyway.
+    if let Err(err) = bridge.outbox.delete(OpDeleteInput { messages: done }).await {
+      warn!(
+        error = format!("{err:?}"),
+        "failed to delete forwarded pushes from bridge outbox"
+      );
+    };
+  }
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct BulkheadCfg {
+  pub max_concurrent_admin_requests: Option<usize>,
+  pub max_concurrent_data_requests: Option<usize>,
+  pub max_concurrent_export_requests: Option<usize>,
+}
+
+impl BulkheadCfg {
+  pub fn is_enabled(&self) -> bool {
+    self.max_concurrent_admin_requests.is_some()
+      || self.max_concurrent_data_requests.is_some()
+      || self.max_concurrent_export_requests.is_some()
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum EndpointClass {
+  /// Configuring and inspecting queues and the server, including listing messages.
+  Admin,
+  /// Operations on messages by producers and consumers.
+  Data,
+  /// Copying entire queues, e.g. exports, imports, and new replicas.
+  Export,
+}
+
+impl EndpointClass {
+  /// Classifies a request by its matched route. Returns None for health checks and replication between cluster nodes, which must never be rejected.
+  pub fn of_route(route: &str) -> Option<Self> {
+    Some(match route {
+      "/healthz" | "/livez" | "/readyz" => return None,
+      r if r.starts_with("/cluster/") => return None,
+      "/admin/export" | "/admin/import" | "/replica/records/:queue" | "/replica/wal/:queue" => {
+        Self::Export
+      }
+      r if r.starts_with("/sqs") => Self::Data,
+      // Listing and message histories are scans, so they're admin requests.
+      "/queue/:queue/messages" | "/queue/:queue/messages/:id/history" => Self::Admin,
+      r if r.starts_with("/queue/:queue/messages/") => Self::Data,
+      _ => Self::Admin,
+    })
+  }
+}
+
+/// Separate limits on how many requests of each `EndpointClass` can be handled at once, so that e.g. a heavy export can't take up capacity needed by pushes and polls.
+pub(crate) struct Bulkheads {
+  admin: Option<Arc<Semaphore>>,
+  data: Option<Arc<Semaphore>>,
+  export: Option<Arc<Semaphore>>,
+}
+
+impl Bulkheads {
+  pub fn new(cfg: BulkheadCfg) -> Self {
+    let sem = |max: Option<usize>| max.map(|n| Arc::new(Semaphore::new(n)));
+    Self {
+      admin: sem(cfg.max_concurrent_admin_requests),
+      data: sem(cfg.max_concurrent_data_requests),
+      export: sem(cfg.max_concurrent_export_requests),
+    }
+  }
+
+  /// Returns a permit to hold until the request has been handled, including its response body, or Err if the class is at its limit. Returns Ok(None) if the class isn't limited.
+  pub fn try_acquire(&self, class: EndpointClass) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    let sem = match class {
+      EndpointClass::Admin => &self.admin,
+      EndpointClass::Data => &self.data,
+      EndpointClass::Export => &self.export,
+    };
+    match sem {
+      None => Ok(None),
+      Some(sem) => sem.clone().try_acquire_owned().map(Some).map_err(|_| ()),
+    }
+  }
+}
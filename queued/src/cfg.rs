@@ -1,5 +1,33 @@
+use crate::auth::Identity;
+use crate::bulkhead::BulkheadCfg;
+use crate::disk_watchdog::DiskWatchdogCfg;
+use crate::endpoint::healthz::ReadyzCfg;
+use crate::queue_template::QueueTemplate;
+use crate::queue_tree::queue_name_is_valid;
+use crate::rate_limit::RateLimitCfg;
+use crate::tenant::TenantCfg;
+use crate::tombstone_gc::TombstoneGcCfg;
 use clap::Parser;
+use clap::Subcommand;
+use libqueued::compression::ContentsCodec;
+use libqueued::compression::ContentsCompression;
+use libqueued::db::CompactionStyle;
+use libqueued::db::FileCompression;
+use libqueued::db::RocksDbTuning;
+use libqueued::db::ZstdCompression;
+#[cfg(feature = "fault_injection")]
+use libqueued::fault::parse_faults;
+#[cfg(feature = "fault_injection")]
+use libqueued::fault::FaultRule;
+use libqueued::id_gen::IdStrategy;
+use libqueued::index_check::IndexMismatchAction;
+use libqueued::messages::PollOrder;
+use libqueued::op::poll::RedeliveryBackoff;
+use libqueued::quota::QuotaLimits;
+use libqueued::storage::StorageBackend;
 use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::env::var;
 use std::env::var_os;
 use std::net::Ipv4Addr;
@@ -13,14 +41,30 @@ use tracing::info;
 #[derive(Parser, Debug, Deserialize)]
 #[command(author, version, about)]
 struct Cli {
+  #[command(subcommand)]
+  #[serde(skip)]
+  command: Option<Command>,
+
   /// Path to the config file.
   #[arg(long)]
   config: Option<PathBuf>,
 
+  /// Check the config file for changes every few seconds, and apply changes to settings that can be changed without restarting. Changes to other settings are logged and ignored.
+  #[arg(long)]
+  watch_config: Option<bool>,
+
   /// Path to the data directory.
   #[arg(long)]
   data_dir: Option<PathBuf>,
 
+  /// Optional path to a snapshot created by `POST /admin/snapshot` to copy into the data directory on startup. The data directory must be empty.
+  #[arg(long)]
+  restore_from: Option<PathBuf>,
+
+  /// Optional comma-separated paths to incremental snapshots created by `POST /admin/snapshot` with a `base`, to apply in order after copying `restore_from`.
+  #[arg(long)]
+  restore_increments: Option<String>,
+
   /// Optional API key that clients must use to authenticate for managing queues. NOTE: This does not set authentication on queues themselves.
   #[arg(long)]
   global_api_key: Option<String>,
@@ -29,6 +73,26 @@ struct Cli {
   #[arg(long)]
   enable_auth: Option<bool>,
 
+  /// Optional comma-separated API keys to create on startup, in the form `key=permissions:prefix`, where `permissions` is one or more of `push`, `poll`, and `admin` joined by `+` (e.g. `k1=push:orders-,k2=push+poll:`). Requires `enable_auth`.
+  #[arg(long)]
+  api_keys: Option<String>,
+
+  /// Optional secret for verifying HS256 JWTs provided as bearer tokens. If provided, queues require authentication.
+  #[arg(long)]
+  jwt_secret: Option<String>,
+
+  /// Serve an SQS-compatible API at `/sqs`.
+  #[arg(long)]
+  enable_sqs_api: Option<bool>,
+
+  /// Optional comma-separated AWS access key IDs for SQS clients, in the form `access_key_id=api_key`. A client uses the API key as its secret access key, and requests signed with SigV4 using that access key ID are verified and authorized as the API key.
+  #[arg(long)]
+  sqs_access_keys: Option<String>,
+
+  /// Serve `POST /admin/generate`, which pushes synthetic messages for demos and smoke tests.
+  #[arg(long)]
+  enable_generator: Option<bool>,
+
   /// Interface for server to listen on. Defaults to 127.0.0.1.
   #[arg(long)]
   interface: Option<Ipv4Addr>,
@@ -38,17 +102,21 @@ struct Cli {
   port: Option<u16>,
 
   /// Optional path to the SSL private key in PEM format to enable SSL. If provided, `ssl_cert` must also be provided.
-  #[arg(long)]
+  #[arg(long, alias = "tls-key")]
   ssl_key: Option<PathBuf>,
 
   /// Optional path to the SSL certificate in PEM format to enable SSL. If provided, `ssl_key` must also be provided.
-  #[arg(long)]
+  #[arg(long, alias = "tls-cert")]
   ssl_cert: Option<PathBuf>,
 
   /// Optional path to the SSL CA in PEM format to enable mutual TLS. If provided, all clients must present a valid signed client certificate.
-  #[arg(long)]
+  #[arg(long, alias = "tls-ca")]
   ssl_ca: Option<PathBuf>,
 
+  /// Optional comma-separated permissions for client certificates, in the form `common_name=permissions:prefix` like `api_keys`. Requires `ssl_ca`. If provided, queues require authentication.
+  #[arg(long)]
+  ssl_client_identities: Option<String>,
+
   /// If provided, the server will create and listen on this Unix socket; `interface`, `port`, and `ssl*` will be ignored.
   #[arg(long)]
   unix_socket: Option<PathBuf>,
@@ -69,45 +137,652 @@ struct Cli {
   #[arg(long)]
   statsd_tags: Option<String>,
 
-  /// Batch sync delay time, in microseconds. For advanced usage only.
+  /// Optional base URL of another queued instance to mirror push and poll requests to, e.g. `http://10.0.0.5:3333`. Responses are compared but never returned to clients.
+  #[arg(long)]
+  mirror_url: Option<String>,
+
+  /// Optional API key to use when mirroring requests.
+  #[arg(long)]
+  mirror_api_key: Option<String>,
+
+  /// Percentage of push and poll requests to mirror, from 0 to 100. Defaults to 100.
+  #[arg(long)]
+  mirror_percent: Option<f64>,
+
+  /// Optional base URL of another queued cluster to forward every accepted push to before acknowledging it, e.g. `http://10.0.0.5:3333`, for migrating to that cluster without losing messages.
+  #[arg(long)]
+  bridge_url: Option<String>,
+
+  /// Optional API key to use when forwarding pushes.
+  #[arg(long)]
+  bridge_api_key: Option<String>,
+
+  /// Directory to durably store pushes that couldn't be forwarded yet, until they are. Required if `--bridge-url` is set, and must not be inside the data dir.
+  #[arg(long)]
+  bridge_outbox_dir: Option<PathBuf>,
+
+  /// Optional OTLP/HTTP endpoint to export traces to, e.g. `http://localhost:4318/v1/traces`.
+  #[arg(long)]
+  otlp_endpoint: Option<String>,
+
+  /// Only write on-disk features supported by this format version, so that it's safe to roll back to an older release that only supports that version. Defaults to the newest version.
+  #[arg(long)]
+  format_compat: Option<u32>,
+
+  /// How IDs of new messages are generated: `sequential` (the default), `snowflake` (ordered by push time, and unique across servers with different `--snowflake-node-id`s), or `random` (unguessable, but messages can't be in a group). Random IDs require format compatibility version 9 or newer. This can be changed at any time.
+  #[arg(long)]
+  id_strategy: Option<String>,
+
+  /// The node ID in Snowflake IDs, from 0 to 1023. Defaults to 0.
+  #[arg(long)]
+  snowflake_node_id: Option<u16>,
+
+  /// The order that visible messages with the same priority are polled in: `visible_time` (the default), earliest visible time first, or `fifo`, lowest ID first, which is push order for sequential and Snowflake IDs. With `visible_time`, a message whose visibility timeout has just expired is polled after messages pushed since that are already visible; with `fifo`, it's polled before them, so it can't be starved by new messages.
+  #[arg(long)]
+  poll_order: Option<String>,
+
+  /// How queues are stored: `rocksdb` (the default), or `redb`, a pure-Rust database that's only available when built with the `redb` feature. Existing queues aren't converted, so only change this for a new data dir. Clustering, index snapshots, and incremental snapshots require `rocksdb`.
+  #[arg(long)]
+  storage: Option<String>,
+
+  /// Batch sync delay time, in microseconds. This is the most latency added to each operation so that concurrent writes can be combined and synced together. For advanced usage only.
   #[arg(long)]
   batch_sync_delay_us: Option<u64>,
+
+  /// How many consecutive storage failures before a queue's storage circuit breaker opens and further storage operations are rejected. Defaults to 5.
+  #[arg(long)]
+  storage_breaker_threshold: Option<u32>,
+
+  /// Time, in milliseconds, that a storage circuit breaker stays open the first time it opens. Each subsequent consecutive opening doubles this, up to `--storage-breaker-max-backoff-ms`. Defaults to 250.
+  #[arg(long)]
+  storage_breaker_base_backoff_ms: Option<u64>,
+
+  /// Maximum time, in milliseconds, that a storage circuit breaker stays open before probing storage again. Defaults to 60000.
+  #[arg(long)]
+  storage_breaker_max_backoff_ms: Option<u64>,
+
+  /// Optional maximum size of a message's contents, in bytes. Pushes containing larger messages will be rejected.
+  #[arg(long)]
+  max_message_size: Option<usize>,
+
+  /// Optional minimum visibility timeout, in seconds, of polls and updates. Requests with a shorter visibility timeout will be rejected. Negative visibility timeouts are always rejected.
+  #[arg(long)]
+  min_visibility_timeout_secs: Option<i64>,
+
+  /// Optional maximum visibility timeout, in seconds, of polls and updates, and delay of pushes and nacks. Requests with a longer visibility timeout or delay will be rejected.
+  #[arg(long)]
+  max_visibility_timeout_secs: Option<i64>,
+
+  /// Each queue recommends a visibility timeout of the 99th percentile of how long consumers took to process messages, from poll to delete, multiplied by this factor. Queues with automatic visibility timeouts enabled use it for polls instead of the requested one. Defaults to 2.
+  #[arg(long)]
+  recommended_visibility_timeout_factor: Option<f64>,
+
+  /// Messages with contents up to this many bytes are stored in a single record with their state, reducing I/O per operation. Defaults to 1024.
+  #[arg(long)]
+  inline_max_contents_len: Option<usize>,
+
+  /// Optionally write a snapshot of each queue's index at this interval, so that restarts only replay writes since the last snapshot instead of scanning all messages. Old WAL files are kept for longer, using more disk space.
+  #[arg(long)]
+  index_snapshot_interval_secs: Option<u64>,
+
+  /// Optionally keep WAL files for at least this many seconds, so that incremental snapshots can be created relative to snapshots taken up to this long ago. This uses more disk space, in proportion to the amount of writes.
+  #[arg(long)]
+  snapshot_wal_retention_secs: Option<u64>,
+
+  /// Record every push, poll, update, nack, takeover, and delete of each message, with who did it, in an append-only log stored with each queue, queryable at `/queue/:queue/messages/:id/history`. This adds a write to every operation.
+  #[arg(long)]
+  audit_log: Option<bool>,
+
+  /// Optionally remove audit log events older than this many seconds. Otherwise, events are kept forever, including those of deleted messages.
+  #[arg(long)]
+  audit_log_retention_secs: Option<u64>,
+
+  /// Split each queue's in-memory index into this many independently locked shards, so that concurrent requests to a busy queue contend less on many cores. With more than one shard, priorities and visible times only order polls within each shard, so messages may be polled slightly out of order. Defaults to 1.
+  #[arg(long)]
+  index_shards: Option<usize>,
+
+  /// Optionally check each queue's index against its storage after loading it, catching corruption and bugs before they cause messages to be lost silently. On a mismatch, `refuse` stops the server, and `suspend` suspends all operations on the queue so it can be inspected. This takes about as long as loading without an index snapshot.
+  #[arg(long)]
+  verify_index: Option<String>,
+
+  /// Optionally compress each queue's data on disk using zstd at this level, from 1 (fastest) to 22 (smallest). Only newly written data is compressed, so this can be enabled or disabled at any time.
+  #[arg(long)]
+  zstd_level: Option<i32>,
+
+  /// Optionally train a zstd dictionary of up to this many bytes from a sample of each queue's recently written data, stored with that data, which greatly improves compression of small, similar messages. Requires `zstd_level`. 16384 to 65536 is usually enough.
+  #[arg(long)]
+  zstd_dictionary_bytes: Option<u32>,
+
+  /// Size in bytes of each queue's RocksDB block cache of uncompressed data read from disk. Defaults to 1073741824 (1 GiB).
+  #[arg(long)]
+  rocksdb_block_cache_bytes: Option<usize>,
+
+  /// Size in bytes of each queue's RocksDB write buffer, which is flushed to disk once full. Larger buffers absorb more writes and deletes before they reach disk. Defaults to 1073741824 (1 GiB).
+  #[arg(long)]
+  rocksdb_write_buffer_bytes: Option<usize>,
+
+  /// RocksDB compaction style: `level` (default), `universal`, or `fifo`. Universal compaction writes less but may temporarily need up to double the disk space. FIFO compaction drops the oldest data once the database is too large, so it's only safe for queues whose messages are always deleted long before then.
+  #[arg(long)]
+  rocksdb_compaction_style: Option<String>,
+
+  /// Once a queue's RocksDB WAL files take up more than this many bytes, memtables are flushed so that the oldest can be deleted. Defaults to 4 times the write buffer size.
+  #[arg(long)]
+  rocksdb_max_total_wal_bytes: Option<u64>,
+
+  /// Optionally sync RocksDB WAL files to disk in the background every this many bytes written, which smooths out I/O spikes.
+  #[arg(long)]
+  rocksdb_wal_bytes_per_sync: Option<u64>,
+
+  /// Comma-separated RocksDB compression algorithm for each level starting from L0, each of `none`, `snappy`, `lz4`, or `zstd`, e.g. `none,none,lz4,zstd`. The last one is used for all lower levels. Levels using zstd use the level and dictionary set by `zstd_level` and `zstd_dictionary_bytes`.
+  #[arg(long)]
+  rocksdb_compression_per_level: Option<String>,
+
+  /// Collect RocksDB statistics, such as time spent stalling writes and block cache hits and misses, and report them in queue metrics. This has a small performance cost.
+  #[arg(long)]
+  rocksdb_statistics: Option<bool>,
+
+  /// Optionally compress the contents of each message before storing it, using `zstd` or `lz4`, and decompress them when they're read. Unlike `zstd_level`, this also compresses offloaded contents, and compresses each message on its own, so it suits large messages like JSON documents. Requires format compatibility version 10 or newer. Existing messages stay as they are, so this can be enabled, disabled, or changed at any time.
+  #[arg(long)]
+  contents_compression: Option<String>,
+
+  /// Only compress contents of messages at least this many bytes. Defaults to 1024.
+  #[arg(long)]
+  contents_compression_min_len: Option<usize>,
+
+  /// Optionally train a dictionary of up to this many bytes for each queue from its recently pushed contents, retrained once as much new contents have been pushed as it was trained on, and compress new contents with it. This greatly improves compression of small, similar messages, like JSON with the same fields. Requires `contents_compression` to be `zstd` and format compatibility version 11 or newer. 16384 to 65536 is usually enough.
+  #[arg(long)]
+  contents_compression_dictionary_bytes: Option<usize>,
+
+  /// Offload contents of messages at least this many bytes to S3, if configured. Defaults to 1048576.
+  #[arg(long)]
+  offload_min_contents_len: Option<usize>,
+
+  /// Optional S3 (or S3-compatible, e.g. GCS) bucket to offload large message contents to.
+  #[arg(long)]
+  offload_s3_bucket: Option<String>,
+
+  /// S3 endpoint, e.g. `https://s3.us-east-1.amazonaws.com` or `https://storage.googleapis.com`. Required if `offload_s3_bucket` is provided.
+  #[arg(long)]
+  offload_s3_endpoint: Option<String>,
+
+  /// S3 region. Defaults to "us-east-1".
+  #[arg(long)]
+  offload_s3_region: Option<String>,
+
+  /// S3 access key ID. Defaults to the `AWS_ACCESS_KEY_ID` env var.
+  #[arg(long)]
+  offload_s3_access_key_id: Option<String>,
+
+  /// S3 secret access key. Defaults to the `AWS_SECRET_ACCESS_KEY` env var.
+  #[arg(long)]
+  offload_s3_secret_access_key: Option<String>,
+
+  /// Optional maximum number of messages per second, per queue, that become available for polling once visible. Useful to avoid a thundering herd when many messages become visible at once.
+  #[arg(long)]
+  release_pacing_max_per_sec: Option<u32>,
+
+  /// Optional delay in seconds to add to the visibility timeout of a message's second poll, to back off redeliveries of messages that keep failing. Each later poll's delay is multiplied by `redelivery_backoff_multiplier`, up to `redelivery_backoff_max_secs`.
+  #[arg(long)]
+  redelivery_backoff_base_secs: Option<u32>,
+
+  /// Factor to multiply the redelivery backoff by for each poll. Defaults to 2.
+  #[arg(long)]
+  redelivery_backoff_multiplier: Option<f64>,
+
+  /// Maximum redelivery backoff in seconds. Defaults to 3600.
+  #[arg(long)]
+  redelivery_backoff_max_secs: Option<u32>,
+
+  /// Optional percentage (1 to 100) of each queue's push rate before a compaction, snapshot, or scrub started, to cap pushes at while it's running. Pushes over the cap fail with 429 Too Many Requests, which keeps maintenance from slowing down other requests.
+  #[arg(long)]
+  maintenance_push_cap_percent: Option<u8>,
+
+  /// Optional maximum number of messages in each queue. Pushes that would exceed it fail with 507 Insufficient Storage.
+  #[arg(long)]
+  max_queue_messages: Option<u64>,
+
+  /// Optional maximum estimated bytes of live data in each queue, including message metadata but not offloaded contents. Pushes that would exceed it fail with 507 Insufficient Storage.
+  #[arg(long)]
+  max_queue_data_bytes: Option<u64>,
+
+  /// Optional maximum bytes used on disk by each queue, including deleted data not yet compacted away. Pushes that would exceed it fail with 507 Insufficient Storage.
+  #[arg(long)]
+  max_queue_disk_bytes: Option<u64>,
+
+  /// Optional maximum number of messages across all queues. Pushes that would exceed it fail with 507 Insufficient Storage.
+  #[arg(long)]
+  max_total_messages: Option<u64>,
+
+  /// Optional maximum estimated bytes of live data across all queues. Pushes that would exceed it fail with 507 Insufficient Storage.
+  #[arg(long)]
+  max_total_data_bytes: Option<u64>,
+
+  /// Optional maximum bytes used on disk across all queues. Pushes that would exceed it fail with 507 Insufficient Storage.
+  #[arg(long)]
+  max_total_disk_bytes: Option<u64>,
+
+  /// Optional maximum number of push requests per second from each client, identified by its API key or else its IP address.
+  #[arg(long)]
+  rate_limit_push_requests_per_sec: Option<u64>,
+
+  /// Optional maximum number of pushed messages per second from each client, identified by its API key or else its IP address.
+  #[arg(long)]
+  rate_limit_push_messages_per_sec: Option<u64>,
+
+  /// Optional maximum number of poll requests per second from each client, identified by its API key or else its IP address.
+  #[arg(long)]
+  rate_limit_polls_per_sec: Option<u64>,
+
+  /// Optional maximum number of admin requests handled at once, e.g. creating queues, changing settings, and listing messages. Requests over the limit fail with 503 Service Unavailable. Health checks and replication between cluster nodes are never limited.
+  #[arg(long)]
+  max_concurrent_admin_requests: Option<usize>,
+
+  /// Optional maximum number of requests on messages handled at once, e.g. pushes, polls, and deletes, including the SQS API. Long polls and streams count until they end. Requests over the limit fail with 503 Service Unavailable.
+  #[arg(long)]
+  max_concurrent_data_requests: Option<usize>,
+
+  /// Optional maximum number of exports, imports, and copies for new replicas handled at once. Requests over the limit fail with 503 Service Unavailable.
+  #[arg(long)]
+  max_concurrent_export_requests: Option<usize>,
+
+  /// Maximum number of writes waiting to be synced to a queue's storage before `/readyz` reports the server as not ready. Defaults to 10000.
+  #[arg(long)]
+  readyz_max_sync_backlog: Option<usize>,
+
+  /// Minimum percentage of the data dir's disk that must be free for `/readyz` to report the server as ready. Defaults to 5; 0 disables the check.
+  #[arg(long)]
+  readyz_min_free_disk_percent: Option<f64>,
+
+  /// How long `/readyz` waits for each queue's storage to respond to a read before reporting the server as not ready. Defaults to 1000.
+  #[arg(long)]
+  readyz_timeout_ms: Option<u64>,
+
+  /// Suspend pushes while less than this percentage of the data dir's disk is free, so that writes don't start failing once it's full. Polls, updates, and deletes continue to work.
+  #[arg(long)]
+  disk_min_free_percent: Option<f64>,
+
+  /// Resume pushes suspended due to low disk space once at least this percentage of the disk is free. Defaults to 5 more than `disk_min_free_percent`.
+  #[arg(long)]
+  disk_resume_free_percent: Option<f64>,
+
+  /// How often free disk space is checked when `disk_min_free_percent` is set. Defaults to 5000.
+  #[arg(long)]
+  disk_check_interval_ms: Option<u64>,
+
+  /// Compact each queue's message keys on disk this often, removing tombstones left behind by deleted messages, which slow down iterating messages until RocksDB compacts them on its own.
+  #[arg(long)]
+  tombstone_gc_interval_secs: Option<u64>,
+
+  /// Compact a queue's message keys on disk once at least this percentage of the entries in its files are tombstones.
+  #[arg(long)]
+  tombstone_gc_max_percent: Option<f64>,
+
+  /// Only compact a queue due to `tombstone_gc_max_percent` once it has at least this many tombstones. Defaults to 100000.
+  #[arg(long)]
+  tombstone_gc_min_tombstones: Option<u64>,
+
+  /// Enables replication to peers with this node ID. All nodes in a cluster must have distinct IDs; the reachable node with the latest data becomes the leader, with the lowest ID breaking ties.
+  #[arg(long)]
+  cluster_node_id: Option<u64>,
+
+  /// Other nodes in the cluster. Use the format: `id1=http://host1:3333,id2=http://host2:3333`.
+  #[arg(long)]
+  cluster_peers: Option<String>,
+
+  /// Minimum number of peers that must apply a write before it's acknowledged. Defaults to a majority of the cluster, counting this node, e.g. 1 for a cluster of 3 nodes.
+  #[arg(long)]
+  cluster_min_in_sync_peers: Option<usize>,
+
+  /// Makes this server a read-only replica of the server at this URL, e.g. `http://primary:3333`, copying all of its queues and then continuously applying the writes from its WAL. Writes are redirected to the primary. The primary must retain its WAL for longer than replicas lag behind using `--snapshot-wal-retention-secs`, or else replicas will have to copy entire queues again. Cannot be used with clustering.
+  #[arg(long)]
+  replica_of: Option<String>,
+
+  /// API key to use for requests to the primary, which must have server-wide access.
+  #[arg(long)]
+  replica_api_key: Option<String>,
+
+  /// How often a replica fetches new writes from the primary. Defaults to 1000.
+  #[arg(long)]
+  replica_sync_interval_ms: Option<u64>,
 }
 
 // We cannot simply rely on default value if omitted, as we need to differentiate between a set (but empty/default) value and an omitted value to know if they override/are overriden by defaults, env vars, CLI, etc.
 #[derive(Default, Deserialize)]
 struct CfgFile {
+  watch_config: Option<bool>,
   data_dir: Option<PathBuf>,
+  restore_from: Option<PathBuf>,
+  restore_increments: Option<String>,
   global_api_key: Option<String>,
   enable_auth: Option<bool>,
+  api_keys: Option<String>,
+  jwt_secret: Option<String>,
+  enable_sqs_api: Option<bool>,
+  sqs_access_keys: Option<String>,
+  enable_generator: Option<bool>,
   interface: Option<Ipv4Addr>,
   port: Option<u16>,
   ssl_key: Option<PathBuf>,
   ssl_cert: Option<PathBuf>,
   ssl_ca: Option<PathBuf>,
+  ssl_client_identities: Option<String>,
   unix_socket: Option<PathBuf>,
   unix_socket_mode: Option<u32>,
   statsd: Option<SocketAddr>,
   statsd_prefix: Option<String>,
   statsd_tags: Option<String>,
+  mirror_url: Option<String>,
+  mirror_api_key: Option<String>,
+  mirror_percent: Option<f64>,
+  bridge_url: Option<String>,
+  bridge_api_key: Option<String>,
+  bridge_outbox_dir: Option<PathBuf>,
+  otlp_endpoint: Option<String>,
+  format_compat: Option<u32>,
+  id_strategy: Option<String>,
+  snowflake_node_id: Option<u16>,
+  poll_order: Option<String>,
+  storage: Option<String>,
   batch_sync_delay_us: Option<u64>,
+  storage_breaker_threshold: Option<u32>,
+  storage_breaker_base_backoff_ms: Option<u64>,
+  storage_breaker_max_backoff_ms: Option<u64>,
+  recommended_visibility_timeout_factor: Option<f64>,
+  inline_max_contents_len: Option<usize>,
+  index_snapshot_interval_secs: Option<u64>,
+  snapshot_wal_retention_secs: Option<u64>,
+  audit_log: Option<bool>,
+  audit_log_retention_secs: Option<u64>,
+  index_shards: Option<usize>,
+  verify_index: Option<String>,
+  zstd_level: Option<i32>,
+  zstd_dictionary_bytes: Option<u32>,
+  rocksdb_block_cache_bytes: Option<usize>,
+  rocksdb_write_buffer_bytes: Option<usize>,
+  rocksdb_compaction_style: Option<String>,
+  rocksdb_max_total_wal_bytes: Option<u64>,
+  rocksdb_wal_bytes_per_sync: Option<u64>,
+  rocksdb_compression_per_level: Option<String>,
+  rocksdb_statistics: Option<bool>,
+  contents_compression: Option<String>,
+  contents_compression_min_len: Option<usize>,
+  contents_compression_dictionary_bytes: Option<usize>,
+  offload_min_contents_len: Option<usize>,
+  offload_s3_bucket: Option<String>,
+  offload_s3_endpoint: Option<String>,
+  offload_s3_region: Option<String>,
+  offload_s3_access_key_id: Option<String>,
+  offload_s3_secret_access_key: Option<String>,
+  maintenance_push_cap_percent: Option<u8>,
+  max_queue_messages: Option<u64>,
+  max_queue_data_bytes: Option<u64>,
+  max_queue_disk_bytes: Option<u64>,
+  max_total_messages: Option<u64>,
+  max_total_data_bytes: Option<u64>,
+  max_total_disk_bytes: Option<u64>,
+  max_concurrent_admin_requests: Option<usize>,
+  max_concurrent_data_requests: Option<usize>,
+  max_concurrent_export_requests: Option<usize>,
+  disk_min_free_percent: Option<f64>,
+  disk_resume_free_percent: Option<f64>,
+  disk_check_interval_ms: Option<u64>,
+  tombstone_gc_interval_secs: Option<u64>,
+  tombstone_gc_max_percent: Option<f64>,
+  tombstone_gc_min_tombstones: Option<u64>,
+  cluster_node_id: Option<u64>,
+  cluster_peers: Option<String>,
+  cluster_min_in_sync_peers: Option<usize>,
+  replica_of: Option<String>,
+  replica_api_key: Option<String>,
+  replica_sync_interval_ms: Option<u64>,
+  #[serde(flatten)]
+  reloadable: ReloadableCfgFile,
+  // Templates are structured, so they can only be set in the config file, as `[templates.<name>]` tables.
+  #[serde(default)]
+  templates: BTreeMap<String, QueueTemplate>,
+  // Also structured, as `[tenants.<name>]` tables.
+  #[serde(default)]
+  tenants: BTreeMap<String, TenantCfg>,
+}
+
+/// The settings that can be changed without restarting, by editing the config file with `--watch-config` or using `PUT /admin/config`. Like `CfgFile`, each is optional so that it can be overridden.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct ReloadableCfgFile {
+  pub max_message_size: Option<usize>,
+  pub min_visibility_timeout_secs: Option<i64>,
+  pub max_visibility_timeout_secs: Option<i64>,
+  pub release_pacing_max_per_sec: Option<u32>,
+  pub redelivery_backoff_base_secs: Option<u32>,
+  pub redelivery_backoff_multiplier: Option<f64>,
+  pub redelivery_backoff_max_secs: Option<u32>,
+  pub rate_limit_push_requests_per_sec: Option<u64>,
+  pub rate_limit_push_messages_per_sec: Option<u64>,
+  pub rate_limit_polls_per_sec: Option<u64>,
+  pub readyz_max_sync_backlog: Option<usize>,
+  pub readyz_min_free_disk_percent: Option<f64>,
+  pub readyz_timeout_ms: Option<u64>,
+}
+
+impl ReloadableCfgFile {
+  /// The names of all settings that can be reloaded, which are also their config file keys.
+  pub fn keys() -> Vec<String> {
+    Self::default().set_keys_or_all(true)
+  }
+
+  /// The names of the settings that are set.
+  pub fn set_keys(&self) -> Vec<String> {
+    self.set_keys_or_all(false)
+  }
+
+  fn set_keys_or_all(&self, all: bool) -> Vec<String> {
+    let serde_json::Value::Object(map) = serde_json::to_value(self).unwrap() else {
+      unreachable!();
+    };
+    map
+      .into_iter()
+      .filter(|(_, v)| all || !v.is_null())
+      .map(|(k, _)| k)
+      .collect()
+  }
+
+  /// Settings from `self`, falling back to `other` for those that aren't set.
+  pub fn or(self, other: Self) -> Self {
+    Self {
+      max_message_size: self.max_message_size.or(other.max_message_size),
+      min_visibility_timeout_secs: self
+        .min_visibility_timeout_secs
+        .or(other.min_visibility_timeout_secs),
+      max_visibility_timeout_secs: self
+        .max_visibility_timeout_secs
+        .or(other.max_visibility_timeout_secs),
+      release_pacing_max_per_sec: self
+        .release_pacing_max_per_sec
+        .or(other.release_pacing_max_per_sec),
+      redelivery_backoff_base_secs: self
+        .redelivery_backoff_base_secs
+        .or(other.redelivery_backoff_base_secs),
+      redelivery_backoff_multiplier: self
+        .redelivery_backoff_multiplier
+        .or(other.redelivery_backoff_multiplier),
+      redelivery_backoff_max_secs: self
+        .redelivery_backoff_max_secs
+        .or(other.redelivery_backoff_max_secs),
+      rate_limit_push_requests_per_sec: self
+        .rate_limit_push_requests_per_sec
+        .or(other.rate_limit_push_requests_per_sec),
+      rate_limit_push_messages_per_sec: self
+        .rate_limit_push_messages_per_sec
+        .or(other.rate_limit_push_messages_per_sec),
+      rate_limit_polls_per_sec: self
+        .rate_limit_polls_per_sec
+        .or(other.rate_limit_polls_per_sec),
+      readyz_max_sync_backlog: self
+        .readyz_max_sync_backlog
+        .or(other.readyz_max_sync_backlog),
+      readyz_min_free_disk_percent: self
+        .readyz_min_free_disk_percent
+        .or(other.readyz_min_free_disk_percent),
+      readyz_timeout_ms: self.readyz_timeout_ms.or(other.readyz_timeout_ms),
+    }
+  }
+
+  /// Fills in defaults for settings that aren't set, and checks that the settings are valid.
+  pub fn resolve(&self) -> Result<ReloadableCfg, String> {
+    let cfg = ReloadableCfg {
+      max_message_size: self.max_message_size,
+      min_visibility_timeout_secs: self.min_visibility_timeout_secs.unwrap_or(0),
+      max_visibility_timeout_secs: self.max_visibility_timeout_secs,
+      release_pacing_max_per_sec: self.release_pacing_max_per_sec,
+      redelivery_backoff: self
+        .redelivery_backoff_base_secs
+        .map(|base_secs| RedeliveryBackoff {
+          base_secs,
+          multiplier: self.redelivery_backoff_multiplier.unwrap_or(2.0),
+          max_secs: self.redelivery_backoff_max_secs.unwrap_or(3600),
+        }),
+      rate_limit: RateLimitCfg {
+        push_requests_per_sec: self.rate_limit_push_requests_per_sec,
+        push_messages_per_sec: self.rate_limit_push_messages_per_sec,
+        polls_per_sec: self.rate_limit_polls_per_sec,
+      },
+      readyz: ReadyzCfg {
+        max_sync_backlog: self.readyz_max_sync_backlog.unwrap_or(10000),
+        min_free_disk_percent: self.readyz_min_free_disk_percent.unwrap_or(5.0),
+        timeout: Duration::from_millis(self.readyz_timeout_ms.unwrap_or(1000)),
+      },
+    };
+    if cfg
+      .redelivery_backoff
+      .is_some_and(|b| b.multiplier < 1.0 || b.base_secs > b.max_secs)
+    {
+      return Err("the redelivery backoff multiplier must be at least 1, and the base must not exceed the maximum".to_string());
+    };
+    if cfg
+      .max_visibility_timeout_secs
+      .is_some_and(|max| max < cfg.min_visibility_timeout_secs)
+    {
+      return Err(
+        "max visibility timeout must not be less than min visibility timeout".to_string(),
+      );
+    };
+    if !(0.0..=100.0).contains(&cfg.readyz.min_free_disk_percent) {
+      return Err("readyz min free disk percent must be between 0 and 100".to_string());
+    };
+    Ok(cfg)
+  }
+}
+
+/// The resolved `ReloadableCfgFile`.
+#[derive(Clone, Copy)]
+pub(crate) struct ReloadableCfg {
+  pub max_message_size: Option<usize>,
+  pub min_visibility_timeout_secs: i64,
+  pub max_visibility_timeout_secs: Option<i64>,
+  pub release_pacing_max_per_sec: Option<u32>,
+  pub redelivery_backoff: Option<RedeliveryBackoff>,
+  pub rate_limit: RateLimitCfg,
+  pub readyz: ReadyzCfg,
+}
+
+impl ReloadableCfg {
+  /// The settings with every value set, including defaults.
+  pub fn to_file(self) -> ReloadableCfgFile {
+    ReloadableCfgFile {
+      max_message_size: self.max_message_size,
+      min_visibility_timeout_secs: Some(self.min_visibility_timeout_secs),
+      max_visibility_timeout_secs: self.max_visibility_timeout_secs,
+      release_pacing_max_per_sec: self.release_pacing_max_per_sec,
+      redelivery_backoff_base_secs: self.redelivery_backoff.map(|b| b.base_secs),
+      redelivery_backoff_multiplier: self.redelivery_backoff.map(|b| b.multiplier),
+      redelivery_backoff_max_secs: self.redelivery_backoff.map(|b| b.max_secs),
+      rate_limit_push_requests_per_sec: self.rate_limit.push_requests_per_sec,
+      rate_limit_push_messages_per_sec: self.rate_limit.push_messages_per_sec,
+      rate_limit_polls_per_sec: self.rate_limit.polls_per_sec,
+      readyz_max_sync_backlog: Some(self.readyz.max_sync_backlog),
+      readyz_min_free_disk_percent: Some(self.readyz.min_free_disk_percent),
+      readyz_timeout_ms: Some(self.readyz.timeout.as_millis() as u64),
+    }
+  }
 }
 
 pub(crate) struct Cfg {
+  pub config_file: Option<PathBuf>,
+  // The config file as loaded on startup, to find changes to settings that require a restart.
+  pub config_file_table: toml::Table,
+  pub watch_config: bool,
+  pub reloadable: ReloadableCfg,
+  // Settings that were set by CLI args or env vars, which take precedence over the config file and can't be reloaded.
+  pub reloadable_overrides: ReloadableCfgFile,
   pub data_dir: PathBuf,
+  pub restore_from: Option<PathBuf>,
+  pub restore_increments: Vec<PathBuf>,
   pub global_api_key: Option<String>,
   pub enable_auth: bool,
+  pub api_keys: Vec<(String, Identity)>,
+  pub jwt_secret: Option<String>,
+  pub enable_sqs_api: bool,
+  pub sqs_access_keys: BTreeMap<String, String>,
+  pub enable_generator: bool,
   pub interface: Ipv4Addr,
   pub port: u16,
   pub ssl_key: Option<PathBuf>,
   pub ssl_cert: Option<PathBuf>,
   pub ssl_ca: Option<PathBuf>,
+  pub ssl_client_identities: Vec<(String, Identity)>,
   pub unix_socket: Option<PathBuf>,
   pub unix_socket_mode: u32,
   pub statsd: Option<SocketAddr>,
   pub statsd_prefix: String,
   pub statsd_tags: Vec<(String, String)>,
+  pub mirror_url: Option<String>,
+  pub mirror_api_key: Option<String>,
+  pub mirror_percent: f64,
+  pub bridge_url: Option<String>,
+  pub bridge_api_key: Option<String>,
+  pub bridge_outbox_dir: Option<PathBuf>,
+  pub otlp_endpoint: Option<String>,
+  // Only set using the `QUEUED_FAULTS` env var, so that it can't end up in a config file used in production. See `libqueued::fault::parse_faults`.
+  #[cfg(feature = "fault_injection")]
+  pub faults: Vec<FaultRule>,
+  pub format_compat: Option<u32>,
+  pub id_strategy: IdStrategy,
+  pub poll_order: PollOrder,
+  pub storage: StorageBackend,
   pub batch_sync_delay: Duration,
+  pub storage_breaker_threshold: u32,
+  pub storage_breaker_base_backoff: Duration,
+  pub storage_breaker_max_backoff: Duration,
+  pub recommended_visibility_timeout_factor: f64,
+  pub inline_max_contents_len: usize,
+  pub index_snapshot_interval: Option<Duration>,
+  pub snapshot_wal_retention: Option<Duration>,
+  pub audit_log: bool,
+  pub audit_log_retention: Option<Duration>,
+  pub index_shards: usize,
+  pub verify_index: Option<IndexMismatchAction>,
+  pub zstd_compression: Option<ZstdCompression>,
+  pub rocksdb: RocksDbTuning,
+  pub contents_compression: Option<ContentsCompression>,
+  pub offload_min_contents_len: usize,
+  pub offload_s3_bucket: Option<String>,
+  pub offload_s3_endpoint: Option<String>,
+  pub offload_s3_region: String,
+  pub offload_s3_access_key_id: Option<String>,
+  pub offload_s3_secret_access_key: Option<String>,
+  pub maintenance_push_cap_percent: Option<u8>,
+  pub queue_quota: QuotaLimits,
+  pub total_quota: QuotaLimits,
+  pub bulkhead: BulkheadCfg,
+  pub disk_watchdog: Option<DiskWatchdogCfg>,
+  pub tombstone_gc: Option<TombstoneGcCfg>,
+  pub cluster_node_id: Option<u64>,
+  pub cluster_peers: Vec<(u64, String)>,
+  pub cluster_min_in_sync_peers: Option<usize>,
+  pub replica_of: Option<String>,
+  pub replica_api_key: Option<String>,
+  pub replica_sync_interval: Duration,
+  pub queue_templates: BTreeMap<String, QueueTemplate>,
+  pub tenants: BTreeMap<String, TenantCfg>,
 }
 
 fn env_parsed<T: FromStr>(name: &str) -> Option<T> {
@@ -127,6 +802,119 @@ fn env_str(name: &str) -> Option<String> {
   var(name).ok()
 }
 
+// Parses comma-separated `name=permissions:prefix` entries.
+fn parse_identities(raw: &str) -> Vec<(String, Identity)> {
+  raw
+    .split(',')
+    .filter(|k| !k.is_empty())
+    .map(|k| {
+      let (name, rest) = k.split_once('=').expect("invalid identity");
+      let (permissions, prefix) = rest.split_once(':').expect("invalid identity");
+      let permissions = permissions
+        .split('+')
+        .map(|p| p.parse().expect("invalid permission"))
+        .collect();
+      (name.to_string(), Identity {
+        prefix: prefix.to_string(),
+        permissions,
+        tenant: None,
+      })
+    })
+    .collect()
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+  /// Checks that a snapshot created by `POST /admin/snapshot` can be restored and has no corrupt messages, without starting the server. Exits with a non-zero status if it can't be restored.
+  BackupVerify {
+    /// Path to the snapshot.
+    path: PathBuf,
+
+    /// Optional comma-separated paths to incremental snapshots to apply in order, as for `restore_increments`.
+    #[arg(long, value_delimiter = ',')]
+    increments: Vec<PathBuf>,
+
+    /// Directory to restore the snapshot into, which must not exist and is removed afterwards. It needs as much space as the snapshot. Defaults to a directory in the system's temporary directory.
+    #[arg(long)]
+    scratch_dir: Option<PathBuf>,
+  },
+
+  /// Downloads an archive of every message in every queue on a running server using `GET /admin/export`, for migrating them to another server with `import`. Exits with a non-zero status if the archive is incomplete.
+  Export {
+    /// URL of the server, e.g. `http://127.0.0.1:3333`.
+    server: String,
+
+    /// Path of the archive file to create.
+    path: PathBuf,
+
+    /// The global API key, if the server has auth enabled.
+    #[arg(long)]
+    api_key: Option<String>,
+  },
+
+  /// Imports an archive created by `export` into a running server using `POST /admin/import`, creating queues that don't exist. Messages that have already been imported are skipped, so an interrupted import can be resumed by running it again. Exits with a non-zero status if it fails.
+  Import {
+    /// URL of the server, e.g. `http://127.0.0.1:3333`.
+    server: String,
+
+    /// Path of the archive file.
+    path: PathBuf,
+
+    /// The global API key, if the server has auth enabled.
+    #[arg(long)]
+    api_key: Option<String>,
+  },
+}
+
+fn reloadable_overrides(cli: &Cli) -> ReloadableCfgFile {
+  ReloadableCfgFile {
+    max_message_size: cli
+      .max_message_size
+      .or(env_parsed("QUEUED_MAX_MESSAGE_SIZE")),
+    min_visibility_timeout_secs: cli
+      .min_visibility_timeout_secs
+      .or(env_parsed("QUEUED_MIN_VISIBILITY_TIMEOUT_SECS")),
+    max_visibility_timeout_secs: cli
+      .max_visibility_timeout_secs
+      .or(env_parsed("QUEUED_MAX_VISIBILITY_TIMEOUT_SECS")),
+    release_pacing_max_per_sec: cli
+      .release_pacing_max_per_sec
+      .or(env_parsed("QUEUED_RELEASE_PACING_MAX_PER_SEC")),
+    redelivery_backoff_base_secs: cli
+      .redelivery_backoff_base_secs
+      .or(env_parsed("QUEUED_REDELIVERY_BACKOFF_BASE_SECS")),
+    redelivery_backoff_multiplier: cli
+      .redelivery_backoff_multiplier
+      .or(env_parsed("QUEUED_REDELIVERY_BACKOFF_MULTIPLIER")),
+    redelivery_backoff_max_secs: cli
+      .redelivery_backoff_max_secs
+      .or(env_parsed("QUEUED_REDELIVERY_BACKOFF_MAX_SECS")),
+    rate_limit_push_requests_per_sec: cli
+      .rate_limit_push_requests_per_sec
+      .or(env_parsed("QUEUED_RATE_LIMIT_PUSH_REQUESTS_PER_SEC")),
+    rate_limit_push_messages_per_sec: cli
+      .rate_limit_push_messages_per_sec
+      .or(env_parsed("QUEUED_RATE_LIMIT_PUSH_MESSAGES_PER_SEC")),
+    rate_limit_polls_per_sec: cli
+      .rate_limit_polls_per_sec
+      .or(env_parsed("QUEUED_RATE_LIMIT_POLLS_PER_SEC")),
+    readyz_max_sync_backlog: cli
+      .readyz_max_sync_backlog
+      .or(env_parsed("QUEUED_READYZ_MAX_SYNC_BACKLOG")),
+    readyz_min_free_disk_percent: cli
+      .readyz_min_free_disk_percent
+      .or(env_parsed("QUEUED_READYZ_MIN_FREE_DISK_PERCENT")),
+    readyz_timeout_ms: cli
+      .readyz_timeout_ms
+      .or(env_parsed("QUEUED_READYZ_TIMEOUT_MS")),
+  }
+}
+
+/// The subcommand to run instead of the server, if one was provided.
+pub(crate) fn load_command() -> Option<Command> {
+  Cli::parse().command
+}
+
 // Precedence:
 // - Lowest: config file.
 // - Then: env vars.
@@ -134,24 +922,57 @@ fn env_str(name: &str) -> Option<String> {
 pub(crate) fn load_cfg() -> Cfg {
   let cli = Cli::parse();
 
-  let f = cli
-    .config
-    .or_else(|| env_path("QUEUED_CONFIG"))
+  let config_file = cli.config.clone().or_else(|| env_path("QUEUED_CONFIG"));
+  let config_file_table = config_file
+    .as_ref()
     .map(|cfg_path| {
       info!(path = format!("{:?}", cfg_path), "loading config file");
-      let cfg = std::fs::read_to_string(&cfg_path).expect("failed to read config file");
-      let cfg: CfgFile = toml::from_str(&cfg).expect("failed to parse config file");
-      cfg
+      let cfg = std::fs::read_to_string(cfg_path).expect("failed to read config file");
+      toml::from_str::<toml::Table>(&cfg).expect("failed to parse config file")
     })
     .unwrap_or_default();
+  let f: CfgFile = toml::Value::Table(config_file_table.clone())
+    .try_into()
+    .expect("failed to parse config file");
+  let reloadable_overrides = reloadable_overrides(&cli);
+  let reloadable = reloadable_overrides
+    .clone()
+    .or(f.reloadable.clone())
+    .resolve()
+    .unwrap_or_else(|err| panic!("{err}"));
 
   Cfg {
+    config_file,
+    config_file_table,
+    watch_config: cli
+      .watch_config
+      .or(env_parsed("QUEUED_WATCH_CONFIG"))
+      .or(f.watch_config)
+      .unwrap_or(false),
+    reloadable,
+    reloadable_overrides,
+
     data_dir: cli
       .data_dir
       .or(env_path("QUEUED_DATA_DIR"))
       .or(f.data_dir)
       .expect("no data dir provided"),
 
+    restore_from: cli
+      .restore_from
+      .or(env_path("QUEUED_RESTORE_FROM"))
+      .or(f.restore_from),
+
+    restore_increments: cli
+      .restore_increments
+      .or(env_str("QUEUED_RESTORE_INCREMENTS"))
+      .or(f.restore_increments)
+      .unwrap_or_default()
+      .split(',')
+      .filter(|p| !p.is_empty())
+      .map(PathBuf::from)
+      .collect(),
+
     global_api_key: cli
       .global_api_key
       .or(env_str("QUEUED_GLOBAL_API_KEY"))
@@ -163,6 +984,53 @@ pub(crate) fn load_cfg() -> Cfg {
       .or(f.enable_auth)
       .unwrap_or(false),
 
+    api_keys: parse_identities(
+      &cli
+        .api_keys
+        .or(env_str("QUEUED_API_KEYS"))
+        .or(f.api_keys)
+        .unwrap_or_default(),
+    )
+    .into_iter()
+    .chain(f.tenants.iter().flat_map(|(tenant, t)| {
+      parse_identities(t.api_keys.as_deref().unwrap_or_default())
+        .into_iter()
+        .map(|(k, i)| {
+          (
+            k,
+            Identity::for_tenant(tenant.clone(), &i.prefix, i.permissions),
+          )
+        })
+    }))
+    .collect(),
+
+    jwt_secret: cli
+      .jwt_secret
+      .or(env_str("QUEUED_JWT_SECRET"))
+      .or(f.jwt_secret),
+
+    enable_sqs_api: cli
+      .enable_sqs_api
+      .or(env_parsed("QUEUED_ENABLE_SQS_API"))
+      .or(f.enable_sqs_api)
+      .unwrap_or(false),
+
+    sqs_access_keys: cli
+      .sqs_access_keys
+      .or(env_str("QUEUED_SQS_ACCESS_KEYS"))
+      .or(f.sqs_access_keys)
+      .unwrap_or_default()
+      .split(',')
+      .filter_map(|e| e.split_once('='))
+      .map(|(id, key)| (id.to_string(), key.to_string()))
+      .collect(),
+
+    enable_generator: cli
+      .enable_generator
+      .or(env_parsed("QUEUED_ENABLE_GENERATOR"))
+      .or(f.enable_generator)
+      .unwrap_or(false),
+
     interface: cli
       .interface
       .or(env_parsed("QUEUED_INTERFACE"))
@@ -181,6 +1049,14 @@ pub(crate) fn load_cfg() -> Cfg {
 
     ssl_ca: cli.ssl_ca.or(env_path("QUEUED_SSL_CA")).or(f.ssl_ca),
 
+    ssl_client_identities: parse_identities(
+      &cli
+        .ssl_client_identities
+        .or(env_str("QUEUED_SSL_CLIENT_IDENTITIES"))
+        .or(f.ssl_client_identities)
+        .unwrap_or_default(),
+    ),
+
     unix_socket: cli
       .unix_socket
       .or(env_path("QUEUED_UNIX_SOCKET"))
@@ -210,6 +1086,93 @@ pub(crate) fn load_cfg() -> Cfg {
       .map(|(k, v)| (k.to_string(), v.to_string()))
       .collect::<Vec<_>>(),
 
+    mirror_url: cli
+      .mirror_url
+      .or(env_str("QUEUED_MIRROR_URL"))
+      .or(f.mirror_url),
+
+    mirror_api_key: cli
+      .mirror_api_key
+      .or(env_str("QUEUED_MIRROR_API_KEY"))
+      .or(f.mirror_api_key),
+
+    mirror_percent: cli
+      .mirror_percent
+      .or(env_parsed("QUEUED_MIRROR_PERCENT"))
+      .or(f.mirror_percent)
+      .unwrap_or(100.0),
+
+    bridge_url: cli
+      .bridge_url
+      .or(env_str("QUEUED_BRIDGE_URL"))
+      .or(f.bridge_url),
+
+    bridge_api_key: cli
+      .bridge_api_key
+      .or(env_str("QUEUED_BRIDGE_API_KEY"))
+      .or(f.bridge_api_key),
+
+    bridge_outbox_dir: cli
+      .bridge_outbox_dir
+      .or(env_path("QUEUED_BRIDGE_OUTBOX_DIR"))
+      .or(f.bridge_outbox_dir),
+
+    otlp_endpoint: cli
+      .otlp_endpoint
+      .or(env_str("QUEUED_OTLP_ENDPOINT"))
+      .or(f.otlp_endpoint),
+
+    #[cfg(feature = "fault_injection")]
+    faults: env_str("QUEUED_FAULTS")
+      .map(|raw| parse_faults(&raw).unwrap_or_else(|err| panic!("{err}")))
+      .unwrap_or_default(),
+
+    format_compat: cli
+      .format_compat
+      .or(env_parsed("QUEUED_FORMAT_COMPAT"))
+      .or(f.format_compat),
+
+    id_strategy: match cli
+      .id_strategy
+      .or(env_str("QUEUED_ID_STRATEGY"))
+      .or(f.id_strategy)
+      .as_deref()
+    {
+      None | Some("sequential") => IdStrategy::Sequential,
+      Some("snowflake") => IdStrategy::Snowflake {
+        node_id: cli
+          .snowflake_node_id
+          .or(env_parsed("QUEUED_SNOWFLAKE_NODE_ID"))
+          .or(f.snowflake_node_id)
+          .unwrap_or(0),
+      },
+      Some("random") => IdStrategy::Random,
+      Some(raw) => panic!("invalid ID strategy {raw:?}"),
+    },
+
+    poll_order: match cli
+      .poll_order
+      .or(env_str("QUEUED_POLL_ORDER"))
+      .or(f.poll_order)
+      .as_deref()
+    {
+      None | Some("visible_time") => PollOrder::VisibleTime,
+      Some("fifo") => PollOrder::Fifo,
+      Some(raw) => panic!("invalid poll order {raw:?}"),
+    },
+
+    storage: match cli
+      .storage
+      .or(env_str("QUEUED_STORAGE"))
+      .or(f.storage)
+      .as_deref()
+    {
+      None | Some("rocksdb") => StorageBackend::RocksDb,
+      #[cfg(feature = "redb")]
+      Some("redb") => StorageBackend::Redb,
+      Some(raw) => panic!("invalid storage backend {raw:?}"),
+    },
+
     batch_sync_delay: Duration::from_micros(
       cli
         .batch_sync_delay_us
@@ -217,5 +1180,360 @@ pub(crate) fn load_cfg() -> Cfg {
         .or(f.batch_sync_delay_us)
         .unwrap_or(10000),
     ),
+
+    storage_breaker_threshold: cli
+      .storage_breaker_threshold
+      .or(env_parsed("QUEUED_STORAGE_BREAKER_THRESHOLD"))
+      .or(f.storage_breaker_threshold)
+      .unwrap_or(5),
+
+    storage_breaker_base_backoff: Duration::from_millis(
+      cli
+        .storage_breaker_base_backoff_ms
+        .or(env_parsed("QUEUED_STORAGE_BREAKER_BASE_BACKOFF_MS"))
+        .or(f.storage_breaker_base_backoff_ms)
+        .unwrap_or(250),
+    ),
+
+    storage_breaker_max_backoff: Duration::from_millis(
+      cli
+        .storage_breaker_max_backoff_ms
+        .or(env_parsed("QUEUED_STORAGE_BREAKER_MAX_BACKOFF_MS"))
+        .or(f.storage_breaker_max_backoff_ms)
+        .unwrap_or(60000),
+    ),
+
+    recommended_visibility_timeout_factor: cli
+      .recommended_visibility_timeout_factor
+      .or(env_parsed("QUEUED_RECOMMENDED_VISIBILITY_TIMEOUT_FACTOR"))
+      .or(f.recommended_visibility_timeout_factor)
+      .unwrap_or(2.0),
+
+    inline_max_contents_len: cli
+      .inline_max_contents_len
+      .or(env_parsed("QUEUED_INLINE_MAX_CONTENTS_LEN"))
+      .or(f.inline_max_contents_len)
+      .unwrap_or(1024),
+
+    index_snapshot_interval: cli
+      .index_snapshot_interval_secs
+      .or(env_parsed("QUEUED_INDEX_SNAPSHOT_INTERVAL_SECS"))
+      .or(f.index_snapshot_interval_secs)
+      .map(Duration::from_secs),
+
+    snapshot_wal_retention: cli
+      .snapshot_wal_retention_secs
+      .or(env_parsed("QUEUED_SNAPSHOT_WAL_RETENTION_SECS"))
+      .or(f.snapshot_wal_retention_secs)
+      .map(Duration::from_secs),
+
+    audit_log: cli
+      .audit_log
+      .or(env_parsed("QUEUED_AUDIT_LOG"))
+      .or(f.audit_log)
+      .unwrap_or(false),
+
+    audit_log_retention: cli
+      .audit_log_retention_secs
+      .or(env_parsed("QUEUED_AUDIT_LOG_RETENTION_SECS"))
+      .or(f.audit_log_retention_secs)
+      .map(Duration::from_secs),
+
+    index_shards: cli
+      .index_shards
+      .or(env_parsed("QUEUED_INDEX_SHARDS"))
+      .or(f.index_shards)
+      .unwrap_or(1),
+
+    verify_index: cli
+      .verify_index
+      .or(env_str("QUEUED_VERIFY_INDEX"))
+      .or(f.verify_index)
+      .map(|raw| match raw.as_str() {
+        "refuse" => IndexMismatchAction::Refuse,
+        "suspend" => IndexMismatchAction::Suspend,
+        _ => panic!("invalid index verification action {raw:?}"),
+      }),
+
+    zstd_compression: {
+      let dictionary_bytes = cli
+        .zstd_dictionary_bytes
+        .or(env_parsed("QUEUED_ZSTD_DICTIONARY_BYTES"))
+        .or(f.zstd_dictionary_bytes);
+      let level = cli
+        .zstd_level
+        .or(env_parsed("QUEUED_ZSTD_LEVEL"))
+        .or(f.zstd_level);
+      assert!(
+        level.is_some() || dictionary_bytes.is_none(),
+        "zstd dictionaries require a zstd level"
+      );
+      level.map(|level| {
+        assert!((1..=22).contains(&level), "invalid zstd level {level}");
+        ZstdCompression {
+          level,
+          dictionary_bytes: dictionary_bytes.unwrap_or(0),
+        }
+      })
+    },
+
+    rocksdb: RocksDbTuning {
+      block_cache_bytes: cli
+        .rocksdb_block_cache_bytes
+        .or(env_parsed("QUEUED_ROCKSDB_BLOCK_CACHE_BYTES"))
+        .or(f.rocksdb_block_cache_bytes),
+      write_buffer_bytes: cli
+        .rocksdb_write_buffer_bytes
+        .or(env_parsed("QUEUED_ROCKSDB_WRITE_BUFFER_BYTES"))
+        .or(f.rocksdb_write_buffer_bytes),
+      compaction_style: cli
+        .rocksdb_compaction_style
+        .or(env_str("QUEUED_ROCKSDB_COMPACTION_STYLE"))
+        .or(f.rocksdb_compaction_style)
+        .map(|raw| match raw.as_str() {
+          "level" => CompactionStyle::Level,
+          "universal" => CompactionStyle::Universal,
+          "fifo" => CompactionStyle::Fifo,
+          _ => panic!("invalid RocksDB compaction style {raw:?}"),
+        }),
+      max_total_wal_bytes: cli
+        .rocksdb_max_total_wal_bytes
+        .or(env_parsed("QUEUED_ROCKSDB_MAX_TOTAL_WAL_BYTES"))
+        .or(f.rocksdb_max_total_wal_bytes),
+      wal_bytes_per_sync: cli
+        .rocksdb_wal_bytes_per_sync
+        .or(env_parsed("QUEUED_ROCKSDB_WAL_BYTES_PER_SYNC"))
+        .or(f.rocksdb_wal_bytes_per_sync),
+      compression_per_level: cli
+        .rocksdb_compression_per_level
+        .or(env_str("QUEUED_ROCKSDB_COMPRESSION_PER_LEVEL"))
+        .or(f.rocksdb_compression_per_level)
+        .map(|raw| {
+          raw
+            .split(',')
+            .map(|c| match c.trim() {
+              "none" => FileCompression::None,
+              "snappy" => FileCompression::Snappy,
+              "lz4" => FileCompression::Lz4,
+              "zstd" => FileCompression::Zstd,
+              _ => panic!("invalid RocksDB compression {c:?}"),
+            })
+            .collect()
+        }),
+      statistics: cli
+        .rocksdb_statistics
+        .or(env_parsed("QUEUED_ROCKSDB_STATISTICS"))
+        .or(f.rocksdb_statistics)
+        .unwrap_or(false),
+    },
+
+    contents_compression: cli
+      .contents_compression
+      .or(env_str("QUEUED_CONTENTS_COMPRESSION"))
+      .or(f.contents_compression)
+      .map(|raw| ContentsCompression {
+        codec: match raw.as_str() {
+          "zstd" => ContentsCodec::Zstd,
+          "lz4" => ContentsCodec::Lz4,
+          _ => panic!("invalid contents compression codec {raw:?}"),
+        },
+        min_len: cli
+          .contents_compression_min_len
+          .or(env_parsed("QUEUED_CONTENTS_COMPRESSION_MIN_LEN"))
+          .or(f.contents_compression_min_len)
+          .unwrap_or(1024),
+        dictionary_bytes: cli
+          .contents_compression_dictionary_bytes
+          .or(env_parsed("QUEUED_CONTENTS_COMPRESSION_DICTIONARY_BYTES"))
+          .or(f.contents_compression_dictionary_bytes)
+          .unwrap_or(0),
+      }),
+
+    offload_min_contents_len: cli
+      .offload_min_contents_len
+      .or(env_parsed("QUEUED_OFFLOAD_MIN_CONTENTS_LEN"))
+      .or(f.offload_min_contents_len)
+      .unwrap_or(1024 * 1024),
+
+    offload_s3_bucket: cli
+      .offload_s3_bucket
+      .or(env_str("QUEUED_OFFLOAD_S3_BUCKET"))
+      .or(f.offload_s3_bucket),
+
+    offload_s3_endpoint: cli
+      .offload_s3_endpoint
+      .or(env_str("QUEUED_OFFLOAD_S3_ENDPOINT"))
+      .or(f.offload_s3_endpoint),
+
+    offload_s3_region: cli
+      .offload_s3_region
+      .or(env_str("QUEUED_OFFLOAD_S3_REGION"))
+      .or(f.offload_s3_region)
+      .unwrap_or("us-east-1".to_string()),
+
+    offload_s3_access_key_id: cli
+      .offload_s3_access_key_id
+      .or(env_str("QUEUED_OFFLOAD_S3_ACCESS_KEY_ID"))
+      .or(f.offload_s3_access_key_id)
+      .or(env_str("AWS_ACCESS_KEY_ID")),
+
+    offload_s3_secret_access_key: cli
+      .offload_s3_secret_access_key
+      .or(env_str("QUEUED_OFFLOAD_S3_SECRET_ACCESS_KEY"))
+      .or(f.offload_s3_secret_access_key)
+      .or(env_str("AWS_SECRET_ACCESS_KEY")),
+
+    maintenance_push_cap_percent: cli
+      .maintenance_push_cap_percent
+      .or(env_parsed("QUEUED_MAINTENANCE_PUSH_CAP_PERCENT"))
+      .or(f.maintenance_push_cap_percent),
+
+    queue_quota: QuotaLimits {
+      max_messages: cli
+        .max_queue_messages
+        .or(env_parsed("QUEUED_MAX_QUEUE_MESSAGES"))
+        .or(f.max_queue_messages),
+      max_data_bytes: cli
+        .max_queue_data_bytes
+        .or(env_parsed("QUEUED_MAX_QUEUE_DATA_BYTES"))
+        .or(f.max_queue_data_bytes),
+      max_disk_bytes: cli
+        .max_queue_disk_bytes
+        .or(env_parsed("QUEUED_MAX_QUEUE_DISK_BYTES"))
+        .or(f.max_queue_disk_bytes),
+    },
+
+    total_quota: QuotaLimits {
+      max_messages: cli
+        .max_total_messages
+        .or(env_parsed("QUEUED_MAX_TOTAL_MESSAGES"))
+        .or(f.max_total_messages),
+      max_data_bytes: cli
+        .max_total_data_bytes
+        .or(env_parsed("QUEUED_MAX_TOTAL_DATA_BYTES"))
+        .or(f.max_total_data_bytes),
+      max_disk_bytes: cli
+        .max_total_disk_bytes
+        .or(env_parsed("QUEUED_MAX_TOTAL_DISK_BYTES"))
+        .or(f.max_total_disk_bytes),
+    },
+
+    bulkhead: BulkheadCfg {
+      max_concurrent_admin_requests: cli
+        .max_concurrent_admin_requests
+        .or(env_parsed("QUEUED_MAX_CONCURRENT_ADMIN_REQUESTS"))
+        .or(f.max_concurrent_admin_requests),
+      max_concurrent_data_requests: cli
+        .max_concurrent_data_requests
+        .or(env_parsed("QUEUED_MAX_CONCURRENT_DATA_REQUESTS"))
+        .or(f.max_concurrent_data_requests),
+      max_concurrent_export_requests: cli
+        .max_concurrent_export_requests
+        .or(env_parsed("QUEUED_MAX_CONCURRENT_EXPORT_REQUESTS"))
+        .or(f.max_concurrent_export_requests),
+    },
+
+    disk_watchdog: cli
+      .disk_min_free_percent
+      .or(env_parsed("QUEUED_DISK_MIN_FREE_PERCENT"))
+      .or(f.disk_min_free_percent)
+      .map(|min_free_percent| DiskWatchdogCfg {
+        min_free_percent,
+        resume_free_percent: cli
+          .disk_resume_free_percent
+          .or(env_parsed("QUEUED_DISK_RESUME_FREE_PERCENT"))
+          .or(f.disk_resume_free_percent)
+          .unwrap_or((min_free_percent + 5.0).min(100.0)),
+        interval: Duration::from_millis(
+          cli
+            .disk_check_interval_ms
+            .or(env_parsed("QUEUED_DISK_CHECK_INTERVAL_MS"))
+            .or(f.disk_check_interval_ms)
+            .unwrap_or(5000),
+        ),
+      }),
+
+    tombstone_gc: {
+      let interval = cli
+        .tombstone_gc_interval_secs
+        .or(env_parsed("QUEUED_TOMBSTONE_GC_INTERVAL_SECS"))
+        .or(f.tombstone_gc_interval_secs)
+        .map(Duration::from_secs);
+      let max_percent = cli
+        .tombstone_gc_max_percent
+        .or(env_parsed("QUEUED_TOMBSTONE_GC_MAX_PERCENT"))
+        .or(f.tombstone_gc_max_percent);
+      (interval.is_some() || max_percent.is_some()).then(|| TombstoneGcCfg {
+        interval,
+        max_percent,
+        min_tombstones: cli
+          .tombstone_gc_min_tombstones
+          .or(env_parsed("QUEUED_TOMBSTONE_GC_MIN_TOMBSTONES"))
+          .or(f.tombstone_gc_min_tombstones)
+          .unwrap_or(100_000),
+      })
+    },
+
+    cluster_node_id: cli
+      .cluster_node_id
+      .or(env_parsed("QUEUED_CLUSTER_NODE_ID"))
+      .or(f.cluster_node_id),
+
+    cluster_peers: cli
+      .cluster_peers
+      .or(env_str("QUEUED_CLUSTER_PEERS"))
+      .or(f.cluster_peers)
+      .unwrap_or_default()
+      .split(',')
+      .filter_map(|p| p.split_once('='))
+      .map(|(id, url)| {
+        let id = id.parse().expect("invalid cluster peer ID");
+        (id, url.trim_end_matches('/').to_string())
+      })
+      .collect::<Vec<_>>(),
+
+    cluster_min_in_sync_peers: cli
+      .cluster_min_in_sync_peers
+      .or(env_parsed("QUEUED_CLUSTER_MIN_IN_SYNC_PEERS"))
+      .or(f.cluster_min_in_sync_peers),
+
+    replica_of: cli
+      .replica_of
+      .or(env_str("QUEUED_REPLICA_OF"))
+      .or(f.replica_of)
+      .map(|url| url.trim_end_matches('/').to_string()),
+
+    replica_api_key: cli
+      .replica_api_key
+      .or(env_str("QUEUED_REPLICA_API_KEY"))
+      .or(f.replica_api_key),
+
+    replica_sync_interval: Duration::from_millis(
+      cli
+        .replica_sync_interval_ms
+        .or(env_parsed("QUEUED_REPLICA_SYNC_INTERVAL_MS"))
+        .or(f.replica_sync_interval_ms)
+        .unwrap_or(1000),
+    ),
+
+    queue_templates: {
+      for (name, t) in f.templates.iter() {
+        assert!(
+          t.throttle.is_none_or(|t| t.is_valid()),
+          "invalid throttle in queue template {name:?}"
+        );
+      }
+      f.templates
+    },
+
+    tenants: {
+      for name in f.tenants.keys() {
+        assert!(
+          queue_name_is_valid(name) && !name.contains('/'),
+          "invalid tenant name {name:?}"
+        );
+      }
+      f.tenants
+    },
   }
 }
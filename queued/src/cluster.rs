@@ -0,0 +1,982 @@
+use crate::endpoint::HttpCtx;
+use axum::body::Bytes;
+use futures::future::join_all;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use libqueued::replication::Replicator;
+use parking_lot::Mutex;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::NON_ALPHANUMERIC;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cmp::max;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::spawn;
+use tokio::sync::watch;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio::time::timeout;
+use tracing::info;
+use tracing::warn;
+
+pub(crate) const CLUSTER_STALE_MARKER_FILE: &str = ".queued_cluster_stale";
+const CLUSTER_POSITION_FILE: &str = ".queued_cluster_position";
+/// Sent by the leader with every write to its peers, so that a peer that follows a different leader rejects the write instead of applying it.
+pub(crate) const CLUSTER_LEADER_ID_HEADER: &str = "x-queued-leader-id";
+/// The leader's term and the write's sequence number within it, so that peers apply writes exactly once and in order.
+pub(crate) const CLUSTER_TERM_HEADER: &str = "x-queued-term";
+pub(crate) const CLUSTER_SEQ_HEADER: &str = "x-queued-seq";
+/// The position the leader started its term at, which a peer must be at to apply the term's first write.
+pub(crate) const CLUSTER_TERM_START_HEADER: &str = "x-queued-term-start";
+
+const NO_LEADER: u64 = u64::MAX;
+// Terms have the node's rank among all node IDs in their low bits, so that two nodes that both start a term at once, e.g. during a failover, never start the same one. This limits clusters to this many nodes.
+const TERM_NODE_SLOTS: u64 = 1024;
+// How long a write waits for enough peers to apply it before failing. The write is still sent to peers afterwards.
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5);
+// Writes are kept until all peers have them, so that peers that are briefly unavailable can catch up, but only up to this many bytes. A peer that falls further behind can never catch up and must be reseeded.
+const REPLICATION_LOG_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+/// A point in the history of writes: a leader's term, and the sequence number of a write within it. Later positions compare greater.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug, Serialize, Deserialize)]
+pub(crate) struct ClusterPosition {
+  pub term: u64,
+  pub seq: u64,
+}
+
+impl ClusterPosition {
+  pub fn to_header(self) -> String {
+    format!("{}.{}", self.term, self.seq)
+  }
+
+  pub fn from_header(raw: &str) -> Option<Self> {
+    let (term, seq) = raw.split_once('.')?;
+    Some(Self {
+      term: term.parse().ok()?,
+      seq: seq.parse().ok()?,
+    })
+  }
+}
+
+/// What a peer should do with a write from the leader.
+pub(crate) enum ApplyDecision {
+  Apply,
+  /// The write has already been applied.
+  Skip,
+  /// Earlier writes are missing; the leader should resend from this sequence number.
+  Resend(u64),
+  /// The peer's data differs from the leader's at the start of its term, so it can't follow it.
+  Diverged,
+}
+
+struct LogEntry {
+  seq: u64,
+  method: reqwest::Method,
+  path: String,
+  body: Bytes,
+}
+
+struct ReplicationLog {
+  // Zero if we aren't the leader.
+  term: u64,
+  // Where our data was when the term started.
+  start: ClusterPosition,
+  // Writes that not all peers have applied yet, oldest first.
+  entries: VecDeque<Arc<LogEntry>>,
+  bytes: usize,
+  next_seq: u64,
+}
+
+impl ReplicationLog {
+  fn first_seq(&self) -> u64 {
+    self.entries.front().map_or(self.next_seq, |e| e.seq)
+  }
+
+  fn pop_front(&mut self) {
+    if let Some(e) = self.entries.pop_front() {
+      self.bytes -= e.body.len();
+    };
+  }
+}
+
+// Where our data is in the history of writes, persisted in the data dir so that it survives restarts.
+struct PositionFile {
+  path: PathBuf,
+  // The latest position written to the file. Positions only move forward, so a slower write of an older position is skipped instead of replacing a newer one.
+  written: Mutex<ClusterPosition>,
+}
+
+impl PositionFile {
+  fn load(data_dir: &std::path::Path) -> (Self, ClusterPosition) {
+    let path = data_dir.join(CLUSTER_POSITION_FILE);
+    let position = match std::fs::read(&path) {
+      Ok(raw) => rmp_serde::from_slice(&raw).expect("corrupt cluster position file"),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => ClusterPosition::default(),
+      Err(e) => panic!("failed to read cluster position file: {e}"),
+    };
+    let file = Self {
+      path,
+      written: Mutex::new(position),
+    };
+    (file, position)
+  }
+
+  // Durably replaces the file, so that after a crash it has either the old or the new position. This blocks, so it must be called from a blocking thread.
+  fn write(&self, position: ClusterPosition) -> Result<(), String> {
+    let mut written = self.written.lock();
+    if position <= *written {
+      return Ok(());
+    };
+    write_file_durably(&self.path, &rmp_serde::to_vec(&position).unwrap())
+      .map_err(|err| format!("failed to write cluster position file: {err}"))?;
+    *written = position;
+    Ok(())
+  }
+}
+
+fn write_file_durably(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+  let tmp_path = path.with_extension("tmp");
+  let mut f = std::fs::File::create(&tmp_path)?;
+  f.write_all(data)?;
+  f.sync_all()?;
+  std::fs::rename(&tmp_path, path)?;
+  std::fs::File::open(path.parent().unwrap())?.sync_all()
+}
+
+pub(crate) struct ClusterPeer {
+  pub id: u64,
+  pub url: String,
+  reachable: AtomicBool,
+  // As reported by the peer itself.
+  stale: AtomicBool,
+  position: Mutex<ClusterPosition>,
+  // Only meaningful while we are the leader: the sequence number of the latest write in our term that the peer has applied.
+  acked: AtomicU64,
+  // Only meaningful while we are the leader: whether the peer is missing writes that we no longer have, so it can never catch up.
+  lost: AtomicBool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ClusterStatusPeer {
+  pub id: u64,
+  pub url: String,
+  pub reachable: bool,
+  pub stale: bool,
+  pub in_sync: bool,
+  #[serde(default)]
+  pub lost: bool,
+  // The sequence number of the latest write in the leader's term that the peer has applied.
+  #[serde(default)]
+  pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ClusterStatus {
+  pub node_id: u64,
+  pub leader_id: Option<u64>,
+  pub stale: bool,
+  // Writes that failed as a peer followed a different leader.
+  #[serde(default)]
+  pub fenced_writes: u64,
+  // The latest write this node has, either as the leader or from one.
+  #[serde(default)]
+  pub position: ClusterPosition,
+  pub peers: Vec<ClusterStatusPeer>,
+}
+
+// This is a leader-follower replication scheme, not a consensus protocol, as there are no elections or votes. Each node decides who the leader is using heartbeats: only a node that can reach a majority of the cluster (including itself) has a leader, the current leader is kept while it's reachable, and otherwise the reachable non-stale node with the latest position wins, with the lowest ID breaking ties.
+// The leader appends every write to a log in the order it was committed locally, and a task per peer sends the log to it in order, resuming from where the peer is if it falls behind, e.g. because it was unavailable. A write is acknowledged once `min_in_sync_peers` peers (by default a majority of the cluster including the leader) have applied it. Peers apply writes exactly once and in order, and only from the position the leader started its term at. A peer whose data differs from the leader's, or that needs writes no longer in the log, marks itself as stale (persisted in its data dir) so that it can never become leader with missing data. A stale node must be reseeded from a healthy node's data before its marker file is removed.
+// Nodes can briefly disagree on who the leader is, e.g. during a failover, so more than one node can act as leader until their next heartbeats. Writes are fenced: peers reject writes from any node other than the one they consider the leader, and a leader that has a write rejected steps down until its next heartbeat. Together with quorum writes, this means only one leader can get writes acknowledged at a time, unless `min_in_sync_peers` is less than a majority.
+pub(crate) struct Cluster {
+  pub node_id: u64,
+  // Our position among all node IDs in ascending order, which is the same on every node.
+  node_rank: u64,
+  pub peers: Vec<ClusterPeer>,
+  api_key: Option<String>,
+  client: reqwest::Client,
+  fenced_writes: AtomicU64,
+  leader_id: AtomicU64,
+  min_in_sync_peers: usize,
+  log: Mutex<ReplicationLog>,
+  // Changed whenever an entry is appended or the term changes, to wake the peer senders.
+  log_changed: watch::Sender<()>,
+  // The term and the latest sequence number in it that enough peers have applied.
+  replicated: watch::Sender<ClusterPosition>,
+  // The latest write applied from a leader, or made by us while we were the leader.
+  position: Mutex<ClusterPosition>,
+  position_file: Arc<PositionFile>,
+  // Writes from the leader are applied one at a time.
+  apply_lock: tokio::sync::Mutex<()>,
+  stale: AtomicBool,
+  stale_marker_path: PathBuf,
+}
+
+fn now_ms() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_millis() as u64
+}
+
+// A term later than `latest_term` that only the node with `node_rank` can start. It's also at least the current time, so that terms keep increasing across restarts even if positions are lost.
+fn next_term(now_ms: u64, latest_term: u64, node_rank: u64) -> u64 {
+  max(now_ms, latest_term / TERM_NODE_SLOTS + 1) * TERM_NODE_SLOTS + node_rank
+}
+
+// Decides who the leader is after a heartbeat: nobody unless we can reach a majority of the cluster (counting ourselves), the current leader while it's still a candidate, and otherwise the candidate with the latest position, with the lowest ID breaking ties.
+fn choose_leader(
+  old_leader_id: u64,
+  cluster_size: usize,
+  reachable_peers: usize,
+  candidates: &[(u64, ClusterPosition)],
+) -> u64 {
+  if (reachable_peers + 1) * 2 <= cluster_size {
+    NO_LEADER
+  } else if candidates.iter().any(|&(id, _)| id == old_leader_id) {
+    old_leader_id
+  } else {
+    candidates
+      .iter()
+      .max_by_key(|&&(id, position)| (position, Reverse(id)))
+      .map_or(NO_LEADER, |&(id, _)| id)
+  }
+}
+
+impl Cluster {
+  pub fn new(
+    node_id: u64,
+    peers: Vec<(u64, String)>,
+    min_in_sync_peers: Option<usize>,
+    api_key: Option<String>,
+    data_dir: &std::path::Path,
+  ) -> Self {
+    // A majority of the whole cluster, counting ourselves.
+    let min_in_sync_peers = min_in_sync_peers.unwrap_or(peers.len().div_ceil(2));
+    assert!(
+      min_in_sync_peers <= peers.len(),
+      "the minimum in-sync peers is more than the number of peers"
+    );
+    assert!(
+      (peers.len() as u64) < TERM_NODE_SLOTS,
+      "a cluster can have at most {TERM_NODE_SLOTS} nodes"
+    );
+    let node_rank = peers.iter().filter(|(id, _)| *id < node_id).count() as u64;
+    let stale_marker_path = data_dir.join(CLUSTER_STALE_MARKER_FILE);
+    let stale = stale_marker_path.exists();
+    if stale {
+      warn!("this node is stale and will not become leader until reseeded");
+    };
+    let (position_file, position) = PositionFile::load(data_dir);
+    Self {
+      node_id,
+      node_rank,
+      peers: peers
+        .into_iter()
+        .map(|(id, url)| ClusterPeer {
+          id,
+          url,
+          reachable: AtomicBool::new(false),
+          stale: AtomicBool::new(false),
+          position: Mutex::new(ClusterPosition::default()),
+          acked: AtomicU64::new(0),
+          lost: AtomicBool::new(false),
+        })
+        .collect(),
+      api_key,
+      client: reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap(),
+      fenced_writes: AtomicU64::new(0),
+      leader_id: AtomicU64::new(NO_LEADER),
+      min_in_sync_peers,
+      log: Mutex::new(ReplicationLog {
+        term: 0,
+        start: ClusterPosition::default(),
+        entries: VecDeque::new(),
+        bytes: 0,
+        next_seq: 1,
+      }),
+      log_changed: watch::Sender::new(()),
+      replicated: watch::Sender::new(ClusterPosition::default()),
+      position: Mutex::new(position),
+      position_file: Arc::new(position_file),
+      apply_lock: tokio::sync::Mutex::new(()),
+      stale: AtomicBool::new(stale),
+      stale_marker_path,
+    }
+  }
+
+  pub fn is_leader(&self) -> bool {
+    self.leader_id.load(Ordering::Relaxed) == self.node_id
+  }
+
+  /// Whether to apply a write from the node with ID `sender`.
+  pub fn accepts_writes_from(&self, sender: u64) -> bool {
+    self.leader_id.load(Ordering::Relaxed) == sender
+  }
+
+  pub fn leader_url(&self) -> Option<String> {
+    let leader_id = self.leader_id.load(Ordering::Relaxed);
+    self
+      .peers
+      .iter()
+      .find(|p| p.id == leader_id)
+      .map(|p| p.url.clone())
+  }
+
+  fn current_position(&self) -> ClusterPosition {
+    let log = self.log.lock();
+    if log.term != 0 {
+      return ClusterPosition {
+        term: log.term,
+        seq: log.next_seq - 1,
+      };
+    };
+    *self.position.lock()
+  }
+
+  // The file is written after the data is synced, so after a crash it may be one write behind the data. That write is then resent and applied again, which leaves the data as it was.
+  async fn persist_position(&self, position: ClusterPosition) -> Result<(), String> {
+    let file = self.position_file.clone();
+    spawn_blocking(move || file.write(position)).await.unwrap()
+  }
+
+  pub fn status(&self) -> ClusterStatus {
+    let leader_id = self.leader_id.load(Ordering::Relaxed);
+    let latest_seq = {
+      let log = self.log.lock();
+      (log.term != 0).then_some(log.next_seq - 1)
+    };
+    ClusterStatus {
+      node_id: self.node_id,
+      leader_id: (leader_id != NO_LEADER).then_some(leader_id),
+      stale: self.stale.load(Ordering::Relaxed),
+      fenced_writes: self.fenced_writes.load(Ordering::Relaxed),
+      position: self.current_position(),
+      peers: self
+        .peers
+        .iter()
+        .map(|p| {
+          let acked = p.acked.load(Ordering::Relaxed);
+          let lost = p.lost.load(Ordering::Relaxed);
+          ClusterStatusPeer {
+            id: p.id,
+            url: p.url.clone(),
+            reachable: p.reachable.load(Ordering::Relaxed),
+            stale: p.stale.load(Ordering::Relaxed),
+            in_sync: latest_seq == Some(acked) && !lost,
+            lost: latest_seq.is_some() && lost,
+            seq: acked,
+          }
+        })
+        .collect(),
+    }
+  }
+
+  // We stop being a candidate for leader immediately, even if persisting the marker fails.
+  async fn mark_stale(&self) -> Result<(), String> {
+    if self.stale.swap(true, Ordering::Relaxed) {
+      return Ok(());
+    };
+    warn!("this node has missed replicated writes and is now stale");
+    let path = self.stale_marker_path.clone();
+    spawn_blocking(move || write_file_durably(&path, b""))
+      .await
+      .unwrap()
+      .map_err(|err| format!("failed to write cluster stale marker file: {err}"))
+  }
+
+  fn request(
+    &self,
+    method: reqwest::Method,
+    peer: &ClusterPeer,
+    path: &str,
+  ) -> reqwest::RequestBuilder {
+    let mut req = self.client.request(method, format!("{}{}", peer.url, path));
+    if let Some(k) = &self.api_key {
+      req = req.header("authorization", k);
+    };
+    req
+  }
+
+  async fn fetch_status(&self, peer: &ClusterPeer) -> Option<ClusterStatus> {
+    let res = self
+      .request(reqwest::Method::GET, peer, "/cluster/status")
+      .header("accept", "application/msgpack")
+      .send()
+      .await
+      .ok()?
+      .error_for_status()
+      .ok()?;
+    rmp_serde::from_slice(&res.bytes().await.ok()?).ok()
+  }
+
+  /// Appends a write to the log of writes sent to peers, and returns a future that resolves once `min_in_sync_peers` peers have applied it, or fails if that takes too long or this node stops being the leader first. The write's place in the log is decided when this is called, so writes must be broadcast in the order they were made locally. Does nothing if this node isn't the leader.
+  pub fn broadcast(
+    &self,
+    method: reqwest::Method,
+    path: String,
+    body: Vec<u8>,
+  ) -> BoxFuture<'static, Result<(), String>> {
+    let (term, seq) = {
+      let mut log = self.log.lock();
+      if log.term == 0 {
+        return async { Ok(()) }.boxed();
+      };
+      let seq = log.next_seq;
+      log.next_seq += 1;
+      log.bytes += body.len();
+      log.entries.push_back(Arc::new(LogEntry {
+        seq,
+        method,
+        path,
+        body: body.into(),
+      }));
+      // Peers that still need the dropped writes will find out that they're lost when they try to send them.
+      while log.bytes > REPLICATION_LOG_MAX_BYTES && log.entries.len() > 1 {
+        log.pop_front();
+      }
+      (log.term, seq)
+    };
+    self.log_changed.send_replace(());
+    let min = self.min_in_sync_peers;
+    let mut replicated = self.replicated.subscribe();
+    let file = self.position_file.clone();
+    async move {
+      if min > 0 {
+        match timeout(
+          REPLICATION_TIMEOUT,
+          replicated.wait_for(|r| r.term != term || r.seq >= seq),
+        )
+        .await
+        {
+          Ok(Ok(r)) if r.term == term => {}
+          Ok(_) => {
+            return Err("stopped being the leader before the write was replicated".to_string())
+          }
+          Err(_) => {
+            return Err(format!(
+              "the write wasn't replicated to {min} peers in time"
+            ))
+          }
+        };
+      };
+      // Our data already has the write, so persist that before acknowledging it. Otherwise, if we crashed, we'd restart at an older position than our data and the peers that applied the write, and find that we've diverged from the next leader.
+      spawn_blocking(move || file.write(ClusterPosition { term, seq }))
+        .await
+        .unwrap()
+    }
+    .boxed()
+  }
+
+  // Starts a new term as the leader, continuing from our current data.
+  fn start_term(&self) {
+    let start = self.current_position();
+    let latest_term = self
+      .peers
+      .iter()
+      .map(|p| p.position.lock().term)
+      .chain([start.term])
+      .max()
+      .unwrap();
+    let term = next_term(now_ms(), latest_term, self.node_rank);
+    for p in self.peers.iter() {
+      p.acked.store(0, Ordering::Relaxed);
+      p.lost.store(false, Ordering::Relaxed);
+    }
+    *self.log.lock() = ReplicationLog {
+      term,
+      start,
+      entries: VecDeque::new(),
+      bytes: 0,
+      next_seq: 1,
+    };
+    self
+      .replicated
+      .send_replace(ClusterPosition { term, seq: 0 });
+    self.log_changed.send_replace(());
+  }
+
+  // Stops sending writes and fails those still waiting for peers. Our data includes every write we made, so that's our position from now on.
+  async fn end_term(&self) {
+    let position = {
+      let mut log = self.log.lock();
+      if log.term == 0 {
+        return;
+      };
+      let position = ClusterPosition {
+        term: log.term,
+        seq: log.next_seq - 1,
+      };
+      log.term = 0;
+      log.entries.clear();
+      log.bytes = 0;
+      position
+    };
+    *self.position.lock() = position;
+    self.replicated.send_replace(ClusterPosition::default());
+    self.log_changed.send_replace(());
+    // Acknowledged writes have already been persisted, so this only covers writes that failed or were still waiting for peers. If this fails, the file is behind our data, so if the next leader starts from where our data is, we'll find that we've diverged and become stale.
+    if let Err(err) = self.persist_position(position).await {
+      warn!(err, "failed to persist cluster position");
+    };
+  }
+
+  async fn step_down(&self) {
+    self.fenced_writes.fetch_add(1, Ordering::Relaxed);
+    if self
+      .leader_id
+      .compare_exchange(
+        self.node_id,
+        NO_LEADER,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+      )
+      .is_ok()
+    {
+      warn!("a peer follows a different leader, stepping down until the next heartbeat");
+      self.end_term().await;
+    };
+  }
+
+  // Called after a peer has applied writes up to `seq` in `term`.
+  fn record_ack(&self, peer: &ClusterPeer, term: u64, seq: u64) {
+    let mut log = self.log.lock();
+    if log.term != term {
+      return;
+    };
+    peer.acked.store(seq, Ordering::Relaxed);
+    let mut acked = self
+      .peers
+      .iter()
+      .filter(|p| !p.lost.load(Ordering::Relaxed))
+      .map(|p| p.acked.load(Ordering::Relaxed))
+      .collect::<Vec<_>>();
+    acked.sort_unstable_by_key(|&s| Reverse(s));
+    // Drop writes that every peer that can still catch up has applied.
+    let all_acked = acked.last().copied().unwrap_or(log.next_seq - 1);
+    while log.first_seq() <= all_acked && !log.entries.is_empty() {
+      log.pop_front();
+    }
+    drop(log);
+    if self.min_in_sync_peers > 0 {
+      let replicated = acked.get(self.min_in_sync_peers - 1).copied().unwrap_or(0);
+      self.replicated.send_if_modified(|r| {
+        if r.term != term || r.seq >= replicated {
+          return false;
+        };
+        r.seq = replicated;
+        true
+      });
+    };
+  }
+
+  // Sends writes in the log to a peer in order, one at a time, for as long as the server runs.
+  async fn send_log_to_peer(self: Arc<Self>, peer_idx: usize) {
+    let peer = &self.peers[peer_idx];
+    let mut log_changed = self.log_changed.subscribe();
+    let mut backoff = Duration::from_millis(100);
+    loop {
+      log_changed.borrow_and_update();
+      let next = {
+        let log = self.log.lock();
+        let want = peer.acked.load(Ordering::Relaxed) + 1;
+        if log.term == 0 || peer.lost.load(Ordering::Relaxed) || want >= log.next_seq {
+          None
+        } else if want < log.first_seq() {
+          warn!(
+            peer = peer.id,
+            "peer needs writes that are no longer kept, it must be reseeded"
+          );
+          peer.lost.store(true, Ordering::Relaxed);
+          None
+        } else {
+          let e = log.entries[(want - log.first_seq()) as usize].clone();
+          Some((log.term, log.start, e))
+        }
+      };
+      let Some((term, start, e)) = next else {
+        if log_changed.changed().await.is_err() {
+          return;
+        };
+        continue;
+      };
+      let res = self
+        .request(e.method.clone(), peer, &e.path)
+        .header("content-type", "application/octet-stream")
+        .header(CLUSTER_LEADER_ID_HEADER, self.node_id.to_string())
+        .header(CLUSTER_TERM_HEADER, term.to_string())
+        .header(CLUSTER_SEQ_HEADER, e.seq.to_string())
+        .header(CLUSTER_TERM_START_HEADER, start.to_header())
+        .body(e.body.clone())
+        .send()
+        .await;
+      let status = res.as_ref().ok().map(|r| r.status());
+      match status {
+        Some(s) if s.is_success() => {
+          backoff = Duration::from_millis(100);
+          self.record_ack(peer, term, e.seq);
+          continue;
+        }
+        Some(reqwest::StatusCode::MISDIRECTED_REQUEST) => {
+          self.step_down().await;
+          continue;
+        }
+        Some(reqwest::StatusCode::CONFLICT) => {
+          // The peer is missing earlier writes, or can't follow us at all.
+          let resend = match res.unwrap().bytes().await {
+            Ok(raw) => rmp_serde::from_slice::<ReplicationConflict>(&raw)
+              .ok()
+              .map(|c| c.error_details.resend_from),
+            Err(_) => None,
+          };
+          match resend {
+            Some(Some(seq)) => {
+              let log = self.log.lock();
+              if log.term == term {
+                peer.acked.store(seq.saturating_sub(1), Ordering::Relaxed);
+              };
+              continue;
+            }
+            Some(None) => {
+              warn!(peer = peer.id, "peer's data has diverged from ours");
+              peer.lost.store(true, Ordering::Relaxed);
+              continue;
+            }
+            None => {}
+          };
+        }
+        _ => {}
+      };
+      warn!(
+        peer = peer.id,
+        path = e.path,
+        status = status.map(|s| s.as_u16()),
+        "failed to replicate to peer, retrying"
+      );
+      sleep(backoff).await;
+      backoff = (backoff * 2).min(Duration::from_secs(2));
+    }
+  }
+
+  /// Decides whether a peer should apply a write from the leader at `position` in a term that started at `start`. The returned guard must be held while applying the write, and then passed to `finish_apply`. Fails if we've diverged but couldn't persist that we're stale.
+  pub async fn begin_apply(
+    &self,
+    position: ClusterPosition,
+    start: ClusterPosition,
+  ) -> Result<(tokio::sync::MutexGuard<'_, ()>, ApplyDecision), String> {
+    let guard = self.apply_lock.lock().await;
+    let current = *self.position.lock();
+    let decision = if current.term == position.term {
+      if position.seq <= current.seq {
+        ApplyDecision::Skip
+      } else if position.seq == current.seq + 1 {
+        ApplyDecision::Apply
+      } else {
+        ApplyDecision::Resend(current.seq + 1)
+      }
+    } else if position.seq != 1 {
+      // We haven't received the start of the term yet.
+      ApplyDecision::Resend(1)
+    } else if current == start {
+      ApplyDecision::Apply
+    } else {
+      self.mark_stale().await?;
+      ApplyDecision::Diverged
+    };
+    Ok((guard, decision))
+  }
+
+  /// If this fails, the write is considered not applied, so the leader resends it.
+  pub async fn finish_apply(
+    &self,
+    _guard: tokio::sync::MutexGuard<'_, ()>,
+    position: ClusterPosition,
+  ) -> Result<(), String> {
+    self.persist_position(position).await?;
+    *self.position.lock() = position;
+    Ok(())
+  }
+
+  async fn heartbeat(&self, http_ctx: &HttpCtx) {
+    let statuses = join_all(self.peers.iter().map(|p| self.fetch_status(p))).await;
+    for (p, status) in self.peers.iter().zip(statuses) {
+      p.reachable.store(status.is_some(), Ordering::Relaxed);
+      let Some(status) = status else {
+        continue;
+      };
+      p.stale.store(status.stale, Ordering::Relaxed);
+      *p.position.lock() = status.position;
+      if status.leader_id == Some(p.id)
+        && status.peers.iter().any(|s| s.id == self.node_id && s.lost)
+      {
+        if let Err(err) = self.mark_stale().await {
+          warn!(err, "failed to mark this node as stale");
+        };
+      };
+    }
+
+    // Only a side of a partition with a majority can have a leader.
+    let reachable = self
+      .peers
+      .iter()
+      .filter(|p| p.reachable.load(Ordering::Relaxed))
+      .count();
+    let candidates = self
+      .peers
+      .iter()
+      .filter(|p| p.reachable.load(Ordering::Relaxed) && !p.stale.load(Ordering::Relaxed))
+      .map(|p| (p.id, *p.position.lock()))
+      .chain((!self.stale.load(Ordering::Relaxed)).then(|| (self.node_id, self.current_position())))
+      .collect::<Vec<_>>();
+    let old_leader_id = self.leader_id.load(Ordering::Relaxed);
+    let new_leader_id = choose_leader(old_leader_id, self.peers.len() + 1, reachable, &candidates);
+    if old_leader_id != new_leader_id {
+      info!(
+        old_leader_id,
+        new_leader_id,
+        node_id = self.node_id,
+        "cluster leader changed"
+      );
+      if old_leader_id == self.node_id {
+        self.end_term().await;
+      };
+      if new_leader_id == self.node_id {
+        // We may have received replicated batches as a follower, so our in-memory indices are out of date. This must be done before we start accepting operations. Reloading scans each queue's storage, so it's done off the runtime, and without holding the map's locks.
+        let queues = http_ctx
+          .queues
+          .iter()
+          .map(|e| e.value().clone())
+          .collect::<Vec<_>>();
+        spawn_blocking(move || {
+          for q in queues {
+            q.reload_index();
+          }
+        })
+        .await
+        .unwrap();
+        self.start_term();
+      };
+      self.leader_id.store(new_leader_id, Ordering::Relaxed);
+    };
+  }
+}
+
+// The body of a 409 response to a replicated write.
+#[derive(Deserialize)]
+struct ReplicationConflict {
+  error_details: ReplicationConflictDetails,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ReplicationConflictDetails {
+  /// The sequence number the peer needs next, or None if it can't follow the leader at all.
+  pub resend_from: Option<u64>,
+}
+
+pub(crate) struct ClusterReplicator {
+  cluster: Arc<Cluster>,
+  queue_name: String,
+}
+
+impl ClusterReplicator {
+  pub fn new(cluster: Arc<Cluster>, queue_name: &str) -> Self {
+    Self {
+      cluster,
+      queue_name: queue_name.to_string(),
+    }
+  }
+}
+
+impl Replicator for ClusterReplicator {
+  fn replicate(&self, batch: Vec<u8>) -> BoxFuture<'static, Result<(), String>> {
+    let path = format!(
+      "/cluster/replicate/{}",
+      utf8_percent_encode(&self.queue_name, NON_ALPHANUMERIC)
+    );
+    self.cluster.broadcast(reqwest::Method::POST, path, batch)
+  }
+}
+
+pub(crate) async fn start_cluster_heartbeat(cluster: Arc<Cluster>, ctx: Weak<HttpCtx>) {
+  for i in 0..cluster.peers.len() {
+    spawn(cluster.clone().send_log_to_peer(i));
+  }
+  // Determine the initial leader before serving any requests.
+  if let Some(ctx) = ctx.upgrade() {
+    cluster.heartbeat(&ctx).await;
+  };
+  spawn(async move {
+    loop {
+      sleep(Duration::from_millis(1000)).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      cluster.heartbeat(&ctx).await;
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::choose_leader;
+  use super::next_term;
+  use super::ApplyDecision;
+  use super::Cluster;
+  use super::ClusterPosition;
+  use super::PositionFile;
+  use super::CLUSTER_STALE_MARKER_FILE;
+  use super::NO_LEADER;
+  use std::path::PathBuf;
+
+  fn data_dir(name: &str) -> PathBuf {
+    let dir =
+      std::env::temp_dir().join(format!("queued-cluster-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  // A follower of node 1 in a cluster of three.
+  fn follower(dir: &std::path::Path) -> Cluster {
+    Cluster::new(
+      2,
+      vec![
+        (1, "http://node1".to_string()),
+        (3, "http://node3".to_string()),
+      ],
+      None,
+      None,
+      dir,
+    )
+  }
+
+  fn pos(term: u64, seq: u64) -> ClusterPosition {
+    ClusterPosition { term, seq }
+  }
+
+  async fn apply(c: &Cluster, position: ClusterPosition, start: ClusterPosition) -> ApplyDecision {
+    let (guard, decision) = c.begin_apply(position, start).await.unwrap();
+    if let ApplyDecision::Apply = decision {
+      c.finish_apply(guard, position).await.unwrap();
+    };
+    decision
+  }
+
+  #[test]
+  fn failover_picks_the_reachable_node_with_the_latest_position() {
+    // Node 1 was the leader but is no longer reachable.
+    let candidates = [(2, pos(5, 7)), (3, pos(5, 9))];
+    assert_eq!(choose_leader(1, 3, 1, &candidates), 3);
+    let candidates = [(3, pos(5, 9)), (2, pos(5, 9))];
+    assert_eq!(choose_leader(1, 3, 1, &candidates), 2);
+  }
+
+  #[test]
+  fn keeps_the_leader_while_it_is_reachable() {
+    let candidates = [(1, pos(5, 3)), (2, pos(5, 9))];
+    assert_eq!(choose_leader(1, 3, 2, &candidates), 1);
+  }
+
+  #[test]
+  fn no_leader_without_a_majority() {
+    assert_eq!(choose_leader(1, 3, 0, &[(2, pos(5, 9))]), NO_LEADER);
+    assert_eq!(choose_leader(1, 4, 1, &[(2, pos(5, 9))]), NO_LEADER);
+    assert_eq!(choose_leader(NO_LEADER, 4, 2, &[(2, pos(5, 9))]), 2);
+  }
+
+  #[test]
+  fn nodes_never_start_the_same_term() {
+    let latest = next_term(1000, 0, 3);
+    let a = next_term(1000, latest, 0);
+    let b = next_term(1000, latest, 1);
+    assert_ne!(a, b);
+    assert!(a > latest && b > latest);
+    // Terms from before the node's rank was part of them are also superseded.
+    assert!(next_term(0, 1_700_000_000_000, 0) > 1_700_000_000_000);
+  }
+
+  #[tokio::test]
+  async fn leader_persists_its_position_when_a_write_is_acknowledged() {
+    let dir = data_dir("leader-persists");
+    let c = Cluster::new(1, Vec::new(), None, None, &dir);
+    c.start_term();
+    let term = c.log.lock().term;
+    c.broadcast(reqwest::Method::POST, "/".to_string(), Vec::new())
+      .await
+      .unwrap();
+    c.broadcast(reqwest::Method::POST, "/".to_string(), Vec::new())
+      .await
+      .unwrap();
+    // As if we crashed now.
+    let (_, position) = PositionFile::load(&dir);
+    assert_eq!(position, pos(term, 2));
+  }
+
+  #[tokio::test]
+  async fn rejoining_node_continues_from_where_it_left_off() {
+    let dir = data_dir("rejoin");
+    let c = follower(&dir);
+    let start = ClusterPosition::default();
+    assert!(matches!(
+      apply(&c, pos(7, 1), start).await,
+      ApplyDecision::Apply
+    ));
+    assert!(matches!(
+      apply(&c, pos(7, 3), start).await,
+      ApplyDecision::Resend(2)
+    ));
+    assert!(matches!(
+      apply(&c, pos(7, 2), start).await,
+      ApplyDecision::Apply
+    ));
+    drop(c);
+
+    // Restarted after missing a write.
+    let c = follower(&dir);
+    assert!(matches!(
+      apply(&c, pos(7, 2), start).await,
+      ApplyDecision::Skip
+    ));
+    assert!(matches!(
+      apply(&c, pos(7, 3), start).await,
+      ApplyDecision::Apply
+    ));
+    // A new leader whose term started where we are.
+    assert!(matches!(
+      apply(&c, pos(9, 1), pos(7, 3)).await,
+      ApplyDecision::Apply
+    ));
+    assert!(!c.status().stale);
+  }
+
+  #[tokio::test]
+  async fn node_with_writes_the_new_leader_lacks_becomes_stale() {
+    let dir = data_dir("diverged");
+    let c = follower(&dir);
+    for seq in 1..=3 {
+      apply(&c, pos(7, seq), ClusterPosition::default()).await;
+    }
+    // Another node also became leader during a failover and started its own term from an older position, so it doesn't have our last write.
+    assert!(matches!(
+      apply(&c, pos(8, 1), pos(7, 2)).await,
+      ApplyDecision::Diverged
+    ));
+    assert!(c.status().stale);
+    assert!(dir.join(CLUSTER_STALE_MARKER_FILE).exists());
+    drop(c);
+    assert!(follower(&dir).status().stale);
+  }
+}
@@ -0,0 +1,186 @@
+use crate::endpoint::HttpCtx;
+use cadence::Counted;
+use cadence::Gauged;
+use cadence::StatsdClient;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tracing::info;
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub(crate) struct DiskSpace {
+  pub free_bytes: u64,
+  pub total_bytes: u64,
+}
+
+impl DiskSpace {
+  pub fn free_percent(&self) -> f64 {
+    self.free_bytes as f64 * 100.0 / self.total_bytes as f64
+  }
+}
+
+/// The space available to unprivileged users on the filesystem containing `path`, which is what RocksDB can actually use.
+pub(crate) fn disk_space(path: &Path) -> Option<DiskSpace> {
+  let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+  let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+  if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 || stat.f_blocks == 0 {
+    return None;
+  };
+  let block = stat.f_frsize as u64;
+  Some(DiskSpace {
+    free_bytes: stat.f_bavail as u64 * block,
+    total_bytes: stat.f_blocks as u64 * block,
+  })
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct DiskWatchdogCfg {
+  /// Pushes are rejected once free space drops below this.
+  pub min_free_percent: f64,
+  /// Pushes are accepted again once free space is at least this, which is higher than `min_free_percent` so that pushes aren't repeatedly suspended and resumed around the threshold.
+  pub resume_free_percent: f64,
+  pub interval: Duration,
+}
+
+pub(crate) struct DiskWatchdog {
+  pub cfg: DiskWatchdogCfg,
+  // Pushes start being accepted as soon as the server starts, before the first check.
+  low: AtomicBool,
+  last: Mutex<Option<DiskSpace>>,
+  queues_disk_bytes: AtomicU64,
+  rejected_push_counter: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DiskWatchdogStatus {
+  pushes_suspended: bool,
+  min_free_percent: f64,
+  resume_free_percent: f64,
+  free_bytes: Option<u64>,
+  total_bytes: Option<u64>,
+  free_percent: Option<f64>,
+  /// The combined size of every queue's RocksDB database.
+  queues_disk_bytes: u64,
+  rejected_push_count: u64,
+}
+
+impl DiskWatchdog {
+  pub fn new(cfg: DiskWatchdogCfg) -> Self {
+    Self {
+      cfg,
+      low: AtomicBool::new(false),
+      last: Mutex::new(None),
+      queues_disk_bytes: AtomicU64::new(0),
+      rejected_push_counter: AtomicU64::new(0),
+    }
+  }
+
+  /// Whether pushes are currently suspended. Counts the push as rejected if so.
+  pub fn reject_push(&self) -> bool {
+    let low = self.low.load(Ordering::Relaxed);
+    if low {
+      self.rejected_push_counter.fetch_add(1, Ordering::Relaxed);
+    };
+    low
+  }
+
+  pub fn status(&self) -> DiskWatchdogStatus {
+    let last = *self.last.lock();
+    DiskWatchdogStatus {
+      pushes_suspended: self.low.load(Ordering::Relaxed),
+      min_free_percent: self.cfg.min_free_percent,
+      resume_free_percent: self.cfg.resume_free_percent,
+      free_bytes: last.map(|d| d.free_bytes),
+      total_bytes: last.map(|d| d.total_bytes),
+      free_percent: last.map(|d| d.free_percent()),
+      queues_disk_bytes: self.queues_disk_bytes.load(Ordering::Relaxed),
+      rejected_push_count: self.rejected_push_counter.load(Ordering::Relaxed),
+    }
+  }
+
+  fn update(&self, space: Option<DiskSpace>, queues_disk_bytes: u64) {
+    *self.last.lock() = space;
+    self
+      .queues_disk_bytes
+      .store(queues_disk_bytes, Ordering::Relaxed);
+    // If free space can't be determined, keep the current state rather than guessing.
+    let Some(space) = space else {
+      return;
+    };
+    let free_percent = space.free_percent();
+    let was_low = self.low.load(Ordering::Relaxed);
+    if !was_low && free_percent < self.cfg.min_free_percent {
+      self.low.store(true, Ordering::Relaxed);
+      warn!(
+        free_percent,
+        free_bytes = space.free_bytes,
+        queues_disk_bytes,
+        "free disk space is low, suspending pushes"
+      );
+    } else if was_low && free_percent >= self.cfg.resume_free_percent {
+      self.low.store(false, Ordering::Relaxed);
+      info!(
+        free_percent,
+        free_bytes = space.free_bytes,
+        queues_disk_bytes,
+        "free disk space has been reclaimed, resuming pushes"
+      );
+    };
+  }
+}
+
+/// Checks free space on the data dir's disk at the configured interval, suspending pushes while it's low. Polls, updates, and deletes continue to work, so consumers can free up space by emptying queues.
+pub(crate) fn start_disk_watchdog(ctx: Weak<HttpCtx>, statsd: Option<StatsdClient>) {
+  spawn(async move {
+    let mut prev_rejected = 0;
+    loop {
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      let Some(watchdog) = &ctx.disk_watchdog else {
+        break;
+      };
+      let data_dir = ctx.data_dir.clone();
+      let space = spawn_blocking(move || disk_space(&data_dir))
+        .await
+        .ok()
+        .flatten();
+      let queues_disk_bytes = ctx
+        .queues
+        .iter()
+        .map(|e| e.value().metrics().disk_bytes())
+        .sum();
+      watchdog.update(space, queues_disk_bytes);
+      if let Some(s) = &statsd {
+        let status = watchdog.status();
+        if let Some(free_bytes) = status.free_bytes {
+          s.gauge("disk_free_bytes", free_bytes).unwrap();
+        };
+        s.gauge("queues_disk_bytes", status.queues_disk_bytes)
+          .unwrap();
+        s.gauge("disk_low", u64::from(status.pushes_suspended))
+          .unwrap();
+        s.count(
+          "disk_low_rejected_push",
+          i64::try_from(status.rejected_push_count - prev_rejected).unwrap(),
+        )
+        .unwrap();
+        prev_rejected = status.rejected_push_count;
+      };
+      let interval = watchdog.cfg.interval;
+      drop(ctx);
+      sleep(interval).await;
+    }
+  });
+}
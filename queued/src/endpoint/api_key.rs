@@ -1,16 +1,30 @@
 use super::qerr;
 use super::HttpCtx;
+use super::QueuedHttpError;
 use super::QueuedHttpResult;
+use crate::auth::Identity;
+use crate::auth::Permission;
 use axum::extract::Path;
 use axum::extract::State;
-use axum::http::HeaderMap;
 use axum::http::StatusCode;
 use axum_msgpack::MsgPack;
+use dashmap::DashMap;
 use itertools::Itertools;
+use libqueued::error_code::ErrorCode;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeSet;
 use std::sync::Arc;
 
+// The `/api-key` endpoints predate permissions and are kept for backwards compatibility; API keys created using them have all permissions.
+
+fn api_keys(ctx: &HttpCtx) -> Result<&DashMap<String, Identity>, QueuedHttpError> {
+  ctx
+    .api_keys
+    .as_deref()
+    .ok_or_else(|| (StatusCode::NOT_FOUND, qerr(ErrorCode::NotFound)))
+}
+
 #[derive(Deserialize)]
 pub(crate) struct EndpointSetApiKeyInput {
   prefix: String,
@@ -19,27 +33,17 @@ pub(crate) struct EndpointSetApiKeyInput {
 pub(crate) async fn endpoint_set_api_key(
   State(ctx): State<Arc<HttpCtx>>,
   Path(api_key): Path<String>,
-  headers: HeaderMap,
   MsgPack(req): MsgPack<EndpointSetApiKeyInput>,
 ) -> QueuedHttpResult<()> {
-  let Some(api_keys) = &ctx.api_keys else {
-    return Err((StatusCode::NOT_FOUND, qerr("NotFound")));
-  };
-  ctx.verify_global_auth(&headers)?;
-  api_keys.insert(api_key, req.prefix);
+  api_keys(&ctx)?.insert(api_key, Identity::unrestricted(req.prefix));
   Ok(MsgPack(()))
 }
 
 pub(crate) async fn endpoint_remove_api_key(
   State(ctx): State<Arc<HttpCtx>>,
   Path(api_key): Path<String>,
-  headers: HeaderMap,
 ) -> QueuedHttpResult<()> {
-  let Some(api_keys) = &ctx.api_keys else {
-    return Err((StatusCode::NOT_FOUND, qerr("NotFound")));
-  };
-  ctx.verify_global_auth(&headers)?;
-  api_keys.remove(&api_key);
+  api_keys(&ctx)?.remove(&api_key);
   Ok(MsgPack(()))
 }
 
@@ -47,6 +51,8 @@ pub(crate) async fn endpoint_remove_api_key(
 pub(crate) struct EndpointListApiKeysOutputKey {
   key: String,
   prefix: String,
+  permissions: BTreeSet<Permission>,
+  tenant: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -56,18 +62,46 @@ pub(crate) struct EndpointListApiKeysOutput {
 
 pub(crate) async fn endpoint_list_api_keys(
   State(ctx): State<Arc<HttpCtx>>,
-  headers: HeaderMap,
 ) -> QueuedHttpResult<EndpointListApiKeysOutput> {
-  let Some(api_keys) = &ctx.api_keys else {
-    return Err((StatusCode::NOT_FOUND, qerr("NotFound")));
-  };
-  ctx.verify_global_auth(&headers)?;
-  let keys = api_keys
+  let keys = api_keys(&ctx)?
     .iter()
     .map(|e| EndpointListApiKeysOutputKey {
       key: e.key().clone(),
-      prefix: e.value().clone(),
+      prefix: e.value().prefix.clone(),
+      permissions: e.value().permissions.clone(),
+      tenant: e.value().tenant.clone(),
     })
     .collect_vec();
   Ok(MsgPack(EndpointListApiKeysOutput { keys }))
 }
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointSetTokenInput {
+  #[serde(default)]
+  prefix: String,
+  permissions: BTreeSet<Permission>,
+  /// If set, the token can only access this tenant's queues, and `prefix` is relative to the tenant.
+  tenant: Option<String>,
+}
+
+pub(crate) async fn endpoint_set_token(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(token): Path<String>,
+  MsgPack(req): MsgPack<EndpointSetTokenInput>,
+) -> QueuedHttpResult<()> {
+  let identity = match req.tenant {
+    Some(tenant) => {
+      if !ctx.tenants.contains_key(&tenant) {
+        return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::TenantNotFound)));
+      };
+      Identity::for_tenant(tenant, &req.prefix, req.permissions)
+    }
+    None => Identity {
+      prefix: req.prefix,
+      permissions: req.permissions,
+      tenant: None,
+    },
+  };
+  api_keys(&ctx)?.insert(token, identity);
+  Ok(MsgPack(()))
+}
@@ -0,0 +1,189 @@
+use super::HttpCtx;
+use crate::archive::send_archive;
+use crate::archive::ArchiveDecoder;
+use crate::archive::ArchiveRecord;
+use crate::endpoint::qerr;
+use crate::endpoint::qerr_d;
+use crate::endpoint::queue::ops::transform_op_error;
+use crate::endpoint::queues::create_queue;
+use crate::endpoint::QueuedHttpError;
+use crate::endpoint::QueuedHttpResult;
+use axum::body::Body;
+use axum::body::Bytes;
+use axum::body::HttpBody;
+use axum::body::StreamBody;
+use axum::extract::RawBody;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum_msgpack::MsgPack;
+use futures::stream::poll_fn;
+use libqueued::error_code::ErrorCode;
+use libqueued::op::import::OpImportInput;
+use libqueued::op::import::OpImportInputMessage;
+use libqueued::Queued;
+use serde::Deserialize;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::mem::take;
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::sync::mpsc::channel;
+use tracing::info;
+use tracing::warn;
+
+// Imported messages are added in batches as the archive is received, so memory is bounded regardless of its size.
+const IMPORT_BATCH: usize = 1000;
+
+/// Streams an archive (see `crate::archive`) of every message in every queue. Messages pushed or deleted while the archive is being created may or may not be in it. If reading a queue fails, the archive ends without its end record, so that it can't be mistaken for a complete one.
+pub(crate) async fn endpoint_export(State(ctx): State<Arc<HttpCtx>>) -> Response {
+  // Collect first so that we don't hold map entry locks across await points.
+  let mut queues = ctx
+    .queues
+    .iter()
+    .map(|e| (e.key().clone(), Arc::clone(e.value())))
+    .collect::<Vec<(String, Arc<Queued>)>>();
+  queues.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+  let (tx, mut rx) = channel::<Bytes>(4);
+  spawn(async move {
+    match send_archive(queues, &tx).await {
+      Ok(()) => info!("export created"),
+      Err(err) => warn!(error = err, "failed to create export"),
+    };
+  });
+  let body = StreamBody::new(poll_fn(move |cx| {
+    rx.poll_recv(cx).map(|b| b.map(Ok::<_, Infallible>))
+  }));
+  ([(CONTENT_TYPE, "application/octet-stream")], body).into_response()
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct EndpointImportOutput {
+  /// Every queue in the archive, in order. Queues that didn't exist are created.
+  pub queues: Vec<String>,
+  pub imported: u64,
+  /// Messages that were already imported, or whose IDs had otherwise already been used in their queue.
+  pub skipped: u64,
+}
+
+struct Import<'a> {
+  ctx: &'a HttpCtx,
+  q: Option<Arc<Queued>>,
+  batch: Vec<OpImportInputMessage>,
+  batch_size: usize,
+  out: EndpointImportOutput,
+}
+
+impl<'a> Import<'a> {
+  async fn flush(&mut self) -> Result<(), QueuedHttpError> {
+    if self.batch.is_empty() {
+      return Ok(());
+    };
+    let messages = take(&mut self.batch);
+    self.batch_size = 0;
+    let n = messages.len();
+    let res = self
+      .q
+      .as_ref()
+      .unwrap()
+      .import(OpImportInput { messages })
+      .await
+      .map_err(transform_op_error)?;
+    self.out.imported += (n - res.skipped) as u64;
+    self.out.skipped += res.skipped as u64;
+    Ok(())
+  }
+
+  async fn add(&mut self, record: ArchiveRecord) -> Result<(), QueuedHttpError> {
+    match record {
+      ArchiveRecord::Queue { name } => {
+        self.flush().await?;
+        if !self.ctx.queues.contains_key(&name) {
+          create_queue(self.ctx, name.clone(), true, None).await?;
+        };
+        self.q = Some(self.ctx.q(&name)?);
+        self.out.queues.push(name);
+      }
+      ArchiveRecord::Message {
+        id,
+        visible_time,
+        poll_count,
+        contents,
+      } => {
+        if self.q.is_none() {
+          return Err((
+            StatusCode::BAD_REQUEST,
+            qerr_d(ErrorCode::InvalidArchive, "message before queue"),
+          ));
+        };
+        self.batch_size += contents.len();
+        self.batch.push(OpImportInputMessage {
+          id,
+          visible_time,
+          poll_count,
+          contents,
+        });
+        if self.batch.len() >= IMPORT_BATCH || self.batch_size >= self.ctx.max_request_body_size {
+          self.flush().await?;
+        };
+      }
+    };
+    Ok(())
+  }
+
+  async fn import_body(&mut self, mut body: Body) -> Result<(), QueuedHttpError> {
+    let mut decoder = ArchiveDecoder::default();
+    while let Some(chunk) = body.data().await {
+      decoder.feed(&chunk.map_err(|_| (StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidBody)))?);
+      loop {
+        // Bind the record first, as the error can't be held across await points.
+        let record = decoder.next_record().map_err(|err| {
+          (
+            StatusCode::BAD_REQUEST,
+            qerr_d(ErrorCode::InvalidArchive, err),
+          )
+        })?;
+        let Some(record) = record else {
+          break;
+        };
+        self.add(record).await?;
+      }
+      if decoder.pending_len() > self.ctx.max_request_body_size {
+        return Err((
+          StatusCode::PAYLOAD_TOO_LARGE,
+          qerr(ErrorCode::MessageTooLarge),
+        ));
+      };
+    }
+    self.flush().await?;
+    if !decoder.is_ended() {
+      return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::ArchiveTruncated)));
+    };
+    Ok(())
+  }
+}
+
+/// Imports an archive created by `endpoint_export`, creating queues that don't exist and keeping each message's ID, visible time, and poll count. The body isn't limited as a whole, as it's imported as it's received. Importing is idempotent, so an interrupted import can be resumed by importing the same archive again. If it fails, the error has the result of the messages imported before it as its details.
+pub(crate) async fn endpoint_import(
+  State(ctx): State<Arc<HttpCtx>>,
+  RawBody(body): RawBody,
+) -> QueuedHttpResult<EndpointImportOutput> {
+  ctx.verify_leader()?;
+  ctx.verify_accepting_pushes()?;
+  let mut import = Import {
+    ctx: &ctx,
+    q: None,
+    batch: Vec::new(),
+    batch_size: 0,
+    out: EndpointImportOutput::default(),
+  };
+  match import.import_body(body).await {
+    Ok(()) => Ok(MsgPack(import.out)),
+    Err((status, MsgPack(mut err))) => {
+      err.error_details = Some(Box::new(import.out));
+      Err((status, MsgPack(err)))
+    }
+  }
+}
@@ -0,0 +1,46 @@
+use super::HttpCtx;
+use crate::auth::ClientCertificate;
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::extract::State;
+use axum::http::header::AUTHORIZATION;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use libqueued::audit::with_audit_client;
+use sha2::Digest;
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// Audit events are stored and returned to anyone who can read the queue, so API keys and tokens are identified by a prefix of their hash rather than themselves.
+fn audit_client<B>(req: &Request<B>, addr: Option<SocketAddr>) -> String {
+  if let Some(k) = req.headers().get(AUTHORIZATION) {
+    return format!("key:{}", &hex::encode(Sha256::digest(k.as_bytes()))[..16]);
+  };
+  if let Some(cn) = req
+    .extensions()
+    .get::<ClientCertificate>()
+    .and_then(|c| c.common_name.as_ref())
+  {
+    return format!("cert:{cn}");
+  };
+  match addr {
+    Some(addr) => format!("ip:{}", addr.ip()),
+    None => "local".to_string(),
+  }
+}
+
+/// Records who made each request as the client of any audit log events it causes.
+pub(crate) async fn audit_middleware(
+  State(ctx): State<Arc<HttpCtx>>,
+  addr: Option<ConnectInfo<SocketAddr>>,
+  req: Request<Body>,
+  next: Next<Body>,
+) -> Response {
+  if !ctx.queue_cfg.audit_log {
+    return next.run(req).await;
+  };
+  let client = audit_client(&req, addr.map(|a| a.0));
+  with_audit_client(client, next.run(req)).await
+}
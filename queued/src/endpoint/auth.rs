@@ -0,0 +1,79 @@
+use super::HttpCtx;
+use crate::auth::Access;
+use crate::auth::Credentials;
+use crate::auth::Permission;
+use axum::extract::MatchedPath;
+use axum::extract::RawPathParams;
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use std::sync::Arc;
+
+fn required_access<'a>(path: &str, queue: Option<&'a str>) -> Access<'a> {
+  let Some(queue) = queue else {
+    return match path {
+      "/capabilities" | "/error-codes" | "/healthz" | "/livez" | "/readyz" | "/ui" => {
+        Access::Public
+      }
+      // The SQS API authorizes each action itself.
+      "/sqs" | "/sqs/" => Access::Public,
+      // Multi-queue pushes authorize each queue themselves.
+      "/messages/push" => Access::Public,
+      p if p.starts_with("/cluster/") => Access::Internal,
+      // Unknown endpoints require the highest level of access, to be safe.
+      _ => Access::Server,
+    };
+  };
+  match path {
+    "/queue/:queue" => Access::QueueManagement(queue),
+    "/queue/:queue/messages/push" | "/queue/:queue/messages/push-stream" => {
+      Access::Queue(queue, Permission::Push)
+    }
+    "/queue/:queue/messages/delete"
+    | "/queue/:queue/messages/nack"
+    | "/queue/:queue/messages/poll"
+    | "/queue/:queue/messages/poll-stream"
+    | "/queue/:queue/messages/takeover"
+    | "/queue/:queue/messages/transaction"
+    | "/queue/:queue/messages/update"
+    | "/queue/:queue/visibility-watermark" => Access::Queue(queue, Permission::Poll),
+    "/queue/:queue/schemas/:version" => Access::QueueRead(queue),
+    "/queue/:queue/messages" => Access::Queue(queue, Permission::Admin),
+    "/schedules/:queue/:name" => Access::Queue(queue, Permission::Admin),
+    "/sqs/:queue" => Access::Public,
+    p if p.starts_with("/cluster/") => Access::Internal,
+    p if p.starts_with("/queue/:queue/") => Access::Queue(queue, Permission::Admin),
+    _ => Access::Server,
+  }
+}
+
+pub(crate) async fn auth_middleware<B>(
+  State(ctx): State<Arc<HttpCtx>>,
+  path: MatchedPath,
+  params: Option<RawPathParams>,
+  req: Request<B>,
+  next: Next<B>,
+) -> Response {
+  let queue = params
+    .iter()
+    .flat_map(|p| p.iter())
+    .find(|(k, _)| *k == "queue")
+    .map(|(_, v)| v.to_string());
+  let access = required_access(path.as_str(), queue.as_deref());
+  let creds = Credentials {
+    headers: req.headers(),
+    client_certificate: req.extensions().get(),
+  };
+  if let Err(err) = ctx.authorize(&creds, access) {
+    return err.into_response();
+  };
+  // Transactions also push, which requires a separate permission.
+  if let ("/queue/:queue/messages/transaction", Some(queue)) = (path.as_str(), queue.as_deref()) {
+    if let Err(err) = ctx.authorize(&creds, Access::Queue(queue, Permission::Push)) {
+      return err.into_response();
+    };
+  };
+  next.run(req).await
+}
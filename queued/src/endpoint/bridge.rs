@@ -0,0 +1,66 @@
+use super::qerr;
+use super::read_body;
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::bridge::BridgeStatus;
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::extract::RawPathParams;
+use axum::extract::State;
+use axum::http::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use std::sync::Arc;
+
+pub(crate) async fn endpoint_bridge_status(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<BridgeStatus> {
+  let Some(bridge) = &ctx.bridge else {
+    return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::BridgeNotEnabled)));
+  };
+  Ok(MsgPack(bridge.status()))
+}
+
+pub(crate) async fn bridge_middleware(
+  State(ctx): State<Arc<HttpCtx>>,
+  path: MatchedPath,
+  params: RawPathParams,
+  req: Request<Body>,
+  next: Next<Body>,
+) -> Response {
+  let Some(bridge) = ctx.bridge.clone() else {
+    return next.run(req).await;
+  };
+  if path.as_str() != "/queue/:queue/messages/push" {
+    return next.run(req).await;
+  };
+  let queue = params
+    .iter()
+    .find(|(k, _)| *k == "queue")
+    .map(|(_, v)| v.to_string())
+    .unwrap();
+  let (parts, body) = req.into_parts();
+  let body = match read_body(body, ctx.max_request_body_size).await {
+    Ok(b) => b,
+    Err(status) => return status.into_response(),
+  };
+  let res = next
+    .run(Request::from_parts(parts, Body::from(body.clone())))
+    .await;
+  if !res.status().is_success() {
+    return res;
+  };
+  // The push has been accepted locally, but must not be acknowledged until the other cluster is guaranteed to eventually get it.
+  if bridge.forward(&queue, body).await.is_err() {
+    return (
+      StatusCode::SERVICE_UNAVAILABLE,
+      qerr(ErrorCode::BridgeFailed),
+    )
+      .into_response();
+  };
+  res
+}
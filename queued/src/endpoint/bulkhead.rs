@@ -0,0 +1,86 @@
+use super::qerr_d;
+use super::HttpCtx;
+use crate::bulkhead::EndpointClass;
+use axum::body::Body;
+use axum::body::BoxBody;
+use axum::body::Bytes;
+use axum::body::HttpBody;
+use axum::extract::MatchedPath;
+use axum::extract::State;
+use axum::http::header::RETRY_AFTER;
+use axum::http::HeaderMap;
+use axum::http::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use hyper::body::SizeHint;
+use libqueued::error_code::ErrorCode;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use tokio::sync::OwnedSemaphorePermit;
+
+// Responses such as exports and streamed polls are still being handled while their bodies are sent, so the permit is only released once the body is done.
+struct PermitBody {
+  inner: BoxBody,
+  _permit: OwnedSemaphorePermit,
+}
+
+impl HttpBody for PermitBody {
+  type Data = Bytes;
+  type Error = axum::Error;
+
+  fn poll_data(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    Pin::new(&mut self.get_mut().inner).poll_data(cx)
+  }
+
+  fn poll_trailers(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+    Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.inner.is_end_stream()
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    self.inner.size_hint()
+  }
+}
+
+pub(crate) async fn bulkhead_middleware(
+  State(ctx): State<Arc<HttpCtx>>,
+  path: MatchedPath,
+  req: Request<Body>,
+  next: Next<Body>,
+) -> Response {
+  let (Some(bulkheads), Some(class)) = (&ctx.bulkheads, EndpointClass::of_route(path.as_str()))
+  else {
+    return next.run(req).await;
+  };
+  let permit = match bulkheads.try_acquire(class) {
+    Ok(Some(permit)) => permit,
+    Ok(None) => return next.run(req).await,
+    Err(()) => {
+      return (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, "1")],
+        qerr_d(ErrorCode::Overloaded, format!("{class:?}").to_lowercase()),
+      )
+        .into_response();
+    }
+  };
+  next.run(req).await.map(|inner| {
+    axum::body::boxed(PermitBody {
+      inner,
+      _permit: permit,
+    })
+  })
+}
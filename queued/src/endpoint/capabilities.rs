@@ -0,0 +1,135 @@
+use crate::endpoint::healthz::VERSION;
+use crate::endpoint::HttpCtx;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub(crate) struct CapabilitiesAuth {
+  /// Whether server-wide endpoints require the global API key.
+  global_api_key: bool,
+  /// Authentication methods that can be used with queues. If empty, queues don't require authentication.
+  queues: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CapabilitiesLimits {
+  max_message_size: Option<usize>,
+  max_request_body_size: usize,
+  min_visibility_timeout_secs: i64,
+  max_visibility_timeout_secs: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointCapabilitiesOutput {
+  version: &'static str,
+  /// Optional features that are available. Clients should ignore unknown values.
+  features: Vec<&'static str>,
+  /// API protocols that are available.
+  protocols: Vec<&'static str>,
+  /// Request and response body compression algorithms that are supported.
+  compression: Vec<&'static str>,
+  auth: CapabilitiesAuth,
+  limits: CapabilitiesLimits,
+}
+
+pub(crate) async fn endpoint_capabilities(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> MsgPack<EndpointCapabilitiesOutput> {
+  let reloadable = *ctx.reloadable.read();
+  let mut features = vec![
+    "batch_delete",
+    "batch_poll",
+    "batch_push",
+    "batch_update",
+    "compaction",
+    "config_reload",
+    "debug_sampling",
+    "drain",
+    "forced_delete",
+    "list_messages",
+    "nack",
+    "peek",
+    "pinning",
+    "poll_count",
+    "poll_stream",
+    "poll_transform",
+    "priorities",
+    "purge",
+    "push_stream",
+    "push_stream_acks",
+    "queue_templates",
+    "routing",
+    "schedules",
+    "schemas",
+    "snapshots",
+    "subtrees",
+    "takeover",
+    "transactions",
+    "visibility_jitter",
+    "webhooks",
+  ];
+  let mut protocols = vec!["msgpack"];
+  if ctx.queue_cfg.format_version >= 4 {
+    features.push("attributes");
+  };
+  if ctx.queue_cfg.format_version >= 5 {
+    features.push("ttl");
+  };
+  if ctx.queue_cfg.format_version >= 6 {
+    features.push("poll_latency");
+  };
+  if ctx.queue_cfg.format_version >= 7 {
+    features.push("groups");
+  };
+  if ctx.queue_cfg.format_version >= 8 {
+    features.push("external_ids");
+  };
+  if ctx.queue_cfg.fanout_log.is_some() && ctx.queue_cfg.format_version >= 12 {
+    features.push("fanout_push");
+  };
+  if ctx.cluster.is_some() {
+    features.push("replication");
+  };
+  if ctx.s3.is_some() {
+    features.push("offload");
+  };
+  if reloadable.redelivery_backoff.is_some() {
+    features.push("redelivery_backoff");
+  };
+  if reloadable.release_pacing_max_per_sec.is_some() {
+    features.push("release_pacing");
+  };
+  if ctx.disk_watchdog.is_some() {
+    features.push("disk_watchdog");
+  };
+  if !ctx.tenants.is_empty() {
+    features.push("tenants");
+  };
+  if ctx.enable_generator {
+    features.push("generator");
+  };
+  if ctx.enable_sqs_api {
+    // Long polling is only available using the SQS API.
+    features.push("long_poll");
+    protocols.push("sqs_json");
+    protocols.push("sqs_query");
+  };
+  MsgPack(EndpointCapabilitiesOutput {
+    version: VERSION,
+    features,
+    protocols,
+    compression: Vec::new(),
+    auth: CapabilitiesAuth {
+      global_api_key: ctx.global_api_key.is_some(),
+      queues: ctx.auth_providers.iter().map(|p| p.kind()).collect(),
+    },
+    limits: CapabilitiesLimits {
+      max_message_size: reloadable.max_message_size,
+      max_request_body_size: ctx.max_request_body_size,
+      min_visibility_timeout_secs: reloadable.min_visibility_timeout_secs,
+      max_visibility_timeout_secs: reloadable.max_visibility_timeout_secs,
+    },
+  })
+}
@@ -0,0 +1,150 @@
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::cluster::ApplyDecision;
+use crate::cluster::ClusterPosition;
+use crate::cluster::ClusterStatus;
+use crate::cluster::ReplicationConflictDetails;
+use crate::cluster::CLUSTER_LEADER_ID_HEADER;
+use crate::cluster::CLUSTER_SEQ_HEADER;
+use crate::cluster::CLUSTER_TERM_HEADER;
+use crate::cluster::CLUSTER_TERM_START_HEADER;
+use crate::endpoint::qerr;
+use crate::endpoint::qerr_d;
+use crate::endpoint::queues::create_queue;
+use crate::endpoint::queues::delete_queue;
+use crate::endpoint::QueuedHttpError;
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use std::future::Future;
+use std::sync::Arc;
+
+// These endpoints are only used between nodes, so `auth_middleware` only accepts the global API key, which clustering requires.
+
+// Rejections because this node doesn't follow the sender use 421, which makes the sender step down; see `Cluster::send_log_to_peer`.
+fn verify_follower(ctx: &HttpCtx, headers: &HeaderMap) -> Result<(), QueuedHttpError> {
+  let Some(cluster) = &ctx.cluster else {
+    return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::ClusterNotEnabled)));
+  };
+  if cluster.is_leader() {
+    return Err((StatusCode::MISDIRECTED_REQUEST, qerr(ErrorCode::IsLeader)));
+  };
+  let sender = headers
+    .get(CLUSTER_LEADER_ID_HEADER)
+    .and_then(|v| v.to_str().ok()?.parse().ok());
+  // Writes without a sender are rejected, as they can't be fenced.
+  if !sender.is_some_and(|id| cluster.accepts_writes_from(id)) {
+    return Err((StatusCode::MISDIRECTED_REQUEST, qerr(ErrorCode::NotLeader)));
+  };
+  Ok(())
+}
+
+fn position_header<T>(
+  headers: &HeaderMap,
+  name: &str,
+  parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<T, QueuedHttpError> {
+  headers
+    .get(name)
+    .and_then(|v| parse(v.to_str().ok()?))
+    .ok_or_else(|| {
+      (
+        StatusCode::BAD_REQUEST,
+        qerr(ErrorCode::InvalidClusterPosition),
+      )
+    })
+}
+
+// Writes from the leader are applied exactly once and in order; see `Cluster::begin_apply`. Out of order writes are rejected with 409, which makes the leader resend from where we are.
+async fn apply_from_leader<F: Future<Output = Result<(), QueuedHttpError>>>(
+  ctx: &HttpCtx,
+  headers: &HeaderMap,
+  apply: impl FnOnce() -> F,
+) -> QueuedHttpResult<()> {
+  verify_follower(ctx, headers)?;
+  let cluster = ctx.cluster.as_ref().unwrap();
+  let position = ClusterPosition {
+    term: position_header(headers, CLUSTER_TERM_HEADER, |v| v.parse().ok())?,
+    seq: position_header(headers, CLUSTER_SEQ_HEADER, |v| v.parse().ok())?,
+  };
+  let start = position_header(
+    headers,
+    CLUSTER_TERM_START_HEADER,
+    ClusterPosition::from_header,
+  )?;
+  let (guard, decision) = cluster.begin_apply(position, start).await.map_err(|_| {
+    (
+      StatusCode::SERVICE_UNAVAILABLE,
+      qerr(ErrorCode::StorageUnavailable),
+    )
+  })?;
+  let resend_from = match decision {
+    ApplyDecision::Apply => None,
+    ApplyDecision::Skip => return Ok(MsgPack(())),
+    ApplyDecision::Resend(seq) => Some(Some(seq)),
+    ApplyDecision::Diverged => Some(None),
+  };
+  if let Some(resend_from) = resend_from {
+    return Err((
+      StatusCode::CONFLICT,
+      qerr_d(
+        ErrorCode::ReplicationOutOfOrder,
+        ReplicationConflictDetails { resend_from },
+      ),
+    ));
+  };
+  apply().await?;
+  cluster.finish_apply(guard, position).await.map_err(|_| {
+    (
+      StatusCode::SERVICE_UNAVAILABLE,
+      qerr(ErrorCode::StorageUnavailable),
+    )
+  })?;
+  Ok(MsgPack(()))
+}
+
+pub(crate) async fn endpoint_cluster_status(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<ClusterStatus> {
+  let Some(cluster) = &ctx.cluster else {
+    return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::ClusterNotEnabled)));
+  };
+  Ok(MsgPack(cluster.status()))
+}
+
+pub(crate) async fn endpoint_cluster_replicate(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(name): Path<String>,
+  headers: HeaderMap,
+  body: Bytes,
+) -> QueuedHttpResult<()> {
+  apply_from_leader(&ctx, &headers, || async {
+    let Some(q) = ctx.queues.get(&name).map(|q| Arc::clone(&*q)) else {
+      return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::QueueNotFound)));
+    };
+    q.apply_replicated_batch(body.to_vec())
+      .await
+      .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, qerr(err.into())))
+  })
+  .await
+}
+
+pub(crate) async fn endpoint_cluster_queue_create(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(name): Path<String>,
+  headers: HeaderMap,
+) -> QueuedHttpResult<()> {
+  apply_from_leader(&ctx, &headers, || create_queue(&ctx, name, false, None)).await
+}
+
+pub(crate) async fn endpoint_cluster_queue_delete(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(name): Path<String>,
+  headers: HeaderMap,
+) -> QueuedHttpResult<()> {
+  apply_from_leader(&ctx, &headers, || delete_queue(&ctx, name)).await
+}
@@ -0,0 +1,44 @@
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::endpoint::queue::ops::transform_op_result;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::op::compact::OpCompactOutput;
+use libqueued::Queued;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointCompactInput {
+  /// Only compact this queue. Otherwise, all queues are compacted, one at a time.
+  queue: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointCompactOutput {
+  queues: BTreeMap<String, OpCompactOutput>,
+}
+
+/// Compaction is local to each node, so unlike scrubbing with quarantine, this can be done on any node.
+pub(crate) async fn endpoint_compact(
+  State(ctx): State<Arc<HttpCtx>>,
+  MsgPack(req): MsgPack<EndpointCompactInput>,
+) -> QueuedHttpResult<EndpointCompactOutput> {
+  let queues = match req.queue {
+    Some(name) => vec![(name.clone(), ctx.q(&name)?)],
+    // Collect first so that we don't hold map entry locks across await points.
+    None => ctx
+      .queues
+      .iter()
+      .map(|e| (e.key().clone(), Arc::clone(e.value())))
+      .collect::<Vec<(String, Arc<Queued>)>>(),
+  };
+  let mut out = BTreeMap::new();
+  for (name, q) in queues {
+    let MsgPack(res) = transform_op_result(q.compact().await)?;
+    out.insert(name, res);
+  }
+  Ok(MsgPack(EndpointCompactOutput { queues: out }))
+}
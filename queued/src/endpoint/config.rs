@@ -0,0 +1,76 @@
+use crate::cfg::ReloadableCfgFile;
+use crate::endpoint::qerr_d;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointConfigOutput {
+  /// The current value of every reloadable setting, including defaults.
+  settings: ReloadableCfgFile,
+  /// Settings set by CLI args or env vars, which can only be changed by restarting.
+  overridden: Vec<String>,
+}
+
+fn config_output(ctx: &HttpCtx) -> EndpointConfigOutput {
+  EndpointConfigOutput {
+    settings: ctx.reloadable.read().to_file(),
+    overridden: ctx.reloadable_overrides.set_keys(),
+  }
+}
+
+pub(crate) async fn endpoint_get_config(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<EndpointConfigOutput> {
+  Ok(MsgPack(config_output(&ctx)))
+}
+
+/// Replaces the reloadable settings, using the same keys as the config file. Like reloading the config file, settings that aren't provided revert to their defaults.
+pub(crate) async fn endpoint_put_config(
+  State(ctx): State<Arc<HttpCtx>>,
+  MsgPack(req): MsgPack<BTreeMap<String, serde_json::Value>>,
+) -> QueuedHttpResult<EndpointConfigOutput> {
+  let invalid = |details: String| {
+    (
+      StatusCode::BAD_REQUEST,
+      qerr_d(ErrorCode::InvalidConfig, details),
+    )
+  };
+  let reloadable = ReloadableCfgFile::keys();
+  let unknown = req
+    .keys()
+    .filter(|k| !reloadable.contains(k))
+    .cloned()
+    .collect::<Vec<_>>();
+  if !unknown.is_empty() {
+    return Err(invalid(format!(
+      "{} can't be changed without restarting",
+      unknown.join(", ")
+    )));
+  };
+  let file: ReloadableCfgFile =
+    serde_json::from_value(serde_json::Value::Object(req.into_iter().collect()))
+      .map_err(|err| invalid(err.to_string()))?;
+  let overrides = ctx.reloadable_overrides.set_keys();
+  let overridden = file
+    .set_keys()
+    .into_iter()
+    .filter(|k| overrides.contains(k))
+    .collect::<Vec<_>>();
+  if !overridden.is_empty() {
+    return Err(invalid(format!(
+      "{} are set by CLI args or env vars, which can't be changed without restarting",
+      overridden.join(", ")
+    )));
+  };
+  ctx.apply_reloadable(file).map_err(invalid)?;
+  info!("config changed");
+  Ok(MsgPack(config_output(&ctx)))
+}
@@ -0,0 +1,21 @@
+use crate::disk_watchdog::DiskWatchdogStatus;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointDiskOutput {
+  /// None if the disk watchdog isn't enabled.
+  watchdog: Option<DiskWatchdogStatus>,
+}
+
+pub(crate) async fn endpoint_disk(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<EndpointDiskOutput> {
+  Ok(MsgPack(EndpointDiskOutput {
+    watchdog: ctx.disk_watchdog.as_ref().map(|w| w.status()),
+  }))
+}
@@ -0,0 +1,31 @@
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DrainState {
+  draining: bool,
+}
+
+pub(crate) async fn endpoint_get_drain(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<DrainState> {
+  Ok(MsgPack(DrainState {
+    draining: ctx.draining.load(Ordering::Relaxed),
+  }))
+}
+
+pub(crate) async fn endpoint_post_drain(
+  State(ctx): State<Arc<HttpCtx>>,
+  MsgPack(req): MsgPack<DrainState>,
+) -> QueuedHttpResult<DrainState> {
+  ctx.draining.store(req.draining, Ordering::Relaxed);
+  info!(draining = req.draining, "drain mode changed");
+  Ok(MsgPack(req))
+}
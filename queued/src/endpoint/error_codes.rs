@@ -0,0 +1,31 @@
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointErrorCodesOutputCode {
+  name: ErrorCode,
+  code: u16,
+  retryable: bool,
+  description: &'static str,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointErrorCodesOutput {
+  codes: Vec<EndpointErrorCodesOutputCode>,
+}
+
+/// Lists every error code that can be returned, so that clients can be generated from or checked against the server's catalog.
+pub(crate) async fn endpoint_error_codes() -> MsgPack<EndpointErrorCodesOutput> {
+  MsgPack(EndpointErrorCodesOutput {
+    codes: ErrorCode::ALL
+      .iter()
+      .map(|&e| EndpointErrorCodesOutputCode {
+        name: e,
+        code: e as u16,
+        retryable: e.is_retryable(),
+        description: e.description(),
+      })
+      .collect(),
+  })
+}
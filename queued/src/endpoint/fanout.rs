@@ -0,0 +1,76 @@
+use super::qerr;
+use super::queue::ops::transform_op_result;
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::auth::Access;
+use crate::auth::ClientCertificate;
+use crate::auth::Credentials;
+use crate::auth::Permission;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::Extension;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub(crate) struct FanoutPush {
+  queue: String,
+  messages: Vec<OpPushInputMessage>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointFanoutPushInput {
+  pushes: Vec<FanoutPush>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointFanoutPushOutput {
+  /// The IDs of each push's messages, in request order.
+  ids: Vec<Vec<u64>>,
+}
+
+// Routing rules don't apply, as routed messages would need to be prepared in queues that haven't been authorized.
+pub(crate) async fn endpoint_fanout_push(
+  State(ctx): State<Arc<HttpCtx>>,
+  headers: HeaderMap,
+  client_certificate: Option<Extension<ClientCertificate>>,
+  MsgPack(req): MsgPack<EndpointFanoutPushInput>,
+) -> QueuedHttpResult<EndpointFanoutPushOutput> {
+  ctx.verify_leader()?;
+  ctx.verify_accepting_pushes()?;
+  // Authorize and look up every queue first, so that nothing is prepared if any of them fails.
+  let creds = Credentials {
+    headers: &headers,
+    client_certificate: client_certificate.as_ref().map(|c| &c.0),
+  };
+  let queues = req
+    .pushes
+    .iter()
+    .map(|p| {
+      ctx.authorize(&creds, Access::Queue(&p.queue, Permission::Push))?;
+      ctx.q(&p.queue)
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+  let Some(log) = &ctx.queue_cfg.fanout_log else {
+    return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::FanoutUnsupported)));
+  };
+  let pushes = queues
+    .iter()
+    .zip(req.pushes)
+    .map(|(q, p)| {
+      (&**q, OpPushInput {
+        messages: p.messages,
+      })
+    })
+    .collect();
+  let MsgPack(outputs) = transform_op_result(log.push(pushes).await)?;
+  Ok(MsgPack(EndpointFanoutPushOutput {
+    ids: outputs.into_iter().map(|o| o.ids).collect(),
+  }))
+}
@@ -0,0 +1,86 @@
+use super::qerr;
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::endpoint::queue::ops::transform_op_result;
+use axum::extract::Query;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use rand::distributions::Alphanumeric;
+use rand::thread_rng;
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
+
+const MAX_GENERATE: usize = 1_000_000;
+const GENERATE_BATCH: usize = 1000;
+
+fn default_size() -> usize {
+  64
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointGenerateInput {
+  queue: String,
+  n: usize,
+  /// Size of each message's contents in bytes, which are random alphanumeric characters.
+  #[serde(default = "default_size")]
+  size: usize,
+  /// Seconds before the messages become visible.
+  #[serde(default)]
+  delay: u32,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointGenerateOutput {
+  pushed: usize,
+}
+
+/// Pushes `n` synthetic messages to a queue, so that a deployment can be smoke tested without a producer. Messages are pushed in batches, so if one fails, earlier batches have still been pushed.
+pub(crate) async fn endpoint_generate(
+  State(ctx): State<Arc<HttpCtx>>,
+  Query(req): Query<EndpointGenerateInput>,
+) -> QueuedHttpResult<EndpointGenerateOutput> {
+  if req.n > MAX_GENERATE {
+    return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::TooManyMessages)));
+  };
+  let q = ctx.q(&req.queue)?;
+  ctx.verify_leader()?;
+  ctx.verify_accepting_pushes()?;
+  let mut pushed = 0;
+  while pushed < req.n {
+    let messages = {
+      let mut rng = thread_rng();
+      (0..GENERATE_BATCH.min(req.n - pushed))
+        .map(|_| OpPushInputMessage {
+          contents: (&mut rng)
+            .sample_iter(Alphanumeric)
+            .take(req.size)
+            .collect(),
+          visibility_timeout_secs: req.delay,
+          visibility_jitter_secs: 0,
+          priority: 0,
+          attributes: Default::default(),
+          ttl_secs: None,
+          group_id: None,
+          external_id: None,
+        })
+        .collect::<Vec<_>>()
+    };
+    let n = messages.len();
+    transform_op_result(q.push(OpPushInput { messages }).await)?;
+    pushed += n;
+  }
+  info!(
+    queue = req.queue,
+    n = req.n,
+    size = req.size,
+    "generated messages"
+  );
+  Ok(MsgPack(EndpointGenerateOutput { pushed }))
+}
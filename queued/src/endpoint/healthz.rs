@@ -1,16 +1,133 @@
+use crate::disk_watchdog::disk_space;
+use crate::endpoint::HttpCtx;
+use axum::extract::State;
+use axum::http::StatusCode;
 use axum_msgpack::MsgPack;
+use futures::future::join_all;
 use serde::Deserialize;
 use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Thresholds used by `/readyz` to decide whether the server should receive traffic.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ReadyzCfg {
+  /// Queues with more writes than this waiting to be synced are considered backlogged.
+  pub max_sync_backlog: usize,
+  /// Zero disables the disk space check.
+  pub min_free_disk_percent: f64,
+  /// Applies to each queue's storage read.
+  pub timeout: Duration,
+}
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct EndpointHealthzOutput {
   version: String,
+  max_message_size: Option<usize>,
 }
 
-pub(crate) async fn endpoint_healthz() -> MsgPack<EndpointHealthzOutput> {
+pub(crate) async fn endpoint_healthz(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> MsgPack<EndpointHealthzOutput> {
   MsgPack(EndpointHealthzOutput {
     version: VERSION.to_string(),
+    max_message_size: ctx.reloadable.read().max_message_size,
+  })
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EndpointLivezOutput {
+  version: String,
+}
+
+/// Only reports that the process is up and serving requests; storage problems are left to `/readyz` so that orchestrators don't restart a server that just needs traffic taken away.
+pub(crate) async fn endpoint_livez() -> MsgPack<EndpointLivezOutput> {
+  MsgPack(EndpointLivezOutput {
+    version: VERSION.to_string(),
   })
 }
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EndpointReadyzOutput {
+  draining: bool,
+  /// Queues whose storage circuit breaker is currently open.
+  storage_unavailable_queues: Vec<String>,
+  /// Queues whose storage failed or timed out on a read.
+  storage_check_failed_queues: Vec<String>,
+  /// Queues with more writes waiting to be synced than allowed.
+  sync_backlogged_queues: Vec<String>,
+  free_disk_percent: Option<f64>,
+  low_disk: bool,
+}
+
+pub(crate) async fn endpoint_readyz(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> (StatusCode, MsgPack<EndpointReadyzOutput>) {
+  let cfg = ctx.reloadable.read().readyz;
+  let queues = ctx
+    .queues
+    .iter()
+    .map(|e| (e.key().clone(), e.value().clone()))
+    .collect::<Vec<_>>();
+
+  let mut storage_unavailable_queues = Vec::new();
+  let mut sync_backlogged_queues = Vec::new();
+  for (name, q) in queues.iter() {
+    if !q.is_storage_available() {
+      storage_unavailable_queues.push(name.clone());
+    };
+    if q.sync_backlog() > cfg.max_sync_backlog {
+      sync_backlogged_queues.push(name.clone());
+    };
+  }
+
+  let checks = join_all(queues.iter().map(|(name, q)| async move {
+    let ok = matches!(timeout(cfg.timeout, q.check_storage()).await, Ok(Ok(())));
+    (name, ok)
+  }))
+  .await;
+  let storage_check_failed_queues = checks
+    .into_iter()
+    .filter(|(_, ok)| !ok)
+    .map(|(name, _)| name.clone())
+    .collect::<Vec<_>>();
+
+  let free_disk_percent = if cfg.min_free_disk_percent > 0.0 {
+    let data_dir = ctx.data_dir.clone();
+    tokio::task::spawn_blocking(move || disk_space(&data_dir))
+      .await
+      .ok()
+      .flatten()
+      .map(|d| d.free_percent())
+  } else {
+    None
+  };
+  let low_disk = free_disk_percent.is_some_and(|p| p < cfg.min_free_disk_percent);
+
+  let draining = ctx.draining.load(Ordering::Relaxed);
+  let ready = !draining
+    && !low_disk
+    && storage_unavailable_queues.is_empty()
+    && storage_check_failed_queues.is_empty()
+    && sync_backlogged_queues.is_empty();
+  let status = if ready {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  (
+    status,
+    MsgPack(EndpointReadyzOutput {
+      draining,
+      storage_unavailable_queues,
+      storage_check_failed_queues,
+      sync_backlogged_queues,
+      free_disk_percent,
+      low_disk,
+    }),
+  )
+}
@@ -0,0 +1,68 @@
+use super::qerr;
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::mirror::MirrorStatus;
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::extract::State;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use std::sync::Arc;
+use tokio::spawn;
+
+pub(crate) async fn endpoint_mirror_status(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<MirrorStatus> {
+  let Some(mirror) = &ctx.mirror else {
+    return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::MirrorNotEnabled)));
+  };
+  Ok(MsgPack(mirror.status()))
+}
+
+pub(crate) async fn mirror_middleware(
+  State(ctx): State<Arc<HttpCtx>>,
+  path: MatchedPath,
+  req: Request<Body>,
+  next: Next<Body>,
+) -> Response {
+  let Some(mirror) = ctx.mirror.clone() else {
+    return next.run(req).await;
+  };
+  if !matches!(
+    path.as_str(),
+    "/queue/:queue/messages/push" | "/queue/:queue/messages/poll"
+  ) || !mirror.should_mirror()
+  {
+    return next.run(req).await;
+  };
+  // Only buffer bodies whose size is known to be within the limit, so we never read more than the endpoint would have.
+  let len = req
+    .headers()
+    .get(CONTENT_LENGTH)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<usize>().ok());
+  if len.is_none_or(|l| l > ctx.max_request_body_size) {
+    return next.run(req).await;
+  };
+  let (parts, body) = req.into_parts();
+  let Ok(body) = hyper::body::to_bytes(body).await else {
+    return StatusCode::BAD_REQUEST.into_response();
+  };
+  let uri = parts.uri.clone();
+  let content_type = parts.headers.get(CONTENT_TYPE).cloned();
+  let res = next
+    .run(Request::from_parts(parts, Body::from(body.clone())))
+    .await;
+  let status = res.status();
+  spawn(async move {
+    mirror.send(uri.path(), content_type, body, status).await;
+  });
+  res
+}
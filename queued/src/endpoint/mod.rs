@@ -1,22 +1,88 @@
 pub(crate) mod api_key;
+pub(crate) mod archive;
+pub(crate) mod audit;
+pub(crate) mod auth;
+pub(crate) mod bridge;
+pub(crate) mod bulkhead;
+pub(crate) mod capabilities;
+pub(crate) mod cluster;
+pub(crate) mod compact;
+pub(crate) mod config;
+pub(crate) mod disk;
+pub(crate) mod drain;
+pub(crate) mod error_codes;
+pub(crate) mod fanout;
+pub(crate) mod generate;
 pub(crate) mod healthz;
+pub(crate) mod mirror;
 pub(crate) mod queue;
 pub(crate) mod queues;
+pub(crate) mod quiesced;
+pub(crate) mod rate_limit;
+pub(crate) mod replica;
+pub(crate) mod request_id;
+pub(crate) mod schedules;
+pub(crate) mod scrub;
+pub(crate) mod snapshot;
+pub(crate) mod sqs;
+pub(crate) mod subtree;
+pub(crate) mod tenants;
+pub(crate) mod top;
+pub(crate) mod ui;
 
-use axum::http::HeaderMap;
+use crate::auth::Access;
+use crate::auth::AuthProvider;
+use crate::auth::Credentials;
+use crate::auth::Identity;
+use crate::auth::Permission;
+use crate::bridge::Bridge;
+use crate::bulkhead::Bulkheads;
+use crate::cfg::ReloadableCfg;
+use crate::cfg::ReloadableCfgFile;
+use crate::cluster::Cluster;
+use crate::cluster::ClusterReplicator;
+use crate::disk_watchdog::DiskWatchdog;
+use crate::latency::QueueLatency;
+use crate::message_move::MessageMoves;
+use crate::mirror::Mirror;
+use crate::offload::S3Client;
+use crate::offload::S3ContentsStore;
+use crate::push_rate::PushRates;
+use crate::queue_template::QueueTemplate;
+use crate::queue_tree::ancestors;
+use crate::queue_tree::is_in_subtree;
+use crate::queue_tree::queue_dir_name;
+use crate::queue_tree::top_level;
+use crate::rate_limit::RateLimiter;
+use crate::replica::Replica;
+use axum::body::Body;
+use axum::body::Bytes;
+use axum::body::HttpBody;
 use axum::http::StatusCode;
 use axum_msgpack::MsgPack;
 use dashmap::DashMap;
+use itertools::Itertools;
+use libqueued::error_code::ErrorCode;
+use libqueued::offload::ContentsStore;
+use libqueued::quota::SharedQuota;
+use libqueued::replication::Replicator;
 use libqueued::Queued;
+use libqueued::QueuedCfg;
+use parking_lot::RwLock;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
 
 #[derive(Serialize)]
 pub(crate) struct QueuedHttpErrorBody {
-  error: String,
+  // Serialized as the name, which stays the same across all APIs. The number and whether it's retryable are included so that clients don't need the catalog to handle errors they don't know about.
+  pub(crate) error: ErrorCode,
+  error_code: u16,
+  retryable: bool,
   error_details: Option<Box<dyn erased_serde::Serialize>>,
 }
 
@@ -24,61 +90,264 @@ pub(crate) type QueuedHttpError = (StatusCode, MsgPack<QueuedHttpErrorBody>);
 
 pub(crate) type QueuedHttpResult<T> = Result<MsgPack<T>, QueuedHttpError>;
 
-pub(crate) fn qerr(error: impl ToString) -> MsgPack<QueuedHttpErrorBody> {
+pub(crate) fn qerr(error: ErrorCode) -> MsgPack<QueuedHttpErrorBody> {
   MsgPack(QueuedHttpErrorBody {
-    error: error.to_string(),
+    error,
+    error_code: error as u16,
+    retryable: error.is_retryable(),
     error_details: None,
   })
 }
 
+/// Buffers a request body, enforcing the same limit as the endpoints, for middleware that needs to inspect or reuse it.
+pub(crate) async fn read_body(mut body: Body, limit: usize) -> Result<Bytes, StatusCode> {
+  let mut out = Vec::new();
+  while let Some(chunk) = body.data().await {
+    out.extend_from_slice(&chunk.map_err(|_| StatusCode::BAD_REQUEST)?);
+    if out.len() > limit {
+      return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    };
+  }
+  Ok(out.into())
+}
+
 pub(crate) fn qerr_d(
-  error: impl ToString,
+  error: ErrorCode,
   error_details: impl Serialize + 'static,
 ) -> MsgPack<QueuedHttpErrorBody> {
   MsgPack(QueuedHttpErrorBody {
-    error: error.to_string(),
+    error,
+    error_code: error as u16,
+    retryable: error.is_retryable(),
     error_details: Some(Box::new(error_details)),
   })
 }
 
 pub(crate) struct HttpCtx {
-  // Map from API key to prefix. If None, auth for queues is disabled.
-  pub(crate) api_keys: Option<DashMap<String, String>>,
-  pub(crate) batch_sync_delay: Duration,
+  // If None, API keys are disabled. This is also used by a `StaticTokenAuthProvider` in `auth_providers`.
+  pub(crate) api_keys: Option<Arc<DashMap<String, Identity>>>,
+  // If empty, auth for queues is disabled.
+  pub(crate) auth_providers: Vec<Arc<dyn AuthProvider>>,
+  pub(crate) bridge: Option<Arc<Bridge>>,
+  pub(crate) bulkheads: Option<Bulkheads>,
+  pub(crate) cluster: Option<Arc<Cluster>>,
   pub(crate) data_dir: PathBuf,
+  pub(crate) disk_watchdog: Option<DiskWatchdog>,
+  // While draining, pushes are rejected so that the server can be shut down or taken out of rotation without losing messages.
+  pub(crate) draining: AtomicBool,
+  pub(crate) enable_generator: bool,
+  pub(crate) enable_sqs_api: bool,
   pub(crate) global_api_key: Option<String>,
+  // Kept separately from `queues` so that recording latency doesn't need to hold on to a queue.
+  pub(crate) latency: DashMap<String, Arc<QueueLatency>>,
+  pub(crate) max_request_body_size: usize,
+  pub(crate) message_moves: MessageMoves,
+  pub(crate) mirror: Option<Arc<Mirror>>,
+  pub(crate) push_rates: PushRates,
+  // The reloadable settings in this are those on startup; use `reloadable` for their current values.
+  pub(crate) queue_cfg: QueuedCfg,
+  pub(crate) queue_templates: BTreeMap<String, QueueTemplate>,
   // We use Arc because we need to hold a ref to it (i.e. a lock to the map entry) across await points, something that would cause deadlocks in this map.
   pub(crate) queues: DashMap<String, Arc<Queued>>,
+  pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+  pub(crate) reloadable: RwLock<ReloadableCfg>,
+  pub(crate) reloadable_overrides: ReloadableCfgFile,
+  // If set, this server is a read-only replica of another.
+  pub(crate) replica: Option<Arc<Replica>>,
+  pub(crate) s3: Option<Arc<S3Client>>,
+  // Maps the AWS access key ID of an SQS client to the API key used both as its SigV4 secret and as its queued authorization.
+  pub(crate) sqs_access_keys: BTreeMap<String, String>,
+  // Authenticates SQS receipt handles so clients can't forge a handle for a message they never received.
+  pub(crate) sqs_receipt_handle_key: Vec<u8>,
   pub(crate) statsd_endpoint: Option<SocketAddr>,
   pub(crate) statsd_prefix: String,
   pub(crate) statsd_tags: Vec<(String, String)>,
+  // The shared quota of each tenant, which also tracks its usage. If empty, tenants are disabled and queues can have any name.
+  pub(crate) tenants: BTreeMap<String, Arc<SharedQuota>>,
 }
 
 impl HttpCtx {
-  pub(crate) fn q(&self, name: &str, headers: &HeaderMap) -> Result<Arc<Queued>, QueuedHttpError> {
-    if let Some(api_keys) = &self.api_keys {
-      let provided_api_key = headers.get("authorization").and_then(|v| v.to_str().ok());
-      if !provided_api_key
-        .and_then(|k| api_keys.get(k))
-        .is_some_and(|pfx| name.starts_with(pfx.as_str()))
-      {
-        return Err((StatusCode::UNAUTHORIZED, qerr("NotAuthorized")));
-      };
+  pub(crate) fn queue_cfg_for(&self, name: &str) -> QueuedCfg {
+    self.queue_cfg_for_with(name, &self.reloadable.read())
+  }
+
+  /// Like `queue_cfg_for`, but with the reloadable settings in `reloadable` instead of the current ones.
+  pub(crate) fn queue_cfg_for_with(&self, name: &str, reloadable: &ReloadableCfg) -> QueuedCfg {
+    QueuedCfg {
+      contents_store: self
+        .s3
+        .as_ref()
+        .map(|c| Arc::new(S3ContentsStore::new(c.clone(), name)) as Arc<dyn ContentsStore>),
+      replicator: self
+        .cluster
+        .as_ref()
+        .map(|c| Arc::new(ClusterReplicator::new(c.clone(), name)) as Arc<dyn Replicator>),
+      shared_quotas: self
+        .queue_cfg
+        .shared_quotas
+        .iter()
+        .chain(self.tenant_of(name).map(|(_, q)| q))
+        .cloned()
+        .collect(),
+      max_message_size: reloadable.max_message_size,
+      min_visibility_timeout_secs: reloadable.min_visibility_timeout_secs,
+      max_visibility_timeout_secs: reloadable.max_visibility_timeout_secs,
+      release_pacing_max_per_sec: reloadable.release_pacing_max_per_sec,
+      redelivery_backoff: reloadable.redelivery_backoff,
+      ..self.queue_cfg.clone()
+    }
+  }
+
+  /// The tenant that a queue belongs to, if any, and its shared quota.
+  pub(crate) fn tenant_of(&self, name: &str) -> Option<(&str, &Arc<SharedQuota>)> {
+    self
+      .tenants
+      .get_key_value(top_level(name))
+      .map(|(t, q)| (t.as_str(), q))
+  }
+
+  pub(crate) fn queue_dir(&self, name: &str) -> PathBuf {
+    self.data_dir.join(queue_dir_name(name))
+  }
+
+  /// The nearest ancestor of a queue that exists, if any.
+  pub(crate) fn nearest_ancestor(&self, name: &str) -> Option<Arc<Queued>> {
+    ancestors(name).find_map(|a| self.queues.get(a).map(|q| Arc::clone(&*q)))
+  }
+
+  /// All queues that are `root` or its descendants, ordered by name. `root` itself doesn't have to exist. Requests must already be authorized for all queues.
+  pub(crate) fn subtree(&self, root: &str) -> Result<Vec<(String, Arc<Queued>)>, QueuedHttpError> {
+    let queues = self
+      .queues
+      .iter()
+      .filter(|e| is_in_subtree(e.key(), root))
+      .map(|e| (e.key().clone(), Arc::clone(e.value())))
+      .sorted_by(|a, b| a.0.cmp(&b.0))
+      .collect_vec();
+    if queues.is_empty() {
+      return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::QueueNotFound)));
     };
+    Ok(queues)
+  }
+
+  /// Requests must already be authorized, which is done by `auth_middleware` for most endpoints.
+  pub(crate) fn q(&self, name: &str) -> Result<Arc<Queued>, QueuedHttpError> {
     self
       .queues
       .get(name)
       .map(|q| Arc::clone(&*q))
-      .ok_or_else(|| (StatusCode::NOT_FOUND, qerr("QueueNotFound")))
+      .ok_or_else(|| (StatusCode::NOT_FOUND, qerr(ErrorCode::QueueNotFound)))
   }
 
-  pub(crate) fn verify_global_auth(&self, headers: &HeaderMap) -> Result<(), QueuedHttpError> {
-    if let Some(expected_api_key) = &self.global_api_key {
-      let provided_api_key = headers.get("authorization").and_then(|h| h.to_str().ok());
-      if !provided_api_key.is_some_and(|k| k == expected_api_key) {
-        return Err((StatusCode::UNAUTHORIZED, qerr("NotAuthorized")));
+  pub(crate) fn latency_for(&self, name: &str) -> Arc<QueueLatency> {
+    if let Some(l) = self.latency.get(name) {
+      return Arc::clone(&*l);
+    };
+    self
+      .latency
+      .entry(name.to_string())
+      .or_insert_with(|| Arc::new(QueueLatency::new()))
+      .clone()
+  }
+
+  /// Whether writes are made by another node, in which case background tasks that write must not run.
+  pub(crate) fn is_follower(&self) -> bool {
+    self.replica.is_some() || self.cluster.as_ref().is_some_and(|c| !c.is_leader())
+  }
+
+  /// Data-plane operations and queue management must go through the cluster leader, if clustering is enabled, or the primary, if this is a read-only replica.
+  pub(crate) fn verify_leader(&self) -> Result<(), QueuedHttpError> {
+    #[derive(Serialize)]
+    struct NotLeaderDetails {
+      leader_url: Option<String>,
+    }
+
+    if let Some(replica) = &self.replica {
+      return Err((
+        StatusCode::MISDIRECTED_REQUEST,
+        qerr_d(ErrorCode::NotLeader, NotLeaderDetails {
+          leader_url: Some(replica.primary_url.clone()),
+        }),
+      ));
+    };
+    if let Some(cluster) = &self.cluster {
+      if !cluster.is_leader() {
+        return Err((
+          StatusCode::MISDIRECTED_REQUEST,
+          qerr_d(ErrorCode::NotLeader, NotLeaderDetails {
+            leader_url: cluster.leader_url(),
+          }),
+        ));
       };
     };
     Ok(())
   }
+
+  /// Reads of messages must also go through the cluster leader, as followers only update their in-memory indices once they become leader. Read-only replicas keep theirs up to date as they apply writes, so they serve reads themselves, although they lag behind the primary.
+  pub(crate) fn verify_index_readable(&self) -> Result<(), QueuedHttpError> {
+    if self.replica.is_some() {
+      return Ok(());
+    };
+    self.verify_leader()
+  }
+
+  /// Checks that pushes aren't rejected because the server is draining or low on disk space.
+  pub(crate) fn verify_accepting_pushes(&self) -> Result<(), QueuedHttpError> {
+    if self.draining.load(Ordering::Relaxed) {
+      return Err((StatusCode::SERVICE_UNAVAILABLE, qerr(ErrorCode::Draining)));
+    };
+    if self.disk_watchdog.as_ref().is_some_and(|w| w.reject_push()) {
+      return Err((StatusCode::INSUFFICIENT_STORAGE, qerr(ErrorCode::DiskFull)));
+    };
+    Ok(())
+  }
+
+  pub(crate) fn authorize(
+    &self,
+    creds: &Credentials,
+    access: Access,
+  ) -> Result<(), QueuedHttpError> {
+    let provided_api_key = creds
+      .headers
+      .get("authorization")
+      .and_then(|h| h.to_str().ok());
+    // The global API key can always be used.
+    if self.global_api_key.is_some() && provided_api_key == self.global_api_key.as_deref() {
+      return Ok(());
+    };
+    let identity = self
+      .auth_providers
+      .iter()
+      .find_map(|p| p.authenticate(creds));
+    let ok = match access {
+      Access::Public => true,
+      // Only the global API key, which was checked above, is accepted.
+      Access::Internal => false,
+      // For backwards compatibility, server-wide endpoints are open if there's no global API key, even if auth is enabled.
+      Access::Server => {
+        self.global_api_key.is_none() || identity.is_some_and(|i| i.is_server_admin())
+      }
+      // Like using queues, managing them requires an identity once auth is enabled. Without auth, only the global API key (if any) protects it.
+      Access::QueueManagement(name) => {
+        if self.auth_providers.is_empty() {
+          self.global_api_key.is_none()
+        } else {
+          identity.is_some_and(|i| i.allows_queue(name, Permission::Admin))
+        }
+      }
+      Access::Queue(name, p) => {
+        self.auth_providers.is_empty() || identity.is_some_and(|i| i.allows_queue(name, p))
+      }
+      Access::QueueRead(name) => {
+        self.auth_providers.is_empty()
+          || identity.is_some_and(|i| {
+            i.allows_queue(name, Permission::Push) || i.allows_queue(name, Permission::Poll)
+          })
+      }
+    };
+    if !ok {
+      return Err((StatusCode::UNAUTHORIZED, qerr(ErrorCode::NotAuthorized)));
+    };
+    Ok(())
+  }
 }
@@ -0,0 +1,54 @@
+use crate::auth::Access;
+use crate::auth::ClientCertificate;
+use crate::auth::Credentials;
+use crate::auth::Permission;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::Extension;
+use axum_msgpack::MsgPack;
+use libqueued::DebugSamplingState;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct EndpointIO {
+  debug_sampling: Option<DebugSamplingState>,
+}
+
+pub(crate) async fn endpoint_get_debug_sampling(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  Ok(MsgPack(EndpointIO {
+    debug_sampling: q.get_debug_sampling_state(),
+  }))
+}
+
+pub(crate) async fn endpoint_post_debug_sampling(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+  headers: HeaderMap,
+  client_certificate: Option<Extension<ClientCertificate>>,
+  MsgPack(req): MsgPack<EndpointIO>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  if let Some(s) = &req.debug_sampling {
+    // The caller must also be allowed to access the debug queue, as sampled messages will be pushed to it.
+    let creds = Credentials {
+      headers: &headers,
+      client_certificate: client_certificate.as_ref().map(|c| &c.0),
+    };
+    ctx.authorize(&creds, Access::Queue(&s.debug_queue, Permission::Push))?;
+    ctx.q(&s.debug_queue)?;
+  };
+  q.set_debug_sampling(req.debug_sampling);
+  Ok(MsgPack(EndpointIO {
+    debug_sampling: q.get_debug_sampling_state(),
+  }))
+}
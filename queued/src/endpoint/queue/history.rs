@@ -0,0 +1,24 @@
+use crate::endpoint::queue::ops::transform_op_error;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::audit::AuditEvent;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointOutput {
+  /// Oldest first. Empty if the message never existed, or its events have been removed by the retention period.
+  events: Vec<AuditEvent>,
+}
+
+pub(crate) async fn endpoint_message_history(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path((queue_name, id)): Path<(String, u64)>,
+) -> QueuedHttpResult<EndpointOutput> {
+  let q = ctx.q(&queue_name)?;
+  let events = q.audit_history(id).await.map_err(transform_op_error)?;
+  Ok(MsgPack(EndpointOutput { events }))
+}
@@ -13,20 +13,26 @@ pub(crate) async fn endpoint_metrics(
   Path(queue_name): Path<String>,
   headers: HeaderMap,
 ) -> Result<(HeaderMap, Vec<u8>), QueuedHttpError> {
-  let q = ctx.q(&queue_name, &headers)?;
+  let q = ctx.q(&queue_name)?;
   let out = build_metrics(&q);
+  let latency = ctx.latency_for(&queue_name);
+  let prometheus = |with_exemplars: bool| {
+    let mut raw = serde_prometheus::to_string(&out, None, HashMap::new()).unwrap();
+    latency.render(&mut raw, with_exemplars);
+    raw
+  };
   let (ct, raw) = match headers.get("accept").map(|h| h.as_bytes()) {
     Some(b"application/json") => ("application/json", serde_json::to_vec(&out).unwrap()),
     Some(b"application/msgpack") => (
       "application/msgpack",
       rmp_serde::to_vec_named(&out).unwrap(),
     ),
-    _ => (
-      "text/plain",
-      serde_prometheus::to_string(&out, None, HashMap::new())
-        .unwrap()
-        .into_bytes(),
+    // Only OpenMetrics supports exemplars.
+    Some(a) if a.starts_with(b"application/openmetrics-text") => (
+      "application/openmetrics-text; version=1.0.0; charset=utf-8",
+      (prometheus(true) + "# EOF\n").into_bytes(),
     ),
+    _ => ("text/plain", prometheus(false).into_bytes()),
   };
   let mut h = HeaderMap::new();
   h.insert(CONTENT_TYPE, ct.parse().unwrap());
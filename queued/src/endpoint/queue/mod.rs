@@ -1,4 +1,13 @@
+pub(crate) mod debug_sampling;
+pub(crate) mod history;
 pub(crate) mod metrics;
 pub(crate) mod ops;
+pub(crate) mod poll_transform;
+pub(crate) mod routing;
+pub(crate) mod schemas;
 pub(crate) mod suspend;
 pub(crate) mod throttle;
+pub(crate) mod ttl;
+pub(crate) mod visibility_timeout;
+pub(crate) mod watermark;
+pub(crate) mod webhook;
@@ -1,72 +1,842 @@
 use crate::endpoint::qerr;
 use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpError;
 use crate::endpoint::QueuedHttpResult;
+use crate::rate_limit::RateLimitClient;
+use crate::rate_limit::RateLimitKind;
+use axum::body::Body;
+use axum::body::Bytes;
+use axum::body::HttpBody;
+use axum::body::StreamBody;
 use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::RawBody;
 use axum::extract::State;
+use axum::http::header::HeaderName;
+use axum::http::header::ACCEPT;
+use axum::http::header::CONTENT_TYPE;
 use axum::http::HeaderMap;
 use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Extension;
 use axum_msgpack::MsgPack;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use futures::pin_mut;
+use futures::stream::poll_fn;
+use futures::StreamExt;
+use libqueued::attributes::MessageAttributes;
+use libqueued::error_code::ErrorCode;
+use libqueued::op::delete::OpDeleteForcedOutput;
 use libqueued::op::delete::OpDeleteInput;
 use libqueued::op::delete::OpDeleteOutput;
+use libqueued::op::list::OpListInput;
+use libqueued::op::list::OpListOutput;
+use libqueued::op::nack::OpNackInput;
+use libqueued::op::nack::OpNackOutput;
+use libqueued::op::peek::OpPeekInput;
+use libqueued::op::peek::OpPeekOutput;
+use libqueued::op::peek::OpPeekOutputMessage;
+use libqueued::op::pin::OpPinInput;
+use libqueued::op::pin::OpPinOutput;
 use libqueued::op::poll::OpPollInput;
 use libqueued::op::poll::OpPollOutput;
+use libqueued::op::poll::OpPollOutputMessage;
+use libqueued::op::purge::OpPurgeOutput;
 use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
 use libqueued::op::push::OpPushOutput;
 use libqueued::op::result::OpError;
 use libqueued::op::result::OpResult;
+use libqueued::op::takeover::OpTakeoverInput;
+use libqueued::op::takeover::OpTakeoverOutput;
+use libqueued::op::transaction::OpTransactionInput;
+use libqueued::op::transaction::OpTransactionOutput;
 use libqueued::op::update::OpUpdateInput;
 use libqueued::op::update::OpUpdateOutput;
+use libqueued::routing::route;
+use libqueued::Queued;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::io::ErrorKind;
+use std::iter::repeat_n;
+use std::mem::take;
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::spawn;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tracing::instrument;
+use tracing::warn;
 
-fn transform_op_result<R: Serialize>(result: OpResult<R>) -> QueuedHttpResult<R> {
-  result.map(|res| MsgPack(res)).map_err(|err| {
-    let status = match err {
-      OpError::InvalidPollTag => StatusCode::BAD_REQUEST,
-      OpError::MessageNotFound => StatusCode::NOT_FOUND,
-      OpError::Suspended => StatusCode::SERVICE_UNAVAILABLE,
-      OpError::Throttled => StatusCode::TOO_MANY_REQUESTS,
-    };
-    (status, qerr(format!("{err:?}")))
-  })
+pub(crate) fn transform_op_error(err: OpError) -> QueuedHttpError {
+  let status = match err {
+    OpError::AuditLogDisabled => StatusCode::NOT_FOUND,
+    OpError::ExternalIdExists => StatusCode::CONFLICT,
+    OpError::FanoutUnsupported => StatusCode::BAD_REQUEST,
+    OpError::InvalidAttributes => StatusCode::BAD_REQUEST,
+    OpError::InvalidExternalId => StatusCode::BAD_REQUEST,
+    OpError::InvalidGroupId => StatusCode::BAD_REQUEST,
+    OpError::InvalidPollTag => StatusCode::BAD_REQUEST,
+    OpError::InvalidSchedule => StatusCode::BAD_REQUEST,
+    OpError::InvalidVisibilityTimeout => StatusCode::BAD_REQUEST,
+    OpError::MessageNotFound => StatusCode::NOT_FOUND,
+    OpError::MessageTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+    OpError::OffloadFailed => StatusCode::SERVICE_UNAVAILABLE,
+    OpError::QueueFull => StatusCode::INSUFFICIENT_STORAGE,
+    OpError::ReplicationFailed => StatusCode::SERVICE_UNAVAILABLE,
+    OpError::ScheduleNotFound => StatusCode::NOT_FOUND,
+    OpError::StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+    OpError::Suspended => StatusCode::SERVICE_UNAVAILABLE,
+    OpError::Throttled => StatusCode::TOO_MANY_REQUESTS,
+    OpError::TtlUnsupported => StatusCode::BAD_REQUEST,
+    OpError::UnknownSchemaVersion => StatusCode::NOT_FOUND,
+  };
+  (status, qerr(err.into()))
+}
+
+pub(crate) fn transform_op_result<R: Serialize>(result: OpResult<R>) -> QueuedHttpResult<R> {
+  result.map(|res| MsgPack(res)).map_err(transform_op_error)
 }
 
 pub(crate) async fn endpoint_delete(
   State(ctx): State<Arc<HttpCtx>>,
   Path(q): Path<String>,
-  headers: HeaderMap,
   MsgPack(req): MsgPack<OpDeleteInput>,
 ) -> QueuedHttpResult<OpDeleteOutput> {
-  let q = ctx.q(&q, &headers)?;
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
   transform_op_result(q.delete(req).await)
 }
 
+pub(crate) async fn endpoint_delete_forced(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path((q, id)): Path<(String, u64)>,
+) -> QueuedHttpResult<OpDeleteForcedOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.delete_forced(id).await)
+}
+
+pub(crate) async fn endpoint_nack(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+  MsgPack(req): MsgPack<OpNackInput>,
+) -> QueuedHttpResult<OpNackOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.nack(req).await)
+}
+
+pub(crate) async fn endpoint_list(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+  Query(req): Query<OpListInput>,
+) -> QueuedHttpResult<OpListOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_index_readable()?;
+  transform_op_result(q.list(req).await)
+}
+
+pub(crate) async fn endpoint_peek(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+  MsgPack(req): MsgPack<OpPeekInput>,
+) -> QueuedHttpResult<OpPeekOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_index_readable()?;
+  transform_op_result(q.peek(req).await)
+}
+
+pub(crate) async fn endpoint_peek_external_id(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path((q, external_id)): Path<(String, String)>,
+) -> QueuedHttpResult<OpPeekOutputMessage> {
+  let q = ctx.q(&q)?;
+  ctx.verify_index_readable()?;
+  transform_op_result(q.peek_external_id(external_id).await)
+}
+
+pub(crate) async fn endpoint_pin(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+  MsgPack(req): MsgPack<OpPinInput>,
+) -> QueuedHttpResult<OpPinOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.pin(req).await)
+}
+
+/// How the body of a push or poll request or response is encoded: MessagePack like every other endpoint (the default), or CBOR if the `Content-Type` (for the request) or `Accept` (for the response) header is `application/cbor`. Both round trip the same structs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BodyEncoding {
+  MsgPack,
+  Cbor,
+}
+
+impl BodyEncoding {
+  fn from_header(headers: &HeaderMap, name: HeaderName) -> Self {
+    match headers.get(name).and_then(|v| v.to_str().ok()) {
+      Some(v) if v.contains("application/cbor") => BodyEncoding::Cbor,
+      _ => BodyEncoding::MsgPack,
+    }
+  }
+
+  fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, QueuedHttpError> {
+    let res = match self {
+      BodyEncoding::MsgPack => rmp_serde::from_slice(body).ok(),
+      BodyEncoding::Cbor => ciborium::from_reader(body).ok(),
+    };
+    res.ok_or_else(|| (StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidBody)))
+  }
+
+  /// Encodes the output or error body, which are both in the negotiated encoding.
+  fn respond<T: Serialize>(self, res: QueuedHttpResult<T>) -> Response {
+    match self {
+      BodyEncoding::MsgPack => res.into_response(),
+      BodyEncoding::Cbor => {
+        let (status, body) = match res {
+          Ok(MsgPack(out)) => (StatusCode::OK, cbor_value(&out)),
+          Err((status, MsgPack(err))) => (status, cbor_value(&err)),
+        };
+        (status, [(CONTENT_TYPE, "application/cbor")], body).into_response()
+      }
+    }
+  }
+}
+
+fn cbor_value(value: &impl Serialize) -> Bytes {
+  let mut out = Vec::new();
+  ciborium::into_writer(value, &mut out).unwrap();
+  Bytes::from(out)
+}
+
+#[instrument(skip_all, fields(queue = %q))]
 pub(crate) async fn endpoint_poll(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+  headers: HeaderMap,
+  body: Bytes,
+) -> Response {
+  let encoding = BodyEncoding::from_header(&headers, ACCEPT);
+  let req = match BodyEncoding::from_header(&headers, CONTENT_TYPE).decode(&body) {
+    Ok(req) => req,
+    Err(err) => return encoding.respond::<OpPollOutput>(Err(err)),
+  };
+  encoding.respond(poll(&ctx, q, req).await)
+}
+
+async fn poll(ctx: &HttpCtx, q: String, req: OpPollInput) -> QueuedHttpResult<OpPollOutput> {
+  let queue_name = q;
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  let started = Instant::now();
+  let res = q.poll(req).await;
+  ctx.latency_for(&queue_name).poll.observe(started.elapsed());
+  if let Ok(polled) = &res {
+    if let Some((debug_queue, sampled)) = q.sample_for_debug(&polled.messages) {
+      push_debug_samples(ctx, &queue_name, &debug_queue, sampled);
+    };
+  };
+  transform_op_result(res)
+}
+
+#[derive(Serialize)]
+struct PollStreamMessage<'a> {
+  id: u64,
+  poll_tag: u32,
+  poll_count: u32,
+  attributes: &'a MessageAttributes,
+  latency_ms: Option<u64>,
+  group_id: Option<&'a str>,
+  /// Base64 encoded, as JSON has no binary type.
+  contents: String,
+}
+
+/// How the messages of a streamed push or poll are encoded: newline-delimited JSON (the default), or consecutive MessagePack or CBOR values, which avoid base64 encoding contents.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StreamEncoding {
+  Json,
+  MsgPack,
+  Cbor,
+}
+
+impl StreamEncoding {
+  fn from_header(headers: &HeaderMap, name: HeaderName) -> Self {
+    match headers.get(name).and_then(|v| v.to_str().ok()) {
+      Some(v) if v.contains("application/msgpack") => StreamEncoding::MsgPack,
+      // This also matches `application/cbor-seq`.
+      Some(v) if v.contains("application/cbor") => StreamEncoding::Cbor,
+      _ => StreamEncoding::Json,
+    }
+  }
+
+  fn content_type(self) -> &'static str {
+    match self {
+      StreamEncoding::Json => "application/x-ndjson",
+      StreamEncoding::MsgPack => "application/msgpack",
+      StreamEncoding::Cbor => "application/cbor-seq",
+    }
+  }
+
+  fn encode_error(self, err: OpError) -> Bytes {
+    // The same as the body of an error response, as the status has already been sent.
+    let MsgPack(err) = qerr(err.into());
+    match self {
+      StreamEncoding::Json => ndjson_line(&err),
+      StreamEncoding::MsgPack => rmp_serde::to_vec_named(&err).unwrap().into(),
+      StreamEncoding::Cbor => cbor_value(&err),
+    }
+  }
+
+  fn encode_message(self, m: &OpPollOutputMessage) -> Bytes {
+    match self {
+      StreamEncoding::Json => ndjson_line(&PollStreamMessage {
+        id: m.id,
+        poll_tag: m.poll_tag,
+        poll_count: m.poll_count,
+        attributes: &m.attributes,
+        latency_ms: m.latency_ms,
+        group_id: m.group_id.as_deref(),
+        contents: STANDARD.encode(&m.contents),
+      }),
+      StreamEncoding::MsgPack => rmp_serde::to_vec_named(m).unwrap().into(),
+      StreamEncoding::Cbor => cbor_value(m),
+    }
+  }
+
+  /// Parses the next message at the start of `buf`, returning how many bytes it took, or None if more of the body is needed. The message is None if there were only blank lines to skip.
+  fn decode_message(
+    self,
+    buf: &[u8],
+    ended: bool,
+  ) -> Result<Option<(usize, Option<OpPushInputMessage>)>, QueuedHttpError> {
+    let invalid = || (StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidMessage));
+    if buf.is_empty() {
+      return Ok(None);
+    };
+    match self {
+      StreamEncoding::Json => {
+        // The last line doesn't need to end with a newline.
+        let (line, len) = match buf.iter().position(|&b| b == b'\n') {
+          Some(i) => (&buf[..i], i + 1),
+          None if ended => (buf, buf.len()),
+          None => return Ok(None),
+        };
+        let line = line.trim_ascii();
+        if line.is_empty() {
+          return Ok(Some((len, None)));
+        };
+        let m = parse_push_stream_line(line).ok_or_else(invalid)?;
+        Ok(Some((len, Some(m))))
+      }
+      StreamEncoding::MsgPack => {
+        let mut de = rmp_serde::Deserializer::new(Cursor::new(buf));
+        match OpPushInputMessage::deserialize(&mut de) {
+          Ok(m) => Ok(Some((de.get_ref().position() as usize, Some(m)))),
+          Err(
+            rmp_serde::decode::Error::InvalidMarkerRead(err)
+            | rmp_serde::decode::Error::InvalidDataRead(err),
+          ) if !ended && err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+          Err(_) => Err(invalid()),
+        }
+      }
+      StreamEncoding::Cbor => {
+        let mut cur = Cursor::new(buf);
+        match ciborium::from_reader::<OpPushInputMessage, _>(&mut cur) {
+          Ok(m) => Ok(Some((cur.position() as usize, Some(m)))),
+          Err(ciborium::de::Error::Io(err)) if !ended && err.kind() == ErrorKind::UnexpectedEof => {
+            Ok(None)
+          }
+          Err(_) => Err(invalid()),
+        }
+      }
+    }
+  }
+}
+
+fn ndjson_line(value: &impl Serialize) -> Bytes {
+  let mut line = serde_json::to_vec(value).unwrap();
+  line.push(b'\n');
+  Bytes::from(line)
+}
+
+/// Like `endpoint_poll`, but writes each message as soon as it's been read, as a line of JSON (NDJSON), or as a MessagePack or CBOR value if the `Accept` header allows `application/msgpack` or `application/cbor-seq`. The status is only sent once the poll has been committed. If a message can't be read, an object with an `error` field is written instead and the response ends, but every message requested is still polled.
+pub(crate) async fn endpoint_poll_stream(
   State(ctx): State<Arc<HttpCtx>>,
   Path(q): Path<String>,
   headers: HeaderMap,
   MsgPack(req): MsgPack<OpPollInput>,
-) -> QueuedHttpResult<OpPollOutput> {
-  let q = ctx.q(&q, &headers)?;
-  transform_op_result(q.poll(req).await)
+) -> Result<Response, QueuedHttpError> {
+  let encoding = StreamEncoding::from_header(&headers, ACCEPT);
+  let queue_name = q;
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  let started = Instant::now();
+  let (started_tx, started_rx) = oneshot::channel();
+  // This is bounded so that messages aren't read faster than the client receives them.
+  let (lines_tx, mut lines_rx) = channel::<Bytes>(16);
+  // The stream borrows the queue, so it's driven by a task that owns it.
+  spawn({
+    let ctx = ctx.clone();
+    let queue_name = queue_name.clone();
+    async move {
+      let messages = match q.poll_stream(req).await {
+        Ok(messages) => messages,
+        Err(err) => {
+          let _ = started_tx.send(Err(err));
+          return;
+        }
+      };
+      let _ = started_tx.send(Ok(()));
+      pin_mut!(messages);
+      while let Some(res) = messages.next().await {
+        let m = match res {
+          Ok(m) => m,
+          Err(err) => {
+            let _ = lines_tx.send(encoding.encode_error(err)).await;
+            break;
+          }
+        };
+        if let Some((debug_queue, sampled)) = q.sample_for_debug([&m]) {
+          push_debug_samples(&ctx, &queue_name, &debug_queue, sampled);
+        };
+        let line = encoding.encode_message(&m);
+        // The client has gone away, so there's no point reading the rest; they'll become visible again once their visibility timeout passes.
+        if lines_tx.send(line).await.is_err() {
+          break;
+        };
+      }
+    }
+  });
+  // The task only drops the sender without sending if it panicked.
+  started_rx.await.unwrap().map_err(transform_op_error)?;
+  ctx.latency_for(&queue_name).poll.observe(started.elapsed());
+  let body = StreamBody::new(poll_fn(move |cx| {
+    lines_rx
+      .poll_recv(cx)
+      .map(|line| line.map(Ok::<_, Infallible>))
+  }));
+  Ok(([(CONTENT_TYPE, encoding.content_type())], body).into_response())
+}
+
+#[derive(Serialize)]
+struct DebugSample<'a> {
+  source_queue: &'a str,
+  id: u64,
+  poll_count: u32,
+  poll_tag: u32,
+  sampled_at: i64,
+  attributes: &'a MessageAttributes,
+  #[serde(with = "serde_bytes")]
+  contents: &'a [u8],
+}
+
+// This is best effort and happens in the background, as it must not slow down or fail the poll.
+fn push_debug_samples(
+  ctx: &HttpCtx,
+  source_queue: &str,
+  debug_queue: &str,
+  sampled: Vec<&OpPollOutputMessage>,
+) {
+  let Some(dq) = ctx.queues.get(debug_queue).map(|q| Arc::clone(&*q)) else {
+    warn!(source_queue, debug_queue, "debug queue not found");
+    return;
+  };
+  let sampled_at = Utc::now().timestamp();
+  let messages = sampled
+    .into_iter()
+    .map(|m| OpPushInputMessage {
+      contents: rmp_serde::to_vec_named(&DebugSample {
+        source_queue,
+        id: m.id,
+        poll_count: m.poll_count,
+        poll_tag: m.poll_tag,
+        sampled_at,
+        attributes: &m.attributes,
+        contents: &m.contents,
+      })
+      .unwrap(),
+      visibility_timeout_secs: 0,
+      visibility_jitter_secs: 0,
+      priority: 0,
+      attributes: Default::default(),
+      ttl_secs: None,
+      group_id: None,
+      external_id: None,
+    })
+    .collect();
+  let source_queue = source_queue.to_string();
+  let debug_queue = debug_queue.to_string();
+  spawn(async move {
+    if let Err(err) = dq.push(OpPushInput { messages }).await {
+      warn!(
+        source_queue,
+        debug_queue,
+        error = format!("{err:?}"),
+        "failed to push debug samples"
+      );
+    };
+  });
+}
+
+pub(crate) async fn endpoint_purge(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+) -> QueuedHttpResult<OpPurgeOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.purge().await)
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointPushOutput {
+  ids: Vec<u64>,
+  /// The queue each message was pushed to. Only present if the queue has routing rules, as IDs are only unique within a queue.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  queues: Option<Vec<String>>,
 }
 
+// Pushes messages to the queues chosen by the queue's routing rules, if any.
+async fn push_routed(
+  ctx: &HttpCtx,
+  queue_name: &str,
+  q: &Queued,
+  messages: Vec<OpPushInputMessage>,
+) -> Result<EndpointPushOutput, QueuedHttpError> {
+  let rules = q.get_routing_rules();
+  if rules.is_empty() {
+    let started = Instant::now();
+    let res = q.push(OpPushInput { messages }).await;
+    ctx.latency_for(queue_name).push.observe(started.elapsed());
+    let MsgPack(OpPushOutput { ids }) = transform_op_result(res)?;
+    return Ok(EndpointPushOutput { ids, queues: None });
+  };
+
+  // Group messages by destination, remembering their positions so that results are in request order. Rules of destination queues aren't applied, so routing can't loop.
+  let n = messages.len();
+  let mut by_queue = BTreeMap::<String, Vec<(usize, OpPushInputMessage)>>::new();
+  for (i, m) in messages.into_iter().enumerate() {
+    let dest = route(&rules, &m.attributes).unwrap_or(queue_name);
+    by_queue.entry(dest.to_string()).or_default().push((i, m));
+  }
+  // Look up all destinations first, so that a missing one doesn't fail the request after some messages were already pushed.
+  let dests = by_queue
+    .into_iter()
+    .map(|(name, msgs)| Ok((ctx.q(&name)?, name, msgs)))
+    .collect::<Result<Vec<_>, QueuedHttpError>>()?;
+  let mut ids = vec![0; n];
+  let mut queues = vec![String::new(); n];
+  for (dq, name, msgs) in dests {
+    let (positions, messages): (Vec<_>, Vec<_>) = msgs.into_iter().unzip();
+    let started = Instant::now();
+    let res = dq.push(OpPushInput { messages }).await;
+    ctx.latency_for(&name).push.observe(started.elapsed());
+    let MsgPack(out) = transform_op_result(res)?;
+    for (i, id) in positions.into_iter().zip(out.ids) {
+      ids[i] = id;
+      queues[i] = name.clone();
+    }
+  }
+  Ok(EndpointPushOutput {
+    ids,
+    queues: Some(queues),
+  })
+}
+
+#[instrument(skip_all, fields(queue = %q))]
 pub(crate) async fn endpoint_push(
   State(ctx): State<Arc<HttpCtx>>,
   Path(q): Path<String>,
   headers: HeaderMap,
-  MsgPack(req): MsgPack<OpPushInput>,
-) -> QueuedHttpResult<OpPushOutput> {
-  let q = ctx.q(&q, &headers)?;
-  transform_op_result(q.push(req).await)
+  body: Bytes,
+) -> Response {
+  let encoding = BodyEncoding::from_header(&headers, ACCEPT);
+  let req = match BodyEncoding::from_header(&headers, CONTENT_TYPE).decode(&body) {
+    Ok(req) => req,
+    Err(err) => return encoding.respond::<EndpointPushOutput>(Err(err)),
+  };
+  encoding.respond(push(&ctx, q, req).await)
 }
 
-pub(crate) async fn endpoint_update(
+async fn push(ctx: &HttpCtx, q: String, req: OpPushInput) -> QueuedHttpResult<EndpointPushOutput> {
+  let queue_name = q;
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  ctx.verify_accepting_pushes()?;
+  Ok(MsgPack(
+    push_routed(ctx, &queue_name, &q, req.messages).await?,
+  ))
+}
+
+// Messages of a streamed push are pushed in batches of up to this many, or fewer if they're larger than the request body limit in total.
+const PUSH_STREAM_BATCH: usize = 1000;
+
+#[derive(Deserialize)]
+struct PushStreamMessage {
+  /// Base64 encoded, as JSON has no binary type.
+  contents: String,
+  visibility_timeout_secs: u32,
+  #[serde(default)]
+  visibility_jitter_secs: u32,
+  #[serde(default)]
+  priority: u8,
+  #[serde(default)]
+  attributes: MessageAttributes,
+  #[serde(default)]
+  ttl_secs: Option<u32>,
+  #[serde(default)]
+  group_id: Option<String>,
+  #[serde(default)]
+  external_id: Option<String>,
+}
+
+fn parse_push_stream_line(line: &[u8]) -> Option<OpPushInputMessage> {
+  let m = serde_json::from_slice::<PushStreamMessage>(line).ok()?;
+  Some(OpPushInputMessage {
+    contents: STANDARD.decode(m.contents).ok()?,
+    visibility_timeout_secs: m.visibility_timeout_secs,
+    visibility_jitter_secs: m.visibility_jitter_secs,
+    priority: m.priority,
+    attributes: m.attributes,
+    ttl_secs: m.ttl_secs,
+    group_id: m.group_id,
+    external_id: m.external_id,
+  })
+}
+
+/// Written for each message of a streamed push once it has been pushed, if acknowledgements were requested.
+#[derive(Serialize)]
+struct PushStreamAck<'a> {
+  id: u64,
+  /// Only present if the queue has routing rules, like `EndpointPushOutput::queues`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  queue: Option<&'a str>,
+}
+
+struct PushStream<'a> {
+  ctx: &'a HttpCtx,
+  encoding: StreamEncoding,
+  // If set, each message is acknowledged as soon as it has been pushed, instead of with a single response once the body has ended, and `pushed` isn't used.
+  acks: Option<Sender<Bytes>>,
+  queue_name: &'a str,
+  q: &'a Queued,
+  rate_limit_client: Option<RateLimitClient>,
+  batch: Vec<OpPushInputMessage>,
+  batch_size: usize,
+  pushed: EndpointPushOutput,
+}
+
+impl<'a> PushStream<'a> {
+  async fn add(&mut self, m: OpPushInputMessage) -> Result<(), QueuedHttpError> {
+    self.batch_size += m.contents.len();
+    self.batch.push(m);
+    if self.batch.len() >= PUSH_STREAM_BATCH || self.batch_size >= self.ctx.max_request_body_size {
+      self.flush().await?;
+    };
+    Ok(())
+  }
+
+  async fn flush(&mut self) -> Result<(), QueuedHttpError> {
+    if self.batch.is_empty() {
+      return Ok(());
+    };
+    // The middleware only counts the request, as it can't count the messages without buffering the body.
+    if let (Some(limiter), Some(client)) = (&self.ctx.rate_limiter, &self.rate_limit_client) {
+      if limiter
+        .take(client, &[(
+          RateLimitKind::PushMessages,
+          self.batch.len() as u64,
+        )])
+        .is_err()
+      {
+        self.q.metrics().record_rate_limited_push();
+        return Err((StatusCode::TOO_MANY_REQUESTS, qerr(ErrorCode::RateLimited)));
+      };
+    };
+    self.batch_size = 0;
+    let out = push_routed(self.ctx, self.queue_name, self.q, take(&mut self.batch)).await?;
+    if let Some(acks) = &self.acks {
+      for (i, &id) in out.ids.iter().enumerate() {
+        let line = ndjson_line(&PushStreamAck {
+          id,
+          queue: out.queues.as_ref().map(|queues| queues[i].as_str()),
+        });
+        // The client has gone away, so there's no point pushing the rest of the body, and nobody will see this error.
+        if acks.send(line).await.is_err() {
+          return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidBody)));
+        };
+      }
+      return Ok(());
+    };
+    // Routing rules can change between batches, so fill in the queue of batches pushed without them.
+    let pushed = &mut self.pushed;
+    match (&mut pushed.queues, out.queues) {
+      (None, None) => {}
+      (Some(queues), None) => queues.extend(repeat_n(self.queue_name.to_string(), out.ids.len())),
+      (queues @ None, Some(new)) => {
+        let mut all = vec![self.queue_name.to_string(); pushed.ids.len()];
+        all.extend(new);
+        *queues = Some(all);
+      }
+      (Some(queues), Some(new)) => queues.extend(new),
+    };
+    pushed.ids.extend(out.ids);
+    Ok(())
+  }
+
+  async fn push_body(&mut self, mut body: Body) -> Result<(), QueuedHttpError> {
+    let mut buf = Vec::new();
+    loop {
+      let chunk = body.data().await;
+      let ended = chunk.is_none();
+      if let Some(chunk) = chunk {
+        buf.extend_from_slice(
+          &chunk.map_err(|_| (StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidBody)))?,
+        );
+      };
+      let mut start = 0;
+      loop {
+        let Some((len, m)) = self.encoding.decode_message(&buf[start..], ended)? else {
+          break;
+        };
+        start += len;
+        if let Some(m) = m {
+          self.add(m).await?;
+        };
+      }
+      buf.drain(..start);
+      if ended {
+        break;
+      };
+      // Producers streaming messages over a long-lived request expect them to be acknowledged promptly, so don't wait for a full batch.
+      if self.acks.is_some() {
+        self.flush().await?;
+      };
+      if buf.len() > self.ctx.max_request_body_size {
+        return Err((
+          StatusCode::PAYLOAD_TOO_LARGE,
+          qerr(ErrorCode::MessageTooLarge),
+        ));
+      };
+    }
+    self.flush().await
+  }
+}
+
+/// Like `endpoint_push`, but the body is newline-delimited JSON (NDJSON) with one message per line, or consecutive MessagePack or CBOR values if the `Content-Type` is `application/msgpack` or `application/cbor-seq`, which are parsed and pushed in batches as it's received, so memory is bounded regardless of how many messages there are. Each line can be at most the request body limit, but the body as a whole isn't limited. Batches are pushed separately, so if one fails, the error has the result of the messages pushed before it as its details.
+///
+/// If the `Accept` header allows `application/x-ndjson`, the response starts immediately instead, and has a line with the ID of each message as soon as it has been pushed, in the same order as the body. Whatever has been received is pushed whenever the body stalls, so a producer can keep a single request open and stream messages over it. If a batch fails, a final line with an `error` field is written and the response ends.
+#[instrument(skip_all, fields(queue = %q))]
+pub(crate) async fn endpoint_push_stream(
   State(ctx): State<Arc<HttpCtx>>,
   Path(q): Path<String>,
+  rate_limit_client: Option<Extension<RateLimitClient>>,
   headers: HeaderMap,
+  RawBody(body): RawBody,
+) -> Result<Response, QueuedHttpError> {
+  let queue_name = q;
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  ctx.verify_accepting_pushes()?;
+  let encoding = StreamEncoding::from_header(&headers, CONTENT_TYPE);
+  let rate_limit_client = rate_limit_client.map(|c| c.0);
+  let wants_acks = headers
+    .get(ACCEPT)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.contains("application/x-ndjson"));
+  if wants_acks {
+    // This is bounded so that the body isn't read faster than the client receives acknowledgements.
+    let (acks_tx, mut acks_rx) = channel::<Bytes>(16);
+    // The stream borrows the queue, so it's driven by a task that owns it.
+    spawn(async move {
+      let mut stream = PushStream {
+        ctx: &ctx,
+        encoding,
+        acks: Some(acks_tx.clone()),
+        queue_name: &queue_name,
+        q: &q,
+        rate_limit_client,
+        batch: Vec::new(),
+        batch_size: 0,
+        pushed: EndpointPushOutput {
+          ids: Vec::new(),
+          queues: None,
+        },
+      };
+      // The same as the body of an error response, as the status has already been sent. The error isn't Send, so it's encoded before sending.
+      let error = match stream.push_body(body).await {
+        Ok(()) => None,
+        Err((_, MsgPack(err))) => Some(ndjson_line(&err)),
+      };
+      if let Some(line) = error {
+        let _ = acks_tx.send(line).await;
+      };
+    });
+    let body = StreamBody::new(poll_fn(move |cx| {
+      acks_rx
+        .poll_recv(cx)
+        .map(|line| line.map(Ok::<_, Infallible>))
+    }));
+    return Ok(([(CONTENT_TYPE, StreamEncoding::Json.content_type())], body).into_response());
+  };
+  let mut stream = PushStream {
+    ctx: &ctx,
+    encoding,
+    acks: None,
+    queue_name: &queue_name,
+    q: &q,
+    rate_limit_client,
+    batch: Vec::new(),
+    batch_size: 0,
+    pushed: EndpointPushOutput {
+      ids: Vec::new(),
+      queues: None,
+    },
+  };
+  match stream.push_body(body).await {
+    Ok(()) => Ok(MsgPack(stream.pushed).into_response()),
+    Err((status, MsgPack(mut err))) => {
+      err.error_details = Some(Box::new(stream.pushed));
+      Err((status, MsgPack(err)))
+    }
+  }
+}
+
+pub(crate) async fn endpoint_takeover(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+  MsgPack(req): MsgPack<OpTakeoverInput>,
+) -> QueuedHttpResult<OpTakeoverOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.takeover(req).await)
+}
+
+pub(crate) async fn endpoint_transaction(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
+  MsgPack(req): MsgPack<OpTransactionInput>,
+) -> QueuedHttpResult<OpTransactionOutput> {
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
+  ctx.verify_accepting_pushes()?;
+  transform_op_result(q.transaction(req).await)
+}
+
+pub(crate) async fn endpoint_update(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(q): Path<String>,
   MsgPack(req): MsgPack<OpUpdateInput>,
 ) -> QueuedHttpResult<OpUpdateOutput> {
-  let q = ctx.q(&q, &headers)?;
+  let q = ctx.q(&q)?;
+  ctx.verify_leader()?;
   transform_op_result(q.update(req).await)
 }
@@ -0,0 +1,51 @@
+use crate::endpoint::subtree::EndpointSubtreeOutput;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::transform::PollTransform;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct EndpointIO {
+  poll_transform: Option<PollTransform>,
+}
+
+pub(crate) async fn endpoint_get_poll_transform(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  Ok(MsgPack(EndpointIO {
+    poll_transform: q.get_poll_transform(),
+  }))
+}
+
+pub(crate) async fn endpoint_post_poll_transform(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+  MsgPack(req): MsgPack<EndpointIO>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  q.set_poll_transform(req.poll_transform);
+  Ok(MsgPack(EndpointIO {
+    poll_transform: q.get_poll_transform(),
+  }))
+}
+
+pub(crate) async fn endpoint_post_subtree_poll_transform(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(root): Path<String>,
+  MsgPack(req): MsgPack<EndpointIO>,
+) -> QueuedHttpResult<EndpointSubtreeOutput> {
+  let mut queues = Vec::new();
+  for (name, q) in ctx.subtree(&root)? {
+    q.set_poll_transform(req.poll_transform.clone());
+    queues.push(name);
+  }
+  Ok(MsgPack(EndpointSubtreeOutput { queues }))
+}
@@ -0,0 +1,49 @@
+use crate::endpoint::qerr_d;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use libqueued::routing::RoutingRule;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct EndpointIO {
+  rules: Vec<RoutingRule>,
+}
+
+pub(crate) async fn endpoint_get_routing(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  Ok(MsgPack(EndpointIO {
+    rules: q.get_routing_rules(),
+  }))
+}
+
+pub(crate) async fn endpoint_post_routing(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+  MsgPack(req): MsgPack<EndpointIO>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  // The destination queues are checked again when pushing, as they may be deleted later.
+  if let Some(i) = req.rules.iter().position(|r| {
+    r.attribute.is_empty() || r.queue == queue_name || !ctx.queues.contains_key(&r.queue)
+  }) {
+    return Err((
+      StatusCode::BAD_REQUEST,
+      qerr_d(ErrorCode::InvalidRoutingRule, i),
+    ));
+  };
+  q.set_routing_rules(req.rules);
+  Ok(MsgPack(EndpointIO {
+    rules: q.get_routing_rules(),
+  }))
+}
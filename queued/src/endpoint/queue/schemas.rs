@@ -0,0 +1,37 @@
+use crate::endpoint::queue::ops::transform_op_result;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::op::schema::OpGetSchemaOutput;
+use libqueued::op::schema::OpListSchemasOutput;
+use libqueued::op::schema::OpRegisterSchemaInput;
+use libqueued::op::schema::OpRegisterSchemaOutput;
+use std::sync::Arc;
+
+pub(crate) async fn endpoint_list_schemas(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<OpListSchemasOutput> {
+  let q = ctx.q(&queue_name)?;
+  transform_op_result(q.list_schemas().await)
+}
+
+pub(crate) async fn endpoint_register_schema(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+  MsgPack(req): MsgPack<OpRegisterSchemaInput>,
+) -> QueuedHttpResult<OpRegisterSchemaOutput> {
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.register_schema(req).await)
+}
+
+pub(crate) async fn endpoint_get_schema(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path((queue_name, version)): Path<(String, u32)>,
+) -> QueuedHttpResult<OpGetSchemaOutput> {
+  let q = ctx.q(&queue_name)?;
+  transform_op_result(q.get_schema(version).await)
+}
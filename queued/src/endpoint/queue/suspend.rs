@@ -1,12 +1,18 @@
+use crate::endpoint::qerr;
+use crate::endpoint::queue::ops::transform_op_result;
+use crate::endpoint::subtree::EndpointSubtreeOutput;
 use crate::endpoint::HttpCtx;
 use crate::endpoint::QueuedHttpResult;
 use axum::extract::Path;
 use axum::extract::State;
-use axum::http::HeaderMap;
+use axum::http::StatusCode;
 use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use libqueued::suspend::SuspensionChange;
 use libqueued::Queued;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 #[derive(Serialize)]
@@ -29,40 +35,79 @@ fn get_suspend_state(q: &Queued) -> SuspendState {
 pub(crate) async fn endpoint_get_suspend(
   State(ctx): State<Arc<HttpCtx>>,
   Path(queue_name): Path<String>,
-  headers: HeaderMap,
 ) -> QueuedHttpResult<SuspendState> {
-  let q = ctx.q(&queue_name, &headers)?;
+  let q = ctx.q(&queue_name)?;
   Ok(MsgPack(get_suspend_state(&q)))
 }
 
-#[derive(Deserialize, Default)]
-#[serde(default)]
-pub(crate) struct EndpointPostSuspendInput {
-  delete: Option<bool>,
-  poll: Option<bool>,
-  push: Option<bool>,
-  update: Option<bool>,
-}
-
 pub(crate) async fn endpoint_post_suspend(
   State(ctx): State<Arc<HttpCtx>>,
   Path(queue_name): Path<String>,
-  headers: HeaderMap,
-  MsgPack(req): MsgPack<EndpointPostSuspendInput>,
+  MsgPack(req): MsgPack<SuspensionChange>,
 ) -> QueuedHttpResult<SuspendState> {
-  let q = ctx.q(&queue_name, &headers)?;
-  if let Some(s) = req.delete {
-    q.suspension().set_delete_suspension(s);
-  };
-  if let Some(s) = req.poll {
-    q.suspension().set_poll_suspension(s);
-  };
-  if let Some(s) = req.push {
-    q.suspension().set_push_suspension(s);
-  };
-  if let Some(s) = req.update {
-    q.suspension().set_update_suspension(s);
-  };
-
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.set_suspension(req).await)?;
   Ok(MsgPack(get_suspend_state(&q)))
 }
+
+pub(crate) async fn endpoint_post_subtree_suspend(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(root): Path<String>,
+  MsgPack(req): MsgPack<SuspensionChange>,
+) -> QueuedHttpResult<EndpointSubtreeOutput> {
+  ctx.verify_leader()?;
+  let subtree = ctx.subtree(&root)?;
+  let mut queues = Vec::new();
+  for (name, q) in subtree {
+    transform_op_result(q.set_suspension(req).await)?;
+    queues.push(name);
+  }
+  Ok(MsgPack(EndpointSubtreeOutput { queues }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointSuspensionMatrixOutput {
+  queues: BTreeMap<String, SuspendState>,
+}
+
+fn suspension_matrix(ctx: &HttpCtx) -> EndpointSuspensionMatrixOutput {
+  EndpointSuspensionMatrixOutput {
+    queues: ctx
+      .queues
+      .iter()
+      .map(|e| (e.key().clone(), get_suspend_state(e.value())))
+      .collect(),
+  }
+}
+
+pub(crate) async fn endpoint_get_suspension_matrix(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<EndpointSuspensionMatrixOutput> {
+  Ok(MsgPack(suspension_matrix(&ctx)))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointPutSuspensionMatrixInput {
+  /// Queues that aren't listed are left as they are.
+  queues: BTreeMap<String, SuspensionChange>,
+}
+
+pub(crate) async fn endpoint_put_suspension_matrix(
+  State(ctx): State<Arc<HttpCtx>>,
+  MsgPack(req): MsgPack<EndpointPutSuspensionMatrixInput>,
+) -> QueuedHttpResult<EndpointSuspensionMatrixOutput> {
+  ctx.verify_leader()?;
+  // Check every queue before changing any, so that a typo doesn't leave the change half applied.
+  let mut changes = Vec::new();
+  for (name, change) in req.queues {
+    let Some(q) = ctx.queues.get(&name).map(|q| Arc::clone(&*q)) else {
+      return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::QueueNotFound)));
+    };
+    changes.push((q, change));
+  }
+  for (q, change) in changes {
+    transform_op_result(q.set_suspension(change).await)?;
+  }
+  Ok(MsgPack(suspension_matrix(&ctx)))
+}
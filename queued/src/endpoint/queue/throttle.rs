@@ -1,8 +1,8 @@
+use crate::endpoint::subtree::EndpointSubtreeOutput;
 use crate::endpoint::HttpCtx;
 use crate::endpoint::QueuedHttpResult;
 use axum::extract::Path;
 use axum::extract::State;
-use axum::http::HeaderMap;
 use axum_msgpack::MsgPack;
 use libqueued::ThrottleState;
 use serde::Deserialize;
@@ -18,9 +18,8 @@ pub(crate) struct EndpointIO {
 pub(crate) async fn endpoint_get_throttle(
   State(ctx): State<Arc<HttpCtx>>,
   Path(queue_name): Path<String>,
-  headers: HeaderMap,
 ) -> QueuedHttpResult<EndpointIO> {
-  let q = ctx.q(&queue_name, &headers)?;
+  let q = ctx.q(&queue_name)?;
   Ok(MsgPack(EndpointIO {
     throttle: q.get_throttle_state(),
   }))
@@ -29,12 +28,24 @@ pub(crate) async fn endpoint_get_throttle(
 pub(crate) async fn endpoint_post_throttle(
   State(ctx): State<Arc<HttpCtx>>,
   Path(queue_name): Path<String>,
-  headers: HeaderMap,
   MsgPack(req): MsgPack<EndpointIO>,
 ) -> QueuedHttpResult<EndpointIO> {
-  let q = ctx.q(&queue_name, &headers)?;
+  let q = ctx.q(&queue_name)?;
   q.set_throttle(req.throttle);
   Ok(MsgPack(EndpointIO {
     throttle: q.get_throttle_state(),
   }))
 }
+
+pub(crate) async fn endpoint_post_subtree_throttle(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(root): Path<String>,
+  MsgPack(req): MsgPack<EndpointIO>,
+) -> QueuedHttpResult<EndpointSubtreeOutput> {
+  let mut queues = Vec::new();
+  for (name, q) in ctx.subtree(&root)? {
+    q.set_throttle(req.throttle);
+    queues.push(name);
+  }
+  Ok(MsgPack(EndpointSubtreeOutput { queues }))
+}
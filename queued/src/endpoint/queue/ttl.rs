@@ -0,0 +1,43 @@
+use crate::endpoint::queue::ops::transform_op_result;
+use crate::endpoint::subtree::EndpointSubtreeOutput;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::op::ttl::DefaultTtlState;
+use std::sync::Arc;
+
+pub(crate) async fn endpoint_get_ttl(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<DefaultTtlState> {
+  let q = ctx.q(&queue_name)?;
+  Ok(MsgPack(q.get_default_ttl()))
+}
+
+pub(crate) async fn endpoint_post_ttl(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+  MsgPack(req): MsgPack<DefaultTtlState>,
+) -> QueuedHttpResult<DefaultTtlState> {
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.set_default_ttl(req).await)?;
+  Ok(MsgPack(q.get_default_ttl()))
+}
+
+pub(crate) async fn endpoint_post_subtree_ttl(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(root): Path<String>,
+  MsgPack(req): MsgPack<DefaultTtlState>,
+) -> QueuedHttpResult<EndpointSubtreeOutput> {
+  ctx.verify_leader()?;
+  let subtree = ctx.subtree(&root)?;
+  let mut queues = Vec::new();
+  for (name, q) in subtree {
+    transform_op_result(q.set_default_ttl(req).await)?;
+    queues.push(name);
+  }
+  Ok(MsgPack(EndpointSubtreeOutput { queues }))
+}
@@ -0,0 +1,52 @@
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::Queued;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointOutput {
+  auto: bool,
+  samples: u64,
+  p50_secs: Option<i64>,
+  p99_secs: Option<i64>,
+  recommended_secs: Option<i64>,
+}
+
+fn output(q: &Queued) -> EndpointOutput {
+  let times = q.metrics().processing_times();
+  EndpointOutput {
+    auto: q.get_auto_visibility_timeout(),
+    samples: times.samples(),
+    p50_secs: times.percentile_secs(0.5),
+    p99_secs: times.percentile_secs(0.99),
+    recommended_secs: q.recommended_visibility_timeout_secs(),
+  }
+}
+
+pub(crate) async fn endpoint_get_visibility_timeout(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<EndpointOutput> {
+  let q = ctx.q(&queue_name)?;
+  Ok(MsgPack(output(&q)))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointInput {
+  auto: bool,
+}
+
+pub(crate) async fn endpoint_post_visibility_timeout(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+  MsgPack(req): MsgPack<EndpointInput>,
+) -> QueuedHttpResult<EndpointOutput> {
+  let q = ctx.q(&queue_name)?;
+  q.set_auto_visibility_timeout(req.auto);
+  Ok(MsgPack(output(&q)))
+}
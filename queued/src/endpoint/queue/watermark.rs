@@ -0,0 +1,17 @@
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::VisibilityWatermark;
+use std::sync::Arc;
+
+pub(crate) async fn endpoint_visibility_watermark(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<VisibilityWatermark> {
+  // Followers don't keep their in-memory state up to date.
+  ctx.verify_index_readable()?;
+  let q = ctx.q(&queue_name)?;
+  Ok(MsgPack(q.visibility_watermark()))
+}
@@ -0,0 +1,78 @@
+use crate::endpoint::qerr;
+use crate::endpoint::subtree::EndpointSubtreeOutput;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use libqueued::webhook::WebhookCfg;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct EndpointIO {
+  webhook: Option<WebhookCfg>,
+}
+
+pub(crate) fn webhook_is_valid(ctx: &HttpCtx, queue_name: &str, w: Option<&WebhookCfg>) -> bool {
+  let Some(w) = w else {
+    return true;
+  };
+  // Like routing rules, the dead letter queue is checked again when moving messages to it, as it may be deleted later.
+  (w.url.starts_with("http://") || w.url.starts_with("https://"))
+    && w.max_attempts > 0
+    && w
+      .dead_letter_queue
+      .as_ref()
+      .is_none_or(|n| n != queue_name && ctx.queues.contains_key(n))
+}
+
+pub(crate) async fn endpoint_get_webhook(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  Ok(MsgPack(EndpointIO {
+    webhook: q.get_webhook(),
+  }))
+}
+
+pub(crate) async fn endpoint_post_webhook(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue_name): Path<String>,
+  MsgPack(req): MsgPack<EndpointIO>,
+) -> QueuedHttpResult<EndpointIO> {
+  let q = ctx.q(&queue_name)?;
+  if !webhook_is_valid(&ctx, &queue_name, req.webhook.as_ref()) {
+    return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidWebhook)));
+  };
+  q.set_webhook(req.webhook);
+  Ok(MsgPack(EndpointIO {
+    webhook: q.get_webhook(),
+  }))
+}
+
+pub(crate) async fn endpoint_post_subtree_webhook(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(root): Path<String>,
+  MsgPack(req): MsgPack<EndpointIO>,
+) -> QueuedHttpResult<EndpointSubtreeOutput> {
+  let queues = ctx.subtree(&root)?;
+  // Check every queue before changing any, so that the subtree isn't left partially configured.
+  if queues
+    .iter()
+    .any(|(name, _)| !webhook_is_valid(&ctx, name, req.webhook.as_ref()))
+  {
+    return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidWebhook)));
+  };
+  for (_, q) in queues.iter() {
+    q.set_webhook(req.webhook.clone());
+  }
+  Ok(MsgPack(EndpointSubtreeOutput {
+    queues: queues.into_iter().map(|(n, _)| n).collect(),
+  }))
+}
@@ -1,18 +1,29 @@
 use super::HttpCtx;
+use super::QueuedHttpError;
 use super::QueuedHttpResult;
 use crate::endpoint::qerr;
 use crate::endpoint::qerr_d;
+use crate::endpoint::queue::webhook::webhook_is_valid;
+use crate::queue_template::apply_template;
+use crate::queue_template::QueueTemplate;
+use crate::queue_tree::inherit_cfg;
+use crate::queue_tree::queue_name_is_valid;
 use crate::statsd::spawn_statsd_emitter;
 use axum::extract::Path;
+use axum::extract::Query;
 use axum::extract::State;
-use axum::http::HeaderMap;
 use axum::http::StatusCode;
 use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
 use libqueued::Queued;
-use libqueued::QueuedCfg;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::NON_ALPHANUMERIC;
 use rand::thread_rng;
 use rand::Rng;
+use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
 use std::io::ErrorKind;
 use std::sync::Arc;
 use std::time::Duration;
@@ -30,7 +41,7 @@ pub(crate) struct SysErr {
 }
 
 impl SysErr {
-  fn from_error(e: std::io::Error) -> SysErr {
+  pub(crate) fn from_error(e: std::io::Error) -> SysErr {
     SysErr {
       code: e.raw_os_error(),
       kind: format!("{:?}", e.kind()),
@@ -39,21 +50,19 @@ impl SysErr {
   }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub(crate) struct EndpointQueuesResponseQueue {
-  name: String,
+  pub name: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub(crate) struct EndpointQueuesResponse {
-  queues: Vec<EndpointQueuesResponseQueue>,
+  pub queues: Vec<EndpointQueuesResponseQueue>,
 }
 
 pub(crate) async fn endpoint_queues(
   State(ctx): State<Arc<HttpCtx>>,
-  headers: HeaderMap,
 ) -> QueuedHttpResult<EndpointQueuesResponse> {
-  ctx.verify_global_auth(&headers)?;
   Ok(MsgPack(EndpointQueuesResponse {
     queues: ctx
       .queues
@@ -65,38 +74,125 @@ pub(crate) async fn endpoint_queues(
   }))
 }
 
+/// Appends a queue creation or deletion to the writes sent to cluster peers, so it's ordered before any writes to the queue made after this is called. The returned future resolves once enough peers have applied it.
+pub(crate) fn replicate_queue_op(
+  ctx: &HttpCtx,
+  method: reqwest::Method,
+  name: &str,
+) -> impl Future<Output = Result<(), QueuedHttpError>> + 'static {
+  let replicated = ctx.cluster.as_ref().map(|cluster| {
+    cluster.broadcast(
+      method,
+      format!(
+        "/cluster/queue/{}",
+        utf8_percent_encode(name, NON_ALPHANUMERIC)
+      ),
+      Vec::new(),
+    )
+  });
+  async move {
+    let Some(replicated) = replicated else {
+      return Ok(());
+    };
+    replicated.await.map_err(|_| {
+      (
+        StatusCode::SERVICE_UNAVAILABLE,
+        qerr(ErrorCode::ReplicationFailed),
+      )
+    })
+  }
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointTemplatesOutput {
+  templates: BTreeMap<String, QueueTemplate>,
+}
+
+pub(crate) async fn endpoint_templates(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<EndpointTemplatesOutput> {
+  Ok(MsgPack(EndpointTemplatesOutput {
+    templates: ctx.queue_templates.clone(),
+  }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointQueueCreateQuery {
+  /// If set, the name of a template in the config file whose settings are applied to the new queue.
+  template: Option<String>,
+}
+
 pub(crate) async fn endpoint_queue_create(
   State(ctx): State<Arc<HttpCtx>>,
   Path(name): Path<String>,
-  headers: HeaderMap,
+  Query(req): Query<EndpointQueueCreateQuery>,
 ) -> QueuedHttpResult<()> {
-  ctx.verify_global_auth(&headers)?;
+  ctx.verify_leader()?;
+  if !queue_name_is_valid(&name) {
+    return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidQueueName)));
+  };
+  if !ctx.tenants.is_empty() && ctx.tenant_of(&name).is_none() {
+    return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::TenantNotFound)));
+  };
+  let template = match &req.template {
+    Some(t) => Some(
+      ctx
+        .queue_templates
+        .get(t)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, qerr(ErrorCode::TemplateNotFound)))?,
+    ),
+    None => None,
+  };
+  if let Some(t) = template {
+    // Check settings that depend on the queue or server before creating the queue, so that it isn't left partially configured.
+    if !webhook_is_valid(&ctx, &name, t.webhook.as_ref()) {
+      return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::InvalidWebhook)));
+    };
+    if t.default_ttl_secs.is_some() && ctx.queue_cfg.format_version < 5 {
+      return Err((StatusCode::BAD_REQUEST, qerr(ErrorCode::TtlUnsupported)));
+    };
+  };
+  create_queue(&ctx, name, true, template).await?;
+  Ok(MsgPack(()))
+}
+
+/// If `replicate` is set, the queue is being created on this node rather than copied from another one: the creation is replicated to cluster peers, and the queue's configuration is copied from its nearest existing ancestor, and then `template` is applied. Followers don't inherit or apply templates, as state that's persisted is replicated from the leader.
+pub(crate) async fn create_queue(
+  ctx: &HttpCtx,
+  name: String,
+  replicate: bool,
+  template: Option<&QueueTemplate>,
+) -> Result<(), QueuedHttpError> {
   // We cannot create a temporary dir, because we cannot rename the folder while RocksDB is running. Instead, we'll ensure it succeeded by writing a success file. Also, if we use a different folder name, we lose the ability to use its existence as a locking mechanism to prevent multiple simultaneous creations of the same queue.
-  let dir = ctx.data_dir.join(&name);
+  let dir = ctx.queue_dir(&name);
   match tokio::fs::create_dir(&dir).await {
     Ok(()) => {}
     Err(e) => {
       return Err(match e.kind() {
-        ErrorKind::AlreadyExists => (StatusCode::CONFLICT, qerr("QueueAlreadyExists")),
+        ErrorKind::AlreadyExists => (StatusCode::CONFLICT, qerr(ErrorCode::QueueAlreadyExists)),
         _ => (
           StatusCode::INTERNAL_SERVER_ERROR,
-          qerr_d("Sys", SysErr::from_error(e)),
+          qerr_d(ErrorCode::Sys, SysErr::from_error(e)),
         ),
       })
     }
   };
-  let q = Arc::new(
-    Queued::load_and_start(&dir, QueuedCfg {
-      batch_sync_delay: ctx.batch_sync_delay,
-    })
-    .await,
-  );
+  // Peers must create the queue before applying any writes to it, which start once it's loaded. If creating it fails from here on, it's still created on peers, like the partially created queue that's left behind here.
+  let replicated = replicate.then(|| replicate_queue_op(ctx, reqwest::Method::PUT, &name));
+  let q = Arc::new(Queued::load_and_start(&dir, ctx.queue_cfg_for(&name)).await);
+  if let Some(parent) = ctx.nearest_ancestor(&name).filter(|_| replicate) {
+    inherit_cfg(&parent, &q).await;
+  };
+  if let Some(t) = template {
+    apply_template(t, &q).await;
+  };
   if let Some(addr) = ctx.statsd_endpoint {
     spawn_statsd_emitter(
       addr,
       &ctx.statsd_prefix,
       &ctx.statsd_tags,
       &name,
+      ctx.tenant_of(&name).map(|(t, _)| t),
       Arc::downgrade(&q),
     );
   };
@@ -105,24 +201,35 @@ pub(crate) async fn endpoint_queue_create(
     Err(e) => {
       return Err((
         StatusCode::INTERNAL_SERVER_ERROR,
-        qerr_d("Sys", Some(SysErr::from_error(e))),
+        qerr_d(ErrorCode::Sys, Some(SysErr::from_error(e))),
       ))
     }
   };
   info!(name, "queue created");
-  assert!(ctx.queues.insert(name, q).is_none());
-  Ok(MsgPack(()))
+  assert!(ctx.queues.insert(name.clone(), q.clone()).is_none());
+  // The config may have been reloaded after the queue was loaded but before it was added, in which case the reload didn't apply to it.
+  q.reload_cfg(&ctx.queue_cfg_for(&name));
+  if let Some(replicated) = replicated {
+    replicated.await?;
+  };
+  Ok(())
 }
 
 pub(crate) async fn endpoint_queue_delete(
   State(ctx): State<Arc<HttpCtx>>,
   Path(name): Path<String>,
-  headers: HeaderMap,
 ) -> QueuedHttpResult<()> {
-  ctx.verify_global_auth(&headers)?;
+  ctx.verify_leader()?;
+  delete_queue(&ctx, name.clone()).await?;
+  replicate_queue_op(&ctx, reqwest::Method::DELETE, &name).await?;
+  Ok(MsgPack(()))
+}
+
+pub(crate) async fn delete_queue(ctx: &HttpCtx, name: String) -> Result<(), QueuedHttpError> {
   let Some((_, mut q)) = ctx.queues.remove(&name) else {
-    return Err((StatusCode::NOT_FOUND, qerr("NotFound")));
+    return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::NotFound)));
   };
+  ctx.latency.remove(&name);
   loop {
     match Arc::try_unwrap(q) {
       Ok(db) => {
@@ -142,14 +249,14 @@ pub(crate) async fn endpoint_queue_delete(
       }
     };
   }
-  let dir = ctx.data_dir.join(&name);
+  let dir = ctx.queue_dir(&name);
   // Remove marker file first in case remove_dir_all fails or doesn't complete and leaves dir in an intermediate corrupt state.
   match tokio::fs::remove_file(dir.join(QUEUE_CREATE_OK_MARKER_FILE)).await {
     Ok(()) => {}
     Err(e) => {
       return Err((
         StatusCode::INTERNAL_SERVER_ERROR,
-        qerr_d("Sys", SysErr::from_error(e)),
+        qerr_d(ErrorCode::Sys, SysErr::from_error(e)),
       ))
     }
   };
@@ -158,10 +265,10 @@ pub(crate) async fn endpoint_queue_delete(
     Err(e) => {
       return Err((
         StatusCode::INTERNAL_SERVER_ERROR,
-        qerr_d("Sys", SysErr::from_error(e)),
+        qerr_d(ErrorCode::Sys, SysErr::from_error(e)),
       ))
     }
   };
   info!(name, "queue deleted");
-  Ok(MsgPack(()))
+  Ok(())
 }
@@ -0,0 +1,49 @@
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use axum::extract::Query;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::QueueQuiescence;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointQuiescedQuery {
+  /// Timestamp in seconds since the epoch. If set, a queue isn't quiesced if a push was accepted at or after this time.
+  since: Option<i64>,
+  /// If set, only this queue is checked instead of all queues.
+  queue: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointQuiescedOutput {
+  quiesced: bool,
+  queues: BTreeMap<String, QueueQuiescence>,
+}
+
+pub(crate) async fn endpoint_quiesced(
+  State(ctx): State<Arc<HttpCtx>>,
+  Query(req): Query<EndpointQuiescedQuery>,
+) -> QueuedHttpResult<EndpointQuiescedOutput> {
+  // Followers don't keep their in-memory state up to date, so only the leader can answer this.
+  ctx.verify_index_readable()?;
+  let queues = match &req.queue {
+    Some(name) => BTreeMap::from([(name.clone(), ctx.q(name)?.quiescence())]),
+    None => ctx
+      .queues
+      .iter()
+      .map(|e| (e.key().clone(), e.value().quiescence()))
+      .collect(),
+  };
+  let since_ms = req.since.map(|s| s * 1000);
+  let quiesced = queues.values().all(|q| {
+    let pushed_since = match (since_ms, q.last_push_ms) {
+      (Some(since_ms), Some(last_push_ms)) => last_push_ms >= since_ms,
+      _ => false,
+    };
+    !q.visible && !q.in_flight && !pushed_since
+  });
+  Ok(MsgPack(EndpointQuiescedOutput { quiesced, queues }))
+}
@@ -0,0 +1,133 @@
+use super::qerr;
+use super::read_body;
+use super::HttpCtx;
+use crate::auth::ClientCertificate;
+use crate::rate_limit::RateLimitClient;
+use crate::rate_limit::RateLimitKind;
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::extract::MatchedPath;
+use axum::extract::RawPathParams;
+use axum::extract::State;
+use axum::http::header::AUTHORIZATION;
+use axum::http::header::RETRY_AFTER;
+use axum::http::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use libqueued::error_code::ErrorCode;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn rate_limit_client<B>(req: &Request<B>, addr: Option<SocketAddr>) -> RateLimitClient {
+  if let Some(k) = req
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|h| h.to_str().ok())
+  {
+    return RateLimitClient(format!("key:{k}"));
+  };
+  if let Some(cn) = req
+    .extensions()
+    .get::<ClientCertificate>()
+    .and_then(|c| c.common_name.as_ref())
+  {
+    return RateLimitClient(format!("cert:{cn}"));
+  };
+  // Clients connecting over a Unix socket are all local, so share a limit.
+  RateLimitClient(match addr {
+    Some(addr) => format!("ip:{}", addr.ip()),
+    None => "local".to_string(),
+  })
+}
+
+pub(crate) fn rate_limited_response(wait: Duration) -> Response {
+  let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+  (
+    StatusCode::TOO_MANY_REQUESTS,
+    [(RETRY_AFTER, secs.to_string())],
+    qerr(ErrorCode::RateLimited),
+  )
+    .into_response()
+}
+
+fn count_pushed_messages(body: &[u8]) -> u64 {
+  #[derive(Deserialize)]
+  struct Input {
+    messages: Vec<IgnoredAny>,
+  }
+  // Invalid requests will be rejected by the endpoint, so only count them as one message.
+  rmp_serde::from_slice::<Input>(body).map_or(1, |i| i.messages.len() as u64)
+}
+
+pub(crate) async fn rate_limit_middleware(
+  State(ctx): State<Arc<HttpCtx>>,
+  path: MatchedPath,
+  params: Option<RawPathParams>,
+  addr: Option<ConnectInfo<SocketAddr>>,
+  req: Request<Body>,
+  next: Next<Body>,
+) -> Response {
+  let Some(limiter) = ctx.rate_limiter.clone() else {
+    return next.run(req).await;
+  };
+  let client = rate_limit_client(&req, addr.map(|a| a.0));
+  let queue = params
+    .iter()
+    .flat_map(|p| p.iter())
+    .find(|(k, _)| *k == "queue")
+    .and_then(|(_, v)| ctx.queues.get(v).map(|q| q.clone()));
+  let (mut req, res) = match path.as_str() {
+    "/queue/:queue/messages/push" => {
+      let mut takes = vec![(RateLimitKind::PushRequests, 1)];
+      let req = if limiter.has(RateLimitKind::PushMessages) {
+        let (parts, body) = req.into_parts();
+        let body = match read_body(body, ctx.max_request_body_size).await {
+          Ok(b) => b,
+          Err(status) => return status.into_response(),
+        };
+        takes.push((RateLimitKind::PushMessages, count_pushed_messages(&body)));
+        Request::from_parts(parts, Body::from(body))
+      } else {
+        req
+      };
+      let res = limiter.take(&client, &takes);
+      if res.is_err() {
+        if let Some(q) = &queue {
+          q.metrics().record_rate_limited_push();
+        };
+      };
+      (req, res)
+    }
+    "/queue/:queue/messages/push-stream" => {
+      // The endpoint counts messages as it reads them, as the body may be too large to buffer.
+      let res = limiter.take(&client, &[(RateLimitKind::PushRequests, 1)]);
+      if res.is_err() {
+        if let Some(q) = &queue {
+          q.metrics().record_rate_limited_push();
+        };
+      };
+      (req, res)
+    }
+    "/queue/:queue/messages/poll" | "/queue/:queue/messages/poll-stream" => {
+      let res = limiter.take(&client, &[(RateLimitKind::Polls, 1)]);
+      if res.is_err() {
+        if let Some(q) = &queue {
+          q.metrics().record_rate_limited_poll();
+        };
+      };
+      (req, res)
+    }
+    _ => (req, Ok(())),
+  };
+  if let Err(wait) = res {
+    return rate_limited_response(wait);
+  };
+  // The SQS API and streamed pushes enforce some limits themselves, as they only know what to take once the body is parsed.
+  req.extensions_mut().insert(client);
+  next.run(req).await
+}
@@ -0,0 +1,125 @@
+use super::HttpCtx;
+use crate::endpoint::qerr;
+use crate::endpoint::qerr_d;
+use crate::endpoint::QueuedHttpError;
+use axum::body::Bytes;
+use axum::body::StreamBody;
+use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use futures::stream::poll_fn;
+use libqueued::error_code::ErrorCode;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::sync::mpsc::channel;
+use tracing::warn;
+
+// These endpoints are used by read-only replicas (see `crate::replica`). Bodies are a series of raw RocksDB write batches, each prefixed by its length as a u32 LE.
+
+pub(crate) const REPLICA_SEQ_HEADER: &str = "x-queued-replica-seq";
+pub(crate) const REPLICA_IDENTITY_HEADER: &str = "x-queued-replica-identity";
+
+// Keeps each response small enough to buffer, as replicas poll for more anyway.
+const WAL_RESPONSE_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+fn frame(out: &mut Vec<u8>, batch: &[u8]) {
+  out.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+  out.extend_from_slice(batch);
+}
+
+fn storage_err(err: String) -> QueuedHttpError {
+  (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    qerr_d(ErrorCode::StorageUnavailable, err),
+  )
+}
+
+/// Streams every record of a queue for a new replica, then an empty batch to mark the end, so that a replica can tell a complete copy from a dropped connection. The sequence number header is where the replica should then continue from using `endpoint_replica_wal`.
+pub(crate) async fn endpoint_replica_records(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(name): Path<String>,
+) -> Result<Response, QueuedHttpError> {
+  let q = ctx.q(&name)?;
+  let identity = q.db_identity().map_err(storage_err)?;
+  let (seq, mut records) = q.replica_records().map_err(storage_err)?;
+  let (tx, mut rx) = channel::<Bytes>(4);
+  spawn(async move {
+    while let Some(res) = records.recv().await {
+      let batch = match res {
+        Ok(batch) => batch,
+        Err(err) => {
+          // Ending without the empty batch tells the replica that the copy is incomplete.
+          warn!(
+            queue = name,
+            error = err,
+            "failed to read records for replica"
+          );
+          return;
+        }
+      };
+      let mut out = Vec::with_capacity(batch.len() + 4);
+      frame(&mut out, &batch);
+      if tx.send(out.into()).await.is_err() {
+        return;
+      };
+    }
+    let _ = tx.send(Bytes::from_static(&[0, 0, 0, 0])).await;
+  });
+  let body = StreamBody::new(poll_fn(move |cx| {
+    rx.poll_recv(cx).map(|b| b.map(Ok::<_, Infallible>))
+  }));
+  Ok(
+    (
+      [
+        (CONTENT_TYPE, "application/octet-stream".to_string()),
+        (REPLICA_SEQ_HEADER.parse().unwrap(), seq.to_string()),
+        (REPLICA_IDENTITY_HEADER.parse().unwrap(), identity),
+      ],
+      body,
+    )
+      .into_response(),
+  )
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointReplicaWalInput {
+  since: u64,
+}
+
+/// Returns the writes to a queue after a sequence number previously returned by this or `endpoint_replica_records`, and the sequence number to continue from. Fails with 409 if the primary no longer has all of them, in which case the replica must copy all records again.
+pub(crate) async fn endpoint_replica_wal(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(name): Path<String>,
+  Query(req): Query<EndpointReplicaWalInput>,
+) -> Result<Response, QueuedHttpError> {
+  let q = ctx.q(&name)?;
+  let identity = q.db_identity().map_err(storage_err)?;
+  let Some(wal) = q
+    .replica_wal_since(req.since, WAL_RESPONSE_MAX_BYTES)
+    .await
+    .map_err(storage_err)?
+  else {
+    return Err((StatusCode::CONFLICT, qerr(ErrorCode::WalUnavailable)));
+  };
+  let mut out = Vec::new();
+  for b in wal.batches {
+    frame(&mut out, &b);
+  }
+  Ok(
+    (
+      [
+        (CONTENT_TYPE, "application/octet-stream".to_string()),
+        (REPLICA_SEQ_HEADER.parse().unwrap(), wal.seq.to_string()),
+        (REPLICA_IDENTITY_HEADER.parse().unwrap(), identity),
+      ],
+      out,
+    )
+      .into_response(),
+  )
+}
@@ -0,0 +1,44 @@
+use axum::http::HeaderName;
+use axum::http::HeaderValue;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use rand::thread_rng;
+use rand::Rng;
+use tracing::info_span;
+use tracing::Instrument;
+
+static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer IDs provided by clients are replaced, so that they can't bloat every log line.
+const MAX_LEN: usize = 128;
+
+fn request_id_is_valid(id: &str) -> bool {
+  !id.is_empty()
+    && id.len() <= MAX_LEN
+    && id
+      .bytes()
+      .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Runs the request in a span with its ID, so that every log line and trace span for it, including those of queue operations, can be correlated. The ID is taken from the `X-Request-Id` header if the client (or a proxy) provided a valid one, and is otherwise generated. It's returned in the `X-Request-Id` response header.
+pub(crate) async fn request_id_middleware<B>(req: Request<B>, next: Next<B>) -> Response {
+  let id = req
+    .headers()
+    .get(&REQUEST_ID)
+    .and_then(|v| v.to_str().ok())
+    .filter(|id| request_id_is_valid(id))
+    .map(|id| id.to_string())
+    .unwrap_or_else(|| format!("{:016x}", thread_rng().gen::<u64>()));
+  let span = info_span!(
+    "request",
+    request_id = %id,
+    method = %req.method(),
+    path = req.uri().path(),
+  );
+  let mut res = next.run(req).instrument(span).await;
+  res
+    .headers_mut()
+    .insert(REQUEST_ID.clone(), HeaderValue::from_str(&id).unwrap());
+  res
+}
@@ -0,0 +1,78 @@
+use super::qerr;
+use super::queue::ops::transform_op_result;
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use libqueued::error_code::ErrorCode;
+use libqueued::op::schedule::OpSetScheduleInput;
+use libqueued::op::schedule::ScheduleInfo;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointSchedule {
+  queue: String,
+  #[serde(flatten)]
+  schedule: ScheduleInfo,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointListSchedulesOutput {
+  schedules: Vec<EndpointSchedule>,
+}
+
+/// Lists the schedules of all queues, ordered by queue and then name.
+pub(crate) async fn endpoint_list_schedules(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<EndpointListSchedulesOutput> {
+  let mut queues = ctx
+    .queues
+    .iter()
+    .map(|e| (e.key().clone(), Arc::clone(e.value())))
+    .collect::<Vec<_>>();
+  queues.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+  let schedules = queues
+    .into_iter()
+    .flat_map(|(queue, q)| {
+      q.list_schedules()
+        .into_iter()
+        .map(move |schedule| EndpointSchedule {
+          queue: queue.clone(),
+          schedule,
+        })
+    })
+    .collect();
+  Ok(MsgPack(EndpointListSchedulesOutput { schedules }))
+}
+
+pub(crate) async fn endpoint_get_schedule(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path((queue_name, name)): Path<(String, String)>,
+) -> QueuedHttpResult<ScheduleInfo> {
+  let q = ctx.q(&queue_name)?;
+  q.get_schedule(&name)
+    .map(MsgPack)
+    .ok_or_else(|| (StatusCode::NOT_FOUND, qerr(ErrorCode::ScheduleNotFound)))
+}
+
+pub(crate) async fn endpoint_put_schedule(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path((queue_name, name)): Path<(String, String)>,
+  MsgPack(req): MsgPack<OpSetScheduleInput>,
+) -> QueuedHttpResult<ScheduleInfo> {
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.set_schedule(&name, req).await)
+}
+
+pub(crate) async fn endpoint_delete_schedule(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path((queue_name, name)): Path<(String, String)>,
+) -> QueuedHttpResult<()> {
+  let q = ctx.q(&queue_name)?;
+  ctx.verify_leader()?;
+  transform_op_result(q.remove_schedule(&name).await)
+}
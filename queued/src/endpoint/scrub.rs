@@ -0,0 +1,50 @@
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::endpoint::queue::ops::transform_op_result;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use libqueued::op::scrub::OpScrubInput;
+use libqueued::op::scrub::OpScrubOutput;
+use libqueued::Queued;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointScrubOutput {
+  queues: BTreeMap<String, OpScrubOutput>,
+}
+
+pub(crate) async fn endpoint_scrub(
+  State(ctx): State<Arc<HttpCtx>>,
+  MsgPack(req): MsgPack<OpScrubInput>,
+) -> QueuedHttpResult<EndpointScrubOutput> {
+  // Quarantining writes to the queues, so it must be replicated like any other write.
+  if req.quarantine {
+    ctx.verify_leader()?;
+  };
+  // Collect first so that we don't hold map entry locks across await points.
+  let queues = ctx
+    .queues
+    .iter()
+    .map(|e| (e.key().clone(), Arc::clone(e.value())))
+    .collect::<Vec<(String, Arc<Queued>)>>();
+  let mut out = BTreeMap::new();
+  for (name, q) in queues {
+    let MsgPack(res) = transform_op_result(
+      q.scrub(OpScrubInput {
+        quarantine: req.quarantine,
+      })
+      .await,
+    )?;
+    info!(
+      queue = name,
+      scanned = res.scanned,
+      issues = res.issues.len(),
+      "scrubbed queue"
+    );
+    out.insert(name, res);
+  }
+  Ok(MsgPack(EndpointScrubOutput { queues: out }))
+}
@@ -0,0 +1,117 @@
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use crate::endpoint::qerr;
+use crate::endpoint::qerr_d;
+use crate::endpoint::queues::SysErr;
+use crate::endpoint::queues::QUEUE_CREATE_OK_MARKER_FILE;
+use crate::queue_tree::queue_dir_name;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use libqueued::backup::SnapshotIncrementError;
+use libqueued::error_code::ErrorCode;
+use libqueued::Queued;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointSnapshotInput {
+  path: PathBuf,
+  /// If set, a previous snapshot, full or incremental, that this snapshot only contains the changes since.
+  base: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointSnapshotOutput {
+  queues: Vec<String>,
+  /// Queues that only have their changes since the base snapshot in this snapshot. Other queues, e.g. those created since the base, are in it in full.
+  incremental: Vec<String>,
+}
+
+pub(crate) async fn endpoint_snapshot(
+  State(ctx): State<Arc<HttpCtx>>,
+  MsgPack(req): MsgPack<EndpointSnapshotInput>,
+) -> QueuedHttpResult<EndpointSnapshotOutput> {
+  // Otherwise a typo would silently create a full snapshot.
+  if req.base.as_ref().is_some_and(|b| !b.is_dir()) {
+    return Err((
+      StatusCode::BAD_REQUEST,
+      qerr(ErrorCode::SnapshotBaseNotFound),
+    ));
+  };
+  match tokio::fs::create_dir(&req.path).await {
+    Ok(()) => {}
+    Err(e) => {
+      return Err(match e.kind() {
+        ErrorKind::AlreadyExists => (
+          StatusCode::CONFLICT,
+          qerr(ErrorCode::SnapshotPathAlreadyExists),
+        ),
+        _ => (
+          StatusCode::INTERNAL_SERVER_ERROR,
+          qerr_d(ErrorCode::Sys, SysErr::from_error(e)),
+        ),
+      })
+    }
+  };
+  // Collect first so that we don't hold map entry locks across await points.
+  let queues = ctx
+    .queues
+    .iter()
+    .map(|e| (e.key().clone(), Arc::clone(e.value())))
+    .collect::<Vec<(String, Arc<Queued>)>>();
+  let mut names = Vec::new();
+  let mut incremental = Vec::new();
+  for (name, q) in queues {
+    let dir = req.path.join(queue_dir_name(&name));
+    let base = req
+      .base
+      .as_ref()
+      .map(|b| b.join(queue_dir_name(&name)))
+      .filter(|b| b.exists());
+    if let Some(base) = base {
+      match q.snapshot_increment(dir, base).await {
+        Ok(()) => {}
+        Err(SnapshotIncrementError::Unavailable) => {
+          return Err((
+            StatusCode::CONFLICT,
+            qerr_d(ErrorCode::SnapshotIncrementUnavailable, name),
+          ));
+        }
+        Err(SnapshotIncrementError::Failed(e)) => {
+          return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            qerr_d(ErrorCode::SnapshotFailed, e),
+          ));
+        }
+      };
+      // Increments aren't valid data dirs, so they don't get a marker file.
+      incremental.push(name.clone());
+      names.push(name);
+      continue;
+    };
+    if let Err(e) = q.snapshot(dir.clone()).await {
+      return Err((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        qerr_d(ErrorCode::SnapshotFailed, e.to_string()),
+      ));
+    };
+    // The snapshot dir is a valid data dir, so it can be used directly with `--restore-from`.
+    if let Err(e) = tokio::fs::write(dir.join(QUEUE_CREATE_OK_MARKER_FILE), "").await {
+      return Err((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        qerr_d(ErrorCode::Sys, SysErr::from_error(e)),
+      ));
+    };
+    names.push(name);
+  }
+  info!(path = format!("{:?}", req.path), "snapshot created");
+  Ok(MsgPack(EndpointSnapshotOutput {
+    queues: names,
+    incremental,
+  }))
+}
@@ -0,0 +1,1468 @@
+use super::queue::ops::transform_op_result;
+use super::read_body;
+use super::HttpCtx;
+use super::QueuedHttpError;
+use crate::auth::Access;
+use crate::auth::ClientCertificate;
+use crate::auth::Credentials;
+use crate::auth::Permission;
+use crate::bridge::Bridge;
+use crate::message_move::MoveError;
+use crate::message_move::MoveStatus;
+use crate::message_move::MoveTask;
+use crate::rate_limit::RateLimitClient;
+use crate::rate_limit::RateLimitKind;
+use crate::sigv4::hmac_sha256;
+use crate::sigv4::signing_key;
+use crate::sigv4::verify_hmac_sha256;
+use crate::sigv4::URI_ENCODE_SET;
+use axum::body::Body;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use axum::http::HeaderValue;
+use axum::http::Method;
+use axum::http::Request;
+use axum::http::StatusCode;
+use axum::http::Uri;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+use itertools::Itertools;
+use libqueued::error_code::ErrorCode;
+use libqueued::op::delete::OpDeleteInput;
+use libqueued::op::delete::OpDeleteInputMessage;
+use libqueued::op::poll::OpPollInput;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use libqueued::op::update::OpUpdateInput;
+use libqueued::op::update::OpUpdateInputMessage;
+use libqueued::Queued;
+use md5::Digest;
+use md5::Md5;
+use percent_encoding::percent_decode_str;
+use percent_encoding::utf8_percent_encode;
+use rand::thread_rng;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::time::sleep;
+
+// SQS defaults and limits.
+const DEFAULT_VISIBILITY_TIMEOUT_SECS: i64 = 30;
+const MAX_WAIT_TIME_SECS: u64 = 20;
+const LONG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_MESSAGE_MOVE_RATE: u32 = 500;
+const MAX_LIST_MESSAGE_MOVE_TASKS_RESULTS: usize = 10;
+const MAX_RECEIVE_MESSAGES: usize = 10;
+const RECEIPT_HANDLE_MAC_LEN: usize = 16;
+// How far a signed request's `x-amz-date` can be from the current time, like AWS.
+const MAX_SIGNATURE_CLOCK_SKEW_SECS: i64 = 15 * 60;
+
+// Query protocol parameters that must be converted to numbers.
+const NUMERIC_PARAMS: &[&str] = &[
+  "DelaySeconds",
+  "MaxNumberOfMessages",
+  "MaxNumberOfMessagesPerSecond",
+  "MaxResults",
+  "VisibilityTimeout",
+  "WaitTimeSeconds",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+  Json,
+  Query,
+}
+
+struct SqsError {
+  status: StatusCode,
+  code: String,
+  message: String,
+}
+
+impl SqsError {
+  fn sender(code: &str, message: impl ToString) -> Self {
+    Self {
+      status: StatusCode::BAD_REQUEST,
+      code: code.to_string(),
+      message: message.to_string(),
+    }
+  }
+}
+
+impl From<QueuedHttpError> for SqsError {
+  fn from((status, body): QueuedHttpError) -> Self {
+    let error = body.0.error;
+    let code = match error {
+      ErrorCode::NotAuthorized => "AccessDenied",
+      ErrorCode::QueueNotFound => "AWS.SimpleQueueService.NonExistentQueue",
+      ErrorCode::InvalidVisibilityTimeout => "InvalidParameterValue",
+      e => e.name(),
+    };
+    Self {
+      status,
+      code: code.to_string(),
+      message: error.name().to_string(),
+    }
+  }
+}
+
+struct ReceivedMessage {
+  message_id: String,
+  receipt_handle: String,
+  md5_of_body: String,
+  body: String,
+  receive_count: u32,
+}
+
+enum SqsOutput {
+  Empty,
+  GetQueueUrl {
+    queue_url: String,
+  },
+  SendMessage {
+    message_id: String,
+    md5_of_message_body: String,
+  },
+  // (entry ID, message ID, MD5 of body).
+  SendMessageBatch(Vec<(String, String, String)>),
+  ReceiveMessage(Vec<ReceivedMessage>),
+  // Entry IDs.
+  DeleteMessageBatch(Vec<String>),
+  StartMessageMoveTask {
+    task_handle: String,
+  },
+  ListMessageMoveTasks(Vec<Arc<MoveTask>>),
+  CancelMessageMoveTask {
+    moved: u64,
+  },
+}
+
+fn xml_escape(raw: &str) -> String {
+  let mut out = String::with_capacity(raw.len());
+  for c in raw.chars() {
+    match c {
+      '&' => out.push_str("&amp;"),
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '"' => out.push_str("&quot;"),
+      '\'' => out.push_str("&apos;"),
+      c => out.push(c),
+    };
+  }
+  out
+}
+
+fn xml_el(name: &str, value: &str) -> String {
+  format!("<{name}>{}</{name}>", xml_escape(value))
+}
+
+fn message_move_task_json(t: &MoveTask) -> Value {
+  let (status, failure_reason) = t.status();
+  let mut out = json!({
+    "Status": status.as_str(),
+    "SourceArn": t.source_arn,
+    "DestinationArn": t.destination_arn,
+    "ApproximateNumberOfMessagesMoved": t.moved(),
+    "ApproximateNumberOfMessagesToMove": t.to_move,
+    "StartedTimestamp": t.started_at_ms,
+  });
+  // Like SQS, the handle is only returned while the task can still be cancelled.
+  if status == MoveStatus::Running {
+    out["TaskHandle"] = json!(t.handle);
+  };
+  if let Some(rate) = t.max_per_sec {
+    out["MaxNumberOfMessagesPerSecond"] = json!(rate);
+  };
+  if let Some(reason) = failure_reason {
+    out["FailureReason"] = json!(reason);
+  };
+  out
+}
+
+impl SqsOutput {
+  fn to_json(&self) -> Value {
+    match self {
+      SqsOutput::Empty => json!({}),
+      SqsOutput::GetQueueUrl { queue_url } => json!({ "QueueUrl": queue_url }),
+      SqsOutput::SendMessage {
+        message_id,
+        md5_of_message_body,
+      } => json!({
+        "MessageId": message_id,
+        "MD5OfMessageBody": md5_of_message_body,
+      }),
+      SqsOutput::SendMessageBatch(entries) => json!({
+        "Successful": entries
+          .iter()
+          .map(|(id, message_id, md5)| json!({
+            "Id": id,
+            "MessageId": message_id,
+            "MD5OfMessageBody": md5,
+          }))
+          .collect_vec(),
+        "Failed": [],
+      }),
+      SqsOutput::ReceiveMessage(msgs) => json!({
+        "Messages": msgs
+          .iter()
+          .map(|m| json!({
+            "MessageId": m.message_id,
+            "ReceiptHandle": m.receipt_handle,
+            "MD5OfBody": m.md5_of_body,
+            "Body": m.body,
+            "Attributes": {
+              "ApproximateReceiveCount": m.receive_count.to_string(),
+            },
+          }))
+          .collect_vec(),
+      }),
+      SqsOutput::DeleteMessageBatch(ids) => json!({
+        "Successful": ids.iter().map(|id| json!({ "Id": id })).collect_vec(),
+        "Failed": [],
+      }),
+      SqsOutput::StartMessageMoveTask { task_handle } => json!({ "TaskHandle": task_handle }),
+      SqsOutput::ListMessageMoveTasks(tasks) => json!({
+        "Results": tasks.iter().map(|t| message_move_task_json(t)).collect_vec(),
+      }),
+      SqsOutput::CancelMessageMoveTask { moved } => json!({
+        "ApproximateNumberOfMessagesMoved": moved,
+      }),
+    }
+  }
+
+  fn to_xml_result(&self) -> String {
+    match self {
+      SqsOutput::Empty => String::new(),
+      SqsOutput::GetQueueUrl { queue_url } => xml_el("QueueUrl", queue_url),
+      SqsOutput::SendMessage {
+        message_id,
+        md5_of_message_body,
+      } => format!(
+        "{}{}",
+        xml_el("MessageId", message_id),
+        xml_el("MD5OfMessageBody", md5_of_message_body)
+      ),
+      SqsOutput::SendMessageBatch(entries) => entries
+        .iter()
+        .map(|(id, message_id, md5)| {
+          format!(
+            "<SendMessageBatchResultEntry>{}{}{}</SendMessageBatchResultEntry>",
+            xml_el("Id", id),
+            xml_el("MessageId", message_id),
+            xml_el("MD5OfMessageBody", md5)
+          )
+        })
+        .join(""),
+      SqsOutput::ReceiveMessage(msgs) => msgs
+        .iter()
+        .map(|m| {
+          format!(
+            "<Message>{}{}{}{}<Attribute>{}{}</Attribute></Message>",
+            xml_el("MessageId", &m.message_id),
+            xml_el("ReceiptHandle", &m.receipt_handle),
+            xml_el("MD5OfBody", &m.md5_of_body),
+            xml_el("Body", &m.body),
+            xml_el("Name", "ApproximateReceiveCount"),
+            xml_el("Value", &m.receive_count.to_string())
+          )
+        })
+        .join(""),
+      SqsOutput::DeleteMessageBatch(ids) => ids
+        .iter()
+        .map(|id| {
+          format!(
+            "<DeleteMessageBatchResultEntry>{}</DeleteMessageBatchResultEntry>",
+            xml_el("Id", id)
+          )
+        })
+        .join(""),
+      SqsOutput::StartMessageMoveTask { task_handle } => xml_el("TaskHandle", task_handle),
+      // The query protocol uses the same fields as the JSON protocol.
+      SqsOutput::ListMessageMoveTasks(tasks) => tasks
+        .iter()
+        .map(|t| {
+          let fields = match message_move_task_json(t) {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+          };
+          format!(
+            "<ListMessageMoveTasksResultEntry>{}</ListMessageMoveTasksResultEntry>",
+            fields
+              .into_iter()
+              .map(|(k, v)| match v {
+                Value::String(v) => xml_el(&k, &v),
+                v => xml_el(&k, &v.to_string()),
+              })
+              .join("")
+          )
+        })
+        .join(""),
+      SqsOutput::CancelMessageMoveTask { moved } => {
+        xml_el("ApproximateNumberOfMessagesMoved", &moved.to_string())
+      }
+    }
+  }
+}
+
+fn md5_hex(raw: &[u8]) -> String {
+  hex::encode(Md5::digest(raw))
+}
+
+/// Derives the key that authenticates receipt handles from the global API key, so that handles remain valid after a restart and on other cluster nodes. Without a global API key, a random key is used instead, so handles received before a restart become invalid.
+pub(crate) fn sqs_receipt_handle_key(global_api_key: Option<&str>) -> Vec<u8> {
+  match global_api_key {
+    Some(k) => hmac_sha256(k.as_bytes(), b"sqs-receipt-handle"),
+    None => thread_rng().gen::<[u8; 32]>().to_vec(),
+  }
+}
+
+fn receipt_handle_mac_input(queue: &str, id: u64, poll_count: u32) -> String {
+  format!("{queue}\n{id}.{poll_count}")
+}
+
+// Receipt handles contain the poll count instead of the poll tag, as the poll tag changes when the visibility timeout is changed but SQS receipt handles don't. Poll counts are easy to guess, so handles also contain a MAC of the queue, ID, and poll count; otherwise, any client could delete a message it never received.
+fn receipt_handle(key: &[u8], queue: &str, id: u64, poll_count: u32) -> String {
+  let mac = hmac_sha256(
+    key,
+    receipt_handle_mac_input(queue, id, poll_count).as_bytes(),
+  );
+  format!(
+    "{id}.{poll_count}.{}",
+    hex::encode(&mac[..RECEIPT_HANDLE_MAC_LEN])
+  )
+}
+
+fn parse_receipt_handle(key: &[u8], queue: &str, raw: &str) -> Result<(u64, u32), SqsError> {
+  let invalid = || SqsError::sender("ReceiptHandleIsInvalid", "invalid receipt handle");
+  let [id, poll_count, mac] = raw.split('.').collect_vec()[..] else {
+    return Err(invalid());
+  };
+  let (Ok(id), Ok(poll_count), Ok(mac)) = (id.parse(), poll_count.parse(), hex::decode(mac)) else {
+    return Err(invalid());
+  };
+  if mac.len() != RECEIPT_HANDLE_MAC_LEN
+    || !verify_hmac_sha256(
+      key,
+      receipt_handle_mac_input(queue, id, poll_count).as_bytes(),
+      &mac,
+    )
+  {
+    return Err(invalid());
+  };
+  Ok((id, poll_count))
+}
+
+fn query_param_value(name: &str, raw: String) -> Value {
+  if NUMERIC_PARAMS.contains(&name) {
+    if let Ok(n) = raw.parse::<i64>() {
+      return Value::from(n);
+    };
+  };
+  Value::String(raw)
+}
+
+// Converts query protocol parameters into the equivalent JSON protocol request, so that both can be parsed the same way. Batch entries (e.g. `SendMessageBatchRequestEntry.1.MessageBody`) become `Entries`. Unsupported parameters (e.g. `AttributeName.1`) are ignored.
+fn query_params_to_json(params: Vec<(String, String)>) -> Value {
+  let mut out = Map::new();
+  let mut entries = BTreeMap::<u32, Map<String, Value>>::new();
+  for (k, v) in params {
+    match k.split('.').collect_vec()[..] {
+      [field] => {
+        out.insert(field.to_string(), query_param_value(field, v));
+      }
+      [list, i, field] if list.ends_with("BatchRequestEntry") => {
+        let Ok(i) = i.parse() else {
+          continue;
+        };
+        entries
+          .entry(i)
+          .or_default()
+          .insert(field.to_string(), query_param_value(field, v));
+      }
+      _ => {}
+    };
+  }
+  if !entries.is_empty() {
+    out.insert(
+      "Entries".to_string(),
+      Value::Array(entries.into_values().map(Value::Object).collect()),
+    );
+  };
+  Value::Object(out)
+}
+
+fn parse_input<T: DeserializeOwned>(input: Value) -> Result<T, SqsError> {
+  serde_json::from_value(input).map_err(|e| SqsError::sender("InvalidParameterValue", e))
+}
+
+fn incomplete_signature(message: &str) -> SqsError {
+  SqsError::sender("IncompleteSignature", message)
+}
+
+fn signature_does_not_match() -> SqsError {
+  SqsError {
+    status: StatusCode::FORBIDDEN,
+    code: "SignatureDoesNotMatch".to_string(),
+    message: "the request signature does not match".to_string(),
+  }
+}
+
+// SigV4 canonical query strings have their parameters encoded the same way and sorted.
+fn canonical_query(uri: &Uri) -> String {
+  let encode = |raw: &str| {
+    utf8_percent_encode(&percent_decode_str(raw).decode_utf8_lossy(), URI_ENCODE_SET).to_string()
+  };
+  uri
+    .query()
+    .unwrap_or("")
+    .split('&')
+    .filter(|p| !p.is_empty())
+    .map(|p| {
+      let (k, v) = p.split_once('=').unwrap_or((p, ""));
+      (encode(k), encode(v))
+    })
+    .sorted()
+    .map(|(k, v)| format!("{k}={v}"))
+    .join("&")
+}
+
+// The SigV4 canonical request, which is what the signature covers: the method, path, query, the signed headers and their values, and a hash of the body.
+fn canonical_request(
+  method: &Method,
+  uri: &Uri,
+  headers: &HeaderMap,
+  signed_headers: &str,
+  body: &[u8],
+) -> String {
+  let canonical_uri = uri
+    .path()
+    .split('/')
+    .map(|seg| utf8_percent_encode(seg, URI_ENCODE_SET).to_string())
+    .join("/");
+  let canonical_headers = signed_headers
+    .split(';')
+    .map(|name| {
+      let mut values = headers
+        .get_all(name)
+        .iter()
+        .map(|v| {
+          String::from_utf8_lossy(v.as_bytes())
+            .split_whitespace()
+            .join(" ")
+        })
+        .collect_vec();
+      // HTTP/2 requests have the host in the URI instead of a header.
+      if values.is_empty() && name == "host" {
+        values.extend(uri.authority().map(|a| a.to_string()));
+      };
+      format!("{name}:{}\n", values.join(","))
+    })
+    .join("");
+  format!(
+    "{method}\n{canonical_uri}\n{}\n{canonical_headers}\n{signed_headers}\n{}",
+    canonical_query(uri),
+    hex::encode(Sha256::digest(body))
+  )
+}
+
+// Returns the `authorization` header to authorize the request with. SQS clients sign requests with SigV4 using their secret access key. If the access key ID is in `--sqs-access-keys`, the signature is verified using its API key as the secret, and the request is then authorized as that API key. Other access key IDs are anonymous, as SQS clients always sign requests, even when auth is disabled. Other `authorization` headers are used as is, like for the queued API. Signed requests are rejected if their `x-amz-date` is more than 15 minutes from `now`.
+fn sqs_authorization(
+  sqs_access_keys: &BTreeMap<String, String>,
+  method: &Method,
+  uri: &Uri,
+  headers: &HeaderMap,
+  body: &[u8],
+  now: NaiveDateTime,
+) -> Result<Option<HeaderValue>, SqsError> {
+  let Some(authorization) = headers.get("authorization") else {
+    return Ok(None);
+  };
+  let Some(params) = authorization
+    .to_str()
+    .ok()
+    .and_then(|raw| raw.strip_prefix("AWS4-HMAC-SHA256 "))
+  else {
+    return Ok(Some(authorization.clone()));
+  };
+  let params: BTreeMap<&str, &str> = params
+    .split(',')
+    .filter_map(|p| p.trim().split_once('='))
+    .collect();
+  let (Some(credential), Some(signed_headers), Some(signature)) = (
+    params.get("Credential"),
+    params.get("SignedHeaders"),
+    params.get("Signature"),
+  ) else {
+    return Err(incomplete_signature(
+      "Authorization must have Credential, SignedHeaders, and Signature",
+    ));
+  };
+  let [access_key_id, date, region, service, "aws4_request"] =
+    credential.split('/').collect_vec()[..]
+  else {
+    return Err(incomplete_signature("invalid Credential"));
+  };
+  let Some(api_key) = sqs_access_keys.get(access_key_id) else {
+    return Ok(None);
+  };
+
+  let amz_date = headers
+    .get("x-amz-date")
+    .and_then(|v| v.to_str().ok())
+    .ok_or_else(|| incomplete_signature("x-amz-date is required"))?;
+  let signed_at = NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ")
+    .map_err(|_| incomplete_signature("invalid x-amz-date"))?;
+  if (now - signed_at).num_seconds().abs() > MAX_SIGNATURE_CLOCK_SKEW_SECS {
+    return Err(SqsError::sender(
+      "RequestExpired",
+      "x-amz-date is too far from the current time",
+    ));
+  };
+  if !amz_date.starts_with(date) {
+    return Err(signature_does_not_match());
+  };
+  // Without the host, a signed request could be replayed against another server using the same keys.
+  if !signed_headers.split(';').contains(&"host") {
+    return Err(incomplete_signature("the host header must be signed"));
+  };
+
+  let canonical_request = canonical_request(method, uri, headers, signed_headers, body);
+  let string_to_sign = format!(
+    "AWS4-HMAC-SHA256\n{amz_date}\n{date}/{region}/{service}/aws4_request\n{}",
+    hex::encode(Sha256::digest(canonical_request.as_bytes()))
+  );
+  let signing_key = signing_key(api_key, date, region, service);
+  let valid = hex::decode(signature).is_ok_and(|signature| {
+    signature.len() == Sha256::output_size()
+      && verify_hmac_sha256(&signing_key, string_to_sign.as_bytes(), &signature)
+  });
+  if !valid {
+    return Err(signature_does_not_match());
+  };
+  Ok(HeaderValue::from_str(api_key).ok())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetQueueUrlInput {
+  queue_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendMessageInput {
+  queue_url: Option<String>,
+  message_body: String,
+  #[serde(default)]
+  delay_seconds: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendMessageBatchEntry {
+  id: String,
+  message_body: String,
+  #[serde(default)]
+  delay_seconds: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendMessageBatchInput {
+  queue_url: Option<String>,
+  entries: Vec<SendMessageBatchEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ReceiveMessageInput {
+  queue_url: Option<String>,
+  max_number_of_messages: Option<usize>,
+  visibility_timeout: Option<i64>,
+  wait_time_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ChangeMessageVisibilityInput {
+  queue_url: Option<String>,
+  receipt_handle: String,
+  visibility_timeout: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeleteMessageInput {
+  queue_url: Option<String>,
+  receipt_handle: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeleteMessageBatchEntry {
+  id: String,
+  receipt_handle: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeleteMessageBatchInput {
+  queue_url: Option<String>,
+  entries: Vec<DeleteMessageBatchEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StartMessageMoveTaskInput {
+  source_arn: String,
+  destination_arn: Option<String>,
+  max_number_of_messages_per_second: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListMessageMoveTasksInput {
+  source_arn: String,
+  max_results: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CancelMessageMoveTaskInput {
+  task_handle: String,
+}
+
+// Queue ARNs look like `arn:aws:sqs:us-east-1:123456789012:my-queue`. Only the queue name is used, so any region and account ID are accepted.
+fn queue_name_from_arn(arn: &str) -> Result<&str, SqsError> {
+  arn
+    .strip_prefix("arn:")
+    .and_then(|rest| rest.rsplit(':').next())
+    .filter(|name| !name.is_empty())
+    .ok_or_else(|| SqsError::sender("InvalidParameterValue", format!("invalid queue ARN {arn}")))
+}
+
+impl From<MoveError> for SqsError {
+  fn from(err: MoveError) -> Self {
+    match err {
+      MoveError::AlreadyRunning => SqsError::sender(
+        "UnsupportedOperation",
+        "a message move task is already running for the source queue",
+      ),
+      MoveError::NotFound => SqsError::sender("ResourceNotFoundException", "task not found"),
+      MoveError::NotRunning => SqsError::sender("UnsupportedOperation", "task is not running"),
+    }
+  }
+}
+
+struct SqsReq<'a> {
+  ctx: &'a Arc<HttpCtx>,
+  headers: HeaderMap,
+  client_certificate: Option<ClientCertificate>,
+  path_queue: Option<String>,
+  rate_limit_client: Option<RateLimitClient>,
+}
+
+impl SqsReq<'_> {
+  fn authorize(&self, access: Access) -> Result<(), SqsError> {
+    let creds = Credentials {
+      headers: &self.headers,
+      client_certificate: self.client_certificate.as_ref(),
+    };
+    self.ctx.authorize(&creds, access)?;
+    Ok(())
+  }
+
+  // The queue name is the last path segment of the queue URL. Older clients send requests directly to the queue URL instead of providing the `QueueUrl` parameter.
+  fn queue_name<'a>(&'a self, queue_url: Option<&'a str>) -> Result<&'a str, SqsError> {
+    queue_url
+      .and_then(|u| u.trim_end_matches('/').rsplit('/').next())
+      .or(self.path_queue.as_deref())
+      .ok_or_else(|| SqsError::sender("MissingParameter", "QueueUrl is required"))
+  }
+
+  fn q(&self, queue_url: Option<String>, p: Permission) -> Result<Arc<Queued>, SqsError> {
+    let name = self.queue_name(queue_url.as_deref())?;
+    self.authorize(Access::Queue(name, p))?;
+    let q = self.ctx.q(name)?;
+    self.ctx.verify_leader()?;
+    Ok(q)
+  }
+
+  fn check_rate_limit(&self, takes: &[(RateLimitKind, u64)]) -> Result<(), SqsError> {
+    let (Some(limiter), Some(client)) = (&self.ctx.rate_limiter, &self.rate_limit_client) else {
+      return Ok(());
+    };
+    limiter
+      .take(client, takes)
+      .map_err(|_| SqsError::sender("RequestThrottled", "Rate exceeded"))
+  }
+
+  async fn send(
+    &self,
+    queue_url: Option<String>,
+    entries: Vec<(String, u32)>,
+  ) -> Result<Vec<(String, String)>, SqsError> {
+    let name = self.queue_name(queue_url.as_deref())?.to_string();
+    let q = self.q(queue_url, Permission::Push)?;
+    self.ctx.verify_accepting_pushes()?;
+    self
+      .check_rate_limit(&[
+        (RateLimitKind::PushRequests, 1),
+        (RateLimitKind::PushMessages, entries.len() as u64),
+      ])
+      .inspect_err(|_| q.metrics().record_rate_limited_push())?;
+    let md5s = entries
+      .iter()
+      .map(|(b, _)| md5_hex(b.as_bytes()))
+      .collect_vec();
+    let bridged = self.ctx.bridge.as_ref().map(|b| {
+      let body = Bridge::push_body(
+        entries
+          .iter()
+          .map(|(body, delay_seconds)| (body.as_bytes().to_vec(), *delay_seconds)),
+      );
+      (b, body)
+    });
+    let res = transform_op_result(
+      q.push(OpPushInput {
+        messages: entries
+          .into_iter()
+          .map(|(body, delay_seconds)| OpPushInputMessage {
+            contents: body.into_bytes(),
+            visibility_timeout_secs: delay_seconds,
+            visibility_jitter_secs: 0,
+            priority: 0,
+            attributes: Default::default(),
+            ttl_secs: None,
+            group_id: None,
+            external_id: None,
+          })
+          .collect(),
+      })
+      .await,
+    )?;
+    if let Some((bridge, body)) = bridged {
+      bridge.forward(&name, body).await.map_err(|_| SqsError {
+        status: StatusCode::SERVICE_UNAVAILABLE,
+        code: ErrorCode::BridgeFailed.name().to_string(),
+        message: ErrorCode::BridgeFailed.name().to_string(),
+      })?;
+    };
+    Ok(
+      res
+        .0
+        .ids
+        .into_iter()
+        .map(|id| id.to_string())
+        .zip(md5s)
+        .collect(),
+    )
+  }
+
+  async fn receive(&self, input: ReceiveMessageInput) -> Result<SqsOutput, SqsError> {
+    let count = input.max_number_of_messages.unwrap_or(1);
+    if !(1..=MAX_RECEIVE_MESSAGES).contains(&count) {
+      return Err(SqsError::sender(
+        "InvalidParameterValue",
+        format!("MaxNumberOfMessages must be between 1 and {MAX_RECEIVE_MESSAGES}"),
+      ));
+    };
+    let name = self.queue_name(input.queue_url.as_deref())?.to_string();
+    let q = self.q(input.queue_url, Permission::Poll)?;
+    // Long polls only count once, however many times the queue is polled while waiting.
+    self
+      .check_rate_limit(&[(RateLimitKind::Polls, 1)])
+      .inspect_err(|_| q.metrics().record_rate_limited_poll())?;
+    let visibility_timeout_secs = input
+      .visibility_timeout
+      .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT_SECS);
+    let wait = Duration::from_secs(input.wait_time_seconds.unwrap_or(0).min(MAX_WAIT_TIME_SECS));
+    let started = Instant::now();
+    loop {
+      let res = transform_op_result(
+        q.poll(OpPollInput {
+          count,
+          visibility_timeout_secs,
+          ignore_existing_visibility_timeouts: false,
+          prefer_group: None,
+          fields: None,
+        })
+        .await,
+      )?;
+      let msgs = res.0.messages;
+      if !msgs.is_empty() || started.elapsed() >= wait {
+        return Ok(SqsOutput::ReceiveMessage(
+          msgs
+            .into_iter()
+            .map(|m| {
+              let body = String::from_utf8_lossy(&m.contents).into_owned();
+              ReceivedMessage {
+                message_id: m.id.to_string(),
+                receipt_handle: receipt_handle(
+                  &self.ctx.sqs_receipt_handle_key,
+                  &name,
+                  m.id,
+                  m.poll_count,
+                ),
+                md5_of_body: md5_hex(body.as_bytes()),
+                body,
+                receive_count: m.poll_count,
+              }
+            })
+            .collect(),
+        ));
+      };
+      sleep(LONG_POLL_INTERVAL).await;
+    }
+  }
+
+  async fn delete(
+    &self,
+    queue_url: Option<String>,
+    receipt_handles: Vec<String>,
+  ) -> Result<(), SqsError> {
+    let name = self.queue_name(queue_url.as_deref())?.to_string();
+    let q = self.q(queue_url, Permission::Poll)?;
+    let mut messages = Vec::new();
+    for rh in receipt_handles {
+      let (id, poll_count) = parse_receipt_handle(&self.ctx.sqs_receipt_handle_key, &name, &rh)?;
+      // Like SQS, deleting with a stale receipt handle is not an error.
+      if let Some(poll_tag) = q.poll_tag_if_poll_count_matches(id, poll_count) {
+        messages.push(OpDeleteInputMessage { id, poll_tag });
+      };
+    }
+    transform_op_result(q.delete(OpDeleteInput { messages }).await)?;
+    Ok(())
+  }
+
+  // Unlike SQS, queues don't have a redrive policy, so the destination must always be provided.
+  fn start_message_move_task(
+    &self,
+    input: StartMessageMoveTaskInput,
+  ) -> Result<SqsOutput, SqsError> {
+    let destination_arn = input.destination_arn.ok_or_else(|| {
+      SqsError::sender(
+        "MissingParameter",
+        "DestinationArn is required, as queues don't have a redrive policy",
+      )
+    })?;
+    if input
+      .max_number_of_messages_per_second
+      .is_some_and(|r| !(1..=MAX_MESSAGE_MOVE_RATE).contains(&r))
+    {
+      return Err(SqsError::sender(
+        "InvalidParameterValue",
+        format!("MaxNumberOfMessagesPerSecond must be between 1 and {MAX_MESSAGE_MOVE_RATE}"),
+      ));
+    };
+    let source = queue_name_from_arn(&input.source_arn)?.to_string();
+    let destination = queue_name_from_arn(&destination_arn)?.to_string();
+    if source == destination {
+      return Err(SqsError::sender(
+        "InvalidParameterValue",
+        "source and destination queues must be different",
+      ));
+    };
+    let src = self.q(Some(source.clone()), Permission::Poll)?;
+    self.q(Some(destination.clone()), Permission::Push)?;
+    self.ctx.verify_accepting_pushes()?;
+    let task = self.ctx.message_moves.start(
+      Arc::downgrade(self.ctx),
+      (input.source_arn, source),
+      (destination_arn, destination),
+      src.metrics().message_counter(),
+      input.max_number_of_messages_per_second,
+    )?;
+    Ok(SqsOutput::StartMessageMoveTask {
+      task_handle: task.handle.clone(),
+    })
+  }
+
+  async fn handle(&self, action: &str, input: Value) -> Result<SqsOutput, SqsError> {
+    Ok(match action {
+      "GetQueueUrl" => {
+        let input: GetQueueUrlInput = parse_input(input)?;
+        let name = input.queue_name.as_str();
+        if self
+          .authorize(Access::Queue(name, Permission::Push))
+          .is_err()
+        {
+          self.authorize(Access::Queue(name, Permission::Poll))?;
+        };
+        self.ctx.q(name)?;
+        let host = self
+          .headers
+          .get("host")
+          .and_then(|h| h.to_str().ok())
+          .unwrap_or("localhost");
+        SqsOutput::GetQueueUrl {
+          queue_url: format!("http://{host}/sqs/{}", input.queue_name),
+        }
+      }
+      "SendMessage" => {
+        let input: SendMessageInput = parse_input(input)?;
+        let (message_id, md5_of_message_body) = self
+          .send(input.queue_url, vec![(
+            input.message_body,
+            input.delay_seconds,
+          )])
+          .await?
+          .pop()
+          .unwrap();
+        SqsOutput::SendMessage {
+          message_id,
+          md5_of_message_body,
+        }
+      }
+      "SendMessageBatch" => {
+        let input: SendMessageBatchInput = parse_input(input)?;
+        let (ids, entries): (Vec<_>, Vec<_>) = input
+          .entries
+          .into_iter()
+          .map(|e| (e.id, (e.message_body, e.delay_seconds)))
+          .unzip();
+        let sent = self.send(input.queue_url, entries).await?;
+        SqsOutput::SendMessageBatch(
+          ids
+            .into_iter()
+            .zip(sent)
+            .map(|(id, (message_id, md5))| (id, message_id, md5))
+            .collect(),
+        )
+      }
+      "ReceiveMessage" => self.receive(parse_input(input)?).await?,
+      "ChangeMessageVisibility" => {
+        let input: ChangeMessageVisibilityInput = parse_input(input)?;
+        let name = self.queue_name(input.queue_url.as_deref())?.to_string();
+        let q = self.q(input.queue_url, Permission::Poll)?;
+        let (id, poll_count) = parse_receipt_handle(
+          &self.ctx.sqs_receipt_handle_key,
+          &name,
+          &input.receipt_handle,
+        )?;
+        let poll_tag = q
+          .poll_tag_if_poll_count_matches(id, poll_count)
+          .ok_or_else(|| SqsError::sender("ReceiptHandleIsInvalid", "message not found"))?;
+        let res = transform_op_result(
+          q.update(OpUpdateInput {
+            messages: vec![OpUpdateInputMessage {
+              id,
+              poll_tag,
+              visibility_timeout_secs: input.visibility_timeout,
+            }],
+          })
+          .await,
+        )?;
+        if res.new_poll_tags[0].is_none() {
+          return Err(SqsError::sender(
+            "ReceiptHandleIsInvalid",
+            "message not found",
+          ));
+        };
+        SqsOutput::Empty
+      }
+      "DeleteMessage" => {
+        let input: DeleteMessageInput = parse_input(input)?;
+        self
+          .delete(input.queue_url, vec![input.receipt_handle])
+          .await?;
+        SqsOutput::Empty
+      }
+      "DeleteMessageBatch" => {
+        let input: DeleteMessageBatchInput = parse_input(input)?;
+        let (ids, receipt_handles) = input
+          .entries
+          .into_iter()
+          .map(|e| (e.id, e.receipt_handle))
+          .unzip();
+        self.delete(input.queue_url, receipt_handles).await?;
+        SqsOutput::DeleteMessageBatch(ids)
+      }
+      "StartMessageMoveTask" => self.start_message_move_task(parse_input(input)?)?,
+      "ListMessageMoveTasks" => {
+        let input: ListMessageMoveTasksInput = parse_input(input)?;
+        let limit = input.max_results.unwrap_or(1);
+        if !(1..=MAX_LIST_MESSAGE_MOVE_TASKS_RESULTS).contains(&limit) {
+          return Err(SqsError::sender(
+            "InvalidParameterValue",
+            format!("MaxResults must be between 1 and {MAX_LIST_MESSAGE_MOVE_TASKS_RESULTS}"),
+          ));
+        };
+        let source = queue_name_from_arn(&input.source_arn)?;
+        self.q(Some(source.to_string()), Permission::Poll)?;
+        SqsOutput::ListMessageMoveTasks(self.ctx.message_moves.list(source, limit))
+      }
+      "CancelMessageMoveTask" => {
+        let input: CancelMessageMoveTaskInput = parse_input(input)?;
+        let task = self
+          .ctx
+          .message_moves
+          .get(&input.task_handle)
+          .ok_or(MoveError::NotFound)?;
+        self.authorize(Access::Queue(&task.source, Permission::Poll))?;
+        let task = self.ctx.message_moves.cancel(&input.task_handle)?;
+        SqsOutput::CancelMessageMoveTask {
+          moved: task.moved(),
+        }
+      }
+      _ => {
+        return Err(SqsError::sender(
+          "InvalidAction",
+          format!("unsupported action {action}"),
+        ))
+      }
+    })
+  }
+}
+
+fn render(
+  protocol: Protocol,
+  action: &str,
+  request_id: &str,
+  res: Result<SqsOutput, SqsError>,
+) -> Response {
+  let headers = [
+    ("content-type", match protocol {
+      Protocol::Json => "application/x-amz-json-1.0",
+      Protocol::Query => "text/xml",
+    }),
+    ("x-amzn-requestid", request_id),
+  ];
+  match (protocol, res) {
+    (Protocol::Json, Ok(out)) => (headers, out.to_json().to_string()).into_response(),
+    (Protocol::Json, Err(err)) => (
+      err.status,
+      headers,
+      json!({ "__type": err.code, "message": err.message }).to_string(),
+    )
+      .into_response(),
+    (Protocol::Query, Ok(out)) => {
+      let result = out.to_xml_result();
+      let result = if result.is_empty() {
+        result
+      } else {
+        format!("<{action}Result>{result}</{action}Result>")
+      };
+      (
+        headers,
+        format!(
+          r#"<?xml version="1.0"?><{action}Response xmlns="http://queue.amazonaws.com/doc/2012-11-05/">{result}<ResponseMetadata>{}</ResponseMetadata></{action}Response>"#,
+          xml_el("RequestId", request_id)
+        ),
+      )
+        .into_response()
+    }
+    (Protocol::Query, Err(err)) => (
+      err.status,
+      headers,
+      format!(
+        r#"<?xml version="1.0"?><ErrorResponse xmlns="http://queue.amazonaws.com/doc/2012-11-05/"><Error><Type>Sender</Type>{}{}</Error>{}</ErrorResponse>"#,
+        xml_el("Code", &err.code),
+        xml_el("Message", &err.message),
+        xml_el("RequestId", request_id)
+      ),
+    )
+      .into_response(),
+  }
+}
+
+async fn handle_sqs(
+  ctx: &Arc<HttpCtx>,
+  path_queue: Option<String>,
+  req: Request<Body>,
+) -> Response {
+  let (parts, body) = req.into_parts();
+  // The signature covers the body, so it's read in full here.
+  let body = match read_body(body, ctx.max_request_body_size).await {
+    Ok(b) => b,
+    Err(status) => return status.into_response(),
+  };
+  let Parts {
+    method,
+    uri,
+    headers,
+    extensions,
+    ..
+  } = parts;
+  let client_certificate = extensions.get::<ClientCertificate>().cloned();
+  let rate_limit_client = extensions.get::<RateLimitClient>().cloned();
+  let request_id = hex::encode(thread_rng().gen::<[u8; 16]>());
+  let json_action = headers
+    .get("x-amz-target")
+    .and_then(|v| v.to_str().ok())
+    .and_then(|t| t.strip_prefix("AmazonSQS."))
+    .map(|a| a.to_string());
+  let (protocol, action, input) = match json_action {
+    Some(action) => match serde_json::from_slice::<Value>(&body) {
+      Ok(input) => (Protocol::Json, action, input),
+      Err(e) => {
+        let err = SqsError::sender("MalformedQueryString", e);
+        return render(Protocol::Json, &action, &request_id, Err(err));
+      }
+    },
+    None => {
+      let params = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body).unwrap_or_default();
+      let action = params
+        .iter()
+        .find(|(k, _)| k == "Action")
+        .map(|(_, v)| v.clone())
+        .unwrap_or_default();
+      (Protocol::Query, action, query_params_to_json(params))
+    }
+  };
+  let authorization = match sqs_authorization(
+    &ctx.sqs_access_keys,
+    &method,
+    &uri,
+    &headers,
+    &body,
+    Utc::now().naive_utc(),
+  ) {
+    Ok(a) => a,
+    Err(err) => return render(protocol, &action, &request_id, Err(err)),
+  };
+  let req = SqsReq {
+    ctx,
+    headers: {
+      let mut h = HeaderMap::new();
+      if let Some(authorization) = authorization {
+        h.insert("authorization", authorization);
+      };
+      if let Some(host) = headers.get("host") {
+        h.insert("host", host.clone());
+      };
+      h
+    },
+    client_certificate,
+    path_queue,
+    rate_limit_client,
+  };
+  let res = req.handle(&action, input).await;
+  render(protocol, &action, &request_id, res)
+}
+
+pub(crate) async fn endpoint_sqs(State(ctx): State<Arc<HttpCtx>>, req: Request<Body>) -> Response {
+  handle_sqs(&ctx, None, req).await
+}
+
+pub(crate) async fn endpoint_sqs_queue(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(queue): Path<String>,
+  req: Request<Body>,
+) -> Response {
+  handle_sqs(&ctx, Some(queue), req).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::canonical_request;
+  use super::parse_receipt_handle;
+  use super::receipt_handle;
+  use super::sqs_authorization;
+  use axum::http::HeaderMap;
+  use axum::http::Method;
+  use axum::http::Uri;
+  use chrono::NaiveDateTime;
+  use std::collections::BTreeMap;
+
+  // The credentials and time used by the AWS SigV4 test suite, whose requests are signed for the service `service` in us-east-1.
+  const ACCESS_KEY_ID: &str = "AKIDEXAMPLE";
+  const SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+  const AMZ_DATE: &str = "20150830T123600Z";
+
+  fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+    let mut h = HeaderMap::new();
+    for &(name, value) in pairs {
+      h.append(name, value.parse().unwrap());
+    }
+    h
+  }
+
+  fn signed_at(amz_date: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ").unwrap()
+  }
+
+  fn authorization(signed_headers: &str, signature: &str) -> String {
+    format!("AWS4-HMAC-SHA256 Credential={ACCESS_KEY_ID}/20150830/us-east-1/service/aws4_request, SignedHeaders={signed_headers}, Signature={signature}")
+  }
+
+  fn access_keys() -> BTreeMap<String, String> {
+    BTreeMap::from([(ACCESS_KEY_ID.to_string(), SECRET_ACCESS_KEY.to_string())])
+  }
+
+  // Returns the error code, if the signature was rejected.
+  fn authorize(
+    method: Method,
+    uri: &str,
+    mut h: HeaderMap,
+    body: &[u8],
+    signed_headers: &str,
+    signature: &str,
+    now: NaiveDateTime,
+  ) -> Result<Option<String>, String> {
+    h.insert(
+      "authorization",
+      authorization(signed_headers, signature).parse().unwrap(),
+    );
+    sqs_authorization(
+      &access_keys(),
+      &method,
+      &uri.parse().unwrap(),
+      &h,
+      body,
+      now,
+    )
+    .map(|a| a.map(|a| a.to_str().unwrap().to_string()))
+    .map_err(|err| err.code)
+  }
+
+  #[test]
+  fn canonical_request_matches_get_vanilla() {
+    let h = headers(&[("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)]);
+    assert_eq!(
+      canonical_request(
+        &Method::GET,
+        &Uri::from_static("/"),
+        &h,
+        "host;x-amz-date",
+        b""
+      ),
+      "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\nhost;x-amz-date\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+  }
+
+  #[test]
+  fn canonical_request_sorts_query_and_trims_header_values() {
+    let h = headers(&[
+      ("host", "example.amazonaws.com"),
+      ("my-header1", "  value1   with  spaces "),
+      ("my-header1", "value2"),
+      ("x-amz-date", AMZ_DATE),
+    ]);
+    assert_eq!(
+      canonical_request(
+        &Method::GET,
+        &Uri::from_static("/?Param2=value2&Param1=value%201"),
+        &h,
+        "host;my-header1;x-amz-date",
+        b""
+      ),
+      "GET\n/\nParam1=value%201&Param2=value2\nhost:example.amazonaws.com\nmy-header1:value1 with spaces,value2\nx-amz-date:20150830T123600Z\n\nhost;my-header1;x-amz-date\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+  }
+
+  // The get-vanilla, get-vanilla-query-order-key-case, and post-x-www-form-urlencoded cases of the AWS SigV4 test suite.
+  #[test]
+  fn accepts_aws_test_suite_signatures() {
+    let now = signed_at(AMZ_DATE);
+    let cases = [
+      (
+        Method::GET,
+        "/",
+        headers(&[("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)]),
+        &b""[..],
+        "host;x-amz-date",
+        "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+      ),
+      (
+        Method::GET,
+        "/?Param2=value2&Param1=value1",
+        headers(&[("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)]),
+        &b""[..],
+        "host;x-amz-date",
+        "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500",
+      ),
+      (
+        Method::POST,
+        "/",
+        headers(&[
+          ("content-type", "application/x-www-form-urlencoded"),
+          ("host", "example.amazonaws.com"),
+          ("x-amz-date", AMZ_DATE),
+        ]),
+        &b"Param1=value1"[..],
+        "content-type;host;x-amz-date",
+        "ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a",
+      ),
+    ];
+    for (method, uri, h, body, signed_headers, signature) in cases {
+      assert_eq!(
+        authorize(method, uri, h, body, signed_headers, signature, now),
+        Ok(Some(SECRET_ACCESS_KEY.to_string())),
+        "{uri}"
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_tampered_signatures() {
+    let now = signed_at(AMZ_DATE);
+    let h = || headers(&[("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)]);
+    let signature = "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31";
+    let does_not_match = Err("SignatureDoesNotMatch".to_string());
+    // A different path, body, or signature.
+    assert_eq!(
+      authorize(
+        Method::GET,
+        "/other",
+        h(),
+        b"",
+        "host;x-amz-date",
+        signature,
+        now
+      ),
+      does_not_match
+    );
+    assert_eq!(
+      authorize(
+        Method::GET,
+        "/",
+        h(),
+        b"body",
+        "host;x-amz-date",
+        signature,
+        now
+      ),
+      does_not_match
+    );
+    let tampered = signature.replacen('5', "6", 1);
+    assert_eq!(
+      authorize(
+        Method::GET,
+        "/",
+        h(),
+        b"",
+        "host;x-amz-date",
+        &tampered,
+        now
+      ),
+      does_not_match
+    );
+    // A truncated signature would pass a prefix check.
+    assert_eq!(
+      authorize(
+        Method::GET,
+        "/",
+        h(),
+        b"",
+        "host;x-amz-date",
+        &signature[..32],
+        now
+      ),
+      does_not_match
+    );
+    assert_eq!(
+      authorize(Method::GET, "/", h(), b"", "x-amz-date", signature, now),
+      Err("IncompleteSignature".to_string())
+    );
+  }
+
+  #[test]
+  fn rejects_expired_and_future_dates() {
+    let h = || headers(&[("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)]);
+    let signature = "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31";
+    for now in ["20150830T125100Z", "20150830T122100Z"] {
+      assert_eq!(
+        authorize(
+          Method::GET,
+          "/",
+          h(),
+          b"",
+          "host;x-amz-date",
+          signature,
+          signed_at(now)
+        ),
+        Ok(Some(SECRET_ACCESS_KEY.to_string()))
+      );
+    }
+    for now in ["20150830T125101Z", "20150830T122059Z", "20150831T123600Z"] {
+      assert_eq!(
+        authorize(
+          Method::GET,
+          "/",
+          h(),
+          b"",
+          "host;x-amz-date",
+          signature,
+          signed_at(now)
+        ),
+        Err("RequestExpired".to_string())
+      );
+    }
+  }
+
+  #[test]
+  fn unknown_access_keys_are_anonymous() {
+    let mut h = headers(&[("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)]);
+    h.insert(
+      "authorization",
+      "AWS4-HMAC-SHA256 Credential=OTHER/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=00"
+        .parse()
+        .unwrap(),
+    );
+    let res = sqs_authorization(
+      &access_keys(),
+      &Method::GET,
+      &Uri::from_static("/"),
+      &h,
+      b"",
+      signed_at(AMZ_DATE),
+    );
+    assert!(matches!(res, Ok(None)));
+  }
+
+  #[test]
+  fn receipt_handles_round_trip() {
+    let handle = receipt_handle(b"key", "q", 190234, 3);
+    assert!(matches!(
+      parse_receipt_handle(b"key", "q", &handle),
+      Ok((190234, 3))
+    ));
+  }
+
+  #[test]
+  fn rejects_forged_and_tampered_receipt_handles() {
+    let handle = receipt_handle(b"key", "q", 190234, 3);
+    let (_, mac) = handle.rsplit_once('.').unwrap();
+    let invalid = [
+      // Forged without the key, or for another message, poll, or queue.
+      receipt_handle(b"other key", "q", 190234, 3),
+      format!("190235.3.{mac}"),
+      format!("190234.4.{mac}"),
+      receipt_handle(b"key", "other", 190234, 3),
+      // A tampered or truncated MAC.
+      format!(
+        "190234.3.{}{}",
+        &mac[..31],
+        if mac.ends_with('0') { "1" } else { "0" }
+      ),
+      format!("190234.3.{}", &mac[..30]),
+      format!("190234.3.{mac}00"),
+      // Malformed.
+      "190234.3".to_string(),
+      format!("190234.3.{mac}.1"),
+      format!("x.3.{mac}"),
+      format!("190234.3.{}", "zz".repeat(16)),
+      String::new(),
+    ];
+    for raw in invalid {
+      let res = parse_receipt_handle(b"key", "q", &raw);
+      assert!(
+        matches!(&res, Err(err) if err.code == "ReceiptHandleIsInvalid"),
+        "{raw}"
+      );
+    }
+  }
+}
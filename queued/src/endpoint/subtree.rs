@@ -0,0 +1,22 @@
+use super::HttpCtx;
+use super::QueuedHttpResult;
+use axum::extract::Path;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// The queues in a subtree that an admin operation was applied to, ordered by name.
+#[derive(Serialize)]
+pub(crate) struct EndpointSubtreeOutput {
+  pub(crate) queues: Vec<String>,
+}
+
+pub(crate) async fn endpoint_subtree(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(root): Path<String>,
+) -> QueuedHttpResult<EndpointSubtreeOutput> {
+  Ok(MsgPack(EndpointSubtreeOutput {
+    queues: ctx.subtree(&root)?.into_iter().map(|(n, _)| n).collect(),
+  }))
+}
@@ -0,0 +1,263 @@
+use super::qerr;
+use super::qerr_d;
+use super::queues::delete_queue;
+use super::queues::replicate_queue_op;
+use super::subtree::EndpointSubtreeOutput;
+use super::HttpCtx;
+use super::QueuedHttpError;
+use super::QueuedHttpResult;
+use crate::queue_tree::is_in_subtree;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum_msgpack::MsgPack;
+use itertools::Itertools;
+use libqueued::error_code::ErrorCode;
+use libqueued::quota::QuotaLimits;
+use libqueued::quota::QuotaUsage;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub(crate) struct EndpointTenantsOutputTenant {
+  name: String,
+  queues: usize,
+  limits: QuotaLimits,
+  /// As of each queue's last refresh, about once a second.
+  usage: QuotaUsage,
+  in_flight: u64,
+  /// Successful pushes per second, over the last 10 seconds.
+  push_rate: f64,
+  successful_push_counter: u64,
+  successful_poll_counter: u64,
+  successful_delete_counter: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointTenantsOutput {
+  tenants: Vec<EndpointTenantsOutputTenant>,
+}
+
+/// Lists every tenant with its limits, and its usage and metrics summed across all of its queues.
+pub(crate) async fn endpoint_tenants(
+  State(ctx): State<Arc<HttpCtx>>,
+) -> QueuedHttpResult<EndpointTenantsOutput> {
+  let mut tenants = ctx
+    .tenants
+    .iter()
+    .map(|(name, quota)| {
+      (name.as_str(), EndpointTenantsOutputTenant {
+        name: name.clone(),
+        queues: 0,
+        limits: quota.limits(),
+        usage: quota.total(),
+        in_flight: 0,
+        push_rate: 0.0,
+        successful_push_counter: 0,
+        successful_poll_counter: 0,
+        successful_delete_counter: 0,
+      })
+    })
+    .collect::<Vec<_>>();
+  for e in ctx.queues.iter() {
+    let Some((tenant, _)) = ctx.tenant_of(e.key()) else {
+      continue;
+    };
+    let t = &mut tenants.iter_mut().find(|(n, _)| *n == tenant).unwrap().1;
+    let m = e.value().metrics();
+    t.queues += 1;
+    t.in_flight += e.value().in_flight_message_count() as u64;
+    t.push_rate += ctx.push_rates.per_sec(e.key());
+    t.successful_push_counter += m.successful_push_counter();
+    t.successful_poll_counter += m.successful_poll_counter();
+    t.successful_delete_counter += m.successful_delete_counter();
+  }
+  Ok(MsgPack(EndpointTenantsOutput {
+    tenants: tenants.into_iter().map(|(_, t)| t).collect(),
+  }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointTenantDeleteFailure {
+  queue: String,
+  error: ErrorCode,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointTenantDeleteIncomplete {
+  /// Queues that were deleted, including any that were already gone.
+  queues: Vec<String>,
+  failed: Vec<EndpointTenantDeleteFailure>,
+}
+
+/// Deletes all of a tenant's queues and revokes its API keys. The tenant itself stays configured until it's removed from the config file; JWTs for it can't be revoked, so rotate the secret if needed. A failure to delete one queue doesn't stop the rest from being deleted; the error's details list the deleted and failed queues, and as queues that are already gone are skipped, the request can simply be retried until it succeeds.
+pub(crate) async fn endpoint_tenant_delete(
+  State(ctx): State<Arc<HttpCtx>>,
+  Path(tenant): Path<String>,
+) -> QueuedHttpResult<EndpointSubtreeOutput> {
+  ctx.verify_leader()?;
+  if !ctx.tenants.contains_key(&tenant) {
+    return Err((StatusCode::NOT_FOUND, qerr(ErrorCode::TenantNotFound)));
+  };
+  // Revoke API keys first, so that the tenant can't create queues while its existing ones are being deleted.
+  if let Some(api_keys) = &ctx.api_keys {
+    api_keys.retain(|_, i| i.tenant.as_ref() != Some(&tenant));
+  };
+  let queues = ctx
+    .queues
+    .iter()
+    .filter(|e| is_in_subtree(e.key(), &tenant))
+    .map(|e| e.key().clone())
+    .sorted()
+    .collect_vec();
+  let ctx = &ctx;
+  let deleted = delete_each(queues, |name| async move {
+    // Only the status and code of errors are kept, as the error body isn't Send.
+    match delete_queue(ctx, name.clone()).await {
+      Ok(()) => {}
+      // Deleted concurrently, e.g. by an earlier attempt that's still running.
+      Err((_, MsgPack(err))) if err.error == ErrorCode::NotFound => {}
+      Err((status, MsgPack(err))) => return Err((status, err.error)),
+    };
+    replicate_queue_op(ctx, reqwest::Method::DELETE, &name)
+      .await
+      .map_err(|(status, MsgPack(err))| (status, err.error))
+  })
+  .await?;
+  Ok(MsgPack(EndpointSubtreeOutput { queues: deleted }))
+}
+
+// Deletes every queue using `delete`, carrying on past failures. If any fail, the error is the first failure's, with details listing the deleted and failed queues.
+async fn delete_each<F, Fut>(
+  queues: Vec<String>,
+  mut delete: F,
+) -> Result<Vec<String>, QueuedHttpError>
+where
+  F: FnMut(String) -> Fut,
+  Fut: Future<Output = Result<(), (StatusCode, ErrorCode)>>,
+{
+  let mut deleted = Vec::new();
+  let mut failed = Vec::new();
+  let mut first_err = None;
+  for name in queues {
+    match delete(name.clone()).await {
+      Ok(()) => deleted.push(name),
+      Err((status, error)) => {
+        failed.push(EndpointTenantDeleteFailure { queue: name, error });
+        first_err.get_or_insert((status, error));
+      }
+    };
+  }
+  if let Some((status, error)) = first_err {
+    return Err((
+      status,
+      qerr_d(error, EndpointTenantDeleteIncomplete {
+        queues: deleted,
+        failed,
+      }),
+    ));
+  };
+  Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::delete_each;
+  use axum::http::StatusCode;
+  use libqueued::error_code::ErrorCode;
+  use serde::Deserialize;
+  use std::collections::BTreeSet;
+  use std::sync::Mutex;
+
+  #[derive(Deserialize, PartialEq, Debug)]
+  struct Failure {
+    queue: String,
+    error: String,
+  }
+
+  #[derive(Deserialize, PartialEq, Debug)]
+  struct Details {
+    queues: Vec<String>,
+    failed: Vec<Failure>,
+  }
+
+  #[derive(Deserialize)]
+  struct Body {
+    error: String,
+    error_details: Details,
+  }
+
+  fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|n| n.to_string()).collect()
+  }
+
+  #[tokio::test]
+  async fn deletes_every_queue() {
+    let deleted = delete_each(names(&["t", "t/a", "t/b"]), |_| async { Ok(()) }).await;
+    assert_eq!(deleted.ok(), Some(names(&["t", "t/a", "t/b"])));
+  }
+
+  #[tokio::test]
+  async fn keeps_deleting_past_failures() {
+    let attempted = Mutex::new(Vec::new());
+    let (status, body) = delete_each(names(&["t/a", "t/b", "t/c", "t/d"]), |name| {
+      attempted.lock().unwrap().push(name.clone());
+      async move {
+        match name.as_str() {
+          "t/b" => Err((StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Sys)),
+          "t/d" => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::StorageUnavailable,
+          )),
+          _ => Ok(()),
+        }
+      }
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(
+      *attempted.lock().unwrap(),
+      names(&["t/a", "t/b", "t/c", "t/d"])
+    );
+    // The first failure's.
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    let body: Body = rmp_serde::from_slice(&rmp_serde::to_vec_named(&body.0).unwrap()).unwrap();
+    assert_eq!(body.error, "Sys");
+    assert_eq!(body.error_details, Details {
+      queues: names(&["t/a", "t/c"]),
+      failed: vec![
+        Failure {
+          queue: "t/b".to_string(),
+          error: "Sys".to_string(),
+        },
+        Failure {
+          queue: "t/d".to_string(),
+          error: "StorageUnavailable".to_string(),
+        },
+      ],
+    });
+  }
+
+  #[tokio::test]
+  async fn retries_delete_what_failed_before() {
+    let existing = Mutex::new(BTreeSet::from_iter(names(&["t/a", "t/b", "t/c"])));
+    let mut fail = true;
+    let mut attempt = || {
+      let queues = existing.lock().unwrap().iter().cloned().collect::<Vec<_>>();
+      let fail_b = std::mem::take(&mut fail);
+      let existing = &existing;
+      delete_each(queues, move |name| async move {
+        if fail_b && name == "t/b" {
+          return Err((StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Sys));
+        };
+        existing.lock().unwrap().remove(&name);
+        Ok(())
+      })
+    };
+    assert!(attempt().await.is_err());
+    assert_eq!(attempt().await.ok().unwrap(), names(&["t/b"]));
+    assert!(existing.lock().unwrap().is_empty());
+    assert_eq!(attempt().await.ok().unwrap(), names(&[]));
+  }
+}
@@ -0,0 +1,97 @@
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpResult;
+use axum::extract::Query;
+use axum::extract::State;
+use axum_msgpack::MsgPack;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cmp::max;
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TopBy {
+  Depth,
+  PushRate,
+  OldestAge,
+}
+
+fn default_n() -> usize {
+  10
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EndpointTopQuery {
+  by: TopBy,
+  #[serde(default = "default_n")]
+  n: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointTopQueue {
+  name: String,
+  depth: u64,
+  in_flight: u64,
+  /// Successful pushes per second, over the last 10 seconds.
+  push_rate: f64,
+  /// How long the longest waiting visible message has been visible, or 0 if there are none.
+  oldest_age_secs: u64,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct EndpointTopTotals {
+  queues: usize,
+  depth: u64,
+  in_flight: u64,
+  push_rate: f64,
+  oldest_age_secs: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointTopOutput {
+  /// The `n` queues with the highest `by`, highest first.
+  queues: Vec<EndpointTopQueue>,
+  /// Across all queues. `oldest_age_secs` is the maximum.
+  totals: EndpointTopTotals,
+}
+
+pub(crate) async fn endpoint_top(
+  State(ctx): State<Arc<HttpCtx>>,
+  Query(req): Query<EndpointTopQuery>,
+) -> QueuedHttpResult<EndpointTopOutput> {
+  let now = Utc::now().timestamp();
+  let mut queues = ctx
+    .queues
+    .iter()
+    .map(|e| {
+      let q = e.value();
+      EndpointTopQueue {
+        name: e.key().clone(),
+        depth: q.metrics().message_counter(),
+        in_flight: q.in_flight_message_count() as u64,
+        push_rate: ctx.push_rates.per_sec(e.key()),
+        oldest_age_secs: q
+          .youngest_message_time()
+          .map(|t| max(0, now - t) as u64)
+          .unwrap_or(0),
+      }
+    })
+    .collect::<Vec<_>>();
+  let mut totals = EndpointTopTotals::default();
+  for q in queues.iter() {
+    totals.queues += 1;
+    totals.depth += q.depth;
+    totals.in_flight += q.in_flight;
+    totals.push_rate += q.push_rate;
+    totals.oldest_age_secs = max(totals.oldest_age_secs, q.oldest_age_secs);
+  }
+  match req.by {
+    TopBy::Depth => queues.sort_by_key(|q| Reverse(q.depth)),
+    TopBy::PushRate => queues.sort_by(|a, b| b.push_rate.total_cmp(&a.push_rate)),
+    TopBy::OldestAge => queues.sort_by_key(|q| Reverse(q.oldest_age_secs)),
+  };
+  queues.truncate(req.n);
+  Ok(MsgPack(EndpointTopOutput { queues, totals }))
+}
@@ -0,0 +1,6 @@
+use axum::response::Html;
+
+// The page has no data of its own, so it's public; it calls the API with the API key entered into it.
+pub(crate) async fn endpoint_ui() -> Html<&'static str> {
+  Html(include_str!("ui.html"))
+}
@@ -0,0 +1,111 @@
+use chrono::Utc;
+use opentelemetry::trace::TraceContextExt;
+use parking_lot::Mutex;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// Upper bounds in seconds; there's an implicit final +Inf bucket.
+const BUCKETS: [f64; 13] = [
+  0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Clone)]
+struct Exemplar {
+  trace_id: String,
+  value: f64,
+  timestamp: f64,
+}
+
+pub(crate) struct LatencyHistogram {
+  counts: [AtomicU64; BUCKETS.len() + 1],
+  sum_us: AtomicU64,
+  // The most recent traced observation in each bucket, so a latency spike can be followed to a representative trace.
+  exemplars: Mutex<[Option<Exemplar>; BUCKETS.len() + 1]>,
+}
+
+impl LatencyHistogram {
+  fn new() -> Self {
+    Self {
+      counts: Default::default(),
+      sum_us: AtomicU64::new(0),
+      exemplars: Mutex::new(Default::default()),
+    }
+  }
+
+  /// Records a duration, using the current span's trace as the exemplar if it's being exported.
+  pub fn observe(&self, dur: Duration) {
+    let value = dur.as_secs_f64();
+    let i = BUCKETS
+      .iter()
+      .position(|&le| value <= le)
+      .unwrap_or(BUCKETS.len());
+    self.counts[i].fetch_add(1, Ordering::Relaxed);
+    self
+      .sum_us
+      .fetch_add(dur.as_micros() as u64, Ordering::Relaxed);
+    let ctx = Span::current().context();
+    let span = ctx.span();
+    let sc = span.span_context();
+    if sc.is_valid() && sc.is_sampled() {
+      self.exemplars.lock()[i] = Some(Exemplar {
+        trace_id: sc.trace_id().to_string(),
+        value,
+        timestamp: Utc::now().timestamp_millis() as f64 / 1000.0,
+      });
+    };
+  }
+
+  /// Writes this histogram in the Prometheus text format, or the OpenMetrics format with exemplars.
+  fn render(&self, name: &str, out: &mut String, with_exemplars: bool) {
+    let exemplars = self.exemplars.lock().clone();
+    writeln!(out, "# TYPE {name} histogram").unwrap();
+    let mut cumulative = 0;
+    for (i, count) in self.counts.iter().enumerate() {
+      cumulative += count.load(Ordering::Relaxed);
+      let le = BUCKETS
+        .get(i)
+        .map(|le| le.to_string())
+        .unwrap_or_else(|| "+Inf".to_string());
+      write!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}").unwrap();
+      if let Some(e) = exemplars[i].as_ref().filter(|_| with_exemplars) {
+        write!(
+          out,
+          " # {{trace_id=\"{}\"}} {} {}",
+          e.trace_id, e.value, e.timestamp
+        )
+        .unwrap();
+      };
+      out.push('\n');
+    }
+    let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    writeln!(out, "{name}_sum {sum}").unwrap();
+    writeln!(out, "{name}_count {cumulative}").unwrap();
+  }
+}
+
+pub(crate) struct QueueLatency {
+  pub poll: LatencyHistogram,
+  pub push: LatencyHistogram,
+}
+
+impl QueueLatency {
+  pub fn new() -> Self {
+    Self {
+      poll: LatencyHistogram::new(),
+      push: LatencyHistogram::new(),
+    }
+  }
+
+  pub fn render(&self, out: &mut String, with_exemplars: bool) {
+    self
+      .poll
+      .render("poll_latency_seconds", out, with_exemplars);
+    self
+      .push
+      .render("push_latency_seconds", out, with_exemplars);
+  }
+}
@@ -0,0 +1,797 @@
+mod archive;
+pub mod auth;
+mod backup_verify;
+mod bridge;
+mod bulkhead;
+mod cfg;
+mod cluster;
+mod disk_watchdog;
+mod endpoint;
+mod latency;
+mod message_move;
+mod mirror;
+mod offload;
+mod push_rate;
+mod queue_template;
+mod queue_tree;
+mod rate_limit;
+mod reaper;
+mod reload;
+mod replica;
+mod scheduler;
+mod shutdown;
+mod sigv4;
+mod statsd;
+mod telemetry;
+mod tenant;
+mod tls;
+mod tombstone_gc;
+mod webhook;
+
+use crate::archive::export;
+use crate::archive::import;
+use crate::auth::AuthProvider;
+use crate::auth::ClientCertificateAuthProvider;
+use crate::auth::JwtAuthProvider;
+use crate::auth::StaticTokenAuthProvider;
+use crate::backup_verify::backup_verify;
+use crate::bridge::Bridge;
+use crate::bulkhead::Bulkheads;
+use crate::cluster::start_cluster_heartbeat;
+use crate::cluster::Cluster;
+use crate::disk_watchdog::start_disk_watchdog;
+use crate::disk_watchdog::DiskWatchdog;
+use crate::endpoint::api_key::endpoint_list_api_keys;
+use crate::endpoint::api_key::endpoint_remove_api_key;
+use crate::endpoint::api_key::endpoint_set_api_key;
+use crate::endpoint::api_key::endpoint_set_token;
+use crate::endpoint::archive::endpoint_export;
+use crate::endpoint::archive::endpoint_import;
+use crate::endpoint::audit::audit_middleware;
+use crate::endpoint::auth::auth_middleware;
+use crate::endpoint::bridge::bridge_middleware;
+use crate::endpoint::bridge::endpoint_bridge_status;
+use crate::endpoint::bulkhead::bulkhead_middleware;
+use crate::endpoint::capabilities::endpoint_capabilities;
+use crate::endpoint::cluster::endpoint_cluster_queue_create;
+use crate::endpoint::cluster::endpoint_cluster_queue_delete;
+use crate::endpoint::cluster::endpoint_cluster_replicate;
+use crate::endpoint::cluster::endpoint_cluster_status;
+use crate::endpoint::compact::endpoint_compact;
+use crate::endpoint::config::endpoint_get_config;
+use crate::endpoint::config::endpoint_put_config;
+use crate::endpoint::disk::endpoint_disk;
+use crate::endpoint::drain::endpoint_get_drain;
+use crate::endpoint::drain::endpoint_post_drain;
+use crate::endpoint::error_codes::endpoint_error_codes;
+use crate::endpoint::fanout::endpoint_fanout_push;
+use crate::endpoint::generate::endpoint_generate;
+use crate::endpoint::healthz::endpoint_healthz;
+use crate::endpoint::healthz::endpoint_livez;
+use crate::endpoint::healthz::endpoint_readyz;
+use crate::endpoint::mirror::endpoint_mirror_status;
+use crate::endpoint::mirror::mirror_middleware;
+use crate::endpoint::queue::debug_sampling::endpoint_get_debug_sampling;
+use crate::endpoint::queue::debug_sampling::endpoint_post_debug_sampling;
+use crate::endpoint::queue::history::endpoint_message_history;
+use crate::endpoint::queue::metrics::endpoint_metrics;
+use crate::endpoint::queue::ops::endpoint_delete;
+use crate::endpoint::queue::ops::endpoint_delete_forced;
+use crate::endpoint::queue::ops::endpoint_list;
+use crate::endpoint::queue::ops::endpoint_nack;
+use crate::endpoint::queue::ops::endpoint_peek;
+use crate::endpoint::queue::ops::endpoint_peek_external_id;
+use crate::endpoint::queue::ops::endpoint_pin;
+use crate::endpoint::queue::ops::endpoint_poll;
+use crate::endpoint::queue::ops::endpoint_poll_stream;
+use crate::endpoint::queue::ops::endpoint_purge;
+use crate::endpoint::queue::ops::endpoint_push;
+use crate::endpoint::queue::ops::endpoint_push_stream;
+use crate::endpoint::queue::ops::endpoint_takeover;
+use crate::endpoint::queue::ops::endpoint_transaction;
+use crate::endpoint::queue::ops::endpoint_update;
+use crate::endpoint::queue::poll_transform::endpoint_get_poll_transform;
+use crate::endpoint::queue::poll_transform::endpoint_post_poll_transform;
+use crate::endpoint::queue::poll_transform::endpoint_post_subtree_poll_transform;
+use crate::endpoint::queue::routing::endpoint_get_routing;
+use crate::endpoint::queue::routing::endpoint_post_routing;
+use crate::endpoint::queue::schemas::endpoint_get_schema;
+use crate::endpoint::queue::schemas::endpoint_list_schemas;
+use crate::endpoint::queue::schemas::endpoint_register_schema;
+use crate::endpoint::queue::suspend::endpoint_get_suspend;
+use crate::endpoint::queue::suspend::endpoint_get_suspension_matrix;
+use crate::endpoint::queue::suspend::endpoint_post_subtree_suspend;
+use crate::endpoint::queue::suspend::endpoint_post_suspend;
+use crate::endpoint::queue::suspend::endpoint_put_suspension_matrix;
+use crate::endpoint::queue::throttle::endpoint_get_throttle;
+use crate::endpoint::queue::throttle::endpoint_post_subtree_throttle;
+use crate::endpoint::queue::throttle::endpoint_post_throttle;
+use crate::endpoint::queue::ttl::endpoint_get_ttl;
+use crate::endpoint::queue::ttl::endpoint_post_subtree_ttl;
+use crate::endpoint::queue::ttl::endpoint_post_ttl;
+use crate::endpoint::queue::visibility_timeout::endpoint_get_visibility_timeout;
+use crate::endpoint::queue::visibility_timeout::endpoint_post_visibility_timeout;
+use crate::endpoint::queue::watermark::endpoint_visibility_watermark;
+use crate::endpoint::queue::webhook::endpoint_get_webhook;
+use crate::endpoint::queue::webhook::endpoint_post_subtree_webhook;
+use crate::endpoint::queue::webhook::endpoint_post_webhook;
+use crate::endpoint::queues::QUEUE_CREATE_OK_MARKER_FILE;
+use crate::endpoint::replica::endpoint_replica_records;
+use crate::endpoint::replica::endpoint_replica_wal;
+use crate::endpoint::request_id::request_id_middleware;
+use crate::endpoint::schedules::endpoint_delete_schedule;
+use crate::endpoint::schedules::endpoint_get_schedule;
+use crate::endpoint::schedules::endpoint_list_schedules;
+use crate::endpoint::schedules::endpoint_put_schedule;
+use crate::endpoint::scrub::endpoint_scrub;
+use crate::endpoint::snapshot::endpoint_snapshot;
+use crate::endpoint::sqs::endpoint_sqs;
+use crate::endpoint::sqs::endpoint_sqs_queue;
+use crate::endpoint::sqs::sqs_receipt_handle_key;
+use crate::endpoint::subtree::endpoint_subtree;
+use crate::endpoint::tenants::endpoint_tenant_delete;
+use crate::endpoint::tenants::endpoint_tenants;
+use crate::endpoint::top::endpoint_top;
+use crate::endpoint::ui::endpoint_ui;
+use crate::endpoint::HttpCtx;
+use crate::message_move::MessageMoves;
+use crate::mirror::Mirror;
+use crate::offload::S3Client;
+use crate::push_rate::start_push_rate_sampler;
+use crate::push_rate::PushRates;
+use crate::queue_tree::queue_name_from_dir_name;
+use crate::rate_limit::RateLimiter;
+use crate::reaper::start_audit_log_trimmer;
+use crate::reaper::start_contents_dictionary_trainer;
+use crate::reaper::start_expiry_reaper;
+use crate::reload::request_body_limit;
+use crate::reload::start_config_watcher;
+use crate::replica::start_replica_sync;
+use crate::replica::Replica;
+use crate::scheduler::start_scheduler;
+use crate::shutdown::close_queues;
+use crate::shutdown::shutdown_signal;
+use crate::statsd::spawn_statsd_emitter;
+use crate::statsd::statsd_client;
+use crate::telemetry::init_tracing;
+use crate::tls::ClientCertificateAcceptor;
+use crate::tombstone_gc::start_tombstone_gc;
+use crate::webhook::start_webhook_delivery;
+use axum::extract::DefaultBodyLimit;
+use axum::middleware::from_fn;
+use axum::middleware::from_fn_with_state;
+use axum::routing::delete;
+use axum::routing::get;
+use axum::routing::post;
+use axum::routing::put;
+use axum::Router;
+use axum_server::Handle;
+use cfg::load_cfg;
+use cfg::load_command;
+use cfg::Command;
+use dashmap::DashMap;
+use endpoint::queues::endpoint_queue_create;
+use endpoint::queues::endpoint_queue_delete;
+use endpoint::queues::endpoint_queues;
+use endpoint::queues::endpoint_templates;
+use endpoint::quiesced::endpoint_quiesced;
+use endpoint::rate_limit::rate_limit_middleware;
+use libqueued::backup::apply_snapshot_increment;
+use libqueued::backup::is_snapshot_increment;
+use libqueued::compression::ContentsCodec;
+use libqueued::db::FORMAT_VERSION;
+use libqueued::db::MIN_FORMAT_VERSION;
+use libqueued::fanout::FanoutLog;
+use libqueued::id_gen::IdStrategy;
+use libqueued::id_gen::MAX_SNOWFLAKE_NODE_ID;
+use libqueued::quota::SharedQuota;
+use libqueued::storage::StorageBackend;
+use libqueued::Queued;
+use parking_lot::RwLock;
+use service_toolkit::server::build_port_server;
+use service_toolkit::server::build_port_server_with_tls;
+use service_toolkit::server::build_unix_socket_server;
+use service_toolkit::server::TlsCfg;
+use std::collections::HashSet;
+use std::fs::read;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tokio::spawn;
+use tracing::info;
+use tracing::warn;
+
+fn copy_dir_all(src: &Path, dst: &Path) -> std::io::Result<()> {
+  std::fs::create_dir_all(dst)?;
+  for e in std::fs::read_dir(src)? {
+    let e = e?;
+    if e.metadata()?.is_dir() {
+      copy_dir_all(&e.path(), &dst.join(e.file_name()))?;
+    } else {
+      std::fs::copy(e.path(), dst.join(e.file_name()))?;
+    };
+  }
+  Ok(())
+}
+
+// Queues created since the increment's base are in it in full, and queues deleted since aren't in it at all.
+fn apply_increment_dir(src: &Path, dst: &Path) -> Result<(), String> {
+  let mut names = HashSet::new();
+  for e in std::fs::read_dir(src).map_err(|err| err.to_string())? {
+    let e = e.map_err(|err| err.to_string())?;
+    let queue_dir = dst.join(e.file_name());
+    if is_snapshot_increment(&e.path()) {
+      apply_snapshot_increment(&queue_dir, &e.path())?;
+    } else {
+      if queue_dir.exists() {
+        std::fs::remove_dir_all(&queue_dir).map_err(|err| err.to_string())?;
+      };
+      copy_dir_all(&e.path(), &queue_dir).map_err(|err| err.to_string())?;
+    };
+    names.insert(e.file_name());
+  }
+  for e in std::fs::read_dir(dst).map_err(|err| err.to_string())? {
+    let e = e.map_err(|err| err.to_string())?;
+    if !names.contains(&e.file_name()) {
+      std::fs::remove_dir_all(e.path()).map_err(|err| err.to_string())?;
+    };
+  }
+  Ok(())
+}
+
+/// Loads the config from the CLI args, env vars, and config file, sets up logging and tracing, and runs the server until it stops. `auth_providers` are used to authenticate requests in addition to the built-in providers, and are tried first. If a subcommand was provided, runs it instead and exits.
+pub async fn run(auth_providers: Vec<Arc<dyn AuthProvider>>) {
+  if let Some(command) = load_command() {
+    init_tracing(None);
+    let ok = match command {
+      Command::BackupVerify {
+        path,
+        increments,
+        scratch_dir,
+      } => backup_verify(&path, &increments, scratch_dir),
+      Command::Export {
+        server,
+        path,
+        api_key,
+      } => export(server.trim_end_matches('/'), api_key.as_deref(), &path).await,
+      Command::Import {
+        server,
+        path,
+        api_key,
+      } => import(server.trim_end_matches('/'), api_key.as_deref(), &path).await,
+    };
+    std::process::exit(if ok { 0 } else { 1 });
+  };
+  let cfg = load_cfg();
+  init_tracing(cfg.otlp_endpoint.as_deref());
+  #[cfg(feature = "fault_injection")]
+  if !cfg.faults.is_empty() {
+    warn!(faults = ?cfg.faults, "injecting faults");
+    libqueued::fault::set_faults(cfg.faults.clone());
+  };
+  let format_version = cfg.format_compat.unwrap_or(FORMAT_VERSION);
+  assert!(
+    cfg.disk_watchdog.is_none_or(|d| 0.0 < d.min_free_percent
+      && d.min_free_percent <= d.resume_free_percent
+      && d.resume_free_percent <= 100.0),
+    "disk min free percent must be between 0 and 100, and not more than the resume free percent"
+  );
+  assert!(
+    cfg
+      .tombstone_gc
+      .and_then(|gc| gc.max_percent)
+      .is_none_or(|p| 0.0 < p && p <= 100.0),
+    "tombstone GC max percent must be between 0 and 100"
+  );
+  assert!(
+    !cfg.watch_config || cfg.config_file.is_some(),
+    "watching the config file requires a config file"
+  );
+  // Followers catch up by reading the leader's WAL, which only RocksDB has.
+  assert!(
+    cfg.storage == StorageBackend::RocksDb || cfg.cluster_peers.is_empty(),
+    "clustering requires RocksDB storage"
+  );
+  // These are also checked when loading each queue, but there may not be any yet.
+  assert!(
+    (MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&format_version),
+    "format compatibility version must be between {MIN_FORMAT_VERSION} and {FORMAT_VERSION}"
+  );
+  assert!(
+    cfg.offload_s3_bucket.is_none() || format_version >= 2,
+    "offloading contents requires format compatibility version 2 or newer"
+  );
+  assert!(
+    cfg.id_strategy != IdStrategy::Random || format_version >= 9,
+    "random IDs require format compatibility version 9 or newer"
+  );
+  assert!(
+    cfg.contents_compression.is_none() || format_version >= 10,
+    "contents compression requires format compatibility version 10 or newer"
+  );
+  if let Some(c) = cfg.contents_compression.filter(|c| c.dictionary_bytes > 0) {
+    assert!(
+      c.codec == ContentsCodec::Zstd,
+      "contents compression dictionaries require zstd contents compression"
+    );
+    assert!(
+      format_version >= 11,
+      "contents compression dictionaries require format compatibility version 11 or newer"
+    );
+  };
+  if let IdStrategy::Snowflake { node_id } = cfg.id_strategy {
+    assert!(
+      node_id <= MAX_SNOWFLAKE_NODE_ID,
+      "Snowflake node ID must be at most {MAX_SNOWFLAKE_NODE_ID}"
+    );
+  };
+  assert!(
+    cfg
+      .maintenance_push_cap_percent
+      .is_none_or(|p| (1..=100).contains(&p)),
+    "maintenance push cap percent must be between 1 and 100"
+  );
+  assert!(cfg.index_shards > 0, "index shards must be at least 1");
+  assert!(
+    cfg.recommended_visibility_timeout_factor >= 1.0,
+    "recommended visibility timeout factor must be at least 1"
+  );
+  let queue_cfg = libqueued::QueuedCfg {
+    batch_sync_delay: cfg.batch_sync_delay,
+    storage_breaker_threshold: cfg.storage_breaker_threshold,
+    storage_breaker_base_backoff: cfg.storage_breaker_base_backoff,
+    storage_breaker_max_backoff: cfg.storage_breaker_max_backoff,
+    max_message_size: cfg.reloadable.max_message_size,
+    min_visibility_timeout_secs: cfg.reloadable.min_visibility_timeout_secs,
+    max_visibility_timeout_secs: cfg.reloadable.max_visibility_timeout_secs,
+    recommended_visibility_timeout_factor: cfg.recommended_visibility_timeout_factor,
+    inline_max_contents_len: cfg.inline_max_contents_len,
+    index_snapshot_interval: cfg.index_snapshot_interval,
+    snapshot_wal_retention: cfg.snapshot_wal_retention,
+    audit_log: cfg.audit_log,
+    index_shards: cfg.index_shards,
+    verify_index: cfg.verify_index,
+    zstd_compression: cfg.zstd_compression,
+    rocksdb: cfg.rocksdb.clone(),
+    contents_compression: cfg.contents_compression,
+    release_pacing_max_per_sec: cfg.reloadable.release_pacing_max_per_sec,
+    redelivery_backoff: cfg.reloadable.redelivery_backoff,
+    maintenance_push_cap_percent: cfg.maintenance_push_cap_percent,
+    quota: cfg.queue_quota,
+    shared_quotas: (!cfg.total_quota.is_empty())
+      .then(|| Arc::new(SharedQuota::new(cfg.total_quota)))
+      .into_iter()
+      .collect(),
+    offload_min_contents_len: cfg.offload_min_contents_len,
+    format_version,
+    id_strategy: cfg.id_strategy,
+    poll_order: cfg.poll_order,
+    storage: cfg.storage,
+    // Followers and replicas apply the leader's writes, including prepared pushes, which only the leader's log can resolve.
+    fanout_log: (cfg.cluster_node_id.is_none() && cfg.replica_of.is_none())
+      .then(|| Arc::new(FanoutLog::new(cfg.data_dir.clone()))),
+    ..Default::default()
+  };
+  if let Some(src) = &cfg.restore_from {
+    assert!(
+      std::fs::read_dir(&cfg.data_dir)
+        .expect("read data dir")
+        .next()
+        .is_none(),
+      "data dir must be empty to restore from a snapshot"
+    );
+    info!(
+      src = format!("{:?}", src),
+      "restoring data dir from snapshot"
+    );
+    copy_dir_all(src, &cfg.data_dir).expect("copy snapshot into data dir");
+  };
+  assert!(
+    cfg.restore_from.is_some() || cfg.restore_increments.is_empty(),
+    "incremental snapshots must be restored on top of a snapshot"
+  );
+  for src in &cfg.restore_increments {
+    info!(src = format!("{:?}", src), "applying incremental snapshot");
+    if let Err(err) = apply_increment_dir(src, &cfg.data_dir) {
+      panic!("failed to apply incremental snapshot {src:?}: {err}");
+    };
+  }
+  // Nodes authenticate to each other with the global API key, and the endpoints between them apply raw writes, so they must never be open.
+  assert!(
+    cfg.cluster_node_id.is_none() || cfg.global_api_key.is_some(),
+    "clustering requires a global API key"
+  );
+  let cluster = cfg.cluster_node_id.map(|node_id| {
+    Arc::new(Cluster::new(
+      node_id,
+      cfg.cluster_peers.clone(),
+      cfg.cluster_min_in_sync_peers,
+      cfg.global_api_key.clone(),
+      &cfg.data_dir,
+    ))
+  });
+  assert!(
+    cfg.replica_of.is_none() || cluster.is_none(),
+    "a read-only replica cannot be part of a cluster"
+  );
+  let replica = cfg.replica_of.clone().map(|url| {
+    Arc::new(Replica::new(
+      url,
+      cfg.replica_api_key.clone(),
+      cfg.replica_sync_interval,
+    ))
+  });
+  let s3 = cfg.offload_s3_bucket.as_ref().map(|bucket| {
+    Arc::new(S3Client::new(
+      cfg
+        .offload_s3_endpoint
+        .clone()
+        .expect("no S3 endpoint provided for offloading"),
+      bucket.clone(),
+      cfg.offload_s3_region.clone(),
+      cfg
+        .offload_s3_access_key_id
+        .clone()
+        .expect("no S3 access key ID provided for offloading"),
+      cfg
+        .offload_s3_secret_access_key
+        .clone()
+        .expect("no S3 secret access key provided for offloading"),
+    ))
+  });
+  assert!(
+    cfg.enable_auth || cfg.api_keys.is_empty(),
+    "API keys were provided but auth is not enabled"
+  );
+  assert!(
+    cfg.ssl_ca.is_some() || cfg.ssl_client_identities.is_empty(),
+    "client certificate identities were provided but mutual TLS is not enabled"
+  );
+  assert!(
+    (0.0..=100.0).contains(&cfg.mirror_percent),
+    "mirror percentage must be between 0 and 100"
+  );
+  assert!(
+    cfg.bridge_url.is_none() || cfg.bridge_outbox_dir.is_some(),
+    "a bridge outbox dir is required when bridging"
+  );
+  let body_limit = request_body_limit(cfg.reloadable.max_message_size);
+  let api_keys = cfg
+    .enable_auth
+    .then(|| Arc::new(cfg.api_keys.iter().cloned().collect::<DashMap<_, _>>()));
+  let mut auth_providers = auth_providers;
+  if let Some(api_keys) = &api_keys {
+    auth_providers.push(Arc::new(StaticTokenAuthProvider::new(api_keys.clone())));
+  };
+  if let Some(secret) = &cfg.jwt_secret {
+    auth_providers.push(Arc::new(JwtAuthProvider::new(secret.as_bytes().to_vec())));
+  };
+  if !cfg.ssl_client_identities.is_empty() {
+    auth_providers.push(Arc::new(ClientCertificateAuthProvider::new(
+      cfg.ssl_client_identities.iter().cloned().collect(),
+    )));
+  };
+  let bridge = match &cfg.bridge_url {
+    Some(url) => {
+      let outbox_dir = cfg.bridge_outbox_dir.as_ref().unwrap();
+      assert!(
+        !outbox_dir.starts_with(&cfg.data_dir),
+        "the bridge outbox dir must not be inside the data dir"
+      );
+      Some(
+        Bridge::start(
+          url.clone(),
+          cfg.bridge_api_key.clone(),
+          outbox_dir,
+          queue_cfg.format_version,
+        )
+        .await,
+      )
+    }
+    None => None,
+  };
+  let ctx = Arc::new(HttpCtx {
+    api_keys,
+    auth_providers,
+    bridge,
+    bulkheads: cfg
+      .bulkhead
+      .is_enabled()
+      .then(|| Bulkheads::new(cfg.bulkhead)),
+    cluster: cluster.clone(),
+    data_dir: cfg.data_dir.clone(),
+    disk_watchdog: cfg.disk_watchdog.map(DiskWatchdog::new),
+    draining: AtomicBool::new(false),
+    enable_generator: cfg.enable_generator,
+    enable_sqs_api: cfg.enable_sqs_api,
+    global_api_key: cfg.global_api_key.clone(),
+    latency: DashMap::new(),
+    max_request_body_size: body_limit,
+    message_moves: MessageMoves::default(),
+    mirror: cfg.mirror_url.clone().map(|url| {
+      Arc::new(Mirror::new(
+        url,
+        cfg.mirror_api_key.clone(),
+        cfg.mirror_percent,
+      ))
+    }),
+    push_rates: PushRates::default(),
+    queue_cfg,
+    queue_templates: cfg.queue_templates.clone(),
+    queues: DashMap::new(),
+    rate_limiter: cfg
+      .reloadable
+      .rate_limit
+      .is_enabled()
+      .then(|| RateLimiter::start(cfg.reloadable.rate_limit)),
+    reloadable: RwLock::new(cfg.reloadable),
+    reloadable_overrides: cfg.reloadable_overrides.clone(),
+    replica: replica.clone(),
+    s3,
+    sqs_access_keys: cfg.sqs_access_keys.clone(),
+    sqs_receipt_handle_key: sqs_receipt_handle_key(cfg.global_api_key.as_deref()),
+    statsd_endpoint: cfg.statsd,
+    statsd_prefix: cfg.statsd_prefix.clone(),
+    statsd_tags: cfg.statsd_tags.clone(),
+    tenants: cfg
+      .tenants
+      .iter()
+      .map(|(name, t)| (name.clone(), Arc::new(SharedQuota::new(t.limits()))))
+      .collect(),
+  });
+
+  info!(
+    dir = format!("{:?}", cfg.data_dir),
+    "loading queues from data dir"
+  );
+  for d in std::fs::read_dir(&cfg.data_dir).expect("read data dir") {
+    let d = d.expect("read data dir entry");
+    let m = d.metadata().expect("get data dir entry metadata");
+    if !m.is_dir() {
+      continue;
+    };
+    match std::fs::read_to_string(d.path().join(QUEUE_CREATE_OK_MARKER_FILE)) {
+      Ok(c) => assert_eq!(&c, "", "unexpected {QUEUE_CREATE_OK_MARKER_FILE} contents in {d:?}"),
+      Err(e) if e.kind() == ErrorKind::NotFound => panic!("no {QUEUE_CREATE_OK_MARKER_FILE} found in {d:?}, which could indicate corruption, external tampering, or a failed creation/deletion (in which case the containing folder can be safely deleted, and must be deleted in order to proceed)"),
+      Err(e) => panic!("failed to read {QUEUE_CREATE_OK_MARKER_FILE} in {d:?}: {e}"),
+    };
+    let name = queue_name_from_dir_name(
+      &d.file_name()
+        .into_string()
+        .expect("data dir entry as UTF-8 string"),
+    );
+    let q = Arc::new(Queued::load_and_start(&d.path(), ctx.queue_cfg_for(&name)).await);
+    info!(name, "loaded queue");
+    let tenant = ctx.tenant_of(&name).map(|(t, _)| t);
+    if !ctx.tenants.is_empty() && tenant.is_none() {
+      warn!(name, "queue doesn't belong to any tenant");
+    };
+    if let Some(addr) = cfg.statsd {
+      spawn_statsd_emitter(
+        addr,
+        &cfg.statsd_prefix,
+        &cfg.statsd_tags,
+        &name,
+        tenant,
+        Arc::downgrade(&q),
+      );
+    };
+    assert!(ctx.queues.insert(name, q).is_none());
+  }
+  info!(count = ctx.queues.len(), "loaded all queues");
+  if let Some(log) = &ctx.queue_cfg.fanout_log {
+    log
+      .clear()
+      .expect("remove records of applied multi-queue pushes");
+  };
+
+  if let Some(cluster) = cluster {
+    start_cluster_heartbeat(cluster, Arc::downgrade(&ctx)).await;
+  };
+  if let Some(replica) = replica {
+    start_replica_sync(replica, Arc::downgrade(&ctx));
+  };
+  start_expiry_reaper(Arc::downgrade(&ctx));
+  start_push_rate_sampler(Arc::downgrade(&ctx));
+  if let Some(retention) = cfg.audit_log_retention.filter(|_| cfg.audit_log) {
+    start_audit_log_trimmer(Arc::downgrade(&ctx), retention);
+  };
+  start_scheduler(Arc::downgrade(&ctx));
+  if cfg
+    .contents_compression
+    .is_some_and(|c| c.dictionary_bytes > 0)
+  {
+    start_contents_dictionary_trainer(Arc::downgrade(&ctx));
+  };
+  if ctx.disk_watchdog.is_some() {
+    start_disk_watchdog(
+      Arc::downgrade(&ctx),
+      cfg
+        .statsd
+        .map(|addr| statsd_client(addr, &cfg.statsd_prefix, &cfg.statsd_tags)),
+    );
+  };
+  if let Some(gc) = cfg.tombstone_gc {
+    start_tombstone_gc(Arc::downgrade(&ctx), gc);
+  };
+  if let Some(path) = cfg.config_file.clone().filter(|_| cfg.watch_config) {
+    start_config_watcher(Arc::downgrade(&ctx), path, cfg.config_file_table.clone());
+  };
+  start_webhook_delivery(Arc::downgrade(&ctx));
+
+  #[rustfmt::skip]
+  let mut app = Router::new()
+    .route("/admin/compact", post(endpoint_compact))
+    .route("/admin/config", get(endpoint_get_config).put(endpoint_put_config))
+    .route("/admin/disk", get(endpoint_disk))
+    .route("/admin/drain", get(endpoint_get_drain).post(endpoint_post_drain))
+    .route("/admin/export", get(endpoint_export))
+    .route("/admin/import", post(endpoint_import))
+    .route("/admin/scrub", post(endpoint_scrub))
+    .route("/admin/snapshot", post(endpoint_snapshot))
+    .route("/admin/suspension", get(endpoint_get_suspension_matrix).put(endpoint_put_suspension_matrix))
+    .route("/admin/tenants", get(endpoint_tenants))
+    .route("/admin/tenants/:tenant", delete(endpoint_tenant_delete))
+    .route("/admin/tokens", get(endpoint_list_api_keys))
+    .route("/admin/tokens/:token", put(endpoint_set_token).delete(endpoint_remove_api_key))
+    .route("/admin/top", get(endpoint_top))
+    .route("/capabilities", get(endpoint_capabilities))
+    .route("/error-codes", get(endpoint_error_codes))
+    .route("/healthz", get(endpoint_healthz))
+    .route("/livez", get(endpoint_livez))
+    .route("/readyz", get(endpoint_readyz))
+    .route("/api-keys", get(endpoint_list_api_keys))
+    .route("/api-key/:apiKey", put(endpoint_set_api_key).delete(endpoint_remove_api_key))
+    .route("/bridge/status", get(endpoint_bridge_status))
+    .route("/cluster/queue/:queue", put(endpoint_cluster_queue_create).delete(endpoint_cluster_queue_delete))
+    .route("/cluster/replicate/:queue", post(endpoint_cluster_replicate))
+    .route("/cluster/status", get(endpoint_cluster_status))
+    .route("/messages/push", post(endpoint_fanout_push))
+    .route("/mirror/status", get(endpoint_mirror_status))
+    .route("/queue/:queue", delete(endpoint_queue_delete))
+    .route("/queue/:queue", put(endpoint_queue_create))
+    .route("/queue/:queue/debug-sampling", get(endpoint_get_debug_sampling).post(endpoint_post_debug_sampling))
+    .route("/queue/:queue/messages", get(endpoint_list))
+    .route("/queue/:queue/messages/:id", delete(endpoint_delete_forced))
+    .route("/queue/:queue/messages/:id/history", get(endpoint_message_history))
+    .route("/queue/:queue/messages/by-external-id/:external_id", get(endpoint_peek_external_id))
+    .route("/queue/:queue/messages/delete", post(endpoint_delete))
+    .route("/queue/:queue/messages/nack", post(endpoint_nack))
+    .route("/queue/:queue/messages/peek", post(endpoint_peek))
+    .route("/queue/:queue/messages/pin", post(endpoint_pin))
+    .route("/queue/:queue/messages/poll", post(endpoint_poll))
+    .route("/queue/:queue/messages/poll-stream", post(endpoint_poll_stream))
+    .route("/queue/:queue/messages/push", post(endpoint_push))
+    .route("/queue/:queue/messages/push-stream", post(endpoint_push_stream))
+    .route("/queue/:queue/messages/takeover", post(endpoint_takeover))
+    .route("/queue/:queue/messages/transaction", post(endpoint_transaction))
+    .route("/queue/:queue/messages/update", post(endpoint_update))
+    .route("/queue/:queue/metrics", get(endpoint_metrics))
+    .route("/queue/:queue/poll-transform", get(endpoint_get_poll_transform).post(endpoint_post_poll_transform))
+    .route("/queue/:queue/purge", post(endpoint_purge))
+    .route("/queue/:queue/routing", get(endpoint_get_routing).post(endpoint_post_routing))
+    .route("/queue/:queue/schemas", get(endpoint_list_schemas).post(endpoint_register_schema))
+    .route("/queue/:queue/schemas/:version", get(endpoint_get_schema))
+    .route("/queue/:queue/suspend", get(endpoint_get_suspend).post(endpoint_post_suspend))
+    .route("/queue/:queue/throttle", get(endpoint_get_throttle).post(endpoint_post_throttle))
+    .route("/queue/:queue/ttl", get(endpoint_get_ttl).post(endpoint_post_ttl))
+    .route("/queue/:queue/visibility-timeout", get(endpoint_get_visibility_timeout).post(endpoint_post_visibility_timeout))
+    .route("/queue/:queue/visibility-watermark", get(endpoint_visibility_watermark))
+    .route("/queue/:queue/webhook", get(endpoint_get_webhook).post(endpoint_post_webhook))
+    .route("/queues", get(endpoint_queues))
+    .route("/replica/records/:queue", get(endpoint_replica_records))
+    .route("/replica/wal/:queue", get(endpoint_replica_wal))
+    .route("/schedules", get(endpoint_list_schedules))
+    .route("/schedules/:queue/:name", get(endpoint_get_schedule).put(endpoint_put_schedule).delete(endpoint_delete_schedule))
+    .route("/subtree/:root", get(endpoint_subtree))
+    .route("/subtree/:root/poll-transform", post(endpoint_post_subtree_poll_transform))
+    .route("/subtree/:root/suspend", post(endpoint_post_subtree_suspend))
+    .route("/subtree/:root/throttle", post(endpoint_post_subtree_throttle))
+    .route("/subtree/:root/ttl", post(endpoint_post_subtree_ttl))
+    .route("/subtree/:root/webhook", post(endpoint_post_subtree_webhook))
+    .route("/templates", get(endpoint_templates))
+    .route("/quiesced", get(endpoint_quiesced))
+    .route("/ui", get(endpoint_ui));
+  if cfg.enable_generator {
+    app = app.route("/admin/generate", post(endpoint_generate));
+  };
+  if cfg.enable_sqs_api {
+    #[rustfmt::skip]
+    {
+      app = app
+        .route("/sqs", post(endpoint_sqs))
+        .route("/sqs/", post(endpoint_sqs))
+        .route("/sqs/:queue", post(endpoint_sqs_queue));
+    };
+  };
+  if ctx.bridge.is_some() {
+    // This must be inside the mirror and rate limit layers, so that only pushes accepted by this server are forwarded.
+    app = app.route_layer(from_fn_with_state(ctx.clone(), bridge_middleware));
+  };
+  if ctx.mirror.is_some() {
+    // This must be inside the auth layer so that unauthorized requests aren't mirrored.
+    app = app.route_layer(from_fn_with_state(ctx.clone(), mirror_middleware));
+  };
+  if ctx.rate_limiter.is_some() {
+    // This must be inside the auth layer so that clients are only identified by valid API keys, and outside the mirror layer so that rejected requests aren't mirrored.
+    app = app.route_layer(from_fn_with_state(ctx.clone(), rate_limit_middleware));
+  };
+  if ctx.bulkheads.is_some() {
+    // This must be outside the rate limit layer so that requests rejected by it don't take up capacity, but inside the auth layer so that unauthorized requests don't either.
+    app = app.route_layer(from_fn_with_state(ctx.clone(), bulkhead_middleware));
+  };
+  if cfg.audit_log {
+    // This must be inside the auth layer so that clients are only identified by valid API keys.
+    app = app.route_layer(from_fn_with_state(ctx.clone(), audit_middleware));
+  };
+  let app = app
+    .route_layer(from_fn_with_state(ctx.clone(), auth_middleware))
+    .layer(DefaultBodyLimit::max(body_limit))
+    // This must be the outermost layer, so that everything done for a request is in its span, and every response has an ID.
+    .layer(from_fn(request_id_middleware))
+    .with_state(ctx.clone());
+
+  match cfg.unix_socket {
+    Some(socket_path) => {
+      info!(
+        unix_socket_path = socket_path.to_string_lossy().to_string(),
+        "server started"
+      );
+      build_unix_socket_server(&socket_path, cfg.unix_socket_mode)
+        .await
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal(ctx.clone()))
+        .await
+        .unwrap();
+    }
+    None => {
+      match (cfg.ssl_cert, cfg.ssl_key, cfg.ssl_ca) {
+        (Some(cert), Some(key), ca) => {
+          info!(
+            interface = cfg.interface.to_string(),
+            port = cfg.port,
+            mtls = ca.is_some(),
+            "HTTPS server started"
+          );
+          build_port_server_with_tls(cfg.interface, cfg.port, &TlsCfg {
+            cert: read(cert).expect("read SSL certificate file"),
+            key: read(key).expect("read SSL key file"),
+            ca: ca.map(|ca| read(ca).expect("read SSL CA file")),
+          })
+          .map(ClientCertificateAcceptor::new)
+          .handle({
+            let handle = Handle::new();
+            spawn({
+              let ctx = ctx.clone();
+              let handle = handle.clone();
+              async move {
+                shutdown_signal(ctx).await;
+                handle.graceful_shutdown(None);
+              }
+            });
+            handle
+          })
+          .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+          .await
+          .unwrap();
+        }
+        (None, None, None) => {
+          info!(
+            interface = cfg.interface.to_string(),
+            port = cfg.port,
+            "HTTP server started"
+          );
+          build_port_server(cfg.interface, cfg.port)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown_signal(ctx.clone()))
+            .await
+            .unwrap();
+        }
+        _ => panic!("invalid SSL configuration"),
+      };
+    }
+  };
+  close_queues(&ctx).await;
+}
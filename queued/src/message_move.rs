@@ -0,0 +1,301 @@
+use crate::endpoint::HttpCtx;
+use chrono::Utc;
+use libqueued::op::delete::OpDeleteInput;
+use libqueued::op::delete::OpDeleteInputMessage;
+use libqueued::op::nack::OpNackInput;
+use libqueued::op::poll::OpPollInput;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use parking_lot::Mutex;
+use rand::thread_rng;
+use rand::Rng;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::info;
+use tracing::warn;
+
+const MOVE_BATCH: usize = 10;
+// Long enough that a message doesn't become visible again in the source queue while it's being moved.
+const MOVE_VISIBILITY_TIMEOUT_SECS: i64 = 60;
+// Like SQS, only the most recent tasks of each source queue are kept.
+const MAX_TASKS_PER_SOURCE: usize = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MoveStatus {
+  Running,
+  Completed,
+  Cancelling,
+  Cancelled,
+  Failed,
+}
+
+impl MoveStatus {
+  pub(crate) fn as_str(self) -> &'static str {
+    match self {
+      MoveStatus::Running => "RUNNING",
+      MoveStatus::Completed => "COMPLETED",
+      MoveStatus::Cancelling => "CANCELLING",
+      MoveStatus::Cancelled => "CANCELLED",
+      MoveStatus::Failed => "FAILED",
+    }
+  }
+}
+
+pub(crate) struct MoveTask {
+  pub(crate) handle: String,
+  pub(crate) source_arn: String,
+  pub(crate) source: String,
+  pub(crate) destination_arn: String,
+  pub(crate) destination: String,
+  pub(crate) max_per_sec: Option<u32>,
+  pub(crate) started_at_ms: i64,
+  // The number of messages in the source queue when the task started. Messages pushed afterwards aren't moved.
+  pub(crate) to_move: u64,
+  moved: AtomicU64,
+  cancelled: AtomicBool,
+  // The status and failure reason, if any.
+  state: Mutex<(MoveStatus, Option<String>)>,
+}
+
+impl MoveTask {
+  pub(crate) fn moved(&self) -> u64 {
+    self.moved.load(Ordering::Relaxed)
+  }
+
+  pub(crate) fn status(&self) -> (MoveStatus, Option<String>) {
+    self.state.lock().clone()
+  }
+
+  fn finish(&self, status: MoveStatus, reason: Option<String>) {
+    *self.state.lock() = (status, reason);
+  }
+}
+
+pub(crate) enum MoveError {
+  AlreadyRunning,
+  NotFound,
+  NotRunning,
+}
+
+/// Tasks that move messages from one queue to another, e.g. to redrive a dead letter queue. They aren't persisted, so tasks running when the server stops aren't resumed, although moved messages stay moved.
+#[derive(Default)]
+pub(crate) struct MessageMoves {
+  // Most recently started last.
+  tasks: Mutex<Vec<Arc<MoveTask>>>,
+}
+
+impl MessageMoves {
+  /// Starts moving all messages currently in `source` to `destination`. Only one task can run per source queue at a time.
+  pub(crate) fn start(
+    &self,
+    ctx: Weak<HttpCtx>,
+    (source_arn, source): (String, String),
+    (destination_arn, destination): (String, String),
+    to_move: u64,
+    max_per_sec: Option<u32>,
+  ) -> Result<Arc<MoveTask>, MoveError> {
+    let task = Arc::new(MoveTask {
+      handle: hex::encode(thread_rng().gen::<[u8; 16]>()),
+      source_arn,
+      source,
+      destination_arn,
+      destination,
+      max_per_sec,
+      started_at_ms: Utc::now().timestamp_millis(),
+      to_move,
+      moved: AtomicU64::new(0),
+      cancelled: AtomicBool::new(false),
+      state: Mutex::new((MoveStatus::Running, None)),
+    });
+    {
+      let mut tasks = self.tasks.lock();
+      let same_source = |t: &MoveTask| t.source == task.source;
+      if tasks.iter().any(|t| {
+        same_source(t) && matches!(t.status().0, MoveStatus::Running | MoveStatus::Cancelling)
+      }) {
+        return Err(MoveError::AlreadyRunning);
+      };
+      tasks.push(Arc::clone(&task));
+      let n = tasks.iter().filter(|t| same_source(t)).count();
+      if n > MAX_TASKS_PER_SOURCE {
+        let oldest = tasks.iter().position(|t| same_source(t)).unwrap();
+        tasks.remove(oldest);
+      };
+    };
+    info!(
+      source = task.source,
+      destination = task.destination,
+      to_move,
+      "message move task started"
+    );
+    spawn(run(ctx, Arc::clone(&task)));
+    Ok(task)
+  }
+
+  /// The most recent tasks of the source queue first.
+  pub(crate) fn list(&self, source: &str, limit: usize) -> Vec<Arc<MoveTask>> {
+    self
+      .tasks
+      .lock()
+      .iter()
+      .rev()
+      .filter(|t| t.source == source)
+      .take(limit)
+      .cloned()
+      .collect()
+  }
+
+  pub(crate) fn get(&self, handle: &str) -> Option<Arc<MoveTask>> {
+    self
+      .tasks
+      .lock()
+      .iter()
+      .find(|t| t.handle == handle)
+      .cloned()
+  }
+
+  /// The task stops after its current batch.
+  pub(crate) fn cancel(&self, handle: &str) -> Result<Arc<MoveTask>, MoveError> {
+    let task = self.get(handle).ok_or(MoveError::NotFound)?;
+    let mut state = task.state.lock();
+    if state.0 != MoveStatus::Running {
+      return Err(MoveError::NotRunning);
+    };
+    state.0 = MoveStatus::Cancelling;
+    task.cancelled.store(true, Ordering::Relaxed);
+    drop(state);
+    Ok(task)
+  }
+}
+
+// Returns the number of messages moved, or None if there are none left to move.
+async fn move_batch(ctx: &HttpCtx, task: &MoveTask, count: usize) -> Result<Option<u64>, String> {
+  if ctx.is_follower() {
+    return Err("no longer the leader".to_string());
+  };
+  let src = ctx
+    .q(&task.source)
+    .map_err(|_| "source queue was deleted".to_string())?;
+  let dest = ctx
+    .q(&task.destination)
+    .map_err(|_| "destination queue was deleted".to_string())?;
+  let polled = src
+    .poll(OpPollInput {
+      count,
+      visibility_timeout_secs: MOVE_VISIBILITY_TIMEOUT_SECS,
+      ignore_existing_visibility_timeouts: false,
+      prefer_group: None,
+      fields: None,
+    })
+    .await
+    .map_err(|err| format!("failed to poll source queue: {err:?}"))?
+    .messages;
+  if polled.is_empty() {
+    return Ok(None);
+  };
+  let res = dest
+    .push(OpPushInput {
+      messages: polled
+        .iter()
+        .map(|m| OpPushInputMessage {
+          contents: m.contents.clone(),
+          visibility_timeout_secs: 0,
+          visibility_jitter_secs: 0,
+          priority: 0,
+          attributes: m.attributes.clone(),
+          ttl_secs: None,
+          group_id: m.group_id.clone(),
+          external_id: None,
+        })
+        .collect(),
+    })
+    .await;
+  if let Err(err) = res {
+    // Make the messages visible again straight away, instead of after the visibility timeout. If this fails, they become visible once it passes.
+    for m in polled.iter() {
+      let _ = src
+        .nack(OpNackInput {
+          id: m.id,
+          poll_tag: m.poll_tag,
+          delay_secs: 0,
+        })
+        .await;
+    }
+    return Err(format!("failed to push to destination queue: {err:?}"));
+  };
+  // If this fails, the messages become visible again in the source queue once their visibility timeout passes, so moving them again would duplicate them in the destination queue.
+  src
+    .delete(OpDeleteInput {
+      messages: polled
+        .iter()
+        .map(|m| OpDeleteInputMessage {
+          id: m.id,
+          poll_tag: m.poll_tag,
+        })
+        .collect(),
+    })
+    .await
+    .map_err(|err| format!("failed to delete from source queue: {err:?}"))?;
+  Ok(Some(polled.len() as u64))
+}
+
+async fn run(ctx: Weak<HttpCtx>, task: Arc<MoveTask>) {
+  let started = Instant::now();
+  let (status, reason) = loop {
+    if task.cancelled.load(Ordering::Relaxed) {
+      break (MoveStatus::Cancelled, None);
+    };
+    let moved = task.moved();
+    if moved >= task.to_move {
+      break (MoveStatus::Completed, None);
+    };
+    let mut count = MOVE_BATCH.min((task.to_move - moved) as usize);
+    if let Some(rate) = task.max_per_sec {
+      count = count.min(rate as usize);
+      // Wait until moving another batch wouldn't exceed the rate since the task started.
+      let due = Duration::from_secs_f64((moved + count as u64) as f64 / rate as f64);
+      if let Some(wait) = due.checked_sub(started.elapsed()) {
+        sleep(wait).await;
+      };
+      // It may have been cancelled while waiting.
+      if task.cancelled.load(Ordering::Relaxed) {
+        continue;
+      };
+    };
+    let Some(ctx) = ctx.upgrade() else {
+      return;
+    };
+    match move_batch(&ctx, &task, count).await {
+      Ok(Some(n)) => {
+        task.moved.fetch_add(n, Ordering::Relaxed);
+      }
+      // The remaining messages have been deleted or are in flight.
+      Ok(None) => break (MoveStatus::Completed, None),
+      Err(reason) => break (MoveStatus::Failed, Some(reason)),
+    };
+  };
+  match &reason {
+    Some(reason) => warn!(
+      source = task.source,
+      destination = task.destination,
+      moved = task.moved(),
+      reason,
+      "message move task failed"
+    ),
+    None => info!(
+      source = task.source,
+      destination = task.destination,
+      moved = task.moved(),
+      status = status.as_str(),
+      "message move task ended"
+    ),
+  };
+  task.finish(status, reason);
+}
@@ -0,0 +1,97 @@
+use axum::body::Bytes;
+use axum::http::HeaderValue;
+use axum::http::StatusCode;
+use rand::thread_rng;
+use rand::Rng;
+use serde::Serialize;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::warn;
+
+#[derive(Serialize)]
+pub(crate) struct MirrorStatus {
+  pub url: String,
+  pub percent: f64,
+  pub sent: u64,
+  // The mirror could not be reached or didn't respond in time.
+  pub failed: u64,
+  // The mirror responded with a different status than this server.
+  pub mismatched: u64,
+}
+
+// Sends a copy of a sample of push and poll requests to another queued instance (e.g. one running a newer version), and compares the response statuses, to validate it before cutting over. Only the requests are copied: the mirror's responses are never returned to clients, and the messages each instance returns will differ. Updates and deletes are not mirrored, as they refer to message IDs and poll tags that only exist on this server.
+pub(crate) struct Mirror {
+  url: String,
+  api_key: Option<String>,
+  percent: f64,
+  client: reqwest::Client,
+  sent: AtomicU64,
+  failed: AtomicU64,
+  mismatched: AtomicU64,
+}
+
+impl Mirror {
+  pub fn new(url: String, api_key: Option<String>, percent: f64) -> Self {
+    Self {
+      url: url.trim_end_matches('/').to_string(),
+      api_key,
+      percent,
+      client: reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap(),
+      sent: AtomicU64::new(0),
+      failed: AtomicU64::new(0),
+      mismatched: AtomicU64::new(0),
+    }
+  }
+
+  pub fn should_mirror(&self) -> bool {
+    thread_rng().gen_bool(self.percent / 100.0)
+  }
+
+  pub async fn send(
+    &self,
+    path: &str,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+    local_status: StatusCode,
+  ) {
+    self.sent.fetch_add(1, Ordering::Relaxed);
+    let mut req = self.client.post(format!("{}{}", self.url, path)).body(body);
+    if let Some(ct) = content_type {
+      req = req.header("content-type", ct.as_bytes());
+    };
+    if let Some(k) = &self.api_key {
+      req = req.header("authorization", k);
+    };
+    let mirror_status = match req.send().await {
+      Ok(res) => res.status(),
+      Err(err) => {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        warn!(path, error = err.to_string(), "failed to mirror request");
+        return;
+      }
+    };
+    if mirror_status.as_u16() != local_status.as_u16() {
+      self.mismatched.fetch_add(1, Ordering::Relaxed);
+      warn!(
+        path,
+        local_status = local_status.as_u16(),
+        mirror_status = mirror_status.as_u16(),
+        "mirrored request had a different outcome"
+      );
+    };
+  }
+
+  pub fn status(&self) -> MirrorStatus {
+    MirrorStatus {
+      url: self.url.clone(),
+      percent: self.percent,
+      sent: self.sent.load(Ordering::Relaxed),
+      failed: self.failed.load(Ordering::Relaxed),
+      mismatched: self.mismatched.load(Ordering::Relaxed),
+    }
+  }
+}
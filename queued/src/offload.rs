@@ -0,0 +1,158 @@
+use crate::sigv4::hmac_sha256;
+use crate::sigv4::signing_key;
+use crate::sigv4::URI_ENCODE_SET;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use libqueued::offload::ContentsStore;
+use percent_encoding::utf8_percent_encode;
+use reqwest::Method;
+use sha2::Digest;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Shared by all queues' `S3ContentsStore` instances.
+pub(crate) struct S3Client {
+  pub access_key_id: String,
+  pub bucket: String,
+  pub client: reqwest::Client,
+  // For example, `https://s3.us-east-1.amazonaws.com` or `https://storage.googleapis.com`. Path-style requests are used.
+  pub endpoint: String,
+  pub region: String,
+  pub secret_access_key: String,
+}
+
+impl S3Client {
+  pub fn new(
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+  ) -> Self {
+    Self {
+      access_key_id,
+      bucket,
+      client: reqwest::Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()
+        .unwrap(),
+      endpoint: endpoint.trim_end_matches('/').to_string(),
+      region,
+      secret_access_key,
+    }
+  }
+
+  // Signs the request using AWS Signature Version 4, which is also supported by S3-compatible stores like GCS (with HMAC keys), R2, and MinIO.
+  fn request(&self, method: Method, key: &str, body: Vec<u8>) -> reqwest::RequestBuilder {
+    let url = reqwest::Url::parse(&self.endpoint).expect("invalid S3 endpoint");
+    let host = match url.port() {
+      Some(port) => format!("{}:{port}", url.host_str().unwrap()),
+      None => url.host_str().unwrap().to_string(),
+    };
+    let canonical_uri = format!(
+      "/{}/{}",
+      utf8_percent_encode(&self.bucket, URI_ENCODE_SET),
+      key
+        .split('/')
+        .map(|seg| utf8_percent_encode(seg, URI_ENCODE_SET).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+    );
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = hex::encode(Sha256::digest(&body));
+    let canonical_request = format!(
+      "{method}\n{canonical_uri}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}"
+    );
+    let scope = format!("{date}/{}/s3/aws4_request", self.region);
+    let string_to_sign = format!(
+      "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+      hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = signing_key(&self.secret_access_key, &date, &self.region, "s3");
+    let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+    self
+      .client
+      .request(method, format!("{}{canonical_uri}", self.endpoint))
+      .header("x-amz-content-sha256", payload_hash)
+      .header("x-amz-date", amz_date)
+      .header(
+        "authorization",
+        format!(
+          "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+          self.access_key_id
+        ),
+      )
+      .body(body)
+  }
+}
+
+pub(crate) struct S3ContentsStore {
+  client: Arc<S3Client>,
+  key_prefix: String,
+}
+
+impl S3ContentsStore {
+  pub fn new(client: Arc<S3Client>, queue_name: &str) -> Self {
+    Self {
+      client,
+      key_prefix: format!("{queue_name}/"),
+    }
+  }
+}
+
+impl ContentsStore for S3ContentsStore {
+  fn put(&self, id: u64, contents: Vec<u8>) -> BoxFuture<'static, Result<(), String>> {
+    let req = self
+      .client
+      .request(Method::PUT, &format!("{}{id}", self.key_prefix), contents);
+    async move {
+      req
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    }
+    .boxed()
+  }
+
+  fn get(&self, id: u64) -> BoxFuture<'static, Result<Vec<u8>, String>> {
+    let req = self
+      .client
+      .request(Method::GET, &format!("{}{id}", self.key_prefix), Vec::new());
+    async move {
+      let res = req
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+      res
+        .bytes()
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| e.to_string())
+    }
+    .boxed()
+  }
+
+  fn delete(&self, id: u64) -> BoxFuture<'static, Result<(), String>> {
+    let req = self.client.request(
+      Method::DELETE,
+      &format!("{}{id}", self.key_prefix),
+      Vec::new(),
+    );
+    async move {
+      req
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    }
+    .boxed()
+  }
+}
@@ -0,0 +1,62 @@
+use crate::endpoint::HttpCtx;
+use dashmap::DashMap;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::spawn;
+use tokio::time::sleep;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+struct PushRateSample {
+  at: Instant,
+  pushed: u64,
+  per_sec: f64,
+}
+
+/// Each queue's rate of successful pushes, measured over the last `SAMPLE_INTERVAL`, so that queues can be compared without a metrics scraper.
+#[derive(Default)]
+pub(crate) struct PushRates {
+  samples: DashMap<String, PushRateSample>,
+}
+
+impl PushRates {
+  /// Returns 0 for queues that haven't been sampled twice yet.
+  pub fn per_sec(&self, queue_name: &str) -> f64 {
+    self.samples.get(queue_name).map_or(0.0, |s| s.per_sec)
+  }
+}
+
+pub(crate) fn start_push_rate_sampler(ctx: Weak<HttpCtx>) {
+  spawn(async move {
+    loop {
+      sleep(SAMPLE_INTERVAL).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      let now = Instant::now();
+      for e in ctx.queues.iter() {
+        let pushed = e.value().metrics().successful_push_counter();
+        ctx
+          .push_rates
+          .samples
+          .entry(e.key().clone())
+          .and_modify(|s| {
+            let secs = now.duration_since(s.at).as_secs_f64();
+            s.per_sec = pushed.saturating_sub(s.pushed) as f64 / secs;
+            s.at = now;
+            s.pushed = pushed;
+          })
+          .or_insert(PushRateSample {
+            at: now,
+            pushed,
+            per_sec: 0.0,
+          });
+      }
+      ctx
+        .push_rates
+        .samples
+        .retain(|name, _| ctx.queues.contains_key(name));
+    }
+  });
+}
@@ -0,0 +1,53 @@
+use libqueued::op::ttl::DefaultTtlState;
+use libqueued::suspend::SuspensionChange;
+use libqueued::transform::PollTransform;
+use libqueued::webhook::WebhookCfg;
+use libqueued::Queued;
+use libqueued::ThrottleState;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+/// A named bundle of settings that can be applied to a queue when it's created, so that queues created by different teams are configured consistently. Templates are defined in the config file. Only settings that are set are applied, on top of any inherited from an ancestor, and they can then be changed for the queue as usual.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct QueueTemplate {
+  pub suspend: Option<SuspensionChange>,
+  pub throttle: Option<ThrottleState>,
+  pub default_ttl_secs: Option<u32>,
+  /// Also the dead letter policy for messages that can't be delivered.
+  pub webhook: Option<WebhookCfg>,
+  pub poll_transform: Option<PollTransform>,
+  pub auto_visibility_timeout: Option<bool>,
+}
+
+/// The queue must have just been created. Settings must already have been validated for the queue, as they are when creating it.
+pub(crate) async fn apply_template(t: &QueueTemplate, q: &Queued) {
+  if let Some(s) = t.suspend {
+    if let Err(err) = q.set_suspension(s).await {
+      warn!(?err, "failed to apply template suspension");
+    };
+  };
+  if t.throttle.is_some() {
+    q.set_throttle(t.throttle);
+  };
+  if t.webhook.is_some() {
+    q.set_webhook(t.webhook.clone());
+  };
+  if t.poll_transform.is_some() {
+    q.set_poll_transform(t.poll_transform.clone());
+  };
+  if let Some(auto) = t.auto_visibility_timeout {
+    q.set_auto_visibility_timeout(auto);
+  };
+  if t.default_ttl_secs.is_some() {
+    if let Err(err) = q
+      .set_default_ttl(DefaultTtlState {
+        default_ttl_secs: t.default_ttl_secs,
+      })
+      .await
+    {
+      warn!(?err, "failed to apply template default TTL");
+    };
+  };
+}
@@ -0,0 +1,76 @@
+use libqueued::suspend::SuspensionChange;
+use libqueued::Queued;
+use percent_encoding::percent_decode_str;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::AsciiSet;
+use percent_encoding::CONTROLS;
+use tracing::warn;
+
+// Queue names can be organized into a hierarchy by separating segments with `/`, e.g. `payments/retries` is a child of `payments`. Parents don't have to exist, so `payments/retries` and `payments/dlq` can be managed together even if there's no `payments` queue.
+const SEPARATOR: char = '/';
+
+// Data dir entries are flat, so the separator is encoded, as is `%` so that decoding is unambiguous.
+const DIR_NAME_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'/').add(b'%');
+
+/// Each segment must be non-empty, and can't be `.` or `..` as names are used as paths in the data dir.
+pub(crate) fn queue_name_is_valid(name: &str) -> bool {
+  name
+    .split(SEPARATOR)
+    .all(|seg| !seg.is_empty() && seg != "." && seg != "..")
+}
+
+pub(crate) fn queue_dir_name(name: &str) -> String {
+  utf8_percent_encode(name, DIR_NAME_ENCODE_SET).to_string()
+}
+
+pub(crate) fn queue_name_from_dir_name(dir_name: &str) -> String {
+  percent_decode_str(dir_name)
+    .decode_utf8()
+    .expect("queue dir name as UTF-8 string")
+    .into_owned()
+}
+
+/// The names of all ancestors of a queue, nearest first, whether or not they exist.
+pub(crate) fn ancestors(name: &str) -> impl Iterator<Item = &str> {
+  name
+    .char_indices()
+    .rev()
+    .filter(|&(_, c)| c == SEPARATOR)
+    .map(|(i, _)| &name[..i])
+}
+
+/// The first segment of a queue's name, which is its tenant's name if it has one.
+pub(crate) fn top_level(name: &str) -> &str {
+  name.split(SEPARATOR).next().unwrap()
+}
+
+/// Whether `name` is `root` or one of its descendants.
+pub(crate) fn is_in_subtree(name: &str, root: &str) -> bool {
+  name
+    .strip_prefix(root)
+    .is_some_and(|rest| rest.is_empty() || rest.starts_with(SEPARATOR))
+}
+
+/// Copies configuration from a queue's parent when it's created, which it can then override. Routing rules and debug sampling aren't inherited, as they target specific queues.
+pub(crate) async fn inherit_cfg(parent: &Queued, q: &Queued) {
+  let from = parent.suspension();
+  let suspension = SuspensionChange {
+    delete: Some(from.is_delete_suspended()),
+    poll: Some(from.is_poll_suspended()),
+    push: Some(from.is_push_suspended()),
+    update: Some(from.is_update_suspended()),
+  };
+  if let Err(err) = q.set_suspension(suspension).await {
+    warn!(?err, "failed to inherit suspension");
+  };
+  q.set_throttle(parent.get_throttle_state());
+  q.set_poll_transform(parent.get_poll_transform());
+  q.set_webhook(parent.get_webhook());
+  q.set_auto_visibility_timeout(parent.get_auto_visibility_timeout());
+  let ttl = parent.get_default_ttl();
+  if ttl.default_ttl_secs.is_some() {
+    if let Err(err) = q.set_default_ttl(ttl).await {
+      warn!(?err, "failed to inherit default TTL");
+    };
+  };
+}
@@ -0,0 +1,148 @@
+use dashmap::DashMap;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::spawn;
+use tokio::time::sleep;
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct RateLimitCfg {
+  pub push_requests_per_sec: Option<u64>,
+  pub push_messages_per_sec: Option<u64>,
+  pub polls_per_sec: Option<u64>,
+}
+
+impl RateLimitCfg {
+  pub fn is_enabled(&self) -> bool {
+    self.push_requests_per_sec.is_some()
+      || self.push_messages_per_sec.is_some()
+      || self.polls_per_sec.is_some()
+  }
+
+  fn rate(&self, k: RateLimitKind) -> Option<u64> {
+    match k {
+      RateLimitKind::PushRequests => self.push_requests_per_sec,
+      RateLimitKind::PushMessages => self.push_messages_per_sec,
+      RateLimitKind::Polls => self.polls_per_sec,
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum RateLimitKind {
+  PushRequests = 0,
+  PushMessages = 1,
+  Polls = 2,
+}
+
+const KINDS: [RateLimitKind; 3] = [
+  RateLimitKind::PushRequests,
+  RateLimitKind::PushMessages,
+  RateLimitKind::Polls,
+];
+
+// A token bucket that holds up to one second's worth of tokens, so clients can briefly burst up to their per-second limit.
+#[derive(Clone, Copy)]
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+impl Bucket {
+  fn refill(&mut self, rate: u64, now: Instant) {
+    let rate = rate as f64;
+    self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(rate);
+    self.updated = now;
+  }
+
+  fn is_full(&self, rate: u64, now: Instant) -> bool {
+    let mut b = *self;
+    b.refill(rate, now);
+    b.tokens >= rate as f64
+  }
+}
+
+/// The client a request counts against: its API key if it provided one, otherwise its IP address.
+#[derive(Clone)]
+pub(crate) struct RateLimitClient(pub String);
+
+pub(crate) struct RateLimiter {
+  // Can be changed by reloading the config.
+  cfg: RwLock<RateLimitCfg>,
+  clients: DashMap<String, [Option<Bucket>; 3]>,
+}
+
+impl RateLimiter {
+  pub fn start(cfg: RateLimitCfg) -> Arc<Self> {
+    let limiter = Arc::new(Self {
+      cfg: RwLock::new(cfg),
+      clients: DashMap::new(),
+    });
+    // Remove idle clients so that memory isn't exhausted by many one-off clients.
+    let weak = Arc::downgrade(&limiter);
+    spawn(async move {
+      loop {
+        sleep(Duration::from_secs(60)).await;
+        let Some(limiter) = Weak::upgrade(&weak) else {
+          break;
+        };
+        let now = Instant::now();
+        let cfg = *limiter.cfg.read();
+        // A bucket's limit may have been removed by a reload, in which case it's no longer needed.
+        limiter.clients.retain(|_, buckets| {
+          buckets.iter().enumerate().any(|(i, b)| {
+            b.zip(cfg.rate(KINDS[i]))
+              .is_some_and(|(b, rate)| !b.is_full(rate, now))
+          })
+        });
+      }
+    });
+    limiter
+  }
+
+  pub fn has(&self, k: RateLimitKind) -> bool {
+    self.cfg.read().rate(k).is_some()
+  }
+
+  pub fn set_cfg(&self, cfg: RateLimitCfg) {
+    *self.cfg.write() = cfg;
+  }
+
+  /// Takes `n` tokens from each of the client's buckets, or returns how long the client should wait before retrying. Kinds without a configured limit are ignored.
+  pub fn take(
+    &self,
+    client: &RateLimitClient,
+    takes: &[(RateLimitKind, u64)],
+  ) -> Result<(), Duration> {
+    let cfg = *self.cfg.read();
+    let now = Instant::now();
+    let mut buckets = self.clients.entry(client.0.clone()).or_default();
+    let mut wait = Duration::ZERO;
+    for &(k, n) in takes {
+      let Some(rate) = cfg.rate(k) else {
+        continue;
+      };
+      let b = buckets[k as usize].get_or_insert(Bucket {
+        tokens: rate as f64,
+        updated: now,
+      });
+      b.refill(rate, now);
+      // Requests for more than the bucket can ever hold are allowed once it's full, as otherwise they'd never succeed. This leaves the bucket in debt, so the client still can't exceed its rate on average.
+      let needed = n.min(rate) as f64;
+      if b.tokens < needed {
+        wait = wait.max(Duration::from_secs_f64((needed - b.tokens) / rate as f64));
+      };
+    }
+    if !wait.is_zero() {
+      return Err(wait);
+    };
+    for &(k, n) in takes {
+      if let Some(b) = buckets[k as usize].as_mut() {
+        b.tokens -= n as f64;
+      };
+    }
+    Ok(())
+  }
+}
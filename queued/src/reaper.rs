@@ -0,0 +1,105 @@
+use crate::endpoint::HttpCtx;
+use chrono::Utc;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::info;
+use tracing::warn;
+
+/// Deletes expired messages from all queues every second. In a cluster, only the leader does this, as deletes are replicated to followers, and read-only replicas similarly never do.
+pub(crate) fn start_expiry_reaper(ctx: Weak<HttpCtx>) {
+  spawn(async move {
+    loop {
+      sleep(Duration::from_millis(1000)).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      if ctx.is_follower() {
+        continue;
+      };
+      let queues = ctx
+        .queues
+        .iter()
+        .map(|e| (e.key().clone(), Arc::clone(e.value())))
+        .collect::<Vec<_>>();
+      // Don't keep the server alive while expiring, in case it takes a while.
+      drop(ctx);
+      for (name, q) in queues {
+        if let Err(err) = q.expire().await {
+          warn!(
+            queue = name,
+            error = format!("{err:?}"),
+            "failed to delete expired messages"
+          );
+        };
+      }
+    }
+  });
+}
+
+/// Trains new contents compression dictionaries for queues that have had enough contents pushed since their last one, checking every minute. Like expiry, only the leader does this, and followers load the dictionaries from storage when they need them.
+pub(crate) fn start_contents_dictionary_trainer(ctx: Weak<HttpCtx>) {
+  spawn(async move {
+    loop {
+      sleep(Duration::from_secs(60)).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      if ctx.is_follower() {
+        continue;
+      };
+      let queues = ctx
+        .queues
+        .iter()
+        .map(|e| (e.key().clone(), Arc::clone(e.value())))
+        .collect::<Vec<_>>();
+      drop(ctx);
+      for (name, q) in queues {
+        match q.train_contents_dictionary().await {
+          Ok(false) => {}
+          Ok(true) => info!(queue = name, "trained contents dictionary"),
+          Err(err) => warn!(
+            queue = name,
+            error = format!("{err:?}"),
+            "failed to train contents dictionary"
+          ),
+        };
+      }
+    }
+  });
+}
+
+/// Removes audit log events older than `retention` from all queues every minute. Like expiry, only the leader does this.
+pub(crate) fn start_audit_log_trimmer(ctx: Weak<HttpCtx>, retention: Duration) {
+  spawn(async move {
+    loop {
+      sleep(Duration::from_secs(60)).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      if ctx.is_follower() {
+        continue;
+      };
+      let queues = ctx
+        .queues
+        .iter()
+        .map(|e| (e.key().clone(), Arc::clone(e.value())))
+        .collect::<Vec<_>>();
+      drop(ctx);
+      let before_ms = Utc::now().timestamp_millis() - retention.as_millis() as i64;
+      for (name, q) in queues {
+        match q.trim_audit_log(before_ms).await {
+          Ok(0) => {}
+          Ok(removed) => info!(queue = name, removed, "trimmed audit log"),
+          Err(err) => warn!(
+            queue = name,
+            error = format!("{err:?}"),
+            "failed to trim audit log"
+          ),
+        };
+      }
+    }
+  });
+}
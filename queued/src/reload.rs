@@ -0,0 +1,122 @@
+use crate::cfg::ReloadableCfg;
+use crate::cfg::ReloadableCfgFile;
+use crate::endpoint::HttpCtx;
+use std::path::PathBuf;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The largest request body accepted, which allows some room for a request's other fields and encoding overhead on top of the maximum message size. It's fixed on startup, so the maximum message size can only be raised without restarting while it still fits.
+pub(crate) fn request_body_limit(max_message_size: Option<usize>) -> usize {
+  max_message_size
+    .map_or(0, |m| m + 1024 * 1024)
+    .max(1024 * 1024 * 128)
+}
+
+impl HttpCtx {
+  /// Replaces the reloadable settings from the config file with `file` and applies them to every queue. Settings set by CLI args or env vars still take precedence. Nothing is changed if the resulting settings are invalid or can't be applied without restarting.
+  pub(crate) fn apply_reloadable(&self, file: ReloadableCfgFile) -> Result<ReloadableCfg, String> {
+    let cfg = self.reloadable_overrides.clone().or(file).resolve()?;
+    if request_body_limit(cfg.max_message_size) > self.max_request_body_size {
+      return Err(format!(
+        "raising the max message size to more than {} bytes requires a restart",
+        self.max_request_body_size - 1024 * 1024
+      ));
+    };
+    match &self.rate_limiter {
+      Some(limiter) => limiter.set_cfg(cfg.rate_limit),
+      None if cfg.rate_limit.is_enabled() => {
+        return Err("enabling rate limiting requires a restart".to_string());
+      }
+      None => {}
+    };
+    // Hold the lock while updating queues so that concurrent reloads are applied in the same order to all of them.
+    let mut current = self.reloadable.write();
+    *current = cfg;
+    for e in self.queues.iter() {
+      e.value()
+        .reload_cfg(&self.queue_cfg_for_with(e.key(), &cfg));
+    }
+    Ok(cfg)
+  }
+}
+
+fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The top-level keys whose values differ between `a` and `b`, ignoring reloadable settings.
+fn changed_keys(a: &toml::Table, b: &toml::Table) -> Vec<String> {
+  let reloadable = ReloadableCfgFile::keys();
+  let mut keys = a
+    .keys()
+    .chain(b.keys())
+    .filter(|k| !reloadable.contains(k) && a.get(*k) != b.get(*k))
+    .cloned()
+    .collect::<Vec<_>>();
+  keys.sort();
+  keys.dedup();
+  keys
+}
+
+fn reload_file(ctx: &HttpCtx, path: &PathBuf, loaded: &toml::Table) -> Result<(), String> {
+  let raw = std::fs::read_to_string(path).map_err(|err| format!("failed to read: {err}"))?;
+  let table =
+    toml::from_str::<toml::Table>(&raw).map_err(|err| format!("failed to parse: {err}"))?;
+  let changed = changed_keys(loaded, &table);
+  if !changed.is_empty() {
+    return Err(format!(
+      "changes to {} require a restart",
+      changed.join(", ")
+    ));
+  };
+  let file: ReloadableCfgFile = toml::Value::Table(table)
+    .try_into()
+    .map_err(|err| format!("failed to parse: {err}"))?;
+  let overridden = file
+    .set_keys()
+    .into_iter()
+    .filter(|k| ctx.reloadable_overrides.set_keys().contains(k))
+    .collect::<Vec<_>>();
+  if !overridden.is_empty() {
+    warn!(
+      keys = overridden.join(", "),
+      "config file settings are overridden by CLI args or env vars"
+    );
+  };
+  ctx.apply_reloadable(file)?;
+  Ok(())
+}
+
+/// Checks the config file for changes every few seconds, and applies changed reloadable settings. If any other setting has changed, the file isn't applied at all, so that the server never runs with a mix of old and new settings that the file doesn't describe.
+pub(crate) fn start_config_watcher(ctx: Weak<HttpCtx>, path: PathBuf, loaded: toml::Table) {
+  spawn(async move {
+    let mut last_modified = modified_time(&path);
+    loop {
+      sleep(WATCH_INTERVAL).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      let modified = modified_time(&path);
+      if modified == last_modified {
+        continue;
+      };
+      last_modified = modified;
+      match reload_file(&ctx, &path, &loaded) {
+        Ok(()) => info!(path = format!("{path:?}"), "reloaded config file"),
+        Err(err) => error!(
+          path = format!("{path:?}"),
+          error = err,
+          "failed to reload config file"
+        ),
+      };
+    }
+  });
+}
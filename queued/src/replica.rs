@@ -0,0 +1,245 @@
+use crate::endpoint::queues::create_queue;
+use crate::endpoint::queues::delete_queue;
+use crate::endpoint::queues::EndpointQueuesResponse;
+use crate::endpoint::replica::REPLICA_IDENTITY_HEADER;
+use crate::endpoint::replica::REPLICA_SEQ_HEADER;
+use crate::endpoint::HttpCtx;
+use crate::endpoint::QueuedHttpError;
+use itertools::Itertools;
+use libqueued::replica::ReplicaPosition;
+use libqueued::Queued;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::NON_ALPHANUMERIC;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::info;
+use tracing::warn;
+
+// A read-only replica keeps a copy of every queue on a primary server by first copying all of a queue's records, and then repeatedly applying the writes since then from the primary's WAL. Reads are served locally from the copy, while writes are redirected to the primary by `HttpCtx::verify_leader`. Unlike with clustering, the primary doesn't know about its replicas, so replicas can be added and removed freely, but a replica lags behind the primary and can never take over from it.
+pub(crate) struct Replica {
+  pub primary_url: String,
+  api_key: Option<String>,
+  client: reqwest::Client,
+  sync_interval: Duration,
+}
+
+fn http_err(action: &str, (status, _): QueuedHttpError) -> String {
+  format!("failed to {action}: {status}")
+}
+
+// Returns the complete batches at the start of `raw`, how many bytes they took up, and whether they were followed by the empty batch that marks the end of a copy.
+fn read_batches(raw: &[u8]) -> (Vec<Vec<u8>>, usize, bool) {
+  let mut batches = Vec::new();
+  let mut pos = 0;
+  while raw.len() >= pos + 4 {
+    let len = u32::from_le_bytes(raw[pos..pos + 4].try_into().unwrap()) as usize;
+    if len == 0 {
+      return (batches, pos + 4, true);
+    };
+    let Some(b) = raw.get(pos + 4..pos + 4 + len) else {
+      break;
+    };
+    batches.push(b.to_vec());
+    pos += 4 + len;
+  }
+  (batches, pos, false)
+}
+
+fn position(res: &reqwest::Response) -> Result<ReplicaPosition, String> {
+  let header = |name: &str| {
+    res
+      .headers()
+      .get(name)
+      .and_then(|v| v.to_str().ok())
+      .map(|v| v.to_string())
+      .ok_or_else(|| format!("primary didn't send {name}"))
+  };
+  Ok(ReplicaPosition {
+    seq: header(REPLICA_SEQ_HEADER)?
+      .parse()
+      .map_err(|_| format!("invalid {REPLICA_SEQ_HEADER}"))?,
+    identity: header(REPLICA_IDENTITY_HEADER)?,
+  })
+}
+
+impl Replica {
+  pub fn new(primary_url: String, api_key: Option<String>, sync_interval: Duration) -> Self {
+    Self {
+      primary_url,
+      api_key,
+      client: reqwest::Client::new(),
+      sync_interval,
+    }
+  }
+
+  async fn get(&self, path: &str) -> Result<reqwest::Response, String> {
+    let mut req = self
+      .client
+      .get(format!("{}{}", self.primary_url, path))
+      .header("accept", "application/msgpack");
+    if let Some(k) = &self.api_key {
+      req = req.header("authorization", k);
+    };
+    req.send().await.map_err(|err| err.to_string())
+  }
+
+  // Deletes and recreates the queue, so that nothing remains from a previous copy, and copies every record into it.
+  async fn copy_queue(&self, ctx: &HttpCtx, name: &str) -> Result<(), String> {
+    if ctx.queues.contains_key(name) {
+      delete_queue(ctx, name.to_string())
+        .await
+        .map_err(|err| http_err("delete queue", err))?;
+    };
+    create_queue(ctx, name.to_string(), false, None)
+      .await
+      .map_err(|err| http_err("create queue", err))?;
+    let q = ctx.q(name).map_err(|err| http_err("get queue", err))?;
+    let mut res = self
+      .get(&format!(
+        "/replica/records/{}",
+        utf8_percent_encode(name, NON_ALPHANUMERIC)
+      ))
+      .await?
+      .error_for_status()
+      .map_err(|err| err.to_string())?;
+    let pos = position(&res)?;
+    let mut buf = Vec::new();
+    let mut complete = false;
+    while let Some(chunk) = res.chunk().await.map_err(|err| err.to_string())? {
+      buf.extend_from_slice(&chunk);
+      let (batches, len, end) = read_batches(&buf);
+      buf.drain(..len);
+      q.apply_replica_batches(batches)
+        .await
+        .map_err(|err| format!("failed to apply records: {err:?}"))?;
+      if end {
+        complete = true;
+        break;
+      };
+    }
+    if !complete {
+      // The queue has no position, so it'll be copied again.
+      return Err("primary ended copy early".to_string());
+    };
+    q.set_replica_position(&pos)
+      .await
+      .map_err(|err| format!("failed to set position: {err:?}"))?;
+    info!(queue = name, seq = pos.seq, "copied queue from primary");
+    Ok(())
+  }
+
+  // Returns false if the queue must be copied again.
+  async fn apply_wal(&self, q: &Queued, name: &str, pos: ReplicaPosition) -> Result<bool, String> {
+    let res = self
+      .get(&format!(
+        "/replica/wal/{}?since={}",
+        utf8_percent_encode(name, NON_ALPHANUMERIC),
+        pos.seq
+      ))
+      .await?;
+    if res.status() == reqwest::StatusCode::CONFLICT {
+      return Ok(false);
+    };
+    let res = res.error_for_status().map_err(|err| err.to_string())?;
+    let new_pos = position(&res)?;
+    // The queue has been deleted and recreated on the primary.
+    if new_pos.identity != pos.identity {
+      return Ok(false);
+    };
+    let raw = res.bytes().await.map_err(|err| err.to_string())?;
+    let (batches, len, _) = read_batches(&raw);
+    if len != raw.len() {
+      return Err("primary sent truncated writes".to_string());
+    };
+    if batches.is_empty() && new_pos == pos {
+      return Ok(true);
+    };
+    q.apply_replica_batches(batches)
+      .await
+      .map_err(|err| format!("failed to apply writes: {err:?}"))?;
+    q.set_replica_position(&new_pos)
+      .await
+      .map_err(|err| format!("failed to set position: {err:?}"))?;
+    Ok(true)
+  }
+
+  async fn sync_queue(&self, ctx: &HttpCtx, name: &str) -> Result<(), String> {
+    let q = ctx.q(name).ok();
+    if let Some(q) = q {
+      let pos = q
+        .replica_position()
+        .await
+        .map_err(|err| format!("failed to get position: {err:?}"))?;
+      if let Some(pos) = pos {
+        if self.apply_wal(&q, name, pos).await? {
+          return Ok(());
+        };
+        info!(
+          queue = name,
+          "can't continue from last sync, copying queue again"
+        );
+      };
+    };
+    self.copy_queue(ctx, name).await
+  }
+
+  async fn sync(&self, ctx: &HttpCtx) -> Result<(), String> {
+    let raw = self
+      .get("/queues")
+      .await?
+      .error_for_status()
+      .map_err(|err| err.to_string())?
+      .bytes()
+      .await
+      .map_err(|err| err.to_string())?;
+    let primary: EndpointQueuesResponse =
+      rmp_serde::from_slice(&raw).map_err(|err| err.to_string())?;
+    let names = primary
+      .queues
+      .into_iter()
+      .map(|q| q.name)
+      .collect::<BTreeSet<_>>();
+    // Collect first so that we don't hold map entry locks across await points.
+    let removed = ctx
+      .queues
+      .iter()
+      .map(|e| e.key().clone())
+      .filter(|n| !names.contains(n))
+      .collect_vec();
+    for name in removed {
+      delete_queue(ctx, name.clone())
+        .await
+        .map_err(|err| http_err("delete queue", err))?;
+      info!(queue = name, "deleted queue removed from primary");
+    }
+    for name in names {
+      if let Err(err) = self.sync_queue(ctx, &name).await {
+        warn!(
+          queue = name,
+          error = err,
+          "failed to sync queue from primary"
+        );
+      };
+    }
+    Ok(())
+  }
+}
+
+pub(crate) fn start_replica_sync(replica: Arc<Replica>, ctx: Weak<HttpCtx>) {
+  spawn(async move {
+    loop {
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      if let Err(err) = replica.sync(&ctx).await {
+        warn!(error = err, "failed to sync from primary");
+      };
+      drop(ctx);
+      sleep(replica.sync_interval).await;
+    }
+  });
+}
@@ -0,0 +1,32 @@
+use crate::endpoint::HttpCtx;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::time::sleep;
+
+/// Pushes the messages of due schedules in all queues every second. In a cluster, only the leader does this, as pushes are replicated to followers.
+pub(crate) fn start_scheduler(ctx: Weak<HttpCtx>) {
+  spawn(async move {
+    loop {
+      sleep(Duration::from_millis(1000)).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      if ctx.is_follower() {
+        continue;
+      };
+      let queues = ctx
+        .queues
+        .iter()
+        .map(|e| Arc::clone(e.value()))
+        .collect::<Vec<_>>();
+      // Don't keep the server alive while pushing, in case it takes a while.
+      drop(ctx);
+      // Failures are logged and retried by each queue.
+      for q in queues {
+        q.run_due_schedules().await;
+      }
+    }
+  });
+}
@@ -0,0 +1,43 @@
+use crate::endpoint::HttpCtx;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tracing::info;
+use tracing::warn;
+
+/// Resolves once SIGTERM or SIGINT is received, after entering drain mode so that no more pushes are accepted while in-flight requests complete.
+pub(crate) async fn shutdown_signal(ctx: Arc<HttpCtx>) {
+  let mut sigterm = signal(SignalKind::terminate()).expect("listen for SIGTERM");
+  tokio::select! {
+    _ = sigterm.recv() => {}
+    _ = ctrl_c() => {}
+  };
+  ctx.draining.store(true, Ordering::Relaxed);
+  info!("shutting down, waiting for in-flight requests");
+}
+
+/// Flushes and closes all queues. This must only be called once the server has stopped.
+pub(crate) async fn close_queues(ctx: &HttpCtx) {
+  let queues = ctx
+    .queues
+    .iter()
+    .map(|e| (e.key().clone(), Arc::clone(e.value())))
+    .collect::<Vec<_>>();
+  for (name, q) in queues {
+    if let Err(err) = q.flush().await {
+      warn!(
+        queue = name,
+        error = format!("{err:?}"),
+        "failed to flush queue"
+      );
+    };
+  }
+  if let Some(bridge) = &ctx.bridge {
+    bridge.flush().await;
+  };
+  // Dropping the queues closes their databases.
+  ctx.queues.clear();
+  info!("shutdown complete");
+}
@@ -0,0 +1,80 @@
+use hmac::Hmac;
+use hmac::Mac;
+use percent_encoding::AsciiSet;
+use percent_encoding::NON_ALPHANUMERIC;
+use sha2::Sha256;
+
+// Everything except unreserved characters, as required by SigV4 canonical URIs and query strings.
+pub(crate) const URI_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'-')
+  .remove(b'.')
+  .remove(b'_')
+  .remove(b'~');
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+/// Whether `tag` is the leftmost bytes of the HMAC-SHA256 of `data`, compared in constant time. Callers must check the length of `tag`, as any non-empty prefix is accepted.
+pub(crate) fn verify_hmac_sha256(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+  mac.update(data);
+  mac.verify_truncated_left(tag).is_ok()
+}
+
+/// Derives the key that signs requests made on `date` (e.g. `20240101`) to `service` in `region`.
+pub(crate) fn signing_key(
+  secret_access_key: &str,
+  date: &str,
+  region: &str,
+  service: &str,
+) -> Vec<u8> {
+  let mut key = format!("AWS4{secret_access_key}").into_bytes();
+  for part in [date, region, service, "aws4_request"] {
+    key = hmac_sha256(&key, part.as_bytes());
+  }
+  key
+}
+
+#[cfg(test)]
+mod tests {
+  use super::hmac_sha256;
+  use super::signing_key;
+  use super::verify_hmac_sha256;
+
+  #[test]
+  fn hmac_sha256_matches_rfc_4231() {
+    assert_eq!(
+      hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+      "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+  }
+
+  #[test]
+  fn verifies_truncated_tags_and_rejects_tampered_ones() {
+    let tag = hmac_sha256(b"key", b"data");
+    assert!(verify_hmac_sha256(b"key", b"data", &tag));
+    assert!(verify_hmac_sha256(b"key", b"data", &tag[..16]));
+    let mut tampered = tag[..16].to_vec();
+    tampered[15] ^= 1;
+    assert!(!verify_hmac_sha256(b"key", b"data", &tampered));
+    assert!(!verify_hmac_sha256(b"key", b"other data", &tag[..16]));
+    assert!(!verify_hmac_sha256(b"other key", b"data", &tag[..16]));
+  }
+
+  // From the AWS documentation's example of deriving a signing key.
+  #[test]
+  fn derives_signing_key() {
+    assert_eq!(
+      hex::encode(signing_key(
+        "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+        "20120215",
+        "us-east-1",
+        "iam",
+      )),
+      "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+    );
+  }
+}
@@ -2,11 +2,14 @@ use cadence::Counted;
 use cadence::Gauged;
 use cadence::QueuingMetricSink;
 use cadence::StatsdClient;
+use cadence::StatsdClientBuilder;
 use cadence::UdpMetricSink;
 use chrono::Utc;
+use libqueued::lifecycle::MessageTransition;
 use libqueued::Queued;
 use serde::Serialize;
 use std::cmp::max;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::net::UdpSocket;
 use std::sync::Weak;
@@ -16,47 +19,141 @@ use tokio::time::sleep;
 
 #[derive(Serialize)]
 pub(crate) struct Metrics {
+  audit_event_counter: u64,
+  compressed_contents_counter: u64,
+  compression_saved_bytes_counter: u64,
   empty_poll_counter: u64,
+  expired_counter: u64,
+  failed_schedule_counter: u64,
+  forced_delete_counter: u64,
+  full_push_counter: u64,
   message_counter: u64,
   missing_delete_counter: u64,
+  missing_nack_counter: u64,
   missing_update_counter: u64,
+  rejected_takeover_counter: u64,
+  rejected_transaction_counter: u64,
+  scheduled_push_counter: u64,
   successful_delete_counter: u64,
+  successful_nack_counter: u64,
   successful_poll_counter: u64,
   successful_push_counter: u64,
+  successful_takeover_counter: u64,
+  successful_transaction_counter: u64,
   successful_update_counter: u64,
+  storage_error_counter: u64,
+  storage_breaker_rejected_counter: u64,
+  offload_error_counter: u64,
+  rate_limited_poll_counter: u64,
+  rate_limited_push_counter: u64,
+  replication_error_counter: u64,
+  rocksdb_block_cache_hit_counter: u64,
+  rocksdb_block_cache_miss_counter: u64,
+  rocksdb_stall_micros_counter: u64,
   suspended_delete_counter: u64,
+  tombstone_compaction_counter: u64,
   suspended_poll_counter: u64,
   suspended_push_counter: u64,
   suspended_update_counter: u64,
   throttled_poll_counter: u64,
+  throttled_push_counter: u64,
+  webhook_delivered_counter: u64,
+  webhook_failed_counter: u64,
+  webhook_dead_lettered_counter: u64,
+  // Keyed by e.g. `poll_transition_counter` and `illegal_poll_transition_counter`.
+  #[serde(flatten)]
+  transition_counters: BTreeMap<String, u64>,
 
+  data_bytes_gauge: u64,
+  disk_bytes_gauge: u64,
   first_message_visibility_timeout_sec_gauge: u64,
+  in_flight_message_gauge: u64,
   last_message_visibility_timeout_sec_gauge: u64,
   longest_unpolled_message_sec_gauge: u64,
+  maintenance_push_cap_gauge: u64,
+  oldest_visible_message_age_sec_gauge: u64,
+  pinned_message_gauge: u64,
+  recommended_visibility_timeout_sec_gauge: u64,
+  rocksdb_block_cache_usage_bytes_gauge: u64,
+  rocksdb_compaction_pending_bytes_gauge: u64,
+  rocksdb_delayed_write_rate_gauge: u64,
+  rocksdb_entries_gauge: u64,
+  rocksdb_running_compactions_gauge: u64,
+  rocksdb_tombstones_gauge: u64,
+  rocksdb_write_stopped_gauge: u64,
+  storage_breaker_open_gauge: u64,
+  visible_message_gauge: u64,
 }
 
 pub(crate) fn build_metrics(q: &Queued) -> Metrics {
   let now = Utc::now().timestamp();
   let m = q.metrics();
+  let storage = m.storage_stats();
   Metrics {
+    audit_event_counter: m.audit_event_counter(),
+    compressed_contents_counter: m.compressed_contents_counter(),
+    compression_saved_bytes_counter: m.compression_saved_bytes_counter(),
     empty_poll_counter: m.empty_poll_counter(),
+    expired_counter: m.expired_counter(),
+    failed_schedule_counter: m.failed_schedule_counter(),
+    forced_delete_counter: m.forced_delete_counter(),
+    full_push_counter: m.full_push_counter(),
     message_counter: m.message_counter(),
     missing_delete_counter: m.missing_delete_counter(),
+    missing_nack_counter: m.missing_nack_counter(),
     missing_update_counter: m.missing_update_counter(),
+    rejected_takeover_counter: m.rejected_takeover_counter(),
+    rejected_transaction_counter: m.rejected_transaction_counter(),
+    scheduled_push_counter: m.scheduled_push_counter(),
     successful_delete_counter: m.successful_delete_counter(),
+    successful_nack_counter: m.successful_nack_counter(),
     successful_poll_counter: m.successful_poll_counter(),
     successful_push_counter: m.successful_push_counter(),
+    successful_takeover_counter: m.successful_takeover_counter(),
+    successful_transaction_counter: m.successful_transaction_counter(),
     successful_update_counter: m.successful_update_counter(),
+    storage_error_counter: m.storage_error_counter(),
+    storage_breaker_rejected_counter: m.storage_breaker_rejected_counter(),
+    offload_error_counter: m.offload_error_counter(),
+    rate_limited_poll_counter: m.rate_limited_poll_counter(),
+    rate_limited_push_counter: m.rate_limited_push_counter(),
+    replication_error_counter: m.replication_error_counter(),
+    rocksdb_block_cache_hit_counter: storage.block_cache_hits,
+    rocksdb_block_cache_miss_counter: storage.block_cache_misses,
+    rocksdb_stall_micros_counter: storage.stall_micros,
     suspended_delete_counter: m.suspended_delete_counter(),
+    tombstone_compaction_counter: m.tombstone_compaction_counter(),
     suspended_poll_counter: m.suspended_poll_counter(),
     suspended_push_counter: m.suspended_push_counter(),
     suspended_update_counter: m.suspended_update_counter(),
     throttled_poll_counter: m.throttled_poll_counter(),
+    throttled_push_counter: m.throttled_push_counter(),
+    webhook_delivered_counter: m.webhook_delivered_counter(),
+    webhook_failed_counter: m.webhook_failed_counter(),
+    webhook_dead_lettered_counter: m.webhook_dead_lettered_counter(),
+    transition_counters: MessageTransition::ALL
+      .iter()
+      .flat_map(|&t| {
+        [
+          (
+            format!("{}_transition_counter", t.name()),
+            m.transitions().counter(t),
+          ),
+          (
+            format!("illegal_{}_transition_counter", t.name()),
+            m.transitions().illegal_counter(t),
+          ),
+        ]
+      })
+      .collect(),
 
+    data_bytes_gauge: m.data_bytes(),
+    disk_bytes_gauge: m.disk_bytes(),
     first_message_visibility_timeout_sec_gauge: q
       .youngest_message_time()
       .map(|t| max(0, t - now) as u64)
       .unwrap_or(0),
+    in_flight_message_gauge: q.in_flight_message_count() as u64,
     last_message_visibility_timeout_sec_gauge: q
       .oldest_message_time()
       .map(|t| max(0, t - now) as u64)
@@ -65,16 +162,31 @@ pub(crate) fn build_metrics(q: &Queued) -> Metrics {
       .youngest_message_time()
       .map(|t| max(0, now - t) as u64)
       .unwrap_or(0),
+    maintenance_push_cap_gauge: q.maintenance_push_cap().unwrap_or(0),
+    oldest_visible_message_age_sec_gauge: q
+      .oldest_visible_message_time()
+      .map(|t| max(0, now - t) as u64)
+      .unwrap_or(0),
+    pinned_message_gauge: q.pinned_message_count() as u64,
+    recommended_visibility_timeout_sec_gauge: q.recommended_visibility_timeout_secs().unwrap_or(0)
+      as u64,
+    rocksdb_block_cache_usage_bytes_gauge: storage.block_cache_usage_bytes,
+    rocksdb_compaction_pending_bytes_gauge: storage.compaction_pending_bytes,
+    rocksdb_delayed_write_rate_gauge: storage.delayed_write_rate,
+    rocksdb_entries_gauge: storage.entries,
+    rocksdb_running_compactions_gauge: storage.running_compactions,
+    rocksdb_tombstones_gauge: storage.tombstones,
+    rocksdb_write_stopped_gauge: u64::from(storage.write_stopped),
+    storage_breaker_open_gauge: u64::from(!q.is_storage_available()),
+    visible_message_gauge: q.visible_message_count() as u64,
   }
 }
 
-pub(crate) fn spawn_statsd_emitter(
+fn statsd_client_builder(
   addr: SocketAddr,
   statsd_prefix: &str,
   statsd_tags: &[(String, String)],
-  queue_name: &str,
-  qref: Weak<Queued>,
-) {
+) -> StatsdClientBuilder {
   let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
   socket.set_nonblocking(true).unwrap();
   let sink = UdpMetricSink::from(addr, socket).unwrap();
@@ -83,7 +195,31 @@ pub(crate) fn spawn_statsd_emitter(
   for (k, v) in statsd_tags {
     sb = sb.with_tag(k, v);
   }
+  sb
+}
+
+/// A client for server-wide metrics, which aren't tagged with a queue.
+pub(crate) fn statsd_client(
+  addr: SocketAddr,
+  statsd_prefix: &str,
+  statsd_tags: &[(String, String)],
+) -> StatsdClient {
+  statsd_client_builder(addr, statsd_prefix, statsd_tags).build()
+}
+
+pub(crate) fn spawn_statsd_emitter(
+  addr: SocketAddr,
+  statsd_prefix: &str,
+  statsd_tags: &[(String, String)],
+  queue_name: &str,
+  tenant: Option<&str>,
+  qref: Weak<Queued>,
+) {
+  let mut sb = statsd_client_builder(addr, statsd_prefix, statsd_tags);
   sb = sb.with_tag("queue", queue_name);
+  if let Some(tenant) = tenant {
+    sb = sb.with_tag("tenant", tenant);
+  };
   let s = sb.build();
 
   #[rustfmt::skip]
@@ -110,22 +246,71 @@ pub(crate) fn spawn_statsd_emitter(
             i64::try_from(m.$f).unwrap() - i64::try_from(p.$f).unwrap()
           };
         }
+        s.count("audit_event", d!(audit_event_counter)).unwrap();
+        s.count("compressed_contents", d!(compressed_contents_counter)).unwrap();
+        s.count("compression_saved_bytes", d!(compression_saved_bytes_counter)).unwrap();
         s.count("empty_poll", d!(empty_poll_counter)).unwrap();
+        s.count("expired", d!(expired_counter)).unwrap();
+        s.count("failed_schedule", d!(failed_schedule_counter)).unwrap();
+        s.count("forced_delete", d!(forced_delete_counter)).unwrap();
+        s.count("full_push", d!(full_push_counter)).unwrap();
         s.gauge("message_count", m.message_counter).unwrap();
         s.count("missing_delete", d!(missing_delete_counter)).unwrap();
+        s.count("missing_nack", d!(missing_nack_counter)).unwrap();
         s.count("missing_update", d!(missing_update_counter)).unwrap();
+        s.count("rejected_takeover", d!(rejected_takeover_counter)).unwrap();
+        s.count("rejected_transaction", d!(rejected_transaction_counter)).unwrap();
+        s.count("scheduled_push", d!(scheduled_push_counter)).unwrap();
         s.count("successful_delete", d!(successful_delete_counter)).unwrap();
+        s.count("successful_nack", d!(successful_nack_counter)).unwrap();
         s.count("successful_poll", d!(successful_poll_counter)).unwrap();
         s.count("successful_push", d!(successful_push_counter)).unwrap();
+        s.count("successful_takeover", d!(successful_takeover_counter)).unwrap();
+        s.count("successful_transaction", d!(successful_transaction_counter)).unwrap();
         s.count("successful_update", d!(successful_update_counter)).unwrap();
+        s.count("storage_error", d!(storage_error_counter)).unwrap();
+        s.count("storage_breaker_rejected", d!(storage_breaker_rejected_counter)).unwrap();
+        s.count("offload_error", d!(offload_error_counter)).unwrap();
+        s.count("rate_limited_poll", d!(rate_limited_poll_counter)).unwrap();
+        s.count("rate_limited_push", d!(rate_limited_push_counter)).unwrap();
+        s.count("replication_error", d!(replication_error_counter)).unwrap();
+        s.count("rocksdb_block_cache_hit", d!(rocksdb_block_cache_hit_counter)).unwrap();
+        s.count("rocksdb_block_cache_miss", d!(rocksdb_block_cache_miss_counter)).unwrap();
+        s.count("rocksdb_stall_micros", d!(rocksdb_stall_micros_counter)).unwrap();
         s.count("suspended_delete", d!(suspended_delete_counter)).unwrap();
+        s.count("tombstone_compaction", d!(tombstone_compaction_counter)).unwrap();
         s.count("suspended_poll", d!(suspended_poll_counter)).unwrap();
         s.count("suspended_push", d!(suspended_push_counter)).unwrap();
         s.count("suspended_update", d!(suspended_update_counter)).unwrap();
         s.count("throttled_poll", d!(throttled_poll_counter)).unwrap();
+        s.count("throttled_push", d!(throttled_push_counter)).unwrap();
+        s.count("webhook_delivered", d!(webhook_delivered_counter)).unwrap();
+        s.count("webhook_failed", d!(webhook_failed_counter)).unwrap();
+        s.count("webhook_dead_lettered", d!(webhook_dead_lettered_counter)).unwrap();
+        let td = |key: String| i64::try_from(m.transition_counters[&key]).unwrap() - i64::try_from(p.transition_counters[&key]).unwrap();
+        for t in MessageTransition::ALL {
+          s.count_with_tags("transition", td(format!("{}_transition_counter", t.name()))).with_tag("transition", t.name()).send();
+          s.count_with_tags("illegal_transition", td(format!("illegal_{}_transition_counter", t.name()))).with_tag("transition", t.name()).send();
+        }
+        s.gauge("data_bytes", m.data_bytes_gauge).unwrap();
+        s.gauge("disk_bytes", m.disk_bytes_gauge).unwrap();
         s.gauge("first_message_visibility_timeout_sec", m.first_message_visibility_timeout_sec_gauge).unwrap();
+        s.gauge("in_flight_message_count", m.in_flight_message_gauge).unwrap();
         s.gauge("last_message_visibility_timeout_sec", m.last_message_visibility_timeout_sec_gauge).unwrap();
         s.gauge("longest_unpolled_message_sec", m.longest_unpolled_message_sec_gauge).unwrap();
+        s.gauge("maintenance_push_cap", m.maintenance_push_cap_gauge).unwrap();
+        s.gauge("oldest_visible_message_age_sec", m.oldest_visible_message_age_sec_gauge).unwrap();
+        s.gauge("pinned_message_count", m.pinned_message_gauge).unwrap();
+        s.gauge("recommended_visibility_timeout_sec", m.recommended_visibility_timeout_sec_gauge).unwrap();
+        s.gauge("rocksdb_block_cache_usage_bytes", m.rocksdb_block_cache_usage_bytes_gauge).unwrap();
+        s.gauge("rocksdb_compaction_pending_bytes", m.rocksdb_compaction_pending_bytes_gauge).unwrap();
+        s.gauge("rocksdb_delayed_write_rate", m.rocksdb_delayed_write_rate_gauge).unwrap();
+        s.gauge("rocksdb_entries", m.rocksdb_entries_gauge).unwrap();
+        s.gauge("rocksdb_running_compactions", m.rocksdb_running_compactions_gauge).unwrap();
+        s.gauge("rocksdb_tombstones", m.rocksdb_tombstones_gauge).unwrap();
+        s.gauge("rocksdb_write_stopped", m.rocksdb_write_stopped_gauge).unwrap();
+        s.gauge("storage_breaker_open", m.storage_breaker_open_gauge).unwrap();
+        s.gauge("visible_message_count", m.visible_message_gauge).unwrap();
         p = m;
       };
     }
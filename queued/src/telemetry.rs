@@ -0,0 +1,31 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Logs to stdout as JSON, including the fields of the spans that each event is in (e.g. `request_id`), and, if an OTLP endpoint is provided, exports spans to it using OTLP over HTTP.
+pub(crate) fn init_tracing(otlp_endpoint: Option<&str>) {
+  let otel = otlp_endpoint.map(|endpoint| {
+    let exporter = SpanExporter::builder()
+      .with_http()
+      .with_endpoint(endpoint)
+      .build()
+      .expect("build OTLP exporter");
+    let provider = SdkTracerProvider::builder()
+      .with_batch_exporter(exporter)
+      .with_resource(Resource::builder().with_service_name("queued").build())
+      .build();
+    let tracer = provider.tracer("queued");
+    opentelemetry::global::set_tracer_provider(provider);
+    tracing_opentelemetry::layer().with_tracer(tracer)
+  });
+  tracing_subscriber::registry()
+    .with(LevelFilter::INFO)
+    .with(tracing_subscriber::fmt::layer().json())
+    .with(otel)
+    .init();
+}
@@ -0,0 +1,24 @@
+use libqueued::quota::QuotaLimits;
+use serde::Deserialize;
+
+/// A team or service that owns a top-level namespace of queues, e.g. `acme` owns `acme` and `acme/orders`. Tenants are defined in the config file. Once any are, new queues must belong to one, so that every queue is subject to its tenant's quota and can be deleted with it.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TenantCfg {
+  /// Limits on the combined storage of all of the tenant's queues, like `max_total_messages` etc. for the server.
+  pub max_messages: Option<u64>,
+  pub max_data_bytes: Option<u64>,
+  pub max_disk_bytes: Option<u64>,
+  /// Optional comma-separated API keys that can only access the tenant's queues, in the same form as `api_keys` but with prefixes relative to the tenant (e.g. `k1=push:orders` for `acme/orders`).
+  pub api_keys: Option<String>,
+}
+
+impl TenantCfg {
+  pub fn limits(&self) -> QuotaLimits {
+    QuotaLimits {
+      max_messages: self.max_messages,
+      max_data_bytes: self.max_data_bytes,
+      max_disk_bytes: self.max_disk_bytes,
+    }
+  }
+}
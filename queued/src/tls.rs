@@ -0,0 +1,108 @@
+use crate::auth::ClientCertificate;
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::RustlsAcceptor;
+use futures::future::BoxFuture;
+use std::io;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use x509_parser::prelude::FromDer;
+use x509_parser::prelude::X509Certificate;
+
+fn common_name(der: &[u8]) -> Option<String> {
+  let (_, cert) = X509Certificate::from_der(der).ok()?;
+  let cn = cert.subject().iter_common_name().next()?;
+  Some(cn.as_str().ok()?.to_string())
+}
+
+/// Wraps the TLS acceptor to add the client's certificate, if any, to every request on the connection as a `ClientCertificate` extension. The certificate has already been verified against the CA during the handshake.
+#[derive(Clone)]
+pub(crate) struct ClientCertificateAcceptor {
+  inner: RustlsAcceptor,
+}
+
+impl ClientCertificateAcceptor {
+  pub fn new(inner: RustlsAcceptor) -> Self {
+    Self { inner }
+  }
+}
+
+impl<I, S> Accept<I, S> for ClientCertificateAcceptor
+where
+  I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  S: Send + 'static,
+{
+  type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+  type Service = AddExtension<S, ClientCertificate>;
+  type Stream = TlsStream<I>;
+
+  fn accept(&self, stream: I, service: S) -> Self::Future {
+    let fut = self.inner.accept(stream, service);
+    Box::pin(async move {
+      let (stream, service) = fut.await?;
+      let common_name = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|c| common_name(&c.0));
+      let service = Extension(ClientCertificate { common_name }).layer(service);
+      Ok((stream, service))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::common_name;
+  use x509_parser::pem::parse_x509_pem;
+
+  // Self-signed certificates with subjects `O=queued, CN=worker-1` and `O=queued`.
+  const WITH_COMMON_NAME: &str = "-----BEGIN CERTIFICATE-----
+MIIBnzCCAUWgAwIBAgIUWUxGssLvsCL/dXUjO4smM5ElbagwCgYIKoZIzj0EAwIw
+JDEPMA0GA1UECgwGcXVldWVkMREwDwYDVQQDDAh3b3JrZXItMTAgFw0yNjEwMTUy
+MjQ3MjVaGA8yMTI2MDkyMTIyNDcyNVowJDEPMA0GA1UECgwGcXVldWVkMREwDwYD
+VQQDDAh3b3JrZXItMTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABBzCywxS6b5M
+aBPOnQJjjB6KM1DU0elUQ2GyNnDkl0/k1vQoKM+IzWZPkDhe35M+zG+tgbeEDZvf
+3jF5bGDHO62jUzBRMB0GA1UdDgQWBBT9m65qasKWvgv1x0qj18cwg1zvcTAfBgNV
+HSMEGDAWgBT9m65qasKWvgv1x0qj18cwg1zvcTAPBgNVHRMBAf8EBTADAQH/MAoG
+CCqGSM49BAMCA0gAMEUCIQDcd0PR1GHflcyGgBOTHM4xmnUtMAWJrt2feWh7uCW4
+iQIgLmCjNDsATU4BlXvBqfAK87C6NjgdoWnYn/KsuWeHpVw=
+-----END CERTIFICATE-----
+";
+  const WITHOUT_COMMON_NAME: &str = "-----BEGIN CERTIFICATE-----
+MIIBeTCCAR+gAwIBAgIULCZ5aMasa+5YS5EnYuuqjm+fqXcwCgYIKoZIzj0EAwIw
+ETEPMA0GA1UECgwGcXVldWVkMCAXDTI2MTAxNTIyNDcyNVoYDzIxMjYwOTIxMjI0
+NzI1WjARMQ8wDQYDVQQKDAZxdWV1ZWQwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNC
+AARMhksl8VZz9sUpO80NfzUL/5h6p53TJBIKgbzVDJ7KIKkSkKhvS8TgvwnCiNyY
+OVdH8j+pou6L+/147u7PSmoko1MwUTAdBgNVHQ4EFgQUSVIrVQ76EUgiNcN8ldRo
+xcCnlEgwHwYDVR0jBBgwFoAUSVIrVQ76EUgiNcN8ldRoxcCnlEgwDwYDVR0TAQH/
+BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiBOKB4W8soNpe7dvL8ckPIJIkD1Us/u
+zPaIq6BLAyu8CQIhAPdtEyUS8YF3JUgQG3VVsDFXK4OI1TOSxapctSLsUaF2
+-----END CERTIFICATE-----
+";
+
+  fn der(pem: &str) -> Vec<u8> {
+    parse_x509_pem(pem.as_bytes()).unwrap().1.contents
+  }
+
+  #[test]
+  fn reads_the_common_name() {
+    assert_eq!(
+      common_name(&der(WITH_COMMON_NAME)).as_deref(),
+      Some("worker-1")
+    );
+    assert_eq!(common_name(&der(WITHOUT_COMMON_NAME)), None);
+  }
+
+  #[test]
+  fn rejects_malformed_certificates() {
+    let der = der(WITH_COMMON_NAME);
+    assert_eq!(common_name(&der[..der.len() / 2]), None);
+    assert_eq!(common_name(b""), None);
+    assert_eq!(common_name(b"worker-1"), None);
+  }
+}
@@ -0,0 +1,70 @@
+use crate::endpoint::HttpCtx;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::warn;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+pub(crate) struct TombstoneGcCfg {
+  /// Compact each queue at least this often, regardless of how many tombstones it has.
+  pub interval: Option<Duration>,
+  /// Compact a queue once this percentage of the entries in its files on disk are tombstones.
+  pub max_percent: Option<f64>,
+  /// Queues with fewer tombstones than this aren't compacted due to `max_percent`, as they're too few to slow anything down.
+  pub min_tombstones: u64,
+}
+
+impl TombstoneGcCfg {
+  fn is_due(&self, last: Instant, entries: u64, tombstones: u64) -> bool {
+    if self.interval.is_some_and(|i| last.elapsed() >= i) {
+      return true;
+    };
+    self.max_percent.is_some_and(|max| {
+      tombstones >= self.min_tombstones.max(1)
+        && tombstones as f64 * 100.0 / entries.max(1) as f64 >= max
+    })
+  }
+}
+
+/// Compacts the message keys of queues that are due, one queue at a time so that compactions don't compete for I/O. Compaction isn't replicated, so every node does this, including followers and read-only replicas.
+pub(crate) fn start_tombstone_gc(ctx: Weak<HttpCtx>, cfg: TombstoneGcCfg) {
+  spawn(async move {
+    let started = Instant::now();
+    let mut last_compacted = HashMap::<String, Instant>::new();
+    loop {
+      sleep(CHECK_INTERVAL).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      let queues = ctx
+        .queues
+        .iter()
+        .map(|e| (e.key().clone(), Arc::clone(e.value())))
+        .collect::<Vec<_>>();
+      drop(ctx);
+      last_compacted.retain(|name, _| queues.iter().any(|(n, _)| n == name));
+      for (name, q) in queues {
+        let stats = q.metrics().storage_stats();
+        let last = last_compacted.get(&name).copied().unwrap_or(started);
+        if !cfg.is_due(last, stats.entries, stats.tombstones) {
+          continue;
+        };
+        // Even if this fails, don't retry until the next interval, as it's likely to fail again.
+        last_compacted.insert(name.clone(), Instant::now());
+        if let Err(err) = q.compact().await {
+          warn!(
+            queue = name,
+            error = format!("{err:?}"),
+            "failed to compact queue"
+          );
+        };
+      }
+    }
+  });
+}
@@ -0,0 +1,332 @@
+use crate::endpoint::HttpCtx;
+use chrono::Utc;
+use dashmap::DashSet;
+use futures::future::join_all;
+use hmac::Hmac;
+use hmac::Mac;
+use libqueued::op::delete::OpDeleteInput;
+use libqueued::op::delete::OpDeleteInputMessage;
+use libqueued::op::nack::OpNackInput;
+use libqueued::op::poll::OpPollInput;
+use libqueued::op::poll::OpPollOutputMessage;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use libqueued::webhook::WebhookCfg;
+use libqueued::Queued;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::warn;
+
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(1);
+const DELIVERY_BATCH: usize = 32;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+// Long enough that a message doesn't become visible again while its delivery is still being attempted.
+const DELIVERY_VISIBILITY_TIMEOUT_SECS: i64 = 60;
+// The delay before a failed delivery is retried doubles with each attempt, up to the max.
+const RETRY_BASE_DELAY_SECS: i64 = 1;
+const RETRY_MAX_DELAY_SECS: i64 = 60 * 60;
+
+fn retry_delay_secs(attempt: u32) -> i64 {
+  RETRY_BASE_DELAY_SECS
+    .checked_shl(attempt.saturating_sub(1))
+    .unwrap_or(RETRY_MAX_DELAY_SECS)
+    .min(RETRY_MAX_DELAY_SECS)
+}
+
+fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+  mac.update(format!("{timestamp}.").as_bytes());
+  mac.update(body);
+  format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn send(
+  client: &reqwest::Client,
+  queue: &str,
+  cfg: &WebhookCfg,
+  m: &OpPollOutputMessage,
+) -> bool {
+  let timestamp = Utc::now().timestamp();
+  let mut req = client
+    .post(&cfg.url)
+    .header("content-type", "application/octet-stream")
+    .header("x-queued-queue", queue)
+    .header("x-queued-message-id", m.id)
+    .header("x-queued-attempt", m.poll_count)
+    .header("x-queued-timestamp", timestamp)
+    .body(m.contents.clone());
+  if let Some(secret) = &cfg.secret {
+    req = req.header("x-queued-signature", sign(secret, timestamp, &m.contents));
+  };
+  match req.send().await {
+    Ok(res) if res.status().is_success() => true,
+    Ok(res) => {
+      warn!(
+        queue,
+        id = m.id,
+        status = res.status().as_u16(),
+        "webhook rejected delivery"
+      );
+      false
+    }
+    Err(err) => {
+      warn!(
+        queue,
+        id = m.id,
+        error = err.to_string(),
+        "failed to deliver to webhook"
+      );
+      false
+    }
+  }
+}
+
+// Returns whether the message was moved, in which case it can be deleted from the original queue.
+async fn dead_letter(queue: &str, dlq: &Queued, m: &OpPollOutputMessage) -> bool {
+  let res = dlq
+    .push(OpPushInput {
+      messages: vec![OpPushInputMessage {
+        contents: m.contents.clone(),
+        visibility_timeout_secs: 0,
+        visibility_jitter_secs: 0,
+        priority: 0,
+        attributes: m.attributes.clone(),
+        ttl_secs: None,
+        group_id: None,
+        external_id: None,
+      }],
+    })
+    .await;
+  if let Err(err) = res {
+    warn!(
+      queue,
+      id = m.id,
+      error = format!("{err:?}"),
+      "failed to move undeliverable message to dead letter queue"
+    );
+    return false;
+  };
+  true
+}
+
+async fn deliver(
+  client: reqwest::Client,
+  name: String,
+  q: Arc<Queued>,
+  cfg: WebhookCfg,
+  dlq: Option<Arc<Queued>>,
+) {
+  // Checking first is much cheaper than an empty poll, which most checks are.
+  if !q.visibility_watermark().visible_now {
+    return;
+  };
+  // Errors such as the queue being suspended or throttled also apply to consumers that poll, so are left to their own metrics.
+  let Ok(polled) = q
+    .poll(OpPollInput {
+      count: DELIVERY_BATCH,
+      visibility_timeout_secs: DELIVERY_VISIBILITY_TIMEOUT_SECS,
+      ignore_existing_visibility_timeouts: false,
+      prefer_group: None,
+      fields: None,
+    })
+    .await
+  else {
+    return;
+  };
+  let results = join_all(
+    polled
+      .messages
+      .iter()
+      .map(|m| send(&client, &name, &cfg, m)),
+  )
+  .await;
+  let mut delivered_msgs = Vec::new();
+  let mut dead_lettered_msgs = Vec::new();
+  for (m, delivered) in polled.messages.iter().zip(results) {
+    let done = OpDeleteInputMessage {
+      id: m.id,
+      poll_tag: m.poll_tag,
+    };
+    if delivered {
+      q.metrics().record_webhook_delivered();
+      delivered_msgs.push(done);
+      continue;
+    };
+    q.metrics().record_webhook_failed();
+    // Pinned messages are excluded from bulk removal, so are retried instead of being moved.
+    let dead_lettered = match &dlq {
+      Some(dlq) if m.poll_count >= cfg.max_attempts && !q.is_message_pinned(m.id) => {
+        dead_letter(&name, dlq, m).await
+      }
+      _ => false,
+    };
+    if dead_lettered {
+      q.metrics().record_webhook_dead_lettered();
+      dead_lettered_msgs.push(done);
+    } else {
+      // If this fails, the message is retried once its visibility timeout passes instead.
+      let _ = q
+        .nack(OpNackInput {
+          id: m.id,
+          poll_tag: m.poll_tag,
+          delay_secs: retry_delay_secs(m.poll_count),
+        })
+        .await;
+    };
+  }
+  // If these fail, the messages will be delivered or dead lettered again, which is safe as delivery is at-least-once anyway.
+  if !delivered_msgs.is_empty() {
+    if let Err(err) = q
+      .delete(OpDeleteInput {
+        messages: delivered_msgs,
+      })
+      .await
+    {
+      warn!(
+        queue = name,
+        error = format!("{err:?}"),
+        "failed to delete messages delivered to webhook"
+      );
+    };
+  };
+  if !dead_lettered_msgs.is_empty() {
+    if let Err(err) = q
+      .dead_letter(OpDeleteInput {
+        messages: dead_lettered_msgs,
+      })
+      .await
+    {
+      warn!(
+        queue = name,
+        error = format!("{err:?}"),
+        "failed to delete messages moved to dead letter queue"
+      );
+    };
+  };
+}
+
+/// Delivers messages of queues with a webhook every second. Each queue has at most one batch being delivered at a time, so a slow webhook doesn't hold up others. In a cluster, only the leader does this, as it polls and deletes.
+pub(crate) fn start_webhook_delivery(ctx: Weak<HttpCtx>) {
+  let client = reqwest::Client::builder()
+    .timeout(DELIVERY_TIMEOUT)
+    .build()
+    .unwrap();
+  let delivering = Arc::new(DashSet::<String>::new());
+  spawn(async move {
+    loop {
+      sleep(DELIVERY_INTERVAL).await;
+      let Some(ctx) = ctx.upgrade() else {
+        break;
+      };
+      if ctx.is_follower() {
+        continue;
+      };
+      let queues = ctx
+        .queues
+        .iter()
+        .filter_map(|e| {
+          Some((
+            e.key().clone(),
+            Arc::clone(e.value()),
+            e.value().get_webhook()?,
+          ))
+        })
+        .collect::<Vec<_>>();
+      for (name, q, cfg) in queues {
+        if !delivering.insert(name.clone()) {
+          continue;
+        };
+        // If the dead letter queue has been deleted, messages are retried until it's recreated or the webhook is changed.
+        let dlq = cfg
+          .dead_letter_queue
+          .as_ref()
+          .and_then(|n| ctx.queues.get(n).map(|q| Arc::clone(&*q)));
+        let client = client.clone();
+        let delivering = Arc::clone(&delivering);
+        spawn(async move {
+          deliver(client, name.clone(), q, cfg, dlq).await;
+          delivering.remove(&name);
+        });
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::deliver;
+  use libqueued::lifecycle::MessageState;
+  use libqueued::op::pin::OpPinInput;
+  use libqueued::op::push::OpPushInput;
+  use libqueued::op::push::OpPushInputMessage;
+  use libqueued::storage::StorageBackend;
+  use libqueued::webhook::WebhookCfg;
+  use libqueued::Queued;
+  use libqueued::QueuedCfg;
+  use std::path::Path;
+  use std::sync::Arc;
+
+  async fn queue() -> Arc<Queued> {
+    let cfg = QueuedCfg {
+      storage: StorageBackend::InMemory,
+      ..Default::default()
+    };
+    Arc::new(Queued::load_and_start(Path::new("/nonexistent"), cfg).await)
+  }
+
+  fn message(contents: &[u8]) -> OpPushInputMessage {
+    OpPushInputMessage {
+      contents: contents.to_vec(),
+      visibility_timeout_secs: 0,
+      visibility_jitter_secs: 0,
+      priority: 0,
+      attributes: Default::default(),
+      ttl_secs: None,
+      group_id: None,
+      external_id: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn pinned_messages_are_not_dead_lettered() {
+    let q = queue().await;
+    let dlq = queue().await;
+    let ids = q
+      .push(OpPushInput {
+        messages: vec![message(b"pinned"), message(b"unpinned")],
+      })
+      .await
+      .unwrap()
+      .ids;
+    q.pin(OpPinInput {
+      ids: vec![ids[0]],
+      pinned: true,
+    })
+    .await
+    .unwrap();
+    let cfg = WebhookCfg {
+      // Nothing listens on this port, so every delivery fails.
+      url: "http://127.0.0.1:1/".to_string(),
+      secret: None,
+      max_attempts: 1,
+      dead_letter_queue: Some("dlq".to_string()),
+    };
+    deliver(
+      reqwest::Client::new(),
+      "q".to_string(),
+      Arc::clone(&q),
+      cfg,
+      Some(Arc::clone(&dlq)),
+    )
+    .await;
+
+    assert_ne!(q.message_state(ids[0]), MessageState::Vacant);
+    assert_eq!(q.message_state(ids[1]), MessageState::Vacant);
+    assert_eq!(dlq.visible_message_count(), 1);
+    assert_eq!(q.metrics().webhook_dead_lettered_counter(), 1);
+  }
+}
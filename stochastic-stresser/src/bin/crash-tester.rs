@@ -0,0 +1,485 @@
+// Repeatedly runs a worker process that pushes, polls, updates, and deletes messages while faults are injected (crashes and failed syncs), or until it's killed. After each run, the data dir is loaded and checked against what the worker was told succeeded:
+// - No message whose push succeeded is lost, unless it was deleted.
+// - No message whose delete succeeded comes back.
+// - Every message has the contents of exactly one attempted push, and no push is present twice.
+// - The index matches storage (using `IndexMismatchAction::Refuse`).
+use libqueued::fault::parse_faults;
+use libqueued::fault::set_faults;
+use libqueued::index_check::IndexMismatchAction;
+use libqueued::op::delete::OpDeleteInput;
+use libqueued::op::delete::OpDeleteInputMessage;
+use libqueued::op::peek::OpPeekInput;
+use libqueued::op::peek::PEEK_MAX_COUNT;
+use libqueued::op::poll::OpPollInput;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use libqueued::op::update::OpUpdateInput;
+use libqueued::op::update::OpUpdateInputMessage;
+use libqueued::Queued;
+use libqueued::QueuedCfg;
+use rand::thread_rng;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::exit;
+use std::process::Stdio;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+use tokio::process::Command;
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::error;
+use tracing::info;
+
+#[derive(Deserialize)]
+struct Config {
+  data_dir: PathBuf,
+
+  /// How many times to run the worker and verify the data dir afterwards.
+  rounds: u64,
+
+  /// Faults to inject into the worker, in the format of `libqueued::fault::parse_faults`, e.g. `*.after_commit=crash@0.001,*.before_commit=fail_sync@0.01`.
+  faults: String,
+
+  /// Operations each worker attempts before exiting if it hasn't crashed. Defaults to 10000.
+  ops_per_round: Option<u64>,
+
+  /// Concurrency level of each worker. Defaults to 16.
+  concurrency: Option<u64>,
+
+  /// If set, each worker is also killed after a random time up to this many milliseconds, to crash at arbitrary points.
+  max_kill_after_ms: Option<u64>,
+}
+
+// Each message's contents identify the push that created it: its number, followed by filler derived from it, so that corruption can be detected.
+fn contents_for(n: u64) -> Vec<u8> {
+  let mut out = n.to_le_bytes().to_vec();
+  out.extend((0..n % 1024).map(|i| (n + i) as u8));
+  out
+}
+
+fn push_number(contents: &[u8]) -> Option<u64> {
+  let n = u64::from_le_bytes(contents.get(..8)?.try_into().unwrap());
+  (contents == contents_for(n)).then_some(n)
+}
+
+fn queued_cfg() -> QueuedCfg {
+  QueuedCfg {
+    batch_sync_delay: Duration::from_millis(5),
+    verify_index: Some(IndexMismatchAction::Refuse),
+    ..Default::default()
+  }
+}
+
+// Every line printed is a fact the verifier relies on, so a success is only printed once the operation has returned.
+async fn run_worker(cfg: &Config, first_n: u64) {
+  set_faults(parse_faults(&cfg.faults).expect("parse faults"));
+  let queued = Arc::new(Queued::load_and_start(&cfg.data_dir, queued_cfg()).await);
+  let next_n = Arc::new(AtomicU64::new(first_n));
+  let remaining = Arc::new(AtomicU64::new(cfg.ops_per_round.unwrap_or(10_000)));
+  let workers = (0..cfg.concurrency.unwrap_or(16))
+    .map(|_| {
+      let queued = queued.clone();
+      let next_n = next_n.clone();
+      let remaining = remaining.clone();
+      spawn(async move {
+        while remaining
+          .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| r.checked_sub(1))
+          .is_ok()
+        {
+          if thread_rng().gen_bool(0.5) {
+            let ns = (0..thread_rng().gen_range(1..=4))
+              .map(|_| next_n.fetch_add(1, Ordering::Relaxed))
+              .collect::<Vec<_>>();
+            for n in ns.iter() {
+              println!("push? {n}");
+            }
+            let res = queued
+              .push(OpPushInput {
+                messages: ns
+                  .iter()
+                  .map(|&n| OpPushInputMessage {
+                    contents: contents_for(n),
+                    visibility_timeout_secs: 0,
+                    visibility_jitter_secs: 0,
+                    priority: 0,
+                    attributes: Default::default(),
+                    ttl_secs: None,
+                    group_id: None,
+                    external_id: None,
+                  })
+                  .collect(),
+              })
+              .await;
+            if let Ok(res) = res {
+              for (id, n) in res.ids.iter().zip(ns.iter()) {
+                println!("push {id} {n}");
+              }
+            };
+            continue;
+          };
+          let count = thread_rng().gen_range(1..=4);
+          let Ok(res) = queued
+            .poll(OpPollInput {
+              count,
+              visibility_timeout_secs: 1,
+              ignore_existing_visibility_timeouts: false,
+              prefer_group: None,
+              fields: None,
+            })
+            .await
+          else {
+            continue;
+          };
+          for m in res.messages {
+            match push_number(&m.contents) {
+              Some(n) => println!("poll {} {n}", m.id),
+              None => println!("corrupt {}", m.id),
+            };
+            let mut poll_tag = m.poll_tag;
+            let (update, delete) = {
+              let mut rng = thread_rng();
+              (rng.gen_bool(0.3), rng.gen_bool(0.7))
+            };
+            if update {
+              let Ok(res) = queued
+                .update(OpUpdateInput {
+                  messages: vec![OpUpdateInputMessage {
+                    id: m.id,
+                    poll_tag,
+                    visibility_timeout_secs: 1,
+                  }],
+                })
+                .await
+              else {
+                continue;
+              };
+              let Some(new_poll_tag) = res.new_poll_tags[0] else {
+                continue;
+              };
+              poll_tag = new_poll_tag;
+            };
+            if delete {
+              println!("delete? {}", m.id);
+              let res = queued
+                .delete(OpDeleteInput {
+                  messages: vec![OpDeleteInputMessage { id: m.id, poll_tag }],
+                })
+                .await;
+              if res.is_ok() {
+                println!("delete {}", m.id);
+              };
+            };
+          }
+        }
+      })
+    })
+    .collect::<Vec<_>>();
+  for w in workers {
+    w.await.unwrap();
+  }
+}
+
+#[derive(Default)]
+struct Expected {
+  // Push numbers that were attempted, whether or not they succeeded.
+  attempted: HashSet<u64>,
+  next_n: u64,
+  // Messages known to exist at some point, by ID, with their push number.
+  known: HashMap<u64, u64>,
+  deleted: HashSet<u64>,
+  // Messages whose delete was attempted in the last run but didn't succeed, so may or may not exist.
+  maybe_deleted: HashSet<u64>,
+  violations: Vec<String>,
+}
+
+impl Expected {
+  fn saw(&mut self, id: u64, n: u64) {
+    if !self.attempted.contains(&n) {
+      self.violations.push(format!(
+        "message {id} has push {n}, which was never attempted"
+      ));
+    };
+    match self.known.insert(id, n) {
+      Some(prev) if prev != n => self
+        .violations
+        .push(format!("message {id} had push {prev} but now has push {n}")),
+      _ => {}
+    };
+  }
+
+  fn apply(&mut self, line: &str) {
+    let parts = line.split(' ').collect::<Vec<_>>();
+    let num = |i: usize| parts[i].parse::<u64>().expect("parse worker output");
+    match parts[0] {
+      "push?" => {
+        self.attempted.insert(num(1));
+        self.next_n = self.next_n.max(num(1) + 1);
+      }
+      "push" | "poll" => {
+        if self.deleted.contains(&num(1)) {
+          self
+            .violations
+            .push(format!("message {} was seen after being deleted", num(1)));
+        };
+        self.saw(num(1), num(2));
+      }
+      "delete?" => {
+        self.maybe_deleted.insert(num(1));
+      }
+      "delete" => {
+        self.maybe_deleted.remove(&num(1));
+        self.deleted.insert(num(1));
+      }
+      "corrupt" => self.violations.push(format!(
+        "message {} was polled with corrupt contents",
+        num(1)
+      )),
+      _ => panic!("unknown worker output {line:?}"),
+    };
+  }
+
+  async fn verify(&mut self, data_dir: &Path) {
+    let queued = Queued::load_and_start(data_dir, queued_cfg()).await;
+    let mut present = HashMap::new();
+    let mut after = None;
+    loop {
+      let page = queued
+        .peek(OpPeekInput {
+          count: PEEK_MAX_COUNT,
+          after,
+        })
+        .await
+        .unwrap();
+      for m in page.messages {
+        match push_number(&m.contents) {
+          Some(n) => {
+            present.insert(m.id, n);
+          }
+          None => self
+            .violations
+            .push(format!("message {} has corrupt contents", m.id)),
+        };
+      }
+      after = page.next;
+      if after.is_none() {
+        break;
+      };
+    }
+    drop(queued);
+    self.check(present);
+  }
+
+  // Checks the messages present after a round, by ID with their push number, against what the worker reported.
+  fn check(&mut self, present: HashMap<u64, u64>) {
+    let mut pushes = HashMap::new();
+    for (&id, &n) in present.iter() {
+      if self.deleted.contains(&id) {
+        self
+          .violations
+          .push(format!("message {id} exists after being deleted"));
+      };
+      if let Some(other) = pushes.insert(n, id) {
+        self.violations.push(format!(
+          "push {n} exists twice, as messages {other} and {id}"
+        ));
+      };
+      self.saw(id, n);
+    }
+    for (&id, &n) in self.known.iter() {
+      if !present.contains_key(&id)
+        && !self.deleted.contains(&id)
+        && !self.maybe_deleted.contains(&id)
+      {
+        self
+          .violations
+          .push(format!("message {id} with push {n} was lost"));
+      };
+    }
+    // Whatever is missing now has been deleted, whether or not the worker found out.
+    for (&id, _) in self.known.iter() {
+      if !present.contains_key(&id) {
+        self.deleted.insert(id);
+      };
+    }
+    self.known = present;
+    self.maybe_deleted.clear();
+  }
+}
+
+async fn run_round(cfg: &Config, config_path: &str, expected: &mut Expected) {
+  let mut child = Command::new(env::current_exe().unwrap())
+    .arg(config_path)
+    .arg("worker")
+    .arg(expected.next_n.to_string())
+    .stdout(Stdio::piped())
+    .spawn()
+    .expect("spawn worker");
+  let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+  let kill_after = cfg
+    .max_kill_after_ms
+    .map(|max| Duration::from_millis(thread_rng().gen_range(0..=max)));
+  let kill = async {
+    match kill_after {
+      Some(d) => sleep(d).await,
+      None => std::future::pending().await,
+    }
+  };
+  tokio::pin!(kill);
+  let mut killed = false;
+  loop {
+    tokio::select! {
+      line = lines.next_line() => match line.unwrap() {
+        Some(line) => expected.apply(&line),
+        None => break,
+      },
+      _ = &mut kill, if !killed => {
+        killed = true;
+        child.start_kill().unwrap();
+      }
+    };
+  }
+  let status = child.wait().await.unwrap();
+  info!(?status, killed, "worker exited");
+}
+
+#[tokio::main]
+async fn main() {
+  tracing_subscriber::fmt()
+    .with_writer(std::io::stderr)
+    .init();
+
+  let config_path = env::args().nth(1).expect("config file path argument");
+  let cfg: Config =
+    serde_yaml::from_str(&fs::read_to_string(&config_path).expect("read config file"))
+      .expect("parse config file");
+
+  if env::args().nth(2).as_deref() == Some("worker") {
+    let first_n = env::args().nth(3).unwrap().parse().unwrap();
+    run_worker(&cfg, first_n).await;
+    return;
+  };
+
+  match fs::remove_dir_all(&cfg.data_dir) {
+    Ok(()) => {}
+    Err(err) if err.kind() == ErrorKind::NotFound => {}
+    Err(err) => panic!("{}", err),
+  };
+  fs::create_dir(&cfg.data_dir).unwrap();
+  info!("cleared data dir");
+
+  let mut expected = Expected::default();
+  for round in 0..cfg.rounds {
+    run_round(&cfg, &config_path, &mut expected).await;
+    expected.verify(&cfg.data_dir).await;
+    if !expected.violations.is_empty() {
+      for v in expected.violations.iter() {
+        error!(round, "{v}");
+      }
+      exit(1);
+    };
+    info!(
+      round,
+      messages = expected.known.len(),
+      deleted = expected.deleted.len(),
+      "verified"
+    );
+  }
+  info!("all done");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::contents_for;
+  use super::push_number;
+  use super::Expected;
+  use std::collections::HashMap;
+
+  fn expected(lines: &[&str]) -> Expected {
+    let mut expected = Expected::default();
+    for line in lines {
+      expected.apply(line);
+    }
+    expected
+  }
+
+  #[test]
+  fn contents_identify_their_push() {
+    for n in [0, 1, 1023, 1024, 123_456_789] {
+      assert_eq!(push_number(&contents_for(n)), Some(n));
+    }
+    let mut corrupt = contents_for(5000);
+    *corrupt.last_mut().unwrap() ^= 1;
+    assert_eq!(push_number(&corrupt), None);
+    assert_eq!(push_number(&contents_for(5000)[..100]), None);
+    assert_eq!(push_number(&[1, 2, 3]), None);
+  }
+
+  #[test]
+  fn accepts_what_the_worker_reported() {
+    let mut e = expected(&[
+      "push? 0",
+      "push 10 0",
+      "push? 1",
+      "push? 2",
+      "push 12 2",
+      "poll 10 0",
+      "delete? 10",
+      "delete 10",
+      "delete? 12",
+    ]);
+    assert_eq!(e.next_n, 3);
+    // Push 1 may or may not have succeeded before the crash, and so may the delete of 12.
+    e.check(HashMap::from([(11, 1)]));
+    assert!(e.violations.is_empty(), "{:?}", e.violations);
+    assert_eq!(e.known, HashMap::from([(11, 1)]));
+    assert!(e.deleted.contains(&10) && e.deleted.contains(&12));
+    assert!(e.maybe_deleted.is_empty());
+  }
+
+  #[test]
+  fn reports_lost_changed_duplicated_and_resurrected_messages() {
+    let mut e = expected(&[
+      "push? 0",
+      "push 10 0",
+      "push? 1",
+      "push 11 1",
+      "delete 11",
+      "poll 11 1",
+      "push? 2",
+      "push 12 2",
+      "push? 3",
+      "push 13 3",
+    ]);
+    assert_eq!(e.violations, vec![
+      "message 11 was seen after being deleted"
+    ]);
+    e.violations.clear();
+    e.check(HashMap::from([(11, 1), (12, 3), (14, 3), (15, 9)]));
+    let mut violations = e.violations.clone();
+    violations.sort();
+    let duplicate = violations.pop().unwrap();
+    assert!(
+      duplicate == "push 3 exists twice, as messages 12 and 14"
+        || duplicate == "push 3 exists twice, as messages 14 and 12",
+      "{duplicate}"
+    );
+    assert_eq!(violations, vec![
+      "message 10 with push 0 was lost",
+      "message 11 exists after being deleted",
+      "message 12 had push 2 but now has push 3",
+      "message 13 with push 3 was lost",
+      "message 15 has push 9, which was never attempted",
+    ]);
+  }
+}
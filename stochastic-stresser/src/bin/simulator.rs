@@ -0,0 +1,533 @@
+// Drives a queue with many simulated consumers using virtual time and seeded randomness, checking every result against a model of what the queue should contain:
+// - A poll returns as many messages as are definitely visible, up to the requested count, and never one that isn't visible yet.
+// - A poll never passes over a visible message that's ahead of a returned one in the configured `PollOrder`, so no message is starved.
+// - Polled messages have the contents they were pushed with, and a new poll tag.
+// - An update or delete only succeeds with the message's current poll tag, so a consumer whose lease has been taken over by another can't affect the message.
+// - A deleted message never comes back.
+// Operations are run one at a time, so that a run is exactly reproducible from its seed, but consumers hold leases across each other's operations, and time advances in between, so visibility timeouts expire and poll tags go stale in arbitrary orders. If a run fails, rerun it by setting `seed` to the printed seed.
+use libqueued::clock::Clock;
+use libqueued::lifecycle::MessageState;
+use libqueued::messages::PollOrder;
+use libqueued::op::delete::OpDeleteInput;
+use libqueued::op::delete::OpDeleteInputMessage;
+use libqueued::op::poll::OpPollInput;
+use libqueued::op::poll::OpPollOutputMessage;
+use libqueued::op::push::OpPushInput;
+use libqueued::op::push::OpPushInputMessage;
+use libqueued::op::update::OpUpdateInput;
+use libqueued::op::update::OpUpdateInputMessage;
+use libqueued::random::SeededRandom;
+use libqueued::storage::StorageBackend;
+use libqueued::Queued;
+use libqueued::QueuedCfg;
+use rand::rngs::StdRng;
+use rand::thread_rng;
+use rand::Rng;
+use rand::SeedableRng;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::process::exit;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+use tracing::info;
+
+#[derive(Deserialize)]
+struct Config {
+  /// The seed of the first run. Defaults to a random seed.
+  seed: Option<u64>,
+
+  /// How many runs to simulate, each with the next seed. Defaults to 1.
+  runs: Option<u64>,
+
+  /// Operations in each run. Defaults to 10000.
+  ops_per_run: Option<u64>,
+
+  /// How many consumers poll, update, and delete messages. Defaults to 8.
+  consumers: Option<usize>,
+
+  /// `visible_time` (the default) or `fifo`. See `libqueued::messages::PollOrder`.
+  poll_order: Option<String>,
+}
+
+// 2023-11-14T22:13:20Z, so that timestamps look like real ones.
+const START_MS: i64 = 1_700_000_000_000;
+
+struct SimClock(AtomicI64);
+
+impl SimClock {
+  fn advance(&self, ms: i64) {
+    self.0.fetch_add(ms, Ordering::Relaxed);
+  }
+}
+
+impl Clock for SimClock {
+  fn now_ms(&self) -> i64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+struct ModelMessage {
+  n: u64,
+  // The earliest and latest possible visible time, which differ while a pushed message's jitter isn't known.
+  visible_from: i64,
+  visible_by: i64,
+  // None until the message is first polled.
+  poll_tag: Option<u32>,
+}
+
+#[derive(Default)]
+struct Model {
+  messages: BTreeMap<u64, ModelMessage>,
+  deleted: BTreeSet<u64>,
+  next_n: u64,
+}
+
+fn contents_for(n: u64) -> Vec<u8> {
+  n.to_le_bytes().to_vec()
+}
+
+struct Sim {
+  queued: Queued,
+  clock: Arc<SimClock>,
+  rng: StdRng,
+  poll_order: PollOrder,
+  model: Model,
+  // The (ID, poll tag) of each message each consumer has received, which may have since been polled again by another consumer.
+  leases: Vec<Vec<(u64, u32)>>,
+}
+
+impl Sim {
+  async fn new(seed: u64, consumers: usize, poll_order: PollOrder) -> Self {
+    let clock = Arc::new(SimClock(AtomicI64::new(START_MS)));
+    let queued = Queued::load_and_start(&env::temp_dir(), QueuedCfg {
+      batch_sync_delay: Duration::ZERO,
+      clock: clock.clone(),
+      poll_order,
+      // Derived from the seed, but distinct from the simulator's own RNG.
+      random: Arc::new(SeededRandom::new(seed ^ 0x5eed)),
+      storage: StorageBackend::InMemory,
+      ..Default::default()
+    })
+    .await;
+    Self {
+      queued,
+      clock,
+      rng: StdRng::seed_from_u64(seed),
+      poll_order,
+      model: Model::default(),
+      leases: vec![Vec::new(); consumers],
+    }
+  }
+
+  fn now(&self) -> i64 {
+    self.clock.now()
+  }
+
+  async fn push(&mut self) -> Result<(), String> {
+    let now = self.now();
+    let messages = (0..self.rng.gen_range(1..=3))
+      .map(|_| {
+        self.model.next_n += 1;
+        let jitter = if self.rng.gen_bool(0.3) { 1 } else { 0 };
+        (self.model.next_n, self.rng.gen_range(0..=3u32), jitter)
+      })
+      .collect::<Vec<_>>();
+    let res = self
+      .queued
+      .push(OpPushInput {
+        messages: messages
+          .iter()
+          .map(|&(n, timeout, jitter)| OpPushInputMessage {
+            contents: contents_for(n),
+            visibility_timeout_secs: timeout,
+            visibility_jitter_secs: jitter,
+            priority: 0,
+            attributes: Default::default(),
+            ttl_secs: None,
+            group_id: None,
+            external_id: None,
+          })
+          .collect(),
+      })
+      .await
+      .map_err(|err| format!("push failed: {err:?}"))?;
+    for (&id, &(n, timeout, jitter)) in res.ids.iter().zip(messages.iter()) {
+      if self.model.messages.contains_key(&id) || self.model.deleted.contains(&id) {
+        return Err(format!("push reused ID {id}"));
+      };
+      let at = now + i64::from(timeout);
+      self.model.messages.insert(id, ModelMessage {
+        n,
+        visible_from: (at - i64::from(jitter)).max(now),
+        visible_by: at + i64::from(jitter),
+        poll_tag: None,
+      });
+    }
+    Ok(())
+  }
+
+  async fn poll(&mut self, consumer: usize) -> Result<(), String> {
+    let now = self.now();
+    let count = self.rng.gen_range(1..=3);
+    let timeout = self.rng.gen_range(1..=4);
+    let definitely = self
+      .model
+      .messages
+      .values()
+      .filter(|m| m.visible_by <= now)
+      .count();
+    let possibly = self
+      .model
+      .messages
+      .values()
+      .filter(|m| m.visible_from <= now)
+      .count();
+    let res = self
+      .queued
+      .poll(OpPollInput {
+        count,
+        visibility_timeout_secs: timeout,
+        ignore_existing_visibility_timeouts: false,
+        prefer_group: None,
+        fields: None,
+      })
+      .await
+      .map_err(|err| format!("poll failed: {err:?}"))?;
+    let got = res.messages.len();
+    if got < count.min(definitely) || got > count.min(possibly) {
+      return Err(format!(
+        "poll of {count} returned {got} messages, but {definitely} to {possibly} were visible"
+      ));
+    };
+    self.check_poll_order(now, &res.messages)?;
+    for m in res.messages {
+      let Some(model) = self.model.messages.get_mut(&m.id) else {
+        return Err(if self.model.deleted.contains(&m.id) {
+          format!("poll returned message {} after it was deleted", m.id)
+        } else {
+          format!("poll returned unknown message {}", m.id)
+        });
+      };
+      if model.visible_from > now {
+        return Err(format!(
+          "poll returned message {} before it was visible",
+          m.id
+        ));
+      };
+      if m.contents != contents_for(model.n) {
+        return Err(format!("message {} has the wrong contents", m.id));
+      };
+      if model.poll_tag == Some(m.poll_tag) {
+        return Err(format!(
+          "message {} was polled with poll tag {}, which it already had",
+          m.id, m.poll_tag
+        ));
+      };
+      model.poll_tag = Some(m.poll_tag);
+      model.visible_from = now + timeout;
+      model.visible_by = now + timeout;
+      self.leases[consumer].push((m.id, m.poll_tag));
+    }
+    Ok(())
+  }
+
+  // Checks that no visible message that must be polled before one of `polled` was passed over, so that messages can't be starved.
+  fn check_poll_order(&self, now: i64, polled: &[OpPollOutputMessage]) -> Result<(), String> {
+    let polled = polled
+      .iter()
+      .filter_map(|m| Some((m.id, self.model.messages.get(&m.id)?)))
+      .collect::<Vec<_>>();
+    for (&id, m) in self.model.messages.iter() {
+      if m.visible_by > now || polled.iter().any(|&(p, _)| p == id) {
+        continue;
+      };
+      // Visible times are only known as a range, so a message is only certainly ahead if its latest possible visible time is.
+      let ahead_of = polled.iter().find(|&&(p, pm)| match self.poll_order {
+        PollOrder::VisibleTime => (m.visible_by, id) < (pm.visible_from, p),
+        PollOrder::Fifo => id < p,
+      });
+      if let Some((p, _)) = ahead_of {
+        return Err(format!(
+          "poll returned message {p} but passed over message {id}, which is ahead of it in {:?} order",
+          self.poll_order
+        ));
+      };
+    }
+    Ok(())
+  }
+
+  fn take_lease(&mut self, consumer: usize) -> Option<(u64, u32)> {
+    let leases = &mut self.leases[consumer];
+    if leases.is_empty() {
+      return None;
+    };
+    let i = self.rng.gen_range(0..leases.len());
+    Some(leases.swap_remove(i))
+  }
+
+  fn is_current(&self, id: u64, poll_tag: u32) -> bool {
+    self
+      .model
+      .messages
+      .get(&id)
+      .is_some_and(|m| m.poll_tag == Some(poll_tag))
+  }
+
+  async fn update(&mut self, consumer: usize) -> Result<(), String> {
+    let Some((id, poll_tag)) = self.take_lease(consumer) else {
+      return Ok(());
+    };
+    let now = self.now();
+    let timeout = self.rng.gen_range(0..=4);
+    let current = self.is_current(id, poll_tag);
+    let res = self
+      .queued
+      .update(OpUpdateInput {
+        messages: vec![OpUpdateInputMessage {
+          id,
+          poll_tag,
+          visibility_timeout_secs: timeout,
+        }],
+      })
+      .await
+      .map_err(|err| format!("update failed: {err:?}"))?;
+    match (res.new_poll_tags[0], current) {
+      (Some(new_poll_tag), true) => {
+        if new_poll_tag == poll_tag {
+          return Err(format!(
+            "update of message {id} returned its existing poll tag {poll_tag}"
+          ));
+        };
+        let model = self.model.messages.get_mut(&id).unwrap();
+        model.poll_tag = Some(new_poll_tag);
+        model.visible_from = now + timeout;
+        model.visible_by = now + timeout;
+        self.leases[consumer].push((id, new_poll_tag));
+      }
+      (None, false) => {}
+      (Some(_), false) => {
+        return Err(format!(
+          "update of message {id} succeeded with stale poll tag {poll_tag}"
+        ))
+      }
+      (None, true) => {
+        return Err(format!(
+          "update of message {id} failed with current poll tag {poll_tag}"
+        ))
+      }
+    };
+    Ok(())
+  }
+
+  async fn delete(&mut self, consumer: usize) -> Result<(), String> {
+    let Some((id, poll_tag)) = self.take_lease(consumer) else {
+      return Ok(());
+    };
+    let current = self.is_current(id, poll_tag);
+    self
+      .queued
+      .delete(OpDeleteInput {
+        messages: vec![OpDeleteInputMessage { id, poll_tag }],
+      })
+      .await
+      .map_err(|err| format!("delete failed: {err:?}"))?;
+    let state = self.queued.message_state(id);
+    if current {
+      if state != MessageState::Vacant {
+        return Err(format!(
+          "message {id} is {state:?} after being deleted with its current poll tag"
+        ));
+      };
+      self.model.messages.remove(&id);
+      self.model.deleted.insert(id);
+    } else if self.model.messages.contains_key(&id) && state == MessageState::Vacant {
+      return Err(format!(
+        "message {id} was deleted with stale poll tag {poll_tag}"
+      ));
+    };
+    Ok(())
+  }
+
+  // Checks that every message is in the state the model expects.
+  fn check_states(&self) -> Result<(), String> {
+    let now = self.now();
+    for (&id, m) in self.model.messages.iter() {
+      let state = self.queued.message_state(id);
+      let ok = match state {
+        MessageState::Available => m.visible_from <= now,
+        MessageState::Delayed | MessageState::InFlight => m.visible_by > now,
+        _ => false,
+      };
+      if !ok {
+        return Err(format!(
+          "message {id} is {state:?}, but its visible time is between {} and {} and it's now {now}",
+          m.visible_from, m.visible_by
+        ));
+      };
+    }
+    for &id in self.model.deleted.iter() {
+      let state = self.queued.message_state(id);
+      if state != MessageState::Vacant {
+        return Err(format!("deleted message {id} is {state:?}"));
+      };
+    }
+    let visible = self.queued.visible_message_count();
+    let definitely = self.model.messages.values().filter(|m| m.visible_by <= now);
+    let possibly = self
+      .model
+      .messages
+      .values()
+      .filter(|m| m.visible_from <= now);
+    if visible < definitely.clone().count() || visible > possibly.clone().count() {
+      return Err(format!(
+        "{visible} messages are visible, but {} to {} should be",
+        definitely.count(),
+        possibly.count()
+      ));
+    };
+    let oldest = self.queued.oldest_visible_message_time();
+    let latest = definitely.map(|m| m.visible_by).min();
+    let earliest = possibly.map(|m| m.visible_from).min();
+    if oldest.is_some_and(|t| earliest.is_none() || earliest > Some(t))
+      || latest.is_some_and(|t| oldest.is_none() || oldest > Some(t))
+    {
+      return Err(format!(
+        "the oldest visible message became visible at {oldest:?}, but should have between {earliest:?} and {latest:?}"
+      ));
+    };
+    Ok(())
+  }
+
+  async fn step(&mut self) -> Result<(), String> {
+    let advance_ms = self.rng.gen_range(0..=1500);
+    self.clock.advance(advance_ms);
+    let consumer = self.rng.gen_range(0..self.leases.len());
+    match self.rng.gen_range(0..100) {
+      0..=24 => self.push().await,
+      25..=54 => self.poll(consumer).await,
+      55..=74 => self.update(consumer).await,
+      _ => self.delete(consumer).await,
+    }
+  }
+}
+
+async fn run(seed: u64, ops: u64, consumers: usize, poll_order: PollOrder) -> Result<(), String> {
+  let mut sim = Sim::new(seed, consumers, poll_order).await;
+  for op in 0..ops {
+    sim.step().await.map_err(|err| format!("op {op}: {err}"))?;
+    if op % 100 == 99 {
+      sim
+        .check_states()
+        .map_err(|err| format!("after op {op}: {err}"))?;
+    };
+  }
+  sim.check_states()?;
+  info!(
+    seed,
+    messages = sim.model.messages.len(),
+    deleted = sim.model.deleted.len(),
+    "run passed"
+  );
+  Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+  tracing_subscriber::fmt()
+    .with_writer(std::io::stderr)
+    .init();
+
+  let config_path = env::args().nth(1).expect("config file path argument");
+  let cfg: Config =
+    serde_yaml::from_str(&fs::read_to_string(&config_path).expect("read config file"))
+      .expect("parse config file");
+
+  let first_seed = cfg.seed.unwrap_or_else(|| thread_rng().gen());
+  let ops = cfg.ops_per_run.unwrap_or(10_000);
+  let consumers = cfg.consumers.unwrap_or(8);
+  assert!(consumers > 0, "there must be at least one consumer");
+  let poll_order = match cfg.poll_order.as_deref() {
+    None | Some("visible_time") => PollOrder::VisibleTime,
+    Some("fifo") => PollOrder::Fifo,
+    Some(raw) => panic!("invalid poll order {raw:?}"),
+  };
+  for seed in (0..cfg.runs.unwrap_or(1)).map(|i| first_seed.wrapping_add(i)) {
+    if let Err(err) = run(seed, ops, consumers, poll_order).await {
+      error!(seed, "{err}");
+      exit(1);
+    };
+  }
+  info!("all done");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::run;
+  use super::ModelMessage;
+  use super::Sim;
+  use super::START_MS;
+  use libqueued::messages::PollOrder;
+
+  #[tokio::test]
+  async fn runs_pass_in_every_poll_order() {
+    for poll_order in [PollOrder::VisibleTime, PollOrder::Fifo] {
+      for seed in 0..3 {
+        run(seed, 1_000, 4, poll_order).await.unwrap();
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn runs_are_reproducible_from_their_seed() {
+    let mut a = Sim::new(42, 4, PollOrder::VisibleTime).await;
+    let mut b = Sim::new(42, 4, PollOrder::VisibleTime).await;
+    for _ in 0..1_000 {
+      a.step().await.unwrap();
+      b.step().await.unwrap();
+    }
+    assert_eq!(a.now(), b.now());
+    assert_eq!(a.leases, b.leases);
+    assert_eq!(
+      a.model.messages.keys().collect::<Vec<_>>(),
+      b.model.messages.keys().collect::<Vec<_>>()
+    );
+    assert_eq!(a.model.deleted, b.model.deleted);
+    assert!(!a.model.deleted.is_empty());
+  }
+
+  #[tokio::test]
+  async fn detects_a_queue_that_differs_from_the_model() {
+    let mut sim = Sim::new(7, 4, PollOrder::VisibleTime).await;
+    for _ in 0..200 {
+      sim.step().await.unwrap();
+    }
+    sim.check_states().unwrap();
+    // Pretend a message was deleted that the queue still has.
+    let (&id, _) = sim.model.messages.iter().next().unwrap();
+    let m = sim.model.messages.remove(&id).unwrap();
+    sim.model.deleted.insert(id);
+    assert!(sim
+      .check_states()
+      .unwrap_err()
+      .starts_with(&format!("deleted message {id} is ")));
+    // Pretend a message exists that was never pushed.
+    sim.model.deleted.remove(&id);
+    sim.model.messages.insert(id, m);
+    sim.model.messages.insert(u64::MAX, ModelMessage {
+      n: 0,
+      visible_from: START_MS,
+      visible_by: START_MS,
+      poll_tag: None,
+    });
+    assert_eq!(
+      sim.check_states().unwrap_err(),
+      format!("message {} is Vacant, but its visible time is between {START_MS} and {START_MS} and it's now {}", u64::MAX, sim.now())
+    );
+  }
+}
@@ -7,6 +7,7 @@ use libqueued::op::poll::OpPollInput;
 use libqueued::op::push::OpPushInput;
 use libqueued::op::push::OpPushInputMessage;
 use libqueued::op::update::OpUpdateInput;
+use libqueued::op::update::OpUpdateInputMessage;
 use libqueued::Queued;
 use libqueued::QueuedCfg;
 use off64::usz;
@@ -105,6 +106,7 @@ async fn main() {
   let queued = Arc::new(
     Queued::load_and_start(&cli.data_dir, QueuedCfg {
       batch_sync_delay: Duration::from_millis(10),
+      ..Default::default()
     })
     .await,
   );
@@ -113,7 +115,7 @@ async fn main() {
   let pool_size = cli
     .pool_size
     .map(|s| s.as_u64())
-    .unwrap_or(1024 * 1024 * 1024 * 1);
+    .unwrap_or(1024 * 1024 * 1024);
   let maximum_message_size = cli.maximum_message_size.as_u64();
   let concurrency = cli.concurrency.unwrap_or(64);
   let pool = Pool::new(pool_size);
@@ -184,6 +186,12 @@ async fn main() {
                   messages: vec![OpPushInputMessage {
                     contents,
                     visibility_timeout_secs: 0,
+                    visibility_jitter_secs: 0,
+                    priority: 0,
+                    attributes: Default::default(),
+                    ttl_secs: None,
+                    group_id: None,
+                    external_id: None,
                   }],
                 })
                 .await
@@ -199,6 +207,8 @@ async fn main() {
                   count: 1,
                   visibility_timeout_secs,
                   ignore_existing_visibility_timeouts: false,
+                  prefer_group: None,
+                  fields: None,
                 })
                 .await
                 .unwrap();
@@ -219,16 +229,18 @@ async fn main() {
               let visibility_timeout_secs = thread_rng().gen_range(1800..3600);
               let res = queued
                 .update(OpUpdateInput {
-                  id,
-                  poll_tag: poll_tag.clone(),
-                  visibility_timeout_secs,
+                  messages: vec![OpUpdateInputMessage {
+                    id,
+                    poll_tag,
+                    visibility_timeout_secs,
+                  }],
                 })
                 .await
                 .unwrap();
               tasks_sender
                 .send(Task::Delete {
                   id,
-                  poll_tag: res.new_poll_tag,
+                  poll_tag: res.new_poll_tags[0].unwrap(),
                 })
                 .unwrap();
               progress.update.fetch_add(1, Ordering::Relaxed);